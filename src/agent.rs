@@ -0,0 +1,248 @@
+//! Pushing verification results to a central fimbl server
+
+use crate::{
+    error::FimblError,
+    report::{ReportItem, Summary},
+};
+
+use hmac::{Hmac, Mac};
+use sha3::Sha3_256;
+use std::{
+    fs::read,
+    path::Path,
+    thread::sleep,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// Header carrying the hex HMAC-SHA3_256 signature of a pushed report
+pub const SIGNATURE_HEADER: &str = "X-Fimbl-Signature";
+
+/// Secret key shared by agents and server for signing reports
+#[derive(Clone)]
+pub struct SigningKey(Vec<u8>);
+
+impl SigningKey {
+    /// Read a key from the entire contents of a file
+    pub fn from_file(path: &Path) -> Result<Self, FimblError> {
+        let key = read(path).map_err(|e| FimblError::KeyFileError(path.to_owned(), e))?;
+        if key.is_empty() {
+            Err(FimblError::EmptyKeyFile(path.to_owned()))
+        } else {
+            Ok(SigningKey(key))
+        }
+    }
+
+    /// A key from raw bytes
+    pub fn from_bytes(key: &[u8]) -> Self {
+        SigningKey(key.to_vec())
+    }
+
+    fn mac(&self) -> Hmac<Sha3_256> {
+        <Hmac<Sha3_256> as Mac>::new_from_slice(&self.0).expect("HMAC accepts keys of any size")
+    }
+
+    /// Raw signature (MAC) of a body
+    pub fn tag(&self, body: &[u8]) -> Vec<u8> {
+        let mut mac = self.mac();
+        mac.update(body);
+        mac.finalize().into_bytes().to_vec()
+    }
+
+    /// Check a raw signature of a body (in constant time)
+    pub fn verify_tag(&self, body: &[u8], tag: &[u8]) -> bool {
+        let mut mac = self.mac();
+        mac.update(body);
+        mac.verify_slice(tag).is_ok()
+    }
+
+    /// Hex signature of a body
+    pub fn sign(&self, body: &[u8]) -> String {
+        hex::encode(self.tag(body))
+    }
+
+    /// Check a hex signature of a body
+    pub fn verify(&self, body: &[u8], signature: &str) -> bool {
+        match hex::decode(signature) {
+            Ok(tag) => self.verify_tag(body, &tag),
+            Err(_) => false,
+        }
+    }
+}
+
+/// The results of one verification run on one host
+#[derive(Serialize)]
+pub struct RunReport<'a> {
+    /// Host which ran the verification
+    pub host: &'a str,
+
+    /// When the run completed (seconds since the unix epoch)
+    pub generated: u64,
+
+    /// Everything reported
+    pub items: &'a [ReportItem],
+
+    /// Totals for the run
+    pub summary: &'a Summary,
+}
+
+impl<'a> RunReport<'a> {
+    /// A report of items from a run completing now
+    pub fn new(host: &'a str, items: &'a [ReportItem], summary: &'a Summary) -> Self {
+        let generated = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        RunReport {
+            host,
+            generated,
+            items,
+            summary,
+        }
+    }
+}
+
+/// Name of this host, for identifying reports
+pub fn local_hostname() -> String {
+    gethostname::gethostname().to_string_lossy().into_owned()
+}
+
+/// Name of the effective user, for recording who changed the database
+#[cfg(unix)]
+pub fn local_user() -> String {
+    let uid = unsafe { libc::geteuid() };
+    let mut passwd: libc::passwd = unsafe { std::mem::zeroed() };
+    let mut buffer = vec![0; 4096];
+    let mut found = std::ptr::null_mut();
+    // SAFETY: the buffer outlives passwd, whose strings point into it
+    let status = unsafe {
+        libc::getpwuid_r(
+            uid,
+            &mut passwd,
+            buffer.as_mut_ptr(),
+            buffer.len(),
+            &mut found,
+        )
+    };
+    if status != 0 || found.is_null() {
+        return format!("uid {uid}");
+    }
+    // SAFETY: getpwuid_r found an entry, with a NUL terminated name
+    unsafe { std::ffi::CStr::from_ptr(passwd.pw_name) }
+        .to_string_lossy()
+        .into_owned()
+}
+
+/// Name the user logged in as (which may differ from the effective
+/// user, say under sudo), if there is a login session
+#[cfg(unix)]
+pub fn local_login() -> Option<String> {
+    let name = unsafe { libc::getlogin() };
+    if name.is_null() {
+        return None;
+    }
+    // SAFETY: getlogin gave a NUL terminated name, copied before any
+    // other call could overwrite it
+    let name = unsafe { std::ffi::CStr::from_ptr(name) };
+    Some(name.to_string_lossy().into_owned())
+}
+
+/// Name the user logged in as, if known
+#[cfg(not(unix))]
+pub fn local_login() -> Option<String> {
+    std::env::var("USERNAME").ok()
+}
+
+/// Name of the user, for recording who changed the database
+#[cfg(not(unix))]
+pub fn local_user() -> String {
+    std::env::var("USERNAME").unwrap_or_else(|_| "unknown".to_string())
+}
+
+/// Push a report as JSON to the endpoint, retrying with exponential
+/// backoff on failure
+pub fn push_report(
+    endpoint: &str,
+    token: Option<&str>,
+    key: Option<&SigningKey>,
+    report: &RunReport,
+    retries: u32,
+) -> Result<(), FimblError> {
+    let body = serde_json::to_vec(report).unwrap();
+    let agent = ureq::Agent::new();
+    let mut backoff = Duration::from_secs(1);
+    let mut attempt = 0;
+
+    loop {
+        let mut request = agent.post(endpoint).set("Content-Type", "application/json");
+        if let Some(token) = token {
+            request = request.set("Authorization", &format!("Bearer {token}"));
+        }
+        if let Some(key) = key {
+            request = request.set(SIGNATURE_HEADER, &key.sign(&body));
+        }
+
+        match request.send_bytes(&body) {
+            Ok(_) => return Ok(()),
+            Err(e) if attempt >= retries => return Err(e.into()),
+            Err(_) => {
+                sleep(backoff);
+                backoff *= 2;
+                attempt += 1;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+
+    use super::*;
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_signatures() {
+        let key = SigningKey(b"shared".to_vec());
+        let signature = key.sign(b"body");
+        assert!(key.verify(b"body", &signature));
+        assert!(!key.verify(b"tampered", &signature));
+        assert!(!SigningKey(b"other".to_vec()).verify(b"body", &signature));
+        assert!(!key.verify(b"body", "not hex"));
+    }
+
+    #[test]
+    fn test_run_report_json() {
+        let items = vec![ReportItem::FileContentChanged {
+            path: PathBuf::from("/etc/hosts"),
+            recorded_hash: None,
+            current_hash: None,
+            blocks: None,
+            fuzzy_distance: None,
+        }];
+        let summary = Summary {
+            files_examined: 3,
+            bytes_hashed: 2048,
+            items: [("file-content-changed".to_string(), 1)].into(),
+            elapsed_seconds: 0.5,
+            bytes_per_second: 4096.0,
+        };
+        let report = RunReport {
+            host: "web1",
+            generated: 1,
+            items: &items,
+            summary: &summary,
+        };
+        assert_eq!(
+            serde_json::to_string(&report).unwrap(),
+            concat!(
+                r#"{"host":"web1","generated":1,"items":[{"code":"F001","kind":"file-content-changed","#,
+                r#""severity":"warning","path":"/etc/hosts","details":{}}],"#,
+                r#""summary":{"files_examined":3,"bytes_hashed":2048,"items":{"file-content-changed":1},"#,
+                r#""elapsed_seconds":0.5,"bytes_per_second":4096.0}}"#
+            )
+        );
+        assert_eq!(
+            summary.to_string(),
+            "3 files examined, 2.0 KiB hashed in 0.50s (4.0 KiB/s); 1 file-content-changed"
+        );
+    }
+}
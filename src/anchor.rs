@@ -0,0 +1,118 @@
+//! Anchoring the database to records kept outside it
+//!
+//! After each change to the baseline its root hash, a digest over
+//! every fingerprint record, is appended to an anchor file and / or
+//! posted to an endpoint, so that an auditor can later confirm that
+//! the database was not rewritten since.
+
+use crate::{error::FimblError, fingerprint::HashValue};
+
+use std::{
+    fs::{read_to_string, OpenOptions},
+    io::{self, Write},
+    path::Path,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// The root hash of a host's database at some time
+#[derive(Serialize, PartialEq, Eq, Debug)]
+pub struct Anchor {
+    /// Host whose database it is
+    pub host: String,
+
+    /// When the hash was taken (seconds since the unix epoch)
+    pub time: u64,
+
+    /// Hex root hash
+    pub root_hash: String,
+}
+
+impl Anchor {
+    /// An anchor for a root hash taken now
+    pub fn new(host: &str, root_hash: &HashValue) -> Self {
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        Anchor {
+            host: host.to_string(),
+            time,
+            root_hash: hex::encode(root_hash),
+        }
+    }
+
+    /// The anchor as a line of an anchor file: time, host and hash
+    pub fn line(&self) -> String {
+        let time = UNIX_EPOCH + Duration::from_secs(self.time);
+        format!(
+            "{} {} {}",
+            humantime::format_rfc3339_seconds(time),
+            self.host,
+            self.root_hash
+        )
+    }
+
+    /// Read a line of an anchor file
+    fn parse(line: &str) -> Option<Self> {
+        let mut words = line.split_whitespace();
+        let time: SystemTime = humantime::parse_rfc3339(words.next()?).ok()?;
+        let (host, root_hash) = (words.next()?, words.next()?);
+        Some(Anchor {
+            host: host.to_string(),
+            time: time.duration_since(UNIX_EPOCH).ok()?.as_secs(),
+            root_hash: root_hash.to_string(),
+        })
+    }
+}
+
+/// Append an anchor to an anchor file (creating it if need be)
+pub fn append(file: &Path, anchor: &Anchor) -> io::Result<()> {
+    let mut output = OpenOptions::new().create(true).append(true).open(file)?;
+    writeln!(output, "{}", anchor.line())
+}
+
+/// Post an anchor, as JSON, to an endpoint
+pub fn post(url: &str, token: Option<&str>, anchor: &Anchor) -> Result<(), FimblError> {
+    let mut request = ureq::post(url).set("Content-Type", "application/json");
+    if let Some(token) = token {
+        request = request.set("Authorization", &format!("Bearer {token}"));
+    }
+    request.send_bytes(&serde_json::to_vec(anchor).unwrap())?;
+    Ok(())
+}
+
+/// The last anchor for a host in an anchor file, if any
+pub fn last_anchor(file: &Path, host: &str) -> Result<Option<Anchor>, FimblError> {
+    let contents =
+        read_to_string(file).map_err(|e| FimblError::AnchorFileError(file.to_owned(), e))?;
+    Ok(contents
+        .lines()
+        .rev()
+        .filter_map(Anchor::parse)
+        .find(|anchor| anchor.host == host))
+}
+
+#[cfg(test)]
+pub mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_last_anchor() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("anchors");
+        let anchors = [
+            Anchor::new("web1", &[1; 32]),
+            Anchor::new("web2", &[2; 32]),
+            Anchor::new("web1", &[3; 32]),
+        ];
+        for anchor in &anchors {
+            append(&file, anchor).unwrap();
+        }
+        assert_eq!(
+            last_anchor(&file, "web1").unwrap(),
+            Some(anchors.into_iter().nth(2).unwrap())
+        );
+        assert_eq!(last_anchor(&file, "db1").unwrap(), None);
+    }
+}
@@ -0,0 +1,372 @@
+//! Backup archives of the database
+//!
+//! An archive is a zstd-compressed tar holding a `backup` member (the
+//! format version and when it was made), one `trees/` member per sled
+//! tree exported and, last, a `SHA3SUMS` member giving the SHA3-256
+//! digest of each member before it, as `sha3sum` would. Archives are
+//! streamed as written and read, so no backup is ever held in memory
+//! whole.
+
+use crate::error::FimblError;
+
+use sha3::{Digest, Sha3_256};
+use std::{
+    fs::{rename, File},
+    io::{self, BufReader, BufWriter, Read, Seek, Write},
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+use tar::{Archive, Builder, Header};
+
+/// Version of the archive format
+const FORMAT_VERSION: u32 = 2;
+
+/// Name of the member describing the archive
+const BACKUP_MEMBER: &str = "backup";
+
+/// Directory of the members holding each tree
+const TREES_DIR: &str = "trees/";
+
+/// Name of the member holding the digests of the others
+const DIGESTS_MEMBER: &str = "SHA3SUMS";
+
+/// Compression level of archives (zstd's default)
+const COMPRESSION_LEVEL: i32 = 3;
+
+/// A raw key and value of a sled tree
+pub type Entry = (Vec<u8>, Vec<u8>);
+
+/// A backup archive being written
+///
+/// Values are copied as stored so the backup of an encrypted database
+/// is itself encrypted. The archive is written alongside the output and
+/// renamed into place by `finish` so a partial archive is never left
+/// behind.
+pub struct BackupWriter {
+    builder: Option<Builder<zstd::Encoder<'static, BufWriter<File>>>>,
+    partial: PathBuf,
+    output: PathBuf,
+    digests: String,
+}
+
+impl BackupWriter {
+    /// Start an archive to be written to the output, made now
+    pub fn create(output: &Path) -> Result<Self, FimblError> {
+        let mut name = output.file_name().unwrap_or_default().to_owned();
+        name.push(".partial");
+        let partial = output.with_file_name(name);
+
+        let file = BufWriter::new(File::create(&partial)?);
+        let mut writer = BackupWriter {
+            builder: Some(Builder::new(zstd::Encoder::new(file, COMPRESSION_LEVEL)?)),
+            partial,
+            output: output.to_owned(),
+            digests: String::new(),
+        };
+        let description = rmp_serde::to_vec(&(FORMAT_VERSION, SystemTime::now())).unwrap();
+        writer.append(
+            BACKUP_MEMBER,
+            &mut &description[..],
+            description.len() as u64,
+        )?;
+        Ok(writer)
+    }
+
+    /// Add a tree, its entries spooled through a temporary file so the
+    /// size of its member is known before it is written
+    pub fn add_tree<I>(&mut self, name: &[u8], entries: I) -> Result<(), FimblError>
+    where
+        I: IntoIterator<Item = Result<Entry, FimblError>>,
+    {
+        let mut spool = BufWriter::new(tempfile::tempfile()?);
+        for entry in entries {
+            let (k, v) = entry?;
+            for bytes in [k, v] {
+                spool.write_all(&(bytes.len() as u64).to_be_bytes())?;
+                spool.write_all(&bytes)?;
+            }
+        }
+        let mut spool = spool.into_inner().map_err(io::Error::from)?;
+        let size = spool.stream_position()?;
+        spool.rewind()?;
+        self.append(
+            &format!("{TREES_DIR}{}", hex::encode(name)),
+            &mut BufReader::new(spool),
+            size,
+        )
+    }
+
+    /// Write the digests of the members and move the archive into place
+    pub fn finish(mut self) -> Result<(), FimblError> {
+        let mut builder = self.builder.take().unwrap();
+        let digests = std::mem::take(&mut self.digests);
+        let mut header = header(DIGESTS_MEMBER, digests.len() as u64);
+        builder.append_data(&mut header, DIGESTS_MEMBER, digests.as_bytes())?;
+        let file = builder.into_inner()?.finish()?;
+        file.into_inner().map_err(io::Error::from)?.sync_all()?;
+        rename(&self.partial, &self.output)?;
+        Ok(())
+    }
+
+    /// Append a member, noting its digest
+    fn append(&mut self, name: &str, data: &mut dyn Read, size: u64) -> Result<(), FimblError> {
+        let mut hashing = Hashing::new(data);
+        let builder = self.builder.as_mut().unwrap();
+        builder.append_data(&mut header(name, size), name, &mut hashing)?;
+        self.digests
+            .push_str(&format!("{}  {name}\n", hex::encode(hashing.digest())));
+        Ok(())
+    }
+}
+
+impl Drop for BackupWriter {
+    fn drop(&mut self) {
+        // an archive not finished is still partial
+        if self.builder.take().is_some() {
+            let _ = std::fs::remove_file(&self.partial);
+        }
+    }
+}
+
+/// The header of a member of the given size
+fn header(name: &str, size: u64) -> Header {
+    let mut header = Header::new_gnu();
+    header.set_path(name).unwrap();
+    header.set_size(size);
+    header.set_mode(0o600);
+    header.set_cksum();
+    header
+}
+
+/// Read an archive, passing each tree's name and entries to `tree` as
+/// they are decoded
+///
+/// The digests of the members come last, so `tree` may have been given
+/// entries of an archive later found to be corrupt; what it did with
+/// them is for the caller to undo on error.
+pub fn read_backup<F>(input: &Path, mut tree: F) -> Result<SystemTime, FimblError>
+where
+    F: FnMut(&[u8], &mut dyn Iterator<Item = Result<Entry, FimblError>>) -> Result<(), FimblError>,
+{
+    let corrupt = || FimblError::BackupCorrupt(input.to_owned());
+    let decoder = zstd::Decoder::new(File::open(input)?).map_err(|_| corrupt())?;
+    let mut archive = Archive::new(decoder);
+
+    let mut created = None;
+    let mut digests = String::new();
+    let mut recorded = None;
+    for member in archive.entries().map_err(|_| corrupt())? {
+        let member = member.map_err(|_| corrupt())?;
+        let name = member
+            .path()
+            .map_err(|_| corrupt())?
+            .to_string_lossy()
+            .into_owned();
+        if recorded.is_some() {
+            return Err(corrupt());
+        }
+        if name == DIGESTS_MEMBER {
+            let mut sums = String::new();
+            BufReader::new(member)
+                .read_to_string(&mut sums)
+                .map_err(|_| corrupt())?;
+            recorded = Some(sums);
+            continue;
+        }
+
+        let mut hashing = Hashing::new(member);
+        if name == BACKUP_MEMBER && created.is_none() {
+            let (version, made): (u32, SystemTime) =
+                rmp_serde::from_read(&mut hashing).map_err(|_| corrupt())?;
+            if version != FORMAT_VERSION {
+                return Err(corrupt());
+            }
+            created = Some(made);
+        } else if let (Some(hex_name), Some(_)) = (name.strip_prefix(TREES_DIR), created) {
+            let tree_name = hex::decode(hex_name).map_err(|_| corrupt())?;
+            let mut entries = Entries {
+                reader: BufReader::new(&mut hashing),
+                input,
+            };
+            tree(&tree_name, &mut entries)?;
+        } else {
+            return Err(corrupt());
+        }
+        io::copy(&mut hashing, &mut io::sink()).map_err(|_| corrupt())?;
+        digests.push_str(&format!("{}  {name}\n", hex::encode(hashing.digest())));
+    }
+
+    match (created, recorded) {
+        (Some(created), Some(recorded)) if recorded == digests => Ok(created),
+        _ => Err(corrupt()),
+    }
+}
+
+/// The entries of a tree member, as length-prefixed keys and values
+struct Entries<'a, R> {
+    reader: BufReader<R>,
+    input: &'a Path,
+}
+
+impl<R: Read> Entries<'_, R> {
+    /// The next length-prefixed field, or none at the end of the member
+    fn field(&mut self, at_end: bool) -> Result<Option<Vec<u8>>, FimblError> {
+        let corrupt = || FimblError::BackupCorrupt(self.input.to_owned());
+        let mut length = [0; 8];
+        match self.reader.read_exact(&mut length) {
+            Err(e) if at_end && e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            result => result.map_err(|_| corrupt())?,
+        }
+        let mut bytes = vec![];
+        let length = u64::from_be_bytes(length);
+        (&mut self.reader)
+            .take(length)
+            .read_to_end(&mut bytes)
+            .map_err(|_| corrupt())?;
+        if bytes.len() as u64 != length {
+            return Err(corrupt());
+        }
+        Ok(Some(bytes))
+    }
+}
+
+impl<R: Read> Iterator for Entries<'_, R> {
+    type Item = Result<Entry, FimblError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.field(true) {
+            Ok(Some(k)) => Some(self.field(false).map(|v| (k, v.unwrap()))),
+            Ok(None) => None,
+            Err(e) => Some(Err(e)),
+        }
+    }
+}
+
+/// A reader hashing what is read through it
+struct Hashing<R> {
+    inner: R,
+    hasher: Sha3_256,
+}
+
+impl<R: Read> Hashing<R> {
+    fn new(inner: R) -> Self {
+        Hashing {
+            inner,
+            hasher: Sha3_256::new(),
+        }
+    }
+
+    fn digest(self) -> [u8; 32] {
+        self.hasher.finalize().into()
+    }
+}
+
+impl<R: Read> Read for Hashing<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.hasher.update(&buf[..read]);
+        Ok(read)
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+
+    use super::*;
+
+    /// Tree names and their entries
+    type Trees = Vec<(Vec<u8>, Vec<Entry>)>;
+
+    /// The trees read back from an archive
+    fn read_trees(archive: &Path) -> Result<Trees, FimblError> {
+        let mut trees = vec![];
+        read_backup(archive, |name, entries| {
+            trees.push((name.to_vec(), entries.collect::<Result<_, _>>()?));
+            Ok(())
+        })?;
+        Ok(trees)
+    }
+
+    #[test]
+    fn test_round_trip_and_corruption() {
+        let dir = tempfile::tempdir().unwrap();
+        let archive = dir.path().join("fimbl.bak");
+        let trees = vec![
+            (
+                b"fingerprints".to_vec(),
+                vec![
+                    (b"/etc/hosts".to_vec(), b"record".to_vec()),
+                    (b"/etc/passwd".to_vec(), vec![]),
+                ],
+            ),
+            (b"logs".to_vec(), vec![]),
+        ];
+
+        let mut writer = BackupWriter::create(&archive).unwrap();
+        for (name, entries) in &trees {
+            writer
+                .add_tree(name, entries.iter().cloned().map(Ok))
+                .unwrap();
+        }
+        assert!(dir.path().join("fimbl.bak.partial").exists());
+        writer.finish().unwrap();
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+        assert_eq!(read_trees(&archive).unwrap(), trees);
+
+        // a tar any tool can list, once decompressed
+        let decompressed = zstd::decode_all(File::open(&archive).unwrap()).unwrap();
+        let names: Vec<_> = Archive::new(&decompressed[..])
+            .entries()
+            .unwrap()
+            .map(|e| e.unwrap().path().unwrap().to_string_lossy().into_owned())
+            .collect();
+        assert_eq!(
+            names,
+            vec![
+                "backup",
+                "trees/66696e6765727072696e7473",
+                "trees/6c6f6773",
+                "SHA3SUMS"
+            ]
+        );
+
+        // a value altered, and the archive compressed again
+        let at = decompressed
+            .windows(6)
+            .position(|w| w == b"record")
+            .unwrap();
+        let mut altered = decompressed.clone();
+        altered[at] ^= 0xff;
+        std::fs::write(&archive, zstd::encode_all(&altered[..], 3).unwrap()).unwrap();
+        assert!(matches!(
+            read_trees(&archive),
+            Err(FimblError::BackupCorrupt(_))
+        ));
+
+        // or cut short
+        std::fs::write(
+            &archive,
+            zstd::encode_all(&decompressed[..decompressed.len() / 2], 3).unwrap(),
+        )
+        .unwrap();
+        assert!(matches!(
+            read_trees(&archive),
+            Err(FimblError::BackupCorrupt(_))
+        ));
+    }
+
+    #[test]
+    fn test_unfinished_archive_removed() {
+        let dir = tempfile::tempdir().unwrap();
+        let archive = dir.path().join("fimbl.bak");
+        let mut writer = BackupWriter::create(&archive).unwrap();
+        assert!(writer
+            .add_tree(
+                b"fingerprints",
+                [Err(FimblError::BackupCorrupt(archive.clone()))]
+            )
+            .is_err());
+        drop(writer);
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 0);
+    }
+}
@@ -0,0 +1,169 @@
+//! Signed baselines for publishing to object storage
+//!
+//! A baseline is an export of the files currently tracked and their
+//! fingerprints. It is signed with a shared key (HMAC-SHA3_256) so a
+//! host verifying against a published baseline knows it hasn't been
+//! tampered with in storage.
+//!
+//! A baseline can also be exported as a JSON manifest, the baseline's
+//! JSON signed as written, for verifying on minimal systems with no
+//! database at all (`fimbl verify --manifest`).
+
+use crate::{agent::SigningKey, error::FimblError, fingerprint::Fingerprint};
+
+use serde_json::value::RawValue;
+use std::{path::PathBuf, time::SystemTime};
+
+/// Magic bytes at the start of every baseline
+const MAGIC: &[u8] = b"FIMBLBSL";
+
+/// Version of the baseline format
+const FORMAT_VERSION: u32 = 1;
+
+/// Size of the trailing signature over the baseline body
+const SIGNATURE_SIZE: usize = 32;
+
+/// A baseline as a signed JSON manifest
+#[derive(Serialize, Deserialize)]
+struct Manifest<'a> {
+    /// The baseline's JSON, exactly as signed
+    #[serde(borrow)]
+    baseline: &'a RawValue,
+
+    /// Hex signature of the baseline's JSON
+    signature: String,
+}
+
+/// Exported fingerprints of tracked files
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug)]
+pub struct Baseline {
+    /// Baseline format version
+    pub version: u32,
+
+    /// When the baseline was exported
+    pub created: SystemTime,
+
+    /// Host the baseline belongs to, if exported from a shared database
+    pub host: Option<String>,
+
+    /// Tracked files and their fingerprints
+    pub files: Vec<(PathBuf, Fingerprint)>,
+}
+
+impl Baseline {
+    /// A baseline of the supplied files exported now
+    pub fn new(host: Option<String>, files: Vec<(PathBuf, Fingerprint)>) -> Self {
+        Baseline {
+            version: FORMAT_VERSION,
+            created: SystemTime::now(),
+            host,
+            files,
+        }
+    }
+
+    /// Serialise and sign: magic, body and a signature of the body
+    pub fn to_bytes(&self, key: &SigningKey) -> Vec<u8> {
+        let body = rmp_serde::to_vec(self).unwrap();
+        [MAGIC, &body, &key.tag(&body)].concat()
+    }
+
+    /// Check the signature and deserialise
+    pub fn from_bytes(bytes: &[u8], key: &SigningKey) -> Result<Self, FimblError> {
+        if bytes.len() < MAGIC.len() + SIGNATURE_SIZE || !bytes.starts_with(MAGIC) {
+            return Err(FimblError::BaselineInvalid);
+        }
+        let (body, signature) =
+            bytes[MAGIC.len()..].split_at(bytes.len() - MAGIC.len() - SIGNATURE_SIZE);
+        if !key.verify_tag(body, signature) {
+            return Err(FimblError::BaselineInvalid);
+        }
+
+        let baseline: Baseline =
+            rmp_serde::from_slice(body).map_err(|_| FimblError::BaselineInvalid)?;
+        if baseline.version != FORMAT_VERSION {
+            return Err(FimblError::BaselineInvalid);
+        }
+        Ok(baseline)
+    }
+
+    /// Serialise and sign as a JSON manifest
+    pub fn to_json(&self, key: &SigningKey) -> Vec<u8> {
+        let body = RawValue::from_string(serde_json::to_string(self).unwrap()).unwrap();
+        let manifest = Manifest {
+            baseline: &body,
+            signature: key.sign(body.get().as_bytes()),
+        };
+        let mut json = serde_json::to_vec(&manifest).unwrap();
+        json.push(b'\n');
+        json
+    }
+
+    /// Check the signature of a JSON manifest and deserialise
+    pub fn from_json(bytes: &[u8], key: &SigningKey) -> Result<Self, FimblError> {
+        let manifest: Manifest =
+            serde_json::from_slice(bytes).map_err(|_| FimblError::BaselineInvalid)?;
+        let body = manifest.baseline.get();
+        if !key.verify(body.as_bytes(), &manifest.signature) {
+            return Err(FimblError::BaselineInvalid);
+        }
+
+        let baseline: Baseline =
+            serde_json::from_str(body).map_err(|_| FimblError::BaselineInvalid)?;
+        if baseline.version != FORMAT_VERSION {
+            return Err(FimblError::BaselineInvalid);
+        }
+        Ok(baseline)
+    }
+
+    /// Check the signature and deserialise a baseline either as
+    /// published or as a JSON manifest
+    pub fn read(bytes: &[u8], key: &SigningKey) -> Result<Self, FimblError> {
+        match bytes.starts_with(MAGIC) {
+            true => Self::from_bytes(bytes, key),
+            false => Self::from_json(bytes, key),
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+
+    use super::*;
+    use crate::fingerprint::Fingerprinter;
+
+    #[test]
+    fn test_signed_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("hosts");
+        std::fs::write(&file, "127.0.0.1 localhost").unwrap();
+        let fingerprint = Fingerprinter::new(None).fingerprint(&file).unwrap();
+        let baseline = Baseline::new(Some("web1".to_string()), vec![(file, fingerprint)]);
+
+        let key = SigningKey::from_bytes(b"shared");
+        let mut bytes = baseline.to_bytes(&key);
+        assert_eq!(Baseline::from_bytes(&bytes, &key).unwrap(), baseline);
+        assert!(matches!(
+            Baseline::from_bytes(&bytes, &SigningKey::from_bytes(b"other")),
+            Err(FimblError::BaselineInvalid)
+        ));
+
+        let middle = bytes.len() / 2;
+        bytes[middle] ^= 0xff;
+        assert!(matches!(
+            Baseline::from_bytes(&bytes, &key),
+            Err(FimblError::BaselineInvalid)
+        ));
+
+        let json = baseline.to_json(&key);
+        assert_eq!(Baseline::read(&json, &key).unwrap(), baseline);
+        let text = String::from_utf8(json).unwrap();
+        let tampered = text.replace("\"web1\"", "\"web2\"");
+        assert!(matches!(
+            Baseline::read(tampered.as_bytes(), &key),
+            Err(FimblError::BaselineInvalid)
+        ));
+        // reformatting outside the signed baseline is harmless
+        let spaced = text.replacen("{", "{ ", 1);
+        assert_eq!(Baseline::read(spaced.as_bytes(), &key).unwrap(), baseline);
+    }
+}
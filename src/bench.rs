@@ -0,0 +1,358 @@
+//! Measuring hashing throughput on this machine
+//!
+//! A scratch file of arbitrary contents is written and read once, so it
+//! is hashed from the page cache and the disk doesn't count, then
+//! fingerprinted with each algorithm and each way of reading it:
+//! buffers of several sizes, or mapping it into memory. Files of at
+//! least `CHUNKED_THRESHOLD` bytes are hashed in chunks in parallel, as
+//! fimbl always hashes them. SHA-256, which fimbl gives only in
+//! attestations, and BLAKE3 are timed reading sequentially, for
+//! comparison.
+
+use crate::{
+    error::FimblError,
+    fingerprint::{Fingerprinter, HashKey, DEFAULT_READ_BUFFER},
+    report::scaled,
+};
+
+use sha2::{Digest, Sha256};
+use std::{
+    fs::File,
+    io::{self, BufWriter, Read, Write},
+    path::Path,
+    time::{Duration, Instant},
+};
+
+/// Sizes of read buffer tried
+const BUFFER_SIZES: &[usize] = &[64 << 10, DEFAULT_READ_BUFFER, 8 << 20];
+
+/// How much faster than the defaults other settings must be to be
+/// worth recommending
+const WORTHWHILE: f64 = 1.1;
+
+/// Algorithms to time
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum BenchAlgorithm {
+    /// All of them
+    All,
+    /// SHA3-256, as fimbl records without a key
+    #[value(name = "sha3-256")]
+    Sha3_256,
+    /// HMAC-SHA3-256, as fimbl records with a key
+    #[value(name = "hmac-sha3-256")]
+    HmacSha3_256,
+    /// SHA-256, for comparison
+    Sha256,
+    /// BLAKE3, for comparison
+    Blake3,
+}
+
+impl std::fmt::Display for BenchAlgorithm {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BenchAlgorithm::All => write!(f, "all"),
+            BenchAlgorithm::Sha3_256 => write!(f, "sha3-256"),
+            BenchAlgorithm::HmacSha3_256 => write!(f, "hmac-sha3-256"),
+            BenchAlgorithm::Sha256 => write!(f, "sha256"),
+            BenchAlgorithm::Blake3 => write!(f, "blake3"),
+        }
+    }
+}
+
+impl BenchAlgorithm {
+    /// The algorithms named, with `all` standing for every one
+    pub fn expand(named: &[BenchAlgorithm]) -> Vec<BenchAlgorithm> {
+        let every = [
+            BenchAlgorithm::Sha3_256,
+            BenchAlgorithm::HmacSha3_256,
+            BenchAlgorithm::Sha256,
+            BenchAlgorithm::Blake3,
+        ];
+        every
+            .into_iter()
+            .filter(|algorithm| named.contains(algorithm) || named.contains(&BenchAlgorithm::All))
+            .collect()
+    }
+}
+
+/// A way of reading file contents
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Strategy {
+    pub buffer_size: usize,
+    pub mmap: bool,
+}
+
+impl Strategy {
+    /// Ways tried: each buffer size, then mapping into memory
+    fn all() -> Vec<Strategy> {
+        let reads = BUFFER_SIZES.iter().map(|&buffer_size| Strategy {
+            buffer_size,
+            mmap: false,
+        });
+        let mapped = Strategy {
+            buffer_size: DEFAULT_READ_BUFFER,
+            mmap: true,
+        };
+        reads.chain(Some(mapped)).collect()
+    }
+
+    /// Options setting this way of reading
+    pub fn options(&self) -> String {
+        match self.mmap {
+            true => format!("--read-buffer {} --mmap", size_option(self.buffer_size)),
+            false => format!("--read-buffer {}", size_option(self.buffer_size)),
+        }
+    }
+}
+
+impl Default for Strategy {
+    fn default() -> Self {
+        Strategy {
+            buffer_size: DEFAULT_READ_BUFFER,
+            mmap: false,
+        }
+    }
+}
+
+impl std::fmt::Display for Strategy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.mmap {
+            true => write!(f, "mmap"),
+            false => write!(f, "{} reads", size_option(self.buffer_size)),
+        }
+    }
+}
+
+/// A size as `--read-buffer` takes it
+fn size_option(size: usize) -> String {
+    match size {
+        size if size % (1 << 20) == 0 => format!("{}M", size >> 20),
+        size if size % (1 << 10) == 0 => format!("{}K", size >> 10),
+        size => size.to_string(),
+    }
+}
+
+/// Time taken to hash a file one way
+#[derive(Debug)]
+pub struct Measurement {
+    pub algorithm: BenchAlgorithm,
+    pub strategy: Strategy,
+    pub bytes: u64,
+    pub elapsed: Duration,
+}
+
+impl Measurement {
+    /// Bytes hashed a second
+    pub fn throughput(&self) -> f64 {
+        self.bytes as f64 / self.elapsed.as_secs_f64().max(f64::EPSILON)
+    }
+}
+
+impl std::fmt::Display for Measurement {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{:<14} {:<10} {:>12}/s",
+            self.algorithm.to_string(),
+            self.strategy.to_string(),
+            scaled(self.throughput())
+        )
+    }
+}
+
+/// Time hashing a scratch file of the size given, in the directory
+/// given, with each of the algorithms and every way of reading
+///
+/// A key given is used for HMAC, otherwise a scratch one.
+pub fn run(
+    dir: &Path,
+    size: u64,
+    algorithms: &[BenchAlgorithm],
+    key: Option<HashKey>,
+) -> Result<Vec<Measurement>, FimblError> {
+    let scratch = tempfile::Builder::new()
+        .prefix(".fimbl-bench")
+        .tempfile_in(dir)?;
+    write_scratch(scratch.as_file(), size)?;
+    // into the page cache
+    io::copy(&mut File::open(scratch.path())?, &mut io::sink())?;
+
+    let key = key.unwrap_or_else(|| HashKey::from_bytes(b"fimbl bench"));
+    let mut measurements = vec![];
+    for algorithm in BenchAlgorithm::expand(algorithms) {
+        for strategy in Strategy::all() {
+            let started = Instant::now();
+            let bytes = match algorithm {
+                BenchAlgorithm::Sha256 => {
+                    let mut hasher = Sha256::new();
+                    let bytes = read_sequentially(scratch.path(), strategy, |bytes| {
+                        hasher.update(bytes);
+                    })?;
+                    hasher.finalize();
+                    bytes
+                }
+                BenchAlgorithm::Blake3 => {
+                    let mut hasher = blake3::Hasher::new();
+                    let bytes = read_sequentially(scratch.path(), strategy, |bytes| {
+                        hasher.update(bytes);
+                    })?;
+                    hasher.finalize();
+                    bytes
+                }
+                _ => {
+                    let key = (algorithm == BenchAlgorithm::HmacSha3_256).then(|| key.clone());
+                    let mut fingerprinter =
+                        Fingerprinter::new(key).with_reads(strategy.buffer_size, strategy.mmap);
+                    fingerprinter.fingerprint(scratch.path())?;
+                    fingerprinter.bytes_hashed()
+                }
+            };
+            measurements.push(Measurement {
+                algorithm,
+                strategy,
+                bytes,
+                elapsed: started.elapsed(),
+            });
+        }
+    }
+    Ok(measurements)
+}
+
+/// Fill a file with bytes no file system or disk will compress away
+fn write_scratch(file: &File, size: u64) -> io::Result<()> {
+    let mut state: u64 = 0x9e37_79b9_7f4a_7c15;
+    let mut block = vec![0; DEFAULT_READ_BUFFER];
+    let mut writer = BufWriter::new(file);
+    let mut left = size;
+    while left > 0 {
+        for word in block.chunks_mut(8) {
+            // xorshift64
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            word.copy_from_slice(&state.to_le_bytes()[..word.len()]);
+        }
+        let len = left.min(block.len() as u64) as usize;
+        writer.write_all(&block[..len])?;
+        left -= len as u64;
+    }
+    writer.flush()
+}
+
+/// Read a file sequentially the way given, passing each piece read
+/// on, and return the number of bytes read
+fn read_sequentially(
+    path: &Path,
+    strategy: Strategy,
+    mut update: impl FnMut(&[u8]),
+) -> io::Result<u64> {
+    let mut file = File::open(path)?;
+    if strategy.mmap && file.metadata()?.len() > 0 {
+        // SAFETY: the scratch file is fimbl's own, and left alone
+        let map = unsafe { memmap2::Mmap::map(&file)? };
+        for chunk in map.chunks(strategy.buffer_size) {
+            update(chunk);
+        }
+        return Ok(map.len() as u64);
+    }
+    let mut buffer = vec![0; strategy.buffer_size];
+    let mut total = 0;
+    loop {
+        let read = file.read(&mut buffer)?;
+        if read == 0 {
+            return Ok(total);
+        }
+        update(&buffer[..read]);
+        total += read as u64;
+    }
+}
+
+/// What the measurements suggest for fimbl's own hashing with the
+/// algorithm given, and how long hashing the bytes tracked would take
+/// at the best rate measured, if it was measured at all
+pub fn recommend(
+    measurements: &[Measurement],
+    algorithm: BenchAlgorithm,
+    tracked_bytes: u64,
+) -> Option<String> {
+    let measured: Vec<&Measurement> = measurements
+        .iter()
+        .filter(|measurement| measurement.algorithm == algorithm)
+        .collect();
+    let best = measured
+        .iter()
+        .max_by(|a, b| a.throughput().total_cmp(&b.throughput()))?;
+    let default = measured
+        .iter()
+        .find(|measurement| measurement.strategy == Strategy::default())?;
+
+    let mut advice = match best.throughput() > default.throughput() * WORTHWHILE {
+        true => format!(
+            "recommended: {} ({:.0}% faster than the defaults with {algorithm})",
+            best.strategy.options(),
+            (best.throughput() / default.throughput() - 1.0) * 100.0
+        ),
+        false => format!("recommended: the defaults, within 10% of the fastest with {algorithm}"),
+    };
+    if tracked_bytes > 0 {
+        let seconds = (tracked_bytes as f64 / best.throughput()).ceil() as u64;
+        advice.push_str(&format!(
+            "\nhashing the {} tracked takes about {} from cache (longer from disk)",
+            scaled(tracked_bytes as f64),
+            humantime::format_duration(Duration::from_secs(seconds.max(1)))
+        ));
+    }
+    Some(advice)
+}
+
+#[cfg(test)]
+pub mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_run() {
+        let dir = tempfile::tempdir().unwrap();
+        let algorithms = [
+            BenchAlgorithm::Sha3_256,
+            BenchAlgorithm::Sha256,
+            BenchAlgorithm::Blake3,
+        ];
+        let measurements = run(dir.path(), 1 << 20, &algorithms, None).unwrap();
+        assert_eq!(measurements.len(), 3 * Strategy::all().len());
+        assert!(measurements.iter().all(|m| m.bytes == 1 << 20));
+        assert_eq!(
+            std::fs::read_dir(dir.path()).unwrap().count(),
+            0,
+            "scratch file removed"
+        );
+        assert_eq!(
+            BenchAlgorithm::expand(&[BenchAlgorithm::All]).len(),
+            4,
+            "all of them"
+        );
+    }
+
+    #[test]
+    fn test_recommend() {
+        let measured = |algorithm, buffer_size, mmap, millis| Measurement {
+            algorithm,
+            strategy: Strategy { buffer_size, mmap },
+            bytes: 1 << 30,
+            elapsed: Duration::from_millis(millis),
+        };
+        let measurements = [
+            measured(BenchAlgorithm::Sha3_256, 64 << 10, false, 1200),
+            measured(BenchAlgorithm::Sha3_256, 1 << 20, false, 1000),
+            measured(BenchAlgorithm::Sha3_256, 1 << 20, true, 500),
+            measured(BenchAlgorithm::Sha256, 1 << 20, false, 100),
+        ];
+        let advice = recommend(&measurements, BenchAlgorithm::Sha3_256, 4 << 30).unwrap();
+        assert!(advice.starts_with("recommended: --read-buffer 1M --mmap (100% faster"));
+        assert!(advice.contains("4.0 GiB tracked takes about 2s"));
+        assert!(recommend(&measurements, BenchAlgorithm::HmacSha3_256, 0).is_none());
+
+        let advice = recommend(&measurements[..2], BenchAlgorithm::Sha3_256, 0).unwrap();
+        assert!(advice.starts_with("recommended: the defaults"));
+    }
+}
@@ -0,0 +1,150 @@
+//! Agent settings from a config file
+//!
+//! Settings in the file (TOML) override those on the command line, and
+//! the file is read again when a running agent is told to reload it
+//! (`fimbl ctl reload-config`), so they can be changed without a
+//! restart:
+//!
+//! ```toml
+//! endpoint = "https://fimbl.example.com/reports"
+//! interval = "30m"
+//! retries = 5
+//! sign-key-file = "/etc/fimbl/report.key"
+//!
+//! # when to run, rather than at the interval (see `schedule`)
+//! schedule = "0 */4 * * *"
+//! jitter = "15m"
+//! quiet-hours = "Mon-Fri 09:00-17:00"
+//! ```
+
+use crate::{
+    agent::SigningKey,
+    error::FimblError,
+    schedule::{Cron, QuietHours},
+};
+
+use serde_derive::Deserialize;
+use std::{
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+/// The file as written
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+struct ConfigFile {
+    endpoint: Option<String>,
+    interval: Option<String>,
+    retries: Option<u32>,
+    sign_key_file: Option<PathBuf>,
+    schedule: Option<String>,
+    jitter: Option<String>,
+    quiet_hours: Option<String>,
+}
+
+/// Agent settings given in a config file, with any key file read
+#[derive(Default)]
+pub struct AgentConfig {
+    /// URL to push reports to
+    pub endpoint: Option<String>,
+
+    /// Time between runs
+    pub interval: Option<Duration>,
+
+    /// Number of times to retry a failed push
+    pub retries: Option<u32>,
+
+    /// Key to sign reports with
+    pub key: Option<SigningKey>,
+
+    /// Times runs are due, instead of at the interval
+    pub schedule: Option<Cron>,
+
+    /// Most random delay added to each run
+    pub jitter: Option<Duration>,
+
+    /// When runs are put off
+    pub quiet_hours: Option<QuietHours>,
+}
+
+impl AgentConfig {
+    /// Read the settings in a config file, and any key file it names
+    pub fn load(path: &Path) -> Result<Self, FimblError> {
+        let invalid = |message: String| FimblError::ConfigError(path.to_owned(), message);
+        let text = std::fs::read_to_string(path).map_err(|e| invalid(e.to_string()))?;
+        let file: ConfigFile = toml::from_str(&text).map_err(|e| invalid(e.message().into()))?;
+
+        let duration = |name: &str, value: Option<String>| {
+            value
+                .map(|value| humantime::parse_duration(&value))
+                .transpose()
+                .map_err(|e| invalid(format!("{name}: {e}")))
+        };
+        let interval = duration("interval", file.interval)?;
+        let jitter = duration("jitter", file.jitter)?;
+        let schedule = file
+            .schedule
+            .map(|schedule| schedule.parse())
+            .transpose()
+            .map_err(|e| invalid(format!("schedule: {e}")))?;
+        let quiet_hours = file
+            .quiet_hours
+            .map(|hours| hours.parse())
+            .transpose()
+            .map_err(|e| invalid(format!("quiet-hours: {e}")))?;
+        let key = file
+            .sign_key_file
+            .as_deref()
+            .map(SigningKey::from_file)
+            .transpose()?;
+        Ok(AgentConfig {
+            endpoint: file.endpoint,
+            interval,
+            retries: file.retries,
+            key,
+            schedule,
+            jitter,
+            quiet_hours,
+        })
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_load() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("agent.toml");
+        std::fs::write(&path, "interval = \"30m\"\nretries = 5\n").unwrap();
+        let config = AgentConfig::load(&path).unwrap();
+        assert_eq!(config.interval, Some(Duration::from_secs(30 * 60)));
+        assert_eq!(config.retries, Some(5));
+        assert!(config.endpoint.is_none() && config.key.is_none());
+
+        let text = "schedule = \"@daily\"\njitter = \"10m\"\nquiet-hours = \"09:00-17:00\"\n";
+        std::fs::write(&path, text).unwrap();
+        let config = AgentConfig::load(&path).unwrap();
+        assert_eq!(config.schedule, "0 0 * * *".parse().ok());
+        assert_eq!(config.jitter, Some(Duration::from_secs(600)));
+        assert!(config.quiet_hours.is_some());
+        std::fs::write(&path, "schedule = \"0 0 30 feb *\"\n").unwrap();
+        assert!(matches!(
+            AgentConfig::load(&path),
+            Err(FimblError::ConfigError(..))
+        ));
+
+        std::fs::write(&path, "interval = \"soon\"\n").unwrap();
+        assert!(matches!(
+            AgentConfig::load(&path),
+            Err(FimblError::ConfigError(..))
+        ));
+        std::fs::write(&path, "intervals = \"30m\"\n").unwrap();
+        assert!(matches!(
+            AgentConfig::load(&path),
+            Err(FimblError::ConfigError(..))
+        ));
+    }
+}
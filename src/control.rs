@@ -0,0 +1,520 @@
+//! The control socket of a running agent
+//!
+//! An agent running repeatedly listens on a unix domain socket for
+//! one-line commands and answers each with a line of JSON. `status`
+//! says what it is doing (for `fimbl top`): whether a run is under way
+//! and how far it has got, how the last run went, the most recent
+//! findings and when the next run is due. Other commands are passed
+//! on to the agent as signals, acted on between runs: to run now, to
+//! reload its config file, or to drain (exit once the run under way,
+//! if any, is done). Only the agent's own user may use the socket.
+
+use crate::{agent::RunReport, config::AgentConfig, error::FimblError, fingerprint::Progress};
+
+use serde_derive::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    sync::{
+        mpsc::{channel, Receiver, Sender},
+        Arc, Mutex,
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+#[cfg(unix)]
+use std::{
+    io::{BufRead, BufReader, Write},
+    time::Duration,
+};
+
+/// Commands a running agent accepts on its control socket
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ControlCommand {
+    /// Show what the agent is doing, as JSON
+    Status,
+    /// Start a run now rather than when it's due (or as soon as the
+    /// run under way is done)
+    TriggerScanNow,
+    /// Read the agent's config file again
+    ReloadConfig,
+    /// Exit once the run under way, if any, is done
+    Drain,
+}
+
+impl ControlCommand {
+    /// The command as sent on the socket
+    pub fn name(&self) -> String {
+        clap::ValueEnum::to_possible_value(self)
+            .map(|value| value.get_name().to_string())
+            .unwrap_or_default()
+    }
+}
+
+/// What the agent is told to do between runs (only over the socket,
+/// so never where there is none)
+#[cfg_attr(not(unix), allow(dead_code))]
+pub enum Signal {
+    /// Run now
+    Scan,
+    /// Apply settings read again from the config file
+    Reload(AgentConfig),
+    /// Exit
+    Drain,
+}
+
+/// Most recent findings kept for the status
+const RECENT: usize = 50;
+
+/// How long a client has to send its command
+#[cfg(unix)]
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How the last completed run went
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct LastRun {
+    /// When it finished (seconds since the unix epoch)
+    pub finished: u64,
+    pub files_examined: u64,
+    pub bytes_hashed: u64,
+    pub elapsed_seconds: f64,
+    /// Number of items reported
+    pub findings: usize,
+    /// Number of items reported, by kind
+    #[serde(default)]
+    pub items: BTreeMap<String, usize>,
+}
+
+/// An item reported by a run
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct RecentFinding {
+    /// When the run reporting it finished (seconds since the unix
+    /// epoch)
+    pub time: u64,
+    pub severity: String,
+    pub message: String,
+    /// Code of the item's kind
+    #[serde(default)]
+    pub code: String,
+}
+
+/// What an agent is doing
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct Status {
+    /// Host the agent reports as
+    pub host: String,
+
+    /// When the run under way started (seconds since the unix epoch),
+    /// if one is
+    pub scanning_since: Option<u64>,
+
+    /// Files tracked when the run under way started
+    pub files_tracked: u64,
+
+    /// Files examined so far in the run under way
+    pub files_examined: u64,
+
+    /// Bytes hashed so far in the run under way
+    pub bytes_hashed: u64,
+
+    /// The last run completed, if any
+    pub last_run: Option<LastRun>,
+
+    /// Items reported by recent runs, oldest first
+    pub recent: Vec<RecentFinding>,
+
+    /// When the next run is due (seconds since the unix epoch), if one
+    /// is scheduled
+    pub next_run: Option<u64>,
+
+    /// True if the agent will exit once the run under way is done
+    #[serde(default)]
+    pub draining: bool,
+
+    /// Number of runs completed
+    #[serde(default)]
+    pub runs: u64,
+}
+
+/// Seconds since the unix epoch
+pub fn epoch_seconds(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// State of an agent shared between its runs and the socket
+pub struct AgentState {
+    status: Mutex<Status>,
+
+    /// Work done by the run under way, counted by the fingerprinter
+    pub progress: Arc<Progress>,
+
+    /// The agent's config file, if it has one
+    #[cfg_attr(not(unix), allow(dead_code))]
+    config: Option<PathBuf>,
+
+    /// Signals to the agent
+    #[cfg_attr(not(unix), allow(dead_code))]
+    signals: Sender<Signal>,
+
+    /// The last run's report, as JSON
+    last_report: Mutex<Option<String>>,
+}
+
+impl AgentState {
+    /// State of an agent with a config file, or not, and where it
+    /// receives signals
+    pub fn new(host: &str, config: Option<PathBuf>) -> (Self, Receiver<Signal>) {
+        let (signals, receiver) = channel();
+        let state = AgentState {
+            status: Mutex::new(Status {
+                host: host.to_string(),
+                ..Status::default()
+            }),
+            progress: Arc::new(Progress::default()),
+            config,
+            signals,
+            last_report: Mutex::new(None),
+        };
+        (state, receiver)
+    }
+
+    /// Note when the next run is due, the schedule having changed
+    pub fn rescheduled(&self, next_run: SystemTime) {
+        self.status.lock().unwrap().next_run = Some(epoch_seconds(next_run));
+    }
+
+    /// Note that a run over the tracked files has started
+    pub fn run_started(&self, files_tracked: u64) {
+        let mut status = self.status.lock().unwrap();
+        status.scanning_since = Some(epoch_seconds(SystemTime::now()));
+        status.files_tracked = files_tracked;
+        status.next_run = None;
+    }
+
+    /// Note that a run has finished, with its report, and when the
+    /// next is due, if one is
+    pub fn run_finished(&self, run: &RunReport, next_run: Option<SystemTime>) {
+        let mut status = self.status.lock().unwrap();
+        status.scanning_since = None;
+        status.last_run = Some(LastRun {
+            finished: run.generated,
+            files_examined: run.summary.files_examined,
+            bytes_hashed: run.summary.bytes_hashed,
+            elapsed_seconds: run.summary.elapsed_seconds,
+            findings: run.items.len(),
+            items: run.summary.items.clone(),
+        });
+        status.runs += 1;
+        status
+            .recent
+            .extend(run.items.iter().map(|item| RecentFinding {
+                time: run.generated,
+                severity: item.severity().name().to_string(),
+                message: item.to_string(),
+                code: item.code().to_string(),
+            }));
+        let excess = status.recent.len().saturating_sub(RECENT);
+        status.recent.drain(..excess);
+        status.next_run = next_run.map(epoch_seconds);
+        *self.last_report.lock().unwrap() = serde_json::to_string(run).ok();
+    }
+
+    /// The last run's report, as JSON, if there has been a run
+    pub fn last_report(&self) -> Option<String> {
+        self.last_report.lock().unwrap().clone()
+    }
+
+    /// The status now, with the progress of any run under way
+    pub fn status(&self) -> Status {
+        let mut status = self.status.lock().unwrap().clone();
+        if status.scanning_since.is_some() {
+            status.files_examined = self.progress.files();
+            status.bytes_hashed = self.progress.bytes();
+        }
+        status
+    }
+}
+
+/// The answer to a command, as a line of JSON: the status, or an
+/// acknowledgement (`ok`) or refusal (`error`) with a message
+#[cfg(unix)]
+fn answer(state: &AgentState, command: &str) -> String {
+    let ok = |message: &str| serde_json::json!({ "ok": message }).to_string();
+    let refused = |message: String| serde_json::json!({ "error": message }).to_string();
+    let scanning = state.status().scanning_since.is_some();
+    let signal = |signal| {
+        // the agent only goes away (dropping the receiver) as it exits
+        let _ = state.signals.send(signal);
+    };
+
+    match clap::ValueEnum::from_str(command, false) {
+        Ok(ControlCommand::Status) => serde_json::to_string(&state.status()).unwrap(),
+        Ok(ControlCommand::TriggerScanNow) => {
+            signal(Signal::Scan);
+            match scanning {
+                true => ok("run queued, to start when the one under way is done"),
+                false => ok("run started"),
+            }
+        }
+        Ok(ControlCommand::ReloadConfig) => {
+            let Some(path) = &state.config else {
+                return refused("the agent has no config file (see --config)".to_string());
+            };
+            match AgentConfig::load(path) {
+                Ok(config) => {
+                    signal(Signal::Reload(config));
+                    ok("config reloaded")
+                }
+                Err(e) => refused(e.to_string()),
+            }
+        }
+        Ok(ControlCommand::Drain) => {
+            state.status.lock().unwrap().draining = true;
+            signal(Signal::Drain);
+            match scanning {
+                true => ok("draining: exiting when the run under way is done"),
+                false => ok("exiting"),
+            }
+        }
+        Err(_) => refused(format!("unknown command: {command}")),
+    }
+}
+
+/// Listen for commands on the socket, answering each client on a
+/// thread of its own
+///
+/// A socket left behind by an agent which has gone is replaced, but
+/// not one another agent is listening on. The socket is only open to
+/// the agent's user, and clients connecting as any other are refused.
+#[cfg(unix)]
+pub fn listen(path: &Path, state: Arc<AgentState>) -> Result<(), FimblError> {
+    use std::os::unix::{
+        fs::PermissionsExt,
+        net::{UnixListener, UnixStream},
+    };
+
+    let error = |e| FimblError::ControlSocketError(path.to_owned(), e);
+    if UnixStream::connect(path).is_ok() {
+        return Err(error(std::io::ErrorKind::AddrInUse.into()));
+    }
+    if path.exists() {
+        std::fs::remove_file(path).map_err(error)?;
+    }
+    let listener = UnixListener::bind(path).map_err(error)?;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600)).map_err(error)?;
+    let uid = unsafe { libc::geteuid() };
+
+    std::thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            let state = state.clone();
+            // a client slow to send its command holds up no other
+            std::thread::spawn(move || serve(&state, &stream, uid));
+        }
+    });
+    Ok(())
+}
+
+/// Answer a client's command, if it is the agent's user and sends one
+/// in time
+#[cfg(unix)]
+fn serve(state: &AgentState, stream: &std::os::unix::net::UnixStream, uid: libc::uid_t) {
+    let reply = match peer_uid(stream) {
+        Ok(peer) if peer == uid => {
+            let mut command = String::new();
+            let read = stream
+                .set_read_timeout(Some(CLIENT_TIMEOUT))
+                .and_then(|_| BufReader::new(stream).read_line(&mut command));
+            if read.is_err() {
+                return;
+            }
+            answer(state, command.trim())
+        }
+        _ => serde_json::json!({ "error": "only the agent's user may control it" }).to_string(),
+    };
+    // the client needn't wait for the answer
+    let _ = writeln!(&*stream, "{reply}");
+}
+
+/// User a client of the socket is connected as
+#[cfg(target_os = "linux")]
+fn peer_uid(stream: &std::os::unix::net::UnixStream) -> std::io::Result<libc::uid_t> {
+    use std::os::fd::AsRawFd;
+
+    let mut credentials = libc::ucred {
+        pid: 0,
+        uid: 0,
+        gid: 0,
+    };
+    let mut len = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+    // SAFETY: the descriptor is open and credentials (of len bytes)
+    // outlives the call
+    let status = unsafe {
+        libc::getsockopt(
+            stream.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_PEERCRED,
+            &mut credentials as *mut libc::ucred as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    match status {
+        0 => Ok(credentials.uid),
+        _ => Err(std::io::Error::last_os_error()),
+    }
+}
+
+/// User a client of the socket is connected as
+#[cfg(all(unix, not(target_os = "linux")))]
+fn peer_uid(stream: &std::os::unix::net::UnixStream) -> std::io::Result<libc::uid_t> {
+    use std::os::fd::AsRawFd;
+
+    let (mut uid, mut gid) = (0, 0);
+    // SAFETY: the descriptor is open and uid and gid outlive the call
+    match unsafe { libc::getpeereid(stream.as_raw_fd(), &mut uid, &mut gid) } {
+        0 => Ok(uid),
+        _ => Err(std::io::Error::last_os_error()),
+    }
+}
+
+#[cfg(not(unix))]
+pub fn listen(path: &Path, _state: Arc<AgentState>) -> Result<(), FimblError> {
+    Err(FimblError::ControlSocketError(
+        path.to_owned(),
+        std::io::ErrorKind::Unsupported.into(),
+    ))
+}
+
+/// Send a command to the agent listening on the socket, returning its
+/// answer
+#[cfg(unix)]
+pub fn request(path: &Path, command: &str) -> Result<String, FimblError> {
+    use std::os::unix::net::UnixStream;
+
+    let exchange = || {
+        let mut stream = UnixStream::connect(path)?;
+        writeln!(stream, "{command}")?;
+        let mut reply = String::new();
+        BufReader::new(&stream).read_line(&mut reply)?;
+        Ok(reply.trim_end().to_string())
+    };
+    exchange().map_err(|e| FimblError::ControlSocketError(path.to_owned(), e))
+}
+
+#[cfg(not(unix))]
+pub fn request(path: &Path, _command: &str) -> Result<String, FimblError> {
+    Err(FimblError::ControlSocketError(
+        path.to_owned(),
+        std::io::ErrorKind::Unsupported.into(),
+    ))
+}
+
+/// The status of the agent listening on the socket
+pub fn status(path: &Path) -> Result<Status, FimblError> {
+    let reply = request(path, "status")?;
+    serde_json::from_str(&reply).map_err(|_| FimblError::ControlReplyInvalid(reply))
+}
+
+/// Send a command to the agent listening on the socket, returning the
+/// status (as pretty JSON) or the acknowledgement
+pub fn command(path: &Path, command: ControlCommand) -> Result<String, FimblError> {
+    let reply = request(path, &command.name())?;
+    let invalid = || FimblError::ControlReplyInvalid(reply.clone());
+    let json: serde_json::Value = serde_json::from_str(&reply).map_err(|_| invalid())?;
+    match (&json["ok"], &json["error"]) {
+        (serde_json::Value::String(message), _) => Ok(message.clone()),
+        (_, serde_json::Value::String(message)) => Err(FimblError::ControlRefused(message.clone())),
+        _ if command == ControlCommand::Status => Ok(serde_json::to_string_pretty(&json).unwrap()),
+        _ => Err(invalid()),
+    }
+}
+
+#[cfg(all(test, unix))]
+pub mod tests {
+
+    use super::*;
+    use crate::report::{ReportItem, Summary};
+    use std::{path::PathBuf, sync::atomic::Ordering, time::Instant};
+
+    #[test]
+    fn test_status() {
+        let (state, _signals) = AgentState::new("web1", None);
+        state.run_started(10);
+        state.progress.files.store(3, Ordering::Relaxed);
+        let status = state.status();
+        assert!(status.scanning_since.is_some());
+        assert_eq!((status.files_examined, status.files_tracked), (3, 10));
+
+        let items: Vec<_> = (0..RECENT + 1)
+            .map(|_| ReportItem::FileMissing {
+                path: PathBuf::from("/etc/hosts"),
+            })
+            .collect();
+        let summary = Summary::new(Instant::now(), 10, 0, &items);
+        let next = SystemTime::now();
+        state.run_finished(&RunReport::new("web1", &items, &summary), Some(next));
+        let status = state.status();
+        assert_eq!(status.scanning_since, None);
+        assert_eq!(status.last_run.unwrap().findings, RECENT + 1);
+        assert_eq!(status.recent.len(), RECENT);
+        assert_eq!(status.next_run, Some(epoch_seconds(next)));
+
+        assert!(answer(&state, "bogus").contains("unknown command"));
+    }
+
+    #[test]
+    fn test_signals() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("agent.toml");
+        let (state, signals) = AgentState::new("web1", Some(path.clone()));
+
+        assert!(answer(&state, "trigger-scan-now").contains("run started"));
+        assert!(matches!(signals.try_recv(), Ok(Signal::Scan)));
+
+        std::fs::write(&path, "interval = \"soon\"\n").unwrap();
+        assert!(answer(&state, "reload-config").contains("error"));
+        assert!(signals.try_recv().is_err());
+        std::fs::write(&path, "interval = \"5m\"\n").unwrap();
+        assert!(answer(&state, "reload-config").contains("config reloaded"));
+        assert!(matches!(signals.try_recv(), Ok(Signal::Reload(_))));
+
+        state.run_started(10);
+        assert!(answer(&state, "drain").contains("when the run under way is done"));
+        assert!(matches!(signals.try_recv(), Ok(Signal::Drain)));
+        assert!(state.status().draining);
+
+        let (state, _signals) = AgentState::new("web1", None);
+        assert!(answer(&state, "reload-config").contains("no config file"));
+    }
+
+    #[test]
+    fn test_socket() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("agent.sock");
+        let (state, _signals) = AgentState::new("web1", None);
+        let state = Arc::new(state);
+        listen(&path, state.clone()).unwrap();
+        let mode = std::fs::metadata(&path).unwrap().permissions();
+        assert_eq!(
+            std::os::unix::fs::PermissionsExt::mode(&mode) & 0o777,
+            0o600
+        );
+        assert!(matches!(
+            listen(&path, state),
+            Err(FimblError::ControlSocketError(..))
+        ));
+        assert_eq!(status(&path).unwrap().host, "web1");
+        assert_eq!(
+            command(&path, ControlCommand::TriggerScanNow).unwrap(),
+            "run started"
+        );
+        assert!(matches!(
+            command(&path, ControlCommand::ReloadConfig),
+            Err(FimblError::ControlRefused(_))
+        ));
+
+        // a client sending nothing doesn't hold up others
+        let _idle = std::os::unix::net::UnixStream::connect(&path).unwrap();
+        assert_eq!(status(&path).unwrap().host, "web1");
+    }
+}
@@ -1,41 +1,257 @@
 //! Managing the state database
 
-use crate::{error::FimblError, fingerprint::Fingerprint, report::ReportItem};
+use crate::flatfile::FlatFile;
+use crate::storage::{Overlay, RemoteStore, Store};
+use crate::{
+    agent::{local_hostname, local_login, local_user, SigningKey},
+    backup::{read_backup, BackupWriter},
+    baseline::Baseline,
+    encryption::{generate_salt, DatabaseCipher, DatabaseKey, SALT_SIZE},
+    error::FimblError,
+    fingerprint::{CacheKey, Fingerprint, HashValue, NamedChange},
+    notify::NotifyState,
+    plan::Change,
+    report::{BlockChanges, ReportItem},
+};
+use aes_gcm::aead::{rand_core::RngCore, OsRng};
+use sha3::{Digest, Sha3_256};
 use sled::{self, Db, IVec};
 use std::{
+    collections::{BTreeMap, BTreeSet},
     path::{Path, PathBuf},
-    time::SystemTime,
+    sync::Mutex,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
+use tracing::{debug, info};
+use unicode_normalization::UnicodeNormalization;
+
+/// Name of the sled tree holding fingerprint records
+const FINGERPRINTS_TREE: &str = "fingerprints";
+
+/// Name of the sled tree holding logs
+const LOGS_TREE: &str = "logs";
+
+/// Name of the sled tree holding the time each file was last verified
+const COVERAGE_TREE: &str = "coverage";
+
+/// Name of the sled tree caching content hashes of files verified
+const HASH_CACHE_TREE: &str = "hash-cache";
+
+/// Name of the sled tree holding the records that each file's current
+/// record replaced
+const HISTORY_TREE: &str = "history";
+
+/// Name of the sled tree holding named snapshots of the fingerprint
+/// records
+const SNAPSHOTS_TREE: &str = "snapshots";
+
+/// Name of the sled tree holding accepted changes awaiting approval
+const PENDING_TREE: &str = "pending";
+
+/// Name of the sled tree holding the MAC of each fingerprint entry,
+/// keyed like the fingerprints
+const RECORD_MACS_TREE: &str = "record-macs";
+
+/// Name of the sled tree mapping other paths of files to the paths
+/// they are recorded by
+const ALIASES_TREE: &str = "aliases";
+
+/// Metadata key holding a check of the key fingerprint entries are
+/// MACed with, if any
+const RECORD_KEY_CHECK_KEY: &str = "record-key-check";
+
+/// Body MACed with the record key to check it
+const RECORD_KEY_CHECK: &[u8] = b"fimbl record key";
+
+/// Metadata key marking a database whose accepted changes must be
+/// approved
+const TWO_PHASE_ACCEPT_KEY: &str = "two-phase-accept";
+
+/// Metadata key holding a check of the key approvals must be made
+/// with, if any
+const APPROVAL_KEY_CHECK_KEY: &str = "approval-key-check";
+
+/// Metadata key marking a database whose records may only be removed,
+/// accepted or rolled back when forced
+const APPEND_ONLY_KEY: &str = "append-only";
+
+/// Metadata key holding the Unicode form paths are keyed in, if it has
+/// been set
+const UNICODE_PATHS_KEY: &str = "unicode-paths";
+
+/// Metadata key holding the path policy the database records paths by,
+/// once one has been used
+const PATH_POLICY_KEY: &str = "path-policy";
+
+/// Metadata key (in this database's host scope) holding the fractions
+/// of files recent scans found changed
+const CHANGE_VOLUME_KEY: &str = "change-volume";
+
+/// Metadata key (in this database's host scope) holding what has been
+/// notified and what is held back (encrypted if the database is)
+const NOTIFY_STATE_KEY: &str = "notifications";
+
+/// Number of recent scans whose change volumes are kept
+const CHANGE_VOLUME_HISTORY: usize = 30;
+
+/// Body signed with the approval key to check it
+const APPROVAL_KEY_CHECK: &[u8] = b"fimbl approval key";
+
+/// Number of previous records of each file kept by default
+pub const DEFAULT_HISTORY_LIMIT: usize = 10;
+
+/// Name of the sled tree holding database metadata
+const META_TREE: &str = "meta";
+
+/// Separator between host name and path in keys of host namespaced
+/// entries
+const HOST_SEPARATOR: &[u8] = b"\0";
+
+/// Metadata key marking an encrypted database, with a value that
+/// checks the key
+const ENCRYPTION_CHECK_KEY: &str = "encryption-check";
+
+/// Metadata key holding the salt the keys of an encrypted database are
+/// derived with
+const ENCRYPTION_SALT_KEY: &str = "encryption-salt";
+
+/// Metadata key holding the schema version of the database
+const SCHEMA_VERSION_KEY: &str = "schema-version";
+
+/// Upgrades the database in place from one schema version to the next
+type Migration = fn(&SystemDatabase) -> Result<(), FimblError>;
+
+/// Migrations, in order: the migration at index n upgrades a database
+/// at schema version n to version n + 1
+///
+/// Databases from before schema versioning are version 0. When a
+/// change to records or keys would break reading existing databases,
+/// append a migration that rewrites them; the schema version follows.
+const MIGRATIONS: &[Migration] = &[unversioned];
+
+/// Schema version of databases written by this fimbl
+pub const SCHEMA_VERSION: u32 = MIGRATIONS.len() as u32;
+
+/// Version 0 to 1: databases from before versioning are already
+/// readable, records having only gained defaulted fields
+fn unversioned(_database: &SystemDatabase) -> Result<(), FimblError> {
+    Ok(())
+}
 
 /// The SystemDatabase stores file fingerprint and logs
 ///
-/// Two sled trees `fingerprints` and `logs`.
+/// Two trees `fingerprints` and `logs` (plus `meta` for metadata),
+/// opened once when the database is opened. Usually these are sled
+/// trees in a local data directory but they may be held remotely.
+///
+/// If the database is encrypted, fingerprint records are keyed by an
+/// HMAC of the path and the path is stored, with the record, in an
+/// encrypted value.
 pub struct SystemDatabase {
-    /// Location of the data directory
+    /// Location of the data directory (or URL of a remote database)
     path: PathBuf,
 
-    /// The (open) sled database
-    db: Db,
+    /// The (open) sled database, if local
+    db: Option<Db>,
+
+    /// Fingerprint records keyed by path
+    fingerprints: Box<dyn Store>,
+
+    /// Log of actions taken, keyed by time
+    logs: Box<dyn Store>,
+
+    /// When each file was last verified, keyed like fingerprints
+    coverage: Box<dyn Store>,
+
+    /// Content hashes of files verified successfully, keyed like
+    /// fingerprints (never part of the baseline)
+    hash_cache: Box<dyn Store>,
+
+    /// Previous records of each file, oldest first, keyed like
+    /// fingerprints
+    history: Box<dyn Store>,
+
+    /// How many previous records of each file to keep
+    history_limit: usize,
+
+    /// Snapshots of the fingerprint records, keyed by name (with any
+    /// host prefix)
+    snapshots: Box<dyn Store>,
+
+    /// Accepted changes awaiting approval, keyed by token (with any
+    /// host prefix)
+    pending: Box<dyn Store>,
+
+    /// MACs of the fingerprint entries, keyed like them
+    record_macs: Box<dyn Store>,
+
+    /// Paths each recorded file was also given by, keyed like
+    /// fingerprints, with the path recorded
+    aliases: Box<dyn Store>,
+
+    /// How the paths of files given are recorded
+    path_policy: PathPolicy,
+
+    /// Unicode form paths are keyed in
+    unicode_paths: UnicodePaths,
+
+    /// Database metadata
+    meta: Box<dyn Store>,
+
+    /// Cipher for an encrypted database
+    cipher: Option<DatabaseCipher>,
+
+    /// Key the cipher was derived from, to derive it again for a
+    /// database restored in place
+    database_key: Option<DatabaseKey>,
+
+    /// Host whose baseline this is, in a database shared between hosts
+    host: Option<String>,
+
+    /// True if records may be removed, accepted or rolled back even in
+    /// an append-only database
+    force_unsafe: bool,
+
+    /// Command line to log changes with, if given
+    command_line: Option<Vec<String>>,
+
+    /// Key fingerprint entries are MACed with, if given
+    record_key: Option<SigningKey>,
+
+    /// True if fingerprint entries are MACed, so can't be written (or
+    /// read one by one) without the key
+    record_key_required: bool,
+
+    /// True if changes are only held in memory, and reported
+    dry_run: bool,
+
+    /// Changes to the records of files made on a dry run, in order
+    planned: Mutex<Vec<Change>>,
 }
 
-/// Convert path to key buffer
+/// Convert path to key buffer, in the Unicode form given
 ///
 /// For now, may fail with windows unicode paths
-fn path_as_key(path: &Path) -> Option<IVec> {
-    path.to_str().map(|s| IVec::from(s.as_bytes()))
+fn path_as_key(path: &Path, form: UnicodePaths) -> Option<IVec> {
+    let path = path.to_str()?;
+    match form {
+        UnicodePaths::Nfc => Some(IVec::from(path.nfc().collect::<String>().as_bytes())),
+        UnicodePaths::AsIs => Some(IVec::from(path.as_bytes())),
+    }
 }
 
-/// Convert key bytes to a PathBuf
+/// Convert key bytes (without host prefix) to a PathBuf
 fn path_from_key<K: AsRef<[u8]>>(key_bytes: K) -> Option<PathBuf> {
     std::str::from_utf8(key_bytes.as_ref())
         .ok()
-        .and_then(|s| PathBuf::try_from(s).ok())
+        .map(PathBuf::from)
 }
 
 /// DB contains facts about fingerprints, either that they are valid
 /// from a given time or that they are no longer verified from a given
 /// time (i.e. removed from the database).
 #[derive(Serialize, Deserialize, PartialEq, Eq, Debug)]
+#[allow(clippy::large_enum_variant)]
 enum FingerprintRecord {
     /// Fingerprint was valid at specified time
     Assert(SystemTime, Fingerprint),
@@ -52,6 +268,13 @@ impl FingerprintRecord {
         FingerprintRecord::Retract(SystemTime::now())
     }
 
+    fn time(&self) -> SystemTime {
+        match self {
+            FingerprintRecord::Assert(t, _) => *t,
+            FingerprintRecord::Retract(t) => *t,
+        }
+    }
+
     fn fingerprint(&self) -> Option<&Fingerprint> {
         match self {
             FingerprintRecord::Assert(_, fp) => Some(fp),
@@ -70,202 +293,4752 @@ impl FingerprintRecord {
     }
 }
 
-impl SystemDatabase {
-    /// Path of database directory
-    pub fn path(&self) -> &Path {
-        self.path.as_ref()
+/// The fingerprint records of a host's files at some time, to roll
+/// back to
+#[derive(Serialize, Deserialize)]
+struct Snapshot {
+    /// When the snapshot was made
+    created: SystemTime,
+
+    /// Every file's record, current or retracted
+    records: Vec<(PathBuf, FingerprintRecord)>,
+}
+
+/// An accepted change to a file, awaiting approval before it is
+/// recorded
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PendingChange {
+    /// The file changed
+    pub path: PathBuf,
+
+    /// Its fingerprint as accepted
+    pub fingerprint: Fingerprint,
+
+    /// True if only its metadata was accepted
+    pub metadata_only: bool,
+
+    /// When the change was accepted
+    pub requested: SystemTime,
+
+    /// Who accepted the change
+    pub user: String,
+
+    /// Where they accepted it
+    pub hostname: String,
+
+    /// Time of the record the change replaces, if any, so that a
+    /// change overtaken by another is not approved
+    pub replaces: Option<SystemTime>,
+
+    /// Where the file was tracked before, if the change is a rename
+    /// (whose record there is retracted on approval)
+    #[serde(default)]
+    pub renamed_from: Option<PathBuf>,
+}
+
+/// Something fimbl did to a file, recorded in the log
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone)]
+pub enum LogEvent {
+    /// Recorded permissions and / or ownership were put back
+    PermissionsRestored {
+        mode: Option<u32>,
+        uid: Option<u32>,
+        gid: Option<u32>,
+    },
+    /// The file's record was moved here from another path
+    Renamed { from: PathBuf },
+    /// The file was added
+    Added,
+    /// Changes to the file were accepted
+    Accepted,
+    /// Changes to the file's metadata only were accepted
+    MetadataAccepted,
+    /// The file was removed from the database
+    Removed,
+    /// The file's record was put back as it was in a snapshot
+    RolledBack { snapshot: String },
+    /// Changes to the file were accepted, pending approval
+    AcceptRequested { token: String },
+    /// Changes accepted by another user were approved
+    Approved { requested_by: String },
+    /// Changes pending approval were rejected
+    Rejected { token: String },
+}
+
+/// Names of the kinds of log event, as given by [`LogEvent::kind`]
+pub const LOG_EVENT_KINDS: &[&str] = &[
+    "add",
+    "accept",
+    "accept-metadata",
+    "accept-requested",
+    "approve",
+    "reject",
+    "remove",
+    "rename",
+    "rollback",
+    "remediate",
+];
+
+impl LogEvent {
+    /// Name of the kind of event, for filtering the log
+    pub fn kind(&self) -> &'static str {
+        match self {
+            LogEvent::PermissionsRestored { .. } => "remediate",
+            LogEvent::Renamed { .. } => "rename",
+            LogEvent::Added => "add",
+            LogEvent::Accepted => "accept",
+            LogEvent::MetadataAccepted => "accept-metadata",
+            LogEvent::Removed => "remove",
+            LogEvent::RolledBack { .. } => "rollback",
+            LogEvent::AcceptRequested { .. } => "accept-requested",
+            LogEvent::Approved { .. } => "approve",
+            LogEvent::Rejected { .. } => "reject",
+        }
     }
 
-    /// Open the database at the specified path, creating if required
-    pub fn open(db_dir: &Path) -> Result<Self, FimblError> {
-        let path = db_dir.to_owned();
-        let db = sled::open(db_dir)?;
+    /// True if the event made a new record of the file (or of its
+    /// removal)
+    pub fn records(&self) -> bool {
+        matches!(
+            self,
+            LogEvent::Renamed { .. }
+                | LogEvent::RolledBack { .. }
+                | LogEvent::Added
+                | LogEvent::Accepted
+                | LogEvent::MetadataAccepted
+                | LogEvent::Approved { .. }
+                | LogEvent::Removed
+        )
+    }
+}
 
-        Ok(SystemDatabase { path, db })
+impl std::fmt::Display for LogEvent {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LogEvent::PermissionsRestored { .. } => write!(f, "remediate"),
+            LogEvent::Renamed { from } => write!(f, "rename from {}", from.display()),
+            LogEvent::Added => write!(f, "add"),
+            LogEvent::Accepted => write!(f, "accept"),
+            LogEvent::MetadataAccepted => write!(f, "accept --metadata-only"),
+            LogEvent::Removed => write!(f, "remove"),
+            LogEvent::RolledBack { snapshot } => write!(f, "rollback to {snapshot}"),
+            LogEvent::AcceptRequested { token } => write!(f, "accept, pending approval {token}"),
+            LogEvent::Approved { requested_by } => write!(f, "approve accept by {requested_by}"),
+            LogEvent::Rejected { token } => write!(f, "reject pending approval {token}"),
+        }
     }
+}
 
-    /// Store fingerprint for new file in the database
-    ///
-    /// Pre-existing files are a report, unless tolerant flag is set
-    /// in which case the file is verified. This will not update an
-    /// existing incorrect fingerprint. For that, use accept.
-    pub fn store_new_file(
-        &mut self,
-        path: &Path,
-        fingerprint: &Fingerprint,
-        tolerate_existing: bool,
-    ) -> Result<Vec<ReportItem>, FimblError> {
-        let tree = self.db.open_tree("fingerprints")?;
-        let mut reports = vec![];
+/// A record of a file: when it was made, the fingerprint asserted
+/// (none, if the file was removed) and the log entry for the change
+/// that made it, if logged
+pub type AttributedRecord = (SystemTime, Option<Fingerprint>, Option<LogEntry>);
 
-        match path_as_key(path) {
-            Some(path_key) => match tree.get(&path_key)? {
-                Some(record_bytes) => {
-                    let record = FingerprintRecord::from_slice(record_bytes.as_ref())?;
+/// A file found to have had some contents: its path, when they were
+/// recorded and when (if at all) that record was replaced
+pub type Sighting = (PathBuf, SystemTime, Option<SystemTime>);
 
-                    match record.fingerprint() {
-                        Some(stored_fingerprint) if tolerate_existing => {
-                            if *stored_fingerprint != *fingerprint {
-                                reports.push(ReportItem::FileContentChanged {
-                                    path: path.to_path_buf(),
-                                })
-                            }
-                        }
-                        Some(_) => {
-                            reports.push(ReportItem::FileAlreadyTracked {
-                                path: path.to_path_buf(),
-                            });
-                        }
-                        None => {
-                            tree.insert(
-                                &path_key,
-                                FingerprintRecord::assert(fingerprint.clone()).to_vec(),
-                            )?;
-                        }
-                    }
-                }
-                None => {
-                    tree.insert(
-                        &path_key,
-                        FingerprintRecord::assert(fingerprint.clone()).to_vec(),
-                    )?;
-                }
-            },
-            None => {
-                reports.push(ReportItem::FileNameNotSupported {
-                    path: path.to_path_buf(),
-                });
-            }
+/// Tracked files with the same contents
+#[derive(PartialEq, Eq, Debug)]
+pub struct Duplicates {
+    /// Content hash they share
+    pub content_hash: HashValue,
+
+    /// Size of each, if recorded
+    pub size: Option<u64>,
+
+    /// Paths of the files, in order
+    pub paths: Vec<PathBuf>,
+}
+
+/// An entry in the log
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone)]
+pub struct LogEntry {
+    /// When it happened
+    pub time: SystemTime,
+
+    /// Host whose baseline the file belongs to, in a shared database
+    pub host: Option<String>,
+
+    /// File affected
+    pub path: PathBuf,
+
+    /// What happened
+    pub event: LogEvent,
+
+    /// Effective user fimbl ran as (absent in older entries)
+    #[serde(default)]
+    pub user: Option<String>,
+
+    /// Host fimbl ran on (absent in older entries)
+    #[serde(default)]
+    pub hostname: Option<String>,
+
+    /// True if done in an append-only database, by force
+    #[serde(default)]
+    pub forced: bool,
+
+    /// Name the user was logged in as, if known (and absent in older
+    /// entries)
+    #[serde(default)]
+    pub login: Option<String>,
+
+    /// Command line of the fimbl run, with secrets redacted (absent in
+    /// older entries)
+    #[serde(default)]
+    pub command_line: Option<Vec<String>>,
+}
+
+impl LogEntry {
+    /// Who did it, where and how: the event, users, host and command
+    /// line, for showing alongside a record
+    pub fn attribution(&self) -> String {
+        let mut attribution = format!(
+            "by {}{}, as {}",
+            self.event,
+            if self.forced { " (forced)" } else { "" },
+            self.user.as_deref().unwrap_or("unknown user")
+        );
+        if let Some(login) = self
+            .login
+            .as_ref()
+            .filter(|&login| Some(login) != self.user.as_ref())
+        {
+            attribution.push_str(&format!(" (logged in as {login})"));
+        }
+        attribution.push_str(&format!(
+            " on {}",
+            self.hostname.as_deref().unwrap_or("unknown host")
+        ));
+        if let Some(command_line) = &self.command_line {
+            let words: Vec<_> = command_line.iter().map(|word| shell_quote(word)).collect();
+            attribution.push_str(&format!(": {}", words.join(" ")));
         }
+        attribution
+    }
+}
 
-        Ok(reports)
+/// A command line word, quoted if need be to be pasted into a shell
+fn shell_quote(word: &str) -> String {
+    let plain = |c: char| c.is_ascii_alphanumeric() || "-_./=:,@%+".contains(c);
+    match !word.is_empty() && word.chars().all(plain) {
+        true => word.to_string(),
+        false => format!("'{}'", word.replace('\'', "'\\''")),
     }
+}
 
-    /// Store updated fingerprint for existing file in the database
-    ///
-    /// Missing files are a report, unless tolerant flag is set
-    /// in which case the file is added.
-    pub fn update_existing_file(
-        &mut self,
-        path: &Path,
-        fingerprint: &Fingerprint,
-        tolerate_untracked: bool,
-    ) -> Result<Vec<ReportItem>, FimblError> {
-        let tree = self.db.open_tree("fingerprints")?;
-        let mut reports = vec![];
+/// What is MACed for a fingerprint entry: its stored key and value,
+/// each prefixed by its length, so values can't be moved between keys
+fn record_mac_body(stored_key: &[u8], value: &[u8]) -> Vec<u8> {
+    let mut body = vec![];
+    for part in [stored_key, value] {
+        body.extend((part.len() as u64).to_be_bytes());
+        body.extend(part);
+    }
+    body
+}
 
-        if let Some(path_key) = path_as_key(path) {
-            let exists = tree.contains_key(&path_key)?;
+/// MAC of a fingerprint entry
+fn record_mac(key: &SigningKey, stored_key: &[u8], value: &[u8]) -> Vec<u8> {
+    key.tag(&record_mac_body(stored_key, value))
+}
 
-            if exists || tolerate_untracked {
-                tree.insert(
-                    &path_key,
-                    FingerprintRecord::assert(fingerprint.clone()).to_vec(),
-                )?;
-            } else {
-                reports.push(ReportItem::FileNotTracked {
-                    path: path.to_path_buf(),
-                })
-            }
-        } else {
-            reports.push(ReportItem::FileNameNotSupported {
-                path: path.to_path_buf(),
-            });
-        }
+/// Start of the log keys of entries made at a time: big-endian
+/// nanoseconds since the epoch, so entries sort in time order
+fn log_time(time: SystemTime) -> [u8; 16] {
+    time.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos())
+        .unwrap_or(0)
+        .to_be_bytes()
+}
 
-        Ok(reports)
+/// Log key for an entry made at a time: its time, then random bytes so
+/// that entries made in the same nanosecond (by other processes or
+/// hosts sharing the database) don't overwrite one another
+fn log_key(time: SystemTime) -> [u8; 24] {
+    let mut key = [0; 24];
+    key[..16].copy_from_slice(&log_time(time));
+    OsRng.fill_bytes(&mut key[16..]);
+    key
+}
+
+/// How far in the future a record's time may be (to allow for clock
+/// differences between hosts) before it is considered insane
+const FUTURE_TOLERANCE: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// True if a record time is plausible: after the epoch and not
+/// (much) in the future
+fn sane_time(time: SystemTime) -> bool {
+    time > UNIX_EPOCH && time <= SystemTime::now() + FUTURE_TOLERANCE
+}
+
+/// How a database is opened
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum Access {
+    /// Read and written, created if need be
+    #[default]
+    ReadWrite,
+    /// Read but never written: changes (a migration among them) are
+    /// held in memory and reported, and one not yet created is empty
+    DryRun,
+    /// Read as it is, not even migrated, for looking at it (as doctor
+    /// does): anything written is held in memory, and one not yet
+    /// created is empty
+    ReadOnly,
+}
+
+/// Which side wins when merging databases that disagree about a file
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum MergePreference {
+    /// The most recently recorded view of the file
+    Newest,
+    /// This database's view
+    Ours,
+    /// The other database's view
+    Theirs,
+}
+
+/// How the paths of files given are recorded
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum PathPolicy {
+    /// By their canonical paths, with symlinks resolved
+    Canonical,
+    /// As given (made absolute), so a file reached through a symlinked
+    /// directory is recorded by that name
+    AsGiven,
+    /// By their canonical paths, keeping the paths given as aliases
+    /// that find the same records
+    Aliased,
+}
+
+impl PathPolicy {
+    /// Name of the policy, as stored and given on the command line
+    pub fn name(&self) -> &'static str {
+        match self {
+            PathPolicy::Canonical => "canonical",
+            PathPolicy::AsGiven => "as-given",
+            PathPolicy::Aliased => "aliased",
+        }
     }
+}
 
-    /// Remove fingerprint for specified file
-    pub fn remove_existing_file(
-        &mut self,
-        path: &Path,
-        tolerate_untracked: bool,
-    ) -> Result<Vec<ReportItem>, FimblError> {
-        let tree = self.db.open_tree("fingerprints")?;
-        let mut reports = vec![];
+/// The Unicode form the paths of records are keyed in
+///
+/// macOS file systems hand out names in either composed (NFC) or
+/// decomposed (NFD) form, and treat both as the same file, so there a
+/// name must key the same record whichever form it comes in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum UnicodePaths {
+    /// Composed (NFC), names in either form finding the same record
+    Nfc,
+    /// As they are, names differing only in form being different files
+    AsIs,
+}
 
-        if let Some(path_key) = path_as_key(path) {
-            let exists = tree.contains_key(&path_key)?;
+impl UnicodePaths {
+    /// Form for new databases on this platform
+    fn native() -> Self {
+        match cfg!(target_os = "macos") {
+            true => UnicodePaths::Nfc,
+            false => UnicodePaths::AsIs,
+        }
+    }
 
-            if exists || tolerate_untracked {
-                tree.insert(&path_key, FingerprintRecord::retract().to_vec())?;
-            } else {
-                reports.push(ReportItem::FileNotTracked {
-                    path: path.to_path_buf(),
-                })
-            }
-        } else {
-            reports.push(ReportItem::FileNameNotSupported {
-                path: path.to_path_buf(),
-            });
+    /// Name of the form, as stored and given on the command line
+    fn name(&self) -> &'static str {
+        match self {
+            UnicodePaths::Nfc => "nfc",
+            UnicodePaths::AsIs => "as-is",
         }
+    }
+}
 
-        Ok(reports)
+/// The Unicode form a database keys paths in: as recorded in metadata,
+/// set (and recorded) for a new database to the form native to the
+/// platform, or as they are for databases from before forms were
+/// recorded
+fn unicode_paths(meta: &dyn Store, fingerprints: &dyn Store) -> Result<UnicodePaths, FimblError> {
+    match meta.get(UNICODE_PATHS_KEY.as_bytes())? {
+        Some(name) if name == b"nfc" => Ok(UnicodePaths::Nfc),
+        Some(_) => Ok(UnicodePaths::AsIs),
+        None if fingerprints.is_empty()? => {
+            let form = UnicodePaths::native();
+            meta.insert(UNICODE_PATHS_KEY.as_bytes(), form.name().into())?;
+            Ok(form)
+        }
+        None => Ok(UnicodePaths::AsIs),
     }
+}
 
-    /// List the currently tracked files and their fingerprints
-    pub fn list_fingerprint_assertions(&self) -> Result<Vec<(PathBuf, Fingerprint)>, FimblError> {
-        let tree = self.db.open_tree("fingerprints")?;
-        let mut fingerprints = vec![];
+/// The cipher of an encrypted database, derived from the key supplied
+/// and the database's salt, checking the key, or an empty database
+/// marked as encrypted (with a fresh salt) if a key is supplied for it
+fn check_encryption(
+    meta: &dyn Store,
+    fingerprints: &dyn Store,
+    key: Option<&DatabaseKey>,
+) -> Result<Option<DatabaseCipher>, FimblError> {
+    let salt = meta.get(ENCRYPTION_SALT_KEY.as_bytes())?;
+    let salt: Option<[u8; SALT_SIZE]> = salt.and_then(|salt| salt.try_into().ok());
 
-        for item in tree.into_iter().flatten() {
-            let (k, v) = item;
-            let path = path_from_key(k).unwrap();
-            let record = FingerprintRecord::from_slice(&v)?;
-            if let FingerprintRecord::Assert(_t, fingerprint) = record {
-                fingerprints.push((path, fingerprint));
+    match (meta.get(ENCRYPTION_CHECK_KEY.as_bytes())?, key) {
+        (None, None) => Ok(None),
+        (Some(_), None) => Err(FimblError::DatabaseEncrypted),
+        (Some(stored), Some(key)) => {
+            let cipher = salt.map(|salt| DatabaseCipher::new(key, &salt));
+            match cipher {
+                Some(cipher) if cipher.obscure_key(ENCRYPTION_CHECK_KEY.as_bytes()) == stored => {
+                    Ok(Some(cipher))
+                }
+                _ => Err(FimblError::WrongDatabaseKey),
             }
         }
-
-        Ok(fingerprints)
+        (None, Some(key)) if fingerprints.is_empty()? => {
+            let salt = generate_salt();
+            let cipher = DatabaseCipher::new(key, &salt);
+            meta.insert(ENCRYPTION_SALT_KEY.as_bytes(), salt.to_vec())?;
+            meta.insert(
+                ENCRYPTION_CHECK_KEY.as_bytes(),
+                cipher.obscure_key(ENCRYPTION_CHECK_KEY.as_bytes()),
+            )?;
+            Ok(Some(cipher))
+        }
+        (None, Some(_)) => Err(FimblError::DatabaseNotEncrypted),
     }
+}
 
-    /// Validate that the supplied fingerprint matches the one
-    /// recorded for the path
-    pub fn verify(
-        &mut self,
-        path: &Path,
-        fingerprint: &Fingerprint,
-    ) -> Result<Vec<ReportItem>, FimblError> {
-        let tree = self.db.open_tree("fingerprints")?;
-        let mut reports = vec![];
+/// A path beside the one given, its name with the suffix given
+fn beside(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_owned();
+    name.push(".");
+    name.push(suffix);
+    path.with_file_name(name)
+}
 
-        match path_as_key(path) {
-            Some(path_key) => match tree.get(&path_key)? {
-                Some(record_bytes) => {
-                    let record = FingerprintRecord::from_slice(record_bytes.as_ref())?;
+/// How often to retry opening a database locked by another process
+const LOCK_POLL_INTERVAL: Duration = Duration::from_millis(100);
 
-                    match record.fingerprint() {
-                        Some(stored_fingerprint) => {
-                            if *stored_fingerprint != *fingerprint {
-                                reports.push(ReportItem::FileContentChanged {
-                                    path: path.to_path_buf(),
-                                })
-                            }
-                        }
-                        None => {
-                            // fingerprint retracted
-                            reports.push(ReportItem::FileNotTracked {
-                                path: path.to_path_buf(),
-                            })
-                        }
-                    }
-                }
-                None => {
-                    // fingerprint never tracked
-                    reports.push(ReportItem::FileNotTracked {
-                        path: path.to_path_buf(),
-                    })
-                }
-            },
-            None => {
-                reports.push(ReportItem::FileNameNotSupported {
-                    path: path.to_path_buf(),
-                });
+/// Open a local sled database, waiting up to lock_wait for another
+/// process to release it
+pub fn open_sled(db_dir: &Path, lock_wait: Duration) -> Result<Db, FimblError> {
+    let deadline = Instant::now() + lock_wait;
+    loop {
+        match try_open_sled(db_dir) {
+            Err(FimblError::DatabaseBusy(..)) if Instant::now() < deadline => {
+                std::thread::sleep(LOCK_POLL_INTERVAL)
             }
+            result => return result,
+        }
+    }
+}
+
+/// Open a local sled database, explaining the common failures: the
+/// database being locked by another process or being inaccessible
+fn try_open_sled(db_dir: &Path) -> Result<Db, FimblError> {
+    sled::open(db_dir).map_err(|e| match e {
+        // sled reports a held lock as an "other" error
+        sled::Error::Io(e) if e.to_string().contains("could not acquire lock") => {
+            FimblError::DatabaseBusy(db_dir.to_owned(), lock_holder(db_dir))
         }
+        sled::Error::Io(e) => FimblError::DatabaseUnopenable(db_dir.to_owned(), e),
+        e => e.into(),
+    })
+}
 
-        Ok(reports)
+/// Process holding the sled database file open, found by looking
+/// through the open files of every process
+#[cfg(target_os = "linux")]
+fn lock_holder(db_dir: &Path) -> Option<u32> {
+    let db_file = db_dir.join("db").canonicalize().ok()?;
+    let me = std::process::id();
+    std::fs::read_dir("/proc")
+        .ok()?
+        .flatten()
+        .filter_map(|entry| entry.file_name().to_str()?.parse::<u32>().ok())
+        .filter(|pid| *pid != me)
+        .find(|pid| {
+            std::fs::read_dir(format!("/proc/{pid}/fd"))
+                .into_iter()
+                .flatten()
+                .flatten()
+                .any(|fd| std::fs::read_link(fd.path()).is_ok_and(|target| target == db_file))
+        })
+}
+
+/// Process holding the sled database file open (not known on this
+/// platform)
+#[cfg(not(target_os = "linux"))]
+fn lock_holder(_db_dir: &Path) -> Option<u32> {
+    None
+}
+
+/// Schema version recorded in metadata, if any
+fn schema_version(meta: &dyn Store) -> Result<Option<u32>, FimblError> {
+    match meta.get(SCHEMA_VERSION_KEY.as_bytes())? {
+        None => Ok(None),
+        Some(bytes) => {
+            let bytes = bytes
+                .try_into()
+                .map_err(|_| FimblError::SchemaVersionInvalid)?;
+            Ok(Some(u32::from_be_bytes(bytes)))
+        }
+    }
+}
+
+/// Compare a current fingerprint against the one recorded, reporting
+/// replacement of the file, removal of the immutable flag, symlinks
+/// pointing elsewhere, alternate data stream and extended attribute
+/// changes and size changes distinctly from other content changes
+pub fn compare_fingerprints(
+    path: &Path,
+    recorded: &Fingerprint,
+    current: &Fingerprint,
+) -> Vec<ReportItem> {
+    let mut reports = vec![];
+
+    if recorded.algorithm != current.algorithm {
+        reports.push(ReportItem::HashAlgorithmMismatch {
+            path: path.to_path_buf(),
+            recorded: recorded.algorithm,
+            current: current.algorithm,
+        });
+        return reports;
+    }
+
+    if !recorded.same_identity(current) {
+        reports.push(ReportItem::FileReplaced {
+            path: path.to_path_buf(),
+        });
+    }
+
+    if recorded.immutable_removed(current) {
+        reports.push(ReportItem::ImmutableFlagRemoved {
+            path: path.to_path_buf(),
+        });
+    } else if !recorded.flags_match(current) {
+        reports.push(ReportItem::FileFlagsChanged {
+            path: path.to_path_buf(),
+        });
+    }
+
+    if let Some((was, now)) = recorded.retargeted(current) {
+        reports.push(ReportItem::SymlinkTargetChanged {
+            path: path.to_path_buf(),
+            recorded: was.to_path_buf(),
+            current: now.to_path_buf(),
+        });
+    }
+
+    for (stream, change) in recorded.stream_changes(current) {
+        let path = path.to_path_buf();
+        reports.push(match change {
+            NamedChange::Added => ReportItem::StreamAdded { path, stream },
+            NamedChange::Changed => ReportItem::StreamChanged { path, stream },
+            NamedChange::Removed => ReportItem::StreamRemoved { path, stream },
+        });
+    }
+
+    for (name, change) in recorded.xattr_changes(current) {
+        let path = path.to_path_buf();
+        reports.push(match change {
+            NamedChange::Added => ReportItem::XattrAdded { path, name },
+            NamedChange::Changed => ReportItem::XattrChanged { path, name },
+            NamedChange::Removed => ReportItem::XattrRemoved { path, name },
+        });
+    }
+
+    let blocks = match (
+        recorded.chunk_size,
+        &recorded.block_hashes,
+        &current.block_hashes,
+    ) {
+        (Some(block_size), Some(recorded), Some(current)) => {
+            BlockChanges::between(block_size, recorded, current)
+        }
+        _ => None,
+    };
+    if let Some((recorded_entropy, current_entropy)) = recorded.entropy_jumped(current) {
+        reports.push(ReportItem::EntropyIncreased {
+            path: path.to_path_buf(),
+            recorded: recorded_entropy,
+            current: current_entropy,
+        });
+    }
+
+    // entries coming and going in a tracked directory are reported
+    // apart from any change to its attributes
+    let attributes;
+    let current = match recorded.directory
+        && current.directory
+        && recorded.content_hash != current.content_hash
+    {
+        true => {
+            reports.push(ReportItem::DirectoryEntriesChanged {
+                path: path.to_path_buf(),
+            });
+            attributes = Fingerprint {
+                content_hash: recorded.content_hash,
+                ..current.clone()
+            };
+            &attributes
+        }
+        false => current,
+    };
+
+    let fuzzy_distance = recorded.fuzzy_distance(current);
+    match (recorded.size, current.size) {
+        (Some(recorded_size), Some(current_size)) if recorded_size != current_size => {
+            reports.push(ReportItem::FileSizeChanged {
+                path: path.to_path_buf(),
+                recorded: recorded_size,
+                current: current_size,
+                recorded_hash: Some(hex::encode(recorded.content_hash)),
+                current_hash: Some(hex::encode(current.content_hash)),
+                blocks,
+                fuzzy_distance,
+            })
+        }
+        _ if !recorded.matches(current) => reports.push(ReportItem::FileContentChanged {
+            path: path.to_path_buf(),
+            recorded_hash: Some(hex::encode(recorded.content_hash)),
+            current_hash: Some(hex::encode(current.content_hash)),
+            blocks,
+            fuzzy_distance,
+        }),
+        _ => {}
+    }
+
+    reports
+}
+
+/// Compare only the contents of a file against the fingerprint
+/// recorded, for files (like those of volumes mounted in a pod) whose
+/// metadata belongs to where they are rather than to them
+///
+/// A symlink is compared by the contents of its target, without its
+/// size, and the entries of directories are not compared.
+pub fn compare_contents(
+    path: &Path,
+    recorded: &Fingerprint,
+    current: &Fingerprint,
+) -> Vec<ReportItem> {
+    if recorded.algorithm != current.algorithm {
+        return vec![ReportItem::HashAlgorithmMismatch {
+            path: path.to_path_buf(),
+            recorded: recorded.algorithm,
+            current: current.algorithm,
+        }];
+    }
+    if recorded.directory || recorded.same_contents(current) {
+        return vec![];
+    }
+
+    let recorded_hash = Some(hex::encode(recorded.content_hash));
+    let current_hash = Some(hex::encode(current.content_hash));
+    let fuzzy_distance = recorded.fuzzy_distance(current);
+    match (recorded.size, current.size) {
+        (Some(recorded_size), Some(current_size))
+            if recorded.symlink == current.symlink && recorded_size != current_size =>
+        {
+            vec![ReportItem::FileSizeChanged {
+                path: path.to_path_buf(),
+                recorded: recorded_size,
+                current: current_size,
+                recorded_hash,
+                current_hash,
+                blocks: None,
+                fuzzy_distance,
+            }]
+        }
+        _ => vec![ReportItem::FileContentChanged {
+            path: path.to_path_buf(),
+            recorded_hash,
+            current_hash,
+            blocks: None,
+            fuzzy_distance,
+        }],
+    }
+}
+
+impl SystemDatabase {
+    /// Path of database directory
+    pub fn path(&self) -> &Path {
+        self.path.as_ref()
+    }
+
+    /// Open the database at the specified path, creating if required
+    ///
+    /// A key must be supplied for an encrypted database. Supplying one
+    /// for a new (or empty) database encrypts it. If another
+    /// process has the database open, wait up to lock_wait for it.
+    ///
+    /// On a dry run, a database not yet created is not created (an
+    /// empty one is used), and nothing is written to one that is.
+    pub fn open(
+        db_dir: &Path,
+        database_key: Option<DatabaseKey>,
+        lock_wait: Duration,
+        access: Access,
+    ) -> Result<Self, FimblError> {
+        let db = match access {
+            Access::DryRun | Access::ReadOnly if !db_dir.exists() => {
+                sled::Config::new().temporary(true).open()?
+            }
+            _ => open_sled(db_dir, lock_wait)?,
+        };
+        Self::from_db_as(db_dir.to_owned(), db, database_key, access)
+    }
+
+    /// Open (or create) a database kept in an append-only flat file,
+    /// its lines signed with the record key if given (see `flatfile`)
+    pub fn open_flat(
+        path: &Path,
+        database_key: Option<DatabaseKey>,
+        key: Option<SigningKey>,
+        lock_wait: Duration,
+        access: Access,
+    ) -> Result<Self, FimblError> {
+        let writable = access == Access::ReadWrite;
+        let file = FlatFile::open(path, key, writable, lock_wait)?;
+        Self::from_stores(
+            path.to_owned(),
+            None,
+            file.tree(FINGERPRINTS_TREE),
+            file.tree(LOGS_TREE),
+            file.tree(COVERAGE_TREE),
+            file.tree(HASH_CACHE_TREE),
+            file.tree(HISTORY_TREE),
+            file.tree(SNAPSHOTS_TREE),
+            file.tree(PENDING_TREE),
+            file.tree(RECORD_MACS_TREE),
+            file.tree(ALIASES_TREE),
+            file.tree(META_TREE),
+            database_key,
+            access,
+        )
+    }
+
+    /// Open a database held by a remote fimbl server
+    ///
+    /// Encryption is applied before records leave this host, so an
+    /// encrypted remote database never sees paths or fingerprints.
+    pub fn open_remote(
+        url: &str,
+        token: Option<String>,
+        database_key: Option<DatabaseKey>,
+        access: Access,
+    ) -> Result<Self, FimblError> {
+        let agent = ureq::Agent::new();
+        let store = |tree| Box::new(RemoteStore::new(agent.clone(), url, tree, token.clone()));
+        Self::from_stores(
+            PathBuf::from(url),
+            None,
+            store(FINGERPRINTS_TREE),
+            store(LOGS_TREE),
+            store(COVERAGE_TREE),
+            store(HASH_CACHE_TREE),
+            store(HISTORY_TREE),
+            store(SNAPSHOTS_TREE),
+            store(PENDING_TREE),
+            store(RECORD_MACS_TREE),
+            store(ALIASES_TREE),
+            store(META_TREE),
+            database_key,
+            access,
+        )
+    }
+
+    /// A temporary in-memory database holding a baseline's assertions,
+    /// for verifying against
+    ///
+    /// The baseline may come from another host, so only its portable
+    /// attributes are kept (see `Fingerprint::portable`).
+    pub fn from_baseline(name: &str, baseline: &Baseline) -> Result<Self, FimblError> {
+        let db = sled::Config::new().temporary(true).open()?;
+        let database = Self::from_db(PathBuf::from(name), db, None)?;
+        for (path, fingerprint) in &baseline.files {
+            let Some(path_key) = database.plain_key(path) else {
+                continue;
+            };
+            database.put_record(&path_key, FingerprintRecord::assert(fingerprint.portable()))?;
+        }
+        Ok(database)
+    }
+
+    /// Export the currently tracked files as a baseline
+    pub fn baseline(&self) -> Result<Baseline, FimblError> {
+        let files = self.iter_assertions().collect::<Result<_, _>>()?;
+        Ok(Baseline::new(self.host.clone(), files))
+    }
+
+    /// Wrap an open sled database, opening the trees we use
+    fn from_db(
+        path: PathBuf,
+        db: Db,
+        database_key: Option<DatabaseKey>,
+    ) -> Result<Self, FimblError> {
+        Self::from_db_as(path, db, database_key, Access::ReadWrite)
+    }
+
+    /// Wrap an open sled database as above, with the access given
+    fn from_db_as(
+        path: PathBuf,
+        db: Db,
+        database_key: Option<DatabaseKey>,
+        access: Access,
+    ) -> Result<Self, FimblError> {
+        let fingerprints = Box::new(db.open_tree(FINGERPRINTS_TREE)?);
+        let logs = Box::new(db.open_tree(LOGS_TREE)?);
+        let coverage = Box::new(db.open_tree(COVERAGE_TREE)?);
+        let hash_cache = Box::new(db.open_tree(HASH_CACHE_TREE)?);
+        let history = Box::new(db.open_tree(HISTORY_TREE)?);
+        let snapshots = Box::new(db.open_tree(SNAPSHOTS_TREE)?);
+        let pending = Box::new(db.open_tree(PENDING_TREE)?);
+        let record_macs = Box::new(db.open_tree(RECORD_MACS_TREE)?);
+        let aliases = Box::new(db.open_tree(ALIASES_TREE)?);
+        let meta = Box::new(db.open_tree(META_TREE)?);
+        Self::from_stores(
+            path,
+            Some(db),
+            fingerprints,
+            logs,
+            coverage,
+            hash_cache,
+            history,
+            snapshots,
+            pending,
+            record_macs,
+            aliases,
+            meta,
+            database_key,
+            access,
+        )
+    }
+
+    /// Assemble a database from its stores, checking encryption
+    #[allow(clippy::too_many_arguments)]
+    fn from_stores(
+        path: PathBuf,
+        db: Option<Db>,
+        fingerprints: Box<dyn Store>,
+        logs: Box<dyn Store>,
+        coverage: Box<dyn Store>,
+        hash_cache: Box<dyn Store>,
+        history: Box<dyn Store>,
+        snapshots: Box<dyn Store>,
+        pending: Box<dyn Store>,
+        record_macs: Box<dyn Store>,
+        aliases: Box<dyn Store>,
+        meta: Box<dyn Store>,
+        database_key: Option<DatabaseKey>,
+        access: Access,
+    ) -> Result<Self, FimblError> {
+        let cipher = check_encryption(meta.as_ref(), fingerprints.as_ref(), database_key.as_ref())?;
+        let unicode_paths = unicode_paths(meta.as_ref(), fingerprints.as_ref())?;
+
+        let database = SystemDatabase {
+            path,
+            db,
+            fingerprints,
+            logs,
+            coverage,
+            hash_cache,
+            history,
+            history_limit: DEFAULT_HISTORY_LIMIT,
+            snapshots,
+            record_key_required: meta.get(RECORD_KEY_CHECK_KEY.as_bytes())?.is_some(),
+            pending,
+            record_macs,
+            aliases,
+            path_policy: PathPolicy::Canonical,
+            unicode_paths,
+            meta,
+            cipher,
+            database_key,
+            host: None,
+            force_unsafe: false,
+            command_line: None,
+            record_key: None,
+            dry_run: false,
+            planned: Mutex::new(vec![]),
+        };
+        // on a dry run, even the migration is held in memory
+        let database = match access {
+            Access::ReadWrite => database,
+            Access::DryRun => database.held_in_memory(true),
+            Access::ReadOnly => database.held_in_memory(false),
+        };
+        match access {
+            Access::ReadOnly => {
+                database.schema_version()?;
+            }
+            _ => database.migrate(database.meta.as_ref())?,
+        }
+        Ok(database)
+    }
+
+    /// Schema version of the database as it is, refusing one newer
+    /// than we know
+    ///
+    /// A new (empty) database is taken to be at the current version,
+    /// and one from before versioning at 0.
+    pub fn schema_version(&self) -> Result<u32, FimblError> {
+        let version = match schema_version(self.meta.as_ref())? {
+            Some(version) => version,
+            None if self.fingerprints.is_empty()? => SCHEMA_VERSION,
+            None => 0,
+        };
+        if version > SCHEMA_VERSION {
+            return Err(FimblError::DatabaseTooNew(version, SCHEMA_VERSION));
+        }
+        Ok(version)
+    }
+
+    /// Bring the database up to the current schema version, running
+    /// each outstanding migration and recording the version after it
+    ///
+    /// A new (empty) database starts at the current version. One with
+    /// a newer version than we know is refused rather than misread.
+    fn migrate(&self, meta: &dyn Store) -> Result<(), FimblError> {
+        let recorded = schema_version(meta)?;
+        let mut version = self.schema_version()?;
+
+        let set_version = |version: u32| {
+            meta.insert(
+                SCHEMA_VERSION_KEY.as_bytes(),
+                version.to_be_bytes().to_vec(),
+            )
+        };
+        for migration in &MIGRATIONS[version as usize..] {
+            info!(from = version, "migrating database");
+            migration(self)?;
+            version += 1;
+            set_version(version)?;
+        }
+        if recorded.is_none() {
+            set_version(version)?;
+        }
+        Ok(())
+    }
+
+    /// The local sled database, for operations not supported remotely
+    fn local_db(&self) -> Result<&Db, FimblError> {
+        self.db.as_ref().ok_or(FimblError::NotSupportedRemotely)
+    }
+
+    /// Compare the assertions in this database with another's,
+    /// reporting entries only in one or the other and entries whose
+    /// fingerprints disagree
+    ///
+    /// Device and inode are not compared as they naturally differ
+    /// between hosts.
+    pub fn diff(&self, other: &SystemDatabase) -> Result<Vec<ReportItem>, FimblError> {
+        let mut theirs = other
+            .iter_assertions()
+            .collect::<Result<BTreeMap<_, _>, _>>()?;
+        let mut reports = vec![];
+
+        for item in self.iter_assertions() {
+            let (path, ours) = item?;
+            match theirs.remove(&path) {
+                Some(fingerprint) if ours.content_hash != fingerprint.content_hash => {
+                    reports.push(ReportItem::DatabasesDisagree {
+                        path,
+                        content: true,
+                    })
+                }
+                Some(fingerprint)
+                    if !ours.matches(&fingerprint)
+                        || !ours.stream_changes(&fingerprint).is_empty()
+                        || !ours.xattr_changes(&fingerprint).is_empty()
+                        || !ours.flags_match(&fingerprint) =>
+                {
+                    reports.push(ReportItem::DatabasesDisagree {
+                        path,
+                        content: false,
+                    })
+                }
+                Some(_) => {}
+                None => reports.push(ReportItem::OnlyInDatabase {
+                    path,
+                    database: self.path.clone(),
+                }),
+            }
+        }
+
+        reports.extend(theirs.into_keys().map(|path| ReportItem::OnlyInDatabase {
+            path,
+            database: other.path.clone(),
+        }));
+
+        Ok(reports)
+    }
+
+    /// Write every tree of the database to a backup archive
+    ///
+    /// The hash cache is left out: it only holds for the files on
+    /// this host as they are now.
+    pub fn backup(&self, output: &Path) -> Result<(), FimblError> {
+        let db = self.local_db()?;
+        db.flush()?;
+
+        let mut writer = BackupWriter::create(output)?;
+        for name in db.tree_names() {
+            if name == db.name() || name == HASH_CACHE_TREE {
+                continue;
+            }
+            let entries = db.open_tree(&name)?.iter().map(|item| {
+                item.map(|(k, v)| (k.to_vec(), v.to_vec()))
+                    .map_err(FimblError::from)
+            });
+            writer.add_tree(&name, entries)?;
+        }
+        writer.finish()
+    }
+
+    /// Replace the entire contents of the database with a backup
+    ///
+    /// Refuses to overwrite existing fingerprints unless forced. The
+    /// backup is restored into a fresh database beside this one, which
+    /// is swapped in only once complete, so a failure part way leaves
+    /// the database as it was.
+    pub fn restore(&mut self, input: &Path, force: bool) -> Result<(), FimblError> {
+        self.local_db()?;
+        if !force && !self.fingerprints.is_empty()? {
+            return Err(FimblError::DatabaseNotEmpty);
+        }
+        // replacing every record, and the append-only and approval
+        // settings with them
+        self.check_may_weaken()?;
+        if self.two_phase_accept()? {
+            return Err(FimblError::RestoreNeedsApproval);
+        }
+
+        let staging = beside(&self.path, "restoring");
+        if staging.exists() {
+            std::fs::remove_dir_all(&staging)?;
+        }
+        if let Err(e) = self.restore_into(&staging, input) {
+            std::fs::remove_dir_all(&staging)?;
+            return Err(e);
+        }
+
+        // closed while swapped, by taking up an empty database
+        let database_key = self.database_key.take();
+        let closed = sled::Config::new().temporary(true).open()?;
+        self.take_stores(Self::from_db(self.path.clone(), closed, None)?);
+        let replaced = beside(&self.path, "replaced");
+        let swapped = std::fs::rename(&self.path, &replaced).and_then(|_| {
+            std::fs::rename(&staging, &self.path).or_else(|e| {
+                std::fs::rename(&replaced, &self.path)?;
+                Err(e)
+            })
+        });
+        // whichever is now in place is opened again
+        let db = try_open_sled(&self.path)?;
+        self.take_stores(Self::from_db(self.path.clone(), db, database_key)?);
+        swapped?;
+        std::fs::remove_dir_all(&replaced)?;
+        Ok(())
+    }
+
+    /// Write a backup to a new database, checking it can be opened with
+    /// the key given for this one
+    fn restore_into(&self, path: &Path, input: &Path) -> Result<(), FimblError> {
+        let db = try_open_sled(path)?;
+        read_backup(input, |name, entries| {
+            let tree = db.open_tree(name)?;
+            for entry in entries {
+                let (k, v) = entry?;
+                tree.insert(k, v)?;
+            }
+            Ok(())
+        })?;
+
+        let meta = db.open_tree(META_TREE)?;
+        check_encryption(
+            &meta,
+            &db.open_tree(FINGERPRINTS_TREE)?,
+            self.database_key.as_ref(),
+        )?;
+        match schema_version(&meta)? {
+            Some(version) if version > SCHEMA_VERSION => {
+                Err(FimblError::DatabaseTooNew(version, SCHEMA_VERSION))
+            }
+            _ => Ok(db.flush().map(|_| ())?),
+        }
+    }
+
+    /// Take the database and stores of another opened at the same path,
+    /// keeping the settings made of this one
+    fn take_stores(&mut self, other: SystemDatabase) {
+        self.db = other.db;
+        self.fingerprints = other.fingerprints;
+        self.logs = other.logs;
+        self.coverage = other.coverage;
+        self.hash_cache = other.hash_cache;
+        self.history = other.history;
+        self.snapshots = other.snapshots;
+        self.pending = other.pending;
+        self.record_macs = other.record_macs;
+        self.aliases = other.aliases;
+        self.unicode_paths = other.unicode_paths;
+        self.meta = other.meta;
+        self.cipher = other.cipher;
+        self.database_key = other.database_key;
+        self.record_key_required = other.record_key_required;
+    }
+
+    /// Restrict the database to the baseline of a single host
+    ///
+    /// Keys are prefixed with the host name so that one (central)
+    /// database can hold baselines for many hosts. Without a host,
+    /// only un-prefixed entries are visible.
+    pub fn with_host(mut self, host: Option<String>) -> Self {
+        self.host = host;
+        self
+    }
+
+    /// Allow records to be removed, accepted or rolled back even if
+    /// the database is append-only (logging each time this is needed)
+    pub fn with_force_unsafe(mut self, force: bool) -> Self {
+        self.force_unsafe = force;
+        self
+    }
+
+    /// MAC fingerprint entries with the key enrolled (see
+    /// `enrol_record_key`), and check them as they are read
+    ///
+    /// A key is refused for a database with none enrolled, rather than
+    /// taken on, so that removing the record of the key enrolled can't
+    /// let records be MACed again with another.
+    pub fn with_record_key(mut self, key: Option<SigningKey>) -> Result<Self, FimblError> {
+        let Some(key) = key else {
+            return Ok(self);
+        };
+        match self.meta.get(RECORD_KEY_CHECK_KEY.as_bytes())? {
+            Some(check) if !key.verify_tag(RECORD_KEY_CHECK, &check) => {
+                Err(FimblError::WrongRecordKey)
+            }
+            Some(_) => {
+                self.record_key = Some(key);
+                Ok(self)
+            }
+            None => Err(FimblError::RecordKeyNotEnrolled),
+        }
+    }
+
+    /// Enrol a key to MAC fingerprint entries with, which must then
+    /// always be given
+    ///
+    /// The entries already present are MACed with it: they are trusted
+    /// as they are.
+    pub fn enrol_record_key(&mut self, key: SigningKey) -> Result<(), FimblError> {
+        if self.record_key_required {
+            return Err(FimblError::RecordKeyEnrolled);
+        }
+        for item in self.fingerprints.scan_prefix(b"") {
+            let (stored_key, value) = item?;
+            self.record_macs
+                .insert(&stored_key, record_mac(&key, &stored_key, &value))?;
+        }
+        self.meta
+            .insert(RECORD_KEY_CHECK_KEY.as_bytes(), key.tag(RECORD_KEY_CHECK))?;
+        self.record_key = Some(key);
+        self.record_key_required = true;
+        Ok(())
+    }
+
+    /// Hold changes in memory rather than writing them, reporting what
+    /// would be changed if on a dry run
+    ///
+    /// Later reads in the run see the changes, so what is reported is
+    /// what a real run would do.
+    fn held_in_memory(self, dry_run: bool) -> Self {
+        let overlay = |store| -> Box<dyn Store> { Box::new(Overlay::new(store)) };
+        SystemDatabase {
+            fingerprints: overlay(self.fingerprints),
+            logs: overlay(self.logs),
+            coverage: overlay(self.coverage),
+            hash_cache: overlay(self.hash_cache),
+            history: overlay(self.history),
+            snapshots: overlay(self.snapshots),
+            pending: overlay(self.pending),
+            record_macs: overlay(self.record_macs),
+            aliases: overlay(self.aliases),
+            meta: overlay(self.meta),
+            dry_run,
+            ..self
+        }
+    }
+
+    /// Changes to the records of files made so far on a dry run, as a
+    /// plan would have them
+    pub fn planned(&self) -> Vec<Change> {
+        self.planned.lock().unwrap().clone()
+    }
+
+    /// Note a change made on a dry run, for a plan
+    fn plan(&self, change: Change) {
+        if self.dry_run {
+            self.planned.lock().unwrap().push(change);
+        }
+    }
+
+    /// Fingerprint recorded for a file now, if tracked
+    fn recorded_now(&self, path_key: &IVec) -> Result<Option<Fingerprint>, FimblError> {
+        let record = self.get_record(path_key)?;
+        Ok(record.and_then(|record| record.fingerprint().cloned()))
+    }
+
+    /// Record the paths of files given as the policy says (or the
+    /// default, if none is given), which is kept with the database the
+    /// first time: any policy given later must be the one kept
+    pub fn with_path_policy(
+        mut self,
+        policy: Option<PathPolicy>,
+        default: PathPolicy,
+    ) -> Result<Self, FimblError> {
+        let Some(kept) = self.meta.get(PATH_POLICY_KEY.as_bytes())? else {
+            let policy = policy.unwrap_or(default);
+            self.meta
+                .insert(PATH_POLICY_KEY.as_bytes(), policy.name().into())?;
+            self.path_policy = policy;
+            return Ok(self);
+        };
+        let kept_policy = [
+            PathPolicy::Canonical,
+            PathPolicy::AsGiven,
+            PathPolicy::Aliased,
+        ]
+        .into_iter()
+        .find(|known| known.name().as_bytes() == kept.as_slice());
+        match (kept_policy, policy) {
+            (Some(kept), None) => self.path_policy = kept,
+            (Some(kept), Some(policy)) if kept == policy => self.path_policy = kept,
+            (_, policy) => {
+                return Err(FimblError::PathPolicyMismatch(
+                    String::from_utf8_lossy(&kept).into_owned(),
+                    policy.unwrap_or(default).name().to_string(),
+                ))
+            }
+        }
+        Ok(self)
+    }
+
+    /// How the paths of files given are recorded
+    pub fn path_policy(&self) -> PathPolicy {
+        self.path_policy
+    }
+
+    /// Log changes as made by this command line (with any secrets
+    /// already redacted)
+    pub fn with_command_line(mut self, command_line: Vec<String>) -> Self {
+        self.command_line = Some(command_line);
+        self
+    }
+
+    /// Keep up to limit previous records of each file (none, if 0)
+    ///
+    /// Histories longer than the limit are trimmed as records are
+    /// next replaced.
+    pub fn with_history_limit(mut self, limit: usize) -> Self {
+        self.history_limit = limit;
+        self
+    }
+
+    /// Plain (unencrypted) key for the record of a path, including
+    /// any host prefix
+    ///
+    /// For now, may fail with windows unicode paths
+    fn plain_key(&self, path: &Path) -> Option<IVec> {
+        let key = path_as_key(path, self.unicode_paths)?;
+        match &self.host {
+            Some(host) => Some(IVec::from([host.as_bytes(), HOST_SEPARATOR, &key].concat())),
+            None => Some(key),
+        }
+    }
+
+    /// Path for a plain key, if the key is in this database's host
+    /// scope
+    fn path_in_scope(&self, plain_key: &[u8]) -> Option<Result<PathBuf, FimblError>> {
+        let path_bytes = match &self.host {
+            Some(host) => plain_key
+                .strip_prefix(host.as_bytes())
+                .and_then(|rest| rest.strip_prefix(HOST_SEPARATOR))?,
+            None if plain_key.contains(&HOST_SEPARATOR[0]) => return None,
+            None => plain_key,
+        };
+        Some(path_from_key(path_bytes).ok_or(FimblError::InvalidPathKey))
+    }
+
+    /// Key under which the record for a plain key is stored
+    fn stored_key(&self, plain_key: &[u8]) -> IVec {
+        match &self.cipher {
+            Some(cipher) => IVec::from(cipher.obscure_key(plain_key)),
+            None => IVec::from(plain_key),
+        }
+    }
+
+    /// Serialize a record for storage, encrypting it (along with its
+    /// plain key) if required
+    fn encode_record(&self, plain_key: &[u8], record: &FingerprintRecord) -> Vec<u8> {
+        match &self.cipher {
+            Some(cipher) => cipher.seal(
+                FINGERPRINTS_TREE,
+                &self.stored_key(plain_key),
+                &rmp_serde::to_vec(&(plain_key, record)).unwrap(),
+            ),
+            None => record.to_vec(),
+        }
+    }
+
+    /// Deserialize a stored entry into its plain key and record
+    fn decode_entry(
+        &self,
+        stored_key: &[u8],
+        value: &[u8],
+    ) -> Result<(Vec<u8>, FingerprintRecord), FimblError> {
+        match &self.cipher {
+            Some(cipher) => Ok(rmp_serde::from_slice(&cipher.open(
+                FINGERPRINTS_TREE,
+                stored_key,
+                value,
+            )?)?),
+            None => Ok((stored_key.to_vec(), FingerprintRecord::from_slice(value)?)),
+        }
+    }
+
+    /// Read the record stored for a plain key, checking its MAC if
+    /// entries are MACed
+    fn get_record(&self, plain_key: &IVec) -> Result<Option<FingerprintRecord>, FimblError> {
+        let stored_key = self.stored_key(plain_key);
+        match self.fingerprints.get(&stored_key)? {
+            Some(value) => {
+                if !self.mac_matches(&stored_key, &value)? {
+                    let path = self.path_in_scope(plain_key).and_then(Result::ok);
+                    return Err(FimblError::RecordTampered(path.unwrap_or_default()));
+                }
+                let decoded = self.decode_entry(&stored_key, &value)?;
+                Ok(Some(self.decoded_for(plain_key, decoded)?))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// What was decoded for a plain key, refusing it if it was stored
+    /// (encrypted along with its key) for another
+    fn decoded_for<T>(&self, plain_key: &[u8], decoded: (Vec<u8>, T)) -> Result<T, FimblError> {
+        if decoded.0 != plain_key {
+            let path = self.path_in_scope(plain_key).and_then(Result::ok);
+            return Err(FimblError::RecordTampered(path.unwrap_or_default()));
+        }
+        Ok(decoded.1)
+    }
+
+    /// True unless entries are MACed and this one's MAC is missing or
+    /// wrong
+    fn mac_matches(&self, stored_key: &[u8], value: &[u8]) -> Result<bool, FimblError> {
+        let key = match (&self.record_key, self.record_key_required) {
+            (Some(key), _) => key,
+            (None, true) => return Err(FimblError::RecordKeyRequired),
+            (None, false) => return Ok(true),
+        };
+        Ok(match self.record_macs.get(stored_key)? {
+            Some(mac) => key.verify_tag(&record_mac_body(stored_key, value), &mac),
+            None => false,
+        })
+    }
+
+    /// Reports that the record of a file was not written by fimbl with
+    /// the record key (when entries are MACed)
+    pub fn check_record(&self, path: &Path) -> Result<Vec<ReportItem>, FimblError> {
+        let Some(path_key) = self.plain_key(path) else {
+            return Ok(vec![]);
+        };
+        match self.get_record(&path_key) {
+            Err(FimblError::RecordTampered(_)) => Ok(vec![ReportItem::RecordTampered {
+                path: path.to_path_buf(),
+            }]),
+            Err(e) => Err(e),
+            Ok(_) => Ok(vec![]),
+        }
+    }
+
+    /// Serialize the previous records for a plain key for storage,
+    /// encrypting them (along with the key) if required
+    fn encode_history(&self, plain_key: &[u8], records: &[FingerprintRecord]) -> Vec<u8> {
+        match &self.cipher {
+            Some(cipher) => cipher.seal(
+                HISTORY_TREE,
+                &self.stored_key(plain_key),
+                &rmp_serde::to_vec(&(plain_key, records)).unwrap(),
+            ),
+            None => rmp_serde::to_vec(records).unwrap(),
+        }
+    }
+
+    /// Deserialize a stored history entry into its plain key and
+    /// previous records
+    fn decode_history(
+        &self,
+        stored_key: &[u8],
+        value: &[u8],
+    ) -> Result<(Vec<u8>, Vec<FingerprintRecord>), FimblError> {
+        match &self.cipher {
+            Some(cipher) => Ok(rmp_serde::from_slice(&cipher.open(
+                HISTORY_TREE,
+                stored_key,
+                value,
+            )?)?),
+            None => Ok((stored_key.to_vec(), rmp_serde::from_slice(value)?)),
+        }
+    }
+
+    /// Read the previous records for a plain key, oldest first
+    fn get_history(&self, plain_key: &IVec) -> Result<Vec<FingerprintRecord>, FimblError> {
+        let stored_key = self.stored_key(plain_key);
+        match self.history.get(&stored_key)? {
+            Some(value) => {
+                let decoded = self.decode_history(&stored_key, &value)?;
+                self.decoded_for(plain_key, decoded)
+            }
+            None => Ok(vec![]),
+        }
+    }
+
+    /// Keep a record being replaced in the history for its plain key,
+    /// dropping the oldest beyond the limit
+    fn archive_record(
+        &self,
+        plain_key: &IVec,
+        record: FingerprintRecord,
+    ) -> Result<(), FimblError> {
+        let stored_key = self.stored_key(plain_key);
+        if self.history_limit == 0 {
+            return self.history.remove(&stored_key);
+        }
+
+        let mut records = self.get_history(plain_key)?;
+        records.push(record);
+        let excess = records.len().saturating_sub(self.history_limit);
+        records.drain(..excess);
+        self.history
+            .insert(&stored_key, self.encode_history(plain_key, &records))
+    }
+
+    /// Store a record for a plain key, keeping the one it replaces in
+    /// the history
+    fn put_record(&self, plain_key: &IVec, record: FingerprintRecord) -> Result<(), FimblError> {
+        debug!(path = %String::from_utf8_lossy(plain_key), "recording fingerprint");
+        if let Some(previous) = self.get_record(plain_key)? {
+            self.archive_record(plain_key, previous)?;
+        }
+        let stored_key = self.stored_key(plain_key);
+        let value = self.encode_record(plain_key, &record);
+        match (&self.record_key, self.record_key_required) {
+            (Some(key), _) => self
+                .record_macs
+                .insert(&stored_key, record_mac(key, &stored_key, &value))?,
+            (None, true) => return Err(FimblError::RecordKeyRequired),
+            (None, false) => {}
+        }
+        self.fingerprints.insert(&stored_key, value)?;
+        Ok(())
+    }
+
+    /// Check every entry in the fingerprints, history and logs trees
+    /// (of all hosts), reporting those that are corrupt and, if
+    /// repairing, removing them
+    ///
+    /// Fingerprint records must decrypt and deserialize, be stored
+    /// under the key for their path, have a valid path and a sane
+    /// time, as must the previous records of a history entry. Log
+    /// entries must deserialize, have a sane time and be keyed by it.
+    pub fn fsck(&self, repair: bool) -> Result<Vec<ReportItem>, FimblError> {
+        let mut reports = vec![];
+
+        for item in self.fingerprints.scan_prefix(b"") {
+            let (stored_key, value) = item?;
+            let problem = match self.decode_entry(&stored_key, &value) {
+                Err(e) => Some(e.to_string()),
+                Ok((plain_key, record)) => {
+                    let path = match plain_key.iter().position(|b| *b == HOST_SEPARATOR[0]) {
+                        Some(separator) => &plain_key[separator + 1..],
+                        None => &plain_key[..],
+                    };
+                    if *self.stored_key(&plain_key) != *stored_key {
+                        Some("stored under the wrong key".to_string())
+                    } else if !self.mac_matches(&stored_key, &value)? {
+                        Some("not written with the record key".to_string())
+                    } else if !path_from_key(path).is_some_and(|p| p.is_absolute()) {
+                        Some("invalid path".to_string())
+                    } else if !sane_time(record.time()) {
+                        Some("implausible time".to_string())
+                    } else {
+                        None
+                    }
+                }
+            };
+            if let Some(problem) = problem {
+                reports.extend(self.corrupt_entry(
+                    FINGERPRINTS_TREE,
+                    &stored_key,
+                    problem,
+                    repair,
+                )?);
+            }
+        }
+
+        for item in self.history.scan_prefix(b"") {
+            let (stored_key, value) = item?;
+            let problem = match self.decode_history(&stored_key, &value) {
+                Err(e) => Some(e.to_string()),
+                Ok((plain_key, records)) => {
+                    let path = match plain_key.iter().position(|b| *b == HOST_SEPARATOR[0]) {
+                        Some(separator) => &plain_key[separator + 1..],
+                        None => &plain_key[..],
+                    };
+                    if *self.stored_key(&plain_key) != *stored_key {
+                        Some("stored under the wrong key".to_string())
+                    } else if !path_from_key(path).is_some_and(|p| p.is_absolute()) {
+                        Some("invalid path".to_string())
+                    } else if !records.iter().all(|record| sane_time(record.time())) {
+                        Some("implausible time".to_string())
+                    } else {
+                        None
+                    }
+                }
+            };
+            if let Some(problem) = problem {
+                reports.extend(self.corrupt_entry(HISTORY_TREE, &stored_key, problem, repair)?);
+            }
+        }
+
+        for item in self.record_macs.scan_prefix(b"") {
+            let (stored_key, _) = item?;
+            if self.fingerprints.get(&stored_key)?.is_none() {
+                let problem = "record removed other than by fimbl".to_string();
+                reports.extend(self.corrupt_entry(
+                    RECORD_MACS_TREE,
+                    &stored_key,
+                    problem,
+                    repair,
+                )?);
+            }
+        }
+
+        for item in self.logs.scan_prefix(b"") {
+            let (key, value) = item?;
+            let entry = match &self.cipher {
+                Some(cipher) => cipher.open(LOGS_TREE, &key, &value),
+                None => Ok(value),
+            }
+            .and_then(|bytes| Ok(rmp_serde::from_slice::<LogEntry>(&bytes)?));
+            let problem = match entry {
+                Err(e) => Some(e.to_string()),
+                Ok(entry) if !sane_time(entry.time) => Some("implausible time".to_string()),
+                Ok(entry) if !key.starts_with(&log_time(entry.time)) => {
+                    Some("stored under the wrong key".to_string())
+                }
+                Ok(_) => None,
+            };
+            if let Some(problem) = problem {
+                reports.extend(self.corrupt_entry(LOGS_TREE, &key, problem, repair)?);
+            }
+        }
+
+        Ok(reports)
+    }
+
+    /// Report (and if repairing, remove) a corrupt entry
+    fn corrupt_entry(
+        &self,
+        tree: &str,
+        key: &[u8],
+        problem: String,
+        repair: bool,
+    ) -> Result<Vec<ReportItem>, FimblError> {
+        let mut reports = vec![];
+        if repair && self.dry_run {
+            reports.push(ReportItem::WouldRemoveEntry {
+                tree: tree.to_string(),
+                key: hex::encode(key),
+            });
+        } else if repair {
+            match tree {
+                FINGERPRINTS_TREE => {
+                    self.fingerprints.remove(key)?;
+                    self.record_macs.remove(key)?
+                }
+                RECORD_MACS_TREE => self.record_macs.remove(key)?,
+                HISTORY_TREE => self.history.remove(key)?,
+                _ => self.logs.remove(key)?,
+            }
+        }
+        reports.insert(
+            0,
+            ReportItem::CorruptEntry {
+                tree: tree.to_string(),
+                key: hex::encode(key),
+                problem,
+                removed: repair && !self.dry_run,
+            },
+        );
+        Ok(reports)
+    }
+
+    /// Record an event in the log (encrypted if the database is)
+    pub fn append_log(&self, path: &Path, event: LogEvent) -> Result<(), FimblError> {
+        let entry = LogEntry {
+            time: SystemTime::now(),
+            host: self.host.clone(),
+            path: path.to_path_buf(),
+            event,
+            user: Some(local_user()),
+            hostname: Some(local_hostname()),
+            forced: self.force_unsafe && self.append_only()?,
+            login: local_login(),
+            command_line: self.command_line.clone(),
+        };
+        debug!(path = %entry.path.display(), event = %entry.event, "logging");
+        let key = log_key(entry.time);
+        let bytes = rmp_serde::to_vec(&entry).unwrap();
+        let value = match &self.cipher {
+            Some(cipher) => cipher.seal(LOGS_TREE, &key, &bytes),
+            None => bytes,
+        };
+        self.logs.insert(&key, value)
+    }
+
+    /// Iterate over the log entries for this host, oldest first
+    pub fn iter_log(&self) -> impl Iterator<Item = Result<LogEntry, FimblError>> + '_ {
+        self.iter_log_since(UNIX_EPOCH)
+    }
+
+    /// Iterate over the log entries for this host made at or after a
+    /// time, oldest first, without reading earlier ones
+    pub fn iter_log_since(
+        &self,
+        since: SystemTime,
+    ) -> impl Iterator<Item = Result<LogEntry, FimblError>> + '_ {
+        self.logs
+            .scan_from(&log_time(since))
+            .map(|item| {
+                let (key, value) = item?;
+                let bytes = match &self.cipher {
+                    Some(cipher) => cipher.open(LOGS_TREE, &key, &value)?,
+                    None => value,
+                };
+                Ok(rmp_serde::from_slice::<LogEntry>(&bytes)?)
+            })
+            .filter(|entry| match entry {
+                Ok(entry) => entry.host == self.host,
+                Err(_) => true,
+            })
+    }
+
+    /// Store fingerprint for new file in the database
+    ///
+    /// Pre-existing files are a report, unless tolerant flag is set
+    /// in which case the file is verified. This will not update an
+    /// existing incorrect fingerprint. For that, use accept.
+    pub fn store_new_file(
+        &mut self,
+        path: &Path,
+        fingerprint: &Fingerprint,
+        tolerate_existing: bool,
+    ) -> Result<Vec<ReportItem>, FimblError> {
+        let mut reports = vec![];
+
+        match self.plain_key(path) {
+            Some(path_key) => match self.get_record(&path_key)? {
+                Some(record) => match record.fingerprint() {
+                    Some(stored_fingerprint) if tolerate_existing => {
+                        reports.extend(compare_fingerprints(path, stored_fingerprint, fingerprint));
+                    }
+                    Some(_) => {
+                        reports.push(ReportItem::FileAlreadyTracked {
+                            path: path.to_path_buf(),
+                        });
+                    }
+                    None => reports.extend(self.store_record(
+                        path,
+                        &path_key,
+                        fingerprint,
+                        Some(record.time()),
+                    )?),
+                },
+                None => reports.extend(self.store_record(path, &path_key, fingerprint, None)?),
+            },
+            None => {
+                reports.push(ReportItem::FileNameNotSupported {
+                    path: path.to_path_buf(),
+                });
+            }
+        }
+
+        Ok(reports)
+    }
+
+    /// Record the fingerprint of a file being added (or, on a dry run,
+    /// report that it would be)
+    ///
+    /// A file tracked before, whose record was retracted at the time
+    /// given, is tracked again only once approved, if accepted changes
+    /// must be.
+    fn store_record(
+        &self,
+        path: &Path,
+        path_key: &IVec,
+        fingerprint: &Fingerprint,
+        replaces: Option<SystemTime>,
+    ) -> Result<Option<ReportItem>, FimblError> {
+        self.plan(Change::Track {
+            path: path.to_path_buf(),
+            fingerprint: fingerprint.clone(),
+        });
+        if replaces.is_some() && self.two_phase_accept()? {
+            let change = self.pending_change(path, fingerprint.clone(), false, replaces);
+            return self.hold_for_approval(change).map(Some);
+        }
+        self.put_record(path_key, FingerprintRecord::assert(fingerprint.clone()))?;
+        self.append_log(path, LogEvent::Added)?;
+        Ok(self.dry_run.then(|| ReportItem::WouldTrack {
+            path: path.to_path_buf(),
+        }))
+    }
+
+    /// Store updated fingerprint for existing file in the database
+    ///
+    /// Missing files are a report, unless tolerant flag is set
+    /// in which case the file is added. Any tags and note recorded are
+    /// kept. If accepted changes must be approved, the change is held
+    /// pending approval instead.
+    pub fn update_existing_file(
+        &mut self,
+        path: &Path,
+        fingerprint: &Fingerprint,
+        tolerate_untracked: bool,
+    ) -> Result<Vec<ReportItem>, FimblError> {
+        let mut reports = vec![];
+
+        if let Some(path_key) = self.plain_key(path) {
+            let record = self.get_record(&path_key)?;
+
+            if record.is_some() || tolerate_untracked {
+                let accepted = match record.as_ref().and_then(|record| record.fingerprint()) {
+                    Some(recorded) => fingerprint.annotated_like(recorded),
+                    None => fingerprint.clone(),
+                };
+                let replaces = record.map(|record| record.time());
+                reports.extend(self.accept_record(path, &path_key, accepted, false, replaces)?);
+            } else {
+                reports.push(ReportItem::FileNotTracked {
+                    path: path.to_path_buf(),
+                })
+            }
+        } else {
+            reports.push(ReportItem::FileNameNotSupported {
+                path: path.to_path_buf(),
+            });
+        }
+
+        Ok(reports)
+    }
+
+    /// Accept changes to the metadata (times, mode, ownership and so
+    /// on) of a tracked file, but only if its contents (including any
+    /// alternate data streams) are unchanged (and, if changes must be
+    /// approved, hold it pending approval)
+    pub fn update_existing_metadata(
+        &mut self,
+        path: &Path,
+        fingerprint: &Fingerprint,
+    ) -> Result<Vec<ReportItem>, FimblError> {
+        let Some(path_key) = self.plain_key(path) else {
+            return Ok(vec![ReportItem::FileNameNotSupported {
+                path: path.to_path_buf(),
+            }]);
+        };
+        let record = self.get_record(&path_key)?;
+        let Some(recorded) = record.as_ref().and_then(|record| record.fingerprint()) else {
+            return Ok(vec![ReportItem::FileNotTracked {
+                path: path.to_path_buf(),
+            }]);
+        };
+
+        if !recorded.same_contents(fingerprint) {
+            return Ok(vec![ReportItem::ContentChangeNotAccepted {
+                path: path.to_path_buf(),
+            }]);
+        }
+
+        let accepted = fingerprint.annotated_like(recorded);
+        let replaces = record.as_ref().map(|record| record.time());
+        self.accept_record(path, &path_key, accepted, true, replaces)
+    }
+
+    /// Record an accepted fingerprint or, if accepted changes must be
+    /// approved, hold it pending approval under a new token
+    fn accept_record(
+        &self,
+        path: &Path,
+        path_key: &IVec,
+        fingerprint: Fingerprint,
+        metadata_only: bool,
+        replaces: Option<SystemTime>,
+    ) -> Result<Vec<ReportItem>, FimblError> {
+        self.check_may_weaken()?;
+        match self.recorded_now(path_key)? {
+            Some(recorded) => self.plan(Change::Accept {
+                path: path.to_path_buf(),
+                recorded,
+                fingerprint: fingerprint.clone(),
+            }),
+            None => self.plan(Change::Track {
+                path: path.to_path_buf(),
+                fingerprint: fingerprint.clone(),
+            }),
+        }
+        if !self.two_phase_accept()? {
+            self.put_record(path_key, FingerprintRecord::assert(fingerprint))?;
+            let event = match metadata_only {
+                true => LogEvent::MetadataAccepted,
+                false => LogEvent::Accepted,
+            };
+            self.append_log(path, event)?;
+            return Ok(self
+                .dry_run
+                .then(|| ReportItem::WouldAccept {
+                    path: path.to_path_buf(),
+                    pending: false,
+                })
+                .into_iter()
+                .collect());
+        }
+
+        let change = self.pending_change(path, fingerprint, metadata_only, replaces);
+        Ok(vec![self.hold_for_approval(change)?])
+    }
+
+    /// A change to a file's record, requested now by this user
+    fn pending_change(
+        &self,
+        path: &Path,
+        fingerprint: Fingerprint,
+        metadata_only: bool,
+        replaces: Option<SystemTime>,
+    ) -> PendingChange {
+        PendingChange {
+            path: path.to_path_buf(),
+            fingerprint,
+            metadata_only,
+            requested: SystemTime::now(),
+            user: local_user(),
+            hostname: local_hostname(),
+            replaces,
+            renamed_from: None,
+        }
+    }
+
+    /// Hold a change pending approval under a new token (or, on a dry
+    /// run, report that it would be)
+    fn hold_for_approval(&self, change: PendingChange) -> Result<ReportItem, FimblError> {
+        let path = change.path.clone();
+        if self.dry_run {
+            return Ok(ReportItem::WouldAccept {
+                path,
+                pending: true,
+            });
+        }
+
+        let mut token = [0; 16];
+        OsRng.fill_bytes(&mut token);
+        let token = hex::encode(token);
+        let key = self.scoped_key(&token);
+        let bytes = rmp_serde::to_vec(&change).unwrap();
+        let value = match &self.cipher {
+            Some(cipher) => cipher.seal(PENDING_TREE, &key, &bytes),
+            None => bytes,
+        };
+        self.pending.insert(&key, value)?;
+        self.append_log(
+            &path,
+            LogEvent::AcceptRequested {
+                token: token.clone(),
+            },
+        )?;
+        Ok(ReportItem::AcceptPending { path, token })
+    }
+
+    /// True if records may only be removed, accepted, renamed, rolled
+    /// back or restored over when forced
+    pub fn append_only(&self) -> Result<bool, FimblError> {
+        Ok(self.meta.get(APPEND_ONLY_KEY.as_bytes())?.is_some())
+    }
+
+    /// Only allow records to be removed, accepted, renamed, rolled back
+    /// or restored over when forced, from now on (there is deliberately no way back)
+    pub fn require_append_only(&self) -> Result<(), FimblError> {
+        self.meta
+            .insert(APPEND_ONLY_KEY.as_bytes(), b"true".to_vec())
+    }
+
+    /// Key paths in the Unicode form given from now on, which can only
+    /// be changed while no file has been recorded
+    pub fn set_unicode_paths(&mut self, form: UnicodePaths) -> Result<(), FimblError> {
+        if form != self.unicode_paths && !self.fingerprints.is_empty()? {
+            return Err(FimblError::UnicodePathsTooLate);
+        }
+        self.meta
+            .insert(UNICODE_PATHS_KEY.as_bytes(), form.name().into())?;
+        self.unicode_paths = form;
+        Ok(())
+    }
+
+    /// Fail unless records may be removed, accepted, renamed, rolled
+    /// back or restored over: the database isn't append-only, or this
+    /// is forced
+    pub fn check_may_weaken(&self) -> Result<(), FimblError> {
+        match self.force_unsafe || !self.append_only()? {
+            true => Ok(()),
+            false => Err(FimblError::AppendOnly),
+        }
+    }
+
+    /// Fractions of the files examined which recent scans found
+    /// changed, oldest first
+    pub fn change_volumes(&self) -> Result<Vec<f64>, FimblError> {
+        let value = self.meta.get(&self.scoped_key(CHANGE_VOLUME_KEY))?;
+        Ok(value
+            .and_then(|bytes| rmp_serde::from_slice(&bytes).ok())
+            .unwrap_or_default())
+    }
+
+    /// Note the fraction of the files examined which a scan found
+    /// changed, forgetting the oldest beyond the most kept
+    pub fn record_change_volume(&self, fraction: f64) -> Result<(), FimblError> {
+        let mut volumes = self.change_volumes()?;
+        volumes.push(fraction);
+        let excess = volumes.len().saturating_sub(CHANGE_VOLUME_HISTORY);
+        volumes.drain(..excess);
+        self.meta.insert(
+            &self.scoped_key(CHANGE_VOLUME_KEY),
+            rmp_serde::to_vec(&volumes).unwrap(),
+        )
+    }
+
+    /// What has been notified and what is held back for the next batch
+    pub fn notify_state(&self) -> Result<NotifyState, FimblError> {
+        let key = self.scoped_key(NOTIFY_STATE_KEY);
+        let Some(value) = self.meta.get(&key)? else {
+            return Ok(NotifyState::default());
+        };
+        let bytes = match &self.cipher {
+            Some(cipher) => cipher.open(META_TREE, &key, &value)?,
+            None => value,
+        };
+        Ok(serde_json::from_slice(&bytes).unwrap_or_default())
+    }
+
+    /// Keep what has been notified and what is held back
+    pub fn set_notify_state(&self, state: &NotifyState) -> Result<(), FimblError> {
+        let key = self.scoped_key(NOTIFY_STATE_KEY);
+        let bytes = serde_json::to_vec(state).unwrap();
+        let value = match &self.cipher {
+            Some(cipher) => cipher.seal(META_TREE, &key, &bytes),
+            None => bytes,
+        };
+        self.meta.insert(&key, value)
+    }
+
+    /// True if accepted changes must be approved before they are
+    /// recorded
+    pub fn two_phase_accept(&self) -> Result<bool, FimblError> {
+        Ok(self.meta.get(TWO_PHASE_ACCEPT_KEY.as_bytes())?.is_some())
+    }
+
+    /// Require accepted changes to be approved from now on: with the
+    /// key, if one is given, or otherwise by a user other than the one
+    /// who accepted them
+    ///
+    /// There is deliberately no way back, and the approval key cannot
+    /// be replaced except by one who holds it.
+    pub fn require_approval(&self, key: Option<&SigningKey>) -> Result<(), FimblError> {
+        let check = self.meta.get(APPROVAL_KEY_CHECK_KEY.as_bytes())?;
+        if let (Some(check), Some(key)) = (&check, key) {
+            if !key.verify_tag(APPROVAL_KEY_CHECK, check) {
+                return Err(FimblError::WrongApprovalKey);
+            }
+        }
+        if let (None, Some(key)) = (&check, key) {
+            if self.two_phase_accept()? {
+                return Err(FimblError::ApprovalKeyTooLate);
+            }
+            self.meta.insert(
+                APPROVAL_KEY_CHECK_KEY.as_bytes(),
+                key.tag(APPROVAL_KEY_CHECK),
+            )?;
+        }
+        self.meta
+            .insert(TWO_PHASE_ACCEPT_KEY.as_bytes(), b"true".to_vec())
+    }
+
+    /// Read a pending change, if there is one with the token
+    fn get_pending(&self, token: &str) -> Result<Option<PendingChange>, FimblError> {
+        let key = self.scoped_key(token);
+        let Some(value) = self.pending.get(&key)? else {
+            return Ok(None);
+        };
+        let bytes = match &self.cipher {
+            Some(cipher) => cipher.open(PENDING_TREE, &key, &value)?,
+            None => value,
+        };
+        Ok(Some(rmp_serde::from_slice(&bytes)?))
+    }
+
+    /// Changes awaiting approval, with their tokens, oldest first
+    pub fn pending(&self) -> Result<Vec<(String, PendingChange)>, FimblError> {
+        let prefix = self.scoped_key("");
+        let mut pending = vec![];
+        for item in self.pending.scan_prefix(&prefix) {
+            let (key, _) = item?;
+            let token = &key[prefix.len()..];
+            if self.host.is_none() && token.contains(&HOST_SEPARATOR[0]) {
+                continue;
+            }
+            let token = String::from_utf8_lossy(token).into_owned();
+            if let Some(change) = self.get_pending(&token)? {
+                pending.push((token, change));
+            }
+        }
+        pending.sort_by_key(|(_, change)| change.requested);
+        Ok(pending)
+    }
+
+    /// Record the change pending under a token, returning the file
+    /// changed and, on a dry run, what would be
+    ///
+    /// Where an approval key is set, approval needs it; otherwise it
+    /// needs a user other than the one who accepted the change. A
+    /// change to a file whose record has since changed is refused.
+    pub fn approve(
+        &mut self,
+        token: &str,
+        key: Option<&SigningKey>,
+    ) -> Result<(PathBuf, Option<ReportItem>), FimblError> {
+        let change = self
+            .get_pending(token)?
+            .ok_or_else(|| FimblError::PendingNotFound(token.to_string()))?;
+        match (self.meta.get(APPROVAL_KEY_CHECK_KEY.as_bytes())?, key) {
+            (Some(check), Some(key)) if key.verify_tag(APPROVAL_KEY_CHECK, &check) => {}
+            (Some(_), Some(_)) => return Err(FimblError::WrongApprovalKey),
+            (Some(_), None) => return Err(FimblError::ApprovalKeyRequired),
+            (None, _) if local_user() == change.user => {
+                return Err(FimblError::SelfApproval(change.user))
+            }
+            (None, _) => {}
+        }
+
+        let path_key = self
+            .plain_key(&change.path)
+            .ok_or(FimblError::InvalidPathKey)?;
+        let record = self.get_record(&path_key)?;
+        if record.as_ref().map(|record| record.time()) != change.replaces {
+            return Err(FimblError::ApprovalStale(change.path));
+        }
+        // a rename also needs the file still tracked where it was
+        let from_key = match &change.renamed_from {
+            Some(from) => {
+                let from_key = self.plain_key(from).ok_or(FimblError::InvalidPathKey)?;
+                if self
+                    .get_record(&from_key)?
+                    .and_then(|r| r.fingerprint().cloned())
+                    .is_none()
+                {
+                    return Err(FimblError::ApprovalStale(from.clone()));
+                }
+                Some(from_key)
+            }
+            None => None,
+        };
+        let approved = match record.as_ref().and_then(|record| record.fingerprint()) {
+            Some(recorded) => change.fingerprint.annotated_like(recorded),
+            None => change.fingerprint,
+        };
+        self.put_record(&path_key, FingerprintRecord::assert(approved))?;
+        self.append_log(
+            &change.path,
+            LogEvent::Approved {
+                requested_by: change.user,
+            },
+        )?;
+        let would = match (change.renamed_from, from_key) {
+            (Some(from), Some(from_key)) => {
+                self.put_record(&from_key, FingerprintRecord::retract())?;
+                self.forget_untracked(&from_key)?;
+                self.append_log(&change.path, LogEvent::Renamed { from: from.clone() })?;
+                ReportItem::WouldRename {
+                    from,
+                    to: change.path.clone(),
+                }
+            }
+            _ => ReportItem::WouldAccept {
+                path: change.path.clone(),
+                pending: false,
+            },
+        };
+        self.pending.remove(&self.scoped_key(token))?;
+        Ok((change.path, self.dry_run.then_some(would)))
+    }
+
+    /// Discard the change pending under a token, returning the file
+    /// changed
+    pub fn reject(&self, token: &str) -> Result<PathBuf, FimblError> {
+        let change = self
+            .get_pending(token)?
+            .ok_or_else(|| FimblError::PendingNotFound(token.to_string()))?;
+        self.pending.remove(&self.scoped_key(token))?;
+        self.append_log(
+            &change.path,
+            LogEvent::Rejected {
+                token: token.to_string(),
+            },
+        )?;
+        Ok(change.path)
+    }
+
+    /// Set (or, given none, clear) the note on a tracked file, unless
+    /// it already has that note
+    pub fn annotate(
+        &mut self,
+        path: &Path,
+        note: Option<&str>,
+    ) -> Result<Vec<ReportItem>, FimblError> {
+        let Some(path_key) = self.plain_key(path) else {
+            return Ok(vec![ReportItem::FileNameNotSupported {
+                path: path.to_path_buf(),
+            }]);
+        };
+        let Some(FingerprintRecord::Assert(time, recorded)) = self.get_record(&path_key)? else {
+            return Ok(vec![ReportItem::FileNotTracked {
+                path: path.to_path_buf(),
+            }]);
+        };
+        if recorded.note.as_deref() == note {
+            return Ok(vec![]);
+        }
+
+        // the fingerprint is as valid as ever, from the same time
+        let annotated = Fingerprint {
+            note: note.map(str::to_string),
+            ..recorded
+        };
+        self.put_record(&path_key, FingerprintRecord::Assert(time, annotated))?;
+        Ok(self
+            .dry_run
+            .then(|| ReportItem::WouldAnnotate {
+                path: path.to_path_buf(),
+                note: note.map(str::to_string),
+            })
+            .into_iter()
+            .collect())
+    }
+
+    /// Move the record of a tracked file to the path it has moved to,
+    /// provided the file there has the recorded contents, and log the
+    /// move
+    ///
+    /// The record keeps the recorded metadata, so later changes to it
+    /// are still reported, but takes the device and inode of the file
+    /// now there (it may have moved between file systems). The old
+    /// path's history is kept, as by `remove`.
+    pub fn rename_file(
+        &mut self,
+        from: &Path,
+        to: &Path,
+        current: &Fingerprint,
+    ) -> Result<Vec<ReportItem>, FimblError> {
+        let (Some(from_key), Some(to_key)) = (self.plain_key(from), self.plain_key(to)) else {
+            return Ok(vec![ReportItem::FileNameNotSupported {
+                path: to.to_path_buf(),
+            }]);
+        };
+        let Some(recorded) = self.recorded_fingerprint(from)? else {
+            return Ok(vec![ReportItem::FileNotTracked {
+                path: from.to_path_buf(),
+            }]);
+        };
+        if self.recorded_fingerprint(to)?.is_some() {
+            return Ok(vec![ReportItem::FileAlreadyTracked {
+                path: to.to_path_buf(),
+            }]);
+        }
+        if !recorded.same_contents(current) {
+            return Ok(vec![ReportItem::RenameRefused {
+                from: from.to_path_buf(),
+                to: to.to_path_buf(),
+            }]);
+        }
+        // the record under the old name is retracted
+        self.check_may_weaken()?;
+
+        let moved = Fingerprint {
+            dev: current.dev,
+            ino: current.ino,
+            ..recorded
+        };
+        // onto a path tracked before, the rename is a change like any
+        // accept
+        if let Some(replaced) = self.get_record(&to_key)? {
+            if self.two_phase_accept()? {
+                let change = PendingChange {
+                    renamed_from: Some(from.to_path_buf()),
+                    ..self.pending_change(to, moved, false, Some(replaced.time()))
+                };
+                return Ok(vec![self.hold_for_approval(change)?]);
+            }
+        }
+        self.put_record(&to_key, FingerprintRecord::assert(moved))?;
+        self.put_record(&from_key, FingerprintRecord::retract())?;
+        self.forget_untracked(&from_key)?;
+        self.append_log(
+            to,
+            LogEvent::Renamed {
+                from: from.to_path_buf(),
+            },
+        )?;
+        Ok(self
+            .dry_run
+            .then(|| ReportItem::WouldRename {
+                from: from.to_path_buf(),
+                to: to.to_path_buf(),
+            })
+            .into_iter()
+            .collect())
+    }
+
+    /// Remove fingerprint for specified file
+    pub fn remove_existing_file(
+        &mut self,
+        path: &Path,
+        tolerate_untracked: bool,
+    ) -> Result<Vec<ReportItem>, FimblError> {
+        let mut reports = vec![];
+
+        if let Some(path_key) = self.plain_key(path) {
+            let exists = self.get_record(&path_key)?.is_some();
+
+            if exists || tolerate_untracked {
+                self.check_may_weaken()?;
+                if let Some(recorded) = self.recorded_now(&path_key)? {
+                    self.plan(Change::Retract {
+                        path: path.to_path_buf(),
+                        recorded,
+                    });
+                }
+                self.put_record(&path_key, FingerprintRecord::retract())?;
+                self.forget_untracked(&path_key)?;
+                self.append_log(path, LogEvent::Removed)?;
+                if self.dry_run {
+                    reports.push(ReportItem::WouldRetract {
+                        path: path.to_path_buf(),
+                    });
+                }
+            } else {
+                reports.push(ReportItem::FileNotTracked {
+                    path: path.to_path_buf(),
+                })
+            }
+        } else {
+            reports.push(ReportItem::FileNameNotSupported {
+                path: path.to_path_buf(),
+            });
+        }
+
+        Ok(reports)
+    }
+
+    /// Drop what is kept about a file no longer tracked besides its
+    /// records: its cached hash and when it was last verified
+    fn forget_untracked(&self, path_key: &IVec) -> Result<(), FimblError> {
+        let stored_key = self.stored_key(path_key);
+        self.hash_cache.remove(&stored_key)?;
+        self.coverage.remove(&stored_key)?;
+        Ok(())
+    }
+
+    /// Serialize the path an alias stands for, encrypting it (along
+    /// with the alias's plain key) if required
+    fn encode_alias(&self, plain_key: &[u8], path: &Path) -> Vec<u8> {
+        match &self.cipher {
+            Some(cipher) => cipher.seal(
+                ALIASES_TREE,
+                &self.stored_key(plain_key),
+                &rmp_serde::to_vec(&(plain_key, path)).unwrap(),
+            ),
+            None => rmp_serde::to_vec(path).unwrap(),
+        }
+    }
+
+    /// Deserialize a stored alias entry into its plain key and the path
+    /// it stands for
+    fn decode_alias(
+        &self,
+        stored_key: &[u8],
+        value: &[u8],
+    ) -> Result<(Vec<u8>, PathBuf), FimblError> {
+        match &self.cipher {
+            Some(cipher) => Ok(rmp_serde::from_slice(&cipher.open(
+                ALIASES_TREE,
+                stored_key,
+                value,
+            )?)?),
+            None => Ok((stored_key.to_vec(), rmp_serde::from_slice(value)?)),
+        }
+    }
+
+    /// Keep another path a file was given by, to find its record by
+    pub fn record_alias(&self, alias: &Path, path: &Path) -> Result<(), FimblError> {
+        let Some(plain_key) = self.plain_key(alias) else {
+            return Ok(());
+        };
+        let stored_key = self.stored_key(&plain_key);
+        self.aliases
+            .insert(&stored_key, self.encode_alias(&plain_key, path))
+    }
+
+    /// Forget an alias
+    pub fn remove_alias(&self, alias: &Path) -> Result<(), FimblError> {
+        match self.plain_key(alias) {
+            Some(plain_key) => self.aliases.remove(&self.stored_key(&plain_key)),
+            None => Ok(()),
+        }
+    }
+
+    /// The path recorded for a file an alias stands for, if it is one
+    pub fn alias(&self, alias: &Path) -> Result<Option<PathBuf>, FimblError> {
+        let Some(plain_key) = self.plain_key(alias) else {
+            return Ok(None);
+        };
+        let stored_key = self.stored_key(&plain_key);
+        match self.aliases.get(&stored_key)? {
+            Some(value) => {
+                let decoded = self.decode_alias(&stored_key, &value)?;
+                Ok(Some(self.decoded_for(&plain_key, decoded)?))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Every alias (in this database's host scope) with the path it
+    /// stands for
+    pub fn aliases(&self) -> Result<Vec<(PathBuf, PathBuf)>, FimblError> {
+        let mut aliases = vec![];
+        for item in self.aliases.scan_prefix(&[]) {
+            let (stored_key, value) = item?;
+            let (plain_key, path) = self.decode_alias(&stored_key, &value)?;
+            if let Some(alias) = self.path_in_scope(&plain_key) {
+                aliases.push((alias?, path));
+            }
+        }
+        aliases.sort();
+        Ok(aliases)
+    }
+
+    /// Iterate over the currently tracked files and their fingerprints
+    ///
+    /// Records are read lazily from the fingerprints tree so memory
+    /// use stays flat regardless of the number of files tracked.
+    pub fn iter_assertions(
+        &self,
+    ) -> impl Iterator<Item = Result<(PathBuf, Fingerprint), FimblError>> + '_ {
+        self.iter_records().filter_map(|item| match item {
+            Ok((path, FingerprintRecord::Assert(_, fingerprint))) => Some(Ok((path, fingerprint))),
+            Ok((_, FingerprintRecord::Retract(_))) => None,
+            Err(e) => Some(Err(e)),
+        })
+    }
+
+    /// Groups of tracked files sharing a content hash, largest first
+    ///
+    /// Symlinks (which share their target's hash), empty files,
+    /// special files and directories are left out.
+    pub fn duplicates(&self) -> Result<Vec<Duplicates>, FimblError> {
+        let mut by_hash: BTreeMap<HashValue, Duplicates> = BTreeMap::new();
+        for item in self.iter_assertions() {
+            let (path, fingerprint) = item?;
+            let contentless = fingerprint.symlink
+                || fingerprint.size == Some(0)
+                || fingerprint.special.is_some()
+                || fingerprint.directory;
+            if contentless {
+                continue;
+            }
+            by_hash
+                .entry(fingerprint.content_hash)
+                .or_insert_with(|| Duplicates {
+                    content_hash: fingerprint.content_hash,
+                    size: fingerprint.size,
+                    paths: vec![],
+                })
+                .paths
+                .push(path);
+        }
+
+        let mut duplicates: Vec<_> = by_hash
+            .into_values()
+            .filter(|group| group.paths.len() > 1)
+            .collect();
+        duplicates.sort_by_key(|group| std::cmp::Reverse(group.size));
+        Ok(duplicates)
+    }
+
+    /// Time of the newest fingerprint record, if any
+    pub fn latest_record_time(&self) -> Result<Option<SystemTime>, FimblError> {
+        let mut latest = None;
+        for item in self.iter_records() {
+            let (_, record) = item?;
+            latest = latest.max(Some(record.time()));
+        }
+        Ok(latest)
+    }
+
+    /// Digest over the entries of the fingerprints tree in this
+    /// database's host scope, as stored, in key order, to anchor the
+    /// database with
+    ///
+    /// The host, keys and values are each prefixed with their length,
+    /// so that entries can't be run together to forge a match.
+    pub fn root_hash(&self) -> Result<HashValue, FimblError> {
+        self.root_hash_where(|_| true)
+    }
+
+    /// Root hash of the records of the files given only, as attested
+    pub fn root_hash_of(&self, files: &BTreeSet<PathBuf>) -> Result<HashValue, FimblError> {
+        self.root_hash_where(|path| files.contains(path))
+    }
+
+    /// Root hash of the records of the files chosen
+    fn root_hash_where(&self, chosen: impl Fn(&Path) -> bool) -> Result<HashValue, FimblError> {
+        let mut hasher = Sha3_256::new();
+        let host = self.host.as_deref().unwrap_or_default();
+        hasher.update((host.len() as u64).to_be_bytes());
+        hasher.update(host);
+        for item in self.fingerprints.scan_prefix(b"") {
+            let (key, value) = item?;
+            let (plain_key, _) = self.decode_entry(&key, &value)?;
+            let Some(path) = self.path_in_scope(&plain_key).transpose()? else {
+                continue;
+            };
+            if !chosen(&path) {
+                continue;
+            }
+            for part in [&key, &value] {
+                hasher.update((part.len() as u64).to_be_bytes());
+                hasher.update(part);
+            }
+        }
+        Ok(hasher.finalize().into())
+    }
+
+    /// Key for the name of a snapshot or token of a pending change, in
+    /// this database's host scope
+    fn scoped_key(&self, name: &str) -> Vec<u8> {
+        match &self.host {
+            Some(host) => [host.as_bytes(), HOST_SEPARATOR, name.as_bytes()].concat(),
+            None => name.as_bytes().to_vec(),
+        }
+    }
+
+    /// Read a snapshot, if there is one of that name
+    fn get_snapshot(&self, name: &str) -> Result<Option<Snapshot>, FimblError> {
+        let key = self.scoped_key(name);
+        let Some(value) = self.snapshots.get(&key)? else {
+            return Ok(None);
+        };
+        let bytes = match &self.cipher {
+            Some(cipher) => cipher.open(SNAPSHOTS_TREE, &key, &value)?,
+            None => value,
+        };
+        Ok(Some(rmp_serde::from_slice(&bytes)?))
+    }
+
+    /// Label the current records of every file (encrypted if the
+    /// database is), so that they can be rolled back to
+    pub fn create_snapshot(&self, name: &str) -> Result<(), FimblError> {
+        let key = self.scoped_key(name);
+        if self.snapshots.get(&key)?.is_some() {
+            return Err(FimblError::SnapshotExists(name.to_string()));
+        }
+        let snapshot = Snapshot {
+            created: SystemTime::now(),
+            records: self.iter_records().collect::<Result<_, _>>()?,
+        };
+        let bytes = rmp_serde::to_vec(&snapshot).unwrap();
+        let value = match &self.cipher {
+            Some(cipher) => cipher.seal(SNAPSHOTS_TREE, &key, &bytes),
+            None => bytes,
+        };
+        self.snapshots.insert(&key, value)
+    }
+
+    /// Names of the snapshots, with when each was made and how many
+    /// files were tracked then
+    pub fn snapshots(&self) -> Result<Vec<(String, SystemTime, usize)>, FimblError> {
+        let prefix = self.scoped_key("");
+        let mut snapshots = vec![];
+        for item in self.snapshots.scan_prefix(&prefix) {
+            let (key, _) = item?;
+            let name = &key[prefix.len()..];
+            if self.host.is_none() && name.contains(&HOST_SEPARATOR[0]) {
+                continue;
+            }
+            let name = String::from_utf8_lossy(name).into_owned();
+            if let Some(snapshot) = self.get_snapshot(&name)? {
+                let tracked = snapshot
+                    .records
+                    .iter()
+                    .filter(|(_, record)| record.fingerprint().is_some())
+                    .count();
+                snapshots.push((name, snapshot.created, tracked));
+            }
+        }
+        Ok(snapshots)
+    }
+
+    /// Put every file's record back as it was in a snapshot, retracting
+    /// those of files tracked since, and return the files changed
+    ///
+    /// The records replaced go into the history as usual, so a
+    /// rollback can itself be undone. If accepted changes must be
+    /// approved, records put back over others are held pending
+    /// approval instead, and reported. On a dry run, every file that
+    /// would be changed is reported instead.
+    pub fn rollback(&mut self, name: &str) -> Result<(Vec<PathBuf>, Vec<ReportItem>), FimblError> {
+        self.check_may_weaken()?;
+        let snapshot = self
+            .get_snapshot(name)?
+            .ok_or_else(|| FimblError::SnapshotNotFound(name.to_string()))?;
+        let mut wanted: BTreeMap<_, _> = snapshot.records.into_iter().collect();
+        let current = self.iter_records().collect::<Result<BTreeMap<_, _>, _>>()?;
+        for (path, record) in &current {
+            if record.fingerprint().is_some() && !wanted.contains_key(path) {
+                wanted.insert(path.clone(), FingerprintRecord::retract());
+            }
+        }
+
+        let two_phase = self.two_phase_accept()?;
+        let (mut changed, mut held) = (vec![], vec![]);
+        for (path, record) in wanted {
+            let Some(path_key) = self.plain_key(&path) else {
+                continue;
+            };
+            let replaced = current.get(&path);
+            if replaced == Some(&record) {
+                continue;
+            }
+            if let (true, Some(replaced), Some(fingerprint)) =
+                (two_phase, replaced, record.fingerprint())
+            {
+                let change =
+                    self.pending_change(&path, fingerprint.clone(), false, Some(replaced.time()));
+                held.push(self.hold_for_approval(change)?);
+                continue;
+            }
+            let retracted = record.fingerprint().is_none();
+            self.put_record(&path_key, record)?;
+            if retracted {
+                self.forget_untracked(&path_key)?;
+            } else {
+                self.hash_cache.remove(&self.stored_key(&path_key))?;
+            }
+            self.append_log(
+                &path,
+                LogEvent::RolledBack {
+                    snapshot: name.to_string(),
+                },
+            )?;
+            match self.dry_run {
+                true => held.push(ReportItem::WouldRollBack {
+                    path,
+                    snapshot: name.to_string(),
+                }),
+                false => changed.push(path),
+            }
+        }
+        Ok((changed, held))
+    }
+
+    /// Delete a snapshot
+    pub fn delete_snapshot(&self, name: &str) -> Result<(), FimblError> {
+        let key = self.scoped_key(name);
+        if self.snapshots.get(&key)?.is_none() {
+            return Err(FimblError::SnapshotNotFound(name.to_string()));
+        }
+        self.snapshots.remove(&key)
+    }
+
+    /// Files whose contents have (or, in their history, had) a hash,
+    /// with when each such record was made and, if it has since been
+    /// replaced, when, in order of path and time
+    pub fn lookup_hash(&self, content_hash: &HashValue) -> Result<Vec<Sighting>, FimblError> {
+        let matching = |record: &FingerprintRecord| {
+            record
+                .fingerprint()
+                .is_some_and(|fingerprint| fingerprint.content_hash == *content_hash)
+        };
+
+        let mut found = vec![];
+        for item in self.iter_records() {
+            let (path, record) = item?;
+            if matching(&record) {
+                found.push((path, record.time(), None));
+            }
+        }
+        for item in self.history.scan_prefix(b"") {
+            let (stored_key, value) = item?;
+            let (plain_key, records) = self.decode_history(&stored_key, &value)?;
+            let Some(path) = self.path_in_scope(&plain_key) else {
+                continue;
+            };
+            let path = path?;
+            let current = self.get_record(&IVec::from(plain_key))?;
+            let replaced = records
+                .iter()
+                .skip(1)
+                .chain(current.as_ref())
+                .map(|record| Some(record.time()));
+            for (record, replaced) in records.iter().zip(replaced) {
+                if matching(record) {
+                    found.push((path.clone(), record.time(), replaced));
+                }
+            }
+        }
+        found.sort();
+        Ok(found)
+    }
+
+    /// The log entry for the change that made a file's current
+    /// record, if it was logged (records from before logging began
+    /// weren't)
+    pub fn recorded_by(&self, path: &Path) -> Result<Option<LogEntry>, FimblError> {
+        Ok(self
+            .attributed_history(path)?
+            .pop()
+            .and_then(|(_, _, entry)| entry))
+    }
+
+    /// The history of a file, with the log entry for the change that
+    /// made each record, if it was logged
+    ///
+    /// A record's entry is the first logged at or after it was made,
+    /// and before the next record was.
+    pub fn attributed_history(&self, path: &Path) -> Result<Vec<AttributedRecord>, FimblError> {
+        let records = self.history(path)?;
+        let mut entries = vec![];
+        for entry in self.iter_log() {
+            let entry = entry?;
+            if entry.path == path && entry.event.records() {
+                entries.push(entry);
+            }
+        }
+
+        let mut entries = entries.into_iter().peekable();
+        let mut attributed = vec![];
+        for (i, (time, fingerprint)) in records.iter().enumerate() {
+            let next = records.get(i + 1).map(|(next, _)| *next);
+            while entries.next_if(|entry| entry.time < *time).is_some() {}
+            let entry = entries.next_if(|entry| next.is_none_or(|next| entry.time < next));
+            attributed.push((*time, fingerprint.clone(), entry));
+        }
+        Ok(attributed)
+    }
+
+    /// The records of a file, oldest first and ending with the
+    /// current one: when each was made and the fingerprint asserted
+    /// (none, if the file was removed)
+    pub fn history(
+        &self,
+        path: &Path,
+    ) -> Result<Vec<(SystemTime, Option<Fingerprint>)>, FimblError> {
+        let Some(path_key) = self.plain_key(path) else {
+            return Ok(vec![]);
+        };
+        let mut records = self.get_history(&path_key)?;
+        records.extend(self.get_record(&path_key)?);
+        Ok(records
+            .into_iter()
+            .map(|record| match record {
+                FingerprintRecord::Assert(time, fingerprint) => (time, Some(fingerprint)),
+                FingerprintRecord::Retract(time) => (time, None),
+            })
+            .collect())
+    }
+
+    /// Iterate over the assertions for files under a directory (or
+    /// the file itself)
+    ///
+    /// Unless the database is encrypted (and so keyed by HMAC), only
+    /// the range of keys starting with the directory is scanned.
+    pub fn iter_assertions_under<'a>(
+        &'a self,
+        directory: &'a Path,
+    ) -> impl Iterator<Item = Result<(PathBuf, Fingerprint), FimblError>> + 'a {
+        self.iter_records_under(Some(directory))
+            .filter_map(|item| match item {
+                Ok((path, FingerprintRecord::Assert(_, fingerprint))) => {
+                    Some(Ok((path, fingerprint)))
+                }
+                Ok((_, FingerprintRecord::Retract(_))) => None,
+                Err(e) => Some(Err(e)),
+            })
+    }
+
+    /// Iterate over all records, assertions and retractions
+    fn iter_records(
+        &self,
+    ) -> impl Iterator<Item = Result<(PathBuf, FingerprintRecord), FimblError>> + '_ {
+        self.iter_records_under(None)
+    }
+
+    /// Iterate over records, for all files or only those under a
+    /// directory
+    fn iter_records_under<'a>(
+        &'a self,
+        directory: Option<&'a Path>,
+    ) -> impl Iterator<Item = Result<(PathBuf, FingerprintRecord), FimblError>> + 'a {
+        let prefix = match (&self.cipher, directory) {
+            (Some(_), _) => vec![],
+            (None, Some(directory)) => self
+                .plain_key(directory)
+                .map(|key| key.to_vec())
+                .unwrap_or_default(),
+            (None, None) => self.plain_key(Path::new("")).unwrap().to_vec(),
+        };
+
+        self.fingerprints
+            .scan_prefix(&prefix)
+            .filter_map(|item| {
+                let (plain_key, record) = match item.and_then(|(k, v)| self.decode_entry(&k, &v)) {
+                    Ok(entry) => entry,
+                    Err(e) => return Some(Err(e)),
+                };
+                self.path_in_scope(&plain_key)
+                    .map(|path| path.map(|path| (path, record)))
+            })
+            // the key range also holds siblings such as /etcetera
+            .filter(move |item| match (item, directory) {
+                (Ok((path, _)), Some(directory)) => path.starts_with(directory),
+                _ => true,
+            })
+    }
+
+    /// Merge the assertions of another database into this one
+    ///
+    /// Files not currently tracked here are simply added. Where both
+    /// databases have a view of a file and they disagree, the
+    /// preference decides which wins and a conflict is reported (and
+    /// theirs, if accepted changes must be approved, is held pending
+    /// approval).
+    pub fn merge(
+        &mut self,
+        other: &SystemDatabase,
+        prefer: MergePreference,
+    ) -> Result<Vec<ReportItem>, FimblError> {
+        let mut reports = vec![];
+
+        for item in other.iter_records() {
+            let (path, theirs) = item?;
+            let theirs_time = match theirs {
+                FingerprintRecord::Assert(time, _) => time,
+                FingerprintRecord::Retract(_) => continue,
+            };
+
+            let Some(path_key) = self.plain_key(&path) else {
+                reports.push(ReportItem::FileNameNotSupported { path });
+                continue;
+            };
+
+            let take_theirs = match self.get_record(&path_key)? {
+                None => true,
+                Some(ours) => {
+                    let agree = match (ours.fingerprint(), theirs.fingerprint()) {
+                        (Some(a), Some(b)) => compare_fingerprints(&path, a, b).is_empty(),
+                        _ => false,
+                    };
+                    if agree {
+                        false
+                    } else {
+                        let take = match prefer {
+                            MergePreference::Ours => false,
+                            MergePreference::Theirs => true,
+                            MergePreference::Newest => theirs_time > ours.time(),
+                        };
+                        reports.push(ReportItem::MergeConflict {
+                            path: path.clone(),
+                            took_theirs: take,
+                        });
+                        take
+                    }
+                }
+            };
+
+            match self.get_record(&path_key)? {
+                Some(ours) if take_theirs && self.two_phase_accept()? => {
+                    let fingerprint = theirs.fingerprint().cloned().unwrap();
+                    let change = self.pending_change(&path, fingerprint, false, Some(ours.time()));
+                    reports.push(self.hold_for_approval(change)?);
+                }
+                ours if take_theirs => {
+                    self.put_record(&path_key, theirs)?;
+                    if self.dry_run {
+                        let tracked = ours.as_ref().and_then(FingerprintRecord::fingerprint);
+                        reports.push(match tracked {
+                            Some(_) => ReportItem::WouldAccept {
+                                path,
+                                pending: false,
+                            },
+                            None => ReportItem::WouldTrack { path },
+                        });
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        Ok(reports)
+    }
+
+    /// Retrieve the currently asserted fingerprint for a path, if any
+    pub fn recorded_fingerprint(&self, path: &Path) -> Result<Option<Fingerprint>, FimblError> {
+        match self.plain_key(path) {
+            Some(path_key) => Ok(self
+                .get_record(&path_key)?
+                .and_then(|record| record.fingerprint().cloned())),
+            None => Ok(None),
+        }
+    }
+
+    /// When a file was last verified in a sample, if ever
+    ///
+    /// Coverage isn't MACed with the records, so a time in the future
+    /// (which would keep the file out of samples) is taken as never.
+    pub fn last_verified(&self, path: &Path) -> Result<Option<SystemTime>, FimblError> {
+        let Some(path_key) = self.plain_key(path) else {
+            return Ok(None);
+        };
+        let time = self
+            .coverage
+            .get(&self.stored_key(&path_key))?
+            .and_then(|bytes| bytes.try_into().ok())
+            .map(|bytes| UNIX_EPOCH + Duration::from_secs(u64::from_be_bytes(bytes)))
+            .filter(|time| *time <= SystemTime::now());
+        Ok(time)
+    }
+
+    /// Note that a file has been verified in a sample
+    pub fn record_verified(&self, path: &Path, time: SystemTime) -> Result<(), FimblError> {
+        if let Some(path_key) = self.plain_key(path) {
+            let secs = time
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs();
+            self.coverage
+                .insert(&self.stored_key(&path_key), secs.to_be_bytes().to_vec())?;
+        }
+        Ok(())
+    }
+
+    /// Cache key of a file when last verified successfully, provided
+    /// the content hash it had then is the one recorded
+    ///
+    /// The cache is only an optimisation: entries that can't be read
+    /// are ignored.
+    pub fn cached_key(
+        &self,
+        path: &Path,
+        recorded: &Fingerprint,
+    ) -> Result<Option<CacheKey>, FimblError> {
+        let Some(path_key) = self.plain_key(path) else {
+            return Ok(None);
+        };
+        let key = self.stored_key(&path_key);
+        let Some(value) = self.hash_cache.get(&key)? else {
+            return Ok(None);
+        };
+        let value = match &self.cipher {
+            Some(cipher) => match cipher.open(HASH_CACHE_TREE, &key, &value) {
+                Ok(value) => value,
+                Err(_) => return Ok(None),
+            },
+            None => value,
+        };
+        Ok(rmp_serde::from_slice::<(CacheKey, HashValue)>(&value)
+            .ok()
+            .filter(|(_, content_hash)| *content_hash == recorded.content_hash)
+            .map(|(key, _)| key))
+    }
+
+    /// Cache the content hash of a file just verified successfully,
+    /// so that fast mode can skip hashing it until it changes
+    pub fn cache_hash(&self, path: &Path, current: &Fingerprint) -> Result<(), FimblError> {
+        let (Some(path_key), Some(key)) = (self.plain_key(path), current.cache_key()) else {
+            return Ok(());
+        };
+        let stored_key = self.stored_key(&path_key);
+        let value = rmp_serde::to_vec(&(key, current.content_hash)).unwrap();
+        let value = match &self.cipher {
+            Some(cipher) => cipher.seal(HASH_CACHE_TREE, &stored_key, &value),
+            None => value,
+        };
+        self.hash_cache.insert(&stored_key, value)?;
+        Ok(())
+    }
+
+    /// Cheaply check the current size of a file against the size
+    /// recorded, without hashing
+    ///
+    /// Untracked files and those with no recorded size produce no
+    /// report; a full verify will deal with them.
+    pub fn verify_size(&self, path: &Path, size: u64) -> Result<Vec<ReportItem>, FimblError> {
+        let mut reports = vec![];
+
+        if let Some(fingerprint) = self.recorded_fingerprint(path)? {
+            match fingerprint.size {
+                Some(recorded) if recorded != size => reports.push(ReportItem::FileSizeChanged {
+                    path: path.to_path_buf(),
+                    recorded,
+                    current: size,
+                    recorded_hash: Some(hex::encode(fingerprint.content_hash)),
+                    current_hash: None,
+                    blocks: None,
+                    fuzzy_distance: None,
+                }),
+                _ => {}
+            }
+        }
+
+        Ok(reports)
+    }
+
+    /// Validate that the supplied fingerprint matches the one
+    /// recorded for the path
+    pub fn verify(
+        &self,
+        path: &Path,
+        fingerprint: &Fingerprint,
+    ) -> Result<Vec<ReportItem>, FimblError> {
+        let mut reports = vec![];
+
+        match self.plain_key(path) {
+            Some(path_key) => match self.get_record(&path_key)? {
+                Some(record) => {
+                    match record.fingerprint() {
+                        Some(stored_fingerprint) => {
+                            reports.extend(compare_fingerprints(
+                                path,
+                                stored_fingerprint,
+                                fingerprint,
+                            ));
+                        }
+                        None => {
+                            // fingerprint retracted
+                            reports.push(ReportItem::FileNotTracked {
+                                path: path.to_path_buf(),
+                            })
+                        }
+                    }
+                }
+                None => {
+                    // fingerprint never tracked
+                    reports.push(ReportItem::FileNotTracked {
+                        path: path.to_path_buf(),
+                    })
+                }
+            },
+            None => {
+                reports.push(ReportItem::FileNameNotSupported {
+                    path: path.to_path_buf(),
+                });
+            }
+        }
+
+        Ok(reports)
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+
+    use super::*;
+    use crate::fingerprint::{fingerprint_file, FileFlags, Fingerprinter, HashAlgorithm};
+    use crate::report::Severity;
+
+    /// An in-memory database that is discarded when dropped
+    pub fn temporary_database() -> SystemDatabase {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        SystemDatabase::from_db(PathBuf::from("<temporary>"), db, None).unwrap()
+    }
+
+    fn lorem_ipsum() -> PathBuf {
+        let mut d = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        d.push("resources/test/loremipsum.txt");
+        d
+    }
+
+    #[test]
+    fn test_iter_assertions_skips_retractions() {
+        let mut db = temporary_database();
+        let path = lorem_ipsum();
+        let fingerprint = fingerprint_file(&path).unwrap();
+        let other = PathBuf::from("/nonexistent/other.txt");
+
+        assert!(db
+            .store_new_file(&path, &fingerprint, false)
+            .unwrap()
+            .is_empty());
+        assert!(db
+            .store_new_file(&other, &fingerprint, false)
+            .unwrap()
+            .is_empty());
+        assert!(db.remove_existing_file(&other, false).unwrap().is_empty());
+
+        let assertions: Vec<_> = db.iter_assertions().collect::<Result<_, _>>().unwrap();
+        assert_eq!(assertions, vec![(path, fingerprint)]);
+    }
+
+    #[test]
+    fn test_verify_reports_size_change() {
+        let mut db = temporary_database();
+        let path = lorem_ipsum();
+        let fingerprint = fingerprint_file(&path).unwrap();
+        db.store_new_file(&path, &fingerprint, false).unwrap();
+
+        let mut grown = fingerprint.clone();
+        grown.size = grown.size.map(|s| s + 1);
+        grown.content_hash = [0; 32];
+        assert!(matches!(
+            db.verify(&path, &grown).unwrap().as_slice(),
+            [ReportItem::FileSizeChanged { .. }]
+        ));
+        assert!(matches!(
+            db.verify_size(&path, grown.size.unwrap())
+                .unwrap()
+                .as_slice(),
+            [ReportItem::FileSizeChanged { .. }]
+        ));
+        assert!(db
+            .verify_size(&path, fingerprint.size.unwrap())
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn test_verify_localizes_changed_blocks() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("log");
+        std::fs::write(&path, [b'a'; 1000]).unwrap();
+        let mut fingerprinter = Fingerprinter::new(None).with_block_hashes(Some(100));
+        let recorded = fingerprinter.fingerprint(&path).unwrap();
+        assert_eq!(recorded.block_hashes.as_ref().map(Vec::len), Some(10));
+
+        let mut db = temporary_database();
+        db.store_new_file(&path, &recorded, false).unwrap();
+
+        let mut contents = vec![b'a'; 1050];
+        contents[250] = b'b';
+        contents[420] = b'b';
+        std::fs::write(&path, contents).unwrap();
+        let current = Fingerprinter::new(None)
+            .fingerprint_like(&path, &recorded)
+            .unwrap();
+        let reports = db.verify(&path, &current).unwrap();
+        let [ReportItem::FileSizeChanged {
+            blocks: Some(blocks),
+            ..
+        }] = reports.as_slice()
+        else {
+            panic!("expected a size change with blocks");
+        };
+        assert_eq!(blocks.changed, vec![(3, 3), (5, 5), (11, 11)]);
+        assert_eq!(blocks.to_string(), "blocks 3, 5, 11 of 11 changed");
+    }
+
+    #[test]
+    fn test_verify_scores_similarity() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config");
+        let original: String = (0..200)
+            .map(|i| format!("setting_{i} = {}\n", i * 37 % 101))
+            .collect();
+        std::fs::write(&path, &original).unwrap();
+        let recorded = Fingerprinter::new(None)
+            .with_fuzzy_hashes(true)
+            .fingerprint(&path)
+            .unwrap();
+        assert!(recorded.fuzzy_hash.is_some());
+        let mut db = temporary_database();
+        db.store_new_file(&path, &recorded, false).unwrap();
+
+        let distance = |contents: &str| {
+            std::fs::write(&path, contents).unwrap();
+            let current = Fingerprinter::new(None)
+                .fingerprint_like(&path, &recorded)
+                .unwrap();
+            match db.verify(&path, &current).unwrap().as_slice() {
+                [ReportItem::FileContentChanged {
+                    fuzzy_distance: Some(distance),
+                    ..
+                }]
+                | [ReportItem::FileSizeChanged {
+                    fuzzy_distance: Some(distance),
+                    ..
+                }] => *distance,
+                _ => panic!("expected a change with a fuzzy distance"),
+            }
+        };
+        let edited = original.replace("setting_42 = 39", "setting_42 = 40");
+        let replaced: String = (0..300)
+            .map(|i| format!("<row id=\"{}\">{}</row>\n", i * 7919 % 1000, i))
+            .collect();
+        assert!(distance(&edited) <= 30);
+        assert!(distance(&replaced) > 100);
+    }
+
+    #[test]
+    fn test_verify_directory_entries() {
+        let dir = tempfile::tempdir().unwrap();
+        let cron = dir.path().join("cron.d");
+        std::fs::create_dir(&cron).unwrap();
+        std::fs::write(cron.join("backup"), "0 3 * * * root backup").unwrap();
+        let recorded = fingerprint_file(&cron).unwrap();
+        assert!(recorded.directory);
+        let mut db = temporary_database();
+        db.store_new_file(&cron, &recorded, false).unwrap();
+
+        // contents of entries aren't tracked, only their names
+        std::fs::write(cron.join("backup"), "0 4 * * * root backup").unwrap();
+        let reports = db.verify(&cron, &fingerprint_file(&cron).unwrap());
+        assert!(reports.unwrap().is_empty());
+
+        std::fs::write(cron.join("miner"), "* * * * * root miner").unwrap();
+        let reports = db.verify(&cron, &fingerprint_file(&cron).unwrap());
+        assert!(matches!(
+            reports.unwrap().as_slice(),
+            [ReportItem::DirectoryEntriesChanged { path }] if *path == cron
+        ));
+    }
+
+    #[test]
+    fn test_verify_reports_entropy_jump() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("report.txt");
+        std::fs::write(&path, "quarterly figures\n".repeat(1000)).unwrap();
+        let recorded = fingerprint_file(&path).unwrap();
+        let mut db = temporary_database();
+        db.store_new_file(&path, &recorded, false).unwrap();
+
+        // pseudo-random bytes, as encryption would leave
+        let mut state = 0x2545f491u32;
+        let encrypted: Vec<u8> = (0..recorded.size.unwrap())
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                state as u8
+            })
+            .collect();
+        std::fs::write(&path, encrypted).unwrap();
+        let reports = db.verify(&path, &fingerprint_file(&path).unwrap()).unwrap();
+        assert!(matches!(
+            reports.as_slice(),
+            [
+                ReportItem::EntropyIncreased { .. },
+                ReportItem::FileContentChanged { .. }
+            ]
+        ));
+
+        let many = vec![reports[0].clone(); 10];
+        assert!(matches!(
+            crate::report::mass_encryption(&many),
+            Some(ReportItem::MassEncryptionSuspected { files: 10 })
+        ));
+        assert!(crate::report::mass_encryption(&many[1..]).is_none());
+    }
+
+    #[test]
+    fn test_notify_state() {
+        let db = temporary_database();
+        assert!(db.notify_state().unwrap().held.is_empty());
+        let mut state = crate::notify::NotifyState::default();
+        let findings = vec![ReportItem::FileMissing {
+            path: PathBuf::from("/etc/hosts"),
+        }];
+        state.admit(&findings, 1000, Duration::from_secs(60));
+        db.set_notify_state(&state).unwrap();
+        let held = db.notify_state().unwrap().held;
+        assert_eq!((held.len(), held[0].found), (1, 1000));
+    }
+
+    #[test]
+    fn test_change_volume() {
+        use crate::report::{anomalous_change_volume, CHANGE_VOLUME_SCANS};
+
+        let db = temporary_database();
+        for _ in 0..CHANGE_VOLUME_HISTORY + 1 {
+            db.record_change_volume(0.01).unwrap();
+        }
+        let earlier = db.change_volumes().unwrap();
+        assert_eq!(earlier, vec![0.01; CHANGE_VOLUME_HISTORY]);
+
+        let changed = |files: usize| -> Vec<ReportItem> {
+            (0..files)
+                .map(|i| ReportItem::FileMissing {
+                    path: PathBuf::from(format!("/srv/{i}")),
+                })
+                .collect()
+        };
+        assert!(matches!(
+            anomalous_change_volume(&changed(60), 1000, &earlier),
+            Some(ReportItem::AnomalousChangeVolume {
+                files: 60,
+                examined: 1000,
+                ..
+            })
+        ));
+        // at most 5% is never anomalous, nor are a handful of files
+        assert!(anomalous_change_volume(&changed(50), 1000, &earlier).is_none());
+        assert!(anomalous_change_volume(&changed(9), 10, &earlier).is_none());
+        // nor is anything without enough earlier scans
+        let few = &earlier[..CHANGE_VOLUME_SCANS - 1];
+        assert!(anomalous_change_volume(&changed(600), 1000, few).is_none());
+        // nor is steady churn
+        let churn = vec![0.4, 0.5, 0.45, 0.5, 0.4];
+        assert!(anomalous_change_volume(&changed(600), 1000, &churn).is_none());
+    }
+
+    #[test]
+    fn test_accept_metadata_only() {
+        let mut db = temporary_database();
+        let path = lorem_ipsum();
+        let fingerprint = fingerprint_file(&path).unwrap();
+        db.store_new_file(&path, &fingerprint, false).unwrap();
+
+        let mut touched = fingerprint.clone();
+        touched.modified = Some(UNIX_EPOCH);
+        touched.unix_mode = Some(0o100600);
+        assert!(!db.verify(&path, &touched).unwrap().is_empty());
+        assert!(db
+            .update_existing_metadata(&path, &touched)
+            .unwrap()
+            .is_empty());
+        assert!(db.verify(&path, &touched).unwrap().is_empty());
+
+        let mut altered = touched.clone();
+        altered.content_hash = [0; 32];
+        assert!(matches!(
+            db.update_existing_metadata(&path, &altered)
+                .unwrap()
+                .as_slice(),
+            [ReportItem::ContentChangeNotAccepted { .. }]
+        ));
+        assert!(!db.verify(&path, &altered).unwrap().is_empty());
+
+        assert!(matches!(
+            db.update_existing_metadata(Path::new("/nonexistent"), &touched)
+                .unwrap()
+                .as_slice(),
+            [ReportItem::FileNotTracked { .. }]
+        ));
+    }
+
+    #[test]
+    fn test_verify_ignores_unrecorded_size() {
+        let mut db = temporary_database();
+        let path = lorem_ipsum();
+        let current = fingerprint_file(&path).unwrap();
+        let mut legacy = current.clone();
+        legacy.size = None;
+        db.store_new_file(&path, &legacy, false).unwrap();
+
+        assert!(db.verify(&path, &current).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_verify_reports_replaced_file() {
+        let mut db = temporary_database();
+        let path = lorem_ipsum();
+        let fingerprint = fingerprint_file(&path).unwrap();
+        db.store_new_file(&path, &fingerprint, false).unwrap();
+
+        let mut replaced = fingerprint.clone();
+        replaced.dev = Some(1);
+        replaced.ino = replaced.ino.map(|i| i + 1).or(Some(1));
+        let reports = db.verify(&path, &replaced).unwrap();
+        if fingerprint.ino.is_some() {
+            assert!(matches!(
+                reports.as_slice(),
+                [ReportItem::FileReplaced { .. }]
+            ));
+        } else {
+            assert!(reports.is_empty());
+        }
+    }
+
+    #[test]
+    fn test_verify_reports_retargeted_symlink() {
+        let mut db = temporary_database();
+        let path = Path::new("/etc/localtime");
+        let mut recorded = fingerprint_file(&lorem_ipsum()).unwrap();
+        recorded.symlink = true;
+        recorded.link_target = Some(PathBuf::from("../usr/share/zoneinfo/Etc/UTC"));
+        db.store_new_file(path, &recorded, false).unwrap();
+
+        let mut current = recorded.clone();
+        current.link_target = Some(PathBuf::from("/tmp/UTC"));
+        assert!(matches!(
+            db.verify(path, &current).unwrap().as_slice(),
+            [ReportItem::SymlinkTargetChanged { recorded, current, .. }]
+                if recorded == Path::new("../usr/share/zoneinfo/Etc/UTC")
+                    && current == Path::new("/tmp/UTC")
+        ));
+
+        // records from before targets were kept
+        let mut older = recorded.clone();
+        older.link_target = None;
+        db.update_existing_file(path, &older, false).unwrap();
+        assert!(db.verify(path, &current).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_verify_reports_algorithm_mismatch() {
+        let mut db = temporary_database();
+        let path = lorem_ipsum();
+        let fingerprint = fingerprint_file(&path).unwrap();
+        let mut keyed = fingerprint.clone();
+        keyed.algorithm = HashAlgorithm::HmacSha3_256;
+        db.store_new_file(&path, &keyed, false).unwrap();
+
+        assert!(matches!(
+            db.verify(&path, &fingerprint).unwrap().as_slice(),
+            [ReportItem::HashAlgorithmMismatch { .. }]
+        ));
+    }
+
+    #[test]
+    fn test_compare_contents() {
+        let path = lorem_ipsum();
+        let recorded = fingerprint_file(&path).unwrap();
+
+        // metadata of a file mounted elsewhere, or of a symlink to it,
+        // is not its own
+        let mut moved = recorded.clone();
+        moved.dev = Some(1);
+        moved.ino = Some(2);
+        moved.modified = Some(SystemTime::UNIX_EPOCH);
+        moved.unix_mode = Some(0o120777);
+        moved.symlink = true;
+        moved.size = Some(20);
+        assert!(compare_contents(&path, &recorded, &moved).is_empty());
+
+        let mut changed = moved.clone();
+        changed.content_hash[0] ^= 0xff;
+        assert!(matches!(
+            compare_contents(&path, &recorded, &changed).as_slice(),
+            [ReportItem::FileContentChanged { .. }]
+        ));
+        changed.symlink = false;
+        assert!(matches!(
+            compare_contents(&path, &recorded, &changed).as_slice(),
+            [ReportItem::FileSizeChanged { .. }]
+        ));
+    }
+
+    #[test]
+    fn test_coverage() {
+        let db = temporary_database();
+        let path = lorem_ipsum();
+        assert_eq!(db.last_verified(&path).unwrap(), None);
+
+        let time = UNIX_EPOCH + Duration::from_secs(1714543628);
+        db.record_verified(&path, time).unwrap();
+        assert_eq!(db.last_verified(&path).unwrap(), Some(time));
+        let future = SystemTime::now() + Duration::from_secs(3600);
+        db.record_verified(&path, future).unwrap();
+        assert_eq!(db.last_verified(&path).unwrap(), None);
+        db.record_verified(&path, time).unwrap();
+        assert_eq!(
+            db.with_host(Some("web1".to_string()))
+                .last_verified(&path)
+                .unwrap(),
+            None
+        );
+
+        // forgotten once the file is no longer tracked
+        let mut db = temporary_database();
+        let fingerprint = fingerprint_file(&path).unwrap();
+        db.store_new_file(&path, &fingerprint, false).unwrap();
+        db.record_verified(&path, time).unwrap();
+        db.remove_existing_file(&path, false).unwrap();
+        assert_eq!(db.last_verified(&path).unwrap(), None);
+        assert!(db.coverage.is_empty().unwrap());
+    }
+
+    #[test]
+    fn test_hash_cache() {
+        let mut db = temporary_database();
+        let path = lorem_ipsum();
+        let fingerprint = fingerprint_file(&path).unwrap();
+        db.store_new_file(&path, &fingerprint, false).unwrap();
+        assert_eq!(db.cached_key(&path, &fingerprint).unwrap(), None);
+
+        db.cache_hash(&path, &fingerprint).unwrap();
+        assert_eq!(
+            db.cached_key(&path, &fingerprint).unwrap(),
+            fingerprint.cache_key()
+        );
+
+        // an entry for other contents than those recorded is no use
+        let mut accepted = fingerprint.clone();
+        accepted.content_hash = [0; 32];
+        assert_eq!(db.cached_key(&path, &accepted).unwrap(), None);
+
+        db.remove_existing_file(&path, false).unwrap();
+        assert_eq!(db.cached_key(&path, &fingerprint).unwrap(), None);
+    }
+
+    #[test]
+    fn test_iter_assertions_under_directory() {
+        let check = |mut db: SystemDatabase| {
+            let fingerprint = fingerprint_file(&lorem_ipsum()).unwrap();
+            for path in [
+                "/etc/hosts",
+                "/etc/ssh/sshd_config",
+                "/etcetera",
+                "/usr/bin/ls",
+            ] {
+                db.store_new_file(Path::new(path), &fingerprint, false)
+                    .unwrap();
+            }
+            let mut under: Vec<_> = db
+                .iter_assertions_under(Path::new("/etc"))
+                .map(|item| item.unwrap().0)
+                .collect();
+            under.sort();
+            assert_eq!(
+                under,
+                vec![
+                    PathBuf::from("/etc/hosts"),
+                    PathBuf::from("/etc/ssh/sshd_config")
+                ]
+            );
+        };
+
+        check(temporary_database());
+        check(temporary_database().with_host(Some("web1".to_string())));
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let cipher = DatabaseKey::Material(b"secret".to_vec());
+        check(SystemDatabase::from_db(PathBuf::from("<temporary>"), db, Some(cipher)).unwrap());
+    }
+
+    #[test]
+    fn test_encrypted_database_hides_paths() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let cipher = DatabaseKey::Material(b"secret".to_vec());
+        let mut encrypted =
+            SystemDatabase::from_db(PathBuf::from("<temporary>"), db.clone(), Some(cipher))
+                .unwrap();
+        let path = lorem_ipsum();
+        let fingerprint = fingerprint_file(&path).unwrap();
+        encrypted
+            .store_new_file(&path, &fingerprint, false)
+            .unwrap();
+
+        let needle = path.to_str().unwrap().as_bytes();
+        for (k, v) in encrypted.fingerprints.scan_prefix(b"").flatten() {
+            assert!(!k.windows(needle.len()).any(|w| w == needle));
+            assert!(!v.windows(needle.len()).any(|w| w == needle));
+        }
+
+        let assertions: Vec<_> = encrypted
+            .iter_assertions()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(assertions, vec![(path.clone(), fingerprint.clone())]);
+        assert!(encrypted.verify(&path, &fingerprint).unwrap().is_empty());
+
+        // a sealed record copied to the key of another path won't open
+        let other = Path::new("/etc/hostname");
+        let sealed = encrypted
+            .fingerprints
+            .get(&encrypted.stored_key(&encrypted.plain_key(&path).unwrap()))
+            .unwrap()
+            .unwrap();
+        let other_key = encrypted.stored_key(&encrypted.plain_key(other).unwrap());
+        encrypted.fingerprints.insert(&other_key, sealed).unwrap();
+        assert!(matches!(
+            encrypted.recorded_fingerprint(other),
+            Err(FimblError::DecryptionError)
+        ));
+        encrypted.fingerprints.remove(&other_key).unwrap();
+        drop(encrypted);
+
+        assert!(matches!(
+            SystemDatabase::from_db(PathBuf::from("<temporary>"), db.clone(), None),
+            Err(FimblError::DatabaseEncrypted)
+        ));
+        assert!(matches!(
+            SystemDatabase::from_db(
+                PathBuf::from("<temporary>"),
+                db,
+                Some(DatabaseKey::Material(b"wrong".to_vec()))
+            ),
+            Err(FimblError::WrongDatabaseKey)
+        ));
+    }
+
+    #[test]
+    fn test_backup_and_restore() {
+        let mut db = temporary_database();
+        let path = lorem_ipsum();
+        let fingerprint = fingerprint_file(&path).unwrap();
+        db.store_new_file(&path, &fingerprint, false).unwrap();
+        let archives = tempfile::tempdir().unwrap();
+        let backup = archives.path().join("fimbl.bak");
+        db.backup(&backup).unwrap();
+
+        let dir = tempfile::tempdir().unwrap();
+        let open = |cipher| {
+            SystemDatabase::open(
+                &dir.path().join("db"),
+                cipher,
+                Duration::ZERO,
+                Access::ReadWrite,
+            )
+        };
+        let mut restored = open(None).unwrap();
+        restored.restore(&backup, false).unwrap();
+        let assertions: Vec<_> = restored
+            .iter_assertions()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(assertions, vec![(path.clone(), fingerprint.clone())]);
+
+        assert!(matches!(
+            restored.restore(&backup, false),
+            Err(FimblError::DatabaseNotEmpty)
+        ));
+        assert!(restored.restore(&backup, true).is_ok());
+        drop(restored);
+        let reopened = open(None).unwrap();
+        assert!(reopened.recorded_fingerprint(&path).unwrap().is_some());
+        drop(reopened);
+
+        // a backup that can't be opened as this database is refused,
+        // leaving it as it was
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let cipher = DatabaseKey::Material(b"secret".to_vec());
+        let other =
+            SystemDatabase::from_db(PathBuf::from("<temporary>"), db, Some(cipher)).unwrap();
+        let other_backup = archives.path().join("other.bak");
+        other.backup(&other_backup).unwrap();
+        let mut restored = open(None).unwrap();
+        assert!(matches!(
+            restored.restore(&other_backup, true),
+            Err(FimblError::DatabaseEncrypted)
+        ));
+        assert!(restored.recorded_fingerprint(&path).unwrap().is_some());
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn test_alternate_data_stream_changes() {
+        let mut db = temporary_database();
+        let path = lorem_ipsum();
+        let mut fingerprint = fingerprint_file(&path).unwrap();
+        fingerprint.streams = Some(vec![("Zone.Identifier".to_string(), [0; 32])]);
+        db.store_new_file(&path, &fingerprint, false).unwrap();
+
+        let mut current = fingerprint.clone();
+        current.streams = Some(vec![
+            ("Payload".to_string(), [1; 32]),
+            ("Zone.Identifier".to_string(), [2; 32]),
+        ]);
+        let reports = db.verify(&path, &current).unwrap();
+        assert!(matches!(
+            reports.as_slice(),
+            [
+                ReportItem::StreamAdded { stream: added, .. },
+                ReportItem::StreamChanged { stream: changed, .. },
+            ] if added == "Payload" && changed == "Zone.Identifier"
+        ));
+
+        current.streams = None;
+        assert!(db.verify(&path, &current).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_immutable_flag_removal_is_critical() {
+        let mut db = temporary_database();
+        let path = lorem_ipsum();
+        let mut fingerprint = fingerprint_file(&path).unwrap();
+        fingerprint.flags = Some(FileFlags {
+            immutable: true,
+            append_only: false,
+        });
+        db.store_new_file(&path, &fingerprint, false).unwrap();
+
+        let mut current = fingerprint.clone();
+        current.flags = Some(FileFlags::default());
+        let reports = db.verify(&path, &current).unwrap();
+        assert!(matches!(
+            reports.as_slice(),
+            [ReportItem::ImmutableFlagRemoved { .. }]
+        ));
+        assert_eq!(reports[0].severity(), Severity::Critical);
+
+        current.flags = Some(FileFlags {
+            immutable: true,
+            append_only: true,
+        });
+        assert!(matches!(
+            db.verify(&path, &current).unwrap().as_slice(),
+            [ReportItem::FileFlagsChanged { .. }]
+        ));
+    }
+
+    #[test]
+    fn test_quarantine_removal_reported() {
+        let mut db = temporary_database();
+        let path = lorem_ipsum();
+        let mut fingerprint = fingerprint_file(&path).unwrap();
+        fingerprint.xattrs = Some(vec![("com.apple.quarantine".to_string(), [0; 32])]);
+        db.store_new_file(&path, &fingerprint, false).unwrap();
+
+        let mut current = fingerprint.clone();
+        current.xattrs = Some(vec![]);
+        assert!(matches!(
+            db.verify(&path, &current).unwrap().as_slice(),
+            [ReportItem::XattrRemoved { name, .. }] if name == "com.apple.quarantine"
+        ));
+    }
+
+    #[test]
+    fn test_open_locked_database() {
+        let dir = tempfile::tempdir().unwrap();
+        let held = open_sled(dir.path(), Duration::ZERO).unwrap();
+        assert!(matches!(
+            SystemDatabase::open(dir.path(), None, Duration::ZERO, Access::ReadWrite),
+            Err(FimblError::DatabaseBusy(path, _)) if path == dir.path()
+        ));
+
+        let release = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(300));
+            drop(held);
+        });
+        assert!(
+            SystemDatabase::open(dir.path(), None, Duration::from_secs(10), Access::ReadWrite)
+                .is_ok()
+        );
+        release.join().unwrap();
+    }
+
+    #[test]
+    fn test_schema_version_migration() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let meta = db.open_tree(META_TREE).unwrap();
+        let reopen = || SystemDatabase::from_db(PathBuf::from("<temporary>"), db.clone(), None);
+
+        let mut database = reopen().unwrap();
+        assert_eq!(schema_version(&meta).unwrap(), Some(SCHEMA_VERSION));
+
+        // a database from before versioning is upgraded in place
+        let path = lorem_ipsum();
+        database
+            .store_new_file(&path, &fingerprint_file(&path).unwrap(), false)
+            .unwrap();
+        meta.remove(SCHEMA_VERSION_KEY.as_bytes()).unwrap();
+        let dry = SystemDatabase::from_db_as(
+            PathBuf::from("<temporary>"),
+            db.clone(),
+            None,
+            Access::DryRun,
+        )
+        .unwrap();
+        assert!(dry.recorded_fingerprint(&path).unwrap().is_some());
+        assert_eq!(
+            schema_version(&meta).unwrap(),
+            None,
+            "not upgraded on a dry run"
+        );
+        drop(dry);
+        let looked_at = SystemDatabase::from_db_as(
+            PathBuf::from("<temporary>"),
+            db.clone(),
+            None,
+            Access::ReadOnly,
+        )
+        .unwrap();
+        assert_eq!(looked_at.schema_version().unwrap(), 0);
+        assert_eq!(
+            schema_version(&meta).unwrap(),
+            None,
+            "not upgraded read-only"
+        );
+        drop(looked_at);
+        let database = reopen().unwrap();
+        assert_eq!(schema_version(&meta).unwrap(), Some(SCHEMA_VERSION));
+        assert!(database.recorded_fingerprint(&path).unwrap().is_some());
+
+        // one from the future is refused
+        meta.insert(
+            SCHEMA_VERSION_KEY.as_bytes(),
+            (SCHEMA_VERSION + 1).to_be_bytes().to_vec(),
+        )
+        .unwrap();
+        assert!(matches!(
+            reopen(),
+            Err(FimblError::DatabaseTooNew(found, _)) if found == SCHEMA_VERSION + 1
+        ));
+    }
+
+    #[test]
+    fn test_fsck_reports_and_repairs_corruption() {
+        let mut db = temporary_database();
+        let path = lorem_ipsum();
+        let fingerprint = fingerprint_file(&path).unwrap();
+        db.store_new_file(&path, &fingerprint, false).unwrap();
+        db.update_existing_file(&path, &fingerprint, false).unwrap();
+        assert_eq!(db.history.scan_prefix(b"").count(), 1);
+        db.append_log(
+            &path,
+            LogEvent::PermissionsRestored {
+                mode: None,
+                uid: None,
+                gid: None,
+            },
+        )
+        .unwrap();
+        assert!(db.fsck(false).unwrap().is_empty());
+
+        db.fingerprints
+            .insert(b"/garbage", b"not a record".to_vec())
+            .unwrap();
+        db.fingerprints
+            .insert(
+                b"relative/path",
+                FingerprintRecord::assert(fingerprint.clone()).to_vec(),
+            )
+            .unwrap();
+        db.fingerprints
+            .insert(
+                b"/from/the/future",
+                FingerprintRecord::Assert(
+                    SystemTime::now() + Duration::from_secs(7 * 24 * 60 * 60),
+                    fingerprint,
+                )
+                .to_vec(),
+            )
+            .unwrap();
+        db.logs.insert(b"bad", b"not a log entry".to_vec()).unwrap();
+        db.history
+            .insert(b"/garbage", b"not a history".to_vec())
+            .unwrap();
+
+        let reports = db.fsck(false).unwrap();
+        assert_eq!(reports.len(), 5);
+        assert!(reports
+            .iter()
+            .all(|r| matches!(r, ReportItem::CorruptEntry { removed: false, .. })));
+
+        assert_eq!(db.fsck(true).unwrap().len(), 5);
+        assert!(db.fsck(false).unwrap().is_empty());
+        assert_eq!(db.iter_assertions().count(), 1);
+    }
+
+    #[test]
+    fn test_rename_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let from = dir.path().join("app.conf");
+        let to = dir.path().join("app").join("app.conf");
+        std::fs::write(&from, "listen 8080").unwrap();
+        let recorded = fingerprint_file(&from).unwrap();
+        let mut db = temporary_database();
+        db.store_new_file(&from, &recorded, false).unwrap();
+        db.record_verified(&from, SystemTime::now()).unwrap();
+
+        std::fs::create_dir(to.parent().unwrap()).unwrap();
+        std::fs::rename(&from, &to).unwrap();
+        let current = fingerprint_file(&to).unwrap();
+        assert!(db.rename_file(&from, &to, &current).unwrap().is_empty());
+        assert_eq!(db.recorded_fingerprint(&from).unwrap(), None);
+        assert_eq!(db.last_verified(&from).unwrap(), None);
+        assert!(db.verify(&to, &current).unwrap().is_empty());
+        let entries: Vec<_> = db.iter_log().collect::<Result<_, _>>().unwrap();
+        assert_eq!(entries[0].event, LogEvent::Added);
+        assert_eq!(entries[1].path, to);
+        assert_eq!(entries[1].event, LogEvent::Renamed { from: from.clone() });
+
+        // nothing is tracked at the old path any more
+        let reports = db.rename_file(&from, &to, &current).unwrap();
+        assert!(matches!(
+            reports.as_slice(),
+            [ReportItem::FileNotTracked { .. }]
+        ));
+
+        std::fs::write(&to, "listen 6666").unwrap();
+        let changed = fingerprint_file(&to).unwrap();
+        let back = dir.path().join("app.conf.bak");
+        let reports = db.rename_file(&to, &back, &changed).unwrap();
+        assert!(matches!(
+            reports.as_slice(),
+            [ReportItem::RenameRefused { .. }]
+        ));
+        assert!(db.recorded_fingerprint(&to).unwrap().is_some());
+    }
+
+    #[test]
+    fn test_aliases() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let open = |host: Option<&str>| {
+            let cipher = DatabaseKey::Material(b"secret".to_vec());
+            SystemDatabase::from_db(PathBuf::from("<temporary>"), db.clone(), Some(cipher))
+                .unwrap()
+                .with_host(host.map(String::from))
+        };
+        let alias = Path::new("/home/user/.bashrc");
+        let path = Path::new("/data/home/user/.bashrc");
+
+        let web = open(Some("web"));
+        web.record_alias(alias, path).unwrap();
+        assert_eq!(web.alias(alias).unwrap(), Some(path.to_path_buf()));
+        assert_eq!(web.alias(path).unwrap(), None);
+        assert_eq!(
+            web.aliases().unwrap(),
+            vec![(alias.to_path_buf(), path.to_path_buf())]
+        );
+        let needle = b"/home/user";
+        for (k, v) in web.aliases.scan_prefix(b"").flatten() {
+            assert!(!k.windows(needle.len()).any(|w| w == needle));
+            assert!(!v.windows(needle.len()).any(|w| w == needle));
+        }
+
+        let db1 = open(Some("db1"));
+        assert_eq!(db1.alias(alias).unwrap(), None);
+        assert!(db1.aliases().unwrap().is_empty());
+
+        web.remove_alias(alias).unwrap();
+        assert_eq!(web.alias(alias).unwrap(), None);
+    }
+
+    #[test]
+    fn test_unicode_paths() {
+        let composed = Path::new("/Users/zoe/caf\u{e9}.txt");
+        let decomposed = Path::new("/Users/zoe/cafe\u{301}.txt");
+        assert_ne!(
+            path_as_key(composed, UnicodePaths::AsIs),
+            path_as_key(decomposed, UnicodePaths::AsIs)
+        );
+        assert_eq!(
+            path_as_key(decomposed, UnicodePaths::Nfc),
+            path_as_key(composed, UnicodePaths::AsIs)
+        );
+
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let open = || SystemDatabase::from_db(PathBuf::from("<temporary>"), db.clone(), None);
+        let mut database = open().unwrap();
+        assert_eq!(database.unicode_paths, UnicodePaths::native());
+        database.set_unicode_paths(UnicodePaths::Nfc).unwrap();
+        let fingerprint = fingerprint_file(&lorem_ipsum()).unwrap();
+        database
+            .store_new_file(decomposed, &fingerprint, false)
+            .unwrap();
+        assert!(database.verify(composed, &fingerprint).unwrap().is_empty());
+        assert!(matches!(
+            database
+                .store_new_file(composed, &fingerprint, false)
+                .unwrap()
+                .as_slice(),
+            [ReportItem::FileAlreadyTracked { .. }]
+        ));
+        let paths: Vec<_> = database.iter_assertions().map(|a| a.unwrap().0).collect();
+        assert_eq!(paths, vec![composed.to_path_buf()]);
+        assert!(matches!(
+            database.set_unicode_paths(UnicodePaths::AsIs),
+            Err(FimblError::UnicodePathsTooLate)
+        ));
+        drop(database);
+        assert_eq!(open().unwrap().unicode_paths, UnicodePaths::Nfc);
+
+        let mut as_is = temporary_database();
+        as_is.set_unicode_paths(UnicodePaths::AsIs).unwrap();
+        as_is
+            .store_new_file(decomposed, &fingerprint, false)
+            .unwrap();
+        assert!(matches!(
+            as_is.verify(composed, &fingerprint).unwrap().as_slice(),
+            [ReportItem::FileNotTracked { .. }]
+        ));
+    }
+
+    #[test]
+    fn test_path_policy_kept() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let open = |policy| {
+            SystemDatabase::from_db(PathBuf::from("<temporary>"), db.clone(), None)
+                .unwrap()
+                .with_path_policy(policy, PathPolicy::Canonical)
+        };
+        let policy = |database: SystemDatabase| database.path_policy();
+        assert_eq!(
+            policy(open(Some(PathPolicy::Aliased)).unwrap()),
+            PathPolicy::Aliased
+        );
+        assert_eq!(policy(open(None).unwrap()), PathPolicy::Aliased);
+        assert_eq!(
+            policy(open(Some(PathPolicy::Aliased)).unwrap()),
+            PathPolicy::Aliased
+        );
+        assert!(matches!(
+            open(Some(PathPolicy::Canonical)),
+            Err(FimblError::PathPolicyMismatch(kept, given))
+                if kept == "aliased" && given == "canonical"
+        ));
+        assert_eq!(
+            policy(
+                temporary_database()
+                    .with_path_policy(None, PathPolicy::Canonical)
+                    .unwrap()
+            ),
+            PathPolicy::Canonical
+        );
+    }
+
+    #[test]
+    fn test_duplicates() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut db = temporary_database();
+        for (name, contents) in [
+            ("a.key", "secret"),
+            ("b.key", "secret"),
+            ("c.key", "public"),
+            ("d.txt", "a much longer file"),
+            ("e.txt", "a much longer file"),
+            ("f.txt", ""),
+            ("g.txt", ""),
+        ] {
+            let path = dir.path().join(name);
+            std::fs::write(&path, contents).unwrap();
+            db.store_new_file(&path, &fingerprint_file(&path).unwrap(), false)
+                .unwrap();
+        }
+
+        let duplicates = db.duplicates().unwrap();
+        assert_eq!(duplicates.len(), 2);
+        assert_eq!(duplicates[0].size, Some(18));
+        assert_eq!(
+            duplicates[0].paths,
+            vec![dir.path().join("d.txt"), dir.path().join("e.txt")]
+        );
+        assert_eq!(
+            duplicates[1].paths,
+            vec![dir.path().join("a.key"), dir.path().join("b.key")]
+        );
+    }
+
+    #[test]
+    fn test_lookup_hash() {
+        let dir = tempfile::tempdir().unwrap();
+        let (a, b) = (dir.path().join("a"), dir.path().join("b"));
+        std::fs::write(&a, "payload").unwrap();
+        std::fs::write(&b, "payload").unwrap();
+        let fingerprint = fingerprint_file(&a).unwrap();
+        let mut db = temporary_database();
+        db.store_new_file(&a, &fingerprint, false).unwrap();
+        db.store_new_file(&b, &fingerprint_file(&b).unwrap(), false)
+            .unwrap();
+
+        let found = db.lookup_hash(&fingerprint.content_hash).unwrap();
+        let paths: Vec<_> = found.into_iter().map(|(path, ..)| path).collect();
+        assert_eq!(paths, vec![a.clone(), b.clone()]);
+        assert!(db.lookup_hash(&[0; 32]).unwrap().is_empty());
+
+        // still found in the history once gone
+        std::fs::write(&a, "cleaned").unwrap();
+        db.update_existing_file(&a, &fingerprint_file(&a).unwrap(), false)
+            .unwrap();
+        let found = db.lookup_hash(&fingerprint.content_hash).unwrap();
+        assert!(matches!(
+            found.as_slice(),
+            [(first, _, Some(_)), (second, _, None)] if *first == a && *second == b
+        ));
+    }
+
+    #[test]
+    fn test_recorded_by() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("hosts");
+        std::fs::write(&path, "127.0.0.1 localhost").unwrap();
+        let mut db = temporary_database();
+        assert_eq!(db.recorded_by(&path).unwrap(), None);
+        db.store_new_file(&path, &fingerprint_file(&path).unwrap(), false)
+            .unwrap();
+        let entry = db.recorded_by(&path).unwrap().unwrap();
+        assert_eq!(entry.event, LogEvent::Added);
+        assert_eq!(entry.user, Some(local_user()));
+
+        std::fs::write(&path, "10.0.0.1 localhost").unwrap();
+        db.update_existing_file(&path, &fingerprint_file(&path).unwrap(), false)
+            .unwrap();
+        let entry = db.recorded_by(&path).unwrap().unwrap();
+        assert_eq!(entry.event, LogEvent::Accepted);
+
+        db.remove_existing_file(&path, false).unwrap();
+        let entry = db.recorded_by(&path).unwrap().unwrap();
+        assert_eq!(entry.event, LogEvent::Removed);
+
+        let history = db.attributed_history(&path).unwrap();
+        let events: Vec<_> = history
+            .iter()
+            .map(|(_, _, entry)| entry.as_ref().map(|entry| &entry.event))
+            .collect();
+        assert_eq!(
+            events,
+            vec![
+                Some(&LogEvent::Added),
+                Some(&LogEvent::Accepted),
+                Some(&LogEvent::Removed)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_iter_log_since() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("hosts");
+        std::fs::write(&path, "127.0.0.1 localhost").unwrap();
+        let mut db = temporary_database();
+        db.store_new_file(&path, &fingerprint_file(&path).unwrap(), false)
+            .unwrap();
+        let since = SystemTime::now();
+        db.remove_existing_file(&path, false).unwrap();
+
+        let kinds: Vec<_> = db
+            .iter_log_since(since)
+            .map(|entry| entry.unwrap().event.kind())
+            .collect();
+        assert_eq!(kinds, vec!["remove"]);
+        assert_eq!(db.iter_log().count(), 2);
+        assert!(LOG_EVENT_KINDS.contains(&LogEvent::MetadataAccepted.kind()));
+    }
+
+    #[test]
+    fn test_attribution() {
+        let entry = LogEntry {
+            time: SystemTime::now(),
+            host: None,
+            path: PathBuf::from("/etc/hosts"),
+            event: LogEvent::Accepted,
+            user: Some("root".to_string()),
+            hostname: Some("web1".to_string()),
+            forced: true,
+            login: Some("alice".to_string()),
+            command_line: Some(vec![
+                "fimbl".to_string(),
+                "accept".to_string(),
+                "/etc/it's here".to_string(),
+            ]),
+        };
+        assert_eq!(
+            entry.attribution(),
+            "by accept (forced), as root (logged in as alice) on web1: \
+             fimbl accept '/etc/it'\\''s here'"
+        );
+    }
+
+    #[test]
+    fn test_snapshot_rollback() {
+        let dir = tempfile::tempdir().unwrap();
+        let (kept, changed, added) = (
+            dir.path().join("kept"),
+            dir.path().join("changed"),
+            dir.path().join("added"),
+        );
+        let mut db = temporary_database();
+        for path in [&kept, &changed] {
+            std::fs::write(path, "before").unwrap();
+            db.store_new_file(path, &fingerprint_file(path).unwrap(), false)
+                .unwrap();
+        }
+        let before = db.recorded_fingerprint(&changed).unwrap();
+        db.create_snapshot("pre-upgrade").unwrap();
+        assert!(matches!(
+            db.create_snapshot("pre-upgrade"),
+            Err(FimblError::SnapshotExists(_))
+        ));
+
+        std::fs::write(&changed, "after").unwrap();
+        db.update_existing_file(&changed, &fingerprint_file(&changed).unwrap(), false)
+            .unwrap();
+        std::fs::write(&added, "after").unwrap();
+        db.store_new_file(&added, &fingerprint_file(&added).unwrap(), false)
+            .unwrap();
+
+        let (rolled_back, held) = db.rollback("pre-upgrade").unwrap();
+        assert_eq!(rolled_back, vec![added.clone(), changed.clone()]);
+        assert!(held.is_empty());
+        assert_eq!(db.recorded_fingerprint(&changed).unwrap(), before);
+        assert_eq!(db.recorded_fingerprint(&added).unwrap(), None);
+        assert!(db.rollback("pre-upgrade").unwrap().0.is_empty());
+
+        let snapshots = db.snapshots().unwrap();
+        assert_eq!(snapshots.len(), 1);
+        assert_eq!(
+            (snapshots[0].0.as_str(), snapshots[0].2),
+            ("pre-upgrade", 2)
+        );
+        db.delete_snapshot("pre-upgrade").unwrap();
+        assert!(matches!(
+            db.rollback("pre-upgrade"),
+            Err(FimblError::SnapshotNotFound(_))
+        ));
+    }
+
+    #[test]
+    fn test_two_phase_accept() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("file");
+        std::fs::write(&path, "before").unwrap();
+        let mut db = temporary_database();
+        db.store_new_file(&path, &fingerprint_file(&path).unwrap(), false)
+            .unwrap();
+        let before = db.recorded_fingerprint(&path).unwrap();
+
+        let key = SigningKey::from_bytes(b"approver");
+        db.require_approval(Some(&key)).unwrap();
+        assert!(matches!(
+            db.require_approval(Some(&SigningKey::from_bytes(b"other"))),
+            Err(FimblError::WrongApprovalKey)
+        ));
+
+        let accept = |db: &mut SystemDatabase| {
+            let fingerprint = fingerprint_file(&path).unwrap();
+            match db.update_existing_file(&path, &fingerprint, false).unwrap()[..] {
+                [ReportItem::AcceptPending { ref token, .. }] => token.clone(),
+                _ => panic!("accept not held for approval"),
+            }
+        };
+        std::fs::write(&path, "after").unwrap();
+        let token = accept(&mut db);
+        let overtaken = accept(&mut db);
+        assert_eq!(db.recorded_fingerprint(&path).unwrap(), before);
+        assert_eq!(db.pending().unwrap().len(), 2);
+
+        assert!(matches!(
+            db.approve(&token, None),
+            Err(FimblError::ApprovalKeyRequired)
+        ));
+        assert!(matches!(
+            db.approve(&token, Some(&SigningKey::from_bytes(b"other"))),
+            Err(FimblError::WrongApprovalKey)
+        ));
+        assert_eq!(db.approve(&token, Some(&key)).unwrap().0, path);
+        assert_eq!(
+            db.recorded_fingerprint(&path).unwrap(),
+            Some(fingerprint_file(&path).unwrap())
+        );
+        assert!(matches!(
+            db.approve(&token, Some(&key)),
+            Err(FimblError::PendingNotFound(_))
+        ));
+        assert!(matches!(
+            db.approve(&overtaken, Some(&key)),
+            Err(FimblError::ApprovalStale(_))
+        ));
+        assert_eq!(db.reject(&overtaken).unwrap(), path);
+        assert!(db.pending().unwrap().is_empty());
+
+        // tracking a file again, renaming onto a file tracked before
+        // and rolling back are changes like any accept
+        let held = |reports: Vec<ReportItem>| match &reports[..] {
+            [ReportItem::AcceptPending { token, .. }] => token.clone(),
+            _ => panic!("change not held for approval"),
+        };
+        db.create_snapshot("approved").unwrap();
+        db.remove_existing_file(&path, false).unwrap();
+        std::fs::write(&path, "again").unwrap();
+        let fingerprint = fingerprint_file(&path).unwrap();
+        let token = held(db.store_new_file(&path, &fingerprint, false).unwrap());
+        assert_eq!(db.recorded_fingerprint(&path).unwrap(), None);
+        db.approve(&token, Some(&key)).unwrap();
+        assert_eq!(db.recorded_fingerprint(&path).unwrap(), Some(fingerprint));
+
+        let moved = dir.path().join("moved");
+        std::fs::write(&moved, "moved").unwrap();
+        db.store_new_file(&moved, &fingerprint_file(&moved).unwrap(), false)
+            .unwrap();
+        db.remove_existing_file(&moved, false).unwrap();
+        let current = fingerprint_file(&path).unwrap();
+        let token = held(db.rename_file(&path, &moved, &current).unwrap());
+        assert!(db.recorded_fingerprint(&moved).unwrap().is_none());
+        db.approve(&token, Some(&key)).unwrap();
+        assert!(db.recorded_fingerprint(&path).unwrap().is_none());
+        assert!(db.recorded_fingerprint(&moved).unwrap().is_some());
+
+        let (changed, reports) = db.rollback("approved").unwrap();
+        assert_eq!(changed, vec![moved.clone()], "retracted at once");
+        held(reports);
+        assert!(db.recorded_fingerprint(&path).unwrap().is_none());
+
+        let backup = dir.path().join("fimbl.bak");
+        db.backup(&backup).unwrap();
+        assert!(matches!(
+            db.restore(&backup, true),
+            Err(FimblError::RestoreNeedsApproval)
+        ));
+    }
+
+    #[test]
+    fn test_dry_run() {
+        let dir = tempfile::tempdir().unwrap();
+        let (kept, new) = (dir.path().join("kept"), dir.path().join("new"));
+        std::fs::write(&kept, "before").unwrap();
+        std::fs::write(&new, "new").unwrap();
+        let sled = sled::Config::new().temporary(true).open().unwrap();
+        let mut real =
+            SystemDatabase::from_db(PathBuf::from("<temporary>"), sled.clone(), None).unwrap();
+        real.store_new_file(&kept, &fingerprint_file(&kept).unwrap(), false)
+            .unwrap();
+        real.create_snapshot("before").unwrap();
+        let logged = real.iter_log().count();
+
+        let mut dry =
+            SystemDatabase::from_db_as(PathBuf::from("<temporary>"), sled, None, Access::DryRun)
+                .unwrap();
+        let fingerprint = fingerprint_file(&new).unwrap();
+        assert!(matches!(
+            dry.store_new_file(&new, &fingerprint, false).unwrap()[..],
+            [ReportItem::WouldTrack { .. }]
+        ));
+        assert!(matches!(
+            dry.store_new_file(&new, &fingerprint, false).unwrap()[..],
+            [ReportItem::FileAlreadyTracked { .. }]
+        ));
+        std::fs::write(&kept, "after").unwrap();
+        let changed = fingerprint_file(&kept).unwrap();
+        assert!(matches!(
+            dry.update_existing_file(&kept, &changed, false).unwrap()[..],
+            [ReportItem::WouldAccept { pending: false, .. }]
+        ));
+        assert!(matches!(
+            dry.remove_existing_file(&kept, false).unwrap()[..],
+            [ReportItem::WouldRetract { .. }]
+        ));
+        let tracked: Vec<_> = dry.iter_assertions().map(|item| item.unwrap().0).collect();
+        assert_eq!(tracked, std::slice::from_ref(&new));
+        assert!(matches!(
+            dry.annotate(&new, Some("expected")).unwrap()[..],
+            [ReportItem::WouldAnnotate { .. }]
+        ));
+        let moved = dir.path().join("moved");
+        assert!(matches!(
+            dry.rename_file(&new, &moved, &fingerprint).unwrap()[..],
+            [ReportItem::WouldRename { .. }]
+        ));
+        let (rolled_back, reports) = dry.rollback("before").unwrap();
+        assert!(rolled_back.is_empty());
+        assert_eq!(reports.len(), 2, "kept put back, moved retracted");
+        assert!(reports
+            .iter()
+            .all(|item| matches!(item, ReportItem::WouldRollBack { .. })));
+
+        assert_eq!(real.recorded_fingerprint(&new).unwrap(), None);
+        assert!(!real.verify(&kept, &changed).unwrap().is_empty());
+        assert_eq!(real.iter_log().count(), logged);
+    }
+
+    #[test]
+    fn test_root_hash() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("file");
+        std::fs::write(&path, "before").unwrap();
+        let sled = sled::Config::new().temporary(true).open().unwrap();
+        let open = || SystemDatabase::from_db(PathBuf::from("<temporary>"), sled.clone(), None);
+        let mut db = open().unwrap();
+        let empty = db.root_hash().unwrap();
+        db.store_new_file(&path, &fingerprint_file(&path).unwrap(), false)
+            .unwrap();
+        let added = db.root_hash().unwrap();
+        assert_ne!(added, empty);
+        db.verify(&path, &fingerprint_file(&path).unwrap()).unwrap();
+        assert_eq!(db.root_hash().unwrap(), added);
+        db.fingerprints
+            .insert(b"/forged", FingerprintRecord::retract().to_vec())
+            .unwrap();
+        let forged = db.root_hash().unwrap();
+        assert_ne!(forged, added);
+
+        let mut other = open().unwrap().with_host(Some("web2".to_string()));
+        let other_empty = other.root_hash().unwrap();
+        assert_ne!(other_empty, empty);
+        other
+            .store_new_file(&path, &fingerprint_file(&path).unwrap(), false)
+            .unwrap();
+        assert_ne!(other.root_hash().unwrap(), other_empty);
+        assert_eq!(db.root_hash().unwrap(), forged);
+
+        let attested = db.root_hash_of(&BTreeSet::from([path.clone()])).unwrap();
+        assert_ne!(attested, forged);
+        assert_ne!(attested, empty);
+        assert_eq!(db.root_hash_of(&BTreeSet::new()).unwrap(), empty);
+    }
+
+    #[test]
+    fn test_record_macs() {
+        let dir = tempfile::tempdir().unwrap();
+        let (kept, forged) = (dir.path().join("kept"), dir.path().join("forged"));
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let open = |key: Option<&[u8]>| {
+            SystemDatabase::from_db(PathBuf::from("<temporary>"), db.clone(), None)
+                .unwrap()
+                .with_record_key(key.map(SigningKey::from_bytes))
+        };
+
+        let mut unkeyed = open(None).unwrap();
+        for path in [&kept, &forged] {
+            std::fs::write(path, "before").unwrap();
+            unkeyed
+                .store_new_file(path, &fingerprint_file(path).unwrap(), false)
+                .unwrap();
+        }
+        assert!(matches!(
+            open(Some(b"record key")),
+            Err(FimblError::RecordKeyNotEnrolled)
+        ));
+        unkeyed
+            .enrol_record_key(SigningKey::from_bytes(b"record key"))
+            .unwrap();
+        assert!(matches!(
+            unkeyed.enrol_record_key(SigningKey::from_bytes(b"other key")),
+            Err(FimblError::RecordKeyEnrolled)
+        ));
+        let keyed = open(Some(b"record key")).unwrap();
+        assert!(keyed.check_record(&kept).unwrap().is_empty());
+        assert!(matches!(
+            open(Some(b"other key")),
+            Err(FimblError::WrongRecordKey)
+        ));
+        assert!(matches!(
+            open(None).unwrap().recorded_fingerprint(&kept),
+            Err(FimblError::RecordKeyRequired)
+        ));
+
+        // rewrite a record behind fimbl's back
+        std::fs::write(&forged, "after").unwrap();
+        let plain_key = keyed.plain_key(&forged).unwrap();
+        let record = FingerprintRecord::assert(fingerprint_file(&forged).unwrap());
+        keyed
+            .fingerprints
+            .insert(&plain_key, keyed.encode_record(&plain_key, &record))
+            .unwrap();
+        assert!(matches!(
+            keyed.check_record(&forged).unwrap()[..],
+            [ReportItem::RecordTampered { .. }]
+        ));
+        assert!(matches!(
+            keyed.recorded_fingerprint(&forged),
+            Err(FimblError::RecordTampered(_))
+        ));
+
+        keyed
+            .fingerprints
+            .remove(&keyed.plain_key(&kept).unwrap())
+            .unwrap();
+        let problems: Vec<_> = keyed
+            .fsck(true)
+            .unwrap()
+            .iter()
+            .map(|item| match item {
+                ReportItem::CorruptEntry { tree, problem, .. } => format!("{tree}: {problem}"),
+                _ => panic!("unexpected report"),
+            })
+            .collect();
+        assert_eq!(
+            problems,
+            vec![
+                "fingerprints: not written with the record key",
+                "record-macs: record removed other than by fimbl"
+            ]
+        );
+        assert!(keyed.fsck(false).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_append_only() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("file");
+        std::fs::write(&path, "before").unwrap();
+        let mut db = temporary_database();
+        db.store_new_file(&path, &fingerprint_file(&path).unwrap(), false)
+            .unwrap();
+        db.create_snapshot("start").unwrap();
+        db.require_append_only().unwrap();
+
+        std::fs::write(&path, "after").unwrap();
+        let fingerprint = fingerprint_file(&path).unwrap();
+        assert!(matches!(
+            db.update_existing_file(&path, &fingerprint, false),
+            Err(FimblError::AppendOnly)
+        ));
+        assert!(matches!(
+            db.remove_existing_file(&path, false),
+            Err(FimblError::AppendOnly)
+        ));
+        assert!(matches!(db.rollback("start"), Err(FimblError::AppendOnly)));
+        let other = dir.path().join("other");
+        std::fs::write(&other, "other").unwrap();
+        db.store_new_file(&other, &fingerprint_file(&other).unwrap(), false)
+            .unwrap();
+        let moved = dir.path().join("moved");
+        assert!(matches!(
+            db.rename_file(&other, &moved, &fingerprint_file(&other).unwrap()),
+            Err(FimblError::AppendOnly)
+        ));
+        let backup = dir.path().join("fimbl.bak");
+        db.backup(&backup).unwrap();
+        assert!(matches!(
+            db.restore(&backup, true),
+            Err(FimblError::AppendOnly)
+        ));
+
+        let mut db = db.with_force_unsafe(true);
+        db.update_existing_file(&path, &fingerprint, false).unwrap();
+        assert_eq!(db.recorded_fingerprint(&path).unwrap(), Some(fingerprint));
+        let entries: Vec<_> = db.iter_log().map(Result::unwrap).collect();
+        assert_eq!(
+            entries
+                .iter()
+                .map(|entry| (&entry.event, entry.forced))
+                .collect::<Vec<_>>(),
+            vec![
+                (&LogEvent::Added, false),
+                (&LogEvent::Added, false),
+                (&LogEvent::Accepted, true)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_self_approval() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("file");
+        std::fs::write(&path, "before").unwrap();
+        let mut db = temporary_database();
+        db.store_new_file(&path, &fingerprint_file(&path).unwrap(), false)
+            .unwrap();
+        db.require_approval(None).unwrap();
+        assert!(matches!(
+            db.require_approval(Some(&SigningKey::from_bytes(b"late"))),
+            Err(FimblError::ApprovalKeyTooLate)
+        ));
+
+        std::fs::write(&path, "after").unwrap();
+        let reports = db
+            .update_existing_metadata(&path, &fingerprint_file(&path).unwrap())
+            .unwrap();
+        assert!(matches!(
+            reports[..],
+            [ReportItem::ContentChangeNotAccepted { .. }]
+        ));
+        db.update_existing_file(&path, &fingerprint_file(&path).unwrap(), false)
+            .unwrap();
+        let (token, change) = db.pending().unwrap().pop().unwrap();
+        assert_eq!(change.user, local_user());
+        assert!(matches!(
+            db.approve(&token, None),
+            Err(FimblError::SelfApproval(_))
+        ));
+    }
+
+    #[test]
+    fn test_history_is_bounded() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("motd");
+        let mut db = temporary_database().with_history_limit(2);
+        std::fs::write(&path, "0").unwrap();
+        db.store_new_file(&path, &fingerprint_file(&path).unwrap(), false)
+            .unwrap();
+        for contents in ["1", "2", "3"] {
+            std::fs::write(&path, contents).unwrap();
+            db.update_existing_file(&path, &fingerprint_file(&path).unwrap(), false)
+                .unwrap();
+        }
+        db.remove_existing_file(&path, false).unwrap();
+
+        let hashes: Vec<_> = db
+            .history(&path)
+            .unwrap()
+            .into_iter()
+            .map(|(_, fingerprint)| fingerprint.map(|f| f.content_hash))
+            .collect();
+        let last = fingerprint_file(&path).unwrap();
+        assert_eq!(hashes, vec![hashes[0], Some(last.content_hash), None]);
+
+        let mut db = db.with_history_limit(0);
+        db.store_new_file(&path, &last, false).unwrap();
+        assert_eq!(db.history(&path).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_accept_keeps_tags() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("sshd_config");
+        std::fs::write(&path, "PermitRootLogin no").unwrap();
+        let tagged = Fingerprint {
+            tags: vec!["ssh".to_string()],
+            ..fingerprint_file(&path).unwrap()
+        };
+        let mut db = temporary_database();
+        db.store_new_file(&path, &tagged, false).unwrap();
+        assert!(db
+            .verify(&path, &fingerprint_file(&path).unwrap())
+            .unwrap()
+            .is_empty());
+
+        std::fs::write(&path, "PermitRootLogin yes").unwrap();
+        let changed = fingerprint_file(&path).unwrap();
+        db.update_existing_file(&path, &changed, false).unwrap();
+        let recorded = db.recorded_fingerprint(&path).unwrap().unwrap();
+        assert_eq!(recorded.tags, vec!["ssh".to_string()]);
+        assert!(recorded.tagged(&["critical".to_string(), "ssh".to_string()]));
+        assert!(!recorded.tagged(&["critical".to_string()]));
+    }
+
+    #[test]
+    fn test_annotate() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("nginx.conf");
+        std::fs::write(&path, "worker_processes 4;").unwrap();
+        let mut db = temporary_database();
+        let reports = db.annotate(&path, Some("managed by ansible")).unwrap();
+        assert!(matches!(
+            reports.as_slice(),
+            [ReportItem::FileNotTracked { .. }]
+        ));
+
+        let fingerprint = fingerprint_file(&path).unwrap();
+        db.store_new_file(&path, &fingerprint, false).unwrap();
+        assert!(db
+            .annotate(&path, Some("managed by ansible"))
+            .unwrap()
+            .is_empty());
+        assert!(db.verify(&path, &fingerprint).unwrap().is_empty());
+        let history = db.history(&path).unwrap().len();
+        db.annotate(&path, Some("managed by ansible")).unwrap();
+        assert_eq!(db.history(&path).unwrap().len(), history);
+
+        std::fs::write(&path, "worker_processes 8;").unwrap();
+        let changed = fingerprint_file(&path).unwrap();
+        db.update_existing_file(&path, &changed, false).unwrap();
+        let recorded = db.recorded_fingerprint(&path).unwrap().unwrap();
+        assert_eq!(recorded.note.as_deref(), Some("managed by ansible"));
+
+        db.annotate(&path, None).unwrap();
+        let recorded = db.recorded_fingerprint(&path).unwrap().unwrap();
+        assert_eq!(recorded.note, None);
+    }
+
+    #[test]
+    fn test_log_keys_are_unique() {
+        let time = SystemTime::now();
+        let (first, second) = (log_key(time), log_key(time));
+        assert_ne!(first, second);
+        assert!(first.starts_with(&log_time(time)));
+        assert!(second.starts_with(&log_time(time)));
+        assert!(log_key(time + Duration::from_nanos(1)) > first.max(second));
+    }
+
+    #[test]
+    fn test_log_is_per_host() {
+        let db = temporary_database();
+        let path = lorem_ipsum();
+        let event = LogEvent::PermissionsRestored {
+            mode: Some(0o644),
+            uid: None,
+            gid: None,
+        };
+        db.append_log(&path, event.clone()).unwrap();
+
+        let entries: Vec<_> = db.iter_log().collect::<Result<_, _>>().unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].path, path);
+        assert_eq!(entries[0].event, event);
+
+        let db = db.with_host(Some("web1".to_string()));
+        assert_eq!(db.iter_log().count(), 0);
+    }
+
+    #[test]
+    fn test_verify_against_baseline() {
+        let mut db = temporary_database().with_host(Some("web1".to_string()));
+        let path = lorem_ipsum();
+        let fingerprint = fingerprint_file(&path).unwrap();
+        db.store_new_file(&path, &fingerprint, false).unwrap();
+        let baseline = db.baseline().unwrap();
+        assert_eq!(baseline.host.as_deref(), Some("web1"));
+
+        let published = SystemDatabase::from_baseline("<baseline>", &baseline).unwrap();
+        assert!(published.verify(&path, &fingerprint).unwrap().is_empty());
+        let elsewhere = Fingerprint {
+            dev: fingerprint.dev.map(|dev| dev + 1),
+            ino: fingerprint.ino.map(|ino| ino + 1),
+            modified: Some(UNIX_EPOCH),
+            ..fingerprint.clone()
+        };
+        assert!(published.verify(&path, &elsewhere).unwrap().is_empty());
+        let mut altered = fingerprint.clone();
+        altered.content_hash = [0; 32];
+        assert!(matches!(
+            published.verify(&path, &altered).unwrap().as_slice(),
+            [ReportItem::FileContentChanged { .. }]
+        ));
+    }
+
+    #[test]
+    fn test_diff_databases() {
+        let mut ours = temporary_database();
+        let mut theirs = temporary_database();
+        let path = lorem_ipsum();
+        let fingerprint = fingerprint_file(&path).unwrap();
+        let mut altered = fingerprint.clone();
+        altered.content_hash = [0; 32];
+        let mut touched = fingerprint.clone();
+        touched.modified = None;
+        touched.ino = None;
+
+        ours.store_new_file(&path, &fingerprint, false).unwrap();
+        theirs.store_new_file(&path, &fingerprint, false).unwrap();
+        ours.store_new_file(Path::new("/ours"), &fingerprint, false)
+            .unwrap();
+        theirs
+            .store_new_file(Path::new("/theirs"), &fingerprint, false)
+            .unwrap();
+        ours.store_new_file(Path::new("/altered"), &fingerprint, false)
+            .unwrap();
+        theirs
+            .store_new_file(Path::new("/altered"), &altered, false)
+            .unwrap();
+        ours.store_new_file(Path::new("/touched"), &fingerprint, false)
+            .unwrap();
+        theirs
+            .store_new_file(Path::new("/touched"), &touched, false)
+            .unwrap();
+
+        let reports: Vec<String> = ours
+            .diff(&theirs)
+            .unwrap()
+            .iter()
+            .map(|r| r.to_string())
+            .collect();
+        assert_eq!(
+            reports,
+            vec![
+                "databases disagree (content): /altered",
+                "only in <temporary>: /ours",
+                "databases disagree (attributes): /touched",
+                "only in <temporary>: /theirs",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_merge_databases() {
+        let mut ours = temporary_database();
+        let mut theirs = temporary_database();
+        let path = lorem_ipsum();
+        let fingerprint = fingerprint_file(&path).unwrap();
+        let mut altered = fingerprint.clone();
+        altered.content_hash = [0; 32];
+
+        theirs
+            .store_new_file(Path::new("/new"), &fingerprint, false)
+            .unwrap();
+        ours.store_new_file(Path::new("/conflict"), &fingerprint, false)
+            .unwrap();
+        theirs
+            .store_new_file(Path::new("/conflict"), &altered, false)
+            .unwrap();
+
+        let reports = ours.merge(&theirs, MergePreference::Ours).unwrap();
+        assert!(matches!(
+            reports.as_slice(),
+            [ReportItem::MergeConflict {
+                took_theirs: false,
+                ..
+            }]
+        ));
+        assert_eq!(
+            ours.recorded_fingerprint(Path::new("/new")).unwrap(),
+            Some(fingerprint.clone())
+        );
+        assert_eq!(
+            ours.recorded_fingerprint(Path::new("/conflict")).unwrap(),
+            Some(fingerprint.clone())
+        );
+
+        // theirs was recorded later
+        ours.merge(&theirs, MergePreference::Newest).unwrap();
+        assert_eq!(
+            ours.recorded_fingerprint(Path::new("/conflict")).unwrap(),
+            Some(altered)
+        );
+    }
+
+    #[test]
+    fn test_host_namespaces_are_separate() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let open = |host: Option<&str>, cipher: Option<DatabaseKey>| {
+            SystemDatabase::from_db(PathBuf::from("<temporary>"), db.clone(), cipher)
+                .unwrap()
+                .with_host(host.map(String::from))
+        };
+        let path = lorem_ipsum();
+        let fingerprint = fingerprint_file(&path).unwrap();
+
+        let mut web = open(Some("web"), None);
+        web.store_new_file(&path, &fingerprint, false).unwrap();
+        let mut shared = open(None, None);
+        shared
+            .store_new_file(Path::new("/shared"), &fingerprint, false)
+            .unwrap();
+
+        let paths = |db: &SystemDatabase| -> Vec<PathBuf> {
+            db.iter_assertions().map(|item| item.unwrap().0).collect()
+        };
+        assert_eq!(paths(&web), vec![path.clone()]);
+        assert_eq!(paths(&shared), vec![PathBuf::from("/shared")]);
+        assert!(paths(&open(Some("db"), None)).is_empty());
+        assert!(matches!(
+            open(Some("db"), None)
+                .verify(&path, &fingerprint)
+                .unwrap()
+                .as_slice(),
+            [ReportItem::FileNotTracked { .. }]
+        ));
+    }
+
+    #[test]
+    fn test_verify_reports_untracked() {
+        let db = temporary_database();
+        let path = lorem_ipsum();
+        let fingerprint = fingerprint_file(&path).unwrap();
+
+        let reports = db.verify(&path, &fingerprint).unwrap();
+        assert!(matches!(
+            reports.as_slice(),
+            [ReportItem::FileNotTracked { .. }]
+        ));
     }
 }
@@ -0,0 +1,221 @@
+//! Diagnosing problems with the environment fimbl runs in
+//!
+//! Each check gives a finding: fine, or a problem with what to do
+//! about it.
+
+use crate::mounts::Mounts;
+
+use std::{
+    fs::{remove_file, OpenOptions},
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+/// A time before which the clock is surely wrong (2024-01-01)
+const EARLIEST_PLAUSIBLE: Duration = Duration::from_secs(1_704_067_200);
+
+/// Leeway for records made on a host whose clock runs a little ahead
+const CLOCK_TOLERANCE: Duration = Duration::from_secs(5 * 60);
+
+/// Most tracked files on network file systems to name
+const EXAMPLES: usize = 3;
+
+/// What a check found
+pub struct Finding {
+    /// What was checked
+    pub check: &'static str,
+
+    /// What was found
+    pub detail: String,
+
+    /// What to do about it, if it's a problem
+    pub fix: Option<String>,
+}
+
+impl Finding {
+    /// Nothing wrong
+    pub fn fine(check: &'static str, detail: String) -> Self {
+        Finding {
+            check,
+            detail,
+            fix: None,
+        }
+    }
+
+    /// A problem, and what to do about it
+    pub fn problem(check: &'static str, detail: String, fix: &str) -> Self {
+        Finding {
+            check,
+            detail,
+            fix: Some(fix.to_string()),
+        }
+    }
+
+    /// True if this is a problem
+    pub fn is_problem(&self) -> bool {
+        self.fix.is_some()
+    }
+}
+
+impl std::fmt::Display for Finding {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.fix {
+            None => write!(f, "ok       {}: {}", self.check, self.detail),
+            Some(fix) => write!(
+                f,
+                "PROBLEM  {}: {}\n         fix: {}",
+                self.check, self.detail, fix
+            ),
+        }
+    }
+}
+
+/// Where the default database comes from, noting an XDG config
+/// directory it isn't in (which a user might expect it to be)
+pub fn home(default_db: Option<&Path>, xdg_config: Option<&Path>) -> Finding {
+    let check = "home directory";
+    match default_db {
+        Some(db) => {
+            let ignored = match xdg_config {
+                Some(xdg) if !db.starts_with(xdg) => {
+                    format!(" (XDG_CONFIG_HOME {} is not used)", xdg.display())
+                }
+                _ => String::new(),
+            };
+            Finding::fine(
+                check,
+                format!("default database is {}{}", db.display(), ignored),
+            )
+        }
+        None => Finding::problem(
+            check,
+            "no home directory found for the default database".to_string(),
+            "set HOME (LOCALAPPDATA on Windows) or pass --database",
+        ),
+    }
+}
+
+/// Whether the database directory, or the directory it will be
+/// created in, can be written
+///
+/// Nothing short of writing a file shows this reliably (ACLs,
+/// read-only mounts, root), so a file is written and removed.
+pub fn writable(db_dir: &Path) -> Finding {
+    let check = "database directory";
+    let Some(existing) = db_dir.ancestors().find(|dir| dir.exists()) else {
+        return Finding::problem(
+            check,
+            format!("{} has no existing ancestor", db_dir.display()),
+            "pass an absolute --database",
+        );
+    };
+    if !existing.is_dir() {
+        return Finding::problem(
+            check,
+            format!("{} is not a directory", existing.display()),
+            "pass --database naming a directory",
+        );
+    }
+
+    let probe = existing.join(format!(".fimbl-doctor-{}", std::process::id()));
+    let written = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&probe)
+        .map(|_| remove_file(&probe));
+    match (written, existing == db_dir) {
+        (Ok(_), true) => Finding::fine(check, format!("{} is writable", db_dir.display())),
+        (Ok(_), false) => Finding::fine(
+            check,
+            format!(
+                "{} does not exist yet, but can be created (by fimbl add)",
+                db_dir.display()
+            ),
+        ),
+        (Err(e), _) => Finding::problem(
+            check,
+            format!("cannot write in {}: {}", existing.display(), e),
+            "fix its ownership or permissions, or pass --database",
+        ),
+    }
+}
+
+/// Whether the clock is plausible: not before fimbl's time, nor
+/// behind the newest record in the database
+pub fn clock(now: SystemTime, latest_record: Option<SystemTime>) -> Finding {
+    let check = "clock";
+    let shown = humantime::format_rfc3339_seconds(now);
+    let fix = "set the clock, and keep it set with NTP";
+    if now < SystemTime::UNIX_EPOCH + EARLIEST_PLAUSIBLE {
+        return Finding::problem(check, format!("time is {shown}, surely wrong"), fix);
+    }
+    match latest_record {
+        Some(latest) if latest > now + CLOCK_TOLERANCE => Finding::problem(
+            check,
+            format!(
+                "time is {shown}, before the newest database record ({})",
+                humantime::format_rfc3339_seconds(latest)
+            ),
+            fix,
+        ),
+        _ => Finding::fine(check, format!("time is {shown}")),
+    }
+}
+
+/// Whether any tracked files are on network or virtual file systems,
+/// where reads may hang, without the policy for them being set
+pub fn file_systems(mounts: &Mounts, tracked: &[PathBuf], policy_set: bool) -> Finding {
+    let check = "file systems";
+    let remote: Vec<_> = tracked
+        .iter()
+        .filter_map(|path| Some((path, mounts.remote_file_system(path)?)))
+        .collect();
+    let examples: Vec<_> = remote
+        .iter()
+        .take(EXAMPLES)
+        .map(|(path, fs_type)| format!("{} ({})", path.display(), fs_type))
+        .collect();
+    let detail = format!(
+        "{} tracked files on network or virtual file systems, e.g. {}",
+        remote.len(),
+        examples.join(", ")
+    );
+    match (remote.is_empty(), policy_set) {
+        (true, _) => Finding::fine(
+            check,
+            "no tracked files on network or virtual file systems".to_string(),
+        ),
+        (false, true) => Finding::fine(check, format!("{detail}, handled by --network-fs")),
+        (false, false) => Finding::problem(
+            check,
+            detail,
+            "pass --network-fs skip or --network-fs-timeout, or remove them",
+        ),
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_writable() {
+        let dir = tempfile::tempdir().unwrap();
+        assert!(!writable(dir.path()).is_problem());
+        assert!(!writable(&dir.path().join("fimbl").join("db")).is_problem());
+        let file = dir.path().join("file");
+        std::fs::write(&file, "").unwrap();
+        assert!(writable(&file.join("db")).is_problem());
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn test_clock() {
+        let now = SystemTime::now();
+        assert!(!clock(now, None).is_problem());
+        assert!(!clock(now, Some(now + Duration::from_secs(60))).is_problem());
+        assert!(clock(now, Some(now + Duration::from_secs(24 * 60 * 60))).is_problem());
+        assert!(clock(SystemTime::UNIX_EPOCH, None).is_problem());
+    }
+}
@@ -0,0 +1,379 @@
+//! Emailing a digest of findings
+//!
+//! For small deployments without a SIEM: after a scan, findings of at
+//! least a minimum severity are sent to the recipients in one message
+//! over SMTP (with STARTTLS or implicit TLS unless told otherwise).
+
+use crate::{
+    agent::RunReport,
+    output,
+    report::{ReportItem, Severity},
+};
+
+use base64::Engine;
+use rustls::pki_types::ServerName;
+use std::{
+    io::{self, BufRead, BufReader, Read, Write},
+    net::TcpStream,
+    sync::Arc,
+    time::Duration,
+};
+
+/// How long to wait on the mail server
+const SMTP_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How the connection to the mail server is secured
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum SmtpTls {
+    /// Upgrade a plain connection with STARTTLS (usually port 587)
+    Starttls,
+    /// Connect with TLS from the start (usually port 465)
+    Implicit,
+    /// No encryption, e.g. for a relay on localhost
+    None,
+}
+
+impl SmtpTls {
+    /// Conventional port for the mode
+    pub fn default_port(&self) -> u16 {
+        match self {
+            SmtpTls::Starttls => 587,
+            SmtpTls::Implicit => 465,
+            SmtpTls::None => 25,
+        }
+    }
+}
+
+/// Where and to whom to send digests
+#[derive(Clone, Debug)]
+pub struct Mailer {
+    /// Mail server host name
+    pub host: String,
+
+    /// Mail server port
+    pub port: u16,
+
+    /// Connection security
+    pub tls: SmtpTls,
+
+    /// User name and password to authenticate with, if any
+    pub credentials: Option<(String, String)>,
+
+    /// Sender address
+    pub from: String,
+
+    /// Recipient addresses
+    pub to: Vec<String>,
+
+    /// Least severe findings to send
+    pub min_severity: Severity,
+}
+
+/// A connection to the mail server, plain or encrypted
+enum Connection {
+    Plain(TcpStream),
+    Tls(Box<rustls::StreamOwned<rustls::ClientConnection, TcpStream>>),
+}
+
+impl Read for Connection {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Connection::Plain(stream) => stream.read(buf),
+            Connection::Tls(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Connection {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Connection::Plain(stream) => stream.write(buf),
+            Connection::Tls(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Connection::Plain(stream) => stream.flush(),
+            Connection::Tls(stream) => stream.flush(),
+        }
+    }
+}
+
+/// Wrap a connected socket in TLS, verifying the server against the
+/// web PKI roots
+fn tls_connect(host: &str, socket: TcpStream) -> io::Result<Connection> {
+    let invalid = |e: &dyn std::fmt::Display| io::Error::other(e.to_string());
+    let roots = rustls::RootCertStore {
+        roots: webpki_roots::TLS_SERVER_ROOTS.into(),
+    };
+    let config = rustls::ClientConfig::builder_with_provider(Arc::new(
+        rustls::crypto::ring::default_provider(),
+    ))
+    .with_safe_default_protocol_versions()
+    .map_err(|e| invalid(&e))?
+    .with_root_certificates(roots)
+    .with_no_client_auth();
+    let name = ServerName::try_from(host.to_string()).map_err(|e| invalid(&e))?;
+    let connection =
+        rustls::ClientConnection::new(Arc::new(config), name).map_err(|e| invalid(&e))?;
+    Ok(Connection::Tls(Box::new(rustls::StreamOwned::new(
+        connection, socket,
+    ))))
+}
+
+/// An SMTP session
+struct Session {
+    stream: BufReader<Connection>,
+}
+
+impl Session {
+    /// Read a (possibly multi-line) reply, failing unless its code is
+    /// the one expected
+    fn expect(&mut self, code: u16) -> io::Result<String> {
+        let mut reply = String::new();
+        loop {
+            let mut line = String::new();
+            if self.stream.read_line(&mut line)? == 0 {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+            reply.push_str(&line);
+            // the last line of a reply has a space after the code
+            if line.as_bytes().get(3) != Some(&b'-') {
+                break;
+            }
+        }
+        match reply.get(..3).and_then(|c| c.parse::<u16>().ok()) {
+            Some(got) if got == code => Ok(reply),
+            _ => Err(io::Error::other(format!(
+                "mail server replied: {}",
+                reply.trim_end()
+            ))),
+        }
+    }
+
+    /// Send a command and check the reply
+    fn command(&mut self, command: &str, code: u16) -> io::Result<String> {
+        let stream = self.stream.get_mut();
+        stream.write_all(command.as_bytes())?;
+        stream.write_all(b"\r\n")?;
+        stream.flush()?;
+        self.expect(code)
+    }
+}
+
+/// Lines of a message body with CRLF endings and leading dots doubled
+/// (so that no line ends the message early)
+fn dot_stuffed(body: &str) -> String {
+    let mut data = String::new();
+    for line in body.lines() {
+        if line.starts_with('.') {
+            data.push('.');
+        }
+        data.push_str(line);
+        data.push_str("\r\n");
+    }
+    data
+}
+
+impl Mailer {
+    /// Subject and body of the digest of a run's findings, and whether
+    /// it is urgent (having critical findings), if there are any severe
+    /// enough to send
+    fn digest(&self, run: &RunReport) -> Option<(String, String, bool)> {
+        let findings: Vec<ReportItem> = run
+            .items
+            .iter()
+            .filter(|item| item.severity() >= self.min_severity)
+            .cloned()
+            .collect();
+        if findings.is_empty() {
+            return None;
+        }
+
+        let subject = format!("fimbl: {} findings on {}", findings.len(), run.host);
+        let body = format!(
+            "{}\nSummary: {}\n",
+            output::render(&findings, false),
+            run.summary
+        );
+        let urgent = findings
+            .iter()
+            .any(|item| item.severity() == Severity::Critical);
+        Some((subject, body, urgent))
+    }
+
+    /// Send the digest of a run's findings, returning a report item if
+    /// sending failed
+    pub fn notify(&self, run: &RunReport) -> Option<ReportItem> {
+        let (subject, body, urgent) = self.digest(run)?;
+        self.send(&subject, &body, urgent)
+            .err()
+            .map(|e| ReportItem::NotificationFailed {
+                sink: "email".to_string(),
+                message: e.to_string(),
+            })
+    }
+
+    /// Send a message to the recipients, marked high priority if urgent
+    ///
+    /// Date and Message-ID headers are left to the submission server
+    /// to add.
+    fn send(&self, subject: &str, body: &str, urgent: bool) -> io::Result<()> {
+        let socket = TcpStream::connect((self.host.as_str(), self.port))?;
+        socket.set_read_timeout(Some(SMTP_TIMEOUT))?;
+        socket.set_write_timeout(Some(SMTP_TIMEOUT))?;
+        let connection = match self.tls {
+            SmtpTls::Implicit => tls_connect(&self.host, socket)?,
+            _ => Connection::Plain(socket),
+        };
+
+        let mut session = Session {
+            stream: BufReader::new(connection),
+        };
+        let hello = format!("EHLO {}", crate::agent::local_hostname());
+        session.expect(220)?;
+        session.command(&hello, 250)?;
+
+        if self.tls == SmtpTls::Starttls {
+            session.command("STARTTLS", 220)?;
+            let Connection::Plain(socket) = session.stream.into_inner() else {
+                unreachable!("STARTTLS on a plain connection");
+            };
+            session = Session {
+                stream: BufReader::new(tls_connect(&self.host, socket)?),
+            };
+            session.command(&hello, 250)?;
+        }
+
+        if let Some((user, password)) = &self.credentials {
+            let token =
+                base64::engine::general_purpose::STANDARD.encode(format!("\0{user}\0{password}"));
+            session.command(&format!("AUTH PLAIN {token}"), 235)?;
+        }
+
+        session.command(&format!("MAIL FROM:<{}>", self.from), 250)?;
+        for to in &self.to {
+            session.command(&format!("RCPT TO:<{to}>"), 250)?;
+        }
+        session.command("DATA", 354)?;
+        let priority = match urgent {
+            true => "X-Priority: 1\r\nImportance: high\r\n",
+            false => "",
+        };
+        let message = format!(
+            "From: {}\r\nTo: {}\r\nSubject: {}\r\n{}Content-Type: text/plain; charset=utf-8\r\n\r\n{}.",
+            self.from,
+            self.to.join(", "),
+            subject,
+            priority,
+            dot_stuffed(body)
+        );
+        session.command(&message, 250)?;
+        session.command("QUIT", 221)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+
+    use super::*;
+    use crate::report::Summary;
+    use std::{net::TcpListener, path::PathBuf, thread};
+
+    #[test]
+    fn test_digest_sent_over_smtp() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = thread::spawn(move || {
+            let (socket, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(socket.try_clone().unwrap());
+            let mut writer = socket;
+            let mut transcript = String::new();
+            writer.write_all(b"220 test ESMTP\r\n").unwrap();
+            let mut in_data = false;
+            loop {
+                let mut line = String::new();
+                if reader.read_line(&mut line).unwrap() == 0 {
+                    break;
+                }
+                transcript.push_str(&line);
+                let reply: &[u8] = if in_data {
+                    if line != ".\r\n" {
+                        continue;
+                    }
+                    in_data = false;
+                    b"250 queued\r\n"
+                } else if line.starts_with("EHLO") {
+                    b"250-test\r\n250 AUTH PLAIN\r\n"
+                } else if line.starts_with("DATA") {
+                    in_data = true;
+                    b"354 go ahead\r\n"
+                } else if line.starts_with("AUTH") {
+                    b"235 ok\r\n"
+                } else if line.starts_with("QUIT") {
+                    writer.write_all(b"221 bye\r\n").unwrap();
+                    break;
+                } else {
+                    b"250 ok\r\n"
+                };
+                writer.write_all(reply).unwrap();
+            }
+            transcript
+        });
+
+        let mailer = Mailer {
+            host: "127.0.0.1".to_string(),
+            port,
+            tls: SmtpTls::None,
+            credentials: Some(("fimbl".to_string(), "secret".to_string())),
+            from: "fimbl@web1".to_string(),
+            to: vec!["ops@example.com".to_string()],
+            min_severity: Severity::Warning,
+        };
+        let items = vec![
+            ReportItem::FileNotTracked {
+                path: PathBuf::from("/etc/motd"),
+            },
+            ReportItem::FileMissing {
+                path: PathBuf::from("/etc/shadow"),
+            },
+        ];
+        let summary = Summary::new(std::time::Instant::now(), 2, 0, &items);
+        let run = RunReport::new("web1", &items, &summary);
+        assert!(mailer.notify(&run).is_none());
+
+        let transcript = server.join().unwrap();
+        assert!(transcript.contains("AUTH PLAIN AGZpbWJsAHNlY3JldA==\r\n"));
+        assert!(transcript.contains("RCPT TO:<ops@example.com>\r\n"));
+        assert!(transcript.contains("Subject: fimbl: 1 findings on web1\r\n"));
+        assert!(transcript.contains("file is missing: /etc/shadow"));
+        assert!(!transcript.contains("/etc/motd"));
+        assert!(!transcript.contains("X-Priority"));
+    }
+
+    #[test]
+    fn test_digest_urgent_with_critical_findings() {
+        let mailer = Mailer {
+            host: "127.0.0.1".to_string(),
+            port: 25,
+            tls: SmtpTls::None,
+            credentials: None,
+            from: "fimbl@web1".to_string(),
+            to: vec!["ops@example.com".to_string()],
+            min_severity: Severity::Warning,
+        };
+        let items = vec![ReportItem::AnomalousChangeVolume {
+            files: 400,
+            examined: 1000,
+            normal: 0.5,
+        }];
+        let summary = Summary::new(std::time::Instant::now(), 1000, 0, &items);
+        let (_, body, urgent) = mailer
+            .digest(&RunReport::new("web1", &items, &summary))
+            .unwrap();
+        assert!(urgent);
+        assert!(body.contains("400 of 1000 files changed (normally 0.5%)"));
+    }
+}
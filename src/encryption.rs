@@ -0,0 +1,202 @@
+//! Encryption of the database at rest
+
+use crate::error::FimblError;
+
+use aes_gcm::{
+    aead::{rand_core::RngCore, Aead, AeadCore, KeyInit, OsRng, Payload},
+    Aes256Gcm, Key, Nonce,
+};
+use argon2::Argon2;
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use sha3::Sha3_256;
+use std::{env, fs::read, path::Path};
+
+/// Environment variable which may hold the database passphrase
+pub const DATABASE_KEY_VAR: &str = "FIMBL_DB_KEY";
+
+/// Size of AES-GCM nonce prefixed to each sealed value
+const NONCE_SIZE: usize = 12;
+
+/// Size of the random salt kept with each encrypted database
+pub const SALT_SIZE: usize = 16;
+
+/// What the user supplies to open an encrypted database
+#[derive(Clone)]
+pub enum DatabaseKey {
+    /// Key material read from a key file, used as it is
+    Material(Vec<u8>),
+
+    /// A passphrase, stretched with Argon2id before use
+    Passphrase(Vec<u8>),
+}
+
+impl DatabaseKey {
+    /// Read key material from the entire contents of a file
+    pub fn from_file(path: &Path) -> Result<Self, FimblError> {
+        let material = read(path).map_err(|e| FimblError::KeyFileError(path.to_owned(), e))?;
+        if material.is_empty() {
+            Err(FimblError::EmptyKeyFile(path.to_owned()))
+        } else {
+            Ok(DatabaseKey::Material(material))
+        }
+    }
+
+    /// Read a passphrase from the environment, if set
+    pub fn from_env() -> Option<Self> {
+        env::var_os(DATABASE_KEY_VAR)
+            .filter(|v| !v.is_empty())
+            .map(|v| DatabaseKey::Passphrase(v.as_encoded_bytes().to_vec()))
+    }
+}
+
+/// A fresh random salt for a newly encrypted database
+pub fn generate_salt() -> [u8; SALT_SIZE] {
+    let mut salt = [0; SALT_SIZE];
+    OsRng.fill_bytes(&mut salt);
+    salt
+}
+
+/// Encrypts values and obscures keys in an encrypted database
+///
+/// Values are sealed with AES-256-GCM, bound to the tree and key they
+/// are stored under. Paths are replaced in sled keys by an HMAC of the
+/// path, so that neither the keys nor the values reveal which files
+/// are tracked. Both keys are derived with HKDF from the key supplied
+/// by the user (a passphrase first stretched with Argon2id) and the
+/// database's salt.
+pub struct DatabaseCipher {
+    /// AES-256-GCM cipher for values
+    aead: Aes256Gcm,
+
+    /// Key for HMAC of paths
+    path_key: [u8; 32],
+}
+
+impl DatabaseCipher {
+    /// Construct the cipher of a database with the salt given
+    pub fn new(key: &DatabaseKey, salt: &[u8; SALT_SIZE]) -> Self {
+        let hkdf = match key {
+            DatabaseKey::Material(material) => Hkdf::<Sha256>::new(Some(salt), material),
+            DatabaseKey::Passphrase(passphrase) => {
+                let mut stretched = [0; 32];
+                Argon2::default()
+                    .hash_password_into(passphrase, salt, &mut stretched)
+                    .expect("Argon2 accepts database salts");
+                Hkdf::<Sha256>::new(Some(salt), &stretched)
+            }
+        };
+        let derive = |purpose: &[u8]| {
+            let mut key = [0; 32];
+            hkdf.expand(purpose, &mut key)
+                .expect("HKDF expands to 32 bytes");
+            key
+        };
+        DatabaseCipher {
+            aead: Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&derive(
+                b"fimbl database values",
+            ))),
+            path_key: derive(b"fimbl database keys"),
+        }
+    }
+
+    /// Obscured form of a database key
+    pub fn obscure_key(&self, key: &[u8]) -> Vec<u8> {
+        let mut mac = <Hmac<Sha3_256> as Mac>::new_from_slice(&self.path_key)
+            .expect("HMAC accepts keys of any size");
+        mac.update(key);
+        mac.finalize().into_bytes().to_vec()
+    }
+
+    /// Encrypt a value to be stored under a key of a tree, prefixing
+    /// a fresh random nonce
+    pub fn seal(&self, tree: &str, key: &[u8], plaintext: &[u8]) -> Vec<u8> {
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let payload = Payload {
+            msg: plaintext,
+            aad: &associated_data(tree, key),
+        };
+        let mut sealed = nonce.to_vec();
+        sealed.extend(
+            self.aead
+                .encrypt(&nonce, payload)
+                .expect("AES-GCM encryption of in-memory value"),
+        );
+        sealed
+    }
+
+    /// Decrypt and authenticate a value sealed for the key of the tree
+    /// it is stored under
+    pub fn open(&self, tree: &str, key: &[u8], sealed: &[u8]) -> Result<Vec<u8>, FimblError> {
+        if sealed.len() < NONCE_SIZE {
+            return Err(FimblError::DecryptionError);
+        }
+        let (nonce, ciphertext) = sealed.split_at(NONCE_SIZE);
+        let payload = Payload {
+            msg: ciphertext,
+            aad: &associated_data(tree, key),
+        };
+        self.aead
+            .decrypt(Nonce::from_slice(nonce), payload)
+            .map_err(|_| FimblError::DecryptionError)
+    }
+}
+
+/// Data a sealed value is bound to: the tree and key it is stored
+/// under, so that it can't be moved to another unnoticed
+fn associated_data(tree: &str, key: &[u8]) -> Vec<u8> {
+    [tree.as_bytes(), &[0], key].concat()
+}
+
+#[cfg(test)]
+pub mod tests {
+
+    use super::*;
+
+    const SALT: &[u8; SALT_SIZE] = b"0123456789abcdef";
+
+    fn cipher(material: &[u8]) -> DatabaseCipher {
+        DatabaseCipher::new(&DatabaseKey::Material(material.to_vec()), SALT)
+    }
+
+    #[test]
+    fn test_seal_round_trip() {
+        let cipher = cipher(b"secret");
+        let sealed = cipher.seal("fingerprints", b"k", b"/etc/passwd");
+        assert_ne!(&sealed[NONCE_SIZE..], b"/etc/passwd");
+        assert_eq!(
+            cipher.open("fingerprints", b"k", &sealed).unwrap(),
+            b"/etc/passwd"
+        );
+    }
+
+    #[test]
+    fn test_wrong_key_fails_to_open() {
+        let sealed = cipher(b"secret").seal("fingerprints", b"k", b"value");
+        assert!(cipher(b"other")
+            .open("fingerprints", b"k", &sealed)
+            .is_err());
+
+        let key = DatabaseKey::Material(b"secret".to_vec());
+        let salted = DatabaseCipher::new(&key, b"fedcba9876543210");
+        assert!(salted.open("fingerprints", b"k", &sealed).is_err());
+        let passphrase = DatabaseCipher::new(&DatabaseKey::Passphrase(b"secret".to_vec()), SALT);
+        assert!(passphrase.open("fingerprints", b"k", &sealed).is_err());
+    }
+
+    #[test]
+    fn test_moved_values_fail_to_open() {
+        let cipher = cipher(b"secret");
+        let sealed = cipher.seal("fingerprints", b"k", b"value");
+        assert!(cipher.open("fingerprints", b"j", &sealed).is_err());
+        assert!(cipher.open("history", b"k", &sealed).is_err());
+    }
+
+    #[test]
+    fn test_obscured_keys_are_deterministic() {
+        let cipher = cipher(b"secret");
+        assert_eq!(cipher.obscure_key(b"/etc"), cipher.obscure_key(b"/etc"));
+        assert_ne!(cipher.obscure_key(b"/etc"), cipher.obscure_key(b"/usr"));
+    }
+}
@@ -1,6 +1,6 @@
 //! Fimbl error type
 
-use std::io;
+use std::{io, path::PathBuf};
 
 use thiserror::Error;
 
@@ -12,8 +12,154 @@ use thiserror::Error;
 pub enum FimblError {
     #[error("database access error")]
     DatabaseError(#[from] sled::Error),
+    #[error(
+        "database {} is busy: locked by another fimbl process{} (retry with --lock-wait or use --database)",
+        .0.display(),
+        holder(.1)
+    )]
+    DatabaseBusy(PathBuf, Option<u32>),
+    #[error("cannot open database {}: check its permissions or use --database", .0.display())]
+    DatabaseUnopenable(PathBuf, #[source] io::Error),
+    #[error("database is encrypted: supply --db-key-file or FIMBL_DB_KEY")]
+    DatabaseEncrypted,
+    #[error("wrong key for encrypted database")]
+    WrongDatabaseKey,
+    #[error("database key supplied but existing database is not encrypted")]
+    DatabaseNotEncrypted,
+    #[error("cannot decrypt database record")]
+    DecryptionError,
+    #[error("database schema version {0} is newer than this fimbl supports ({1}): upgrade fimbl")]
+    DatabaseTooNew(u32, u32),
+    #[error("database schema version is unreadable")]
+    SchemaVersionInvalid,
+    #[error("not confirmed: nothing accepted")]
+    NotConfirmed,
+    #[error("database already contains fingerprints")]
+    DatabaseNotEmpty,
+    #[error("backup archive {} is corrupt", .0.display())]
+    BackupCorrupt(PathBuf),
+    #[error("operation not supported on a remote or flat-file database")]
+    NotSupportedRemotely,
+    #[error("remote database error: {0}")]
+    RemoteError(String),
+    #[error("object store error: {0}")]
+    ObjectStoreError(String),
+    #[error("baseline is corrupt or not signed with this key")]
+    BaselineInvalid,
+    #[error("plan is corrupt or not signed with this key")]
+    PlanInvalid,
+    #[error("a plan can only be made of a command that tracks, accepts or removes files")]
+    PlanUnsupported,
+    #[error("plan is stale: the record of {} has changed since it was made", .0.display())]
+    PlanStale(PathBuf),
+    #[error("server error: {0}")]
+    ServerError(String),
     #[error("bad fingerprint in database")]
     FingerprintDeserializationError(#[from] rmp_serde::decode::Error),
+    #[error("invalid path key in database")]
+    InvalidPathKey,
     #[error("error while accessing file for fingerprinting")]
     FileAccessError(#[from] io::Error),
+    #[error("cannot read key file {}", .0.display())]
+    KeyFileError(PathBuf, #[source] io::Error),
+    #[error("cannot read the mount table")]
+    MountTableError(#[source] io::Error),
+    #[error("cannot lower process priority")]
+    PriorityError(#[source] io::Error),
+    #[error("cannot write output file {}", .0.display())]
+    OutputError(PathBuf, #[source] io::Error),
+    #[error("cannot use control socket {}: is an agent running with it?", .0.display())]
+    ControlSocketError(PathBuf, #[source] io::Error),
+    #[error("unexpected answer on the control socket: {0}")]
+    ControlReplyInvalid(String),
+    #[error("the agent refused: {0}")]
+    ControlRefused(String),
+    #[error("invalid config file {}: {1}", .0.display())]
+    ConfigError(PathBuf, String),
+    #[error("cannot drive the terminal")]
+    TerminalError(#[source] io::Error),
+    #[error("cannot report to {0}: {1}")]
+    ReportSinkError(String, String),
+    #[error("key file {} is empty", .0.display())]
+    EmptyKeyFile(PathBuf),
+    #[error("key file {} is not an Ed25519 private key (PKCS#8, PEM)", .0.display())]
+    AttestKeyInvalid(PathBuf),
+    #[error("no snapshot named {0}")]
+    SnapshotNotFound(String),
+    #[error("a snapshot named {0} already exists: delete it first")]
+    SnapshotExists(String),
+    #[error("TPM error: {0}")]
+    TpmError(String),
+    #[error("git error: {0}")]
+    GitError(String),
+    #[error("database records are MACed: supply --record-key-file")]
+    RecordKeyRequired,
+    #[error("wrong record key for database")]
+    WrongRecordKey,
+    #[error("no record key is enrolled for the database: enrol one with enrol-record-key")]
+    RecordKeyNotEnrolled,
+    #[error("a record key is already enrolled for the database")]
+    RecordKeyEnrolled,
+    #[error("give the record key to enrol with --record-key-file or --record-key-tpm")]
+    RecordKeyNotGiven,
+    #[error("record of {} was not written by fimbl with the record key: check with fsck", .0.display())]
+    RecordTampered(PathBuf),
+    #[error("cannot read anchor file {}", .0.display())]
+    AnchorFileError(PathBuf, #[source] io::Error),
+    #[error("no root hash anchored for {1} in {}", .0.display())]
+    NotAnchored(PathBuf, String),
+    #[error("database is append-only: pass --force-unsafe to remove, accept, rename, roll back or restore")]
+    AppendOnly,
+    #[error("no change pending approval with token {0}")]
+    PendingNotFound(String),
+    #[error("approval needs the approval key: supply --sign-key-file")]
+    ApprovalKeyRequired,
+    #[error("wrong approval key")]
+    WrongApprovalKey,
+    #[error("approval key must be set when two-phase accept is first required")]
+    ApprovalKeyTooLate,
+    #[error("the Unicode form of paths can only be changed before any file is recorded")]
+    UnicodePathsTooLate,
+    #[error("the database records paths by the {0} path policy, not {1}")]
+    PathPolicyMismatch(String, String),
+    #[error("changes to this database must be approved, so it can't be restored over")]
+    RestoreNeedsApproval,
+    #[error("change accepted by {0} must be approved by another user")]
+    SelfApproval(String),
+    #[error("record of {} has changed since this change was accepted: reject it and accept again", .0.display())]
+    ApprovalStale(PathBuf),
+    #[error("database {} is corrupt or has been tampered with at line {1} (or was signed with another record key)", .0.display())]
+    FlatFileInvalid(PathBuf, usize),
+    #[error("--root is only for verify, verify-all, check and osquery")]
+    RootOnlyForVerifying,
+    #[error("--dry-run is only for commands that change the records (but not restore)")]
+    DryRunOnlyForChanges,
+    #[error("no kind of report item has code {0} (see fimbl explain)")]
+    UnknownCode(String),
+    #[error("container image {} is unreadable: {1}", .0.display())]
+    ImageInvalid(PathBuf, String),
+    #[error("cannot import {}: {1}", .0.display())]
+    ImportInvalid(PathBuf, String),
+    #[error("invalid interval: {0}")]
+    IntervalInvalid(String),
+    #[error("the manifest lists no files under the directories given")]
+    NothingToGate,
+}
+
+/// Description of the process holding a lock, if known
+fn holder(pid: &Option<u32>) -> String {
+    pid.map(|pid| format!(" (pid {pid})")).unwrap_or_default()
+}
+
+impl FimblError {
+    /// Process exit code for this error, distinguishing database
+    /// access problems (which a retry or a different `--database` may
+    /// fix) from other failures
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            FimblError::DatabaseBusy(..) => 3,
+            FimblError::DatabaseUnopenable(..) => 4,
+            _ => 1,
+        }
+    }
 }
@@ -0,0 +1,503 @@
+//! What each kind of report item means
+//!
+//! Every kind of report item has a stable code (`F001` and so on),
+//! carried in each output format and sink so that automation can match
+//! on it rather than on the wording. The code's letter groups the
+//! kinds: F for changes to files, A for changes to their attributes, S
+//! for a scan as a whole, D for the database, C for the outcome of a
+//! command and E for the environment. Codes are never reused.
+
+use crate::error::FimblError;
+
+/// What a kind of report item means and what to do about it
+pub struct Explanation {
+    pub code: &'static str,
+    /// Kebab-case kind, as in JSON
+    pub kind: &'static str,
+    pub severity: &'static str,
+    pub summary: &'static str,
+    pub advice: &'static str,
+}
+
+/// Every kind of report item, by code
+pub const EXPLANATIONS: &[Explanation] = &[
+    Explanation {
+        code: "F001",
+        kind: "file-content-changed",
+        severity: "warning",
+        summary: "The file's contents (or attributes fingerprinted with them) have changed.",
+        advice: "Find out who changed it and why; if the change is expected, `fimbl accept` it.",
+    },
+    Explanation {
+        code: "F002",
+        kind: "file-size-changed",
+        severity: "warning",
+        summary: "The file's size has changed, so its contents have too.",
+        advice: "Find out who changed it and why; if the change is expected, `fimbl accept` it.",
+    },
+    Explanation {
+        code: "F003",
+        kind: "file-missing",
+        severity: "warning",
+        summary: "A tracked file no longer exists.",
+        advice: "Restore it, or `fimbl remove` it if it was removed on purpose.",
+    },
+    Explanation {
+        code: "F004",
+        kind: "file-replaced",
+        severity: "warning",
+        summary: "The path now refers to a different file (device and inode), \
+                  as when a file is replaced rather than written in place.",
+        advice: "Check the new file; editors and package managers often replace files, \
+                 so accept it if the replacement is expected.",
+    },
+    Explanation {
+        code: "F005",
+        kind: "file-is-directory",
+        severity: "warning",
+        summary: "A tracked file is now a directory.",
+        advice: "Find out why the file was replaced by a directory.",
+    },
+    Explanation {
+        code: "F006",
+        kind: "file-moved",
+        severity: "warning",
+        summary: "A missing file has turned up, with its recorded contents, at another path.",
+        advice: "If the move is expected, accept it to track the file at its new path.",
+    },
+    Explanation {
+        code: "F007",
+        kind: "directory-entries-changed",
+        severity: "warning",
+        summary: "Entries have been added to, removed from or renamed in a directory \
+                  tracked as an entry.",
+        advice: "List the directory to see what has changed, and accept it if expected.",
+    },
+    Explanation {
+        code: "F008",
+        kind: "entropy-increased",
+        severity: "warning",
+        summary: "The contents have gone from plaintext to looking encrypted (or compressed).",
+        advice: "Check the file: ransomware leaves files like this, as does compressing them.",
+    },
+    Explanation {
+        code: "F009",
+        kind: "differs-from-commit",
+        severity: "warning",
+        summary: "The file in a git working tree differs from (or isn't in) the commit at HEAD.",
+        advice: "Check the change with git diff (or git status): commit it if intended, \
+                 otherwise restore the file with git checkout.",
+    },
+    Explanation {
+        code: "F010",
+        kind: "alias-redirected",
+        severity: "warning",
+        summary: "Another path a file was given by now leads to a different file than the \
+                  one recorded, as when a symlinked directory on the way has been repointed.",
+        advice: "Check where the symlinks on the way lead now, and whether that was intended.",
+    },
+    Explanation {
+        code: "F011",
+        kind: "symlink-target-changed",
+        severity: "warning",
+        summary: "A symlink now points somewhere else, whether or not the file there has \
+                  the same contents.",
+        advice: "Check where the link points now and who changed it: a repointed link can \
+                 swap in a look-alike file unnoticed.",
+    },
+    Explanation {
+        code: "F012",
+        kind: "file-not-in-manifest",
+        severity: "warning",
+        summary: "A file is in a directory the manifest covers, but the manifest does not \
+                  list it.",
+        advice: "Check where the file came from: something may have been added to a volume \
+                 after the manifest was made.",
+    },
+    Explanation {
+        code: "A001",
+        kind: "immutable-flag-removed",
+        severity: "critical",
+        summary: "The immutable flag has been removed, often the first step in tampering \
+                  with a protected file.",
+        advice: "Treat as a likely intrusion: find out who removed the flag, \
+                 and check the file and its neighbours.",
+    },
+    Explanation {
+        code: "A002",
+        kind: "file-flags-changed",
+        severity: "warning",
+        summary: "The file's immutable or append-only flags have otherwise changed.",
+        advice: "Find out who changed the flags, and accept it if expected.",
+    },
+    Explanation {
+        code: "A003",
+        kind: "xattr-added",
+        severity: "warning",
+        summary: "A tracked extended attribute (e.g. quarantine) has appeared.",
+        advice: "Check where the file came from; accept it if expected.",
+    },
+    Explanation {
+        code: "A004",
+        kind: "xattr-changed",
+        severity: "warning",
+        summary: "A tracked extended attribute's value has changed.",
+        advice: "Find out what changed the attribute, and accept it if expected.",
+    },
+    Explanation {
+        code: "A005",
+        kind: "xattr-removed",
+        severity: "warning",
+        summary: "A tracked extended attribute has gone.",
+        advice: "Removing a quarantine attribute bypasses Gatekeeper: \
+                 find out who removed it.",
+    },
+    Explanation {
+        code: "A006",
+        kind: "stream-added",
+        severity: "warning",
+        summary: "An NTFS alternate data stream has appeared on the file.",
+        advice: "Streams can hide data or code: inspect it, and accept it if expected.",
+    },
+    Explanation {
+        code: "A007",
+        kind: "stream-changed",
+        severity: "warning",
+        summary: "An NTFS alternate data stream's contents have changed.",
+        advice: "Inspect the stream, and accept it if expected.",
+    },
+    Explanation {
+        code: "A008",
+        kind: "stream-removed",
+        severity: "warning",
+        summary: "An NTFS alternate data stream has gone.",
+        advice: "Removing a Zone.Identifier stream hides where a file came from: \
+                 find out who removed it.",
+    },
+    Explanation {
+        code: "S001",
+        kind: "mass-encryption-suspected",
+        severity: "critical",
+        summary: "Many files jumped to high entropy in the same run, suggesting ransomware.",
+        advice: "Isolate the host and investigate before accepting anything.",
+    },
+    Explanation {
+        code: "S002",
+        kind: "anomalous-change-volume",
+        severity: "critical",
+        summary: "A scan found changes to a far larger fraction of the files examined \
+                  than scans normally do.",
+        advice: "Check whether an upgrade or deployment explains it; \
+                 if not, suspect mass tampering.",
+    },
+    Explanation {
+        code: "D001",
+        kind: "record-tampered",
+        severity: "critical",
+        summary: "The file's record in the database was not written by fimbl with the \
+                  record key.",
+        advice: "The database has been tampered with: restore it from a backup \
+                 and check the file.",
+    },
+    Explanation {
+        code: "D002",
+        kind: "root-hash-mismatch",
+        severity: "critical",
+        summary: "The database's root hash is not the one last anchored, so it has been \
+                  changed other than by fimbl (or without anchoring).",
+        advice: "Find out what changed the database; restore it from a backup if unsure.",
+    },
+    Explanation {
+        code: "D003",
+        kind: "corrupt-entry",
+        severity: "warning (info once removed)",
+        summary: "A database entry is corrupt.",
+        advice: "Run `fimbl fsck --repair` to remove it, then add the file again.",
+    },
+    Explanation {
+        code: "D004",
+        kind: "hash-algorithm-mismatch",
+        severity: "info",
+        summary: "The recorded fingerprint was hashed differently (e.g. with a key), \
+                  so it cannot be compared.",
+        advice: "Verify with the settings the file was added with, or accept it to \
+                 record it afresh.",
+    },
+    Explanation {
+        code: "D005",
+        kind: "only-in-database",
+        severity: "info",
+        summary: "The file is tracked in only one of two databases compared.",
+        advice: "Merge the databases if both should track it.",
+    },
+    Explanation {
+        code: "D006",
+        kind: "databases-disagree",
+        severity: "info",
+        summary: "Two databases compared disagree on the file's fingerprint.",
+        advice: "Find out which is right, and merge the databases.",
+    },
+    Explanation {
+        code: "D007",
+        kind: "merge-conflict",
+        severity: "info",
+        summary: "Databases being merged disagree on the file's fingerprint.",
+        advice: "Check the fingerprint kept is the right one.",
+    },
+    Explanation {
+        code: "C001",
+        kind: "file-already-tracked",
+        severity: "info",
+        summary: "The file given to add is already tracked.",
+        advice: "Nothing to do; use `fimbl accept` to record changes to it.",
+    },
+    Explanation {
+        code: "C002",
+        kind: "file-not-tracked",
+        severity: "info",
+        summary: "The file given is not tracked.",
+        advice: "Add it with `fimbl add` to track it.",
+    },
+    Explanation {
+        code: "C003",
+        kind: "content-change-not-accepted",
+        severity: "warning",
+        summary: "Modifications to the file were not accepted as its contents have \
+                  changed (when accepting metadata only, or since accept-all listed it).",
+        advice: "Check the contents, and accept them (without `--metadata-only`) if \
+                 expected.",
+    },
+    Explanation {
+        code: "C004",
+        kind: "rename-refused",
+        severity: "warning",
+        summary: "A tracked file was not renamed as the file at its new path has \
+                  different contents.",
+        advice: "Check the file at the new path, and add it if expected.",
+    },
+    Explanation {
+        code: "C005",
+        kind: "accept-pending",
+        severity: "info",
+        summary: "A change was accepted but must be approved before it is recorded.",
+        advice: "Have another user `fimbl approve` it with the token given.",
+    },
+    Explanation {
+        code: "C006",
+        kind: "permissions-restored",
+        severity: "info",
+        summary: "Recorded permissions and ownership were (or would be) restored.",
+        advice: "Nothing to do.",
+    },
+    Explanation {
+        code: "C007",
+        kind: "preset-file-unreadable",
+        severity: "info",
+        summary: "A file in a preset could not be read, so was not added.",
+        advice: "Add it as a user who can read it, if it should be tracked.",
+    },
+    Explanation {
+        code: "C008",
+        kind: "file-note",
+        severity: "info",
+        summary: "The note on a file found to have changed.",
+        advice: "Read it when triaging the change.",
+    },
+    Explanation {
+        code: "C009",
+        kind: "file-name-not-supported",
+        severity: "info",
+        summary: "The file's name cannot be stored (e.g. it is not valid Unicode).",
+        advice: "Rename the file if it should be tracked.",
+    },
+    Explanation {
+        code: "C010",
+        kind: "would-track",
+        severity: "info",
+        summary: "On a dry run, the file would have been added.",
+        advice: "Run again without --dry-run to add it.",
+    },
+    Explanation {
+        code: "C011",
+        kind: "would-retract",
+        severity: "info",
+        summary: "On a dry run, the file's record would have been removed.",
+        advice: "Run again without --dry-run to remove it.",
+    },
+    Explanation {
+        code: "C012",
+        kind: "would-accept",
+        severity: "info",
+        summary:
+            "On a dry run, the file's changes would have been accepted (or held pending approval).",
+        advice: "Run again without --dry-run to accept them.",
+    },
+    Explanation {
+        code: "C013",
+        kind: "import-unverifiable",
+        severity: "warning",
+        summary: "A file in the baseline imported has no hash recorded (SHA-512, SHA-256 \
+                  or SHA-1) to check its contents against, or is a symlink, with no \
+                  contents to check, so was not imported.",
+        advice: "Check the file by other means, and add it if it is as expected.",
+    },
+    Explanation {
+        code: "C014",
+        kind: "would-rename",
+        severity: "info",
+        summary: "On a dry run, the file's record would have been moved to its new path.",
+        advice: "Run again without --dry-run to move it.",
+    },
+    Explanation {
+        code: "C015",
+        kind: "would-annotate",
+        severity: "info",
+        summary: "On a dry run, the file's note would have been set (or cleared).",
+        advice: "Run again without --dry-run to set it.",
+    },
+    Explanation {
+        code: "C016",
+        kind: "would-remove-entry",
+        severity: "info",
+        summary: "On a dry run, fsck --repair would have removed the corrupt entry.",
+        advice: "Run again without --dry-run to remove it.",
+    },
+    Explanation {
+        code: "C017",
+        kind: "would-roll-back",
+        severity: "info",
+        summary: "On a dry run, the file's record would have been put back as it was in \
+                  the snapshot.",
+        advice: "Run again without --dry-run to roll it back.",
+    },
+    Explanation {
+        code: "E001",
+        kind: "network-file-system",
+        severity: "warning",
+        summary: "The file is on a network or virtual file system, \
+                  where changes may be made elsewhere.",
+        advice: "Track it on the host serving it, or skip such files.",
+    },
+    Explanation {
+        code: "E002",
+        kind: "network-file-skipped",
+        severity: "info",
+        summary: "The file is on a network or virtual file system and was not read.",
+        advice: "Track it on the host serving it.",
+    },
+    Explanation {
+        code: "E003",
+        kind: "file-read-timeout",
+        severity: "warning",
+        summary: "Reading the file took longer than its time limit, so it could not \
+                  be fingerprinted.",
+        advice: "Check the storage is healthy, or allow longer reads.",
+    },
+    Explanation {
+        code: "E004",
+        kind: "hook-failed",
+        severity: "info",
+        summary: "An external hook command failed.",
+        advice: "Run the hook by hand to see why.",
+    },
+    Explanation {
+        code: "E005",
+        kind: "notification-failed",
+        severity: "info",
+        summary: "A notification (e.g. email) could not be sent.",
+        advice: "Check the notification settings and that the server is reachable.",
+    },
+    Explanation {
+        code: "E006",
+        kind: "file-changed-during-read",
+        severity: "warning",
+        summary: "The file changed or was replaced while it was read, so what was \
+                  read may not be what is there.",
+        advice: "Verify it again once it is left alone; if it keeps changing, find \
+                 out what is writing it.",
+    },
+    Explanation {
+        code: "E007",
+        kind: "reads-disagree",
+        severity: "warning",
+        summary: "Two reads of the file gave different contents, though it didn't \
+                  change in between: the storage, not the file, is suspect.",
+        advice: "Check the disk's health and the file system, then verify again.",
+    },
+    Explanation {
+        code: "E008",
+        kind: "file-unreadable",
+        severity: "warning",
+        summary: "The file could not be fingerprinted: it could not be opened or read, \
+                  or went while it was being looked at.",
+        advice: "Check that fimbl may read it, and verify it again.",
+    },
+];
+
+/// The explanation of a code or kind (in any case)
+pub fn explain(code_or_kind: &str) -> Result<&'static Explanation, FimblError> {
+    EXPLANATIONS
+        .iter()
+        .find(|explanation| {
+            explanation.code.eq_ignore_ascii_case(code_or_kind)
+                || explanation.kind.eq_ignore_ascii_case(code_or_kind)
+        })
+        .ok_or_else(|| FimblError::UnknownCode(code_or_kind.to_string()))
+}
+
+impl std::fmt::Display for Explanation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "{} {} ({})", self.code, self.kind, self.severity)?;
+        writeln!(f, "{}", self.summary)?;
+        write!(f, "{}", self.advice)
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+
+    use super::*;
+    use crate::report::ReportItem;
+    use std::{collections::BTreeSet, path::PathBuf};
+
+    #[test]
+    fn test_explanations() {
+        let codes: BTreeSet<_> = EXPLANATIONS.iter().map(|e| e.code).collect();
+        let kinds: BTreeSet<_> = EXPLANATIONS.iter().map(|e| e.kind).collect();
+        assert_eq!(codes.len(), EXPLANATIONS.len());
+        assert_eq!(kinds.len(), EXPLANATIONS.len());
+
+        let items = [
+            ReportItem::FileMissing {
+                path: PathBuf::from("/etc/hosts"),
+            },
+            ReportItem::AnomalousChangeVolume {
+                files: 400,
+                examined: 1000,
+                normal: 0.5,
+            },
+            ReportItem::HookFailed {
+                hook: "on-change".to_string(),
+                message: "exit status: 1".to_string(),
+            },
+        ];
+        for item in &items {
+            let explanation = explain(item.code()).unwrap();
+            assert_eq!(explanation.kind, item.kind());
+            assert_eq!(explanation.severity, item.kind_severity().name());
+
+            let json = serde_json::to_value(item).unwrap();
+            assert_eq!(json["code"], item.code());
+            assert_eq!(json["kind"], item.kind());
+            let read: ReportItem = serde_json::from_value(json).unwrap();
+            assert_eq!(read.to_string(), item.to_string());
+        }
+        let json = serde_json::to_value(&items[1]).unwrap();
+        assert!(json["path"].is_null());
+        assert_eq!(json["details"]["files"], 400);
+
+        assert_eq!(explain("f003").unwrap().kind, "file-missing");
+        assert_eq!(explain("file-missing").unwrap().code, "F003");
+        assert!(matches!(explain("F999"), Err(FimblError::UnknownCode(_))));
+    }
+}
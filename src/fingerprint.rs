@@ -1,92 +1,2246 @@
 //! Hashing file content and attributes
 
-use crate::error::FimblError;
+use crate::{error::FimblError, mounts::Mounts, throttle::Throttle};
 
+use hmac::{Hmac, Mac};
+use sha1::Sha1;
+use sha2::{Sha256, Sha512};
 use sha3::{Digest, Sha3_256};
-use std::os::unix::fs::PermissionsExt;
+#[cfg(unix)]
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
 use std::{
-    fs::{symlink_metadata, File, Metadata},
+    collections::{HashMap, VecDeque},
+    ffi::OsString,
+    fs::{read, read_link, symlink_metadata, File, Metadata},
     io::{self, Read},
-    path::Path,
-    time::SystemTime,
+    path::{Component, Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        mpsc, Arc,
+    },
+    thread,
+    time::{Duration, SystemTime},
 };
+use tracing::{debug, warn};
 
 type Hash = Sha3_256;
 const HASH_SIZE: usize = 32;
 pub type HashValue = [u8; HASH_SIZE];
 
+/// Device and inode of a hardlinked file, with how it was hashed
+/// (chunk size, and what else was wanted of the contents)
+type HardlinkKey = ((u64, u64), Option<u64>, Wanted);
+
+/// What is wanted of file contents besides their hash
+#[derive(Clone, Copy, Default, PartialEq, Eq, Hash, Debug)]
+struct Wanted {
+    /// A TLSH fuzzy hash
+    fuzzy: bool,
+
+    /// A digest of the contents by another algorithm
+    digest: Option<DigestAlgorithm>,
+}
+
+/// Hash algorithms other tools digest contents with, which can be
+/// taken alongside fimbl's own, strongest first
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+pub enum DigestAlgorithm {
+    Sha512,
+    Sha256,
+    Sha1,
+}
+
+impl DigestAlgorithm {
+    fn hasher(self) -> Box<dyn sha2::digest::DynDigest> {
+        match self {
+            DigestAlgorithm::Sha512 => Box::new(Sha512::default()),
+            DigestAlgorithm::Sha256 => Box::new(Sha256::default()),
+            DigestAlgorithm::Sha1 => Box::new(Sha1::default()),
+        }
+    }
+}
+
+/// Results of hashing a file's contents
+#[derive(Clone)]
+struct Hashed {
+    content_hash: HashValue,
+
+    /// Hashes of each chunk, if hashed in chunks
+    chunks: Option<Vec<HashValue>>,
+
+    /// Entropy in thousandths of a bit per byte
+    entropy: Option<u16>,
+
+    /// TLSH fuzzy hash, if wanted (and the contents allow one)
+    fuzzy_hash: Option<String>,
+
+    /// Digest of the contents by another algorithm, if wanted
+    digest: Option<Vec<u8>>,
+}
+
+/// Files with less entropy than this (in thousandths of a bit per
+/// byte) are taken to be plaintext, e.g. text or executables
+const LOW_ENTROPY: u16 = 6000;
+
+/// Files with at least this much entropy look compressed or encrypted
+const HIGH_ENTROPY: u16 = 7500;
+
+/// Smallest file whose entropy is worth comparing (the estimate for
+/// fewer bytes is unreliable)
+const MIN_ENTROPY_SIZE: u64 = 4096;
+
+impl Hashed {
+    /// Contents of a special file, which are never read
+    fn nothing(content_hash: HashValue) -> Self {
+        Hashed {
+            content_hash,
+            chunks: None,
+            entropy: None,
+            fuzzy_hash: None,
+            digest: None,
+        }
+    }
+
+    /// The contents as recorded in a fingerprint, taken as read
+    fn recorded(recorded: &Fingerprint) -> Self {
+        Hashed {
+            content_hash: recorded.content_hash,
+            chunks: recorded.block_hashes.clone(),
+            entropy: recorded.entropy,
+            fuzzy_hash: recorded.fuzzy_hash.clone(),
+            digest: None,
+        }
+    }
+}
+
+/// Statistics gathered from file contents while hashing them
+#[derive(Default)]
+struct ContentStats {
+    /// Counts of each byte value
+    counts: ByteCounts,
+
+    /// Fuzzy hash under construction, if wanted
+    tlsh: Option<Box<tlsh2::TlshDefaultBuilder>>,
+
+    /// Digest by another algorithm under construction, if wanted
+    digest: Option<Box<dyn sha2::digest::DynDigest>>,
+}
+
+impl ContentStats {
+    fn new(wanted: Wanted) -> Self {
+        ContentStats {
+            counts: ByteCounts::default(),
+            tlsh: wanted.fuzzy.then(Box::default),
+            digest: wanted.digest.map(DigestAlgorithm::hasher),
+        }
+    }
+
+    fn add(&mut self, bytes: &[u8]) {
+        self.counts.add(bytes);
+        if let Some(tlsh) = &mut self.tlsh {
+            tlsh.update(bytes);
+        }
+        if let Some(digest) = &mut self.digest {
+            digest.update(bytes);
+        }
+    }
+
+    /// Whether the contents must be seen in order
+    fn sequential(&self) -> bool {
+        self.tlsh.is_some() || self.digest.is_some()
+    }
+
+    /// Take the hashes of chunks hashed separately, adding up their
+    /// byte counts
+    fn merge_chunks(&mut self, hashed: Vec<(HashValue, ByteCounts)>) -> Vec<HashValue> {
+        hashed
+            .into_iter()
+            .map(|(hash, counts)| {
+                self.counts.merge(&counts);
+                hash
+            })
+            .collect()
+    }
+
+    /// TLSH of the contents, if wanted and they are long and varied
+    /// enough to have one
+    fn fuzzy_hash(&self) -> Option<String> {
+        let tlsh = self.tlsh.as_ref()?.build()?;
+        Some(String::from_utf8_lossy(&tlsh.hash()).into_owned())
+    }
+}
+
+/// Counts of each byte value in file contents, for estimating their
+/// entropy
+#[derive(Clone)]
+struct ByteCounts([u64; 256]);
+
+impl Default for ByteCounts {
+    fn default() -> Self {
+        ByteCounts([0; 256])
+    }
+}
+
+impl ByteCounts {
+    fn add(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0[*byte as usize] += 1;
+        }
+    }
+
+    fn merge(&mut self, other: &ByteCounts) {
+        for (count, more) in self.0.iter_mut().zip(other.0) {
+            *count += more;
+        }
+    }
+
+    /// Number of bytes counted
+    fn total(&self) -> u64 {
+        self.0.iter().sum()
+    }
+
+    /// Shannon entropy of the bytes counted, in thousandths of a bit
+    /// per byte (0 to 8000)
+    fn entropy(&self) -> u16 {
+        let total = self.total() as f64;
+        let bits: f64 = self
+            .0
+            .iter()
+            .filter(|count| **count > 0)
+            .map(|count| {
+                let p = *count as f64 / total;
+                -p * p.log2()
+            })
+            .sum();
+        (bits * 1000.0).round() as u16
+    }
+}
+
+/// Size of the chunks large files are hashed in
+pub const CHUNK_SIZE: u64 = 64 << 20;
+
+/// Files of at least this size are newly fingerprinted in chunks
+pub const CHUNKED_THRESHOLD: u64 = 4 * CHUNK_SIZE;
+
+/// Name and content hash of a named part of a file, such as an NTFS
+/// alternate data stream or an extended attribute
+pub type NamedHash = (String, HashValue);
+
+/// How a named part of a file differs from that recorded
+#[derive(PartialEq, Eq, Debug)]
+pub enum NamedChange {
+    Added,
+    Changed,
+    Removed,
+}
+
+/// Differences between recorded and current named hashes, both
+/// sorted by name
+pub fn named_changes(recorded: &[NamedHash], current: &[NamedHash]) -> Vec<(String, NamedChange)> {
+    let recorded: HashMap<_, _> = recorded.iter().cloned().collect();
+    let mut changes = vec![];
+
+    for (name, hash) in current {
+        match recorded.get(name) {
+            None => changes.push((name.clone(), NamedChange::Added)),
+            Some(recorded_hash) if recorded_hash != hash => {
+                changes.push((name.clone(), NamedChange::Changed))
+            }
+            Some(_) => {}
+        }
+    }
+    for name in recorded.keys() {
+        if !current.iter().any(|(n, _)| n == name) {
+            changes.push((name.clone(), NamedChange::Removed));
+        }
+    }
+
+    changes.sort_by(|a, b| a.0.cmp(&b.0));
+    changes
+}
+
+/// How the content hash in a fingerprint was calculated
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone, Copy, Default)]
+pub enum HashAlgorithm {
+    /// Plain SHA3_256 of file contents
+    #[default]
+    Sha3_256,
+    /// HMAC-SHA3_256 of file contents with a secret key
+    HmacSha3_256,
+}
+
+impl std::fmt::Display for HashAlgorithm {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HashAlgorithm::Sha3_256 => write!(f, "sha3-256"),
+            HashAlgorithm::HmacSha3_256 => write!(f, "hmac-sha3-256"),
+        }
+    }
+}
+
+/// Secret key for keyed content hashing
+///
+/// The key is held outside the database so that an attacker able to
+/// modify both a file and the database cannot forge a matching
+/// fingerprint.
+#[derive(Clone)]
+pub struct HashKey(Vec<u8>);
+
+impl HashKey {
+    /// Read a key from the entire contents of a file
+    pub fn from_file(path: &Path) -> Result<Self, FimblError> {
+        let key = read(path).map_err(|e| FimblError::KeyFileError(path.to_owned(), e))?;
+        if key.is_empty() {
+            Err(FimblError::EmptyKeyFile(path.to_owned()))
+        } else {
+            Ok(HashKey(key))
+        }
+    }
+
+    /// A key from raw bytes
+    pub fn from_bytes(key: &[u8]) -> Self {
+        HashKey(key.to_vec())
+    }
+}
+
+/// File flags restricting modification, as set by `chattr` (Linux)
+/// or `chflags` (BSD, macOS)
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone, Copy, Default)]
+pub struct FileFlags {
+    /// File may not be modified, removed or renamed
+    pub immutable: bool,
+
+    /// File may only be appended to
+    pub append_only: bool,
+}
+
+/// Kinds of file with no contents to hash (unix only)
+///
+/// Opening a FIFO blocks until something writes to it and reading a
+/// device may never end, so these are tracked by type and, for
+/// devices, device number only.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone, Copy)]
+pub enum SpecialFile {
+    Fifo,
+    Socket,
+    CharDevice { rdev: u64 },
+    BlockDevice { rdev: u64 },
+}
+
+impl std::fmt::Display for SpecialFile {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SpecialFile::Fifo => write!(f, "FIFO"),
+            SpecialFile::Socket => write!(f, "socket"),
+            SpecialFile::CharDevice { rdev } => write!(f, "character device {rdev:#x}"),
+            SpecialFile::BlockDevice { rdev } => write!(f, "block device {rdev:#x}"),
+        }
+    }
+}
+
+/// The kind of special file, if it is one
+#[cfg(unix)]
+fn special_file(metadata: &Metadata) -> Option<SpecialFile> {
+    use std::os::unix::fs::FileTypeExt;
+
+    let file_type = metadata.file_type();
+    if file_type.is_fifo() {
+        Some(SpecialFile::Fifo)
+    } else if file_type.is_socket() {
+        Some(SpecialFile::Socket)
+    } else if file_type.is_char_device() {
+        Some(SpecialFile::CharDevice {
+            rdev: metadata.rdev(),
+        })
+    } else if file_type.is_block_device() {
+        Some(SpecialFile::BlockDevice {
+            rdev: metadata.rdev(),
+        })
+    } else {
+        None
+    }
+}
+
+#[cfg(not(unix))]
+fn special_file(_metadata: &Metadata) -> Option<SpecialFile> {
+    None
+}
+
+/// Sorted names of the entries in a directory, separated by NULs, to
+/// hash as its contents
+fn directory_listing(path: &Path) -> io::Result<Vec<u8>> {
+    let mut names = std::fs::read_dir(path)?
+        .map(|entry| entry.map(|entry| entry.file_name()))
+        .collect::<io::Result<Vec<_>>>()?;
+    names.sort();
+    Ok(names
+        .iter()
+        .map(|name| name.as_encoded_bytes())
+        .collect::<Vec<_>>()
+        .join(&0))
+}
+
+/// The file system a file is on, where that can be told
+#[cfg(unix)]
+fn file_system(metadata: &Metadata) -> Option<u64> {
+    Some(metadata.dev())
+}
+
+#[cfg(not(unix))]
+fn file_system(_metadata: &Metadata) -> Option<u64> {
+    None
+}
+
+/// The files (and symlinks and special files) under a directory, in
+/// order, walked without following symlinks and, if asked, not into
+/// directories on another file system than it
+///
+/// A symlink given (even to a directory) is not walked, but is the one
+/// file under itself.
+pub fn files_under(dir: &Path, one_file_system: bool) -> io::Result<Vec<PathBuf>> {
+    let metadata = symlink_metadata(dir)?;
+    if !metadata.is_dir() {
+        return Ok(vec![dir.to_path_buf()]);
+    }
+    let device = file_system(&metadata);
+    let mut files = vec![];
+    let mut dirs = vec![dir.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        for entry in std::fs::read_dir(&dir)? {
+            let entry = entry?;
+            let metadata = entry.metadata()?;
+            if !metadata.is_dir() {
+                files.push(entry.path());
+            } else if !one_file_system || file_system(&metadata) == device {
+                dirs.push(entry.path());
+            }
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
 /// Fingerprint of file data and attributes at a point in time
 ///
-/// Tracked attributes include file type (file or symlink), creation
-/// and modification times and unix permissions. Access time is ignored.
+/// Tracked attributes include file type (file, directory, symlink or
+/// special file), size,
+/// creation and modification times, ownership, permissions (unix
+/// mode, or Windows attributes and security descriptor) and the
+/// device and inode (Windows volume and file index) identifying the
+/// file. Access time is ignored.
 #[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone)]
 pub struct Fingerprint {
     /// Hash of file contents
     pub content_hash: HashValue,
 
-    /// True if file is a symlink to elsewhere
-    pub symlink: bool,
+    /// True if file is a symlink to elsewhere
+    pub symlink: bool,
+
+    /// File creation (birth) time, to the nanosecond where the file
+    /// system keeps it
+    pub created: Option<SystemTime>,
+
+    /// File modification time, to the nanosecond where the file system
+    /// keeps it
+    pub modified: Option<SystemTime>,
+
+    /// Unix file mode
+    pub unix_mode: Option<u32>,
+
+    /// Readonly (unix or windows)
+    pub read_only: bool,
+
+    /// File size in bytes (absent in records from older versions)
+    #[serde(default)]
+    pub size: Option<u64>,
+
+    /// Device containing the file (volume serial number on Windows)
+    #[serde(default)]
+    pub dev: Option<u64>,
+
+    /// Inode number of the file (file index on Windows)
+    #[serde(default)]
+    pub ino: Option<u64>,
+
+    /// Algorithm used to calculate the content hash
+    #[serde(default)]
+    pub algorithm: HashAlgorithm,
+
+    /// Windows file attributes, excluding the archive flag (Windows
+    /// only)
+    #[serde(default)]
+    pub windows_attributes: Option<u32>,
+
+    /// Hash of the owner, group and DACL (Windows only)
+    #[serde(default)]
+    pub security_hash: Option<HashValue>,
+
+    /// NTFS alternate data streams (Windows only)
+    #[serde(default)]
+    pub streams: Option<Vec<NamedHash>>,
+
+    /// Security relevant extended attributes, e.g. quarantine (macOS
+    /// only)
+    #[serde(default)]
+    pub xattrs: Option<Vec<NamedHash>>,
+
+    /// Immutable and append-only flags (Linux, BSD and macOS)
+    #[serde(default)]
+    pub flags: Option<FileFlags>,
+
+    /// Owner's user id (unix only)
+    #[serde(default)]
+    pub uid: Option<u32>,
+
+    /// Group id (unix only)
+    #[serde(default)]
+    pub gid: Option<u32>,
+
+    /// Size of the chunks hashed separately to make up the content
+    /// hash, if the file was large enough to be hashed in chunks
+    ///
+    /// The content hash is then the hash (or HMAC) of the
+    /// concatenated SHA3_256 hashes of successive chunks.
+    #[serde(default, deserialize_with = "nonzero_chunk_size")]
+    pub chunk_size: Option<u64>,
+
+    /// Hashes of each chunk, if recorded to show which parts of a
+    /// changed file have changed (each the HMAC of the chunk's SHA3_256
+    /// hash, if there is a key)
+    #[serde(default)]
+    pub block_hashes: Option<Vec<HashValue>>,
+
+    /// Shannon entropy of the contents, in thousandths of a bit per
+    /// byte
+    #[serde(default)]
+    pub entropy: Option<u16>,
+
+    /// TLSH fuzzy hash of the contents, if one was wanted (and the
+    /// contents were long and varied enough)
+    #[serde(default)]
+    pub fuzzy_hash: Option<String>,
+
+    /// The kind of special file this is (or a symlink points to), if
+    /// any, in which case its contents are not hashed
+    #[serde(default)]
+    pub special: Option<SpecialFile>,
+
+    /// True if this is a directory (or a symlink to one), tracked as
+    /// an entry: the content hash is of the names in it
+    #[serde(default)]
+    pub directory: bool,
+
+    /// Free-form tags given when the file was added, for picking out
+    /// files to list or verify (not part of the file's identity)
+    #[serde(default)]
+    pub tags: Vec<String>,
+
+    /// Free-form note on the file, e.g. who owns it, shown alongside
+    /// changes to it
+    #[serde(default)]
+    pub note: Option<String>,
+
+    /// Where a symlink points, as written in the link (absent for
+    /// other files, and in records from older versions)
+    #[serde(default)]
+    pub link_target: Option<PathBuf>,
+
+    /// Digest of the contents by another algorithm, if the
+    /// fingerprinter was asked for one (never recorded)
+    #[serde(skip)]
+    pub digest: Option<Vec<u8>>,
+}
+
+/// Device, inode, size and modification time of a file, which
+/// together are taken to show that its contents haven't changed since
+/// they were hashed
+///
+/// Modification times can be set at will, so this is only trusted to
+/// skip hashing in fast mode.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone)]
+pub struct CacheKey {
+    pub dev: u64,
+    pub ino: u64,
+    pub size: u64,
+    pub modified: SystemTime,
+}
+
+/// Takes fingerprints using the configured hashing scheme, hashing
+/// each hardlinked inode only once
+#[derive(Default)]
+pub struct Fingerprinter {
+    /// Key for keyed hashing, if any
+    key: Option<HashKey>,
+
+    /// Content hashes of hardlinked files already read, keyed by
+    /// device and inode (and chunk size), with any chunk hashes
+    hardlinks: HashMap<HardlinkKey, Hashed>,
+
+    /// Bytes of file contents hashed since the last reset
+    bytes_hashed: u64,
+
+    /// How file contents are read
+    reader: ContentReader,
+
+    /// Size of blocks to record hashes of in new fingerprints, if any
+    block_size: Option<u64>,
+
+    /// Record fuzzy hashes in new fingerprints
+    fuzzy: bool,
+
+    /// Digest contents read by another algorithm too, if any
+    digest: Option<DigestAlgorithm>,
+
+    /// Mount table, for finding files on network file systems
+    mounts: Mounts,
+
+    /// What to do about files on network (or virtual) file systems
+    network_fs: NetworkFs,
+
+    /// Time limit for reading a file on a network file system, if any
+    network_timeout: Option<Duration>,
+
+    /// Time limit for reading any file, if any
+    file_timeout: Option<Duration>,
+
+    /// Time limit for reading the file being fingerprinted, if any
+    time_limit: Option<Duration>,
+
+    /// Counts of work done to share, if any
+    progress: Option<Arc<Progress>>,
+
+    /// Read contents twice, and fail if the reads disagree
+    paranoid: bool,
+
+    /// How far creation and modification times may be from those
+    /// recorded and still count as unchanged
+    time_tolerance: Duration,
+
+    /// Where the file system whose files are fingerprinted is mounted,
+    /// if not at /
+    root: Option<PathBuf>,
+}
+
+/// Files fingerprinted and bytes hashed since the last reset, shared
+/// with other threads (say, answering a control socket) during a run
+#[derive(Default, Debug)]
+pub struct Progress {
+    pub files: AtomicU64,
+    pub bytes: AtomicU64,
+}
+
+impl Progress {
+    /// Files fingerprinted so far
+    pub fn files(&self) -> u64 {
+        self.files.load(Ordering::Relaxed)
+    }
+
+    /// Bytes hashed so far
+    pub fn bytes(&self) -> u64 {
+        self.bytes.load(Ordering::Relaxed)
+    }
+}
+
+/// What to do about files on network and virtual file systems (NFS,
+/// CIFS, FUSE, procfs, sysfs...), where reading may hang
+#[derive(PartialEq, Eq, Debug, Default, Clone, Copy, clap::ValueEnum)]
+pub enum NetworkFs {
+    /// Read them like any other file
+    #[default]
+    Read,
+    /// Read them, but report each one
+    Warn,
+    /// Don't read them at all, reporting each one skipped
+    Skip,
+}
+
+/// Default size of the buffer file contents are read into
+pub const DEFAULT_READ_BUFFER: usize = 1 << 20;
+
+/// Sizes are a number of bytes, optionally with a binary suffix
+/// (e.g. "512K", "20M", "1G")
+pub fn parse_size(size: &str) -> Result<u64, String> {
+    let (digits, scale) = match size.char_indices().last() {
+        Some((i, 'k' | 'K')) => (&size[..i], 1 << 10),
+        Some((i, 'm' | 'M')) => (&size[..i], 1 << 20),
+        Some((i, 'g' | 'G')) => (&size[..i], 1 << 30),
+        _ => (size, 1),
+    };
+    match digits.parse::<u64>() {
+        Ok(n) if n > 0 => n
+            .checked_mul(scale)
+            .ok_or_else(|| "size too large".to_string()),
+        _ => Err("expected a number of bytes, e.g. 20M".to_string()),
+    }
+}
+
+/// Largest read buffer allowed, as each reading thread allocates one
+const MAX_READ_BUFFER: u64 = 1 << 30;
+
+/// Read buffers are sizes as above, of at most `MAX_READ_BUFFER` bytes
+pub fn parse_read_buffer(size: &str) -> Result<u64, String> {
+    match parse_size(size)? {
+        size if size > MAX_READ_BUFFER => Err("read buffer larger than 1G".to_string()),
+        size => Ok(size),
+    }
+}
+
+/// A recorded chunk size of 0 is refused, rather than divided by when
+/// verifying
+fn nonzero_chunk_size<'de, D>(deserializer: D) -> Result<Option<u64>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    match <Option<u64> as serde::Deserialize>::deserialize(deserializer)? {
+        Some(0) => Err(serde::de::Error::custom("chunk size of 0")),
+        size => Ok(size),
+    }
+}
+
+/// Content hashes are given in hex, as fimbl shows them
+pub fn parse_hash(hash: &str) -> Result<HashValue, String> {
+    let mut value = [0; HASH_SIZE];
+    hex::decode_to_slice(hash.trim(), &mut value)
+        .map_err(|_| format!("expected {} hex digits", HASH_SIZE * 2))?;
+    Ok(value)
+}
+
+/// Reads file contents for hashing
+#[derive(Clone, Debug)]
+pub struct ContentReader {
+    /// Size of the read buffer, and of the chunks a mapped file is
+    /// hashed in
+    buffer_size: usize,
+
+    /// Map regular files into memory rather than reading them
+    mmap: bool,
+
+    /// Limit on the rate of reading, if any
+    throttle: Option<Throttle>,
+}
+
+impl Default for ContentReader {
+    fn default() -> Self {
+        ContentReader {
+            buffer_size: DEFAULT_READ_BUFFER,
+            mmap: false,
+            throttle: None,
+        }
+    }
+}
+
+impl ContentReader {
+    /// Feed the entire contents of an open file to `update` in
+    /// chunks, returning the number of bytes read
+    ///
+    /// Files that can't be mapped (empty, or on file systems without
+    /// mmap support) are read instead.
+    fn read(&mut self, file: &File, mut update: impl FnMut(&[u8])) -> io::Result<u64> {
+        if self.mmap && file.metadata()?.len() > 0 {
+            // SAFETY: the map is only read while the file is open; if
+            // another process truncates the file meanwhile, reads
+            // past the new end fault (hence mmap is opt-in)
+            if let Ok(map) = unsafe { memmap2::Mmap::map(file) } {
+                for chunk in map.chunks(self.buffer_size) {
+                    update(chunk);
+                    self.consumed(chunk.len());
+                }
+                return Ok(map.len() as u64);
+            }
+        }
+
+        feed(
+            ChunkOf::new(file, 0, u64::MAX),
+            &mut vec![0; self.buffer_size],
+            self.throttle.as_mut(),
+            update,
+        )
+    }
+
+    /// Account for bytes read against any rate limit
+    fn consumed(&mut self, bytes: usize) {
+        if let Some(throttle) = &mut self.throttle {
+            throttle.consume(bytes as u64);
+        }
+    }
+
+    /// SHA3_256 hashes of successive chunks of an open file,
+    /// gathering statistics of the contents
+    ///
+    /// Chunks are hashed in parallel unless reads are rate-limited, a
+    /// fuzzy hash or SHA-256 (which must see the contents in order) is
+    /// wanted or the platform can't read at an offset without seeking.
+    fn chunk_hashes(
+        &mut self,
+        file: &File,
+        chunk_size: u64,
+        stats: &mut ContentStats,
+    ) -> io::Result<Vec<HashValue>> {
+        let len = file.metadata()?.len();
+        let sequential = self.throttle.is_some() || stats.sequential() || !cfg!(any(unix, windows));
+
+        if self.mmap && len > 0 {
+            // SAFETY: as for `read`
+            if let Ok(map) = unsafe { memmap2::Mmap::map(file) } {
+                let chunks: Vec<&[u8]> = map.chunks(chunk_size as usize).collect();
+                if sequential {
+                    let mut hashes = vec![];
+                    for chunk in chunks {
+                        stats.add(chunk);
+                        self.consumed(chunk.len());
+                        hashes.push(Hash::digest(chunk).into());
+                    }
+                    return Ok(hashes);
+                }
+                let hashed = in_parallel(
+                    chunks.len(),
+                    || (),
+                    |_, i| {
+                        let mut counts = ByteCounts::default();
+                        counts.add(chunks[i]);
+                        Ok((Hash::digest(chunks[i]).into(), counts))
+                    },
+                )?;
+                return Ok(stats.merge_chunks(hashed));
+            }
+        }
+
+        // even an empty file has one (empty) chunk
+        let count = len.div_ceil(chunk_size).max(1) as usize;
+        // one buffer for each thread, no bigger than a chunk
+        let buffer_size = self
+            .buffer_size
+            .min(chunk_size.try_into().unwrap_or(usize::MAX));
+        let hash_chunk = |i: usize,
+                          buffer: &mut [u8],
+                          throttle: Option<&mut Throttle>,
+                          stats: &mut ContentStats| {
+            let chunk = ChunkOf::new(file, i as u64 * chunk_size, chunk_size);
+            let mut hasher = Hash::new();
+            feed(chunk, buffer, throttle, |bytes| {
+                hasher.update(bytes);
+                stats.add(bytes);
+            })?;
+            Ok::<HashValue, io::Error>(hasher.finalize().into())
+        };
+
+        if sequential {
+            let mut buffer = vec![0; buffer_size];
+            return (0..count)
+                .map(|i| hash_chunk(i, &mut buffer, self.throttle.as_mut(), stats))
+                .collect();
+        }
+        let hashed = in_parallel(
+            count,
+            || vec![0; buffer_size],
+            |buffer, i| {
+                let mut chunk_stats = ContentStats::default();
+                let hash = hash_chunk(i, buffer, None, &mut chunk_stats)?;
+                Ok((hash, chunk_stats.counts))
+            },
+        )?;
+        Ok(stats.merge_chunks(hashed))
+    }
+}
+
+/// Part of an open file, read at its offset without moving the file's
+/// own position, so that threads can share the file
+struct ChunkOf<'a> {
+    file: &'a File,
+    offset: u64,
+    left: u64,
+}
+
+impl<'a> ChunkOf<'a> {
+    /// Up to `len` bytes of the file from `offset`
+    fn new(file: &'a File, offset: u64, len: u64) -> Self {
+        ChunkOf {
+            file,
+            offset,
+            left: len,
+        }
+    }
+}
+
+impl Read for ChunkOf<'_> {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        let len = buffer.len().min(self.left.try_into().unwrap_or(usize::MAX));
+        let read = read_at(self.file, &mut buffer[..len], self.offset)?;
+        self.offset += read as u64;
+        self.left -= read as u64;
+        Ok(read)
+    }
+}
+
+#[cfg(unix)]
+fn read_at(file: &File, buffer: &mut [u8], offset: u64) -> io::Result<usize> {
+    std::os::unix::fs::FileExt::read_at(file, buffer, offset)
+}
+
+#[cfg(windows)]
+fn read_at(file: &File, buffer: &mut [u8], offset: u64) -> io::Result<usize> {
+    std::os::windows::fs::FileExt::seek_read(file, buffer, offset)
+}
+
+/// Chunks are only read one at a time here, so seeking is safe
+#[cfg(not(any(unix, windows)))]
+fn read_at(mut file: &File, buffer: &mut [u8], offset: u64) -> io::Result<usize> {
+    use std::io::{Seek, SeekFrom};
+
+    file.seek(SeekFrom::Start(offset))?;
+    file.read(buffer)
+}
+
+/// Feed everything from a source to `update` in chunks of up to the
+/// size of the buffer, read through it, returning the number of bytes
+/// read
+fn feed(
+    mut source: impl Read,
+    buffer: &mut [u8],
+    mut throttle: Option<&mut Throttle>,
+    mut update: impl FnMut(&[u8]),
+) -> io::Result<u64> {
+    let mut total = 0;
+    loop {
+        let bytes_read = source.read(buffer)?;
+        if bytes_read == 0 {
+            break;
+        }
+        update(&buffer[..bytes_read]);
+        total += bytes_read as u64;
+        if let Some(throttle) = throttle.as_deref_mut() {
+            throttle.consume(bytes_read as u64);
+        }
+    }
+
+    Ok(total)
+}
+
+/// Run `job` for each index below `count` on as many threads as there
+/// are CPUs, each with its own state made by `state` (to reuse between
+/// its jobs), returning the results in order
+fn in_parallel<S, T: Send>(
+    count: usize,
+    state: impl Fn() -> S + Sync,
+    job: impl Fn(&mut S, usize) -> io::Result<T> + Sync,
+) -> io::Result<Vec<T>> {
+    let threads = thread::available_parallelism()
+        .map_or(1, |n| n.get())
+        .min(count);
+    let next = AtomicUsize::new(0);
+    let (state, job, next) = (&state, &job, &next);
+
+    let done = thread::scope(|scope| {
+        let workers: Vec<_> = (0..threads)
+            .map(|_| {
+                scope.spawn(move || {
+                    let mut done = vec![];
+                    let mut state = state();
+                    loop {
+                        let i = next.fetch_add(1, Ordering::Relaxed);
+                        if i >= count {
+                            return Ok(done);
+                        }
+                        done.push((i, job(&mut state, i)?));
+                    }
+                })
+            })
+            .collect();
+        workers
+            .into_iter()
+            .map(|worker| worker.join().expect("hashing thread panicked"))
+            .collect::<io::Result<Vec<Vec<_>>>>()
+    })?;
+
+    let mut done: Vec<(usize, T)> = done.into_iter().flatten().collect();
+    done.sort_by_key(|(i, _)| *i);
+    Ok(done.into_iter().map(|(_, result)| result).collect())
+}
+
+/// Read the entire open file and calculate a hash of its contents,
+/// gathering statistics of the contents
+fn hash_contents(
+    file: &File,
+    reader: &mut ContentReader,
+    stats: &mut ContentStats,
+) -> io::Result<HashValue> {
+    let mut hasher = Hash::new();
+    reader.read(file, |bytes| {
+        hasher.update(bytes);
+        stats.add(bytes);
+    })?;
+    Ok(hasher.finalize().as_slice().try_into().unwrap())
+}
+
+/// Read the entire open file and calculate a keyed hash of its
+/// contents, gathering statistics of the contents
+fn hmac_contents(
+    file: &File,
+    key: &HashKey,
+    reader: &mut ContentReader,
+    stats: &mut ContentStats,
+) -> io::Result<HashValue> {
+    let mut mac = Hmac::<Hash>::new_from_slice(&key.0).expect("HMAC accepts keys of any size");
+    reader.read(file, |bytes| {
+        mac.update(bytes);
+        stats.add(bytes);
+    })?;
+    Ok(mac.finalize().into_bytes().as_slice().try_into().unwrap())
+}
+
+/// Attributes which are only available on some platforms
+#[derive(Default)]
+struct PlatformAttributes {
+    /// Unix file mode
+    unix_mode: Option<u32>,
+
+    /// Device and inode (or equivalent)
+    identity: Option<(u64, u64)>,
+
+    /// True if the file has more than one hardlink
+    hardlinked: bool,
+
+    /// Windows file attributes
+    windows_attributes: Option<u32>,
+
+    /// Hash of the Windows security descriptor
+    security_hash: Option<HashValue>,
+
+    /// Immutable and append-only flags
+    flags: Option<FileFlags>,
+
+    /// Owner and group
+    ownership: Option<(u32, u32)>,
+}
+
+#[cfg(unix)]
+fn platform_attributes(path: &Path, metadata: &Metadata) -> io::Result<PlatformAttributes> {
+    Ok(PlatformAttributes {
+        unix_mode: Some(metadata.permissions().mode()),
+        identity: Some((metadata.dev(), metadata.ino())),
+        hardlinked: metadata.nlink() > 1,
+        flags: file_flags(path, metadata)?,
+        ownership: Some((metadata.uid(), metadata.gid())),
+        ..Default::default()
+    })
+}
+
+/// Inode flags from `linux/fs.h`
+#[cfg(any(target_os = "linux", target_os = "android"))]
+const FS_IMMUTABLE_FL: libc::c_long = 0x10;
+#[cfg(any(target_os = "linux", target_os = "android"))]
+const FS_APPEND_FL: libc::c_long = 0x20;
+
+/// Read inode flags with `FS_IOC_GETFLAGS`, if supported by the file
+/// system
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn file_flags(path: &Path, metadata: &Metadata) -> io::Result<Option<FileFlags>> {
+    use std::os::unix::{fs::OpenOptionsExt, io::AsRawFd};
+
+    if !metadata.is_file() && !metadata.is_dir() {
+        return Ok(None);
+    }
+    let file = std::fs::OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_NONBLOCK | libc::O_NOFOLLOW)
+        .open(path)?;
+
+    let mut flags: libc::c_long = 0;
+    // SAFETY: the descriptor is open and flags outlives the call
+    if unsafe { libc::ioctl(file.as_raw_fd(), libc::FS_IOC_GETFLAGS, &mut flags) } < 0 {
+        let error = io::Error::last_os_error();
+        return match error.raw_os_error() {
+            Some(libc::ENOTTY) | Some(libc::EOPNOTSUPP) | Some(libc::EINVAL) => Ok(None),
+            _ => Err(error),
+        };
+    }
+
+    Ok(Some(FileFlags {
+        immutable: flags & FS_IMMUTABLE_FL != 0,
+        append_only: flags & FS_APPEND_FL != 0,
+    }))
+}
+
+/// User and system flags from `sys/stat.h`
+#[cfg(any(target_os = "macos", target_os = "freebsd"))]
+const UF_IMMUTABLE: u32 = 0x2;
+#[cfg(any(target_os = "macos", target_os = "freebsd"))]
+const UF_APPEND: u32 = 0x4;
+#[cfg(any(target_os = "macos", target_os = "freebsd"))]
+const SF_IMMUTABLE: u32 = 0x20000;
+#[cfg(any(target_os = "macos", target_os = "freebsd"))]
+const SF_APPEND: u32 = 0x40000;
+
+/// Read user and system flags from `st_flags`
+#[cfg(any(target_os = "macos", target_os = "freebsd"))]
+fn file_flags(_path: &Path, metadata: &Metadata) -> io::Result<Option<FileFlags>> {
+    #[cfg(target_os = "macos")]
+    let flags = std::os::macos::fs::MetadataExt::st_flags(metadata);
+    #[cfg(target_os = "freebsd")]
+    let flags = std::os::freebsd::fs::MetadataExt::st_flags(metadata);
+
+    Ok(Some(FileFlags {
+        immutable: flags & (UF_IMMUTABLE | SF_IMMUTABLE) != 0,
+        append_only: flags & (UF_APPEND | SF_APPEND) != 0,
+    }))
+}
+
+#[cfg(all(
+    unix,
+    not(any(
+        target_os = "linux",
+        target_os = "android",
+        target_os = "macos",
+        target_os = "freebsd"
+    ))
+))]
+fn file_flags(_path: &Path, _metadata: &Metadata) -> io::Result<Option<FileFlags>> {
+    Ok(None)
+}
+
+/// Windows sets the archive attribute on every write and backup
+/// software clears it, so it says nothing about integrity
+#[cfg(windows)]
+const FILE_ATTRIBUTE_ARCHIVE: u32 = 0x20;
+
+#[cfg(windows)]
+fn platform_attributes(path: &Path, _metadata: &Metadata) -> io::Result<PlatformAttributes> {
+    let details = crate::windows::file_details(path)?;
+    Ok(PlatformAttributes {
+        identity: Some((details.volume, details.index)),
+        hardlinked: details.links > 1,
+        windows_attributes: Some(details.attributes & !FILE_ATTRIBUTE_ARCHIVE),
+        security_hash: details.security_hash,
+        ..Default::default()
+    })
+}
+
+#[cfg(not(any(unix, windows)))]
+fn platform_attributes(_path: &Path, _metadata: &Metadata) -> io::Result<PlatformAttributes> {
+    Ok(PlatformAttributes::default())
+}
+
+/// The error within an `io::Error` when a file changed while it was
+/// being fingerprinted, so what was read may not be what is there
+#[derive(Debug)]
+pub struct ChangedDuringRead;
+
+impl std::fmt::Display for ChangedDuringRead {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "file changed while it was read")
+    }
+}
+
+impl std::error::Error for ChangedDuringRead {}
+
+impl ChangedDuringRead {
+    /// Whether an error is this one
+    pub fn is(error: &io::Error) -> bool {
+        error
+            .get_ref()
+            .is_some_and(|inner| inner.is::<ChangedDuringRead>())
+    }
+}
+
+/// The error within an `io::Error` when two reads of a file that
+/// didn't change in between gave different contents, which points to
+/// failing storage rather than tampering
+#[derive(Debug)]
+pub struct ReadsDisagree;
+
+impl std::fmt::Display for ReadsDisagree {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "reads of the file disagree")
+    }
+}
+
+impl std::error::Error for ReadsDisagree {}
+
+impl ReadsDisagree {
+    /// Whether an error is this one
+    pub fn is(error: &io::Error) -> bool {
+        error
+            .get_ref()
+            .is_some_and(|inner| inner.is::<ReadsDisagree>())
+    }
+}
+
+/// Ask the kernel to drop a file's clean pages from the page cache,
+/// so that reading it again goes to the storage
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn drop_cached(file: &File) {
+    use std::os::unix::io::AsRawFd;
+
+    // SAFETY: the descriptor is open; advice is only a hint, and
+    // failing to take it leaves the second read to the cache
+    unsafe { libc::posix_fadvise(file.as_raw_fd(), 0, 0, libc::POSIX_FADV_DONTNEED) };
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn drop_cached(_file: &File) {}
+
+/// The time recorded, if the current time is within the tolerance of
+/// it, otherwise the current time
+fn within(
+    recorded: Option<SystemTime>,
+    current: Option<SystemTime>,
+    tolerance: Duration,
+) -> Option<SystemTime> {
+    match (recorded, current) {
+        (Some(recorded), Some(time))
+            if recorded
+                .duration_since(time)
+                .or_else(|_| time.duration_since(recorded))
+                .is_ok_and(|apart| apart <= tolerance) =>
+        {
+            Some(recorded)
+        }
+        _ => current,
+    }
+}
+
+/// When a file was created, from `statx` (of the open file, if there
+/// is one), which the standard library consults only when built
+/// against glibc
+#[cfg(target_os = "linux")]
+fn birth_time(path: &Path, file: Option<&File>, metadata: &Metadata) -> Option<SystemTime> {
+    use std::os::unix::{ffi::OsStrExt, io::AsRawFd};
+
+    // SAFETY: statx is plain data, zeroes and all
+    let mut statx: libc::statx = unsafe { std::mem::zeroed() };
+    // SAFETY: the descriptor is open or the path a C string, and
+    // statx outlives the call
+    let result = match file {
+        Some(file) => unsafe {
+            libc::statx(
+                file.as_raw_fd(),
+                c"".as_ptr(),
+                libc::AT_EMPTY_PATH,
+                libc::STATX_BTIME,
+                &mut statx,
+            )
+        },
+        None => {
+            let path = std::ffi::CString::new(path.as_os_str().as_bytes()).ok()?;
+            unsafe {
+                libc::statx(
+                    libc::AT_FDCWD,
+                    path.as_ptr(),
+                    libc::AT_SYMLINK_NOFOLLOW,
+                    libc::STATX_BTIME,
+                    &mut statx,
+                )
+            }
+        }
+    };
+    // kernels before 4.11 (and some sandboxes) have no statx
+    if result != 0 {
+        return metadata.created().ok();
+    }
+    if statx.stx_mask & libc::STATX_BTIME == 0 {
+        return None;
+    }
+    let since_epoch = Duration::from_secs(statx.stx_btime.tv_sec.unsigned_abs());
+    let nanos = Duration::from_nanos(statx.stx_btime.tv_nsec.into());
+    match statx.stx_btime.tv_sec < 0 {
+        true => SystemTime::UNIX_EPOCH
+            .checked_sub(since_epoch)?
+            .checked_add(nanos),
+        false => SystemTime::UNIX_EPOCH.checked_add(since_epoch + nanos),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn birth_time(_path: &Path, _file: Option<&File>, metadata: &Metadata) -> Option<SystemTime> {
+    metadata.created().ok()
+}
+
+/// Fail with `ChangedDuringRead` unless two looks at a file saw the
+/// same one, unchanged: the same device and inode (where there are
+/// such), type, size and modification time
+fn unchanged_since(before: &Metadata, after: &Metadata) -> io::Result<()> {
+    #[cfg(unix)]
+    let same_inode = (before.dev(), before.ino()) == (after.dev(), after.ino());
+    #[cfg(not(unix))]
+    let same_inode = true;
+    match same_inode
+        && before.file_type() == after.file_type()
+        && before.len() == after.len()
+        && before.modified().ok() == after.modified().ok()
+    {
+        true => Ok(()),
+        false => {
+            warn!("file changed while it was read");
+            Err(io::Error::other(ChangedDuringRead))
+        }
+    }
+}
+
+/// The error opening a file just found there, as `ChangedDuringRead`
+/// if it has since gone or (refused as a final symlink) been swapped
+/// for a symlink
+fn changed_since_found(error: io::Error) -> io::Error {
+    #[cfg(unix)]
+    let now_symlink = error.raw_os_error() == Some(libc::ELOOP);
+    #[cfg(not(unix))]
+    let now_symlink = false;
+    match error.kind() == io::ErrorKind::NotFound || now_symlink {
+        true => {
+            warn!("file changed while it was read");
+            io::Error::other(ChangedDuringRead)
+        }
+        false => error,
+    }
+}
+
+/// Open a file's contents to read them, following a final symlink only
+/// if asked, and without blocking should it have become a FIFO
+#[cfg(unix)]
+fn open_contents(path: &Path, follow: bool) -> io::Result<File> {
+    use std::os::unix::fs::OpenOptionsExt;
+
+    let nofollow = match follow {
+        true => 0,
+        false => libc::O_NOFOLLOW,
+    };
+    std::fs::OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_NONBLOCK | nofollow)
+        .open(path)
+}
+
+/// Opens a reparse point (a symlink, say) itself rather than its target
+#[cfg(windows)]
+const FILE_FLAG_OPEN_REPARSE_POINT: u32 = 0x0020_0000;
+
+#[cfg(windows)]
+fn open_contents(path: &Path, follow: bool) -> io::Result<File> {
+    use std::os::windows::fs::OpenOptionsExt;
+
+    let flags = match follow {
+        true => 0,
+        false => FILE_FLAG_OPEN_REPARSE_POINT,
+    };
+    std::fs::OpenOptions::new()
+        .read(true)
+        .custom_flags(flags)
+        .open(path)
+}
+
+#[cfg(not(any(unix, windows)))]
+fn open_contents(path: &Path, _follow: bool) -> io::Result<File> {
+    File::open(path)
+}
+
+/// Generate file fingerprint with plain hashing
+#[cfg(test)]
+pub fn fingerprint_file(path: &Path) -> io::Result<Fingerprint> {
+    Fingerprinter::default().fingerprint_file(path)
+}
+
+impl Fingerprinter {
+    /// A fingerprinter using keyed hashing if a key is supplied
+    pub fn new(key: Option<HashKey>) -> Self {
+        Fingerprinter {
+            key,
+            hardlinks: HashMap::new(),
+            bytes_hashed: 0,
+            reader: ContentReader::default(),
+            block_size: None,
+            fuzzy: false,
+            digest: None,
+            mounts: Mounts::default(),
+            network_fs: NetworkFs::default(),
+            network_timeout: None,
+            file_timeout: None,
+            time_limit: None,
+            progress: None,
+            paranoid: false,
+            time_tolerance: Duration::ZERO,
+            root: None,
+        }
+    }
+
+    /// Fingerprint the files of a file system mounted at root (a
+    /// forensic image, say, or a chroot) as if it were mounted at /
+    pub fn with_root(mut self, root: Option<PathBuf>) -> Self {
+        self.root = root;
+        self
+    }
+
+    /// Where the file system fingerprinted is mounted, if not at /
+    pub fn root(&self) -> Option<&Path> {
+        self.root.as_deref()
+    }
+
+    /// Where a file is on disk: under the root, if there is one
+    pub fn on_disk(&self, path: &Path) -> PathBuf {
+        match &self.root {
+            Some(root) => rooted(root, path, false),
+            None => path.to_path_buf(),
+        }
+    }
+
+    /// Count the work done in progress shared with other threads
+    pub fn with_progress(mut self, progress: Arc<Progress>) -> Self {
+        self.progress = Some(progress);
+        self
+    }
+
+    /// Apply a policy, and optionally a time limit for reading, to
+    /// files on network and virtual file systems, reading the mount
+    /// table to find them
+    pub fn with_network_fs(
+        mut self,
+        policy: NetworkFs,
+        timeout: Option<Duration>,
+    ) -> io::Result<Self> {
+        if policy != NetworkFs::Read || timeout.is_some() {
+            self.mounts = Mounts::load()?;
+        }
+        self.network_fs = policy;
+        self.network_timeout = timeout;
+        Ok(self)
+    }
+
+    /// Give up reading any file's contents after the time given
+    ///
+    /// Each file is then read on a thread of its own, which costs a
+    /// little.
+    pub fn with_file_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.file_timeout = timeout;
+        self
+    }
+
+    /// The policy for a file and the network or virtual file system
+    /// it is on, if it is on one
+    pub fn network_fs(&self, path: &Path) -> Option<(NetworkFs, &str)> {
+        self.mounts
+            .remote_file_system(&self.on_disk(path))
+            .map(|fs_type| (self.network_fs, fs_type))
+    }
+
+    /// Record fuzzy hashes in new fingerprints, to show how much
+    /// changed files have changed
+    pub fn with_fuzzy_hashes(mut self, fuzzy: bool) -> Self {
+        self.fuzzy = fuzzy;
+        self
+    }
+
+    /// Digest the contents of each file read by another algorithm
+    /// too, in the same read as its fingerprint, e.g. to attest to it
+    pub fn with_digest(mut self, digest: Option<DigestAlgorithm>) -> Self {
+        self.digest = digest;
+        self
+    }
+
+    /// Hash new fingerprints' contents in blocks of the size given and
+    /// record the hashes of each block
+    pub fn with_block_hashes(mut self, block_size: Option<u64>) -> Self {
+        self.block_size = block_size.map(|size| size.max(1));
+        self
+    }
+
+    /// Limit reading of file contents to an average number of bytes
+    /// per second
+    pub fn with_rate_limit(mut self, bytes_per_sec: Option<u64>) -> Self {
+        self.reader.throttle = bytes_per_sec.map(Throttle::new);
+        self
+    }
+
+    /// Read file contents in chunks of the size given and, optionally,
+    /// by mapping files into memory
+    pub fn with_reads(mut self, buffer_size: usize, mmap: bool) -> Self {
+        self.reader.buffer_size = buffer_size.max(1);
+        self.reader.mmap = mmap;
+        self
+    }
+
+    /// Read file contents twice, dropping them from the page cache in
+    /// between where the platform allows, so that storage returning
+    /// different data each time is caught
+    pub fn with_paranoid_reads(mut self, paranoid: bool) -> Self {
+        self.paranoid = paranoid;
+        self
+    }
+
+    /// Take creation and modification times within the tolerance of
+    /// those recorded to be the same, as a file system keeping times
+    /// to the second (or two, like FAT) would have them
+    pub fn with_time_tolerance(mut self, tolerance: Duration) -> Self {
+        self.time_tolerance = tolerance;
+        self
+    }
+
+    /// Forget hashes of hardlinked files and zero the count of bytes
+    /// hashed, before starting a new run
+    pub fn reset(&mut self) {
+        self.hardlinks.clear();
+        self.bytes_hashed = 0;
+        if let Some(progress) = &self.progress {
+            progress.files.store(0, Ordering::Relaxed);
+            progress.bytes.store(0, Ordering::Relaxed);
+        }
+        if let Some(throttle) = &mut self.reader.throttle {
+            throttle.reset();
+        }
+    }
+
+    /// Bytes of file contents hashed since the last reset
+    pub fn bytes_hashed(&self) -> u64 {
+        self.bytes_hashed
+    }
+
+    /// Algorithm used for content hashes
+    pub fn algorithm(&self) -> HashAlgorithm {
+        match self.key {
+            Some(_) => HashAlgorithm::HmacSha3_256,
+            None => HashAlgorithm::Sha3_256,
+        }
+    }
+
+    /// Hash an open file's contents according to the configured
+    /// scheme, in chunks of the size given, if any, and with a fuzzy
+    /// hash or SHA-256 if wanted
+    ///
+    /// With a time limit, an error of kind `TimedOut` is returned if
+    /// reading takes longer. Paranoid reads that disagree fail with
+    /// `ReadsDisagree`, unless the file changed in between.
+    fn hash_contents(
+        &mut self,
+        file: &File,
+        chunk_size: Option<u64>,
+        wanted: Wanted,
+    ) -> io::Result<Hashed> {
+        if !self.paranoid {
+            return self.hash_contents_once(file, chunk_size, wanted);
+        }
+        let before = file.metadata()?;
+        let hashed = self.hash_contents_once(file, chunk_size, wanted)?;
+        drop_cached(file);
+        let again = self.hash_contents_once(file, chunk_size, wanted)?;
+        if again.content_hash != hashed.content_hash {
+            unchanged_since(&before, &file.metadata()?)?;
+            warn!("reads of the file disagree");
+            return Err(io::Error::other(ReadsDisagree));
+        }
+        Ok(hashed)
+    }
 
-    /// File creation time
-    pub created: Option<SystemTime>,
+    /// Hash an open file's contents once
+    fn hash_contents_once(
+        &mut self,
+        file: &File,
+        chunk_size: Option<u64>,
+        wanted: Wanted,
+    ) -> io::Result<Hashed> {
+        let (hashed, bytes) = match self.time_limit {
+            Some(limit) => self.hash_contents_within(file, chunk_size, wanted, limit)?,
+            None => hash_with(
+                self.key.as_ref(),
+                &mut self.reader,
+                file,
+                chunk_size,
+                wanted,
+            )?,
+        };
+        self.bytes_hashed += bytes;
+        if let Some(progress) = &self.progress {
+            progress.bytes.fetch_add(bytes, Ordering::Relaxed);
+        }
+        debug!(bytes, "hashed contents");
+        Ok(hashed)
+    }
 
-    /// File modification time
-    pub modified: Option<SystemTime>,
+    /// Hash file contents on a thread of their own, giving up on them
+    /// after the time limit
+    fn hash_contents_within(
+        &mut self,
+        file: &File,
+        chunk_size: Option<u64>,
+        wanted: Wanted,
+        limit: Duration,
+    ) -> io::Result<(Hashed, u64)> {
+        let key = self.key.clone();
+        let mut reader = self.reader.clone();
+        let file = file.try_clone()?;
+        let (hashed, reader) = timed(Some(limit), "reading contents", move || {
+            let hashed = hash_with(key.as_ref(), &mut reader, &file, chunk_size, wanted)?;
+            Ok::<_, io::Error>((hashed, reader))
+        })?;
+        self.reader = reader;
+        Ok(hashed)
+    }
 
-    /// Unix file mode
-    pub unix_mode: Option<u32>,
+    /// The time limit on touching a file (as it is on disk): the
+    /// shorter of any for the network or virtual file system it is on
+    /// and any for every file
+    fn time_limit_for(&self, path: &Path) -> Option<Duration> {
+        let network_timeout = self
+            .mounts
+            .remote_file_system(path)
+            .and(self.network_timeout);
+        match (network_timeout, self.file_timeout) {
+            (Some(network), Some(file)) => Some(network.min(file)),
+            (network, file) => network.or(file),
+        }
+    }
 
-    /// Readonly (unix or windows)
-    pub read_only: bool,
-}
+    /// Do something touching a file on a thread of its own, giving up
+    /// on it after the file's time limit, if it has one, as for
+    /// reading its contents: a stat or path lookup on a hung mount
+    /// blocks just as a read does
+    pub fn within_time_limit<T, E>(
+        &self,
+        path: &Path,
+        what: &str,
+        touch: impl FnOnce() -> Result<T, E> + Send + 'static,
+    ) -> Result<T, E>
+    where
+        T: Send + 'static,
+        E: From<io::Error> + Send + 'static,
+    {
+        timed(self.time_limit_for(&self.on_disk(path)), what, touch)
+    }
 
-/// Read the entire file and calculate a hash of its contents
-fn hash_contents(path: &Path) -> io::Result<HashValue> {
-    let mut file = File::open(path)?;
+    /// Metadata of a file on disk, not following a final symlink,
+    /// within the file's time limit
+    pub fn metadata(&self, path: &Path) -> io::Result<Metadata> {
+        let on_disk = self.on_disk(path);
+        self.within_time_limit(path, "reading metadata", move || symlink_metadata(on_disk))
+    }
 
-    let mut hasher = Hash::new();
+    /// Hash a value (rather than a file) according to the configured
+    /// scheme
+    fn hash_bytes(&self, bytes: &[u8]) -> HashValue {
+        keyed_hash(self.key.as_ref(), bytes)
+    }
 
-    let mut buffer = vec![0; 4096];
-    loop {
-        let bytes_read = file.read(&mut buffer)?;
-        if bytes_read == 0 {
-            break;
+    /// Names and hashes of the tracked extended attributes present
+    #[cfg(target_os = "macos")]
+    fn xattrs(&self, path: &Path) -> io::Result<Option<Vec<NamedHash>>> {
+        let mut xattrs = vec![];
+        for name in crate::macos::TRACKED_XATTRS {
+            if let Some(value) = crate::macos::xattr(path, name)? {
+                xattrs.push((name.to_string(), self.hash_bytes(&value)));
+            }
         }
-        hasher.update(&buffer[..bytes_read]);
+        Ok(Some(xattrs))
     }
 
-    Ok(hasher.finalize().as_slice().try_into().unwrap())
+    #[cfg(not(target_os = "macos"))]
+    fn xattrs(&self, _path: &Path) -> io::Result<Option<Vec<NamedHash>>> {
+        Ok(None)
+    }
+
+    /// Names and hashes of a regular file's alternate data streams
+    #[cfg(windows)]
+    fn alternate_streams(
+        &mut self,
+        path: &Path,
+        metadata: &Metadata,
+    ) -> io::Result<Option<Vec<NamedHash>>> {
+        if metadata.is_file() {
+            crate::windows::alternate_streams(path, |stream| {
+                self.hash_contents(&File::open(stream)?, None, Wanted::default())
+                    .map(|hashed| hashed.content_hash)
+            })
+            .map(Some)
+        } else {
+            Ok(None)
+        }
+    }
+
+    #[cfg(not(windows))]
+    fn alternate_streams(
+        &mut self,
+        _path: &Path,
+        _metadata: &Metadata,
+    ) -> io::Result<Option<Vec<NamedHash>>> {
+        Ok(None)
+    }
+
+    /// Generate file fingerprint, reusing the content hash of any
+    /// hardlink to the same inode that has already been read
+    ///
+    /// Files of at least `CHUNKED_THRESHOLD` bytes are hashed in
+    /// chunks (in parallel), as are all files if block hashes are to
+    /// be recorded. Special files (FIFOs, sockets and devices) are
+    /// never opened.
+    pub fn fingerprint_file(&mut self, path: &Path) -> io::Result<Fingerprint> {
+        self.fingerprint_file_as(path, None, None)
+    }
+
+    /// Generate file fingerprint, hashing contents the same way as in
+    /// the fingerprint recorded, if any
+    ///
+    /// If the file still has the cache key given, its contents are
+    /// taken to be those recorded and not read at all.
+    fn fingerprint_file_as(
+        &mut self,
+        path: &Path,
+        recorded: Option<&Fingerprint>,
+        cached: Option<&CacheKey>,
+    ) -> io::Result<Fingerprint> {
+        if let Some(progress) = &self.progress {
+            progress.files.fetch_add(1, Ordering::Relaxed);
+        }
+        // a symlink's contents are those of its target, which under a
+        // root must be found there too
+        let (path, contents) = match &self.root {
+            Some(root) => (rooted(root, path, false), rooted(root, path, true)),
+            None => (path.to_path_buf(), path.to_path_buf()),
+        };
+        self.time_limit = self.time_limit_for(&path);
+
+        // a regular file is opened once, and what is recorded of it
+        // comes from the open file, so it can't be swapped for
+        // another in between; the file is first touched here, so a
+        // hung mount is given up on within the time limit
+        let opening = path.clone();
+        let (linked, opened) = timed(self.time_limit, "opening", move || {
+            let linked = symlink_metadata(&opening)?;
+            let opened = match linked.is_file() {
+                true => {
+                    let file = open_contents(&opening, false).map_err(changed_since_found)?;
+                    let opened = file.metadata()?;
+                    unchanged_since(&linked, &opened)?;
+                    Some((file, opened))
+                }
+                false => None,
+            };
+            Ok::<_, io::Error>((linked, opened))
+        })?;
+        let (path, contents) = (path.as_path(), contents.as_path());
+        let (metadata, mut file) = match opened {
+            Some((file, opened)) => (opened.clone(), Some((file, opened))),
+            None => (linked.clone(), None),
+        };
+        let platform = platform_attributes(path, &metadata)?;
+        let identity = platform.identity;
+        // paranoid reads read every file, cached or not
+        let unchanged = match (recorded, cached, identity, metadata.modified()) {
+            _ if self.paranoid => None,
+            (Some(recorded), Some(cached), Some((dev, ino)), Ok(modified))
+                if *cached
+                    == (CacheKey {
+                        dev,
+                        ino,
+                        size: metadata.len(),
+                        modified,
+                    }) =>
+            {
+                Some(recorded)
+            }
+            _ => None,
+        };
+        if unchanged.is_some() {
+            debug!("unchanged since cached, not hashed");
+        }
+        // a symlink to a special file or directory is just as
+        // unreadable
+        let target = match metadata.is_symlink() {
+            true => std::fs::metadata(contents).ok(),
+            false => Some(metadata.clone()),
+        };
+        let special = target.as_ref().and_then(special_file);
+        let directory = target.as_ref().is_some_and(Metadata::is_dir);
+
+        let (chunk_size, keep_blocks) = match (recorded, self.block_size) {
+            _ if special.is_some() || directory => (None, false),
+            (Some(recorded), _) => (recorded.chunk_size, recorded.block_hashes.is_some()),
+            (None, Some(block_size)) => (Some(block_size), true),
+            (None, None) => (
+                (metadata.len() >= CHUNKED_THRESHOLD).then_some(CHUNK_SIZE),
+                false,
+            ),
+        };
+
+        let wanted = Wanted {
+            fuzzy: match recorded {
+                Some(recorded) => recorded.fuzzy_hash.is_some(),
+                None => self.fuzzy,
+            },
+            digest: self.digest,
+        };
+
+        // a symlink's target is opened only to be read, and must be
+        // the file found there
+        let reading = special.is_none() && !directory && unchanged.is_none();
+        if reading && file.is_none() {
+            let target_file = open_contents(contents, true).map_err(|e| match target {
+                Some(_) => changed_since_found(e),
+                None => e,
+            })?;
+            let opened = target_file.metadata()?;
+            if let Some(target) = &target {
+                unchanged_since(target, &opened)?;
+            }
+            file = Some((target_file, opened));
+        }
+
+        let hashed = match (special, unchanged, identity, &file) {
+            _ if directory => Hashed::nothing(self.hash_bytes(&directory_listing(contents)?)),
+            (Some(_), _, _, _) => Hashed::nothing(self.hash_bytes(&[])),
+            (None, Some(recorded), _, _) => Hashed::recorded(recorded),
+            (None, None, _, None) => unreachable!("contents to be read are opened"),
+            (None, None, Some(key), Some((file, _)))
+                if !metadata.is_symlink() && platform.hardlinked =>
+            {
+                match self.hardlinks.get(&(key, chunk_size, wanted)) {
+                    Some(hashed) => hashed.clone(),
+                    None => {
+                        let hashed = self.hash_contents(file, chunk_size, wanted)?;
+                        self.hardlinks
+                            .insert((key, chunk_size, wanted), hashed.clone());
+                        hashed
+                    }
+                }
+            }
+            (None, None, _, Some((file, _))) => self.hash_contents(file, chunk_size, wanted)?,
+        };
+
+        // what was read must still be there, unchanged, afterwards
+        if let (true, Some((file, opened))) = (reading, &file) {
+            unchanged_since(opened, &file.metadata()?)?;
+            unchanged_since(&linked, &symlink_metadata(path)?)?;
+        }
+
+        // device and inode numbers of a file system mounted elsewhere
+        // say nothing about whether the file was replaced there
+        let (dev, ino) = match (&self.root, recorded) {
+            (Some(_), Some(recorded)) => (recorded.dev, recorded.ino),
+            _ => (identity.map(|(dev, _)| dev), identity.map(|(_, ino)| ino)),
+        };
+        // a symlink's, like its modification time, is the link's own,
+        // not that of the target opened through it
+        let opened = file
+            .as_ref()
+            .filter(|_| !metadata.is_symlink())
+            .map(|(file, _)| file);
+        let created = birth_time(path, opened, &metadata);
+        // touched by any entry coming and going, as is the size on
+        // some file systems
+        let modified = metadata.modified().ok().filter(|_| !metadata.is_dir());
+        let (created, modified) = match recorded {
+            Some(recorded) => (
+                within(recorded.created, created, self.time_tolerance),
+                within(recorded.modified, modified, self.time_tolerance),
+            ),
+            None => (created, modified),
+        };
+        Ok(Fingerprint {
+            content_hash: hashed.content_hash,
+            symlink: metadata.is_symlink(),
+            created,
+            modified,
+            unix_mode: platform.unix_mode,
+            read_only: metadata.permissions().readonly(),
+            size: Some(metadata.len()).filter(|_| !metadata.is_dir()),
+            dev,
+            ino,
+            algorithm: self.algorithm(),
+            windows_attributes: platform.windows_attributes,
+            security_hash: platform.security_hash,
+            streams: self.alternate_streams(path, &metadata)?,
+            xattrs: self.xattrs(path)?,
+            flags: platform.flags,
+            uid: platform.ownership.map(|(uid, _)| uid),
+            gid: platform.ownership.map(|(_, gid)| gid),
+            chunk_size,
+            block_hashes: hashed.chunks.filter(|_| keep_blocks),
+            entropy: hashed.entropy,
+            fuzzy_hash: hashed.fuzzy_hash,
+            digest: hashed.digest,
+            special,
+            directory,
+            tags: vec![],
+            note: None,
+            link_target: match metadata.is_symlink() {
+                true => Some(read_link(path)?),
+                false => None,
+            },
+        })
+    }
+
+    /// Fingerprint a file on disk
+    pub fn fingerprint(&mut self, path: &Path) -> Result<Fingerprint, FimblError> {
+        Ok(self.fingerprint_file(path)?)
+    }
+
+    /// Fingerprint a file on disk, digesting its contents by the
+    /// algorithm given as well, in the same read
+    pub fn fingerprint_digested(
+        &mut self,
+        path: &Path,
+        algorithm: DigestAlgorithm,
+    ) -> Result<Fingerprint, FimblError> {
+        let digest = self.digest.replace(algorithm);
+        let fingerprint = self.fingerprint_file(path);
+        self.digest = digest;
+        Ok(fingerprint?)
+    }
+
+    /// Fingerprint a file on disk for comparison with the fingerprint
+    /// recorded, hashing it the same way (in chunks or not)
+    pub fn fingerprint_like(
+        &mut self,
+        path: &Path,
+        recorded: &Fingerprint,
+    ) -> Result<Fingerprint, FimblError> {
+        Ok(self.fingerprint_file_as(path, Some(recorded), None)?)
+    }
+
+    /// Fingerprint a file for comparison with the fingerprint
+    /// recorded but, if it still has the cache key given, without
+    /// reading its contents
+    pub fn fingerprint_cached(
+        &mut self,
+        path: &Path,
+        recorded: &Fingerprint,
+        cached: &CacheKey,
+    ) -> Result<Fingerprint, FimblError> {
+        Ok(self.fingerprint_file_as(path, Some(recorded), Some(cached))?)
+    }
 }
 
-#[cfg(windows)]
-fn unix_mode(metadata: &Metadata) -> Option<u32> {
-    None
+/// Do something touching a file on a thread of its own, giving up on it
+/// after the time limit, if there is one
+///
+/// A call stuck on a hung mount can't be interrupted, so the thread is
+/// left behind, blocked, and fimbl moves on.
+fn timed<T, E>(
+    limit: Option<Duration>,
+    what: &str,
+    touch: impl FnOnce() -> Result<T, E> + Send + 'static,
+) -> Result<T, E>
+where
+    T: Send + 'static,
+    E: From<io::Error> + Send + 'static,
+{
+    let Some(limit) = limit else {
+        return touch();
+    };
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        // nobody is listening any more if this took too long
+        let _ = sender.send(touch());
+    });
+    match receiver.recv_timeout(limit) {
+        Ok(done) => done,
+        Err(mpsc::RecvTimeoutError::Timeout) => {
+            let limit = humantime::format_duration(limit);
+            warn!(%limit, "gave up {what}");
+            Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!("{what} not done within {limit}"),
+            )
+            .into())
+        }
+        Err(mpsc::RecvTimeoutError::Disconnected) => {
+            Err(io::Error::other(format!("thread {what} failed")).into())
+        }
+    }
+}
+
+/// Hash file contents, with an HMAC if there is a key, in chunks of
+/// the size given, if any, and with a fuzzy hash or SHA-256 if wanted,
+/// returning the results and the number of bytes read
+fn hash_with(
+    key: Option<&HashKey>,
+    reader: &mut ContentReader,
+    file: &File,
+    chunk_size: Option<u64>,
+    wanted: Wanted,
+) -> io::Result<(Hashed, u64)> {
+    let mut stats = ContentStats::new(wanted);
+    let (content_hash, chunks) = match (chunk_size, key) {
+        (Some(chunk_size), _) => {
+            let hashes = reader.chunk_hashes(file, chunk_size, &mut stats)?;
+            let content_hash = keyed_hash(key, &hashes.concat());
+            // with a key, the hash of each chunk is kept keyed too, so
+            // that those recorded say nothing of the contents
+            let hashes = match key {
+                Some(_) => hashes.iter().map(|hash| keyed_hash(key, hash)).collect(),
+                None => hashes,
+            };
+            (content_hash, Some(hashes))
+        }
+        (None, Some(key)) => (hmac_contents(file, key, reader, &mut stats)?, None),
+        (None, None) => (hash_contents(file, reader, &mut stats)?, None),
+    };
+    let hashed = Hashed {
+        content_hash,
+        chunks,
+        entropy: Some(stats.counts.entropy()),
+        fuzzy_hash: stats.fuzzy_hash(),
+        digest: stats
+            .digest
+            .take()
+            .map(|digest| digest.finalize().into_vec()),
+    };
+    Ok((hashed, stats.counts.total()))
 }
 
-#[cfg(not(windows))]
-fn unix_mode(metadata: &Metadata) -> Option<u32> {
-    Some(metadata.permissions().mode())
+/// Hash a value, with an HMAC if there is a key
+fn keyed_hash(key: Option<&HashKey>, bytes: &[u8]) -> HashValue {
+    match key {
+        Some(key) => {
+            let mut mac =
+                Hmac::<Hash>::new_from_slice(&key.0).expect("HMAC accepts keys of any size");
+            mac.update(bytes);
+            mac.finalize().into_bytes().into()
+        }
+        None => Hash::digest(bytes).into(),
+    }
 }
 
-/// Generate file fingerprint for comparison or storage
-pub fn fingerprint_file(path: &Path) -> io::Result<Fingerprint> {
-    let metadata = symlink_metadata(path)?;
-    let content_hash = hash_contents(path)?;
+/// Most symlinks followed in finding a file under a root, as for
+/// `ELOOP`
+const MAX_SYMLINKS: usize = 40;
 
-    Ok(Fingerprint {
-        content_hash,
-        symlink: metadata.is_symlink(),
-        created: metadata.created().ok(),
-        modified: metadata.modified().ok(),
-        unix_mode: unix_mode(&metadata),
-        read_only: metadata.permissions().readonly(),
-    })
+/// Where a path of the file system mounted at root is on disk,
+/// following symlinks in it (but not a final one, unless asked) as if
+/// root were /, so that none leads out of it
+pub fn rooted(root: &Path, path: &Path, follow: bool) -> PathBuf {
+    // components still to resolve, and those resolved (under root)
+    let mut pending: VecDeque<OsString> = VecDeque::new();
+    let mut resolved: Vec<OsString> = vec![];
+    let mut followed = 0;
+    fn visit(path: &Path, pending: &mut VecDeque<OsString>, resolved: &mut Vec<OsString>) {
+        if path.has_root() {
+            resolved.clear();
+        }
+        let names = path.components().filter_map(|component| match component {
+            Component::Normal(name) => Some(name.to_owned()),
+            Component::ParentDir => Some(OsString::from("..")),
+            _ => None,
+        });
+        for (i, name) in names.enumerate() {
+            pending.insert(i, name);
+        }
+    }
+    visit(path, &mut pending, &mut resolved);
+
+    let on_disk = |resolved: &[OsString]| -> PathBuf {
+        std::iter::once(root.as_os_str())
+            .chain(resolved.iter().map(OsString::as_os_str))
+            .collect()
+    };
+    while let Some(name) = pending.pop_front() {
+        if name == ".." {
+            resolved.pop();
+            continue;
+        }
+        resolved.push(name);
+        if (follow || !pending.is_empty()) && followed < MAX_SYMLINKS {
+            if let Ok(target) = read_link(on_disk(&resolved)) {
+                followed += 1;
+                resolved.pop();
+                visit(&target, &mut pending, &mut resolved);
+            }
+        }
+    }
+    on_disk(&resolved)
+}
+
+/// A path and, if it is a symlink, the chain of paths it leads to,
+/// each relative target taken from the directory of the link
+///
+/// The chain stops short after `MAX_SYMLINKS` links, as in a loop.
+pub fn symlink_chain(path: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut chain = vec![path.to_path_buf()];
+    let mut link = path.to_path_buf();
+    while link.is_symlink() && chain.len() <= MAX_SYMLINKS {
+        let target = read_link(&link)?;
+        link = match link.parent() {
+            Some(dir) => dir.join(target),
+            None => target,
+        };
+        chain.push(link.clone());
+    }
+    Ok(chain)
+}
+
+/// Size of file, without reading its contents
+pub fn file_size(path: &Path) -> io::Result<u64> {
+    Ok(symlink_metadata(path)?.len())
 }
 
 impl Fingerprint {
-    /// Fingerprint a file on disk
-    pub fn from_file(path: &Path) -> Result<Self, FimblError> {
-        Ok(fingerprint_file(path)?)
+    /// Key for caching this fingerprint's content hash, if device,
+    /// inode and modification time are all known
+    pub fn cache_key(&self) -> Option<CacheKey> {
+        Some(CacheKey {
+            dev: self.dev?,
+            ino: self.ino?,
+            size: self.size?,
+            modified: self.modified?,
+        })
+    }
+
+    /// True unless both fingerprints record a device and inode and
+    /// these differ (i.e. the file has been replaced)
+    pub fn same_identity(&self, current: &Fingerprint) -> bool {
+        match (self.dev.zip(self.ino), current.dev.zip(current.ino)) {
+            (Some(recorded), Some(current)) => recorded == current,
+            _ => true,
+        }
+    }
+
+    /// True if the current fingerprint is consistent with this
+    /// (recorded) one
+    ///
+    /// Attributes that were not recorded, for instance by an older
+    /// version of fimbl, are not compared. Nor are file identity,
+    /// which is checked separately by `same_identity`, alternate data
+    /// streams (see `stream_changes`), extended attributes (see
+    /// `xattr_changes`), flags (see `flags_match`) or where a symlink
+    /// points (see `retargeted`).
+    pub fn matches(&self, current: &Fingerprint) -> bool {
+        let mut current = current.clone();
+        current.flags = self.flags;
+        current.link_target = self.link_target.clone();
+        current.streams = self.streams.clone();
+        current.xattrs = self.xattrs.clone();
+        current.block_hashes = self.block_hashes.clone();
+        current.entropy = self.entropy;
+        current.fuzzy_hash = self.fuzzy_hash.clone();
+        current.tags = self.tags.clone();
+        current.note = self.note.clone();
+        if self.size.is_none() {
+            current.size = None;
+        }
+        if self.windows_attributes.is_none() {
+            current.windows_attributes = None;
+        }
+        if self.security_hash.is_none() {
+            current.security_hash = None;
+        }
+        if self.uid.is_none() {
+            current.uid = None;
+        }
+        if self.gid.is_none() {
+            current.gid = None;
+        }
+        if self.created.is_none() {
+            current.created = None;
+        }
+        if self.modified.is_none() {
+            current.modified = None;
+        }
+        current.dev = self.dev;
+        current.ino = self.ino;
+        *self == current
+    }
+
+    /// This fingerprint without the attributes that belong to the host
+    /// the file is on rather than to the file (its device and inode,
+    /// times, flags and Windows security), for comparing with the same
+    /// file on other hosts
+    pub fn portable(&self) -> Fingerprint {
+        Fingerprint {
+            dev: None,
+            ino: None,
+            created: None,
+            modified: None,
+            flags: None,
+            security_hash: None,
+            ..self.clone()
+        }
+    }
+
+    /// This (current) fingerprint with the tags and note of the
+    /// recorded one, which belong to the record rather than the file
+    pub fn annotated_like(&self, recorded: &Fingerprint) -> Fingerprint {
+        Fingerprint {
+            tags: recorded.tags.clone(),
+            note: recorded.note.clone(),
+            ..self.clone()
+        }
+    }
+
+    /// True if this has any of the tags given, or none are given
+    pub fn tagged(&self, tags: &[String]) -> bool {
+        tags.is_empty() || tags.iter().any(|tag| self.tags.contains(tag))
+    }
+
+    /// True if the current fingerprint has the same contents as this
+    /// (recorded) one, including any alternate data streams, whatever
+    /// has happened to its metadata
+    pub fn same_contents(&self, current: &Fingerprint) -> bool {
+        self.algorithm == current.algorithm
+            && self.content_hash == current.content_hash
+            && self.stream_changes(current).is_empty()
+    }
+
+    /// Alternate data streams added, changed or removed since this
+    /// (recorded) fingerprint, if both record streams
+    pub fn stream_changes(&self, current: &Fingerprint) -> Vec<(String, NamedChange)> {
+        match (&self.streams, &current.streams) {
+            (Some(recorded), Some(current)) => named_changes(recorded, current),
+            _ => vec![],
+        }
+    }
+
+    /// Recorded and current targets of a symlink, if both are recorded
+    /// and differ
+    pub fn retargeted<'a>(&'a self, current: &'a Fingerprint) -> Option<(&'a Path, &'a Path)> {
+        match (&self.link_target, &current.link_target) {
+            (Some(recorded), Some(now)) if recorded != now => Some((recorded, now)),
+            _ => None,
+        }
+    }
+
+    /// True unless both fingerprints record flags and these differ
+    pub fn flags_match(&self, current: &Fingerprint) -> bool {
+        match (self.flags, current.flags) {
+            (Some(recorded), Some(current)) => recorded == current,
+            _ => true,
+        }
+    }
+
+    /// True if this (recorded) fingerprint has the immutable flag but
+    /// the current one does not
+    pub fn immutable_removed(&self, current: &Fingerprint) -> bool {
+        matches!(
+            (self.flags, current.flags),
+            (Some(recorded), Some(current)) if recorded.immutable && !current.immutable
+        )
+    }
+
+    /// Recorded and current entropy (in bits per byte), if the
+    /// contents have gone from plaintext to looking encrypted or
+    /// compressed
+    pub fn entropy_jumped(&self, current: &Fingerprint) -> Option<(f64, f64)> {
+        match (self.entropy, current.entropy) {
+            (Some(recorded), Some(now))
+                if recorded < LOW_ENTROPY
+                    && now >= HIGH_ENTROPY
+                    && current.size.unwrap_or(0) >= MIN_ENTROPY_SIZE =>
+            {
+                Some((recorded as f64 / 1000.0, now as f64 / 1000.0))
+            }
+            _ => None,
+        }
+    }
+
+    /// TLSH distance from this (recorded) fingerprint's contents to the
+    /// current contents, if both have fuzzy hashes: 0 for (nearly)
+    /// identical contents, growing with the extent of changes
+    pub fn fuzzy_distance(&self, current: &Fingerprint) -> Option<i32> {
+        let recorded: tlsh2::TlshDefault = self.fuzzy_hash.as_deref()?.parse().ok()?;
+        let current: tlsh2::TlshDefault = current.fuzzy_hash.as_deref()?.parse().ok()?;
+        Some(recorded.diff(&current, true))
+    }
+
+    /// Tracked extended attributes added, changed or removed since
+    /// this (recorded) fingerprint, if both record them
+    pub fn xattr_changes(&self, current: &Fingerprint) -> Vec<(String, NamedChange)> {
+        match (&self.xattrs, &current.xattrs) {
+            (Some(recorded), Some(current)) => named_changes(recorded, current),
+            _ => vec![],
+        }
     }
 }
 
@@ -106,7 +2260,172 @@ pub mod tests {
             180, 140, 108, 172, 36, 194, 255, 235, 235, 56, 177, 126, 45, 82, 184, 188, 208, 200,
             0, 45, 188, 213, 174, 119, 118, 223, 231, 174, 161, 208, 249, 145,
         ];
-        assert_eq!(hash_contents(&d).unwrap(), expected);
+        let readers = [
+            ContentReader::default(),
+            ContentReader {
+                buffer_size: 7,
+                ..Default::default()
+            },
+            ContentReader {
+                mmap: true,
+                ..Default::default()
+            },
+        ];
+        for mut reader in readers {
+            let mut stats = ContentStats::default();
+            let hash = hash_contents(&File::open(&d).unwrap(), &mut reader, &mut stats).unwrap();
+            assert_eq!((hash, stats.counts.total()), (expected, 446));
+        }
+    }
+
+    #[test]
+    fn test_chunked_hash() {
+        let mut d = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        d.push("resources/test/loremipsum.txt");
+        let contents = read(&d).unwrap();
+        let chunks: Vec<u8> = contents
+            .chunks(100)
+            .flat_map(|chunk| Hash::digest(chunk).to_vec())
+            .collect();
+        let expected: HashValue = Hash::digest(chunks).into();
+
+        let wanted = Wanted {
+            fuzzy: true,
+            digest: Some(DigestAlgorithm::Sha256),
+        };
+        let mut fingerprinters = [
+            Fingerprinter::default(),
+            Fingerprinter::default().with_reads(7, true),
+            Fingerprinter::default().with_rate_limit(Some(1 << 30)),
+        ];
+        for fingerprinter in &mut fingerprinters {
+            let hashed = fingerprinter
+                .hash_contents(&File::open(&d).unwrap(), Some(100), wanted)
+                .unwrap();
+            assert_eq!(hashed.content_hash, expected);
+            assert_eq!(hashed.chunks.map(|chunks| chunks.len()), Some(5));
+            assert!(hashed.fuzzy_hash.is_some());
+            assert_eq!(hashed.digest, Some(Sha256::digest(&contents).to_vec()));
+            assert_eq!(fingerprinter.bytes_hashed(), 446);
+        }
+    }
+
+    #[test]
+    fn test_fingerprint_like_recorded_chunking() {
+        let mut d = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        d.push("resources/test/loremipsum.txt");
+        let mut fingerprinter = Fingerprinter::default();
+
+        let whole = fingerprinter.fingerprint(&d).unwrap();
+        assert_eq!(whole.chunk_size, None);
+
+        let mut recorded = whole.clone();
+        recorded.chunk_size = Some(100);
+        let chunked = fingerprinter.fingerprint_like(&d, &recorded).unwrap();
+        assert_eq!(chunked.chunk_size, Some(100));
+        assert_ne!(chunked.content_hash, whole.content_hash);
+        assert_eq!(fingerprinter.fingerprint_like(&d, &whole).unwrap(), whole);
+
+        recorded.chunk_size = Some(0);
+        let encoded = rmp_serde::to_vec(&recorded).unwrap();
+        assert!(rmp_serde::from_slice::<Fingerprint>(&encoded).is_err());
+    }
+
+    #[test]
+    fn test_entropy() {
+        let mut counts = ByteCounts::default();
+        counts.add(&[b'a'; 1000]);
+        assert_eq!(counts.entropy(), 0);
+        counts.add(&[b'b'; 1000]);
+        assert_eq!(counts.entropy(), 1000);
+
+        let mut counts = ByteCounts::default();
+        counts.add(&(0..=255).collect::<Vec<u8>>());
+        assert_eq!(counts.entropy(), 8000);
+
+        let mut d = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        d.push("resources/test/loremipsum.txt");
+        let entropy = fingerprint_file(&d).unwrap().entropy.unwrap();
+        assert!((3000..LOW_ENTROPY).contains(&entropy));
+    }
+
+    #[test]
+    fn test_files_under() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path();
+        std::fs::create_dir_all(dir.join("ssh/sshd_config.d")).unwrap();
+        std::fs::write(dir.join("hosts"), "127.0.0.1 localhost").unwrap();
+        std::fs::write(dir.join("ssh/sshd_config.d/local.conf"), "Port 22").unwrap();
+        let expected = vec![dir.join("hosts"), dir.join("ssh/sshd_config.d/local.conf")];
+        assert_eq!(files_under(dir, false).unwrap(), expected);
+        assert_eq!(files_under(dir, true).unwrap(), expected);
+
+        // symlinks are files, not followed
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink("ssh", dir.join("link")).unwrap();
+            let files = files_under(dir, false).unwrap();
+            assert_eq!(files.len(), 3);
+            assert!(files.contains(&dir.join("link")));
+            assert_eq!(
+                files_under(&dir.join("link"), false).unwrap(),
+                vec![dir.join("link")]
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("1000"), Ok(1000));
+        assert_eq!(parse_size("512K"), Ok(512 * 1024));
+        assert_eq!(parse_size("20m"), Ok(20 * 1024 * 1024));
+        assert_eq!(parse_size("1G"), Ok(1 << 30));
+        assert!(parse_size("0").is_err());
+        assert!(parse_size("fast").is_err());
+        assert!(parse_size("M").is_err());
+        assert_eq!(parse_read_buffer("1G"), Ok(1 << 30));
+        assert!(parse_read_buffer("2G").is_err());
+        assert!(parse_read_buffer("0").is_err());
+    }
+
+    /// Compare hashing throughput of small reads, large reads and
+    /// mmap (run with `cargo test --release -- --ignored --nocapture`)
+    #[test]
+    #[ignore]
+    fn bench_hash_contents() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("large");
+        let chunk: Vec<u8> = (0..DEFAULT_READ_BUFFER).map(|i| i as u8).collect();
+        let mut file = File::create(&path).unwrap();
+        for _ in 0..256 {
+            std::io::Write::write_all(&mut file, &chunk).unwrap();
+        }
+        drop(file);
+
+        let readers = [
+            ("4 KiB reads", 4096, false),
+            ("1 MiB reads", DEFAULT_READ_BUFFER, false),
+            ("mmap", DEFAULT_READ_BUFFER, true),
+        ];
+        let mut hashes = vec![];
+        for (name, buffer_size, mmap) in readers {
+            let mut reader = ContentReader {
+                buffer_size,
+                mmap,
+                throttle: None,
+            };
+            let started = std::time::Instant::now();
+            let mut stats = ContentStats::default();
+            let hash = hash_contents(&File::open(&path).unwrap(), &mut reader, &mut stats).unwrap();
+            let bytes = stats.counts.total();
+            let seconds = started.elapsed().as_secs_f64();
+            println!(
+                "{name}: {:.1} MiB/s",
+                bytes as f64 / seconds / (1 << 20) as f64
+            );
+            hashes.push(hash);
+        }
+        assert!(hashes.windows(2).all(|pair| pair[0] == pair[1]));
     }
 
     #[test]
@@ -124,5 +2443,437 @@ pub mod tests {
         }
         assert!(!fingerprint.symlink);
         assert!(!fingerprint.read_only);
+        assert_eq!(fingerprint.size, Some(file_size(&d).unwrap()));
+        assert!(fingerprint.dev.is_some());
+        assert!(fingerprint.ino.is_some());
+        assert_eq!(
+            fingerprint.windows_attributes.is_some(),
+            cfg!(target_os = "windows")
+        );
+    }
+
+    #[test]
+    fn test_time_tolerance() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("coarse");
+        std::fs::write(&path, "coarse").unwrap();
+
+        let metadata = symlink_metadata(&path).unwrap();
+        let current = fingerprint_file(&path).unwrap();
+        assert_eq!(current.modified, metadata.modified().ok(), "full precision");
+        assert_eq!(current.created, metadata.created().ok());
+
+        // as recorded on a file system keeping times to the second
+        let truncate = |time: SystemTime| {
+            let since = time.duration_since(SystemTime::UNIX_EPOCH).unwrap();
+            SystemTime::UNIX_EPOCH + Duration::from_secs(since.as_secs())
+        };
+        let mut recorded = current.clone();
+        recorded.modified = current.modified.map(truncate);
+        recorded.created = current.created.map(truncate);
+
+        let strict = Fingerprinter::default()
+            .fingerprint_like(&path, &recorded)
+            .unwrap();
+        assert_eq!(strict.modified, current.modified);
+        let tolerant = Fingerprinter::default()
+            .with_time_tolerance(Duration::from_secs(1))
+            .fingerprint_like(&path, &recorded)
+            .unwrap();
+        assert!(recorded.matches(&tolerant));
+
+        recorded.modified = current.modified.map(|time| time - Duration::from_secs(5));
+        let tolerant = Fingerprinter::default()
+            .with_time_tolerance(Duration::from_secs(1))
+            .fingerprint_like(&path, &recorded)
+            .unwrap();
+        assert!(!recorded.matches(&tolerant));
+        assert_eq!(tolerant.modified, current.modified);
+    }
+
+    #[test]
+    fn test_hardlinks_share_content_hash() {
+        let dir = tempfile::tempdir().unwrap();
+        let original = dir.path().join("original");
+        let link = dir.path().join("link");
+        std::fs::write(&original, "hardlinked").unwrap();
+        std::fs::hard_link(&original, &link).unwrap();
+
+        let mut fingerprinter = Fingerprinter::default();
+        let first = fingerprinter.fingerprint_file(&original).unwrap();
+        let second = fingerprinter.fingerprint_file(&link).unwrap();
+        assert_eq!(first.content_hash, second.content_hash);
+        assert_eq!(first.ino, second.ino);
+        assert_eq!(fingerprinter.hardlinks.len(), 1);
+    }
+
+    #[test]
+    fn test_cached_contents_are_not_read() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("cached");
+        std::fs::write(&path, "cached contents").unwrap();
+        let recorded = fingerprint_file(&path).unwrap();
+        let mut key = recorded.cache_key().unwrap();
+
+        let mut fingerprinter = Fingerprinter::default();
+        let current = fingerprinter
+            .fingerprint_cached(&path, &recorded, &key)
+            .unwrap();
+        assert_eq!(fingerprinter.bytes_hashed(), 0);
+        assert!(recorded.matches(&current));
+
+        key.size += 1;
+        fingerprinter
+            .fingerprint_cached(&path, &recorded, &key)
+            .unwrap();
+        assert_eq!(fingerprinter.bytes_hashed(), 15);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_special_files_are_not_read() {
+        let dir = tempfile::tempdir().unwrap();
+        let fifo = dir.path().join("fifo");
+        let name = std::ffi::CString::new(fifo.to_str().unwrap()).unwrap();
+        // SAFETY: name is a valid C string
+        assert_eq!(unsafe { libc::mkfifo(name.as_ptr(), 0o600) }, 0);
+
+        // opening the FIFO would block until a writer turned up
+        let recorded = fingerprint_file(&fifo).unwrap();
+        assert_eq!(recorded.special, Some(SpecialFile::Fifo));
+        assert_eq!(recorded.entropy, None);
+
+        let null = fingerprint_file(Path::new("/dev/null")).unwrap();
+        assert!(matches!(null.special, Some(SpecialFile::CharDevice { .. })));
+
+        let link = dir.path().join("link");
+        std::os::unix::fs::symlink("/dev/null", &link).unwrap();
+        let linked = fingerprint_file(&link).unwrap();
+        assert!(linked.symlink);
+        assert_eq!(linked.special, null.special);
+
+        std::fs::remove_file(&fifo).unwrap();
+        std::fs::write(&fifo, "").unwrap();
+        assert!(!recorded.matches(&fingerprint_file(&fifo).unwrap()));
+    }
+
+    #[test]
+    fn test_file_timeout() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("slow");
+        std::fs::write(&path, [b'x'; 1000]).unwrap();
+
+        let mut fingerprinter =
+            Fingerprinter::default().with_file_timeout(Some(Duration::from_secs(60)));
+        fingerprinter.fingerprint_file(&path).unwrap();
+        assert_eq!(fingerprinter.bytes_hashed(), 1000);
+
+        let mut fingerprinter = Fingerprinter::default()
+            .with_rate_limit(Some(100))
+            .with_reads(100, false)
+            .with_file_timeout(Some(Duration::from_millis(50)));
+        let error = fingerprinter.fingerprint_file(&path).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::TimedOut);
+
+        // as is anything else touching the file, stuck as on a hung mount
+        assert!(fingerprinter.metadata(&path).unwrap().is_file());
+        let stuck = fingerprinter.within_time_limit(&path, "reading metadata", || {
+            std::thread::sleep(Duration::from_secs(1));
+            Ok::<_, io::Error>(())
+        });
+        assert_eq!(stuck.unwrap_err().kind(), io::ErrorKind::TimedOut);
+    }
+
+    #[test]
+    fn test_paranoid_reads() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("twice");
+        std::fs::write(&path, [b'x'; 1000]).unwrap();
+
+        let mut fingerprinter = Fingerprinter::default().with_paranoid_reads(true);
+        let fingerprint = fingerprinter.fingerprint_file(&path).unwrap();
+        assert_eq!(fingerprinter.bytes_hashed(), 2000, "read twice");
+        assert_eq!(
+            fingerprint.content_hash,
+            fingerprint_file(&path).unwrap().content_hash
+        );
+
+        // even if unchanged since cached
+        #[cfg(unix)]
+        {
+            let metadata = std::fs::metadata(&path).unwrap();
+            let cached = CacheKey {
+                dev: metadata.dev(),
+                ino: metadata.ino(),
+                size: metadata.len(),
+                modified: metadata.modified().unwrap(),
+            };
+            fingerprinter.reset();
+            fingerprinter
+                .fingerprint_file_as(&path, Some(&fingerprint), Some(&cached))
+                .unwrap();
+            assert_eq!(
+                fingerprinter.bytes_hashed(),
+                2000,
+                "not taken from the cache"
+            );
+        }
+
+        // a file giving different contents each time it is read, with
+        // nothing about it changed
+        #[cfg(target_os = "linux")]
+        {
+            let file = File::open("/proc/sys/kernel/random/uuid").unwrap();
+            let read = fingerprinter.hash_contents(&file, None, Wanted::default());
+            assert!(read.is_err_and(|error| ReadsDisagree::is(&error)));
+        }
+    }
+
+    #[test]
+    fn test_file_changed_during_read() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("busy");
+        let other = dir.path().join("other");
+        std::fs::write(&path, [b'x'; 1000]).unwrap();
+        std::fs::write(&other, [b'y'; 1000]).unwrap();
+
+        // throttling keeps the file open long enough to change it
+        let slow = || {
+            Fingerprinter::default()
+                .with_rate_limit(Some(2000))
+                .with_reads(100, false)
+        };
+        let changing = |change: Box<dyn FnOnce() + Send>| {
+            let changer = thread::spawn(move || {
+                thread::sleep(Duration::from_millis(100));
+                change();
+            });
+            let error = slow().fingerprint_file(&path).unwrap_err();
+            changer.join().unwrap();
+            error
+        };
+
+        let written = path.clone();
+        let error = changing(Box::new(move || {
+            let mut file = std::fs::OpenOptions::new()
+                .append(true)
+                .open(written)
+                .unwrap();
+            std::io::Write::write_all(&mut file, b"more").unwrap();
+        }));
+        assert!(ChangedDuringRead::is(&error), "{error}");
+
+        let (from, to) = (other.clone(), path.clone());
+        let error = changing(Box::new(move || std::fs::rename(from, to).unwrap()));
+        assert!(ChangedDuringRead::is(&error), "{error}");
+
+        assert!(slow().fingerprint_file(&path).is_ok());
+
+        // gone, or swapped for a symlink, between lstat and open
+        let gone = changed_since_found(io::Error::from(io::ErrorKind::NotFound));
+        assert!(ChangedDuringRead::is(&gone), "{gone}");
+        #[cfg(unix)]
+        {
+            let linked = changed_since_found(io::Error::from_raw_os_error(libc::ELOOP));
+            assert!(ChangedDuringRead::is(&linked), "{linked}");
+        }
+        let denied = changed_since_found(io::Error::from(io::ErrorKind::PermissionDenied));
+        assert_eq!(denied.kind(), io::ErrorKind::PermissionDenied);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_network_fs_time_limit() {
+        // procfs counts as virtual, and throttling stands in for a
+        // hung server
+        let mut fingerprinter = Fingerprinter::default()
+            .with_rate_limit(Some(100))
+            .with_reads(100, false)
+            .with_network_fs(NetworkFs::Warn, Some(Duration::from_millis(50)))
+            .unwrap();
+        let status = Path::new("/proc/self/status");
+        assert_eq!(
+            fingerprinter.network_fs(status),
+            Some((NetworkFs::Warn, "proc"))
+        );
+        let error = fingerprinter.fingerprint_file(status).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::TimedOut);
+
+        // local files have no limit
+        let dir = tempfile::tempdir().unwrap();
+        let local = dir.path().join("local");
+        std::fs::write(&local, "local").unwrap();
+        assert_eq!(fingerprinter.network_fs(&local), None);
+        assert!(fingerprinter.fingerprint_file(&local).is_ok());
+    }
+
+    #[test]
+    fn test_keyed_hash_depends_on_key() {
+        let mut d = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        d.push("resources/test/loremipsum.txt");
+
+        let plain = fingerprint_file(&d).unwrap();
+        let keyed = Fingerprinter::new(Some(HashKey(b"secret".to_vec())))
+            .fingerprint_file(&d)
+            .unwrap();
+        let rekeyed = Fingerprinter::new(Some(HashKey(b"other".to_vec())))
+            .fingerprint_file(&d)
+            .unwrap();
+
+        assert_eq!(plain.algorithm, HashAlgorithm::Sha3_256);
+        assert_eq!(keyed.algorithm, HashAlgorithm::HmacSha3_256);
+        assert_ne!(plain.content_hash, keyed.content_hash);
+        assert_ne!(keyed.content_hash, rekeyed.content_hash);
+
+        let blocks = |key: Option<&[u8]>| {
+            Fingerprinter::new(key.map(|key| HashKey(key.to_vec())))
+                .with_block_hashes(Some(100))
+                .fingerprint_file(&d)
+                .unwrap()
+                .block_hashes
+                .unwrap()
+        };
+        let (plain, keyed) = (blocks(None), blocks(Some(b"secret")));
+        assert_eq!(plain.len(), keyed.len());
+        assert!(plain
+            .iter()
+            .zip(&keyed)
+            .all(|(plain, keyed)| plain != keyed));
+        assert_ne!(keyed, blocks(Some(b"other")));
+    }
+
+    #[test]
+    fn test_identity_change_is_not_a_mismatch() {
+        let mut d = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        d.push("resources/test/loremipsum.txt");
+
+        let recorded = fingerprint_file(&d).unwrap();
+        let mut replaced = recorded.clone();
+        replaced.ino = replaced.ino.map(|i| i + 1).or(Some(1));
+        assert!(recorded.matches(&replaced));
+        assert_eq!(recorded.same_identity(&replaced), recorded.ino.is_none());
+    }
+
+    #[test]
+    fn test_windows_security_compared_only_when_recorded() {
+        let mut d = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        d.push("resources/test/loremipsum.txt");
+
+        let mut recorded = fingerprint_file(&d).unwrap();
+        recorded.security_hash = None;
+        let mut current = recorded.clone();
+        current.security_hash = Some([1; HASH_SIZE]);
+        assert!(recorded.matches(&current));
+
+        recorded.security_hash = Some([2; HASH_SIZE]);
+        assert!(!recorded.matches(&current));
+    }
+
+    #[test]
+    fn test_named_changes() {
+        let recorded = vec![
+            ("Kept".to_string(), [0; HASH_SIZE]),
+            ("Modified".to_string(), [1; HASH_SIZE]),
+            ("Removed".to_string(), [2; HASH_SIZE]),
+        ];
+        let current = vec![
+            ("Added".to_string(), [3; HASH_SIZE]),
+            ("Kept".to_string(), [0; HASH_SIZE]),
+            ("Modified".to_string(), [4; HASH_SIZE]),
+        ];
+        assert_eq!(
+            named_changes(&recorded, &current),
+            vec![
+                ("Added".to_string(), NamedChange::Added),
+                ("Modified".to_string(), NamedChange::Changed),
+                ("Removed".to_string(), NamedChange::Removed),
+            ]
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_rooted() {
+        use std::os::unix::fs::symlink;
+
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        std::fs::create_dir_all(root.join("usr/lib")).unwrap();
+        std::fs::create_dir(root.join("etc")).unwrap();
+        std::fs::write(root.join("usr/lib/os-release"), "ID=image").unwrap();
+        // absolute links lead to the root, not out of it
+        symlink("/usr/lib", root.join("lib")).unwrap();
+        symlink("/lib/os-release", root.join("etc/os-release")).unwrap();
+        symlink("../../../../etc", root.join("usr/lib/up")).unwrap();
+        symlink("loop", root.join("loop")).unwrap();
+
+        let release = Path::new("/etc/os-release");
+        assert_eq!(rooted(root, release, false), root.join("etc/os-release"));
+        assert_eq!(rooted(root, release, true), root.join("usr/lib/os-release"));
+        assert_eq!(
+            rooted(root, Path::new("/lib/up/os-release"), false),
+            root.join("etc/os-release")
+        );
+        assert_eq!(rooted(root, Path::new("/loop"), true), root.join("loop"));
+
+        let mut fingerprinter = Fingerprinter::default().with_root(Some(root.to_path_buf()));
+        let image = fingerprinter.fingerprint(release).unwrap();
+        let direct = fingerprint_file(&root.join("usr/lib/os-release")).unwrap();
+        assert!(image.symlink);
+        assert_eq!(image.link_target, Some(PathBuf::from("/lib/os-release")));
+        assert_eq!(image.content_hash, direct.content_hash);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_symlink_chain() {
+        use std::os::unix::fs::symlink;
+
+        let dir = tempfile::tempdir().unwrap();
+        let etc = dir.path().join("etc");
+        std::fs::create_dir_all(etc.join("alternatives")).unwrap();
+        std::fs::write(dir.path().join("vim.basic"), "#!").unwrap();
+        // created a tick or two before the links to it
+        thread::sleep(Duration::from_millis(20));
+        // relative targets lead from the link's directory, not the
+        // current one
+        symlink("../../vim.basic", etc.join("alternatives/editor")).unwrap();
+        symlink("alternatives/editor", etc.join("editor")).unwrap();
+        symlink("loop", dir.path().join("loop")).unwrap();
+
+        let editor = etc.join("editor");
+        assert_eq!(
+            symlink_chain(&editor).unwrap(),
+            vec![
+                editor.clone(),
+                etc.join("alternatives/editor"),
+                etc.join("alternatives/../../vim.basic")
+            ]
+        );
+        let recorded = fingerprint_file(&editor).unwrap();
+        let linked = symlink_metadata(&editor).unwrap();
+        assert_eq!(recorded.created, birth_time(&editor, None, &linked));
+        assert_eq!(
+            recorded.link_target,
+            Some(PathBuf::from("alternatives/editor"))
+        );
+        assert_eq!(
+            symlink_chain(&dir.path().join("vim.basic")).unwrap().len(),
+            1
+        );
+        assert_eq!(
+            symlink_chain(&dir.path().join("loop")).unwrap().len(),
+            MAX_SYMLINKS + 1
+        );
+
+        std::fs::remove_file(&editor).unwrap();
+        symlink("../vim.basic", &editor).unwrap();
+        let current = fingerprint_file(&editor).unwrap();
+        assert_eq!(current.content_hash, recorded.content_hash);
+        assert_eq!(
+            recorded.retargeted(&current),
+            Some((Path::new("alternatives/editor"), Path::new("../vim.basic")))
+        );
+        assert_eq!(recorded.retargeted(&recorded), None);
     }
 }
@@ -0,0 +1,457 @@
+//! Append-only flat-file storage behind the database
+//!
+//! A database whose path ends `.jsonl` is kept in one plain file
+//! rather than a sled directory, for auditors and forensic analysts who
+//! would rather read it with `less` than trust a tool. Each change to
+//! any tree is appended as a line of JSON, and nothing is ever
+//! rewritten:
+//!
+//! ```text
+//! {"entry":{"op":"insert","tree":"meta","key":"schema-version","value-hex":"00000003"},"mac":"…"}
+//! ```
+//!
+//! Keys are given as strings where they are UTF-8 (else `key-hex`) and
+//! values as JSON where that decodes exactly to their MessagePack
+//! (else `value-hex`). A removal has no value.
+//!
+//! Each line's MAC covers the previous line's and the entry as written:
+//! HMAC-SHA3_256 with the record key, if there is one, otherwise plain
+//! SHA3-256. With the key, a line changed, removed or inserted (other
+//! than at the end) shows up when the file is next opened, and the
+//! database is refused. Without it, the chain only shows up damage:
+//! anyone who can write the file can compute it afresh.
+//!
+//! Each line is synced to disk as it is appended. A final line cut off
+//! without its newline, as by a crash while it was written, was never
+//! synced and so is taken not to have been written: it is cut off the
+//! file when next opened. The file is read into memory when opened,
+//! and locked while it is. Opened read-only (as on a dry run), it is
+//! never created, cut or written, and others may read it meanwhile.
+
+use crate::{
+    agent::SigningKey,
+    error::FimblError,
+    storage::{Entries, Store},
+};
+
+use serde_json::value::RawValue;
+use sha3::{Digest, Sha3_256};
+use std::{
+    collections::BTreeMap,
+    fs::{File, OpenOptions, TryLockError},
+    io::{Read, Write},
+    ops::Bound,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tracing::warn;
+
+/// Time between attempts to lock a file locked by another process
+const LOCK_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// What a line does
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone, Copy)]
+#[serde(rename_all = "kebab-case")]
+enum Op {
+    Insert,
+    Remove,
+}
+
+/// A change to one entry of one tree, as written
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "kebab-case")]
+struct Change {
+    op: Op,
+    tree: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    key: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    key_hex: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    value: Option<serde_json::Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    value_hex: Option<String>,
+}
+
+impl Change {
+    /// A change, with key and value as readable as they can be
+    fn new(op: Op, tree: &str, key: &[u8], value: Option<&[u8]>) -> Self {
+        let (key, key_hex) = match std::str::from_utf8(key) {
+            Ok(key) => (Some(key.to_string()), None),
+            Err(_) => (None, Some(hex::encode(key))),
+        };
+        let decoded = value.and_then(|value| {
+            let decoded: serde_json::Value = rmp_serde::from_slice(value).ok()?;
+            // null would read back as no value at all
+            (!decoded.is_null() && rmp_serde::to_vec(&decoded).ok()? == value).then_some(decoded)
+        });
+        let value_hex = match decoded {
+            None => value.map(hex::encode),
+            Some(_) => None,
+        };
+        Change {
+            op,
+            tree: tree.to_string(),
+            key,
+            key_hex,
+            value: decoded,
+            value_hex,
+        }
+    }
+
+    /// The key and, for an insertion, the value
+    fn entry(&self) -> Option<(Vec<u8>, Option<Vec<u8>>)> {
+        let key = match (&self.key, &self.key_hex) {
+            (Some(key), None) => key.as_bytes().to_vec(),
+            (None, Some(key)) => hex::decode(key).ok()?,
+            _ => return None,
+        };
+        let value = match (self.op, &self.value, &self.value_hex) {
+            (Op::Remove, None, None) => None,
+            (Op::Insert, Some(value), None) => Some(rmp_serde::to_vec(value).ok()?),
+            (Op::Insert, None, Some(value)) => Some(hex::decode(value).ok()?),
+            _ => return None,
+        };
+        Some((key, value))
+    }
+}
+
+/// A line of the file: a change, exactly as written, and its MAC
+#[derive(Serialize, Deserialize)]
+struct Line<'a> {
+    #[serde(borrow)]
+    entry: &'a RawValue,
+    mac: String,
+}
+
+/// The file and its contents
+struct State {
+    /// The file, unless opened read-only and never created
+    file: Option<File>,
+    trees: BTreeMap<String, BTreeMap<Vec<u8>, Vec<u8>>>,
+    /// MAC of the last line
+    last: Vec<u8>,
+}
+
+/// A database kept in an append-only flat file
+pub struct FlatFile {
+    path: PathBuf,
+    key: Option<SigningKey>,
+    state: Mutex<State>,
+}
+
+/// One tree of a database kept in a flat file
+pub struct FlatTree {
+    file: Arc<FlatFile>,
+    tree: &'static str,
+}
+
+/// True if the database at the path is kept in a flat file
+pub fn is_flat_file(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|extension| extension == "jsonl")
+}
+
+/// MAC of a line, given the previous line's
+fn mac(key: Option<&SigningKey>, previous: &[u8], entry: &str) -> Vec<u8> {
+    let body = [previous, entry.as_bytes()].concat();
+    match key {
+        Some(key) => key.tag(&body),
+        None => Sha3_256::digest(&body).to_vec(),
+    }
+}
+
+/// Lock a file (shared, unless to be written), waiting up to the time
+/// given for another process to release it
+fn lock(file: &File, path: &Path, writable: bool, lock_wait: Duration) -> Result<(), FimblError> {
+    let deadline = Instant::now() + lock_wait;
+    loop {
+        let locked = match writable {
+            true => file.try_lock(),
+            false => file.try_lock_shared(),
+        };
+        match locked {
+            Ok(()) => return Ok(()),
+            Err(TryLockError::WouldBlock) if Instant::now() < deadline => {
+                std::thread::sleep(LOCK_POLL_INTERVAL)
+            }
+            Err(TryLockError::WouldBlock) => {
+                return Err(FimblError::DatabaseBusy(path.to_owned(), None))
+            }
+            Err(TryLockError::Error(e)) => {
+                return Err(FimblError::DatabaseUnopenable(path.to_owned(), e))
+            }
+        }
+    }
+}
+
+impl FlatFile {
+    /// Open (or, if writable, create) the file, checking every line
+    pub fn open(
+        path: &Path,
+        key: Option<SigningKey>,
+        writable: bool,
+        lock_wait: Duration,
+    ) -> Result<Arc<Self>, FimblError> {
+        let unopenable = |e| FimblError::DatabaseUnopenable(path.to_owned(), e);
+        let file = match writable {
+            true => {
+                if let Some(parent) = path
+                    .parent()
+                    .filter(|parent| !parent.as_os_str().is_empty())
+                {
+                    std::fs::create_dir_all(parent).map_err(unopenable)?;
+                }
+                let file = OpenOptions::new()
+                    .read(true)
+                    .append(true)
+                    .create(true)
+                    .open(path)
+                    .map_err(unopenable)?;
+                Some(file)
+            }
+            false => match File::open(path) {
+                Ok(file) => Some(file),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+                Err(e) => return Err(unopenable(e)),
+            },
+        };
+
+        let mut contents = vec![];
+        if let Some(file) = &file {
+            lock(file, path, writable, lock_wait)?;
+            (&*file).read_to_end(&mut contents).map_err(unopenable)?;
+        }
+        let written = contents
+            .iter()
+            .rposition(|byte| *byte == b'\n')
+            .map_or(0, |newline| newline + 1);
+        // read-only, the torn line is simply left unread
+        if let (true, Some(file)) = (writable && written < contents.len(), &file) {
+            warn!(path = %path.display(), "cutting off a line torn when written");
+            file.set_len(written as u64).map_err(unopenable)?;
+            file.sync_data().map_err(unopenable)?;
+        }
+
+        let mut trees: BTreeMap<String, BTreeMap<Vec<u8>, Vec<u8>>> = BTreeMap::new();
+        let mut last = vec![];
+        for (number, line) in contents[..written]
+            .split_inclusive(|byte| *byte == b'\n')
+            .enumerate()
+        {
+            let invalid = || FimblError::FlatFileInvalid(path.to_owned(), number + 1);
+            let line: Line = serde_json::from_slice(line).map_err(|_| invalid())?;
+            let entry = line.entry.get();
+            last = mac(key.as_ref(), &last, entry);
+            if hex::encode(&last) != line.mac {
+                return Err(invalid());
+            }
+            let change: Change = serde_json::from_str(entry).map_err(|_| invalid())?;
+            let tree = trees.entry(change.tree.clone()).or_default();
+            match change.entry().ok_or_else(invalid)? {
+                (key, Some(value)) => tree.insert(key, value),
+                (key, None) => tree.remove(&key),
+            };
+        }
+
+        Ok(Arc::new(FlatFile {
+            path: path.to_owned(),
+            key,
+            state: Mutex::new(State { file, trees, last }),
+        }))
+    }
+
+    /// Store for a tree of the database
+    pub fn tree(self: &Arc<Self>, tree: &'static str) -> Box<FlatTree> {
+        Box::new(FlatTree {
+            file: self.clone(),
+            tree,
+        })
+    }
+
+    /// Append a change, syncing it to disk, then apply it
+    fn append(&self, tree: &str, key: &[u8], value: Option<Vec<u8>>) -> Result<(), FimblError> {
+        let op = match value {
+            Some(_) => Op::Insert,
+            None => Op::Remove,
+        };
+        let mut state = self.state.lock().unwrap();
+        let change = Change::new(op, tree, key, value.as_deref());
+        let entry = RawValue::from_string(serde_json::to_string(&change).unwrap()).unwrap();
+        let next = mac(self.key.as_ref(), &state.last, entry.get());
+        let mut line = serde_json::to_vec(&Line {
+            entry: &entry,
+            mac: hex::encode(&next),
+        })
+        .unwrap();
+        line.push(b'\n');
+        let Some(mut file) = state.file.as_ref() else {
+            let read_only = std::io::Error::from(std::io::ErrorKind::PermissionDenied);
+            return Err(FimblError::DatabaseUnopenable(self.path.clone(), read_only));
+        };
+        file.write_all(&line)
+            .and_then(|_| file.sync_data())
+            .map_err(|e| FimblError::DatabaseUnopenable(self.path.clone(), e))?;
+        state.last = next;
+
+        let entries = state.trees.entry(tree.to_string()).or_default();
+        match value {
+            Some(value) => entries.insert(key.to_vec(), value),
+            None => entries.remove(key),
+        };
+        Ok(())
+    }
+}
+
+impl Store for FlatTree {
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, FimblError> {
+        let state = self.file.state.lock().unwrap();
+        Ok(state
+            .trees
+            .get(self.tree)
+            .and_then(|entries| entries.get(key))
+            .cloned())
+    }
+
+    fn insert(&self, key: &[u8], value: Vec<u8>) -> Result<(), FimblError> {
+        if self.get(key)?.as_ref() == Some(&value) {
+            return Ok(());
+        }
+        self.file.append(self.tree, key, Some(value))
+    }
+
+    fn remove(&self, key: &[u8]) -> Result<(), FimblError> {
+        if self.get(key)?.is_none() {
+            return Ok(());
+        }
+        self.file.append(self.tree, key, None)
+    }
+
+    fn scan_prefix(&self, prefix: &[u8]) -> Entries<'_> {
+        let state = self.file.state.lock().unwrap();
+        let entries: Vec<_> = state
+            .trees
+            .get(self.tree)
+            .into_iter()
+            .flat_map(|entries| {
+                entries.range::<[u8], _>((Bound::Included(prefix), Bound::Unbounded))
+            })
+            .take_while(|(key, _)| key.starts_with(prefix))
+            .map(|(key, value)| Ok((key.clone(), value.clone())))
+            .collect();
+        Box::new(entries.into_iter())
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_flat_file_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("db.jsonl");
+        let record = rmp_serde::to_vec(&("/etc/hosts", 42)).unwrap();
+        {
+            let file = FlatFile::open(&path, None, true, Duration::ZERO).unwrap();
+            let fingerprints = file.tree("fingerprints");
+            let meta = file.tree("meta");
+            assert!(fingerprints.is_empty().unwrap());
+            fingerprints.insert(b"/etc/hosts", record.clone()).unwrap();
+            fingerprints.insert(b"/etc/motd", vec![0xc1]).unwrap();
+            fingerprints.insert(b"/usr/bin", vec![1]).unwrap();
+            fingerprints.remove(b"/usr/bin").unwrap();
+            meta.insert(&[0xff, 0], vec![2]).unwrap();
+            assert!(matches!(
+                FlatFile::open(&path, None, true, Duration::ZERO),
+                Err(FimblError::DatabaseBusy(..))
+            ));
+        }
+
+        // readable, and read back as written
+        let text = std::fs::read_to_string(&path).unwrap();
+        assert!(text.contains(r#""key":"/etc/hosts","value":["/etc/hosts",42]"#));
+        assert!(text.contains(r#""key":"/etc/motd","value-hex":"c1""#));
+        assert!(text.contains(r#""key-hex":"ff00""#));
+        assert_eq!(text.lines().count(), 5);
+        let file = FlatFile::open(&path, None, true, Duration::ZERO).unwrap();
+        let fingerprints = file.tree("fingerprints");
+        assert_eq!(fingerprints.get(b"/etc/hosts").unwrap(), Some(record));
+        let etc: Vec<_> = fingerprints
+            .scan_prefix(b"/etc/")
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(etc.len(), 2);
+        assert_eq!(fingerprints.get(b"/usr/bin").unwrap(), None);
+        assert_eq!(file.tree("meta").get(&[0xff, 0]).unwrap(), Some(vec![2]));
+        drop((fingerprints, file));
+
+        // any line changed or dropped is found
+        let changed = text.replacen("42", "43", 1);
+        std::fs::write(&path, changed).unwrap();
+        assert!(matches!(
+            FlatFile::open(&path, None, true, Duration::ZERO),
+            Err(FimblError::FlatFileInvalid(_, 1))
+        ));
+        let dropped: String = text
+            .lines()
+            .skip(1)
+            .map(|line| line.to_owned() + "\n")
+            .collect();
+        std::fs::write(&path, dropped).unwrap();
+        assert!(matches!(
+            FlatFile::open(&path, None, true, Duration::ZERO),
+            Err(FimblError::FlatFileInvalid(_, 1))
+        ));
+
+        // a line torn when written is cut off
+        let torn = text[..text.len() - 10].to_string();
+        std::fs::write(&path, &torn).unwrap();
+        let file = FlatFile::open(&path, None, false, Duration::ZERO).unwrap();
+        assert!(file.tree("meta").insert(&[0xff, 0], vec![3]).is_err());
+        drop(file);
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            torn,
+            "left read-only"
+        );
+        let file = FlatFile::open(&path, None, true, Duration::ZERO).unwrap();
+        assert_eq!(file.tree("meta").get(&[0xff, 0]).unwrap(), None);
+        assert_eq!(
+            file.tree("fingerprints").get(b"/usr/bin").unwrap(),
+            None,
+            "removed by the last line whole"
+        );
+        file.tree("meta").insert(&[0xff, 0], vec![3]).unwrap();
+        drop(file);
+        let file = FlatFile::open(&path, None, true, Duration::ZERO).unwrap();
+        assert_eq!(file.tree("meta").get(&[0xff, 0]).unwrap(), Some(vec![3]));
+        assert_eq!(std::fs::read_to_string(&path).unwrap().lines().count(), 5);
+
+        // nor created, read-only
+        let absent = dir.path().join("absent.jsonl");
+        let file = FlatFile::open(&absent, None, false, Duration::ZERO).unwrap();
+        assert!(file.tree("meta").is_empty().unwrap());
+        assert!(!absent.exists());
+    }
+
+    #[test]
+    fn test_flat_file_signed() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("db.jsonl");
+        let key = SigningKey::from_bytes(b"record key");
+        {
+            let file = FlatFile::open(&path, Some(key.clone()), true, Duration::ZERO).unwrap();
+            file.tree("fingerprints")
+                .insert(b"/etc/hosts", vec![1])
+                .unwrap();
+        }
+        assert!(FlatFile::open(&path, Some(key), true, Duration::ZERO).is_ok());
+        assert!(matches!(
+            FlatFile::open(&path, None, true, Duration::ZERO),
+            Err(FimblError::FlatFileInvalid(_, 1))
+        ));
+    }
+}
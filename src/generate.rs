@@ -0,0 +1,475 @@
+//! Configuration for other tools, generated from the tracked files
+//!
+//! auditd rules watch the tracked files for writes and attribute
+//! changes (`-p wa`), so the kernel logs who changed a file as it
+//! happens, between the runs that find it has changed. The rules are
+//! keyed (`-k fimbl`, by default) so `ausearch -k` finds the events.
+//!
+//! A systemd service runs `verify-all` hardened: the whole file system
+//! read-only to it but for the database's directory, no new privileges
+//! and no capabilities but reading any file. A timer starts it at an
+//! interval (a duration, from boot and then from each run) or at times
+//! (a calendar event such as `daily`). A launchd daemon does the same
+//! on macOS, at an interval or at calendar times launchd can express
+//! (hourly, daily, weekly, monthly or at a time of day), logging what
+//! it finds to a file, and a scheduled task does it on Windows as
+//! SYSTEM, reporting what it finds to the Event Log. Each job opens
+//! the database as the command generating it did, with the same keys,
+//! host and path policy.
+
+use std::{
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+/// Name of the systemd service (and timer) generated
+pub const SYSTEMD_UNIT: &str = "fimbl-verify";
+
+/// Label of the launchd daemon generated
+const LAUNCHD_LABEL: &str = "com.github.curvelogic.fimbl.verify";
+
+/// Name of the scheduled task generated
+pub const SCHEDULED_TASK: &str = "fimbl-verify";
+
+/// A scheduled verification: this fimbl verifying a database, opened
+/// with the options given
+pub struct Job {
+    /// Path of fimbl
+    pub fimbl: PathBuf,
+    /// Path of the database
+    pub database: PathBuf,
+    /// Options (and their values) for the keys, host and path policy
+    /// the database is used with
+    pub options: Vec<String>,
+    /// True if the record key is sealed to the TPM, which the job then
+    /// needs to reach
+    pub tpm: bool,
+}
+
+impl Job {
+    /// Arguments to run fimbl with, before any of its own and the
+    /// command
+    fn arguments(&self) -> Vec<String> {
+        let database = self.database.to_string_lossy().into_owned();
+        [
+            vec!["--database".to_string(), database],
+            self.options.clone(),
+        ]
+        .concat()
+    }
+}
+
+/// auditd rules watching the files given for writes and attribute
+/// changes, in a form for /etc/audit/rules.d
+///
+/// auditctl can't watch a path containing whitespace, so any such path
+/// is left as a comment.
+pub fn audit_rules(files: &[PathBuf], key: &str) -> String {
+    let mut rules = String::from("## Generated by fimbl: watch the files it tracks\n");
+    for file in files {
+        let path = file.to_string_lossy();
+        match path.contains(char::is_whitespace) {
+            true => rules.push_str(&format!("# cannot watch {path:?}\n")),
+            false => rules.push_str(&format!("-w {path} -p wa -k {key}\n")),
+        }
+    }
+    rules
+}
+
+/// A systemd service running a job verifying all files, and a timer
+/// starting it at the interval given: a duration (as for `agent
+/// --interval`) or a systemd calendar event
+///
+/// A job using the TPM is allowed to reach it, but no other device.
+pub fn systemd_units(job: &Job, interval: &str) -> (String, String) {
+    let directory = job.database.parent().unwrap_or(&job.database);
+    let command: Vec<String> = [job.fimbl.to_string_lossy().into_owned()]
+        .into_iter()
+        .chain(job.arguments())
+        .map(|word| systemd_quote(&word))
+        .collect();
+    let devices = match job.tpm {
+        true => "DevicePolicy=closed\nDeviceAllow=/dev/tpmrm0 rw",
+        false => "PrivateDevices=true",
+    };
+    let service = format!(
+        "[Unit]
+Description=Verify the files fimbl tracks
+Documentation=https://github.com/curvelogic/fimbl
+After=local-fs.target
+
+[Service]
+Type=oneshot
+ExecStart={} verify-all
+Nice=19
+IOSchedulingClass=idle
+ProtectSystem=strict
+ProtectHome=read-only
+ReadOnlyPaths=/
+ReadWritePaths={}
+PrivateTmp=true
+{devices}
+NoNewPrivileges=true
+CapabilityBoundingSet=CAP_DAC_READ_SEARCH
+ProtectKernelTunables=true
+ProtectKernelModules=true
+ProtectKernelLogs=true
+ProtectControlGroups=true
+ProtectClock=true
+ProtectHostname=true
+RestrictSUIDSGID=true
+RestrictRealtime=true
+RestrictNamespaces=true
+LockPersonality=true
+MemoryDenyWriteExecute=true
+SystemCallArchitectures=native
+",
+        command.join(" "),
+        systemd_quote(&directory.to_string_lossy())
+    );
+    let when = match humantime::parse_duration(interval) {
+        Ok(interval) => format!(
+            "OnBootSec=5min\nOnUnitActiveSec={}s",
+            interval.max(Duration::from_secs(1)).as_secs()
+        ),
+        Err(_) => format!("OnCalendar={interval}\nPersistent=true"),
+    };
+    let timer = format!(
+        "[Unit]
+Description=Verify the files fimbl tracks, {interval}
+
+[Timer]
+{when}
+RandomizedDelaySec=5min
+
+[Install]
+WantedBy=timers.target
+"
+    );
+    (service, timer)
+}
+
+/// A word of a systemd unit setting, quoted if it has whitespace (or
+/// quotes) in it
+fn systemd_quote(word: &str) -> String {
+    match word.contains(|c: char| c.is_whitespace() || c == '"' || c == '\\') {
+        true => format!("\"{}\"", word.replace('\\', "\\\\").replace('"', "\\\"")),
+        false => word.to_string(),
+    }
+}
+
+/// When a scheduled job runs, as launchd and the Windows Task
+/// Scheduler can both say it
+#[derive(Debug, PartialEq, Eq)]
+enum Schedule {
+    /// At an interval
+    Every(Duration),
+    Hourly,
+    /// At midnight
+    Daily,
+    /// On Mondays at midnight
+    Weekly,
+    /// On the first of the month at midnight
+    Monthly,
+    /// Daily at an hour and minute
+    At(u32, u32),
+}
+
+impl Schedule {
+    /// When to run a job: a duration, hourly, daily, weekly, monthly
+    /// (the last four as systemd takes them) or HH:MM, for the
+    /// scheduler named
+    fn parse(interval: &str, scheduler: &str) -> Result<Self, String> {
+        match interval {
+            "hourly" => Ok(Schedule::Hourly),
+            "daily" => Ok(Schedule::Daily),
+            "weekly" => Ok(Schedule::Weekly),
+            "monthly" => Ok(Schedule::Monthly),
+            _ => match (humantime::parse_duration(interval), time_of_day(interval)) {
+                (Ok(every), _) => Ok(Schedule::Every(every.max(Duration::from_secs(1)))),
+                (_, Some((hour, minute))) => Ok(Schedule::At(hour, minute)),
+                _ => Err(format!(
+                    "{scheduler} can't start a job at {interval:?}: give a duration, \
+                     hourly, daily, weekly, monthly or HH:MM"
+                )),
+            },
+        }
+    }
+}
+
+/// A launchd daemon (property list) running a job verifying all files
+/// at the interval given (see `Schedule::parse`), writing its output to
+/// the log file given
+pub fn launchd_plist(job: &Job, interval: &str, log: &Path) -> Result<String, String> {
+    let calendar = |fields: &[(&str, u32)]| {
+        let fields: String = fields
+            .iter()
+            .map(|(key, value)| {
+                format!("        <key>{key}</key>\n        <integer>{value}</integer>\n")
+            })
+            .collect();
+        format!("    <key>StartCalendarInterval</key>\n    <dict>\n{fields}    </dict>\n")
+    };
+    let schedule = match Schedule::parse(interval, "launchd")? {
+        Schedule::Every(every) => format!(
+            "    <key>StartInterval</key>\n    <integer>{}</integer>\n",
+            every.as_secs()
+        ),
+        Schedule::Hourly => calendar(&[("Minute", 0)]),
+        Schedule::Daily => calendar(&[("Hour", 0), ("Minute", 0)]),
+        Schedule::Weekly => calendar(&[("Weekday", 1), ("Hour", 0), ("Minute", 0)]),
+        Schedule::Monthly => calendar(&[("Day", 1), ("Hour", 0), ("Minute", 0)]),
+        Schedule::At(hour, minute) => calendar(&[("Hour", hour), ("Minute", minute)]),
+    };
+    let string = |path: &Path| xml_escape(&path.to_string_lossy());
+    let arguments: String = job
+        .arguments()
+        .iter()
+        .map(|argument| format!("        <string>{}</string>\n", xml_escape(argument)))
+        .collect();
+    Ok(format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<!DOCTYPE plist PUBLIC "-//Apple//DTD PLIST 1.0//EN" "http://www.apple.com/DTDs/PropertyList-1.0.dtd">
+<plist version="1.0">
+<dict>
+    <key>Label</key>
+    <string>{LAUNCHD_LABEL}</string>
+    <key>ProgramArguments</key>
+    <array>
+        <string>{}</string>
+{arguments}        <string>verify-all</string>
+    </array>
+{schedule}    <key>StandardOutPath</key>
+    <string>{log}</string>
+    <key>StandardErrorPath</key>
+    <string>{log}</string>
+    <key>ProcessType</key>
+    <string>Background</string>
+    <key>LowPriorityIO</key>
+    <true/>
+    <key>Nice</key>
+    <integer>19</integer>
+</dict>
+</plist>
+"#,
+        string(&job.fimbl),
+        log = string(log),
+    ))
+}
+
+/// A Windows Task Scheduler task running a job as SYSTEM verifying all
+/// files at the interval given (see `Schedule::parse`), reporting to
+/// the Event Log, for `Register-ScheduledTask -Xml`
+pub fn scheduled_task(job: &Job, interval: &str) -> Result<String, String> {
+    // times are local, from a day long past
+    let start = |hour: u32, minute: u32| {
+        format!("      <StartBoundary>2024-01-01T{hour:02}:{minute:02}:00</StartBoundary>\n")
+    };
+    let repeating = |every: Duration| {
+        format!(
+            "    <TimeTrigger>\n{}      <Repetition>\n        <Interval>PT{}S</Interval>\n      \
+             </Repetition>\n    </TimeTrigger>\n",
+            start(0, 0),
+            every.max(Duration::from_secs(60)).as_secs()
+        )
+    };
+    let calendar = |hour: u32, minute: u32, schedule: &str| {
+        format!(
+            "    <CalendarTrigger>\n{}{schedule}    </CalendarTrigger>\n",
+            start(hour, minute)
+        )
+    };
+    let daily =
+        "      <ScheduleByDay>\n        <DaysInterval>1</DaysInterval>\n      </ScheduleByDay>\n";
+    let trigger = match Schedule::parse(interval, "the Task Scheduler")? {
+        Schedule::Every(every) => repeating(every),
+        Schedule::Hourly => repeating(Duration::from_secs(3600)),
+        Schedule::Daily => calendar(0, 0, daily),
+        Schedule::At(hour, minute) => calendar(hour, minute, daily),
+        Schedule::Weekly => calendar(
+            0,
+            0,
+            "      <ScheduleByWeek>\n        <WeeksInterval>1</WeeksInterval>\n        \
+             <DaysOfWeek><Monday /></DaysOfWeek>\n      </ScheduleByWeek>\n",
+        ),
+        Schedule::Monthly => calendar(
+            0,
+            0,
+            "      <ScheduleByMonth>\n        <DaysOfMonth><Day>1</Day></DaysOfMonth>\n        \
+             <Months><January /><February /><March /><April /><May /><June /><July />\
+             <August /><September /><October /><November /><December /></Months>\n      \
+             </ScheduleByMonth>\n",
+        ),
+    };
+    let arguments: Vec<String> = job
+        .arguments()
+        .iter()
+        .map(|argument| windows_quote(argument))
+        .chain(["--report-to eventlog verify-all".to_string()])
+        .collect();
+    let arguments = arguments.join(" ");
+    Ok(format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<Task version="1.2" xmlns="http://schemas.microsoft.com/windows/2004/02/mit/task">
+  <RegistrationInfo>
+    <Description>Verify the files fimbl tracks</Description>
+    <URI>\{SCHEDULED_TASK}</URI>
+  </RegistrationInfo>
+  <Triggers>
+{trigger}  </Triggers>
+  <Principals>
+    <Principal id="Author">
+      <UserId>S-1-5-18</UserId>
+      <RunLevel>HighestAvailable</RunLevel>
+    </Principal>
+  </Principals>
+  <Settings>
+    <MultipleInstancesPolicy>IgnoreNew</MultipleInstancesPolicy>
+    <DisallowStartIfOnBatteries>false</DisallowStartIfOnBatteries>
+    <StopIfGoingOnBatteries>false</StopIfGoingOnBatteries>
+    <StartWhenAvailable>true</StartWhenAvailable>
+    <ExecutionTimeLimit>PT12H</ExecutionTimeLimit>
+    <Priority>7</Priority>
+  </Settings>
+  <Actions Context="Author">
+    <Exec>
+      <Command>{}</Command>
+      <Arguments>{}</Arguments>
+    </Exec>
+  </Actions>
+</Task>
+"#,
+        xml_escape(&job.fimbl.to_string_lossy()),
+        xml_escape(&arguments),
+    ))
+}
+
+/// An argument for a Windows command line, quoted if it has spaces in
+/// it (Windows paths can't have quotes in them)
+fn windows_quote(argument: &str) -> String {
+    match argument.contains(char::is_whitespace) {
+        true => format!("\"{argument}\""),
+        false => argument.to_string(),
+    }
+}
+
+/// Hour and minute of a time of day given as HH:MM
+fn time_of_day(time: &str) -> Option<(u32, u32)> {
+    let (hour, minute) = time.split_once(':')?;
+    let (hour, minute) = (hour.parse().ok()?, minute.parse().ok()?);
+    (hour < 24 && minute < 60).then_some((hour, minute))
+}
+
+/// Text escaped for an XML element
+fn xml_escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+#[cfg(test)]
+pub mod tests {
+
+    use super::*;
+
+    /// A job for fimbl and the database given, with no options
+    fn job(fimbl: &str, database: &str) -> Job {
+        Job {
+            fimbl: PathBuf::from(fimbl),
+            database: PathBuf::from(database),
+            options: vec![],
+            tpm: false,
+        }
+    }
+
+    #[test]
+    fn test_audit_rules() {
+        let files = ["/etc/passwd", "/etc/my file"].map(PathBuf::from);
+        assert_eq!(
+            audit_rules(&files, "fimbl"),
+            "## Generated by fimbl: watch the files it tracks\n\
+             -w /etc/passwd -p wa -k fimbl\n\
+             # cannot watch \"/etc/my file\"\n"
+        );
+    }
+
+    #[test]
+    fn test_systemd_units() {
+        let (service, timer) =
+            systemd_units(&job("/usr/bin/fimbl", "/var/lib/fimbl db/db"), "daily");
+        assert!(service
+            .contains("ExecStart=/usr/bin/fimbl --database \"/var/lib/fimbl db/db\" verify-all\n"));
+        assert!(service.contains("ReadWritePaths=\"/var/lib/fimbl db\"\n"));
+        assert!(service.contains("PrivateDevices=true\n"));
+        assert!(timer.contains("OnCalendar=daily\n"));
+
+        let (_, timer) = systemd_units(&job("fimbl", "/db"), "30m");
+        assert!(timer.contains("OnUnitActiveSec=1800s\n"));
+        assert!(!timer.contains("OnCalendar"));
+
+        let sealed = Job {
+            options: ["--host", "web1", "--record-key-tpm", "0x81000100"]
+                .map(String::from)
+                .into(),
+            tpm: true,
+            ..job("fimbl", "/db")
+        };
+        let (service, _) = systemd_units(&sealed, "daily");
+        assert!(service.contains(
+            "ExecStart=fimbl --database /db --host web1 --record-key-tpm 0x81000100 verify-all\n"
+        ));
+        assert!(service.contains("DeviceAllow=/dev/tpmrm0 rw\n"));
+        assert!(!service.contains("PrivateDevices"));
+    }
+
+    #[test]
+    fn test_launchd_plist() {
+        let fimbl = "/usr/local/bin/fimbl";
+        let log = Path::new("/var/log/fimbl-verify.log");
+        let keyed = Job {
+            options: vec!["--key-file".to_string(), "/var/db/fimbl.key".to_string()],
+            ..job(fimbl, "/var/db/R&D/db")
+        };
+        let plist = launchd_plist(&keyed, "02:30", log).unwrap();
+        assert!(plist.contains(
+            "<string>/var/db/R&amp;D/db</string>\n        <string>--key-file</string>\n        \
+             <string>/var/db/fimbl.key</string>\n        <string>verify-all</string>"
+        ));
+        assert!(plist.contains(
+            "<key>Hour</key>\n        <integer>2</integer>\n        \
+             <key>Minute</key>\n        <integer>30</integer>"
+        ));
+        assert!(plist.contains("<key>StandardOutPath</key>\n    <string>/var/log/fimbl-verify.log"));
+
+        let plist = launchd_plist(&job(fimbl, "/db"), "6h", log).unwrap();
+        assert!(plist.contains("<key>StartInterval</key>\n    <integer>21600</integer>"));
+        assert!(launchd_plist(&job(fimbl, "/db"), "Mon *-*-* 02:00", log).is_err());
+        assert!(launchd_plist(&job(fimbl, "/db"), "25:00", log).is_err());
+    }
+
+    #[test]
+    fn test_scheduled_task() {
+        let job = job(
+            r"C:\Program Files\fimbl\fimbl.exe",
+            r"C:\ProgramData\fimbl\db",
+        );
+        let task = scheduled_task(&job, "weekly").unwrap();
+        assert!(task.contains(r"<Command>C:\Program Files\fimbl\fimbl.exe</Command>"));
+        assert!(task.contains(
+            r"<Arguments>--database C:\ProgramData\fimbl\db --report-to eventlog verify-all</Arguments>"
+        ));
+        let hosted = Job {
+            options: vec!["--host".to_string(), "web 1".to_string()],
+            ..job
+        };
+        let task = scheduled_task(&hosted, "weekly").unwrap();
+        assert!(task.contains(r#"db --host "web 1" --report-to"#));
+        assert!(task.contains("<DaysOfWeek><Monday /></DaysOfWeek>"));
+
+        let task = scheduled_task(&hosted, "6h").unwrap();
+        assert!(task.contains("<Interval>PT21600S</Interval>"));
+        let task = scheduled_task(&hosted, "03:15").unwrap();
+        assert!(task.contains("<StartBoundary>2024-01-01T03:15:00</StartBoundary>"));
+        assert!(scheduled_task(&hosted, "Mon 02:00").is_err());
+    }
+}
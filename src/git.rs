@@ -0,0 +1,389 @@
+//! Tracking the files of git working trees
+//!
+//! The files of a working tree are those git lists: committed or
+//! staged, or new but not ignored (by .gitignore and the like). Their
+//! contents can also be checked against the commit at HEAD by the
+//! object ids git gives them (`git hash-object`, with any filters the
+//! repository sets), so a change shows even where git's own index
+//! would hide it. Listing and hashing go through `git`, which must be
+//! installed.
+
+use crate::{error::FimblError, report::ReportItem};
+
+use std::{
+    collections::HashMap,
+    ffi::OsString,
+    io::{self, BufRead, Write},
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+};
+
+/// Modes git gives regular files in trees (the executable bit aside)
+const REGULAR_FILE_MODES: &[&str] = &["100644", "100755"];
+
+/// A git working tree
+pub struct WorkingTree {
+    /// Top level directory of the working tree
+    top: PathBuf,
+}
+
+impl WorkingTree {
+    /// The working tree containing the path given
+    pub fn discover(path: &Path) -> Result<Self, FimblError> {
+        let top = git(path, &["rev-parse", "--show-toplevel"], None)?;
+        let top = String::from_utf8_lossy(&top).trim_end().to_string();
+        Ok(WorkingTree {
+            top: std::fs::canonicalize(top)?,
+        })
+    }
+
+    /// Top level directory of the working tree
+    pub fn top(&self) -> &Path {
+        &self.top
+    }
+
+    /// The files of the working tree, whether or not they are (still)
+    /// there: committed, staged or new, but not ignored
+    pub fn files(&self) -> Result<Vec<PathBuf>, FimblError> {
+        let listed = git(
+            &self.top,
+            &[
+                "ls-files",
+                "-z",
+                "--cached",
+                "--others",
+                "--exclude-standard",
+            ],
+            None,
+        )?;
+        let mut files: Vec<PathBuf> = listed
+            .split(|byte| *byte == 0)
+            .filter(|name| !name.is_empty())
+            .map(|name| self.top.join(path_from_bytes(name)))
+            .collect();
+        // files with unmerged changes are listed once for each stage
+        files.dedup();
+        Ok(files)
+    }
+
+    /// The commit at HEAD
+    pub fn head(&self) -> Result<String, FimblError> {
+        let head = git(&self.top, &["rev-parse", "--verify", "HEAD"], None)?;
+        Ok(String::from_utf8_lossy(&head).trim_end().to_string())
+    }
+
+    /// Reports of the regular files given that differ from (or aren't
+    /// in) the commit at HEAD
+    ///
+    /// Symlinks, and files not there, are left to verify.
+    pub fn differences_from_head(&self, files: &[PathBuf]) -> Result<Vec<ReportItem>, FimblError> {
+        let commit = self.head()?;
+        let tree = git(
+            &self.top,
+            &["ls-tree", "-r", "-z", "--full-tree", &commit],
+            None,
+        )?;
+        let mut committed: HashMap<PathBuf, (&str, &str)> = HashMap::new();
+        for entry in tree.split(|byte| *byte == 0) {
+            let Some(tab) = entry.iter().position(|byte| *byte == b'\t') else {
+                continue;
+            };
+            let fields = std::str::from_utf8(&entry[..tab]).unwrap_or_default();
+            if let [mode, _, id] = fields.split(' ').collect::<Vec<_>>()[..] {
+                let path = self.top.join(path_from_bytes(&entry[tab + 1..]));
+                committed.insert(path, (mode, id));
+            }
+        }
+
+        // hash-object takes a path a line, so none with a newline
+        let hashed: Vec<&PathBuf> = files
+            .iter()
+            .filter(|file| std::fs::symlink_metadata(file).is_ok_and(|m| m.is_file()))
+            .filter(|file| !file.as_os_str().to_string_lossy().contains('\n'))
+            .collect();
+        let mut paths = vec![];
+        for file in &hashed {
+            paths.extend(file.as_os_str().as_encoded_bytes());
+            paths.push(b'\n');
+        }
+        let ids = git(&self.top, &["hash-object", "--stdin-paths"], Some(&paths))?;
+        let ids = String::from_utf8_lossy(&ids);
+
+        let mut reports = vec![];
+        for (file, current) in hashed.into_iter().zip(ids.lines()) {
+            let committed = match committed.get(file) {
+                Some((mode, _)) if !REGULAR_FILE_MODES.contains(mode) => continue,
+                Some((_, id)) if *id == current => continue,
+                Some((_, id)) => Some(id.to_string()),
+                None => None,
+            };
+            reports.push(ReportItem::DiffersFromCommit {
+                path: file.clone(),
+                commit: commit.clone(),
+                committed,
+                current: current.to_string(),
+            });
+        }
+        Ok(reports)
+    }
+}
+
+/// The contents of the blobs named (e.g. `:path` for one in the index,
+/// or `commit:path`), with nothing for any not there or not a blob
+pub fn blobs(repo: &Path, objects: &[String]) -> Result<Vec<Option<Vec<u8>>>, FimblError> {
+    let input: String = objects.iter().map(|object| format!("{object}\n")).collect();
+    let output = git(repo, &["cat-file", "--batch"], Some(input.as_bytes()))?;
+    let invalid = || FimblError::GitError("unexpected output from cat-file".to_string());
+
+    let mut blobs = vec![];
+    let mut rest = output.as_slice();
+    for _ in objects {
+        let end = rest
+            .iter()
+            .position(|byte| *byte == b'\n')
+            .ok_or_else(invalid)?;
+        let header = String::from_utf8_lossy(&rest[..end]).into_owned();
+        rest = &rest[end + 1..];
+        // "<name> missing", or "<id> <type> <size>" and the contents
+        let fields: Vec<&str> = header.rsplitn(3, ' ').collect();
+        let size = match fields[..] {
+            [size, kind, _] if kind != "missing" => size.parse::<usize>().ok(),
+            _ => None,
+        };
+        let Some(size) = size else {
+            blobs.push(None);
+            continue;
+        };
+        if rest.len() <= size {
+            return Err(invalid());
+        }
+        blobs.push(Some(rest[..size].to_vec()).filter(|_| fields[1] == "blob"));
+        rest = &rest[size + 1..];
+    }
+    Ok(blobs)
+}
+
+/// The old and new commits of each ref pushed, from a pre-receive
+/// hook's input (a line of old and new commit and ref name for each),
+/// leaving out refs deleted
+///
+/// The old commit of a ref created is none (all zeros).
+pub fn pushed_refs(input: impl BufRead) -> io::Result<Vec<(String, String)>> {
+    let mut refs = vec![];
+    for line in input.lines() {
+        let line = line?;
+        let mut fields = line.split_whitespace();
+        let (Some(old), Some(new)) = (fields.next(), fields.next()) else {
+            continue;
+        };
+        if !is_none(new) {
+            refs.push((old.to_string(), new.to_string()));
+        }
+    }
+    Ok(refs)
+}
+
+/// Every commit pushed to the refs given (as from `pushed_refs`):
+/// those after the old commit of each, or for a ref created, those not
+/// already in the repository
+pub fn pushed_commits(repo: &Path, refs: &[(String, String)]) -> Result<Vec<String>, FimblError> {
+    let mut commits: Vec<String> = vec![];
+    for (old, new) in refs {
+        let range = format!("{old}..{new}");
+        let args = match is_none(old) {
+            true => vec!["rev-list", new, "--not", "--all"],
+            false => vec!["rev-list", &range],
+        };
+        for commit in String::from_utf8_lossy(&git(repo, &args, None)?).lines() {
+            if !commits.iter().any(|seen| seen == commit) {
+                commits.push(commit.to_string());
+            }
+        }
+    }
+    Ok(commits)
+}
+
+/// True if an object id is none (all zeros), as for a ref created or
+/// deleted
+fn is_none(id: &str) -> bool {
+    id.chars().all(|c| c == '0')
+}
+
+/// Run git in a directory, with any input given, returning its output
+fn git(dir: &Path, args: &[&str], input: Option<&[u8]>) -> Result<Vec<u8>, FimblError> {
+    let mut child = Command::new("git")
+        .arg("-C")
+        .arg(dir)
+        .args(args)
+        .stdin(match input {
+            Some(_) => Stdio::piped(),
+            None => Stdio::null(),
+        })
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| FimblError::GitError(format!("cannot run git: {e}")))?;
+    // written while the output is read, lest git block writing it
+    let output = std::thread::scope(|scope| {
+        if let (Some(input), Some(mut stdin)) = (input, child.stdin.take()) {
+            scope.spawn(move || stdin.write_all(input));
+        }
+        child.wait_with_output()
+    })?;
+    if !output.status.success() {
+        let message = String::from_utf8_lossy(&output.stderr).trim().to_string();
+        return Err(FimblError::GitError(format!(
+            "{} failed in {}: {message}",
+            args[0],
+            dir.display()
+        )));
+    }
+    Ok(output.stdout)
+}
+
+/// A path as git gives it, relative to the top of the working tree
+#[cfg(unix)]
+fn path_from_bytes(bytes: &[u8]) -> PathBuf {
+    use std::os::unix::ffi::OsStringExt;
+    PathBuf::from(OsString::from_vec(bytes.to_vec()))
+}
+
+/// A path as git gives it, relative to the top of the working tree
+#[cfg(not(unix))]
+fn path_from_bytes(bytes: &[u8]) -> PathBuf {
+    PathBuf::from(OsString::from(String::from_utf8_lossy(bytes).into_owned()))
+}
+
+#[cfg(test)]
+pub mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_pushed_refs() {
+        let zero = "0".repeat(40);
+        let (a, b) = ("a".repeat(40), "b".repeat(40));
+        let input = format!(
+            "{zero} {a} refs/heads/main\n{a} {b} refs/heads/dev\n{b} {zero} refs/heads/old\n"
+        );
+        assert_eq!(
+            pushed_refs(input.as_bytes()).unwrap(),
+            [(zero, a.clone()), (a, b)]
+        );
+    }
+
+    #[test]
+    fn test_pushed_commits() {
+        let dir = tempfile::tempdir().unwrap();
+        let run = |args: &[&str]| {
+            git(dir.path(), args, None)
+                .map(|out| String::from_utf8(out).unwrap().trim().to_string())
+        };
+        if run(&["init", "--quiet"]).is_err() {
+            // no git to test with
+            return;
+        }
+        let commit = |parent: &str| {
+            let tree = run(&["mktree"]).unwrap();
+            let mut args = vec!["commit-tree", &tree, "-m", "pushed"];
+            if !parent.is_empty() {
+                args.extend(["-p", parent]);
+            }
+            run(&[
+                &[
+                    "-c",
+                    "user.name=fimbl",
+                    "-c",
+                    "user.email=fimbl@example.com",
+                ],
+                &args[..],
+            ]
+            .concat())
+            .unwrap()
+        };
+        let first = commit("");
+        run(&["update-ref", "refs/heads/main", &first]).unwrap();
+        let second = commit(&first);
+        let third = commit(&second);
+
+        // commits after the old one, each once
+        let refs = [
+            (first.clone(), third.clone()),
+            (second.clone(), third.clone()),
+        ];
+        assert_eq!(
+            pushed_commits(dir.path(), &refs).unwrap(),
+            [third.clone(), second.clone()]
+        );
+        // a new ref: those not in the repository already
+        let refs = [("0".repeat(40), third.clone())];
+        assert_eq!(pushed_commits(dir.path(), &refs).unwrap(), [third, second]);
+    }
+
+    #[test]
+    fn test_differences_from_head() {
+        let dir = tempfile::tempdir().unwrap();
+        let run = |args: &[&str]| git(dir.path(), args, None);
+        if run(&["init", "--quiet"]).is_err() {
+            // no git to test with
+            return;
+        }
+        std::fs::write(dir.path().join(".gitignore"), "*.log\n").unwrap();
+        std::fs::write(dir.path().join("committed"), "as committed").unwrap();
+        std::fs::write(dir.path().join("changed"), "as committed").unwrap();
+        run(&["add", "."]).unwrap();
+        run(&[
+            "-c",
+            "user.name=fimbl",
+            "-c",
+            "user.email=fimbl@example.com",
+            "commit",
+            "--quiet",
+            "-m",
+            "initial",
+        ])
+        .unwrap();
+        std::fs::write(dir.path().join("changed"), "changed since").unwrap();
+        std::fs::write(dir.path().join("new"), "not committed").unwrap();
+        std::fs::write(dir.path().join("ignored.log"), "ignored").unwrap();
+
+        let tree = WorkingTree::discover(dir.path()).unwrap();
+        let mut files = tree.files().unwrap();
+        files.sort();
+        let top = tree.top();
+        assert_eq!(
+            files,
+            [".gitignore", "changed", "committed", "new"].map(|name| top.join(name))
+        );
+
+        let blobs = blobs(
+            top,
+            &[":changed", "HEAD:changed", ":new", "HEAD:"].map(String::from),
+        )
+        .unwrap();
+        assert_eq!(
+            blobs,
+            [
+                Some(b"as committed".to_vec()),
+                Some(b"as committed".to_vec()),
+                None,
+                None
+            ]
+        );
+
+        let reports = tree.differences_from_head(&files).unwrap();
+        let differing: Vec<_> = reports
+            .iter()
+            .map(|report| match report {
+                ReportItem::DiffersFromCommit {
+                    path, committed, ..
+                } => (path.file_name().unwrap(), committed.is_some()),
+                _ => panic!("unexpected report {report}"),
+            })
+            .collect();
+        assert_eq!(
+            differing,
+            [("changed".as_ref(), true), ("new".as_ref(), false)]
+        );
+    }
+}
@@ -0,0 +1,235 @@
+//! Health and metrics endpoint of a running agent
+//!
+//! An agent running repeatedly with `--listen` serves, over plain HTTP,
+//! `/healthz` (for orchestration: 200, or 503 once draining or when a
+//! run is overdue), `/metrics` (the status, in the Prometheus text
+//! format) and `/last-report` (the last run's report, as pushed).
+
+use crate::{
+    control::{epoch_seconds, AgentState, Status},
+    error::FimblError,
+    server::{self, respond, Reply},
+};
+
+use std::{fmt::Write, sync::Arc, time::SystemTime};
+use tiny_http::Server;
+use tracing::debug;
+
+/// Seconds a run may be late before the agent is deemed unhealthy
+const OVERDUE: u64 = 60;
+
+/// Listen on the address, answering requests on a thread of its own
+pub fn serve(listen: &str, state: Arc<AgentState>) -> Result<(), FimblError> {
+    let server = Server::http(listen).map_err(|e| FimblError::ServerError(e.to_string()))?;
+    std::thread::spawn(move || {
+        for request in server.incoming_requests() {
+            let now = epoch_seconds(SystemTime::now());
+            let (code, content_type, body) =
+                route(&state, request.method().as_str(), request.url(), now);
+            debug!(method = %request.method(), url = request.url(), code, "request");
+            respond(request, code, content_type, body);
+        }
+    });
+    Ok(())
+}
+
+/// Handle a single request at time `now` (seconds since the unix
+/// epoch)
+fn route(state: &AgentState, method: &str, url: &str, now: u64) -> Reply {
+    let path = url.split_once('?').map_or(url, |(path, _)| path);
+    match (method, path) {
+        ("GET", "/healthz") => match unhealthy(&state.status(), now) {
+            Some(reason) => (503, "text/plain", format!("{reason}\n").into_bytes()),
+            None => (200, "text/plain", b"ok\n".to_vec()),
+        },
+        ("GET", "/metrics") => (
+            200,
+            "text/plain; version=0.0.4",
+            metrics(&state.status()).into_bytes(),
+        ),
+        ("GET", "/last-report") => match state.last_report() {
+            Some(report) => (200, "application/json", report.into_bytes()),
+            None => server::status(404),
+        },
+        (_, "/healthz" | "/metrics" | "/last-report") => server::status(405),
+        _ => server::status(404),
+    }
+}
+
+/// Why the agent is unhealthy, if it is
+fn unhealthy(status: &Status, now: u64) -> Option<String> {
+    if status.draining {
+        return Some("draining".to_string());
+    }
+    match (status.scanning_since, status.next_run) {
+        (None, Some(next)) if now > next + OVERDUE => {
+            Some(format!("run overdue by {}s", now - next))
+        }
+        _ => None,
+    }
+}
+
+/// A label value, escaped
+fn label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// The status in the Prometheus text exposition format
+pub fn metrics(status: &Status) -> String {
+    let mut text = String::new();
+    let mut metric = |name: &str, kind: &str, help: &str, samples: &[(String, f64)]| {
+        let _ = writeln!(text, "# HELP {name} {help}");
+        let _ = writeln!(text, "# TYPE {name} {kind}");
+        for (labels, value) in samples {
+            let _ = writeln!(text, "{name}{labels} {value}");
+        }
+    };
+    let value = |value: f64| vec![(String::new(), value)];
+
+    let host = format!("{{host=\"{}\"}}", label(&status.host));
+    metric(
+        "fimbl_info",
+        "gauge",
+        "Host the agent reports as",
+        &[(host, 1.0)],
+    );
+    metric(
+        "fimbl_runs_total",
+        "counter",
+        "Runs completed",
+        &value(status.runs as f64),
+    );
+    metric(
+        "fimbl_scanning",
+        "gauge",
+        "1 if a run is under way",
+        &value(status.scanning_since.is_some() as u8 as f64),
+    );
+    metric(
+        "fimbl_draining",
+        "gauge",
+        "1 if the agent will exit once the run under way is done",
+        &value(status.draining as u8 as f64),
+    );
+    metric(
+        "fimbl_files_tracked",
+        "gauge",
+        "Files tracked when the last run started",
+        &value(status.files_tracked as f64),
+    );
+    if status.scanning_since.is_some() {
+        metric(
+            "fimbl_scan_files_examined",
+            "gauge",
+            "Files examined so far in the run under way",
+            &value(status.files_examined as f64),
+        );
+        metric(
+            "fimbl_scan_bytes_hashed",
+            "gauge",
+            "Bytes hashed so far in the run under way",
+            &value(status.bytes_hashed as f64),
+        );
+    }
+    if let Some(next) = status.next_run {
+        metric(
+            "fimbl_next_run_timestamp_seconds",
+            "gauge",
+            "When the next run is due",
+            &value(next as f64),
+        );
+    }
+    if let Some(run) = &status.last_run {
+        metric(
+            "fimbl_last_run_timestamp_seconds",
+            "gauge",
+            "When the last run finished",
+            &value(run.finished as f64),
+        );
+        metric(
+            "fimbl_last_run_duration_seconds",
+            "gauge",
+            "Time the last run took",
+            &value(run.elapsed_seconds),
+        );
+        metric(
+            "fimbl_last_run_files_examined",
+            "gauge",
+            "Files examined by the last run",
+            &value(run.files_examined as f64),
+        );
+        metric(
+            "fimbl_last_run_bytes_hashed",
+            "gauge",
+            "Bytes hashed by the last run",
+            &value(run.bytes_hashed as f64),
+        );
+        metric(
+            "fimbl_last_run_findings",
+            "gauge",
+            "Items reported by the last run",
+            &value(run.findings as f64),
+        );
+        let items: Vec<_> = run
+            .items
+            .iter()
+            .map(|(kind, count)| (format!("{{kind=\"{}\"}}", label(kind)), *count as f64))
+            .collect();
+        metric(
+            "fimbl_last_run_items",
+            "gauge",
+            "Items reported by the last run, by kind",
+            &items,
+        );
+    }
+    text
+}
+
+#[cfg(test)]
+pub mod tests {
+
+    use super::*;
+    use crate::{
+        agent::RunReport,
+        report::{ReportItem, Summary},
+    };
+    use std::{path::PathBuf, time::Instant};
+
+    #[test]
+    fn test_route() {
+        let (state, _signals) = AgentState::new("web1", None);
+        state.run_started(2);
+        assert_eq!(route(&state, "GET", "/healthz", 0).0, 200);
+        assert_eq!(route(&state, "GET", "/last-report", 0).0, 404);
+        assert_eq!(route(&state, "POST", "/healthz", 0).0, 405);
+        assert_eq!(route(&state, "GET", "/elsewhere", 0).0, 404);
+
+        let items = vec![ReportItem::FileMissing {
+            path: PathBuf::from("/etc/hosts"),
+        }];
+        let summary = Summary::new(Instant::now(), 2, 10, &items);
+        let run = RunReport::new("web1", &items, &summary);
+        let next = SystemTime::now();
+        state.run_finished(&run, Some(next));
+        let next = epoch_seconds(next);
+
+        assert_eq!(route(&state, "GET", "/healthz", next + OVERDUE).0, 200);
+        let (code, _, body) = route(&state, "GET", "/healthz", next + OVERDUE + 1);
+        assert_eq!((code, body), (503, b"run overdue by 61s\n".to_vec()));
+
+        let (code, content_type, body) = route(&state, "GET", "/last-report", next);
+        assert_eq!((code, content_type), (200, "application/json"));
+        let report: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(report["host"], "web1");
+
+        let (_, _, body) = route(&state, "GET", "/metrics?x=1", next);
+        let metrics = String::from_utf8(body).unwrap();
+        assert!(metrics.contains("fimbl_info{host=\"web1\"} 1\n"));
+        assert!(metrics.contains("# TYPE fimbl_runs_total counter\nfimbl_runs_total 1\n"));
+        assert!(metrics.contains("fimbl_last_run_items{kind=\"file-missing\"} 1\n"));
+        assert!(!metrics.contains("fimbl_scan_files_examined"));
+    }
+}
@@ -0,0 +1,184 @@
+//! Running external commands on events
+//!
+//! Each hook is a shell command. It receives the event as JSON on
+//! stdin and, for convenience, `FIMBL_HOOK`, `FIMBL_KIND`, `FIMBL_CODE`
+//! (for a report item) and `FIMBL_PATH` in its environment. A hook that fails is reported but
+//! doesn't stop fimbl.
+
+use crate::report::{ReportItem, Severity};
+
+use std::{
+    io::Write,
+    path::Path,
+    process::{Command, Stdio},
+};
+
+/// Commands to run on events
+#[derive(Default, Clone)]
+pub struct Hooks {
+    /// Run for each change found by a verify
+    pub on_change: Option<String>,
+
+    /// Run for each tracked file found to be missing
+    pub on_missing: Option<String>,
+
+    /// Run for each file whose modifications are accepted
+    pub on_accept: Option<String>,
+
+    /// Run once after each verify, with every item reported
+    pub post_verify: Option<String>,
+}
+
+/// Shell command to run a hook
+#[cfg(not(windows))]
+fn shell(command: &str) -> Command {
+    let mut shell = Command::new("sh");
+    shell.arg("-c").arg(command);
+    shell
+}
+
+/// Shell command to run a hook
+#[cfg(windows)]
+fn shell(command: &str) -> Command {
+    let mut shell = Command::new("cmd");
+    shell.arg("/C").arg(command);
+    shell
+}
+
+/// Run one hook, returning a report item if it fails
+fn run(
+    name: &str,
+    command: &str,
+    kind: &str,
+    code: Option<&str>,
+    path: Option<&Path>,
+    json: &[u8],
+) -> Option<ReportItem> {
+    let mut shell = shell(command);
+    shell
+        .env("FIMBL_HOOK", name)
+        .env("FIMBL_KIND", kind)
+        .stdin(Stdio::piped());
+    if let Some(code) = code {
+        shell.env("FIMBL_CODE", code);
+    }
+    if let Some(path) = path {
+        shell.env("FIMBL_PATH", path);
+    }
+
+    let failed = |message: String| {
+        Some(ReportItem::HookFailed {
+            hook: name.to_string(),
+            message,
+        })
+    };
+
+    let mut child = match shell.spawn() {
+        Ok(child) => child,
+        Err(e) => return failed(e.to_string()),
+    };
+    if let Some(mut stdin) = child.stdin.take() {
+        // the hook needn't read its input
+        let _ = stdin.write_all(json);
+    }
+    match child.wait() {
+        Ok(status) if status.success() => None,
+        Ok(status) => failed(status.to_string()),
+        Err(e) => failed(e.to_string()),
+    }
+}
+
+impl Hooks {
+    /// Run the change, missing and post-verify hooks for the items
+    /// reported by a verify, returning any hook failures
+    pub fn verified(&self, items: &[ReportItem]) -> Vec<ReportItem> {
+        let mut failures = vec![];
+
+        for item in items {
+            let hook = match item {
+                ReportItem::FileMissing { .. } => ("on-missing", &self.on_missing),
+                _ if item.severity() >= Severity::Warning => ("on-change", &self.on_change),
+                _ => continue,
+            };
+            if let (name, Some(command)) = hook {
+                let json = serde_json::to_vec(item).unwrap();
+                failures.extend(run(
+                    name,
+                    command,
+                    &item.kind(),
+                    Some(item.code()),
+                    item.path(),
+                    &json,
+                ));
+            }
+        }
+
+        if let Some(command) = &self.post_verify {
+            let json = serde_json::to_vec(items).unwrap();
+            failures.extend(run("post-verify", command, "verify", None, None, &json));
+        }
+
+        failures
+    }
+
+    /// Run the accept hook for a file whose modifications have been
+    /// accepted, returning any failure
+    pub fn accepted(&self, path: &Path) -> Option<ReportItem> {
+        let command = self.on_accept.as_ref()?;
+        let json = serde_json::to_vec(&serde_json::json!({
+            "kind": "accepted",
+            "path": path,
+        }))
+        .unwrap();
+        run("on-accept", command, "accepted", None, Some(path), &json)
+    }
+}
+
+#[cfg(all(test, unix))]
+pub mod tests {
+
+    use super::*;
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_hooks_receive_items() {
+        let dir = tempfile::tempdir().unwrap();
+        let out = dir.path().join("out");
+        let hooks = Hooks {
+            on_change: Some(format!(
+                "printf '%s ' \"$FIMBL_CODE\" \"$FIMBL_KIND\" \"$FIMBL_PATH\" >> {0}; cat >> {0}",
+                out.display()
+            )),
+            on_missing: Some("exit 3".to_string()),
+            ..Default::default()
+        };
+        let items = vec![
+            ReportItem::FileContentChanged {
+                path: PathBuf::from("/etc/hosts"),
+                recorded_hash: None,
+                current_hash: None,
+                blocks: None,
+                fuzzy_distance: None,
+            },
+            ReportItem::FileNotTracked {
+                path: PathBuf::from("/etc/motd"),
+            },
+            ReportItem::FileMissing {
+                path: PathBuf::from("/etc/passwd"),
+            },
+        ];
+
+        let failures = hooks.verified(&items);
+        assert_eq!(
+            std::fs::read_to_string(&out).unwrap(),
+            concat!(
+                r#"F001 file-content-changed /etc/hosts {"code":"F001","kind":"file-content-changed","#,
+                r#""severity":"warning","path":"/etc/hosts","details":{}}"#
+            )
+        );
+        assert!(matches!(
+            failures.as_slice(),
+            [ReportItem::HookFailed { hook, .. }] if hook == "on-missing"
+        ));
+    }
+}
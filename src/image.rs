@@ -0,0 +1,468 @@
+//! Files of container images
+//!
+//! An image exported with `docker save` (a docker archive) or held as
+//! an OCI image layout (a directory, or a tar of one) is unpacked
+//! layer upon layer, honouring whiteouts, into a temporary directory,
+//! so its files can be fingerprinted there as if the image were a file
+//! system mounted elsewhere (see `Fingerprinter::with_root`). The
+//! owners, modes and modification times of its files are those in the
+//! layers, whoever unpacks them. Special files (devices and FIFOs)
+//! are left out, as they can't be made without privilege.
+
+use crate::{
+    error::FimblError,
+    fingerprint::{rooted, Fingerprint},
+};
+
+use flate2::read::GzDecoder;
+use serde_derive::Deserialize;
+use std::{
+    collections::HashMap,
+    fs::{symlink_metadata, File},
+    io::{self, BufRead, BufReader, ErrorKind, Read, Seek, SeekFrom},
+    path::{Component, Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+use tar::{Archive, EntryType};
+use tempfile::TempDir;
+
+/// File type bits of unix modes, which tar headers leave out
+const REGULAR: u32 = 0o100000;
+const DIRECTORY: u32 = 0o040000;
+const SYMLINK: u32 = 0o120000;
+
+/// Indexes (at most) followed from an OCI index to an image manifest
+const MAX_INDEX_DEPTH: usize = 4;
+
+/// An image's entry in a docker archive's manifest.json
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct DockerManifest {
+    layers: Vec<String>,
+}
+
+/// Reference to a blob of an OCI image layout by its digest
+#[derive(Deserialize)]
+struct Descriptor {
+    digest: String,
+}
+
+/// An OCI image index or manifest: an index lists manifests, a
+/// manifest lists layers
+#[derive(Deserialize)]
+struct OciManifest {
+    #[serde(default)]
+    manifests: Vec<Descriptor>,
+    #[serde(default)]
+    layers: Vec<Descriptor>,
+}
+
+/// Where the manifests and layers of an image are kept
+enum Blobs {
+    /// A directory holding them
+    Directory(PathBuf),
+
+    /// An archive holding them, with the offset into it and size of each
+    Archive(PathBuf, HashMap<PathBuf, (u64, u64)>),
+}
+
+impl Blobs {
+    fn open(location: &Path) -> Result<Self, FimblError> {
+        if location.is_dir() {
+            return Ok(Blobs::Directory(location.to_owned()));
+        }
+        let mut members = HashMap::new();
+        let file = File::open(location).map_err(|e| invalid(location, e.to_string()))?;
+        let mut archive = Archive::new(file);
+        for entry in archive.entries()? {
+            let entry = entry?;
+            if let Some(name) = image_path(&entry.path()?) {
+                members.insert(name, (entry.raw_file_position(), entry.size()));
+            }
+        }
+        Ok(Blobs::Archive(location.to_owned(), members))
+    }
+
+    fn contains(&self, name: &str) -> bool {
+        match self {
+            Blobs::Directory(dir) => dir.join(name).is_file(),
+            Blobs::Archive(_, members) => members.contains_key(Path::new(name)),
+        }
+    }
+
+    fn read(&self, name: &str) -> Result<Box<dyn Read>, FimblError> {
+        let missing = || invalid(self.location(), format!("{name} is missing"));
+        let name = image_path(Path::new(name)).ok_or_else(missing)?;
+        match self {
+            Blobs::Directory(dir) => match File::open(dir.join(name)) {
+                Ok(file) => Ok(Box::new(file)),
+                Err(e) if e.kind() == ErrorKind::NotFound => Err(missing()),
+                Err(e) => Err(e.into()),
+            },
+            Blobs::Archive(path, members) => {
+                let (offset, size) = *members.get(&name).ok_or_else(missing)?;
+                let mut file = File::open(path)?;
+                file.seek(SeekFrom::Start(offset))?;
+                Ok(Box::new(file.take(size)))
+            }
+        }
+    }
+
+    fn read_json<T: serde::de::DeserializeOwned>(&self, name: &str) -> Result<T, FimblError> {
+        serde_json::from_reader(self.read(name)?)
+            .map_err(|e| invalid(self.location(), format!("{name}: {e}")))
+    }
+
+    fn location(&self) -> &Path {
+        match self {
+            Blobs::Directory(path) | Blobs::Archive(path, _) => path,
+        }
+    }
+
+    /// Names of the image's layers, lowest first
+    fn layers(&self) -> Result<Vec<String>, FimblError> {
+        if self.contains("manifest.json") {
+            let manifests: Vec<DockerManifest> = self.read_json("manifest.json")?;
+            return match manifests.into_iter().next() {
+                Some(manifest) => Ok(manifest.layers),
+                None => Err(invalid(self.location(), "manifest.json is empty".into())),
+            };
+        }
+
+        let mut manifest: OciManifest = self.read_json("index.json")?;
+        for _ in 0..MAX_INDEX_DEPTH {
+            if manifest.manifests.is_empty() {
+                return manifest.layers.iter().map(|d| self.blob(d)).collect();
+            }
+            manifest = self.read_json(&self.blob(&manifest.manifests[0])?)?;
+        }
+        Err(invalid(self.location(), "no image manifest found".into()))
+    }
+
+    /// Name of the blob a descriptor refers to
+    fn blob(&self, descriptor: &Descriptor) -> Result<String, FimblError> {
+        match descriptor.digest.split_once(':') {
+            Some((algorithm, hex)) if !algorithm.contains('/') && !hex.contains('/') => {
+                Ok(format!("blobs/{algorithm}/{hex}"))
+            }
+            _ => Err(invalid(
+                self.location(),
+                format!("bad digest {}", descriptor.digest),
+            )),
+        }
+    }
+}
+
+/// Attributes of a file in an image, as given in its layer
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Attributes {
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub modified: SystemTime,
+}
+
+/// The files of an image, unpacked
+pub struct ImageFiles {
+    dir: TempDir,
+    attributes: HashMap<PathBuf, Attributes>,
+}
+
+impl ImageFiles {
+    /// Unpack the image at `location`, given as a docker archive or
+    /// OCI layout path, optionally prefixed by its transport in the
+    /// manner of skopeo (`docker-archive:`, `oci:` or `oci-archive:`)
+    pub fn unpack(location: &str) -> Result<Self, FimblError> {
+        let location = ["docker-archive:", "oci-archive:", "oci:"]
+            .iter()
+            .find_map(|transport| location.strip_prefix(transport))
+            .unwrap_or(location);
+        let blobs = Blobs::open(Path::new(location))?;
+        let mut image = ImageFiles {
+            dir: tempfile::Builder::new().prefix("fimbl-image-").tempdir()?,
+            attributes: HashMap::new(),
+        };
+        for layer in blobs.layers()? {
+            let layer = decompressed(blobs.read(&layer)?)
+                .map_err(|e| invalid(blobs.location(), format!("{layer}: {e}")))?;
+            image
+                .apply(layer)
+                .map_err(|e| invalid(blobs.location(), e.to_string()))?;
+        }
+        Ok(image)
+    }
+
+    /// Where the image's files have been unpacked
+    pub fn root(&self) -> &Path {
+        self.dir.path()
+    }
+
+    /// Attributes of a file in the image, if it is there
+    pub fn attributes(&self, path: &Path) -> Option<&Attributes> {
+        self.attributes.get(path)
+    }
+
+    /// A fingerprint of a file unpacked from the image as the file is
+    /// in the image, for comparison with the one recorded
+    ///
+    /// Its owner, mode and modification time are those in the image,
+    /// and attributes an image doesn't keep (creation time, flags,
+    /// extended attributes and anything Windows-specific) are taken as
+    /// recorded.
+    pub fn as_in_image(
+        &self,
+        path: &Path,
+        current: Fingerprint,
+        recorded: &Fingerprint,
+    ) -> Fingerprint {
+        let Some(attributes) = self.attributes.get(path) else {
+            return current;
+        };
+        Fingerprint {
+            created: recorded.created,
+            modified: current.modified.map(|_| attributes.modified),
+            unix_mode: Some(attributes.mode),
+            read_only: attributes.mode & 0o222 == 0,
+            uid: Some(attributes.uid),
+            gid: Some(attributes.gid),
+            flags: recorded.flags,
+            xattrs: recorded.xattrs.clone(),
+            streams: recorded.streams.clone(),
+            windows_attributes: recorded.windows_attributes,
+            security_hash: recorded.security_hash,
+            ..current
+        }
+    }
+
+    /// Unpack a layer over those already unpacked
+    fn apply(&mut self, layer: impl Read) -> io::Result<()> {
+        let root = self.dir.path().to_owned();
+        let mut archive = Archive::new(layer);
+        for entry in archive.entries()? {
+            let mut entry = entry?;
+            let Some(path) = image_path(&entry.path()?).map(|path| Path::new("/").join(path))
+            else {
+                continue;
+            };
+            let (Some(parent), Some(name)) = (path.parent(), path.file_name()) else {
+                continue;
+            };
+            // unpacked within the image, whatever symlinks lead there,
+            // and known by the path they lead to
+            let dir = rooted(&root, parent, true);
+            let parent = &in_image(&root, &dir);
+            let path = parent.join(name);
+            let name = name.to_string_lossy();
+
+            if name == ".wh..wh..opq" {
+                if dir.is_dir() {
+                    for child in std::fs::read_dir(&dir)? {
+                        remove(&child?.path())?;
+                    }
+                }
+                self.attributes
+                    .retain(|kept, _| kept == parent || !kept.starts_with(parent));
+                continue;
+            }
+            if let Some(hidden) = name.strip_prefix(".wh.") {
+                remove(&dir.join(hidden))?;
+                let hidden = parent.join(hidden);
+                self.attributes.retain(|kept, _| !kept.starts_with(&hidden));
+                continue;
+            }
+
+            std::fs::create_dir_all(&dir)?;
+            let on_disk = dir.join(&*name);
+            let header = entry.header();
+            let mut attributes = Attributes {
+                mode: header.mode()? & 0o7777,
+                uid: header.uid()? as u32,
+                gid: header.gid()? as u32,
+                modified: SystemTime::UNIX_EPOCH + Duration::from_secs(header.mtime()?),
+            };
+            match header.entry_type() {
+                EntryType::Directory => {
+                    if !symlink_metadata(&on_disk).is_ok_and(|m| m.is_dir()) {
+                        remove(&on_disk)?;
+                        std::fs::create_dir(&on_disk)?;
+                    }
+                    attributes.mode |= DIRECTORY;
+                }
+                EntryType::Regular | EntryType::Continuous => {
+                    remove(&on_disk)?;
+                    io::copy(&mut entry, &mut File::create(&on_disk)?)?;
+                    attributes.mode |= REGULAR;
+                }
+                EntryType::Symlink => {
+                    let Some(target) = entry.link_name()? else {
+                        continue;
+                    };
+                    remove(&on_disk)?;
+                    symlink(&target, &on_disk)?;
+                    attributes.mode |= SYMLINK;
+                }
+                EntryType::Link => {
+                    let Some(linked) = entry.link_name()?.as_deref().and_then(image_path) else {
+                        continue;
+                    };
+                    let linked = rooted(&root, &Path::new("/").join(linked), false);
+                    let Some(linked_attributes) = self.attributes.get(&in_image(&root, &linked))
+                    else {
+                        continue;
+                    };
+                    attributes = linked_attributes.clone();
+                    remove(&on_disk)?;
+                    std::fs::hard_link(linked, &on_disk)?;
+                }
+                _ => continue,
+            }
+            self.attributes.insert(path, attributes);
+        }
+        Ok(())
+    }
+}
+
+/// A path within an image or archive, relative and with no `..`
+fn image_path(path: &Path) -> Option<PathBuf> {
+    let mut relative = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(name) => relative.push(name),
+            Component::ParentDir => return None,
+            _ => {}
+        }
+    }
+    (!relative.as_os_str().is_empty()).then_some(relative)
+}
+
+/// The path in the image of a file unpacked under root
+fn in_image(root: &Path, on_disk: &Path) -> PathBuf {
+    Path::new("/").join(on_disk.strip_prefix(root).unwrap_or(on_disk))
+}
+
+/// A layer's contents, decompressed if gzipped
+fn decompressed(layer: Box<dyn Read>) -> io::Result<Box<dyn Read>> {
+    let mut layer = BufReader::new(layer);
+    let magic = layer.fill_buf()?;
+    if magic.starts_with(&[0x1f, 0x8b]) {
+        Ok(Box::new(GzDecoder::new(layer)))
+    } else if magic.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+        Err(io::Error::other("zstd compressed layers are not supported"))
+    } else {
+        Ok(Box::new(layer))
+    }
+}
+
+/// Remove a file or directory, if it is there
+fn remove(path: &Path) -> io::Result<()> {
+    match symlink_metadata(path) {
+        Ok(metadata) if metadata.is_dir() => std::fs::remove_dir_all(path),
+        Ok(_) => std::fs::remove_file(path),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(unix)]
+fn symlink(target: &Path, path: &Path) -> io::Result<()> {
+    std::os::unix::fs::symlink(target, path)
+}
+
+#[cfg(windows)]
+fn symlink(target: &Path, path: &Path) -> io::Result<()> {
+    std::os::windows::fs::symlink_file(target, path)
+}
+
+fn invalid(location: &Path, message: String) -> FimblError {
+    FimblError::ImageInvalid(location.to_owned(), message)
+}
+
+#[cfg(test)]
+pub mod tests {
+
+    use super::*;
+    use tar::{Builder, Header};
+
+    /// A layer of files, symlinks (`target` beginning `->`) and
+    /// whiteouts (named `.wh.*`, with no contents)
+    fn layer(files: &[(&str, &str)]) -> Vec<u8> {
+        let mut builder = Builder::new(vec![]);
+        for (path, contents) in files {
+            let mut header = Header::new_gnu();
+            header.set_mode(0o4755);
+            header.set_uid(0);
+            header.set_gid(42);
+            header.set_mtime(1_700_000_000);
+            // as written, even with a `..` which builders refuse
+            header.as_old_mut().name[..path.len()].copy_from_slice(path.as_bytes());
+            match contents.strip_prefix("->") {
+                Some(target) => {
+                    header.set_entry_type(EntryType::Symlink);
+                    header.set_link_name(target).unwrap();
+                    header.set_size(0);
+                    header.set_cksum();
+                    builder.append(&header, io::empty()).unwrap();
+                }
+                None => {
+                    header.set_size(contents.len() as u64);
+                    header.set_cksum();
+                    builder.append(&header, contents.as_bytes()).unwrap();
+                }
+            }
+        }
+        builder.into_inner().unwrap()
+    }
+
+    #[test]
+    fn test_unpack_docker_archive() {
+        let dir = tempfile::tempdir().unwrap();
+        let lower = layer(&[
+            ("./etc/passwd", "root:x:0:0"),
+            ("etc/hosts", "127.0.0.1"),
+            ("etc/cron.d/job", "* * * * *"),
+            ("sbin", "->/usr/sbin"),
+            ("../escaped", "outside"),
+        ]);
+        let upper = layer(&[
+            ("etc/.wh.hosts", ""),
+            ("etc/cron.d/.wh..wh..opq", ""),
+            ("sbin/init", "#!/bin/sh"),
+        ]);
+        let manifest = r#"[{"Config":"config.json","Layers":["1/layer.tar","2/layer.tar"]}]"#;
+        let mut archive = Builder::new(vec![]);
+        for (name, contents) in [
+            ("manifest.json", manifest.as_bytes()),
+            ("1/layer.tar", &lower),
+            ("2/layer.tar", &upper),
+        ] {
+            let mut header = Header::new_gnu();
+            header.set_size(contents.len() as u64);
+            header.set_mode(0o644);
+            archive.append_data(&mut header, name, contents).unwrap();
+        }
+        let path = dir.path().join("image.tar");
+        std::fs::write(&path, archive.into_inner().unwrap()).unwrap();
+
+        let image = ImageFiles::unpack(&format!("docker-archive:{}", path.display())).unwrap();
+        let mut paths: Vec<_> = image.attributes.keys().collect();
+        paths.sort();
+        assert_eq!(
+            paths,
+            ["/etc/passwd", "/sbin", "/usr/sbin/init"].map(Path::new)
+        );
+        assert_eq!(
+            std::fs::read_to_string(image.root().join("usr/sbin/init")).unwrap(),
+            "#!/bin/sh"
+        );
+        assert_eq!(
+            image.attributes(Path::new("/etc/passwd")),
+            Some(&Attributes {
+                mode: 0o104755,
+                uid: 0,
+                gid: 42,
+                modified: SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000),
+            })
+        );
+        assert!(!dir.path().join("escaped").exists());
+    }
+}
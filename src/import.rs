@@ -0,0 +1,829 @@
+//! Importing the baselines of other integrity checkers
+//!
+//! An AIDE database (`aide.db`, gzipped or not) has a line for each
+//! file giving the attributes its `@@db_spec` line names, hashes in
+//! base64. A Tripwire database printed as text (`twprint -m d`) has an
+//! "Object name:" paragraph for each file listing its properties, the
+//! SHA-1 among them. Neither hashes with SHA3-256, so contents can't
+//! simply be carried over: a file is imported by hashing it afresh,
+//! once its contents are found to match the strongest hash recorded of
+//! them that fimbl can check (SHA-512, SHA-256 or SHA-1), and recording
+//! it with the permissions, owner and (from AIDE) modification time
+//! recorded, so any change to those since shows when it is verified.
+//!
+//! A Tripwire policy (`twpol.txt`) names files and directories to
+//! watch and stop points (`!path ;`) not to; the files it takes in, as
+//! they are now, can be added like any others, tagged with the name,
+//! severity and property mask of the rule taking each in.
+
+use crate::{
+    error::FimblError,
+    fingerprint::{DigestAlgorithm, Fingerprint},
+    report::ReportItem,
+};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use flate2::read::GzDecoder;
+use std::{
+    collections::BTreeMap,
+    ffi::OsString,
+    fs::{symlink_metadata, File},
+    io::{self, BufRead, BufReader},
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+/// File type bits of unix modes
+const FILE_TYPE: u32 = 0o170000;
+const DIRECTORY: u32 = 0o040000;
+
+/// Tripwire's base64 digits, in order
+const TRIPWIRE_BASE64: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// Formats of baseline imported
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum ImportFormat {
+    /// An AIDE database
+    Aide,
+    /// A Tripwire database printed as text (`twprint -m d`)
+    Tripwire,
+    /// A Tripwire policy, whose files are added as they are
+    Twpol,
+}
+
+/// A file as another integrity checker recorded it
+#[derive(Debug, PartialEq, Eq)]
+pub struct Entry {
+    pub path: PathBuf,
+
+    /// Unix mode, with the file type where recorded
+    pub mode: Option<u32>,
+
+    pub uid: Option<u32>,
+
+    pub gid: Option<u32>,
+
+    pub modified: Option<SystemTime>,
+
+    /// Hashes of the contents recorded that can be checked
+    digests: Vec<(DigestAlgorithm, Vec<u8>)>,
+}
+
+impl Entry {
+    fn new(path: PathBuf) -> Self {
+        Entry {
+            path,
+            mode: None,
+            uid: None,
+            gid: None,
+            modified: None,
+            digests: vec![],
+        }
+    }
+
+    /// True if recorded as a directory
+    pub fn is_directory(&self) -> bool {
+        self.mode.is_some_and(|mode| mode & FILE_TYPE == DIRECTORY)
+    }
+
+    /// The strongest algorithm a hash of the contents was recorded
+    /// with, if any, to check them by
+    pub fn strongest_digest(&self) -> Option<DigestAlgorithm> {
+        self.digests.iter().map(|(algorithm, _)| *algorithm).min()
+    }
+
+    /// A report of the file's contents if, as fingerprinted (with a
+    /// digest by the strongest algorithm), they don't match the hash
+    /// recorded of them
+    pub fn changed_contents(&self, current: &Fingerprint) -> Option<ReportItem> {
+        let (_, recorded) = self.digests.iter().min()?;
+        (current.digest.as_ref() != Some(recorded)).then(|| ReportItem::FileContentChanged {
+            path: self.path.clone(),
+            recorded_hash: Some(hex::encode(recorded)),
+            current_hash: current.digest.as_ref().map(hex::encode),
+            blocks: None,
+            fuzzy_distance: None,
+        })
+    }
+
+    /// The current fingerprint of the file with the attributes
+    /// recorded in place of its own
+    pub fn as_recorded(&self, current: Fingerprint) -> Fingerprint {
+        let unix_mode = match (self.mode, current.unix_mode) {
+            (Some(mode), Some(current)) => Some(current & FILE_TYPE | mode & !FILE_TYPE),
+            (mode, current) => mode.or(current),
+        };
+        Fingerprint {
+            unix_mode,
+            read_only: match self.mode {
+                Some(mode) => mode & 0o222 == 0,
+                None => current.read_only,
+            },
+            uid: self.uid.or(current.uid),
+            gid: self.gid.or(current.gid),
+            modified: self.modified.or(current.modified),
+            ..current
+        }
+    }
+}
+
+/// The entries of a baseline, AIDE's or Tripwire's
+pub fn read_entries(format: ImportFormat, path: &Path) -> Result<Vec<Entry>, FimblError> {
+    let invalid = |message: String| FimblError::ImportInvalid(path.to_owned(), message);
+    let mut input = BufReader::new(File::open(path).map_err(|e| invalid(e.to_string()))?);
+    let magic = input.fill_buf().map_err(|e| invalid(e.to_string()))?;
+    let input: Box<dyn BufRead> = match magic.starts_with(&[0x1f, 0x8b]) {
+        true => Box::new(BufReader::new(GzDecoder::new(input))),
+        false => Box::new(input),
+    };
+    match format {
+        ImportFormat::Aide => parse_aide(input),
+        ImportFormat::Tripwire => parse_tripwire(input),
+        ImportFormat::Twpol => Err("a policy lists no entries".to_string()),
+    }
+    .map_err(invalid)
+}
+
+/// The entries of an AIDE database's lines
+fn parse_aide(input: impl BufRead) -> Result<Vec<Entry>, String> {
+    let mut spec: Option<Vec<String>> = None;
+    let mut entries = vec![];
+    for (number, line) in input.lines().enumerate() {
+        let line = line.map_err(|e| e.to_string())?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if let Some(fields) = line.strip_prefix("@@db_spec") {
+            spec = Some(fields.split_whitespace().map(String::from).collect());
+            continue;
+        }
+        if line == "@@end_db" {
+            break;
+        }
+        if line.starts_with("@@") {
+            continue;
+        }
+        let spec = spec
+            .as_ref()
+            .ok_or("entries come before the @@db_spec line")?;
+        let values: Vec<&str> = line.split_whitespace().collect();
+        if values.len() != spec.len() {
+            return Err(format!(
+                "line {}: {} values given for {} fields",
+                number + 1,
+                values.len(),
+                spec.len()
+            ));
+        }
+        let fields = spec.iter().map(String::as_str).zip(values);
+        entries.push(aide_entry(fields).map_err(|e| format!("line {}: {e}", number + 1))?);
+    }
+    match spec {
+        Some(_) => Ok(entries),
+        None => Err("not an AIDE database (there is no @@db_spec line)".to_string()),
+    }
+}
+
+/// An entry from the fields of a line of an AIDE database, ignoring
+/// those fimbl has no use for
+fn aide_entry<'a>(fields: impl Iterator<Item = (&'a str, &'a str)>) -> Result<Entry, String> {
+    let number = |value: &str| value.parse::<u32>().map_err(|e| format!("{value}: {e}"));
+    let digest = |value: &str| STANDARD.decode(value).map_err(|e| format!("{value}: {e}"));
+    let mut path = None;
+    let mut entry = Entry::new(PathBuf::new());
+    for (field, value) in fields {
+        match field {
+            "name" => path = Some(decode_name(value)?),
+            "perm" => {
+                let mode = u32::from_str_radix(value, 8).map_err(|e| format!("{value}: {e}"))?;
+                entry.mode = Some(mode);
+            }
+            "uid" => entry.uid = Some(number(value)?),
+            "gid" => entry.gid = Some(number(value)?),
+            "mtime" => entry.modified = decode_time(value)?,
+            // "0" where it wasn't hashed (as for a directory)
+            _ if value == "0" => {}
+            "sha512" => entry
+                .digests
+                .push((DigestAlgorithm::Sha512, digest(value)?)),
+            "sha256" => entry
+                .digests
+                .push((DigestAlgorithm::Sha256, digest(value)?)),
+            _ => {}
+        }
+    }
+    entry.path = path.ok_or("no name is given")?;
+    Ok(entry)
+}
+
+/// The entries of a Tripwire database printed as text
+fn parse_tripwire(input: impl BufRead) -> Result<Vec<Entry>, String> {
+    let mut entries: Vec<Entry> = vec![];
+    for (number, line) in input.lines().enumerate() {
+        let line = line.map_err(|e| e.to_string())?;
+        if let Some(name) = line.strip_prefix("Object name:") {
+            let path = PathBuf::from(name.trim());
+            if !path.is_absolute() {
+                return Err(format!(
+                    "line {}: {}: not an absolute path",
+                    number + 1,
+                    name.trim()
+                ));
+            }
+            entries.push(Entry::new(path));
+            continue;
+        }
+        // properties are "  Name  Value", names having single spaces
+        let (Some(entry), Some((property, value))) =
+            (entries.last_mut(), line.trim().split_once("  "))
+        else {
+            continue;
+        };
+        let value = value.trim();
+        let bad = || format!("line {}: bad {property}: {value}", number + 1);
+        match property {
+            "Mode" => entry.mode = Some(symbolic_mode(value).ok_or_else(bad)?),
+            "UID" => entry.uid = Some(numeric_id(value).ok_or_else(bad)?),
+            "GID" => entry.gid = Some(numeric_id(value).ok_or_else(bad)?),
+            "SHA" => {
+                let digest = tripwire_digest(value, 20).ok_or_else(bad)?;
+                entry.digests.push((DigestAlgorithm::Sha1, digest));
+            }
+            _ => {}
+        }
+    }
+    match entries.is_empty() {
+        false => Ok(entries),
+        true => Err("not a Tripwire database (there are no objects)".to_string()),
+    }
+}
+
+/// A unix mode as `ls -l` shows it, e.g. `-rwsr-xr-x`
+fn symbolic_mode(mode: &str) -> Option<u32> {
+    let mut chars = mode.chars();
+    let mut bits = match chars.next()? {
+        '-' => 0o100000,
+        'd' => DIRECTORY,
+        'l' => 0o120000,
+        'p' => 0o010000,
+        'c' => 0o020000,
+        'b' => 0o060000,
+        's' => 0o140000,
+        _ => return None,
+    };
+    let permissions: Vec<char> = chars.collect();
+    if permissions.len() != 9 {
+        return None;
+    }
+    for (i, c) in permissions.into_iter().enumerate() {
+        let bit = 0o400 >> i;
+        // setuid, setgid or sticky, shown in place of execute
+        let special = [0o4000, 0o2000, 0o1000][i / 3];
+        match (i % 3, c) {
+            (_, '-') => {}
+            (0, 'r') | (1, 'w') | (2, 'x') => bits |= bit,
+            (2, 's' | 't') => bits |= bit | special,
+            (2, 'S' | 'T') => bits |= special,
+            _ => return None,
+        }
+    }
+    Some(bits)
+}
+
+/// The id in a user or group as Tripwire shows it, e.g. `root (0)`
+fn numeric_id(value: &str) -> Option<u32> {
+    let id = match value.rsplit_once('(') {
+        Some((_, id)) => id.strip_suffix(')')?,
+        None => value,
+    };
+    id.trim().parse().ok()
+}
+
+/// A hash of the length given as Tripwire prints it: in hex (with
+/// `twprint --hexadecimal`), or in its own base64, a number written
+/// most significant digit first with no padding
+fn tripwire_digest(value: &str, length: usize) -> Option<Vec<u8>> {
+    let value = value.strip_prefix("0x").unwrap_or(value);
+    if value.len() == 2 * length {
+        if let Ok(digest) = hex::decode(value) {
+            return Some(digest);
+        }
+    }
+    let mut digest = vec![0u8; length];
+    for c in value.bytes() {
+        let mut carry = TRIPWIRE_BASE64.iter().position(|digit| *digit == c)? as u32;
+        for byte in digest.iter_mut().rev() {
+            let shifted = (*byte as u32) * 64 + carry;
+            *byte = shifted as u8;
+            carry = shifted >> 8;
+        }
+        if carry != 0 {
+            return None;
+        }
+    }
+    Some(digest)
+}
+
+/// A file a Tripwire policy takes in, with tags for the rule taking it
+/// in: `tripwire-rule:NAME`, `tripwire-severity:LEVEL` and
+/// `tripwire-mask:MASK`, as far as the policy gives them
+#[derive(Debug, PartialEq)]
+pub struct PolicyFile {
+    pub path: PathBuf,
+    pub tags: Vec<String>,
+}
+
+/// A rule of a policy: the path it names, how many directories deep it
+/// goes (if not without limit), and the tags of the files it takes in
+#[derive(Debug, PartialEq)]
+struct Rule {
+    path: PathBuf,
+    depth: Option<usize>,
+    tags: Vec<String>,
+}
+
+/// The files a Tripwire policy takes in, as they are now: those its
+/// rules name, and those in the directories they name (as deep as any
+/// `recurse` attribute allows, and, if asked, not beyond the file
+/// system of the directory named), short of its stop points, with any
+/// directory that can't be read reported
+///
+/// A file two rules take in is tagged for the more specific (longer)
+/// path, as Tripwire checks it by.
+pub fn policy_files(
+    path: &Path,
+    one_file_system: bool,
+) -> Result<(Vec<PolicyFile>, Vec<ReportItem>), FimblError> {
+    let policy = std::fs::read_to_string(path)
+        .map_err(|e| FimblError::ImportInvalid(path.to_owned(), e.to_string()))?;
+    let (rules, stop_points) = parse_twpol(&policy);
+    let mut taken: BTreeMap<PathBuf, &Rule> = BTreeMap::new();
+    let mut reports = vec![];
+    for rule in &rules {
+        let mut files = vec![];
+        let walk = Walk {
+            stop_points: &stop_points,
+            one_file_system,
+        };
+        walk.collect(&rule.path, rule.depth, None, &mut files, &mut reports);
+        for file in files {
+            let by = taken.entry(file).or_insert(rule);
+            if rule.path.as_os_str().len() > by.path.as_os_str().len() {
+                *by = rule;
+            }
+        }
+    }
+    let files = taken
+        .into_iter()
+        .map(|(path, rule)| PolicyFile {
+            path,
+            tags: rule.tags.clone(),
+        })
+        .collect();
+    Ok((files, reports))
+}
+
+/// The rules of a policy, and its stop points
+///
+/// Rules take the attributes (`rulename`, `severity` and `recurse`) of
+/// the blocks they are in, and their own, with variables (`NAME =
+/// value ;`) substituted. Rules naming paths by variable, as for
+/// Tripwire's own files, are left out, as are directives (`@@section`
+/// and the like).
+fn parse_twpol(policy: &str) -> (Vec<Rule>, Vec<PathBuf>) {
+    let text: String = policy
+        .lines()
+        .map(|line| line.split_once('#').map_or(line, |(code, _)| code))
+        .filter(|line| !line.trim_start().starts_with("@@"))
+        .collect::<Vec<_>>()
+        .join("\n");
+    let mut variables = BTreeMap::new();
+    // the attributes of each block the statement is in, innermost last
+    let mut blocks: Vec<Vec<(String, String)>> = vec![];
+    let mut rules = vec![];
+    let mut stop_points = vec![];
+    for statement in text.split(';') {
+        // blocks closed and opened (each after its attributes) before
+        // the statement proper
+        let mut statement = statement;
+        while let Some(at) = statement.find(['{', '}']) {
+            match statement.as_bytes()[at] {
+                b'{' => blocks.push(attributes(&statement[..at], &variables)),
+                _ => {
+                    blocks.pop();
+                }
+            }
+            statement = &statement[at + 1..];
+        }
+        let statement = statement.trim();
+        if let Some((object, rest)) = statement.split_once("->") {
+            // after any attributes of the rule alone
+            let (object, before) = match object.contains("$(") {
+                true => continue,
+                false => match object.rsplit_once(')') {
+                    Some((before, object)) => (object, attributes(before, &variables)),
+                    None => (object, vec![]),
+                },
+            };
+            let Some(path) = object_path(object) else {
+                continue;
+            };
+            let rest = substitute(rest, &variables);
+            let (mask, own) = rest.split_once('(').unwrap_or((&rest, ""));
+            let mut merged = BTreeMap::new();
+            for (name, value) in blocks.iter().flatten().chain(&before) {
+                merged.insert(name.as_str(), value.clone());
+            }
+            let own = attributes(&format!("({own}"), &variables);
+            merged.extend(
+                own.iter()
+                    .map(|(name, value)| (name.as_str(), value.clone())),
+            );
+            let mut tags = vec![];
+            if let Some(name) = merged.get("rulename") {
+                tags.push(format!("tripwire-rule:{name}"));
+            }
+            if let Some(severity) = merged.get("severity") {
+                tags.push(format!("tripwire-severity:{severity}"));
+            }
+            let mask: String = mask.split_whitespace().collect();
+            if !mask.is_empty() {
+                tags.push(format!("tripwire-mask:{mask}"));
+            }
+            rules.push(Rule {
+                path,
+                depth: merged.get("recurse").and_then(|value| recurse_depth(value)),
+                tags,
+            });
+        } else if let Some(object) = statement.strip_prefix('!') {
+            stop_points.extend(object_path(object));
+        } else if let Some((name, value)) = statement.split_once('=') {
+            variables.insert(
+                name.trim().to_string(),
+                substitute(value.trim(), &variables),
+            );
+        }
+    }
+    (rules, stop_points)
+}
+
+/// Text with each `$(NAME)` replaced by the variable's value, or by
+/// NAME for those (such as Tripwire's own property masks, e.g.
+/// `$(ReadOnly)`) not defined
+fn substitute(text: &str, variables: &BTreeMap<String, String>) -> String {
+    let mut substituted = String::new();
+    let mut rest = text;
+    while let Some((before, after)) = rest.split_once("$(") {
+        let Some((name, after)) = after.split_once(')') else {
+            break;
+        };
+        substituted.push_str(before);
+        substituted.push_str(
+            variables
+                .get(name.trim())
+                .map_or(name.trim(), |value| value),
+        );
+        rest = after;
+    }
+    substituted.push_str(rest);
+    substituted
+}
+
+/// The attributes in the last parenthesised list of some text, e.g.
+/// `(rulename = "Binaries", severity = $(SIG_HI))`, by name, unquoted
+fn attributes(text: &str, variables: &BTreeMap<String, String>) -> Vec<(String, String)> {
+    let text = substitute(text, variables);
+    let Some((_, list)) = text.rsplit_once('(') else {
+        return vec![];
+    };
+    let list = list.rsplit_once(')').map_or(list, |(list, _)| list);
+    list.split(',')
+        .filter_map(|attribute| attribute.split_once('='))
+        .map(|(name, value)| {
+            let value = value.trim();
+            let value = value
+                .strip_prefix('"')
+                .and_then(|quoted| quoted.strip_suffix('"'))
+                .unwrap_or(value);
+            (name.trim().to_string(), value.to_string())
+        })
+        .collect()
+}
+
+/// The path of an object named in a policy, quoted or not
+fn object_path(object: &str) -> Option<PathBuf> {
+    let object = object.trim();
+    let object = object
+        .strip_prefix('"')
+        .and_then(|quoted| quoted.strip_suffix('"'))
+        .unwrap_or(object);
+    Some(PathBuf::from(object)).filter(|path| path.is_absolute())
+}
+
+/// How many directories deep a rule goes, by the value of its
+/// `recurse` attribute: without limit if it is true or -1
+fn recurse_depth(value: &str) -> Option<usize> {
+    match value {
+        "false" => Some(0),
+        value => value.parse().ok(),
+    }
+}
+
+/// The file system a file is on, where that can be told
+#[cfg(unix)]
+fn file_system(metadata: &std::fs::Metadata) -> Option<u64> {
+    use std::os::unix::fs::MetadataExt;
+    Some(metadata.dev())
+}
+
+#[cfg(not(unix))]
+fn file_system(_metadata: &std::fs::Metadata) -> Option<u64> {
+    None
+}
+
+/// How the files under a rule's path are gathered
+struct Walk<'a> {
+    stop_points: &'a [PathBuf],
+    one_file_system: bool,
+}
+
+impl Walk<'_> {
+    /// Gather the files (and symlinks) at or under a path, to the depth
+    /// given, short of the stop points and (if staying on one file
+    /// system) of directories on another than the one given, reporting
+    /// those that can't be read
+    fn collect(
+        &self,
+        path: &Path,
+        depth: Option<usize>,
+        device: Option<u64>,
+        files: &mut Vec<PathBuf>,
+        reports: &mut Vec<ReportItem>,
+    ) {
+        let unreadable = |path: &Path, e: io::Error| ReportItem::FileUnreadable {
+            path: path.to_path_buf(),
+            error: e.to_string(),
+        };
+        if self.stop_points.iter().any(|stop| stop == path) {
+            return;
+        }
+        let metadata = match symlink_metadata(path) {
+            Ok(metadata) => metadata,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return,
+            Err(e) => return reports.push(unreadable(path, e)),
+        };
+        if !metadata.is_dir() {
+            return files.push(path.to_owned());
+        }
+        let here = file_system(&metadata);
+        if self.one_file_system && device.is_some() && here != device {
+            return;
+        }
+        if depth == Some(0) {
+            return;
+        }
+        let entries = match std::fs::read_dir(path) {
+            Ok(entries) => entries,
+            Err(e) => return reports.push(unreadable(path, e)),
+        };
+        for entry in entries {
+            match entry {
+                Ok(entry) => self.collect(
+                    &entry.path(),
+                    depth.map(|depth| depth - 1),
+                    device.or(here),
+                    files,
+                    reports,
+                ),
+                Err(e) => reports.push(unreadable(path, e)),
+            }
+        }
+    }
+}
+
+/// A path as AIDE writes it, with unsafe bytes as `%` and two hex
+/// digits
+fn decode_name(name: &str) -> Result<PathBuf, String> {
+    let mut bytes = vec![];
+    let mut rest = name.as_bytes();
+    while let Some((&byte, after)) = rest.split_first() {
+        if byte == b'%' {
+            let hex = after
+                .get(..2)
+                .ok_or_else(|| format!("{name}: bad escape"))?;
+            let decoded = u8::from_str_radix(&String::from_utf8_lossy(hex), 16)
+                .map_err(|_| format!("{name}: bad escape"))?;
+            bytes.push(decoded);
+            rest = &after[2..];
+        } else {
+            bytes.push(byte);
+            rest = after;
+        }
+    }
+    let path = path_from_bytes(bytes);
+    match path.is_absolute() {
+        true => Ok(path),
+        false => Err(format!("{name}: not an absolute path")),
+    }
+}
+
+/// A time as AIDE writes it: seconds since the epoch, in base64 of
+/// their decimal digits, or "0" where none was recorded
+fn decode_time(time: &str) -> Result<Option<SystemTime>, String> {
+    if time == "0" {
+        return Ok(None);
+    }
+    let seconds = match time.parse::<u64>() {
+        Ok(seconds) => seconds,
+        Err(_) => STANDARD
+            .decode(time)
+            .ok()
+            .and_then(|digits| String::from_utf8(digits).ok())
+            .and_then(|digits| digits.parse::<u64>().ok())
+            .ok_or_else(|| format!("{time}: not a time"))?,
+    };
+    Ok(Some(SystemTime::UNIX_EPOCH + Duration::from_secs(seconds)))
+}
+
+#[cfg(unix)]
+fn path_from_bytes(bytes: Vec<u8>) -> PathBuf {
+    use std::os::unix::ffi::OsStringExt;
+    PathBuf::from(OsString::from_vec(bytes))
+}
+
+#[cfg(not(unix))]
+fn path_from_bytes(bytes: Vec<u8>) -> PathBuf {
+    PathBuf::from(OsString::from(String::from_utf8_lossy(&bytes).into_owned()))
+}
+
+#[cfg(test)]
+pub mod tests {
+
+    use super::*;
+    use crate::fingerprint::Fingerprinter;
+    use sha1::Sha1;
+    use sha2::{Digest, Sha256};
+
+    #[test]
+    fn test_aide_database() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("pass wd");
+        std::fs::write(&file, "root:x:0:0").unwrap();
+        let sha256 = STANDARD.encode(Sha256::digest("root:x:0:0"));
+        let name = file.to_string_lossy().replace(' ', "%20");
+        let db = format!(
+            "@@begin_db\n\
+             # This file was generated by Aide\n\
+             @@db_spec name lname attr perm uid gid size mtime md5 sha256\n\
+             {dir} 0 1 40755 0 0 4096 MTcwMDAwMDAwMA== 0 0\n\
+             {name} 0 1 100600 12 34 10 MTcwMDAwMDAwMA== abc= {sha256}\n\
+             @@end_db\n",
+            dir = dir.path().display()
+        );
+        let entries = parse_aide(db.as_bytes()).unwrap();
+        assert_eq!(entries.len(), 2);
+        assert!(entries[0].is_directory());
+        let entry = &entries[1];
+        assert_eq!(entry.path, file);
+        assert_eq!(entry.mode, Some(0o100600));
+        assert_eq!(
+            entry.modified,
+            Some(SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000))
+        );
+        assert_eq!(entry.strongest_digest(), Some(DigestAlgorithm::Sha256));
+        let mut fingerprinter = Fingerprinter::new(None);
+        let current = fingerprinter
+            .fingerprint_digested(&file, DigestAlgorithm::Sha256)
+            .unwrap();
+        assert!(entry.changed_contents(&current).is_none());
+        let recorded = entry.as_recorded(current.clone());
+        assert_eq!((recorded.uid, recorded.gid), (Some(12), Some(34)));
+        assert_eq!(recorded.modified, entry.modified);
+        assert_eq!(recorded.content_hash, current.content_hash);
+        #[cfg(unix)]
+        assert_eq!(recorded.unix_mode, Some(0o100600));
+
+        std::fs::write(&file, "root:x:0:0:evil").unwrap();
+        let current = fingerprinter
+            .fingerprint_digested(&file, DigestAlgorithm::Sha256)
+            .unwrap();
+        assert!(matches!(
+            entry.changed_contents(&current),
+            Some(ReportItem::FileContentChanged { .. })
+        ));
+        assert!(fingerprinter.fingerprint(&file).unwrap().digest.is_none());
+        assert_eq!(entries[0].strongest_digest(), None);
+
+        assert!(parse_aide("/etc/passwd 0\n".as_bytes()).is_err());
+        assert!(parse_aide("@@db_spec name perm\n/etc/passwd\n".as_bytes()).is_err());
+    }
+
+    #[test]
+    fn test_tripwire_database() {
+        let printout = "\
+Object Detail:
+===============================================================================
+
+Object name:  /etc/passwd
+
+  Property:            Value:
+  -------------        -----------
+  Object Type          Regular File
+  Mode                 -rw-r--r--
+  UID                  root (0)
+  GID                  shadow (42)
+  Modify Time          Thu Dec 10 10:00:00 2020
+  SHA                  Emalh91zTCxdfMKWPh9Zio9xfXz
+
+Object name:  /usr/bin/passwd
+
+  Mode                 -rwsr-xr-x
+  SHA                  499a961f75cd30b175f30a58f87d662a3dc5f5f3
+";
+        let entries = parse_tripwire(printout.as_bytes()).unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].path, Path::new("/etc/passwd"));
+        assert_eq!(entries[0].mode, Some(0o100644));
+        assert_eq!((entries[0].uid, entries[0].gid), (Some(0), Some(42)));
+        assert_eq!(entries[1].mode, Some(0o104755));
+        let sha1 = Sha1::digest("root:x:0:0").to_vec();
+        assert_eq!(entries[0].digests, [(DigestAlgorithm::Sha1, sha1.clone())]);
+        assert_eq!(entries[1].digests, [(DigestAlgorithm::Sha1, sha1)]);
+
+        assert!(parse_tripwire("Object Summary:\n".as_bytes()).is_err());
+        assert!(symbolic_mode("-rwxr-xr-").is_none());
+    }
+
+    #[test]
+    fn test_twpol() {
+        let dir = tempfile::tempdir().unwrap();
+        let etc = dir.path().join("etc");
+        std::fs::create_dir_all(etc.join("ssh/keys")).unwrap();
+        for file in ["passwd", "mtab", "ssh/sshd_config", "ssh/keys/host"] {
+            std::fs::write(etc.join(file), file).unwrap();
+        }
+        let policy = format!(
+            "@@section GLOBAL\n\
+             TWBIN = /usr/sbin ;\n\
+             @@section FS\n\
+             SIG_HI = 100 ;\n\
+             SEC_CRIT = $(IgnoreNone)-SHa ; # critical files\n\
+             (\n  rulename = \"Configuration\",\n  severity = $(SIG_HI)\n)\n\
+             {{\n\
+               \"{etc}\" -> $(SEC_CRIT) (recurse = 2) ;\n\
+               {etc}/ssh -> $(ReadOnly) (rulename = SSH) ;\n\
+               !{etc}/mtab ;\n\
+               $(TWBIN)/siggen -> $(SEC_CRIT) ;\n\
+             }}\n",
+            etc = etc.display()
+        );
+        let tags = |rule: &str, mask: &str| {
+            vec![
+                format!("tripwire-rule:{rule}"),
+                "tripwire-severity:100".to_string(),
+                format!("tripwire-mask:{mask}"),
+            ]
+        };
+        let (rules, stop_points) = parse_twpol(&policy);
+        assert_eq!(
+            rules,
+            [
+                Rule {
+                    path: etc.clone(),
+                    depth: Some(2),
+                    tags: tags("Configuration", "IgnoreNone-SHa"),
+                },
+                Rule {
+                    path: etc.join("ssh"),
+                    depth: None,
+                    tags: tags("SSH", "ReadOnly"),
+                },
+            ]
+        );
+        assert_eq!(stop_points, [etc.join("mtab")]);
+
+        // files under both rules are tagged for the more specific
+        let path = dir.path().join("twpol.txt");
+        std::fs::write(&path, policy).unwrap();
+        let (files, reports) = policy_files(&path, true).unwrap();
+        assert!(reports.is_empty());
+        assert_eq!(
+            files,
+            [
+                PolicyFile {
+                    path: etc.join("passwd"),
+                    tags: tags("Configuration", "IgnoreNone-SHa"),
+                },
+                PolicyFile {
+                    path: etc.join("ssh/keys/host"),
+                    tags: tags("SSH", "ReadOnly"),
+                },
+                PolicyFile {
+                    path: etc.join("ssh/sshd_config"),
+                    tags: tags("SSH", "ReadOnly"),
+                },
+            ]
+        );
+    }
+}
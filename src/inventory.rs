@@ -0,0 +1,212 @@
+//! Exporting the tracked files as an inventory
+//!
+//! A CycloneDX (1.5) bill of materials lists each tracked file as a
+//! component of type `file`, for asset-inventory and vulnerability
+//! tooling that takes SBOMs. A file's hash is its recorded SHA3-256,
+//! where it was recorded plainly (not keyed, nor hashed in chunks), and
+//! its size, mode, tags and note, where recorded, are given as
+//! properties. The host is the component the bill describes.
+
+use crate::fingerprint::{Fingerprint, HashAlgorithm};
+
+use std::{
+    collections::BTreeSet,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+/// CycloneDX specification version followed
+const SPEC_VERSION: &str = "1.5";
+
+/// Formats of inventory
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum ExportFormat {
+    /// A CycloneDX bill of materials (JSON)
+    #[value(name = "cyclonedx")]
+    CycloneDx,
+}
+
+/// A CycloneDX bill of materials
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Bom {
+    bom_format: &'static str,
+    spec_version: &'static str,
+    version: u32,
+    metadata: Metadata,
+    components: Vec<Component>,
+}
+
+#[derive(Serialize)]
+struct Metadata {
+    timestamp: String,
+    tools: Tools,
+    component: Component,
+}
+
+#[derive(Serialize)]
+struct Tools {
+    components: Vec<Component>,
+}
+
+#[derive(Serialize)]
+struct Component {
+    #[serde(rename = "type")]
+    component_type: &'static str,
+    #[serde(rename = "bom-ref", skip_serializing_if = "Option::is_none")]
+    bom_ref: Option<String>,
+    name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    version: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    description: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    hashes: Vec<Hash>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    properties: Vec<Property>,
+}
+
+#[derive(Serialize)]
+struct Hash {
+    alg: &'static str,
+    content: String,
+}
+
+#[derive(Serialize)]
+struct Property {
+    name: &'static str,
+    value: String,
+}
+
+impl Component {
+    fn new(component_type: &'static str, name: String) -> Self {
+        Component {
+            component_type,
+            bom_ref: None,
+            name,
+            version: None,
+            description: None,
+            hashes: vec![],
+            properties: vec![],
+        }
+    }
+
+    /// A tracked file as a component
+    fn file(path: &Path, fingerprint: &Fingerprint) -> Self {
+        let name = path.to_string_lossy().into_owned();
+        let plain = fingerprint.algorithm == HashAlgorithm::Sha3_256
+            && fingerprint.chunk_size.is_none()
+            && !fingerprint.directory
+            && fingerprint.special.is_none();
+        let hashes = match plain {
+            true => vec![Hash {
+                alg: "SHA3-256",
+                content: hex::encode(fingerprint.content_hash),
+            }],
+            false => vec![],
+        };
+        let properties = [
+            ("fimbl:size", fingerprint.size.map(|size| size.to_string())),
+            (
+                "fimbl:mode",
+                fingerprint.unix_mode.map(|m| format!("{m:o}")),
+            ),
+            (
+                "fimbl:tags",
+                Some(fingerprint.tags.join(",")).filter(|tags| !tags.is_empty()),
+            ),
+        ]
+        .into_iter()
+        .filter_map(|(name, value)| {
+            Some(Property {
+                name,
+                value: value?,
+            })
+        })
+        .collect();
+        Component {
+            bom_ref: Some(format!("file:{name}")),
+            description: fingerprint.note.clone(),
+            hashes,
+            properties,
+            ..Component::new("file", name)
+        }
+    }
+}
+
+impl Bom {
+    /// A bill of the tracked files given, on the host given, now
+    ///
+    /// Each bom-ref must be unique, so a file given twice (or named no
+    /// differently from another, once made UTF-8) is listed once.
+    pub fn new(host: &str, files: &[(PathBuf, Fingerprint)]) -> Self {
+        let tool = Component {
+            version: Some(env!("CARGO_PKG_VERSION")),
+            ..Component::new("application", "fimbl".to_string())
+        };
+        let mut listed = BTreeSet::new();
+        Bom {
+            bom_format: "CycloneDX",
+            spec_version: SPEC_VERSION,
+            version: 1,
+            metadata: Metadata {
+                timestamp: humantime::format_rfc3339_seconds(SystemTime::now()).to_string(),
+                tools: Tools {
+                    components: vec![tool],
+                },
+                component: Component::new("device", host.to_string()),
+            },
+            components: files
+                .iter()
+                .map(|(path, fingerprint)| Component::file(path, fingerprint))
+                .filter(|component| listed.insert(component.bom_ref.clone()))
+                .collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+
+    use super::*;
+    use crate::fingerprint::Fingerprinter;
+
+    #[test]
+    fn test_cyclonedx() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("hosts");
+        std::fs::write(&file, "127.0.0.1 localhost").unwrap();
+        let mut fingerprint = Fingerprinter::new(None).fingerprint(&file).unwrap();
+        fingerprint.tags = vec!["network".to_string()];
+
+        let bom = Bom::new("web1", &[(file.clone(), fingerprint.clone())]);
+        let json = serde_json::to_value(&bom).unwrap();
+        assert_eq!(json["bomFormat"], "CycloneDX");
+        assert_eq!(json["metadata"]["component"]["name"], "web1");
+        let component = &json["components"][0];
+        assert_eq!(component["type"], "file");
+        assert_eq!(component["name"], file.to_string_lossy().as_ref());
+        assert_eq!(component["hashes"][0]["alg"], "SHA3-256");
+        assert_eq!(
+            component["hashes"][0]["content"],
+            hex::encode(fingerprint.content_hash)
+        );
+        let properties = component["properties"].as_array().unwrap();
+        assert!(properties
+            .iter()
+            .any(|p| p["name"] == "fimbl:tags" && p["value"] == "network"));
+
+        // keyed hashes mean nothing to anyone without the key
+        fingerprint.algorithm = HashAlgorithm::HmacSha3_256;
+        let bom = Bom::new("web1", &[(file.clone(), fingerprint.clone())]);
+        let json = serde_json::to_value(&bom).unwrap();
+        assert!(json["components"][0].get("hashes").is_none());
+
+        // listed once, however often given
+        let bom = Bom::new(
+            "web1",
+            &[(file.clone(), fingerprint.clone()), (file, fingerprint)],
+        );
+        assert_eq!(bom.components.len(), 1);
+    }
+}
@@ -0,0 +1,34 @@
+//! Diagnostic logging of what fimbl is doing, on stderr
+//!
+//! Commands and the files they work on are spans, so each event
+//! carries the command and file it happened in. Findings are reports,
+//! not log events: the log is for debugging fimbl itself, especially
+//! when it runs unattended as an agent or server.
+
+use tracing::level_filters::LevelFilter;
+use tracing_subscriber::EnvFilter;
+
+/// How log events are written
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LogFormat {
+    /// Human readable lines
+    #[default]
+    Text,
+    /// One JSON object per event, with its span fields
+    Json,
+}
+
+/// Send fimbl's log events at or above a level to stderr
+///
+/// Libraries (notably sled, which is chatty at debug) are held to
+/// warnings at most.
+pub fn init(level: LevelFilter, format: LogFormat) {
+    let libraries = level.min(LevelFilter::WARN);
+    let subscriber = tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::new(format!("{libraries},fimbl={level}")))
+        .with_writer(std::io::stderr);
+    match format {
+        LogFormat::Text => subscriber.init(),
+        LogFormat::Json => subscriber.json().flatten_event(true).init(),
+    }
+}
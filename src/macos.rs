@@ -0,0 +1,67 @@
+//! macOS extended attributes with a bearing on security
+
+use std::{
+    ffi::{c_void, CString},
+    io,
+    os::unix::ffi::OsStrExt,
+    path::Path,
+    ptr,
+};
+
+/// Extended attributes tracked: Gatekeeper's quarantine flag, the
+/// provenance of downloaded code, and the resource fork
+pub const TRACKED_XATTRS: &[&str] = &[
+    "com.apple.quarantine",
+    "com.apple.provenance",
+    "com.apple.ResourceFork",
+];
+
+/// Value of an extended attribute of a file (not following symlinks),
+/// if present
+pub fn xattr(path: &Path, name: &str) -> io::Result<Option<Vec<u8>>> {
+    let path = CString::new(path.as_os_str().as_bytes())?;
+    let name = CString::new(name)?;
+    let get = |value: *mut c_void, size: usize| {
+        // SAFETY: path and name are NUL terminated and value is null
+        // or valid for size bytes
+        unsafe {
+            libc::getxattr(
+                path.as_ptr(),
+                name.as_ptr(),
+                value,
+                size,
+                0,
+                libc::XATTR_NOFOLLOW,
+            )
+        }
+    };
+
+    loop {
+        let size = get(ptr::null_mut(), 0);
+        if size < 0 {
+            return absent_or_error(io::Error::last_os_error());
+        }
+
+        let mut value = vec![0u8; size as usize];
+        let read = get(value.as_mut_ptr() as *mut c_void, value.len());
+        if read < 0 {
+            let error = io::Error::last_os_error();
+            if error.raw_os_error() == Some(libc::ERANGE) {
+                // grew between calls
+                continue;
+            }
+            return absent_or_error(error);
+        }
+        value.truncate(read as usize);
+        return Ok(Some(value));
+    }
+}
+
+/// Attributes that don't exist (or can't on this file system) are
+/// simply absent
+fn absent_or_error(error: io::Error) -> io::Result<Option<Vec<u8>>> {
+    match error.raw_os_error() {
+        Some(libc::ENOATTR) | Some(libc::ENOTSUP) => Ok(None),
+        _ => Err(error),
+    }
+}
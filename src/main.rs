@@ -1,26 +1,108 @@
 //! Simple command line file integrity management tool
 
+mod agent;
+mod anchor;
+mod attest;
+mod backup;
+mod baseline;
+mod bench;
+mod config;
+mod control;
 mod database;
+mod doctor;
+mod email;
+mod encryption;
 mod error;
+mod explain;
 mod fingerprint;
+mod flatfile;
+mod generate;
+mod git;
+mod health;
+mod hooks;
+mod image;
+mod import;
+mod inventory;
+mod logging;
+#[cfg(target_os = "macos")]
+mod macos;
+mod mounts;
+mod notify;
+mod objectstore;
+mod output;
+mod plan;
+mod presets;
+mod remediate;
 mod report;
+mod review;
+mod schedule;
+mod server;
+mod sink;
+mod storage;
+mod throttle;
+mod top;
+mod tpm;
+#[cfg(windows)]
+mod windows;
 
 #[macro_use]
 extern crate serde_derive;
 
-use clap::{Parser, Subcommand};
-use database::SystemDatabase;
+use agent::{local_hostname, push_report, RunReport, SigningKey};
+use anchor::Anchor;
+use attest::{AttestFormat, AttestKey, Statement, Subject};
+use baseline::Baseline;
+use bench::BenchAlgorithm;
+use clap::{parser::ValueSource, CommandFactory, FromArgMatches, Parser, Subcommand};
+use config::AgentConfig;
+use control::{AgentState, ControlCommand, Signal};
+use database::{
+    compare_contents, compare_fingerprints, Access, LogEvent, MergePreference, PathPolicy,
+    SystemDatabase, UnicodePaths,
+};
+use email::{Mailer, SmtpTls};
+use encryption::DatabaseKey;
 use error::FimblError;
-use fingerprint::Fingerprint;
-use report::ReportItem;
+use fingerprint::{
+    file_size, ChangedDuringRead, DigestAlgorithm, Fingerprint, Fingerprinter, HashAlgorithm,
+    HashKey, NetworkFs, ReadsDisagree,
+};
+use git::WorkingTree;
+use hooks::Hooks;
+use image::ImageFiles;
+use import::ImportFormat;
+use inventory::{Bom, ExportFormat};
+use logging::LogFormat;
+use mounts::Mounts;
+use notify::NotifyState;
+use objectstore::{get_object, put_object};
+use output::Format;
+use plan::{Change, Plan};
+use presets::Preset;
+use report::{ReportItem, Severity, Summary};
+use ring::rand::{SecureRandom, SystemRandom};
+use schedule::Schedule;
+use server::ServerConfig;
+use sink::{SinkOptions, SinkSpec};
 use std::{
-    fs::{canonicalize, read_link},
-    path::{Path, PathBuf},
+    collections::{hash_map::RandomState, BTreeMap, BTreeSet},
+    fs::{canonicalize, symlink_metadata},
+    hash::BuildHasher,
+    io::ErrorKind,
+    path::{Component, Path, PathBuf},
+    sync::{
+        mpsc::{Receiver, RecvTimeoutError},
+        Arc,
+    },
+    thread::sleep,
+    time::{Duration, Instant, SystemTime},
 };
+use tracing::{debug_span, info, info_span, level_filters::LevelFilter, warn};
 
 /// fimbl - command line file integrity checker
 ///
 /// All commands use a database at "~/.config/fimbl/db" by default
+/// ("%LOCALAPPDATA%\fimbl\db" on Windows)
 #[derive(Parser)]
 #[command(version)]
 struct CliArgs {
@@ -32,56 +114,1146 @@ struct CliArgs {
     #[arg(short = 's', long)]
     follow_symlinks: bool,
 
+    /// Report size changes without hashing file contents
+    #[arg(short, long)]
+    fast: bool,
+
+    /// Verify the file system mounted at DIR (a forensic image or
+    /// chroot, say) as if it were mounted at /, files being named by
+    /// their paths there
+    #[arg(long, value_name = "DIR")]
+    root: Option<PathBuf>,
+
+    /// Record files by their canonical paths, by the paths given (made
+    /// absolute, symlinks on the way kept), or canonically with the
+    /// paths given kept as aliases finding the same records [default:
+    /// canonical, or the policy the database was first used with]
+    #[arg(long, value_enum, env = "FIMBL_PATH_POLICY")]
+    path_policy: Option<PathPolicy>,
+
+    /// Report changes to files tagged TAG at SEVERITY (info, warning or
+    /// critical) instead, e.g. critical=critical (may be repeated; the
+    /// most severe applies to a file with several such tags)
+    #[arg(long, value_name = "TAG=SEVERITY", value_parser = report::parse_tag_severity)]
+    tag_severity: Vec<(String, Severity)>,
+
     /// Tolerate unexpected pre-existing or absent files
     #[arg(short, long)]
     tolerant: bool,
 
+    /// Report what a command changing the records (add, remove,
+    /// accept...) would change, without changing anything
+    #[arg(long)]
+    dry_run: bool,
+
     /// Specify alternative database
     #[arg(short, long, value_name = "FILE")]
     database: Option<PathBuf>,
 
+    /// Control socket of a running agent (by default the database
+    /// path with ".sock" appended)
+    #[arg(long, value_name = "PATH", env = "FIMBL_CONTROL_SOCKET")]
+    control_socket: Option<PathBuf>,
+
+    /// Log what fimbl does at LEVEL (off, error, warn, info, debug or
+    /// trace) and above, on stderr
+    #[arg(
+        long,
+        value_name = "LEVEL",
+        env = "FIMBL_LOG_LEVEL",
+        default_value = "warn"
+    )]
+    log_level: LevelFilter,
+
+    /// Format of log events
+    #[arg(long, value_enum, env = "FIMBL_LOG_FORMAT", default_value = "text")]
+    log_format: LogFormat,
+
+    /// Wait up to DURATION for another fimbl process to release the
+    /// database (e.g. "30s")
+    #[arg(
+        long,
+        value_name = "DURATION",
+        env = "FIMBL_LOCK_WAIT",
+        default_value = "0s",
+        value_parser = humantime::parse_duration
+    )]
+    lock_wait: Duration,
+
+    /// Read file contents at no more than RATE bytes per second on
+    /// average (e.g. "20M")
+    #[arg(
+        long,
+        value_name = "RATE",
+        env = "FIMBL_MAX_BYTES_PER_SEC",
+        value_parser = fingerprint::parse_size
+    )]
+    max_bytes_per_sec: Option<u64>,
+
+    /// Read file contents in chunks of SIZE bytes (e.g. "64K"), at most
+    /// 1G
+    #[arg(long, value_name = "SIZE", default_value = "1M", value_parser = fingerprint::parse_read_buffer)]
+    read_buffer: u64,
+
+    /// Record hashes of each SIZE block (e.g. "1M") of files added or
+    /// accepted, to show where their contents later change
+    #[arg(
+        long,
+        value_name = "SIZE",
+        env = "FIMBL_BLOCK_SIZE",
+        value_parser = fingerprint::parse_size
+    )]
+    block_size: Option<u64>,
+
+    /// Record TLSH fuzzy hashes of files added or accepted, to show how
+    /// similar their contents remain when they change
+    #[arg(long, env = "FIMBL_FUZZY_HASH")]
+    fuzzy_hash: bool,
+
+    /// What to do about files on network and virtual file systems
+    /// (NFS, CIFS, FUSE, procfs, sysfs...), where reads may hang
+    #[arg(
+        long,
+        value_enum,
+        env = "FIMBL_NETWORK_FS",
+        default_value_t = NetworkFs::Read
+    )]
+    network_fs: NetworkFs,
+
+    /// Give up reading a file on a network or virtual file system
+    /// after DURATION (e.g. "30s"), reporting it instead
+    #[arg(
+        long,
+        value_name = "DURATION",
+        env = "FIMBL_NETWORK_FS_TIMEOUT",
+        value_parser = humantime::parse_duration
+    )]
+    network_fs_timeout: Option<Duration>,
+
+    /// Give up reading any file after DURATION (e.g. "30s"), reporting
+    /// it instead, so slow or broken storage can't hang a run
+    #[arg(
+        long,
+        value_name = "DURATION",
+        env = "FIMBL_FILE_TIMEOUT",
+        value_parser = humantime::parse_duration
+    )]
+    file_timeout: Option<Duration>,
+
+    /// Take creation and modification times within DURATION (e.g.
+    /// "2s") of those recorded as unchanged, for file systems keeping
+    /// coarser times than were recorded
+    #[arg(
+        long,
+        value_name = "DURATION",
+        env = "FIMBL_TIME_TOLERANCE",
+        default_value = "0s",
+        value_parser = humantime::parse_duration
+    )]
+    time_tolerance: Duration,
+
+    /// Map files into memory to hash them, where possible (faster for
+    /// very large files, but files truncated meanwhile crash fimbl)
+    #[arg(long)]
+    mmap: bool,
+
+    /// Read each file twice, dropping it from the page cache in
+    /// between where supported, and report reads that disagree
+    #[arg(long, env = "FIMBL_PARANOID")]
+    paranoid: bool,
+
+    /// Run with the lowest CPU priority and idle IO priority, where
+    /// supported
+    #[arg(long, env = "FIMBL_IDLE_PRIORITY")]
+    idle_priority: bool,
+
+    /// Use the database held by a remote fimbl server at URL
+    #[arg(long, value_name = "URL", conflicts_with = "database")]
+    remote: Option<String>,
+
+    /// Bearer token for the remote database or server
+    #[arg(long, env = "FIMBL_REMOTE_TOKEN", hide_env_values = true)]
+    remote_token: Option<String>,
+
+    /// Keep up to N (at least 1) previous records of each file in the
+    /// database
+    #[arg(
+        long,
+        value_name = "N",
+        env = "FIMBL_HISTORY",
+        default_value_t = database::DEFAULT_HISTORY_LIMIT,
+        value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..)
+    )]
+    history: usize,
+
+    /// After each change to the baseline, append its root hash to FILE
+    #[arg(long, value_name = "FILE", env = "FIMBL_ANCHOR_FILE")]
+    anchor_file: Option<PathBuf>,
+
+    /// After each change to the baseline, post its root hash to URL
+    /// (bearer token as --remote-token)
+    #[arg(long, value_name = "URL", env = "FIMBL_ANCHOR_URL")]
+    anchor_url: Option<String>,
+
+    /// Use the baseline for HOST in a database shared between hosts
+    #[arg(long, env = "FIMBL_HOST", value_parser = parse_host)]
+    host: Option<String>,
+
+    /// Encrypt the database with the key in FILE (or the passphrase in
+    /// FIMBL_DB_KEY)
+    #[arg(long, value_name = "FILE")]
+    db_key_file: Option<PathBuf>,
+
+    /// MAC each database record with the key in FILE, and check them
+    /// as they are read (once given, the key is always needed)
+    #[arg(long, value_name = "FILE", env = "FIMBL_RECORD_KEY_FILE")]
+    record_key_file: Option<PathBuf>,
+
+    /// Use the record key sealed to the TPM at persistent HANDLE (e.g.
+    /// 0x81000100) rather than one in a file
+    #[arg(
+        long,
+        value_name = "HANDLE",
+        env = "FIMBL_RECORD_KEY_TPM",
+        value_parser = tpm::parse_handle,
+        conflicts_with = "record_key_file"
+    )]
+    record_key_tpm: Option<u32>,
+
+    /// PCRs the TPM record key was sealed to (e.g. sha256:0,7), if any
+    #[arg(
+        long,
+        value_name = "PCRS",
+        env = "FIMBL_RECORD_KEY_TPM_PCRS",
+        value_parser = tpm::parse_pcrs,
+        requires = "record_key_tpm"
+    )]
+    record_key_tpm_pcrs: Option<String>,
+
+    /// Hash file contents with the secret key in FILE (HMAC-SHA3_256)
+    #[arg(short, long, value_name = "FILE")]
+    key_file: Option<PathBuf>,
+
+    /// Run COMMAND for each change found (item as JSON on stdin)
+    #[arg(long, value_name = "COMMAND", env = "FIMBL_ON_CHANGE")]
+    on_change: Option<String>,
+
+    /// Run COMMAND for each tracked file found to be missing
+    #[arg(long, value_name = "COMMAND", env = "FIMBL_ON_MISSING")]
+    on_missing: Option<String>,
+
+    /// Run COMMAND for each file whose modifications are accepted
+    #[arg(long, value_name = "COMMAND", env = "FIMBL_ON_ACCEPT")]
+    on_accept: Option<String>,
+
+    /// Run COMMAND after each verify (all items as JSON on stdin)
+    #[arg(long, value_name = "COMMAND", env = "FIMBL_POST_VERIFY")]
+    post_verify: Option<String>,
+
+    /// Email findings from verify-all and agent runs via SMTP server HOST
+    #[arg(
+        long,
+        value_name = "HOST",
+        env = "FIMBL_SMTP_HOST",
+        requires = "mail_to"
+    )]
+    smtp_host: Option<String>,
+
+    /// SMTP port (default 587, 465 or 25 according to --smtp-tls)
+    #[arg(long, value_name = "PORT", env = "FIMBL_SMTP_PORT")]
+    smtp_port: Option<u16>,
+
+    /// How to secure the SMTP connection
+    #[arg(long, value_enum, default_value_t = SmtpTls::Starttls)]
+    smtp_tls: SmtpTls,
+
+    /// User to authenticate to the SMTP server as
+    #[arg(long, env = "FIMBL_SMTP_USER", requires = "smtp_password")]
+    smtp_user: Option<String>,
+
+    /// Password for the SMTP user
+    #[arg(long, env = "FIMBL_SMTP_PASSWORD", hide_env_values = true)]
+    smtp_password: Option<String>,
+
+    /// Sender of emailed findings (default fimbl@HOSTNAME)
+    #[arg(long, value_name = "ADDRESS", env = "FIMBL_MAIL_FROM")]
+    mail_from: Option<String>,
+
+    /// Recipients of emailed findings
+    #[arg(
+        long,
+        value_name = "ADDRESS",
+        env = "FIMBL_MAIL_TO",
+        value_delimiter = ','
+    )]
+    mail_to: Vec<String>,
+
+    /// Least severe findings worth emailing
+    #[arg(long, value_enum, default_value_t = Severity::Warning)]
+    mail_min_severity: Severity,
+
+    /// Notify a finding for a file (by email, and to sinks other than
+    /// stdout) at most once in this time, batching new findings until
+    /// the first has waited it (critical findings go at once)
+    #[arg(
+        long,
+        value_name = "DURATION",
+        env = "FIMBL_NOTIFY_WINDOW",
+        default_value = "0s",
+        value_parser = humantime::parse_duration
+    )]
+    notify_window: Duration,
+
+    /// Format of the report on stdout
+    #[arg(long, value_enum, default_value_t = Format::Text)]
+    format: Format,
+
+    /// Also write the report to FILE ("{date}" and "{time}" are
+    /// replaced by the UTC date and time; "*.json" and "*.csv" files
+    /// get JSON and CSV)
+    #[arg(long, value_name = "FILE", env = "FIMBL_OUTPUT")]
+    output: Option<String>,
+
+    /// Send the report to these sinks, comma separated: stdout,
+    /// file:FILE (named as for --output), json-file:FILE, syslog or
+    /// webhook:URL
+    #[arg(
+        long,
+        value_name = "SINKS",
+        env = "FIMBL_REPORT_TO",
+        value_delimiter = ',',
+        default_value = "stdout"
+    )]
+    report_to: Vec<SinkSpec>,
+
+    /// Bearer token for webhook sinks
+    #[arg(long, value_name = "TOKEN", env = "FIMBL_WEBHOOK_TOKEN")]
+    webhook_token: Option<String>,
+
+    /// Append to output files rather than replacing them
+    #[arg(long)]
+    append: bool,
+
     #[command(subcommand)]
     command: Command,
 }
 
+/// Host names must be non-empty and may not contain NUL
+fn parse_host(host: &str) -> Result<String, String> {
+    if host.is_empty() || host.contains('\0') {
+        Err("invalid host name".to_string())
+    } else {
+        Ok(host.to_string())
+    }
+}
+
+/// Times are given as a date (taken as midnight UTC), an RFC 3339
+/// time or a duration before now
+fn parse_since(since: &str) -> Result<SystemTime, String> {
+    if let Ok(ago) = humantime::parse_duration(since) {
+        return SystemTime::now()
+            .checked_sub(ago)
+            .ok_or_else(|| "duration too long".to_string());
+    }
+    let since = match since.len() {
+        10 => format!("{since}T00:00:00Z"),
+        _ => since.to_string(),
+    };
+    humantime::parse_rfc3339_weak(&since)
+        .map_err(|_| "expected a date, an RFC 3339 time or a duration".to_string())
+}
+
+/// Percentages are given as e.g. "5%" (the sign is optional)
+fn parse_percent(percent: &str) -> Result<f64, String> {
+    match percent.strip_suffix('%').unwrap_or(percent).parse::<f64>() {
+        Ok(p) if p > 0.0 && p <= 100.0 => Ok(p / 100.0),
+        _ => Err("expected a percentage between 0% and 100%".to_string()),
+    }
+}
+
+/// How much of the database a sampling verification covers
+#[derive(Clone, Copy, Debug)]
+enum Sample {
+    /// A fraction of the tracked files
+    Fraction(f64),
+    /// A fixed number of files
+    Count(usize),
+}
+
+impl Sample {
+    fn from_args(fraction: Option<f64>, count: Option<usize>) -> Option<Self> {
+        fraction.map(Sample::Fraction).or(count.map(Sample::Count))
+    }
+
+    /// Number of files to verify out of a total
+    fn size(&self, total: usize) -> usize {
+        match self {
+            Sample::Fraction(fraction) => ((total as f64) * fraction).ceil() as usize,
+            Sample::Count(count) => *count,
+        }
+    }
+}
+
 impl CliArgs {
     fn database(&self) -> Option<&Path> {
         self.database.as_deref()
     }
+
+    /// Path of the control socket of an agent using the database
+    fn control_socket(&self, db_path: &Path) -> PathBuf {
+        match &self.control_socket {
+            Some(path) => path.clone(),
+            None => {
+                let mut path = db_path.as_os_str().to_owned();
+                path.push(".sock");
+                PathBuf::from(path)
+            }
+        }
+    }
+
+    /// True if the command may change the fingerprint records, so the
+    /// root hash should be anchored after it
+    fn changes_baseline(&self) -> bool {
+        matches!(
+            &self.command,
+            Command::Add { .. }
+                | Command::GitAdd { .. }
+                | Command::Import { .. }
+                | Command::Remove { .. }
+                | Command::Rename { .. }
+                | Command::Note { .. }
+                | Command::Accept { .. }
+                | Command::AcceptAll { .. }
+                | Command::Review { .. }
+                | Command::Approve { .. }
+                | Command::Apply { .. }
+                | Command::Fsck { repair: true }
+                | Command::DbMerge { .. }
+                | Command::Restore { .. }
+                | Command::Snapshot {
+                    action: SnapshotAction::Rollback { .. }
+                }
+        )
+    }
+
+    /// True if a plan can be made of the command: all it changes is
+    /// which files are tracked and the fingerprints recorded for them
+    fn plans(&self) -> bool {
+        matches!(
+            &self.command,
+            Command::Add { .. }
+                | Command::GitAdd { .. }
+                | Command::Import { .. }
+                | Command::Remove { .. }
+                | Command::Accept { .. }
+                | Command::AcceptAll { .. }
+                | Command::Review { .. }
+        )
+    }
+
+    /// True if the command can be dry run: it changes the records only
+    /// (restore replacing the database wholesale) or, for remediate,
+    /// has its own dry run
+    fn dry_runs(&self) -> bool {
+        match &self.command {
+            Command::Restore { .. } => false,
+            Command::Remediate { .. } => true,
+            _ => self.changes_baseline(),
+        }
+    }
+
+    /// The path policy for a database not yet keeping one, if none is
+    /// given: as given for a Tripwire policy imported, as Tripwire
+    /// checks each file by the name its policy gives
+    fn default_path_policy(&self) -> PathPolicy {
+        match &self.command {
+            Command::Import {
+                format: ImportFormat::Twpol,
+                ..
+            } => PathPolicy::AsGiven,
+            _ => PathPolicy::Canonical,
+        }
+    }
+
+    /// True if the command is forced to weaken an append-only database
+    fn force_unsafe(&self) -> bool {
+        match &self.command {
+            Command::Remove { force_unsafe, .. }
+            | Command::Rename { force_unsafe, .. }
+            | Command::Restore { force_unsafe, .. }
+            | Command::Accept { force_unsafe, .. }
+            | Command::AcceptAll { force_unsafe, .. }
+            | Command::Review { force_unsafe }
+            | Command::Snapshot {
+                action: SnapshotAction::Rollback { force_unsafe, .. },
+            } => *force_unsafe,
+            _ => false,
+        }
+    }
+
+    /// Key for database encryption, if one is supplied
+    fn database_key(&self) -> Result<Option<DatabaseKey>, FimblError> {
+        match &self.db_key_file {
+            Some(path) => Ok(Some(DatabaseKey::from_file(path)?)),
+            None => Ok(DatabaseKey::from_env()),
+        }
+    }
+
+    /// External commands to run on events
+    fn hooks(&self) -> Hooks {
+        // nothing happened for them to hear of
+        if self.dry_run {
+            return Hooks::default();
+        }
+        Hooks {
+            on_change: self.on_change.clone(),
+            on_missing: self.on_missing.clone(),
+            on_accept: self.on_accept.clone(),
+            post_verify: self.post_verify.clone(),
+        }
+    }
+
+    /// Host named in reports
+    fn report_host(&self) -> String {
+        self.host.clone().unwrap_or_else(local_hostname)
+    }
+
+    /// Mail server and recipients for findings, if configured
+    fn mailer(&self) -> Option<Mailer> {
+        let host = self.smtp_host.clone()?;
+        Some(Mailer {
+            port: self.smtp_port.unwrap_or(self.smtp_tls.default_port()),
+            tls: self.smtp_tls,
+            credentials: self.smtp_user.clone().zip(self.smtp_password.clone()),
+            from: self
+                .mail_from
+                .clone()
+                .unwrap_or_else(|| format!("fimbl@{}", local_hostname())),
+            to: self.mail_to.clone(),
+            min_severity: self.mail_min_severity,
+            host,
+        })
+    }
+
+    /// Fingerprinter for the hashing scheme requested
+    fn fingerprinter(&self) -> Result<Fingerprinter, FimblError> {
+        let key = match &self.key_file {
+            Some(path) => Some(HashKey::from_file(path)?),
+            None => None,
+        };
+        let root = self.root.as_deref().map(canonicalize).transpose()?;
+        Fingerprinter::new(key)
+            .with_root(root)
+            .with_rate_limit(self.max_bytes_per_sec)
+            .with_reads(self.read_buffer as usize, self.mmap)
+            .with_paranoid_reads(self.paranoid)
+            .with_block_hashes(self.block_size)
+            .with_fuzzy_hashes(self.fuzzy_hash)
+            .with_digest(
+                matches!(self.command, Command::Attest { .. }).then_some(DigestAlgorithm::Sha256),
+            )
+            .with_file_timeout(self.file_timeout)
+            .with_time_tolerance(self.time_tolerance)
+            .with_network_fs(self.network_fs, self.network_fs_timeout)
+            .map_err(FimblError::MountTableError)
+    }
+}
+
+/// The command a plan is made of
+#[derive(Parser)]
+struct Planned {
+    #[command(subcommand)]
+    command: Command,
 }
 
 #[derive(Subcommand)]
 enum Command {
+    /// Create the database (if need be) and set policies for it, which
+    /// can't later be unset
+    Init {
+        /// Only remove, accept or roll back records when forced with
+        /// --force-unsafe
+        #[arg(long)]
+        append_only: bool,
+        /// Hold accepted changes pending approval (by another user, or
+        /// with the approval key) before recording them
+        #[arg(long)]
+        two_phase_accept: bool,
+        /// Require approvals to be made with the key in FILE
+        #[arg(long, value_name = "FILE", requires = "two_phase_accept")]
+        approval_key_file: Option<PathBuf>,
+        /// Key paths in Unicode FORM: nfc, so names in composed and
+        /// decomposed form find the same record (the default for new
+        /// databases on macOS), or as-is (elsewhere); only before any
+        /// file is recorded
+        #[arg(long, value_enum, value_name = "FORM")]
+        unicode_paths: Option<UnicodePaths>,
+    },
     /// Add new files to the database (and fingerprint)
-    Add { files: Vec<PathBuf> },
+    Add {
+        /// Track directories given as entries in their own right: their
+        /// attributes and the names in them, not their contents
+        #[arg(long)]
+        dir_entry: bool,
+        /// Add the files under directories given, however deep
+        #[arg(long, short = 'r')]
+        recursive: bool,
+        /// Add no files beyond the file system of each directory given
+        #[arg(long, requires = "recursive")]
+        one_file_system: bool,
+        /// Tag the files with TAG (may be repeated)
+        #[arg(long, value_name = "TAG")]
+        tag: Vec<String>,
+        /// Also add the files of a curated preset that exist on this
+        /// host (may be repeated)
+        #[arg(long, value_enum)]
+        preset: Vec<Preset>,
+        files: Vec<PathBuf>,
+    },
+    /// Add the files another integrity checker's baseline lists (but
+    /// not directories or symlinks), with the attributes it recorded,
+    /// if their contents still match it, or those a Tripwire policy
+    /// takes in
+    Import {
+        /// Format of the baseline
+        #[arg(long, value_enum)]
+        format: ImportFormat,
+        /// Tag the files with TAG (may be repeated)
+        #[arg(long, value_name = "TAG")]
+        tag: Vec<String>,
+        /// Take in no files beyond the file system of each directory a
+        /// policy names
+        #[arg(long)]
+        one_file_system: bool,
+        /// The baseline or policy, e.g. /var/lib/aide/aide.db
+        baseline: PathBuf,
+    },
     /// Remove files from the database (keeping historic fingerprints)
-    Remove { files: Vec<PathBuf> },
+    Remove {
+        /// Allow this in an append-only database (and log that it was
+        /// forced)
+        #[arg(long)]
+        force_unsafe: bool,
+        files: Vec<PathBuf>,
+    },
+    /// Move a tracked file's record to the path it has moved to, once
+    /// the file there is found to have the recorded contents
+    Rename {
+        from: PathBuf,
+        to: PathBuf,
+        /// Allow this in an append-only database (and log that it was
+        /// forced)
+        #[arg(long)]
+        force_unsafe: bool,
+    },
+    /// Set the note on a tracked file (e.g. who owns it), or clear it
+    /// if no note is given
+    Note { file: PathBuf, note: Option<String> },
     /// List all files current in the database
-    List {},
+    List {
+        /// Only list files tagged with TAG (may be repeated)
+        #[arg(long, value_name = "TAG")]
+        tag: Vec<String>,
+        /// Show each file's tags and note
+        #[arg(short, long)]
+        long: bool,
+    },
+    /// List groups of tracked files with the same contents
+    Dupes {},
+    /// Show the records of a file, oldest first
+    History { file: PathBuf },
+    /// Show when, how and by whom a file's current record was made,
+    /// and the record it replaced
+    Blame { file: PathBuf },
+    /// Show the log of changes to the database, oldest first
+    Log {
+        /// Only show changes made at or after TIME (a date, an RFC
+        /// 3339 time or a duration ago, e.g. 7d)
+        #[arg(long, value_name = "TIME", value_parser = parse_since)]
+        since: Option<SystemTime>,
+        /// Only show changes of KIND (may be repeated)
+        #[arg(
+            long,
+            value_name = "KIND",
+            value_parser = clap::builder::PossibleValuesParser::new(database::LOG_EVENT_KINDS)
+        )]
+        kind: Vec<String>,
+        /// Only show changes to PATH, or to files under it
+        #[arg(long, value_name = "PATH")]
+        path: Option<PathBuf>,
+    },
+    /// Find files with (or, in their history, that had) the given
+    /// contents
+    Lookup {
+        /// Content hash to look for, in hex
+        #[arg(long, value_name = "HEX", value_parser = fingerprint::parse_hash)]
+        hash: fingerprint::HashValue,
+    },
     /// Verify the files specified against the database
-    Verify { files: Vec<PathBuf> },
+    Verify {
+        files: Vec<PathBuf>,
+        /// Verify against the baseline published at URL instead (all
+        /// files in it, if none are specified)
+        #[arg(long, value_name = "URL", requires = "sign_key_file")]
+        baseline: Option<String>,
+        /// Key the baseline or manifest is signed with
+        #[arg(long, value_name = "FILE")]
+        sign_key_file: Option<PathBuf>,
+        /// Verify against the exported manifest (or baseline) in FILE,
+        /// with no database at all (all files in it, or those under the
+        /// directories specified, if none are specified)
+        #[arg(
+            long,
+            value_name = "FILE",
+            requires = "sign_key_file",
+            conflicts_with = "baseline"
+        )]
+        manifest: Option<PathBuf>,
+    },
+    /// Verify the files of a container image, as exported by docker
+    /// save or held in an OCI layout, against a signed baseline (or
+    /// manifest) before it is deployed
+    VerifyImage {
+        /// The image: docker-archive:FILE, oci:DIR, oci-archive:FILE or
+        /// just its path
+        image: String,
+        /// Baseline to verify against, at an s3://bucket/key or HTTP(S)
+        /// URL or in a file
+        #[arg(long, value_name = "URL")]
+        baseline: String,
+        /// Key the baseline is signed with
+        #[arg(long, value_name = "FILE")]
+        sign_key_file: PathBuf,
+        /// Only verify files under PREFIX (may be repeated)
+        #[arg(long, value_name = "PREFIX")]
+        prefix: Vec<PathBuf>,
+    },
+    /// Verify the contents of files in mounted volumes against a signed
+    /// manifest, with no database, exiting 1 if any differ: for a
+    /// Kubernetes init container to keep a pod from starting with
+    /// tampered config or secrets (reports are JSON unless --format is
+    /// given)
+    Gate {
+        /// Manifest (or baseline) to verify against
+        #[arg(long, value_name = "FILE")]
+        manifest: PathBuf,
+        /// Key the manifest is signed with
+        #[arg(long, value_name = "FILE")]
+        sign_key_file: PathBuf,
+        /// Only verify the files in the manifest under these
+        /// directories (the volumes mounted, say)
+        dirs: Vec<PathBuf>,
+    },
+    /// Add the files of a git working tree (committed, staged or new,
+    /// but not ignored)
+    GitAdd {
+        /// Any directory of the working tree
+        #[arg(default_value = ".")]
+        repo: PathBuf,
+        /// Tag the files added with TAG (may be repeated)
+        #[arg(long, value_name = "TAG")]
+        tag: Vec<String>,
+    },
+    /// Verify the files of a git working tree, reporting new ones not
+    /// tracked, and those tracked that are gone
+    GitVerify {
+        /// Any directory of the working tree
+        #[arg(default_value = ".")]
+        repo: PathBuf,
+        /// Also report files that differ from (or aren't in) the commit
+        /// at HEAD
+        #[arg(long)]
+        against_head: bool,
+    },
+    /// Export the tracked files (as recorded) as an inventory to stdout,
+    /// e.g. a CycloneDX bill of materials
+    Export {
+        /// Format of the inventory
+        #[arg(long, value_enum, default_value_t = ExportFormat::CycloneDx)]
+        format: ExportFormat,
+        /// Only export files under PREFIX (may be repeated)
+        #[arg(long, value_name = "PREFIX")]
+        prefix: Vec<PathBuf>,
+        /// Only export files tagged with TAG (may be repeated)
+        #[arg(long, value_name = "TAG")]
+        tag: Vec<String>,
+    },
+    /// Attest to the tracked files, if they match their records, with a
+    /// statement of their digests signed with an Ed25519 key, written
+    /// to stdout
+    Attest {
+        /// Format of the attestation
+        #[arg(long, value_enum, default_value_t = AttestFormat::InToto)]
+        format: AttestFormat,
+        /// Ed25519 private key (PKCS#8 PEM) to sign the attestation with
+        #[arg(long, value_name = "FILE")]
+        sign_key_file: PathBuf,
+        /// Only attest to files under PREFIX (may be repeated)
+        #[arg(long, value_name = "PREFIX")]
+        prefix: Vec<PathBuf>,
+    },
+    /// Run as a git hook, blocking a commit (or push) whose versions of
+    /// files tracked in the working tree differ from their records
+    Hook {
+        #[command(subcommand)]
+        hook: GitHook,
+    },
     /// Verify all files current in the database
-    VerifyAll {},
+    VerifyAll {
+        /// Only verify files under PREFIX (may be repeated)
+        #[arg(long, value_name = "PREFIX")]
+        prefix: Vec<PathBuf>,
+        /// Only verify PERCENT of the files (e.g. "5%"), choosing those
+        /// verified least recently
+        #[arg(long, value_name = "PERCENT", value_parser = parse_percent)]
+        sample: Option<f64>,
+        /// Only verify N files, choosing those verified least recently
+        #[arg(long, value_name = "N", conflicts_with = "sample")]
+        sample_count: Option<usize>,
+        /// Only verify files tagged with TAG (may be repeated)
+        #[arg(long, value_name = "TAG")]
+        tag: Vec<String>,
+        /// Report a missing file found elsewhere (a changed file or a
+        /// new one beside it, with the same contents) as moved
+        #[arg(long)]
+        detect_renames: bool,
+    },
+    /// Verify all files as a Nagios/Icinga plugin, with plugin output
+    /// and exit codes
+    Check {},
+    /// Verify all files, writing each finding to stdout as an osquery
+    /// result log line with file_events columns (as `verify-all
+    /// --format osquery` does), for fleets collecting osquery's logs
+    Osquery {},
+    /// Check the fimbl executable (and any other files given, such as
+    /// key files) against a signed anchor at s3://bucket/key, an
+    /// HTTP(S) URL or a file, exiting 1 if any have changed
+    SelfCheck {
+        anchor: String,
+        /// The anchor is signed with the key in FILE
+        #[arg(long, value_name = "FILE")]
+        sign_key_file: PathBuf,
+        /// Record the anchor, from the files as they are now, rather
+        /// than checking against it
+        #[arg(long)]
+        record: bool,
+        /// Check the executable at PATH rather than this one
+        #[arg(long, value_name = "PATH")]
+        executable: Option<PathBuf>,
+        /// Also check FILE (repeatable)
+        #[arg(long = "file", value_name = "FILE")]
+        files: Vec<PathBuf>,
+    },
     /// Accept modifications to the specified files
-    Accept { files: Vec<PathBuf> },
+    Accept {
+        /// Only accept changes to metadata (times, mode, ownership),
+        /// refusing files whose contents have changed
+        #[arg(long)]
+        metadata_only: bool,
+        /// Allow this in an append-only database (and log that it was
+        /// forced)
+        #[arg(long)]
+        force_unsafe: bool,
+        files: Vec<PathBuf>,
+    },
+    /// Accept modifications to every file that fails verification
+    /// (e.g. after an OS upgrade), once they are listed and confirmed
+    AcceptAll {
+        /// Only accept files matching GLOB
+        #[arg(long, value_name = "GLOB", value_parser = glob::Pattern::new)]
+        filter: Option<glob::Pattern>,
+        /// Accept without asking for confirmation
+        #[arg(short, long)]
+        yes: bool,
+        /// Allow this in an append-only database (and log that it was
+        /// forced)
+        #[arg(long)]
+        force_unsafe: bool,
+    },
+    /// Review every file that fails verification on the terminal,
+    /// marking each to be accepted, ignored or investigated, and
+    /// accept the files so marked when done
+    Review {
+        /// Allow accepts in an append-only database (and log that they
+        /// were forced)
+        #[arg(long)]
+        force_unsafe: bool,
+    },
+    /// Record a change pending approval
+    Approve {
+        token: String,
+        /// Approve with the approval key in FILE
+        #[arg(long, value_name = "FILE")]
+        sign_key_file: Option<PathBuf>,
+    },
+    /// Discard a change pending approval
+    Reject { token: String },
+    /// List changes pending approval
+    Pending {},
+    /// Write what a command changing the records (add, accept-all...)
+    /// would change, worked out on a dry run, to a plan to apply later
+    Plan {
+        /// Write the plan (JSON) to FILE
+        #[arg(short, long, value_name = "FILE")]
+        output: PathBuf,
+        /// Sign the plan with the key in FILE
+        #[arg(long, value_name = "FILE")]
+        sign_key_file: PathBuf,
+        /// The command to plan, with its arguments
+        #[arg(
+            value_name = "COMMAND",
+            required = true,
+            trailing_var_arg = true,
+            allow_hyphen_values = true
+        )]
+        command: Vec<String>,
+    },
+    /// Make the changes a plan lists, unless records have changed
+    /// since it was made
+    Apply {
+        plan: PathBuf,
+        /// Check the plan's signature with the key in FILE
+        #[arg(long, value_name = "FILE")]
+        sign_key_file: PathBuf,
+    },
+    /// Restore recorded attributes of files which have drifted
+    Remediate {
+        /// Restore unix permissions and ownership
+        #[arg(long, required = true)]
+        permissions: bool,
+        /// Report what would be restored without changing anything
+        #[arg(long)]
+        dry_run: bool,
+        files: Vec<PathBuf>,
+    },
+    /// Diagnose problems with the database and the environment,
+    /// suggesting fixes
+    Doctor {},
+    /// Measure hashing throughput on this machine with each algorithm
+    /// and way of reading, and recommend settings
+    Bench {
+        /// Size of the scratch file hashed (e.g. "1G"); files of 256M
+        /// or more are hashed in parallel chunks
+        #[arg(long, value_name = "SIZE", default_value = "256M", value_parser = fingerprint::parse_size)]
+        size: u64,
+        /// Algorithms to time, comma separated
+        #[arg(long, value_enum, value_delimiter = ',', default_value = "all")]
+        algorithms: Vec<BenchAlgorithm>,
+    },
+    /// Explain a report item code (e.g. F001) or kind, or list them all
+    Explain {
+        #[arg(value_name = "CODE")]
+        code: Option<String>,
+    },
+    /// Check the integrity of every database entry
+    Fsck {
+        /// Remove corrupt entries
+        #[arg(long)]
+        repair: bool,
+    },
+    /// Compare this database with another
+    DbDiff {
+        other: PathBuf,
+        /// Key for the other database, if encrypted
+        #[arg(long, value_name = "FILE")]
+        other_db_key_file: Option<PathBuf>,
+    },
+    /// Merge another database's assertions into this one
+    DbMerge {
+        source: PathBuf,
+        /// Which view wins when the databases disagree
+        #[arg(long, value_enum, default_value = "newest")]
+        prefer: MergePreference,
+        /// Key for the source database, if encrypted
+        #[arg(long, value_name = "FILE")]
+        source_db_key_file: Option<PathBuf>,
+    },
+    /// Periodically verify all files and push the results to a server
+    Agent {
+        /// URL to push reports to, e.g. https://fimbl.example.com/reports
+        #[arg(required_unless_present = "config")]
+        endpoint: Option<String>,
+        /// Time between runs
+        #[arg(long, default_value = "1h", value_parser = humantime::parse_duration)]
+        interval: Duration,
+        /// Run once and exit (e.g. under cron)
+        #[arg(long)]
+        once: bool,
+        /// Number of times to retry a failed push
+        #[arg(long, default_value_t = 3)]
+        retries: u32,
+        /// Sign reports with the key in FILE
+        #[arg(long, value_name = "FILE")]
+        sign_key_file: Option<PathBuf>,
+        /// Take settings from FILE (TOML), overriding those given here,
+        /// and read it again when told to reload it (by `fimbl ctl`)
+        #[arg(long, value_name = "FILE")]
+        config: Option<PathBuf>,
+        /// Serve /healthz, /metrics (Prometheus) and /last-report over
+        /// HTTP on this address, e.g. 127.0.0.1:9614
+        #[arg(long, value_name = "ADDRESS", conflicts_with = "once")]
+        listen: Option<String>,
+    },
+    /// Send a command to a running agent over its control socket
+    Ctl {
+        #[arg(value_enum)]
+        command: ControlCommand,
+    },
+    /// Show what a running agent is doing, live, from its control
+    /// socket
+    Top {
+        /// Time between updates
+        #[arg(long, default_value = "1s", value_parser = humantime::parse_duration)]
+        refresh: Duration,
+    },
+    /// Serve the database to remote clients and collect agent reports,
+    /// over plain HTTP: put it behind a TLS terminating proxy and never
+    /// let it face the network directly
+    Server {
+        /// Address to listen on
+        #[arg(long, default_value = "127.0.0.1:9613")]
+        listen: String,
+        /// Only accept reports signed with the key in FILE
+        #[arg(long, value_name = "FILE")]
+        sign_key_file: Option<PathBuf>,
+        /// Require clients to present this bearer token (without one,
+        /// clients may only read the database)
+        #[arg(long, env = "FIMBL_SERVER_TOKEN", hide_env_values = true)]
+        token: Option<String>,
+    },
+    /// Publish the tracked files as a signed baseline to s3://bucket/key,
+    /// an HTTP(S) URL or a file (a JSON manifest, if the name ends
+    /// .json)
+    Publish {
+        url: String,
+        /// Sign the baseline with the key in FILE
+        #[arg(long, value_name = "FILE")]
+        sign_key_file: PathBuf,
+    },
+    /// Print the root hash of the fingerprint records, or check it is
+    /// the one last anchored
+    RootHash {
+        /// Check against the last root hash anchored in FILE
+        #[arg(long, value_name = "FILE")]
+        check: Option<PathBuf>,
+    },
+    /// Start MACing the database's records with the record key given
+    /// (--record-key-file or --record-key-tpm), trusting those already
+    /// there as they are
+    EnrolRecordKey {},
+    /// Seal a new record key to the TPM at --record-key-tpm (to the
+    /// PCRs of --record-key-tpm-pcrs, as they are now) and enrol it, or
+    /// seal the key already enrolled, from a file
+    SealRecordKey {
+        /// Seal the key in FILE instead, without enrolling it (then
+        /// shred the file)
+        #[arg(long, value_name = "FILE")]
+        from: Option<PathBuf>,
+    },
+    /// Write a consistent, checksummed snapshot of the database
+    Backup { output: PathBuf },
+    /// Replace the database contents from a backup
+    Restore {
+        backup: PathBuf,
+        /// Overwrite a database which already has fingerprints
+        #[arg(long)]
+        force: bool,
+        /// Allow this over an append-only database
+        #[arg(long)]
+        force_unsafe: bool,
+    },
+    /// Generate configuration for other tools from the tracked files,
+    /// to stdout
+    Generate {
+        #[command(subcommand)]
+        artifact: Artifact,
+    },
+    /// Label the state of the baseline, to roll back to (e.g. after a
+    /// mistaken accept-all)
+    Snapshot {
+        #[command(subcommand)]
+        action: SnapshotAction,
+    },
 }
 
-/// Expand a symlink into chain of links and ultimate target
-fn symlink_reference_chain(path: &Path) -> Result<Vec<PathBuf>, FimblError> {
-    let mut chain = vec![];
-    let mut target: PathBuf = path.to_owned();
-    loop {
-        chain.push(target.clone());
-        let symlink = target.is_symlink();
+/// Which git hook to run as
+#[derive(Subcommand)]
+enum GitHook {
+    /// Check the files as staged to be committed, for a pre-commit hook
+    PreCommit {},
+    /// Check the files of each commit pushed, for a pre-receive hook in
+    /// a (bare) repository
+    PreReceive {
+        /// Working tree the files are tracked in, as a checkout of the
+        /// repository
+        #[arg(long, value_name = "DIR")]
+        worktree: PathBuf,
+    },
+}
 
-        if symlink {
-            target = read_link(target)?;
-        } else {
-            break;
-        }
-    }
+/// Configuration to generate
+#[derive(Subcommand)]
+enum Artifact {
+    /// auditd rules watching each tracked file for writes and
+    /// attribute changes
+    AuditRules {
+        /// Key to tag audit events with
+        #[arg(long, default_value = "fimbl")]
+        key: String,
+        /// Only watch files under PREFIX (may be repeated)
+        #[arg(long, value_name = "PREFIX")]
+        prefix: Vec<PathBuf>,
+        /// Only watch files tagged with TAG (may be repeated)
+        #[arg(long, value_name = "TAG")]
+        tag: Vec<String>,
+    },
+    /// A hardened systemd service verifying all files, and a timer to
+    /// start it, to install in /etc/systemd/system
+    Systemd {
+        /// When to verify: an interval (e.g. "6h") or a systemd
+        /// calendar event (e.g. "daily")
+        #[arg(long, default_value = "daily")]
+        interval: String,
+    },
+    /// A launchd daemon verifying all files, to install in
+    /// /Library/LaunchDaemons (on macOS)
+    Launchd {
+        /// When to verify: an interval (e.g. "6h"), hourly, daily,
+        /// weekly, monthly or a time of day (HH:MM)
+        #[arg(long, default_value = "daily")]
+        interval: String,
+        /// Log verifications to FILE
+        #[arg(long, value_name = "FILE", default_value = "/var/log/fimbl-verify.log")]
+        log_file: PathBuf,
+    },
+    /// A Windows scheduled task verifying all files as SYSTEM and
+    /// reporting to the Event Log, to register with
+    /// Register-ScheduledTask
+    ScheduledTask {
+        /// When to verify: an interval (e.g. "6h"), hourly, daily,
+        /// weekly, monthly or a time of day (HH:MM)
+        #[arg(long, default_value = "daily")]
+        interval: String,
+    },
+}
 
-    Ok(chain)
+/// What to do with snapshots of the baseline
+#[derive(Subcommand)]
+enum SnapshotAction {
+    /// Snapshot the current records of every file as NAME
+    Create { name: String },
+    /// Put every file's record back as it was in snapshot NAME
+    Rollback {
+        name: String,
+        /// Allow this in an append-only database (and log that it was
+        /// forced)
+        #[arg(long)]
+        force_unsafe: bool,
+    },
+    /// List the snapshots
+    List {},
+    /// Delete snapshot NAME
+    Delete { name: String },
 }
 
 /// Expand symlinks to include targets as well and filter out directories...
@@ -90,7 +1262,7 @@ fn preprocess_file_list(files: &Vec<PathBuf>) -> Result<(Vec<PathBuf>, Vec<PathB
     let mut directories = vec![];
 
     for file in files {
-        let mut chain = symlink_reference_chain(file)?;
+        let mut chain = fingerprint::symlink_chain(file)?;
         let target = chain.last().unwrap();
         if Path::is_dir(target) {
             directories.append(&mut chain);
@@ -101,6 +1273,54 @@ fn preprocess_file_list(files: &Vec<PathBuf>) -> Result<(Vec<PathBuf>, Vec<PathB
     Ok((files_and_symlinks, directories))
 }
 
+/// Files and directories given to verify under a root, as paths there
+///
+/// Paths are taken as they are there, relative ones from its top, with
+/// no symlink given standing for its target as well.
+fn rooted_file_list(
+    files: &[PathBuf],
+    fingerprinter: &Fingerprinter,
+) -> (Vec<PathBuf>, Vec<PathBuf>) {
+    files
+        .iter()
+        .map(|file| Path::new("/").join(file))
+        .partition(|file| {
+            let root = fingerprinter.root().unwrap();
+            !fingerprint::rooted(root, file, true).is_dir()
+        })
+}
+
+/// The path a file given is tracked by: as the path policy says or,
+/// under a root, absolute there
+fn tracked_path(
+    fingerprinter: &Fingerprinter,
+    database: &SystemDatabase,
+    file: &Path,
+) -> Result<PathBuf, FimblError> {
+    match fingerprinter.root() {
+        Some(_) => Ok(Path::new("/").join(file)),
+        None => record_path_within(database, fingerprinter, file),
+    }
+}
+
+/// Separate directories tracked as directory entries, to treat like
+/// files, from the rest
+fn tracked_directories(
+    dirs: Vec<PathBuf>,
+    database: &SystemDatabase,
+    fingerprinter: &Fingerprinter,
+) -> Result<(Vec<PathBuf>, Vec<PathBuf>), FimblError> {
+    let mut tracked = vec![];
+    let mut untracked = vec![];
+    for dir in dirs {
+        match database.recorded_fingerprint(&tracked_path(fingerprinter, database, &dir)?)? {
+            Some(recorded) if recorded.directory => tracked.push(dir),
+            _ => untracked.push(dir),
+        }
+    }
+    Ok((tracked, untracked))
+}
+
 /// If dirs is non-empty, return an error
 fn reject_directories(dirs: &[PathBuf]) -> Vec<ReportItem> {
     dirs.iter()
@@ -108,163 +1328,3124 @@ fn reject_directories(dirs: &[PathBuf]) -> Vec<ReportItem> {
         .collect()
 }
 
-/// Fingerprint files and add to database
+/// How the directories given to add are treated (by default, refused)
+#[derive(Clone, Copy, Default)]
+struct AddDirs {
+    /// Tracked as entries in their own right
+    entries: bool,
+    /// Walked for the files under them
+    recursive: bool,
+    /// Walked no further than their own file systems
+    one_file_system: bool,
+}
+
+/// Fingerprint files, and those of any presets (and, if recursive,
+/// those under the directories given), and add to database
 fn add(
-    files: &Vec<PathBuf>,
+    files: &[PathBuf],
+    presets: &[Preset],
     database: &mut SystemDatabase,
+    fingerprinter: &mut Fingerprinter,
     tolerate_existing: bool,
+    dirs: AddDirs,
+    tags: &[String],
 ) -> Result<Vec<ReportItem>, FimblError> {
-    let (files, dirs) = preprocess_file_list(files)?;
-    let mut reports = reject_directories(&dirs);
+    let mut reports = vec![];
+    let mut files = files.to_vec();
+    for file in presets::expand(presets) {
+        // presets include files only root can read
+        match presets::readable(&file) {
+            true => files.push(file),
+            false => reports.push(ReportItem::PresetFileUnreadable { path: file }),
+        }
+    }
 
-    for file in files {
-        let file = canonicalize(&file)?;
+    let (mut files, given_dirs) = preprocess_file_list(&files)?;
+    if dirs.recursive {
+        for dir in &given_dirs {
+            files.extend(fingerprint::files_under(dir, dirs.one_file_system)?);
+        }
+    }
+    match (dirs.entries, dirs.recursive) {
+        (true, _) => files.extend(given_dirs),
+        (false, true) => {}
+        (false, false) => reports.extend(reject_directories(&given_dirs)),
+    }
 
-        match Fingerprint::from_file(&file) {
-            Ok(fingerprint) => {
+    for given in files {
+        let resolved = record_path_within(database, fingerprinter, &given);
+        let file = match resolve_timeout(&given, resolved)? {
+            Ok(file) => file,
+            Err(report) => {
+                reports.push(report);
+                continue;
+            }
+        };
+        let _file = debug_span!("file", path = %file.display()).entered();
+        let (report, read) = network_fs_report(fingerprinter, &file);
+        reports.extend(report);
+        if !read {
+            continue;
+        }
+
+        match fingerprinter.fingerprint(&file) {
+            Ok(mut fingerprint) => {
+                fingerprint.tags = tags.to_vec();
                 let mut file_reports =
                     database.store_new_file(&file, &fingerprint, tolerate_existing)?;
+                keep_alias(database, &given, &file)?;
                 reports.append(&mut file_reports);
             }
-            Err(e) => {
-                panic!("Cannot add {}: {}", file.to_string_lossy(), e);
-            }
+            Err(e) => reports.push(unreadable(&file, e)?),
         }
     }
 
     Ok(reports)
 }
 
-/// List all the files currently in the database to stdout
-fn list(database: &SystemDatabase, verbose: bool) -> Result<Vec<ReportItem>, FimblError> {
-    if verbose {
-        println!("Fimbl DB is at {}", database.path().display());
-        println!("Files tracked:\n");
+/// Add the files another integrity checker's baseline lists, with the
+/// attributes it recorded, reporting those whose contents no longer
+/// match it, or the files a Tripwire policy takes in (tagged for the
+/// rules taking them in)
+fn import(
+    format: ImportFormat,
+    baseline: &Path,
+    tags: &[String],
+    one_file_system: bool,
+    database: &mut SystemDatabase,
+    fingerprinter: &mut Fingerprinter,
+    tolerate_existing: bool,
+) -> Result<Vec<ReportItem>, FimblError> {
+    if format == ImportFormat::Twpol {
+        let (files, mut reports) = import::policy_files(baseline, one_file_system)?;
+        for file in files {
+            reports.extend(add(
+                &[file.path],
+                &[],
+                database,
+                fingerprinter,
+                tolerate_existing,
+                AddDirs::default(),
+                &[tags, &file.tags].concat(),
+            )?);
+        }
+        return Ok(reports);
     }
+    let entries = import::read_entries(format, baseline)?;
+    let mut reports = vec![];
+    for entry in entries.iter().filter(|entry| !entry.is_directory()) {
+        // recorded by the path add would record it by
+        let given = &entry.path;
+        let resolved = record_path_within(database, fingerprinter, given);
+        let file = match resolve_timeout(given, resolved) {
+            Ok(Ok(file)) => file,
+            Ok(Err(report)) => {
+                reports.push(report);
+                continue;
+            }
+            Err(FimblError::FileAccessError(e)) if e.kind() == ErrorKind::NotFound => {
+                reports.push(ReportItem::FileMissing {
+                    path: given.clone(),
+                });
+                continue;
+            }
+            Err(e) => {
+                reports.push(unreadable(given, e)?);
+                continue;
+            }
+        };
+        let _file = debug_span!("file", path = %file.display()).entered();
+        let (report, read) = network_fs_report(fingerprinter, &file);
+        reports.extend(report);
+        if !read {
+            continue;
+        }
+        match symlink_metadata(&file) {
+            Ok(metadata) if metadata.is_dir() => continue,
+            // there are no contents of a symlink to check
+            Ok(metadata) if metadata.is_symlink() => {
+                reports.push(ReportItem::ImportUnverifiable { path: file });
+                continue;
+            }
+            Ok(_) => {}
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                reports.push(ReportItem::FileMissing { path: file });
+                continue;
+            }
+            Err(e) => {
+                reports.push(unreadable(&file, e.into())?);
+                continue;
+            }
+        }
+        let Some(algorithm) = entry.strongest_digest() else {
+            reports.push(ReportItem::ImportUnverifiable { path: file });
+            continue;
+        };
 
-    for (path, _fingerprint) in database.list_fingerprint_assertions()? {
-        println!("{}", path.display());
+        // the contents checked are those fingerprinted, in one read
+        match fingerprinter.fingerprint_digested(&file, algorithm) {
+            Ok(fingerprint) => {
+                if let Some(report) = entry.changed_contents(&fingerprint) {
+                    reports.push(report);
+                    continue;
+                }
+                let mut fingerprint = entry.as_recorded(fingerprint);
+                fingerprint.tags = tags.to_vec();
+                reports.extend(database.store_new_file(&file, &fingerprint, tolerate_existing)?);
+                keep_alias(database, given, &file)?;
+            }
+            Err(e) => reports.push(unreadable(&file, e)?),
+        }
     }
-
-    Ok(vec![])
+    Ok(reports)
 }
 
-/// Remove files from database (by marking as gone)
-fn remove(
-    files: &Vec<PathBuf>,
+/// Add the files of the git working tree containing repo that are
+/// there (and not directories, as submodules are)
+fn git_add(
+    repo: &Path,
     database: &mut SystemDatabase,
-    tolerate_untracked: bool,
+    fingerprinter: &mut Fingerprinter,
+    tolerate_existing: bool,
+    tags: &[String],
 ) -> Result<Vec<ReportItem>, FimblError> {
-    let (files, dirs) = preprocess_file_list(files)?;
-    let mut reports = reject_directories(&dirs);
-
-    for file in files {
-        let file = canonicalize(&file)?;
+    let files: Vec<PathBuf> = WorkingTree::discover(repo)?
+        .files()?
+        .into_iter()
+        .filter(|file| symlink_metadata(file).is_ok_and(|m| !m.is_dir()))
+        .collect();
+    add(
+        &files,
+        &[],
+        database,
+        fingerprinter,
+        tolerate_existing,
+        AddDirs::default(),
+        tags,
+    )
+}
 
-        let mut file_reports = database.remove_existing_file(&file, tolerate_untracked)?;
-        reports.append(&mut file_reports);
+/// Verify the files tracked in the git working tree containing repo,
+/// and report those git lists that aren't tracked, counting the files
+/// examined, and optionally those that differ from the commit at HEAD
+fn git_verify(
+    repo: &Path,
+    against_head: bool,
+    database: &mut SystemDatabase,
+    fingerprinter: &mut Fingerprinter,
+    fast: bool,
+    examined: &mut u64,
+) -> Result<Vec<ReportItem>, FimblError> {
+    let tree = WorkingTree::discover(repo)?;
+    let files: Vec<PathBuf> = tree
+        .files()?
+        .into_iter()
+        .filter(|file| symlink_metadata(file).is_ok_and(|m| !m.is_dir()))
+        .collect();
+    let prefixes = [tree.top().to_path_buf()];
+    let mut reports = verify_all(
+        database,
+        fingerprinter,
+        fast,
+        &prefixes,
+        &[],
+        None,
+        examined,
+    )?;
+    for file in &files {
+        if database.recorded_fingerprint(file)?.is_none() {
+            reports.push(ReportItem::FileNotTracked { path: file.clone() });
+        }
+    }
+    if against_head {
+        reports.extend(tree.differences_from_head(&files)?);
     }
-
     Ok(reports)
 }
 
-/// Verify the specified files match fingerprints in the database
-fn verify(
-    files: &Vec<PathBuf>,
-    database: &mut SystemDatabase,
+/// Check the versions of tracked files in the working tree that git has
+/// staged (or, for a pre-receive hook, has been pushed in each commit)
+/// against their records, counting the files examined
+///
+/// Only contents are compared, and only those of regular files: git
+/// keeps no owners or times, and keeps a symlink's target rather than
+/// its contents.
+fn git_hook(
+    hook: &GitHook,
+    database: &SystemDatabase,
+    fingerprinter: &mut Fingerprinter,
+    examined: &mut u64,
 ) -> Result<Vec<ReportItem>, FimblError> {
-    let (files, dirs) = preprocess_file_list(files)?;
-    let mut reports = reject_directories(&dirs);
-
-    for file in files {
-        let file = canonicalize(&file)?;
+    let (repo, worktree, revisions) = match hook {
+        GitHook::PreCommit {} => {
+            let top = WorkingTree::discover(Path::new("."))?.top().to_path_buf();
+            (top.clone(), top, vec![String::new()])
+        }
+        GitHook::PreReceive { worktree } => {
+            let refs = git::pushed_refs(std::io::stdin().lock())?;
+            let repo = PathBuf::from(".");
+            let commits = git::pushed_commits(&repo, &refs)?;
+            (repo, canonicalize(worktree)?, commits)
+        }
+    };
 
-        match Fingerprint::from_file(&file) {
-            Ok(fingerprint) => {
-                let mut file_reports = database.verify(&file, &fingerprint)?;
-                reports.append(&mut file_reports);
-            }
-            Err(e) => {
-                panic!("Cannot verify {}: {}", file.to_string_lossy(), e);
-            }
+    let mut guarded = vec![];
+    for item in database.iter_assertions_under(&worktree) {
+        let (path, recorded) = item?;
+        let name = path
+            .strip_prefix(&worktree)
+            .map(|relative| relative.to_string_lossy().replace('\\', "/"))
+            .unwrap_or_default();
+        if !recorded.symlink && !recorded.directory && !name.is_empty() && !name.contains('\n') {
+            guarded.push((path, name, recorded));
         }
     }
 
+    let mut reports = vec![];
+    for revision in revisions {
+        let objects: Vec<String> = guarded
+            .iter()
+            .map(|(_, name, _)| format!("{revision}:{name}"))
+            .collect();
+        for ((path, _, recorded), blob) in guarded.iter().zip(git::blobs(&repo, &objects)?) {
+            *examined += 1;
+            let Some(contents) = blob else {
+                reports.push(ReportItem::FileMissing { path: path.clone() });
+                continue;
+            };
+            let file = tempfile::NamedTempFile::new()?;
+            std::fs::write(file.path(), &contents)?;
+            let current = fingerprinter.fingerprint_like(file.path(), recorded)?;
+            reports.extend(compare_contents(path, recorded, &current));
+        }
+    }
     Ok(reports)
 }
 
-/// Verify all files that are current in the database
-fn verify_all(database: &mut SystemDatabase) -> Result<Vec<ReportItem>, FimblError> {
-    let mut reports = vec![];
+/// List all the files currently in the database (with any of the tags
+/// given) to stdout
+fn list(
+    database: &SystemDatabase,
+    verbose: bool,
+    tags: &[String],
+    long: bool,
+) -> Result<Vec<ReportItem>, FimblError> {
+    if verbose {
+        println!("Fimbl DB is at {}", database.path().display());
+        println!("Files tracked:\n");
+    }
 
-    for (file, _) in database.list_fingerprint_assertions()? {
-        match Fingerprint::from_file(&file) {
-            Ok(fingerprint) => {
-                let mut file_reports = database.verify(&file, &fingerprint)?;
-                reports.append(&mut file_reports);
-            }
-            Err(e) => {
-                panic!("Cannot verify {}: {}", file.to_string_lossy(), e);
-            }
+    for item in database.iter_assertions() {
+        let (path, fingerprint) = item?;
+        if !fingerprint.tagged(tags) {
+            continue;
+        }
+        let mut line = path.display().to_string();
+        if long && !fingerprint.tags.is_empty() {
+            line.push_str(&format!(" [{}]", fingerprint.tags.join(", ")));
+        }
+        if let (true, Some(note)) = (long, &fingerprint.note) {
+            line.push_str(&format!(": {}", note));
         }
+        println!("{}", line);
     }
 
-    Ok(reports)
+    Ok(vec![])
 }
 
-/// Accept modifications to the specified files
-fn accept(
-    files: &Vec<PathBuf>,
-    database: &mut SystemDatabase,
-    tolerate_untracked: bool,
+/// Time hashing with each algorithm and way of reading, printing the
+/// rates and what they suggest for this database
+fn bench(
+    cli: &CliArgs,
+    size: u64,
+    algorithms: &[BenchAlgorithm],
+    database: &SystemDatabase,
 ) -> Result<Vec<ReportItem>, FimblError> {
-    let (files, dirs) = preprocess_file_list(files)?;
-    let mut reports = reject_directories(&dirs);
+    let key = cli
+        .key_file
+        .as_deref()
+        .map(HashKey::from_file)
+        .transpose()?;
+    let configured = match key {
+        Some(_) => BenchAlgorithm::HmacSha3_256,
+        None => BenchAlgorithm::Sha3_256,
+    };
+    let measurements = bench::run(&std::env::temp_dir(), size, algorithms, key)?;
+    for measurement in &measurements {
+        println!("{measurement}");
+    }
 
-    for file in files {
-        let file = canonicalize(&file)?;
-        match Fingerprint::from_file(&file) {
-            Ok(fingerprint) => {
-                let mut file_reports =
-                    database.update_existing_file(&file, &fingerprint, tolerate_untracked)?;
-                reports.append(&mut file_reports);
-            }
-            Err(e) => {
-                panic!("Cannot add {}: {}", file.to_string_lossy(), e);
-            }
+    let mut tracked_bytes = 0;
+    for item in database.iter_assertions() {
+        tracked_bytes += item?.1.size.unwrap_or(0);
+    }
+    if let Some(advice) = bench::recommend(&measurements, configured, tracked_bytes) {
+        println!("\n{advice}");
+    }
+    Ok(vec![])
+}
+
+/// List groups of tracked files with the same contents to stdout,
+/// largest first
+fn dupes(database: &SystemDatabase) -> Result<Vec<ReportItem>, FimblError> {
+    for group in database.duplicates()? {
+        let size = match group.size {
+            Some(size) => format!("{} each", report::scaled(size as f64)),
+            None => "size unknown".to_string(),
+        };
+        println!(
+            "{} files, {}, content hash {}:",
+            group.paths.len(),
+            size,
+            hex::encode(group.content_hash)
+        );
+        for path in group.paths {
+            println!("  {}", path.display());
         }
     }
 
-    Ok(reports)
+    Ok(vec![])
 }
 
-fn report(report_items: Vec<ReportItem>) {
-    for item in report_items {
-        println!("- {item}")
+/// List files with (or that had) the given content hash to stdout,
+/// with when each was recorded and, if no longer, replaced
+fn lookup(
+    database: &SystemDatabase,
+    content_hash: &fingerprint::HashValue,
+) -> Result<Vec<ReportItem>, FimblError> {
+    for (path, recorded, replaced) in database.lookup_hash(content_hash)? {
+        let until = match replaced {
+            Some(time) => format!(", until {}", humantime::format_rfc3339_seconds(time)),
+            None => String::new(),
+        };
+        println!(
+            "{} (recorded {}{})",
+            path.display(),
+            humantime::format_rfc3339_seconds(recorded),
+            until
+        );
     }
+
+    Ok(vec![])
 }
 
-fn main() {
-    let cli = CliArgs::parse();
+/// Content hash, size and note of a recorded fingerprint, for display
+fn describe(fingerprint: &fingerprint::Fingerprint) -> String {
+    let size = fingerprint
+        .size
+        .map(|size| format!(" ({})", report::scaled(size as f64)))
+        .unwrap_or_default();
+    let note = fingerprint
+        .note
+        .as_ref()
+        .map(|note| format!(": {note}"))
+        .unwrap_or_default();
+    format!("{}{size}{note}", hex::encode(fingerprint.content_hash))
+}
 
-    let default_db = if let Some(path) = dirs::home_dir() {
-        path.join(".config/fimbl/db")
-    } else {
-        panic!("No HOME directory")
-    };
+/// List the records of a file to stdout, oldest first
+fn history(file: &Path, database: &SystemDatabase) -> Result<Vec<ReportItem>, FimblError> {
+    let file = record_path(database, file)?;
+    let records = database.attributed_history(&file)?;
+    if records.is_empty() {
+        return Ok(vec![ReportItem::FileNotTracked { path: file }]);
+    }
 
-    let db_path = cli.database().unwrap_or(&*default_db);
+    for (time, fingerprint, entry) in records {
+        let time = humantime::format_rfc3339_seconds(time);
+        match fingerprint {
+            Some(fingerprint) => println!("{time} recorded {}", describe(&fingerprint)),
+            None => println!("{time} removed"),
+        }
+        if let Some(entry) = entry {
+            println!("  {}", entry.attribution());
+        }
+    }
 
-    let mut database = SystemDatabase::open(db_path).unwrap();
+    Ok(vec![])
+}
+
+/// List the log entries matching the filters given to stdout, oldest
+/// first
+fn log(
+    since: Option<SystemTime>,
+    kinds: &[String],
+    path: Option<&Path>,
+    database: &SystemDatabase,
+) -> Result<Vec<ReportItem>, FimblError> {
+    let path = path.map(|path| record_path(database, path)).transpose()?;
+    let entries = database.iter_log_since(since.unwrap_or(SystemTime::UNIX_EPOCH));
+    for entry in entries {
+        let entry = entry?;
+        let kind = entry.event.kind();
+        if !kinds.is_empty() && !kinds.iter().any(|k| k == kind) {
+            continue;
+        }
+        if path.as_ref().is_some_and(|p| !entry.path.starts_with(p)) {
+            continue;
+        }
+        println!(
+            "{} {} {}",
+            humantime::format_rfc3339_seconds(entry.time),
+            entry.path.display(),
+            entry.attribution()
+        );
+    }
+    Ok(vec![])
+}
+
+/// Show when, by which command and by whom the current record of a
+/// file was made, and the record it replaced, on stdout
+fn blame(file: &Path, database: &SystemDatabase) -> Result<Vec<ReportItem>, FimblError> {
+    let file = record_path(database, file)?;
+    let mut records = database.history(&file)?;
+    let Some((time, Some(current))) = records.pop() else {
+        return Ok(vec![ReportItem::FileNotTracked { path: file }]);
+    };
+
+    let how = match database.recorded_by(&file)? {
+        Some(entry) => format!(" {}", entry.attribution()),
+        None => " (not logged)".to_string(),
+    };
+    println!("{}", file.display());
+    println!(
+        "  recorded {}{how}",
+        humantime::format_rfc3339_seconds(time)
+    );
+    println!("  contents {}", describe(&current));
+    match records.pop() {
+        Some((time, Some(previous))) => println!(
+            "  replaced record of {}: {}",
+            humantime::format_rfc3339_seconds(time),
+            describe(&previous)
+        ),
+        Some((time, None)) => println!(
+            "  replaced removal of {}",
+            humantime::format_rfc3339_seconds(time)
+        ),
+        None => println!("  no previous record kept"),
+    }
+
+    Ok(vec![])
+}
+
+/// Set policies for the database
+fn init(
+    append_only: bool,
+    two_phase_accept: bool,
+    approval_key_file: Option<&Path>,
+    unicode_paths: Option<UnicodePaths>,
+    database: &mut SystemDatabase,
+) -> Result<Vec<ReportItem>, FimblError> {
+    if let Some(form) = unicode_paths {
+        database.set_unicode_paths(form)?;
+    }
+    if append_only {
+        database.require_append_only()?;
+    }
+    if two_phase_accept {
+        let key = approval_key_file.map(SigningKey::from_file).transpose()?;
+        database.require_approval(key.as_ref())?;
+    }
+    Ok(vec![])
+}
+
+/// Record a change pending approval, running the accept hook for it
+fn approve(
+    token: &str,
+    key_file: Option<&Path>,
+    database: &mut SystemDatabase,
+    hooks: &Hooks,
+) -> Result<Vec<ReportItem>, FimblError> {
+    let key = key_file.map(SigningKey::from_file).transpose()?;
+    match database.approve(token, key.as_ref())? {
+        (_, Some(would)) => Ok(vec![would]),
+        (path, None) => Ok(Vec::from_iter(hooks.accepted(&path))),
+    }
+}
+
+/// Make the changes a plan lists, checking first that every record
+/// is as it was when the plan was made, running the accept hook for
+/// each change accepted
+fn apply(
+    plan: &Path,
+    key_file: &Path,
+    database: &mut SystemDatabase,
+    hooks: &Hooks,
+) -> Result<Vec<ReportItem>, FimblError> {
+    let key = SigningKey::from_file(key_file)?;
+    let plan = Plan::from_json(&std::fs::read(plan)?, &key)?;
+    plan.check(|path| database.recorded_fingerprint(path))?;
+
+    let mut reports = vec![];
+    for change in plan.changes {
+        match change {
+            Change::Track { path, fingerprint } => {
+                reports.extend(database.store_new_file(&path, &fingerprint, false)?);
+            }
+            Change::Accept {
+                path, fingerprint, ..
+            } => {
+                let file_reports = database.update_existing_file(&path, &fingerprint, false)?;
+                if file_reports.is_empty() {
+                    reports.extend(hooks.accepted(&path));
+                }
+                reports.extend(file_reports);
+            }
+            Change::Retract { path, .. } => {
+                reports.extend(database.remove_existing_file(&path, false)?);
+            }
+        }
+    }
+    Ok(reports)
+}
+
+/// Write the changes to the records made on a dry run to a plan
+fn write_plan(
+    output: &Path,
+    key: &SigningKey,
+    host: String,
+    database: &SystemDatabase,
+) -> Result<(), FimblError> {
+    let command = command_line(std::env::args_os().map(|arg| arg.to_string_lossy().into_owned()));
+    let plan = Plan::new(host, command, database.planned());
+    std::fs::write(output, plan.to_json(key))?;
+    Ok(())
+}
+
+/// List changes pending approval on stdout
+fn pending(database: &SystemDatabase) -> Result<Vec<ReportItem>, FimblError> {
+    for (token, change) in database.pending()? {
+        let metadata_only = match change.metadata_only {
+            true => " (metadata only)",
+            false => "",
+        };
+        println!(
+            "{token} {} {}@{} {}{metadata_only}",
+            humantime::format_rfc3339_seconds(change.requested),
+            change.user,
+            change.hostname,
+            change.path.display()
+        );
+    }
+    Ok(vec![])
+}
+
+/// Remove files from database (by marking as gone)
+fn remove(
+    files: &Vec<PathBuf>,
+    database: &mut SystemDatabase,
+    tolerate_untracked: bool,
+) -> Result<Vec<ReportItem>, FimblError> {
+    let (files, dirs) = preprocess_file_list(files)?;
+    let mut reports = reject_directories(&dirs);
+
+    for given in files {
+        let file = record_path(database, &given)?;
+        let _file = debug_span!("file", path = %file.display()).entered();
+
+        let mut file_reports = database.remove_existing_file(&file, tolerate_untracked)?;
+        if database.path_policy() == PathPolicy::Aliased {
+            database.remove_alias(&absolute_path(&given)?)?;
+        }
+        reports.append(&mut file_reports);
+    }
+
+    Ok(reports)
+}
+
+/// Canonical path of a file, which may no longer exist or may be a
+/// symlink: that of its directory, with its own name, so that a
+/// symlink is taken as itself rather than its target
+fn canonical_path(file: &Path) -> Result<PathBuf, FimblError> {
+    let file = std::path::absolute(file)?;
+    match (file.parent(), file.file_name()) {
+        (Some(parent), Some(name)) => Ok(canonicalize(parent)?.join(name)),
+        _ => Ok(canonicalize(file)?),
+    }
+}
+
+/// Absolute path of a file given, `.` and `..` taken by name rather
+/// than by following symlinks
+fn absolute_path(file: &Path) -> Result<PathBuf, FimblError> {
+    let mut path = PathBuf::new();
+    for component in std::path::absolute(file)?.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                path.pop();
+            }
+            component => path.push(component),
+        }
+    }
+    Ok(path)
+}
+
+/// The path a file given is recorded by, as the path policy says: its
+/// canonical path, its path as given, or (if it is an alias) the path
+/// it stands for
+///
+/// The file need not exist any more.
+fn record_path(database: &SystemDatabase, file: &Path) -> Result<PathBuf, FimblError> {
+    record_path_by(database, file, canonical_path)
+}
+
+/// The path a file given is recorded by, as above, resolved within the
+/// file's time limit: resolving it is the first touch of its file
+/// system, so blocks on a hung mount as reading it would
+fn record_path_within(
+    database: &SystemDatabase,
+    fingerprinter: &Fingerprinter,
+    file: &Path,
+) -> Result<PathBuf, FimblError> {
+    record_path_by(database, file, |file| {
+        let given = file.to_path_buf();
+        fingerprinter.within_time_limit(file, "resolving path", move || canonical_path(&given))
+    })
+}
+
+/// The path a file given is recorded by, resolved to its canonical
+/// path as given
+fn record_path_by(
+    database: &SystemDatabase,
+    file: &Path,
+    canonical_path: impl FnOnce(&Path) -> Result<PathBuf, FimblError>,
+) -> Result<PathBuf, FimblError> {
+    match database.path_policy() {
+        PathPolicy::Canonical => canonical_path(file),
+        PathPolicy::AsGiven => absolute_path(file),
+        PathPolicy::Aliased => match database.alias(&absolute_path(file)?)? {
+            Some(path) => Ok(path),
+            None => canonical_path(file),
+        },
+    }
+}
+
+/// Report a file whose path could not be resolved within its time
+/// limit, passing on any other error
+fn resolve_timeout(
+    file: &Path,
+    resolved: Result<PathBuf, FimblError>,
+) -> Result<Result<PathBuf, ReportItem>, FimblError> {
+    match resolved {
+        Err(FimblError::FileAccessError(e)) if e.kind() == ErrorKind::TimedOut => {
+            Ok(Err(ReportItem::FileReadTimeout {
+                path: file.to_path_buf(),
+            }))
+        }
+        resolved => resolved.map(Ok),
+    }
+}
+
+/// Keep the path a file was given by as an alias of the path it is
+/// recorded by, if they differ and the path policy keeps aliases
+fn keep_alias(database: &SystemDatabase, file: &Path, path: &Path) -> Result<(), FimblError> {
+    let given = absolute_path(file)?;
+    match database.path_policy() == PathPolicy::Aliased && given != path {
+        true => database.record_alias(&given, path),
+        false => Ok(()),
+    }
+}
+
+/// Report a file given that is an alias, if it now leads to another
+/// file than the one it stands for (unless under a root, where aliases
+/// aren't kept)
+fn alias_redirected(
+    database: &SystemDatabase,
+    fingerprinter: &Fingerprinter,
+    file: &Path,
+) -> Result<Option<ReportItem>, FimblError> {
+    if database.path_policy() != PathPolicy::Aliased || fingerprinter.root().is_some() {
+        return Ok(None);
+    }
+    let alias = absolute_path(file)?;
+    let Some(recorded) = database.alias(&alias)? else {
+        return Ok(None);
+    };
+    let target = canonical_path(&alias)?;
+    Ok((target != recorded).then_some(ReportItem::AliasRedirected {
+        path: alias,
+        recorded,
+        target,
+    }))
+}
+
+/// Move the record of a tracked file to its new path, checking the
+/// file there has the recorded contents
+fn rename(
+    from: &Path,
+    to: &Path,
+    database: &mut SystemDatabase,
+    fingerprinter: &mut Fingerprinter,
+) -> Result<Vec<ReportItem>, FimblError> {
+    let from = record_path(database, from)?;
+    let to = record_path(database, to)?;
+    let Some(recorded) = database.recorded_fingerprint(&from)? else {
+        return Ok(vec![ReportItem::FileNotTracked { path: from }]);
+    };
+    match fingerprinter.fingerprint_like(&to, &recorded) {
+        Ok(current) => database.rename_file(&from, &to, &current),
+        Err(e) => Ok(vec![unreadable(&to, e)?]),
+    }
+}
+
+/// Set or clear the note on a tracked file
+fn annotate(
+    file: &Path,
+    note: Option<&str>,
+    database: &mut SystemDatabase,
+) -> Result<Vec<ReportItem>, FimblError> {
+    database.annotate(&record_path(database, file)?, note)
+}
+
+/// Report on a file on a network or virtual file system, if the
+/// policy calls for it, and say whether to read the file at all
+///
+/// Nothing on the file system is touched, so a hung mount can be
+/// skipped.
+fn network_fs_report(fingerprinter: &Fingerprinter, file: &Path) -> (Option<ReportItem>, bool) {
+    let path = file.to_path_buf();
+    match fingerprinter.network_fs(file) {
+        Some((NetworkFs::Warn, file_system)) => {
+            let file_system = file_system.to_string();
+            (
+                Some(ReportItem::NetworkFileSystem { path, file_system }),
+                true,
+            )
+        }
+        Some((NetworkFs::Skip, file_system)) => {
+            let file_system = file_system.to_string();
+            (
+                Some(ReportItem::NetworkFileSkipped { path, file_system }),
+                false,
+            )
+        }
+        _ => (None, true),
+    }
+}
+
+/// Report a file that could not be read within its time limit, that
+/// changed while it was read, whose reads disagree or that could not
+/// be fingerprinted for any other reason
+///
+/// Errors other than those reading the file (the database's, say) are
+/// passed on.
+fn unreadable(file: &Path, error: FimblError) -> Result<ReportItem, FimblError> {
+    let path = file.to_path_buf();
+    match error {
+        FimblError::FileAccessError(e) if e.kind() == ErrorKind::TimedOut => {
+            Ok(ReportItem::FileReadTimeout { path })
+        }
+        FimblError::FileAccessError(e) if ChangedDuringRead::is(&e) => {
+            Ok(ReportItem::FileChangedDuringRead { path })
+        }
+        FimblError::FileAccessError(e) if ReadsDisagree::is(&e) => {
+            Ok(ReportItem::ReadsDisagree { path })
+        }
+        FimblError::FileAccessError(e) => Ok(ReportItem::FileUnreadable {
+            path,
+            error: e.to_string(),
+        }),
+        e => Err(e),
+    }
+}
+
+/// Verify a single file against the database, unless the network
+/// file system policy says to skip it or its record has been tampered
+/// with
+///
+/// Any note on a file found to have changed follows the report.
+fn verify_file(
+    file: &Path,
+    database: &SystemDatabase,
+    fingerprinter: &mut Fingerprinter,
+    fast: bool,
+) -> Result<Vec<ReportItem>, FimblError> {
+    let _file = debug_span!("file", path = %file.display()).entered();
+    let tampered = database.check_record(file)?;
+    if !tampered.is_empty() {
+        return Ok(tampered);
+    }
+
+    let (report, read) = network_fs_report(fingerprinter, file);
+    let mut reports = Vec::from_iter(report);
+    if read {
+        reports.extend(verify_file_contents(file, database, fingerprinter, fast)?);
+    }
+    if reports
+        .iter()
+        .any(|item| item.severity() >= Severity::Warning)
+    {
+        if let Some(note) = database.recorded_fingerprint(file)?.and_then(|f| f.note) {
+            let path = file.to_path_buf();
+            reports.push(ReportItem::FileNote { path, note });
+        }
+    }
+    Ok(reports)
+}
+
+/// Verify a single file against the database
+///
+/// In fast mode, a change in size is reported without hashing, as is
+/// any change at all to a file whose device, inode, size and
+/// modification time are unchanged since it was last verified
+/// successfully.
+fn verify_file_contents(
+    file: &Path,
+    database: &SystemDatabase,
+    fingerprinter: &mut Fingerprinter,
+    fast: bool,
+) -> Result<Vec<ReportItem>, FimblError> {
+    let metadata = match fingerprinter.metadata(file) {
+        Ok(metadata) => Some(metadata),
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            return Ok(vec![ReportItem::FileMissing {
+                path: file.to_path_buf(),
+            }]);
+        }
+        Err(e) if e.kind() == ErrorKind::TimedOut => {
+            return Ok(vec![ReportItem::FileReadTimeout {
+                path: file.to_path_buf(),
+            }]);
+        }
+        Err(_) => None,
+    };
+
+    if let (true, Some(metadata)) = (fast, metadata) {
+        let reports = database.verify_size(file, metadata.len())?;
+        if !reports.is_empty() {
+            return Ok(reports);
+        }
+    }
+
+    let fingerprint = match database.recorded_fingerprint(file)? {
+        Some(recorded) => {
+            let cached = match fast {
+                true => database.cached_key(file, &recorded)?,
+                false => None,
+            };
+            match cached {
+                Some(cached) => fingerprinter.fingerprint_cached(file, &recorded, &cached),
+                None => fingerprinter.fingerprint_like(file, &recorded),
+            }
+        }
+        None => fingerprinter.fingerprint(file),
+    };
+    match fingerprint {
+        Ok(fingerprint) => {
+            let reports = database.verify(file, &fingerprint)?;
+            if reports.is_empty() {
+                database.cache_hash(file, &fingerprint)?;
+            }
+            Ok(reports)
+        }
+        Err(e) => Ok(vec![unreadable(file, e)?]),
+    }
+}
+
+/// Verify the specified files match fingerprints in the database,
+/// counting the files examined
+fn verify(
+    files: &Vec<PathBuf>,
+    database: &mut SystemDatabase,
+    fingerprinter: &mut Fingerprinter,
+    fast: bool,
+    examined: &mut u64,
+) -> Result<Vec<ReportItem>, FimblError> {
+    let (mut files, dirs) = match fingerprinter.root() {
+        Some(_) => rooted_file_list(files, fingerprinter),
+        None => preprocess_file_list(files)?,
+    };
+    let (tracked, dirs) = tracked_directories(dirs, database, fingerprinter)?;
+    files.extend(tracked);
+    let mut reports = reject_directories(&dirs);
+
+    for file in files {
+        *examined += 1;
+        reports.extend(alias_redirected(database, fingerprinter, &file)?);
+        let file = match resolve_timeout(&file, tracked_path(fingerprinter, database, &file))? {
+            Ok(file) => file,
+            Err(report) => {
+                reports.push(report);
+                continue;
+            }
+        };
+        let mut file_reports = verify_file(&file, database, fingerprinter, fast)?;
+        reports.append(&mut file_reports);
+    }
+
+    Ok(reports)
+}
+
+/// Add findings across files (e.g. suspected mass encryption), then run
+/// the verify hooks, adding any failures to the reports
+fn conclude_verify(hooks: &Hooks, mut reports: Vec<ReportItem>) -> Vec<ReportItem> {
+    reports.extend(report::mass_encryption(&reports));
+    let failures = hooks.verified(&reports);
+    reports.extend(failures);
+    reports
+}
+
+/// Add a report of an anomalous volume of changes, judged against
+/// recent scans, to the reports of a scan, and note the scan's volume
+/// of changes for judging later ones
+///
+/// An anomalous scan is not noted, so that later scans finding the
+/// same changes (not yet accepted) are judged against normal scans.
+fn judge_change_volume(
+    database: &SystemDatabase,
+    mut reports: Vec<ReportItem>,
+    examined: u64,
+) -> Result<Vec<ReportItem>, FimblError> {
+    if examined == 0 {
+        return Ok(reports);
+    }
+    match report::anomalous_change_volume(&reports, examined, &database.change_volumes()?) {
+        Some(anomaly) => reports.push(anomaly),
+        None => database
+            .record_change_volume(report::changed_files(&reports) as f64 / examined as f64)?,
+    }
+    Ok(reports)
+}
+
+/// Verify all files that are current in the database, or only those
+/// under the prefixes given, counting the files examined
+///
+/// When sampling, the files sampled least recently (or never) are
+/// chosen, in random order among equals, so that repeated runs cover
+/// the whole database.
+fn verify_all(
+    database: &mut SystemDatabase,
+    fingerprinter: &mut Fingerprinter,
+    fast: bool,
+    prefixes: &[PathBuf],
+    tags: &[String],
+    sample: Option<Sample>,
+    examined: &mut u64,
+) -> Result<Vec<ReportItem>, FimblError> {
+    let mut reports = vec![];
+
+    let everything = prefixes.is_empty() && tags.is_empty() && sample.is_none();
+
+    // tracked paths are as recorded; prefixes under others are redundant
+    let prefixes: Vec<PathBuf> = prefixes
+        .iter()
+        .map(|prefix| {
+            tracked_path(fingerprinter, database, prefix).unwrap_or_else(|_| prefix.clone())
+        })
+        .collect();
+    let prefixes = outermost(prefixes);
+    let files: Box<dyn Iterator<Item = _>> = if prefixes.is_empty() {
+        Box::new(database.iter_assertions())
+    } else {
+        Box::new(
+            prefixes
+                .iter()
+                .flat_map(|prefix| database.iter_assertions_under(prefix)),
+        )
+    };
+    let files = files.filter(|item| match item {
+        Ok((_, fingerprint)) => fingerprint.tagged(tags),
+        Err(_) => true,
+    });
+
+    let files: Box<dyn Iterator<Item = _>> = match sample {
+        None => Box::new(files.map(|item| item.map(|(file, _)| file))),
+        Some(sample) => {
+            let random = RandomState::new();
+            let mut candidates = files
+                .map(|item| {
+                    let (file, _) = item?;
+                    let last = database.last_verified(&file)?;
+                    Ok((last, random.hash_one(&file), file))
+                })
+                .collect::<Result<Vec<_>, FimblError>>()?;
+            candidates.sort();
+            candidates.truncate(sample.size(candidates.len()));
+            Box::new(candidates.into_iter().map(|(_, _, file)| Ok(file)))
+        }
+    };
+
+    for file in files {
+        let file = file?;
+        *examined += 1;
+        let mut file_reports = verify_file(&file, database, fingerprinter, fast)?;
+        reports.append(&mut file_reports);
+        if sample.is_some() {
+            database.record_verified(&file, SystemTime::now())?;
+        }
+    }
+    if everything {
+        for (alias, _) in database.aliases()? {
+            reports.extend(alias_redirected(database, fingerprinter, &alias)?);
+        }
+    }
+
+    Ok(reports)
+}
+
+/// Replace the reports of a missing file, and of a file found to have
+/// its recorded contents, with a report that it has moved
+///
+/// Candidates are tracked files whose contents have changed and
+/// untracked files in the missing file's directory. Only those of the
+/// recorded size are hashed. A tracked file moved over is still
+/// reported as changed, its own contents being gone.
+fn find_moves(
+    database: &SystemDatabase,
+    fingerprinter: &mut Fingerprinter,
+    reports: Vec<ReportItem>,
+) -> Result<Vec<ReportItem>, FimblError> {
+    let changed: Vec<PathBuf> = reports
+        .iter()
+        .filter_map(|item| match item {
+            ReportItem::FileContentChanged { path, .. }
+            | ReportItem::FileSizeChanged { path, .. } => Some(path.clone()),
+            _ => None,
+        })
+        .collect();
+
+    let mut moves: Vec<(PathBuf, PathBuf)> = vec![];
+    for item in &reports {
+        let ReportItem::FileMissing { path: from } = item else {
+            continue;
+        };
+        let Some(recorded) = database.recorded_fingerprint(from)? else {
+            continue;
+        };
+        let beside = untracked_beside(database, fingerprinter, from)?;
+        for candidate in changed.iter().chain(&beside) {
+            let taken = moves.iter().any(|(_, to)| to == candidate);
+            let (_, read) = network_fs_report(fingerprinter, candidate);
+            if taken
+                || !read
+                || recorded.size.is_some_and(|size| {
+                    file_size(&fingerprinter.on_disk(candidate)).ok() != Some(size)
+                })
+            {
+                continue;
+            }
+            let current = fingerprinter.fingerprint_like(candidate, &recorded);
+            if current.is_ok_and(|current| recorded.same_contents(&current)) {
+                moves.push((from.clone(), candidate.clone()));
+                break;
+            }
+        }
+    }
+
+    let mut reports: Vec<ReportItem> = reports
+        .into_iter()
+        .filter(|item| match item {
+            ReportItem::FileMissing { path } => !moves.iter().any(|(from, _)| from == path),
+            _ => true,
+        })
+        .collect();
+    reports.extend(
+        moves
+            .into_iter()
+            .map(|(from, to)| ReportItem::FileMoved { from, to }),
+    );
+    Ok(reports)
+}
+
+/// Untracked regular files in the same directory as a file, passing
+/// over any entry that can't be read
+fn untracked_beside(
+    database: &SystemDatabase,
+    fingerprinter: &Fingerprinter,
+    file: &Path,
+) -> Result<Vec<PathBuf>, FimblError> {
+    let Some(parent) = file.parent() else {
+        return Ok(vec![]);
+    };
+    let Ok(entries) = std::fs::read_dir(fingerprinter.on_disk(parent)) else {
+        return Ok(vec![]);
+    };
+    let mut untracked = vec![];
+    for entry in entries.flatten() {
+        let path = parent.join(entry.file_name());
+        let is_file = entry.file_type().is_ok_and(|file_type| file_type.is_file());
+        if is_file && database.recorded_fingerprint(&path)?.is_none() {
+            untracked.push(path);
+        }
+    }
+    Ok(untracked)
+}
+
+/// Verify files against a published baseline rather than the database
+///
+/// With no files specified, every file in the baseline is verified.
+fn verify_baseline(
+    url: &str,
+    key_file: &Path,
+    files: &Vec<PathBuf>,
+    fingerprinter: &mut Fingerprinter,
+    fast: bool,
+    examined: &mut u64,
+) -> Result<Vec<ReportItem>, FimblError> {
+    let key = SigningKey::from_file(key_file)?;
+    let baseline = Baseline::from_bytes(&get_object(url)?, &key)?;
+    let mut database = SystemDatabase::from_baseline(url, &baseline)?;
+
+    if files.is_empty() {
+        verify_all(&mut database, fingerprinter, fast, &[], &[], None, examined)
+    } else {
+        verify(files, &mut database, fingerprinter, fast, examined)
+    }
+}
+
+/// Verify files against a signed manifest (or baseline) file, without a
+/// database, counting the files examined
+///
+/// With no files, every file in the manifest is verified; a directory
+/// given verifies every file in the manifest under it, and reports
+/// those in it that the manifest does not list. The manifest
+/// may come from another host, so only portable attributes are
+/// compared (see `Fingerprint::portable`), or only contents if asked
+/// (see `compare_contents`).
+fn verify_manifest(
+    manifest: &Path,
+    key_file: &Path,
+    files: &[PathBuf],
+    contents_only: bool,
+    fingerprinter: &mut Fingerprinter,
+    examined: &mut u64,
+) -> Result<Vec<ReportItem>, FimblError> {
+    let key = SigningKey::from_file(key_file)?;
+    let baseline = Baseline::read(&std::fs::read(manifest)?, &key)?;
+    let recorded: BTreeMap<&Path, &Fingerprint> = baseline
+        .files
+        .iter()
+        .map(|(path, fingerprint)| (path.as_path(), fingerprint))
+        .collect();
+
+    let mut selected: Vec<(PathBuf, Option<&Fingerprint>)> = vec![];
+    let mut reports = vec![];
+    match files.is_empty() {
+        true => selected.extend(
+            recorded
+                .iter()
+                .map(|(path, f)| (path.to_path_buf(), Some(*f))),
+        ),
+        false => {
+            for file in files {
+                let file = match fingerprinter.root() {
+                    Some(_) => Path::new("/").join(file),
+                    None => canonical_path(file)?,
+                };
+                if fingerprinter.on_disk(&file).is_dir() {
+                    // a symlink given to a directory stands for it
+                    let dir = match fingerprinter.root() {
+                        Some(_) => file,
+                        None => canonicalize(file)?,
+                    };
+                    let under = recorded.iter().filter(|(path, _)| path.starts_with(&dir));
+                    selected.extend(under.map(|(path, f)| (path.to_path_buf(), Some(*f))));
+                    reports.extend(not_in_manifest(&dir, &recorded, fingerprinter));
+                } else {
+                    let fingerprint = recorded.get(file.as_path()).copied();
+                    selected.push((file, fingerprint));
+                }
+            }
+        }
+    }
+
+    for (file, recorded) in selected {
+        *examined += 1;
+        let Some(recorded) = recorded else {
+            reports.push(ReportItem::FileNotTracked { path: file });
+            continue;
+        };
+        match symlink_metadata(fingerprinter.on_disk(&file)) {
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                reports.push(ReportItem::FileMissing { path: file });
+            }
+            _ => match fingerprinter.fingerprint_like(&file, recorded) {
+                Ok(current) if contents_only => {
+                    reports.extend(compare_contents(&file, recorded, &current))
+                }
+                Ok(current) => {
+                    reports.extend(compare_fingerprints(&file, &recorded.portable(), &current))
+                }
+                Err(e) => reports.push(unreadable(&file, e)?),
+            },
+        }
+    }
+    Ok(reports)
+}
+
+/// Report the files (and symlinks) under a directory that a manifest
+/// does not list, walking it without following symlinks, and any part
+/// of it that can't be read
+///
+/// Entries named `..*`, in which Kubernetes keeps the versions of a
+/// mounted volume, are passed over.
+fn not_in_manifest(
+    dir: &Path,
+    recorded: &BTreeMap<&Path, &Fingerprint>,
+    fingerprinter: &Fingerprinter,
+) -> Vec<ReportItem> {
+    let unreadable = |path: &Path, e: std::io::Error| ReportItem::FileUnreadable {
+        path: path.to_path_buf(),
+        error: e.to_string(),
+    };
+    let mut reports = vec![];
+    let mut dirs = vec![dir.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        let entries = match std::fs::read_dir(fingerprinter.on_disk(&dir)) {
+            Ok(entries) => entries,
+            Err(e) => {
+                reports.push(unreadable(&dir, e));
+                continue;
+            }
+        };
+        for entry in entries {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(e) => {
+                    reports.push(unreadable(&dir, e));
+                    continue;
+                }
+            };
+            // the hidden versions of a config map or secret volume that
+            // its visible symlinks lead into
+            if entry.file_name().to_string_lossy().starts_with("..") {
+                continue;
+            }
+            let path = dir.join(entry.file_name());
+            match entry.file_type() {
+                Ok(file_type) if file_type.is_dir() => dirs.push(path),
+                Ok(_) if recorded.contains_key(path.as_path()) => {}
+                Ok(_) => reports.push(ReportItem::FileNotInManifest { path }),
+                Err(e) => reports.push(unreadable(&path, e)),
+            }
+        }
+    }
+    reports
+}
+
+/// Verify the files of a container image against a signed baseline,
+/// without a database, counting the files examined and bytes hashed
+///
+/// The image is unpacked to a temporary directory and each file in the
+/// baseline (under the prefixes given, if any) is verified as it is in
+/// the image.
+fn verify_image(
+    image: &str,
+    url: &str,
+    key_file: &Path,
+    prefixes: &[PathBuf],
+    cli: &CliArgs,
+    examined: &mut u64,
+    bytes_hashed: &mut u64,
+) -> Result<Vec<ReportItem>, FimblError> {
+    let key = SigningKey::from_file(key_file)?;
+    let baseline = Baseline::read(&get_object(url)?, &key)?;
+    let image = ImageFiles::unpack(image)?;
+    let mut fingerprinter = cli
+        .fingerprinter()?
+        .with_root(Some(image.root().to_owned()));
+
+    let mut reports = vec![];
+    for (file, recorded) in &baseline.files {
+        if !prefixes.is_empty() && !prefixes.iter().any(|prefix| file.starts_with(prefix)) {
+            continue;
+        }
+        *examined += 1;
+        if image.attributes(file).is_none() {
+            reports.push(ReportItem::FileMissing { path: file.clone() });
+            continue;
+        }
+        match fingerprinter.fingerprint_like(file, recorded) {
+            Ok(current) => {
+                let current = image.as_in_image(file, current, recorded);
+                reports.extend(compare_fingerprints(file, recorded, &current));
+            }
+            Err(e) => reports.push(unreadable(file, e)?),
+        }
+    }
+    *bytes_hashed = fingerprinter.bytes_hashed();
+    Ok(reports)
+}
+
+/// Record a signed anchor of the fimbl executable and other files, or
+/// verify them against one, counting the files examined
+///
+/// A trojaned fimbl could claim to pass its own check, so the check
+/// is best run with a known good copy, from read-only media say, on
+/// the installed one given as the executable.
+fn self_check(
+    anchor: &str,
+    key_file: &Path,
+    record: bool,
+    executable: Option<&Path>,
+    files: &[PathBuf],
+    fingerprinter: &mut Fingerprinter,
+    examined: &mut u64,
+) -> Result<Vec<ReportItem>, FimblError> {
+    let key = SigningKey::from_file(key_file)?;
+    let executable = match executable {
+        Some(executable) => executable.to_path_buf(),
+        None => std::env::current_exe()?,
+    };
+    let checked = std::iter::once(&executable)
+        .chain(files)
+        .map(canonicalize)
+        .collect::<Result<Vec<_>, _>>()?;
+
+    if record {
+        let mut fingerprints = vec![];
+        for file in checked {
+            let fingerprint = fingerprinter.fingerprint(&file)?;
+            fingerprints.push((file, fingerprint));
+        }
+        put_object(anchor, &Baseline::new(None, fingerprints).to_bytes(&key))?;
+        return Ok(vec![]);
+    }
+
+    let baseline = Baseline::from_bytes(&get_object(anchor)?, &key)?;
+    let mut database = SystemDatabase::from_baseline(anchor, &baseline)?;
+    let mut reports = verify_all(
+        &mut database,
+        fingerprinter,
+        false,
+        &[],
+        &[],
+        None,
+        examined,
+    )?;
+    for file in checked {
+        if database.recorded_fingerprint(&file)?.is_none() {
+            reports.push(ReportItem::FileNotTracked { path: file });
+        }
+    }
+    Ok(reports)
+}
+
+/// Accept modifications to the specified files (or only to their
+/// metadata)
+fn accept(
+    files: &Vec<PathBuf>,
+    database: &mut SystemDatabase,
+    fingerprinter: &mut Fingerprinter,
+    tolerate_untracked: bool,
+    metadata_only: bool,
+    hooks: &Hooks,
+) -> Result<Vec<ReportItem>, FimblError> {
+    let (mut files, dirs) = preprocess_file_list(files)?;
+    let (tracked, dirs) = tracked_directories(dirs, database, fingerprinter)?;
+    files.extend(tracked);
+    let mut reports = reject_directories(&dirs);
+
+    for given in files {
+        let resolved = record_path_within(database, fingerprinter, &given);
+        let file = match resolve_timeout(&given, resolved)? {
+            Ok(file) => file,
+            Err(report) => {
+                reports.push(report);
+                continue;
+            }
+        };
+        let _file = debug_span!("file", path = %file.display()).entered();
+        let (report, read) = network_fs_report(fingerprinter, &file);
+        reports.extend(report);
+        if !read {
+            continue;
+        }
+
+        // contents are compared, so must be hashed as recorded
+        let fingerprint = match database.recorded_fingerprint(&file)? {
+            Some(recorded) if metadata_only => fingerprinter.fingerprint_like(&file, &recorded),
+            _ => fingerprinter.fingerprint(&file),
+        };
+        match fingerprint {
+            Ok(fingerprint) => {
+                let mut file_reports = if metadata_only {
+                    database.update_existing_metadata(&file, &fingerprint)?
+                } else {
+                    database.update_existing_file(&file, &fingerprint, tolerate_untracked)?
+                };
+                if file_reports.is_empty() {
+                    keep_alias(database, &given, &file)?;
+                    reports.extend(hooks.accepted(&file));
+                }
+                reports.append(&mut file_reports);
+            }
+            Err(e) => reports.push(unreadable(&file, e)?),
+        }
+    }
+
+    Ok(reports)
+}
+
+/// Ask a yes/no question on the terminal, defaulting to no
+fn confirm(question: &str) -> Result<bool, FimblError> {
+    eprint!("{question} [y/N] ");
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer)?;
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes"))
+}
+
+/// Accept modifications to every tracked file that fails verification
+/// (optionally only those matching a pattern), listing exactly what
+/// will be accepted and asking for confirmation unless told not to
+///
+/// Files are accepted as they were when listed: one whose contents
+/// have changed since is reported rather than accepted. Missing files
+/// are left alone: accepting their absence is a job for `remove`.
+fn accept_all(
+    filter: Option<&glob::Pattern>,
+    yes: bool,
+    database: &mut SystemDatabase,
+    fingerprinter: &mut Fingerprinter,
+    hooks: &Hooks,
+) -> Result<Vec<ReportItem>, FimblError> {
+    let mut examined = 0;
+    let failing: BTreeSet<PathBuf> = verify_all(
+        database,
+        fingerprinter,
+        false,
+        &[],
+        &[],
+        None,
+        &mut examined,
+    )?
+    .iter()
+    .filter(|item| item.severity() >= Severity::Warning)
+    .filter(|item| !matches!(item, ReportItem::FileMissing { .. }))
+    .filter_map(|item| item.path().map(Path::to_path_buf))
+    .filter(|path| filter.is_none_or(|pattern| pattern.matches_path(path)))
+    .collect();
+    if failing.is_empty() {
+        return Ok(vec![]);
+    }
+    database.check_may_weaken()?;
+
+    fingerprinter.reset();
+    let mut listed = vec![];
+    for path in failing {
+        let fingerprint = fingerprinter.fingerprint(&path)?;
+        listed.push((path, fingerprint));
+    }
+    eprintln!("Files to accept as they are now:");
+    for (path, _) in &listed {
+        eprintln!("  {}", path.display());
+    }
+    if !yes && !confirm(&format!("Accept {} files?", listed.len()))? {
+        return Err(FimblError::NotConfirmed);
+    }
+
+    let mut reports = vec![];
+    fingerprinter.reset();
+    for (path, fingerprint) in listed {
+        let now = fingerprinter.fingerprint_like(&path, &fingerprint)?;
+        if !fingerprint.same_contents(&now) {
+            reports.push(ReportItem::ContentChangeNotAccepted { path });
+            continue;
+        }
+        let file_reports = database.update_existing_file(&path, &fingerprint, false)?;
+        if file_reports.is_empty() {
+            reports.extend(hooks.accepted(&path));
+        }
+        reports.extend(file_reports);
+    }
+    Ok(reports)
+}
+
+/// Review the findings of verifying every tracked file on the
+/// terminal, then accept the files marked to be accepted, as they were
+/// reviewed, and list those marked for investigation on stdout
+///
+/// Findings not about files, and files with only informational
+/// findings, are reported as usual.
+fn review(
+    database: &mut SystemDatabase,
+    fingerprinter: &mut Fingerprinter,
+    hooks: &Hooks,
+) -> Result<Vec<ReportItem>, FimblError> {
+    let mut examined = 0;
+    let mut by_path: BTreeMap<PathBuf, Vec<ReportItem>> = BTreeMap::new();
+    let mut reports = vec![];
+    for item in verify_all(
+        database,
+        fingerprinter,
+        false,
+        &[],
+        &[],
+        None,
+        &mut examined,
+    )? {
+        match item.path().map(Path::to_path_buf) {
+            Some(path) => by_path.entry(path).or_default().push(item),
+            None => reports.push(item),
+        }
+    }
+    let (failing, passing): (Vec<_>, Vec<_>) = by_path.into_iter().partition(|(_, items)| {
+        items
+            .iter()
+            .any(|item| item.severity() >= Severity::Warning)
+    });
+    reports.extend(passing.into_iter().flat_map(|(_, items)| items));
+    if failing.is_empty() {
+        eprintln!("Nothing to review");
+        return Ok(reports);
+    }
+
+    fingerprinter.reset();
+    let mut findings = vec![];
+    for (path, items) in failing {
+        let recorded = database.recorded_fingerprint(&path)?;
+        let current = fingerprinter.fingerprint(&path).ok();
+        findings.push(review::Finding::new(
+            path,
+            items,
+            recorded.as_ref(),
+            current,
+        ));
+    }
+    let Some(review) = review::run(review::Review::new(findings))? else {
+        return Err(FimblError::NotConfirmed);
+    };
+
+    if review.count(review::Decision::Accept) > 0 {
+        database.check_may_weaken()?;
+    }
+    for finding in review.findings {
+        match (finding.decision, finding.current) {
+            (review::Decision::Accept, Some(fingerprint)) => {
+                let file_reports =
+                    database.update_existing_file(&finding.path, &fingerprint, false)?;
+                if file_reports.is_empty() {
+                    reports.extend(hooks.accepted(&finding.path));
+                }
+                reports.extend(file_reports);
+            }
+            (review::Decision::Investigate, _) => {
+                println!("investigate {}", finding.path.display())
+            }
+            _ => {}
+        }
+    }
+    Ok(reports)
+}
+
+/// Restore the recorded permissions and ownership of files, logging
+/// each restoration
+///
+/// Files with no recorded fingerprint are reported as untracked.
+fn remediate_permissions(
+    files: &Vec<PathBuf>,
+    database: &mut SystemDatabase,
+    dry_run: bool,
+) -> Result<Vec<ReportItem>, FimblError> {
+    let (files, dirs) = preprocess_file_list(files)?;
+    let mut reports = reject_directories(&dirs);
+
+    for file in files {
+        let file = record_path(database, &file)?;
+        let Some(recorded) = database.recorded_fingerprint(&file)? else {
+            reports.push(ReportItem::FileNotTracked { path: file });
+            continue;
+        };
+
+        let drift = remediate::permission_drift(&file, &recorded)?;
+        if drift.is_empty() {
+            continue;
+        }
+        if !dry_run {
+            remediate::restore_permissions(&file, &drift)?;
+            database.append_log(
+                &file,
+                LogEvent::PermissionsRestored {
+                    mode: drift.mode,
+                    uid: drift.uid,
+                    gid: drift.gid,
+                },
+            )?;
+        }
+        reports.push(ReportItem::PermissionsRestored {
+            path: file,
+            dry_run,
+        });
+    }
+
+    Ok(reports)
+}
+
+/// Open a second database, with its own key if encrypted
+///
+/// It is only read, so is opened as on a dry run: never written, not
+/// even to migrate it.
+fn open_other_database(path: &Path, key_file: Option<&Path>) -> Result<SystemDatabase, FimblError> {
+    let database_key = match key_file {
+        Some(key_file) => Some(DatabaseKey::from_file(key_file)?),
+        None => None,
+    };
+    match flatfile::is_flat_file(path) {
+        true => SystemDatabase::open_flat(path, database_key, None, Duration::ZERO, Access::DryRun),
+        false => SystemDatabase::open(path, database_key, Duration::ZERO, Access::DryRun),
+    }
+}
+
+/// Compare database with another
+fn db_diff(
+    other: &Path,
+    other_key_file: Option<&Path>,
+    database: &SystemDatabase,
+) -> Result<Vec<ReportItem>, FimblError> {
+    database.diff(&open_other_database(other, other_key_file)?)
+}
+
+/// Merge another database into this one
+fn db_merge(
+    source: &Path,
+    source_key_file: Option<&Path>,
+    prefer: MergePreference,
+    database: &mut SystemDatabase,
+) -> Result<Vec<ReportItem>, FimblError> {
+    database.merge(&open_other_database(source, source_key_file)?, prefer)
+}
+
+/// Agent settings which a config file may override
+#[derive(Clone)]
+struct Configurable {
+    endpoint: String,
+    schedule: Schedule,
+    retries: u32,
+    key: Option<SigningKey>,
+}
+
+impl Configurable {
+    /// These settings, overridden by those given in a config file
+    fn configured(&self, config: AgentConfig) -> Self {
+        let mut settings = self.clone();
+        if let Some(endpoint) = config.endpoint {
+            settings.endpoint = endpoint;
+        }
+        if let Some(interval) = config.interval {
+            settings.schedule.interval = interval;
+        }
+        if config.schedule.is_some() {
+            settings.schedule.cron = config.schedule;
+        }
+        if let Some(jitter) = config.jitter {
+            settings.schedule.jitter = jitter;
+        }
+        if config.quiet_hours.is_some() {
+            settings.schedule.quiet_hours = config.quiet_hours;
+        }
+        if let Some(retries) = config.retries {
+            settings.retries = retries;
+        }
+        if config.key.is_some() {
+            settings.key = config.key;
+        }
+        settings
+    }
+}
+
+/// Settings for agent runs
+struct AgentSettings<'a> {
+    /// As given on the command line
+    command_line: Configurable,
+    /// As given on the command line or in the config file
+    current: Configurable,
+    once: bool,
+    token: Option<&'a str>,
+    host: String,
+    mailer: Option<Mailer>,
+    notify_window: Duration,
+    /// Shared with the control socket, when running repeatedly
+    state: Option<Arc<AgentState>>,
+    /// Signals from the control socket, when running repeatedly
+    signals: Option<Receiver<Signal>>,
+    /// Severities tags are mapped to
+    tag_severities: &'a [(String, Severity)],
+}
+
+impl AgentSettings<'_> {
+    /// Apply the settings given in a config file, in place of any
+    /// given in it before
+    fn configure(&mut self, config: AgentConfig) {
+        self.current = self.command_line.configured(config);
+    }
+
+    /// When the run after one finished then is due, jittered
+    fn next_run(&self, finished: SystemTime) -> SystemTime {
+        let random = RandomState::new().hash_one(finished);
+        self.current.schedule.next_run(finished, random)
+    }
+
+    /// Wait until the run after one finished then is due, or until told
+    /// to run now, reloading the config file (and so rescheduling) if
+    /// told to meanwhile; false if told to drain instead
+    fn wait(&mut self, finished: SystemTime, mut due: SystemTime) -> bool {
+        let remaining = |due: SystemTime| due.duration_since(SystemTime::now()).unwrap_or_default();
+        let Some(signals) = self.signals.take() else {
+            sleep(remaining(due));
+            return true;
+        };
+        let carry_on = loop {
+            match signals.recv_timeout(remaining(due)) {
+                Ok(Signal::Scan) | Err(RecvTimeoutError::Timeout) => break true,
+                Ok(Signal::Drain) => break false,
+                Ok(Signal::Reload(config)) => {
+                    self.configure(config);
+                    due = self.next_run(finished);
+                    info!(
+                        next_at = %humantime::format_rfc3339_seconds(due),
+                        "config reloaded"
+                    );
+                    if let Some(state) = &self.state {
+                        state.rescheduled(due);
+                    }
+                }
+                Err(RecvTimeoutError::Disconnected) => {
+                    sleep(remaining(due));
+                    break true;
+                }
+            }
+        };
+        self.signals = Some(signals);
+        carry_on
+    }
+}
+
+/// The findings of a run to notify now, by email and to the sinks
+/// other than stdout, and the state to keep once they have been
+///
+/// With a window, findings notified within it are left out, and the
+/// rest are batched with those held back from earlier runs (see
+/// `notify`); without one, every finding goes, every time, and there
+/// is nothing to keep.
+fn coordinate(
+    database: &SystemDatabase,
+    window: Duration,
+    items: &[ReportItem],
+) -> Result<(Vec<ReportItem>, Option<NotifyState>), FimblError> {
+    if window.is_zero() {
+        return Ok((items.to_vec(), None));
+    }
+    let mut state = database.notify_state()?;
+    let due = state.admit(items, control::epoch_seconds(SystemTime::now()), window);
+    Ok((due, Some(state)))
+}
+
+/// Verify all files, pushing the results to a server, once or
+/// repeatedly
+///
+/// When running repeatedly, failures to push are reported on stderr
+/// and the agent carries on.
+fn agent(
+    mut settings: AgentSettings,
+    database: &mut SystemDatabase,
+    fingerprinter: &mut Fingerprinter,
+    fast: bool,
+    hooks: &Hooks,
+) -> Result<Vec<ReportItem>, FimblError> {
+    loop {
+        fingerprinter.reset();
+        // files may have been added, with tags, since the last run
+        map_tag_severities(database, settings.tag_severities)?;
+        if let Some(state) = &settings.state {
+            state.run_started(database.iter_assertions().count() as u64);
+        }
+        let started = Instant::now();
+        let mut examined = 0;
+        let reports = verify_all(database, fingerprinter, fast, &[], &[], None, &mut examined)?;
+        let reports = conclude_verify(hooks, judge_change_volume(database, reports, examined)?);
+        let summary = Summary::new(started, examined, fingerprinter.bytes_hashed(), &reports);
+        let run = RunReport::new(&settings.host, &reports, &summary);
+        let pushed = push_report(
+            &settings.current.endpoint,
+            settings.token,
+            settings.current.key.as_ref(),
+            &run,
+            settings.current.retries,
+        );
+        // run once, findings are notified as for verify-all
+        if settings.once {
+            pushed?;
+            return Ok(reports);
+        } else if let Err(e) = pushed {
+            warn!(error = %e, "push failed");
+        }
+        if let Some(mailer) = &settings.mailer {
+            // if the state can't be read, everything goes
+            let (due, state) = coordinate(database, settings.notify_window, &reports)
+                .unwrap_or_else(|e| {
+                    warn!(error = %e, "notification state not read");
+                    (reports.clone(), None)
+                });
+            match mailer.notify(&RunReport { items: &due, ..run }) {
+                Some(failure) => warn!(%failure, "notification failed"),
+                // if sending fails, the findings stay unsent for the next run
+                None => {
+                    if let Some(Err(e)) = state.map(|state| database.set_notify_state(&state)) {
+                        warn!(error = %e, "notification state not kept");
+                    }
+                }
+            }
+        }
+        let finished = SystemTime::now();
+        let due = settings.next_run(finished);
+        if let Some(state) = &settings.state {
+            state.run_finished(&run, Some(due));
+        }
+        info!(
+            examined,
+            reported = reports.len(),
+            next_at = %humantime::format_rfc3339_seconds(due),
+            "run complete"
+        );
+
+        if !settings.wait(finished, due) {
+            info!("drained");
+            return Ok(vec![]);
+        }
+    }
+}
+
+/// Have changes to the files tracked take the severities their tags are
+/// mapped to, if any are
+fn map_tag_severities(
+    database: &SystemDatabase,
+    mapping: &[(String, Severity)],
+) -> Result<(), FimblError> {
+    if !mapping.is_empty() {
+        let files = tracked_files(database, &[], &[])?;
+        report::map_tag_severities(
+            files
+                .into_iter()
+                .map(|(path, fingerprint)| (path, fingerprint.tags)),
+            mapping,
+        );
+    }
+    Ok(())
+}
+
+/// Publish the tracked files as a signed baseline, or a signed JSON
+/// manifest if the URL ends `.json`
+fn publish(
+    url: &str,
+    key_file: &Path,
+    database: &SystemDatabase,
+    verbose: bool,
+) -> Result<Vec<ReportItem>, FimblError> {
+    let key = SigningKey::from_file(key_file)?;
+    let baseline = database.baseline()?;
+    let body = match url.ends_with(".json") {
+        true => baseline.to_json(&key),
+        false => baseline.to_bytes(&key),
+    };
+    put_object(url, &body)?;
+    if verbose {
+        println!("Published {} to {url}", database.path().display());
+    }
+    Ok(vec![])
+}
+
+/// The prefixes given less any repeated or under another, so that
+/// no file is found under two
+fn outermost(mut prefixes: Vec<PathBuf>) -> Vec<PathBuf> {
+    // sorted, the paths under a prefix follow it
+    prefixes.sort();
+    let mut outermost: Vec<PathBuf> = vec![];
+    for prefix in prefixes {
+        if !outermost
+            .last()
+            .is_some_and(|last| prefix.starts_with(last))
+        {
+            outermost.push(prefix);
+        }
+    }
+    outermost
+}
+
+/// The tracked files under the prefixes given and with any of the
+/// tags given (if any), with their records
+fn tracked_files(
+    database: &SystemDatabase,
+    prefixes: &[PathBuf],
+    tags: &[String],
+) -> Result<Vec<(PathBuf, Fingerprint)>, FimblError> {
+    let prefixes = outermost(prefixes.to_vec());
+    let files: Box<dyn Iterator<Item = _>> = match prefixes.is_empty() {
+        true => Box::new(database.iter_assertions()),
+        false => Box::new(
+            prefixes
+                .iter()
+                .flat_map(|prefix| database.iter_assertions_under(prefix)),
+        ),
+    };
+    let mut tracked = vec![];
+    for item in files {
+        let (path, fingerprint) = item?;
+        if fingerprint.tagged(tags) {
+            tracked.push((path, fingerprint));
+        }
+    }
+    Ok(tracked)
+}
+
+/// A scheduled job verifying the database with this fimbl, opening it
+/// with the keys, host and path policy given to this command (but not
+/// a database key given only in FIMBL_DB_KEY)
+fn scheduled_job(cli: &CliArgs, database: &SystemDatabase) -> Result<generate::Job, FimblError> {
+    let absolute = |path: &Path| {
+        canonicalize(path)
+            .or_else(|_| std::path::absolute(path))
+            .map(|path| path.to_string_lossy().into_owned())
+    };
+    let mut options = vec![];
+    let files = [
+        ("--key-file", &cli.key_file),
+        ("--db-key-file", &cli.db_key_file),
+        ("--record-key-file", &cli.record_key_file),
+    ];
+    for (option, file) in files {
+        if let Some(file) = file {
+            options.extend([option.to_string(), absolute(file)?]);
+        }
+    }
+    if let Some(handle) = cli.record_key_tpm {
+        options.extend(["--record-key-tpm".to_string(), format!("{handle:#x}")]);
+    }
+    if let Some(pcrs) = &cli.record_key_tpm_pcrs {
+        options.extend(["--record-key-tpm-pcrs".to_string(), pcrs.clone()]);
+    }
+    if let Some(host) = &cli.host {
+        options.extend(["--host".to_string(), host.clone()]);
+    }
+    if let Some(policy) = cli.path_policy {
+        options.extend(["--path-policy".to_string(), policy.name().to_string()]);
+    }
+    for (tag, severity) in &cli.tag_severity {
+        options.extend([
+            "--tag-severity".to_string(),
+            format!("{tag}={}", severity.name()),
+        ]);
+    }
+    Ok(generate::Job {
+        fimbl: std::env::current_exe()?,
+        database: canonicalize(database.path()).unwrap_or(database.path().to_owned()),
+        options,
+        tpm: cli.record_key_tpm.is_some(),
+    })
+}
+
+/// Write configuration generated from the tracked files to stdout
+fn generate(
+    artifact: &Artifact,
+    cli: &CliArgs,
+    database: &SystemDatabase,
+) -> Result<Vec<ReportItem>, FimblError> {
+    match artifact {
+        Artifact::AuditRules { key, prefix, tag } => {
+            let files: Vec<PathBuf> = tracked_files(database, prefix, tag)?
+                .into_iter()
+                .map(|(path, _)| path)
+                .collect();
+            print!("{}", generate::audit_rules(&files, key));
+        }
+        Artifact::Systemd { interval } => {
+            let job = scheduled_job(cli, database)?;
+            let (service, timer) = generate::systemd_units(&job, interval);
+            let unit = generate::SYSTEMD_UNIT;
+            print!("# /etc/systemd/system/{unit}.service\n{service}\n");
+            print!("# /etc/systemd/system/{unit}.timer\n{timer}");
+        }
+        Artifact::Launchd { interval, log_file } => {
+            let job = scheduled_job(cli, database)?;
+            let plist = generate::launchd_plist(&job, interval, log_file)
+                .map_err(FimblError::IntervalInvalid)?;
+            // nothing may come before the XML declaration
+            print!("{plist}");
+        }
+        Artifact::ScheduledTask { interval } => {
+            let job = scheduled_job(cli, database)?;
+            let task =
+                generate::scheduled_task(&job, interval).map_err(FimblError::IntervalInvalid)?;
+            print!("{task}");
+        }
+    }
+    Ok(vec![])
+}
+
+/// Export the tracked files (those under the prefixes given and with
+/// any of the tags given, if any) as an inventory to stdout
+fn export(
+    format: ExportFormat,
+    prefixes: &[PathBuf],
+    tags: &[String],
+    host: &str,
+    database: &SystemDatabase,
+) -> Result<Vec<ReportItem>, FimblError> {
+    let exported = tracked_files(database, prefixes, tags)?;
+    match format {
+        ExportFormat::CycloneDx => {
+            let bom = Bom::new(host, &exported);
+            println!("{}", serde_json::to_string_pretty(&bom).unwrap());
+        }
+    }
+    Ok(vec![])
+}
+
+/// Attest to the tracked files (those under the prefixes given, if
+/// any), writing the attestation to stdout, unless any differ from
+/// their records, which are reported instead, counting the files
+/// examined
+///
+/// Directories and special files are not attested to, having no
+/// contents to digest.
+fn attest(
+    format: AttestFormat,
+    key_file: &Path,
+    prefixes: &[PathBuf],
+    host: &str,
+    database: &SystemDatabase,
+    fingerprinter: &mut Fingerprinter,
+    examined: &mut u64,
+) -> Result<Vec<ReportItem>, FimblError> {
+    let key = AttestKey::from_file(key_file)?;
+    let prefixes = outermost(prefixes.to_vec());
+    let files: Box<dyn Iterator<Item = _>> = match prefixes.is_empty() {
+        true => Box::new(database.iter_assertions()),
+        false => Box::new(
+            prefixes
+                .iter()
+                .flat_map(|prefix| database.iter_assertions_under(prefix)),
+        ),
+    };
+
+    let mut subjects = vec![];
+    let mut attested = BTreeSet::new();
+    let mut reports = vec![];
+    for item in files {
+        let (path, recorded) = item?;
+        if recorded.directory || recorded.special.is_some() {
+            continue;
+        }
+        *examined += 1;
+        if symlink_metadata(&path).is_err_and(|e| e.kind() == ErrorKind::NotFound) {
+            reports.push(ReportItem::FileMissing { path });
+            continue;
+        }
+        let current = match fingerprinter.fingerprint_like(&path, &recorded) {
+            Ok(current) => current,
+            Err(e) => {
+                reports.push(unreadable(&path, e)?);
+                continue;
+            }
+        };
+        let differences = compare_contents(&path, &recorded, &current);
+        if !differences.is_empty() {
+            reports.extend(differences);
+            continue;
+        }
+
+        let sha256 = current
+            .digest
+            .expect("attest takes the SHA-256 of what it reads");
+        let mut digest = BTreeMap::from([("sha256".to_string(), hex::encode(sha256))]);
+        if recorded.algorithm == HashAlgorithm::Sha3_256 && recorded.chunk_size.is_none() {
+            digest.insert("sha3_256".to_string(), hex::encode(recorded.content_hash));
+        }
+        subjects.push(Subject {
+            name: path.to_string_lossy().into_owned(),
+            digest,
+        });
+        attested.insert(path);
+    }
+
+    if reports.is_empty() {
+        let root_hash = hex::encode(database.root_hash_of(&attested)?);
+        let statement = Statement::new(subjects, host, root_hash);
+        match format {
+            AttestFormat::InToto => print!("{}", statement.to_envelope(&key)),
+        }
+    }
+    Ok(reports)
+}
+
+/// Back up the database to an archive file
+fn backup(
+    output: &Path,
+    database: &SystemDatabase,
+    verbose: bool,
+) -> Result<Vec<ReportItem>, FimblError> {
+    database.backup(output)?;
+    if verbose {
+        println!(
+            "Backed up {} to {}",
+            database.path().display(),
+            output.display()
+        );
+    }
+    Ok(vec![])
+}
+
+/// Restore the database from an archive file
+fn restore(
+    input: &Path,
+    database: &mut SystemDatabase,
+    force: bool,
+    verbose: bool,
+) -> Result<Vec<ReportItem>, FimblError> {
+    database.restore(input, force)?;
+    if verbose {
+        println!(
+            "Restored {} from {}",
+            database.path().display(),
+            input.display()
+        );
+    }
+    Ok(vec![])
+}
+
+/// Create, roll back to, list or delete snapshots of the baseline,
+/// listing the files a rollback changes to stdout and reporting those
+/// held for approval
+fn snapshot(
+    action: &SnapshotAction,
+    database: &mut SystemDatabase,
+) -> Result<Vec<ReportItem>, FimblError> {
+    match action {
+        SnapshotAction::Create { name } => database.create_snapshot(name)?,
+        SnapshotAction::Rollback { name, .. } => {
+            let (changed, held) = database.rollback(name)?;
+            for path in changed {
+                println!("{}", path.display());
+            }
+            return Ok(held);
+        }
+        SnapshotAction::List {} => {
+            for (name, created, tracked) in database.snapshots()? {
+                println!(
+                    "{name} ({}, {tracked} files)",
+                    humantime::format_rfc3339_seconds(created)
+                );
+            }
+        }
+        SnapshotAction::Delete { name } => database.delete_snapshot(name)?,
+    }
+    Ok(vec![])
+}
+
+/// Print the root hash of the baseline to stderr, appending it to the
+/// anchor file and posting it to the anchor endpoint if configured
+fn anchor(cli: &CliArgs, database: &SystemDatabase) -> Result<Vec<ReportItem>, FimblError> {
+    let anchor = Anchor::new(&cli.report_host(), &database.root_hash()?);
+    eprintln!("root hash: {}", anchor.root_hash);
+
+    let mut reports = vec![];
+    if let Some(file) = &cli.anchor_file {
+        if let Err(e) = anchor::append(file, &anchor) {
+            reports.push(ReportItem::NotificationFailed {
+                sink: "anchor file".to_string(),
+                message: e.to_string(),
+            });
+        }
+    }
+    if let Some(url) = &cli.anchor_url {
+        if let Err(e) = anchor::post(url, cli.remote_token.as_deref(), &anchor) {
+            reports.push(ReportItem::NotificationFailed {
+                sink: "anchor endpoint".to_string(),
+                message: e.to_string(),
+            });
+        }
+    }
+    Ok(reports)
+}
+
+/// Print the root hash of the baseline on stdout or, given an anchor
+/// file, report if it isn't the one last anchored there
+fn root_hash(
+    check: Option<&Path>,
+    cli: &CliArgs,
+    database: &SystemDatabase,
+) -> Result<Vec<ReportItem>, FimblError> {
+    let actual = hex::encode(database.root_hash()?);
+    let Some(file) = check else {
+        println!("{actual}");
+        return Ok(vec![]);
+    };
+
+    let host = cli.report_host();
+    let anchored = anchor::last_anchor(file, &host)?
+        .ok_or_else(|| FimblError::NotAnchored(file.to_owned(), host))?
+        .root_hash;
+    match anchored == actual {
+        true => Ok(vec![]),
+        false => Ok(vec![ReportItem::RootHashMismatch { anchored, actual }]),
+    }
+}
+
+/// Location of the database when none is specified
+#[cfg(not(windows))]
+fn default_database() -> Option<PathBuf> {
+    dirs::home_dir().map(|home| home.join(".config/fimbl/db"))
+}
+
+/// Location of the database when none is specified
+///
+/// Services typically run as accounts with no home directory but do
+/// have a local application data directory.
+#[cfg(windows)]
+fn default_database() -> Option<PathBuf> {
+    dirs::data_local_dir().map(|data| data.join("fimbl").join("db"))
+}
+
+/// Send the report (and, for verification, its summary) to each sink
+/// requested, and to the output file if one was
+///
+/// Sinks other than stdout get only the findings due to be notified,
+/// if given (see `coordinate`). A sink failing doesn't stop the others,
+/// but fimbl then fails.
+fn finish(
+    cli: &CliArgs,
+    reports: Vec<ReportItem>,
+    due: Option<Vec<ReportItem>>,
+    summary: Summary,
+    show_summary: bool,
+) {
+    let host = cli.report_host();
+    let run = RunReport::new(&host, &reports, &summary);
+    let notified = due.as_ref().map(|due| RunReport::new(&host, due, &summary));
+    let options = SinkOptions {
+        format: cli.format,
+        append: cli.append,
+        webhook_token: cli.webhook_token.clone(),
+    };
+    let output = cli.output.iter().map(|file| SinkSpec::File(file.clone()));
+    let mut failure = None;
+    let sinks = cli.report_to.iter().map(|spec| match (spec, &notified) {
+        (SinkSpec::Stdout, _) | (_, None) => (spec.clone(), &run),
+        (_, Some(notified)) => (spec.clone(), notified),
+    });
+    for (spec, run) in output.map(|spec| (spec, &run)).chain(sinks) {
+        if let Err(e) = spec.open(&options).report(run, show_summary) {
+            failure.get_or_insert(e);
+        }
+    }
+    if let Some(e) = failure {
+        fail(e);
+    }
+}
+
+/// The record key given, from its file or unsealed from the TPM
+fn record_key(cli: &CliArgs) -> Result<Option<SigningKey>, FimblError> {
+    match (&cli.record_key_file, cli.record_key_tpm) {
+        (Some(file), _) => Ok(Some(SigningKey::from_file(file)?)),
+        (None, Some(handle)) => {
+            let pcrs = cli.record_key_tpm_pcrs.as_deref();
+            Ok(Some(SigningKey::from_bytes(&tpm::unseal(handle, pcrs)?)))
+        }
+        (None, None) => Ok(None),
+    }
+}
+
+/// Seal the record key in a file (enrolled already) to the TPM, or a
+/// new one, enrolling it, so that it never rests on disk
+fn seal_record_key(
+    from: Option<&Path>,
+    cli: &CliArgs,
+    database: &mut SystemDatabase,
+) -> Result<Vec<ReportItem>, FimblError> {
+    let handle = cli.record_key_tpm.ok_or(FimblError::RecordKeyNotGiven)?;
+    let pcrs = cli.record_key_tpm_pcrs.as_deref();
+    let key = match from {
+        Some(file) => match std::fs::read(file) {
+            Ok(key) if key.is_empty() => return Err(FimblError::EmptyKeyFile(file.into())),
+            Ok(key) => key,
+            Err(e) => return Err(FimblError::KeyFileError(file.into(), e)),
+        },
+        None => {
+            let mut key = vec![0; 32];
+            SystemRandom::new()
+                .fill(&mut key)
+                .map_err(|_| FimblError::TpmError("no random key to seal".to_string()))?;
+            key
+        }
+    };
+    tpm::seal(handle, pcrs, &key)?;
+    if from.is_none() {
+        database.enrol_record_key(SigningKey::from_bytes(&key))?;
+    }
+    Ok(vec![])
+}
+
+/// Open the local or remote database requested
+fn open_database(cli: &CliArgs, db_path: &Path) -> Result<SystemDatabase, FimblError> {
+    let database_key = cli.database_key()?;
+    let record_key = match &cli.command {
+        // the key is yet to be sealed
+        Command::SealRecordKey { from: Some(file) } => Some(SigningKey::from_file(file)?),
+        Command::SealRecordKey { from: None } => None,
+        _ => record_key(cli)?,
+    };
+    let access = match (&cli.command, cli.dry_run) {
+        // looked at as it is, not upgraded
+        (Command::Doctor {}, _) => Access::ReadOnly,
+        // a probe, leaving no trace of its scan
+        (Command::Check {}, _) => Access::DryRun,
+        (_, true) => Access::DryRun,
+        (_, false) => Access::ReadWrite,
+    };
+    let database = match &cli.remote {
+        Some(url) => {
+            SystemDatabase::open_remote(url, cli.remote_token.clone(), database_key, access)?
+        }
+        None if flatfile::is_flat_file(db_path) => SystemDatabase::open_flat(
+            db_path,
+            database_key,
+            record_key.clone(),
+            cli.lock_wait,
+            access,
+        )?,
+        None => SystemDatabase::open(db_path, database_key, cli.lock_wait, access)?,
+    };
+    database
+        .with_host(cli.host.clone())
+        .with_history_limit(cli.history)
+        .with_force_unsafe(cli.force_unsafe())
+        .with_command_line(command_line(
+            std::env::args_os().map(|arg| arg.to_string_lossy().into_owned()),
+        ))
+        .with_path_policy(cli.path_policy, cli.default_path_policy())?
+        .with_record_key(match cli.command {
+            // the key is yet to be enrolled
+            Command::EnrolRecordKey {} | Command::SealRecordKey { from: None } => None,
+            _ => record_key,
+        })
+}
+
+/// Options whose values are secrets, not to be logged
+const SECRET_OPTIONS: &[&str] = &[
+    "--remote-token",
+    "--smtp-password",
+    "--token",
+    "--webhook-token",
+];
+
+/// The command line, with the values of secret options redacted, for
+/// logging changes with
+fn command_line(args: impl Iterator<Item = String>) -> Vec<String> {
+    let mut redact_next = false;
+    args.map(|arg| {
+        if std::mem::take(&mut redact_next) {
+            return "<redacted>".to_string();
+        }
+        match arg.split_once('=') {
+            Some((option, _)) if SECRET_OPTIONS.contains(&option) => {
+                format!("{option}=<redacted>")
+            }
+            _ => {
+                redact_next = SECRET_OPTIONS.contains(&arg.as_str());
+                arg
+            }
+        }
+    })
+    .collect()
+}
+
+/// Check the database and the environment for problems
+///
+/// The database is only opened if it exists, so as not to create it.
+fn doctor(cli: &CliArgs) -> Vec<doctor::Finding> {
+    let default_db = default_database();
+    let xdg_config = std::env::var_os("XDG_CONFIG_HOME").map(PathBuf::from);
+    let mut findings = vec![doctor::home(default_db.as_deref(), xdg_config.as_deref())];
+    let local = match (&cli.remote, cli.database().or(default_db.as_deref())) {
+        (Some(_), _) => None,
+        (None, db_path) => db_path,
+    };
+    if let Some(db_path) = local {
+        findings.push(doctor::writable(db_path));
+        if !db_path.exists() {
+            findings.push(doctor::clock(SystemTime::now(), None));
+            return findings;
+        }
+    }
+
+    let check = "database";
+    let database = match open_database(cli, local.unwrap_or(Path::new(""))) {
+        Ok(database) => database,
+        Err(e) => {
+            let fix = match e {
+                FimblError::WrongDatabaseKey => "supply the key the database was encrypted with",
+                FimblError::DatabaseNotEncrypted => "drop --db-key-file and FIMBL_DB_KEY",
+                _ => "as the message says or, if the database is corrupt, restore a backup with fimbl restore",
+            };
+            findings.push(doctor::Finding::problem(check, e.to_string(), fix));
+            findings.push(doctor::clock(SystemTime::now(), None));
+            return findings;
+        }
+    };
+    let path = database.path().display();
+    let opens = match database.schema_version() {
+        Ok(version) if version < database::SCHEMA_VERSION => format!(
+            "{path} opens, at schema version {version} (upgraded to {} when next opened)",
+            database::SCHEMA_VERSION
+        ),
+        _ => format!("{path} opens, at the current schema version"),
+    };
+    findings.push(doctor::Finding::fine(check, opens));
+
+    let latest = database.latest_record_time();
+    findings.push(doctor::clock(SystemTime::now(), latest.ok().flatten()));
+
+    let tracked: Result<Vec<_>, _> = database
+        .iter_assertions()
+        .map(|item| item.map(|(path, _)| path))
+        .collect();
+    let finding = match (Mounts::load(), tracked) {
+        (Ok(mounts), Ok(tracked)) => {
+            let policy_set = cli.network_fs != NetworkFs::Read || cli.network_fs_timeout.is_some();
+            doctor::file_systems(&mounts, &tracked, policy_set)
+        }
+        (Err(e), _) => doctor::Finding::problem(
+            "file systems",
+            format!("cannot read the mount table: {e}"),
+            "check /proc is mounted",
+        ),
+        (_, Err(e)) => {
+            doctor::Finding::problem(check, format!("cannot read records: {e}"), "run fimbl fsck")
+        }
+    };
+    findings.push(finding);
+    findings
+}
+
+/// Verify all files, returning Nagios plugin output and exit status
+fn check(cli: &CliArgs, db_path: &Path) -> Result<(String, i32), FimblError> {
+    let (reports, summary) = verify_everything(cli, db_path)?;
+    Ok(output::nagios(&reports, &summary))
+}
+
+/// Verify all files, returning osquery result log lines
+fn osquery(cli: &CliArgs, db_path: &Path) -> Result<String, FimblError> {
+    let (reports, summary) = verify_everything(cli, db_path)?;
+    let host = cli.report_host();
+    Ok(output::render_osquery(&RunReport::new(
+        &host, &reports, &summary,
+    )))
+}
+
+/// Verify all files, as `verify-all` with no options does
+fn verify_everything(
+    cli: &CliArgs,
+    db_path: &Path,
+) -> Result<(Vec<ReportItem>, Summary), FimblError> {
+    let mut database = open_database(cli, db_path)?;
+    let mut fingerprinter = cli.fingerprinter()?;
+    let started = Instant::now();
+    let mut examined = 0;
+    let reports = verify_all(
+        &mut database,
+        &mut fingerprinter,
+        cli.fast,
+        &[],
+        &[],
+        None,
+        &mut examined,
+    )?;
+    let reports = judge_change_volume(&database, reports, examined)?;
+    let reports = conclude_verify(&cli.hooks(), reports);
+    let summary = Summary::new(started, examined, fingerprinter.bytes_hashed(), &reports);
+    Ok((reports, summary))
+}
+
+/// Report an error and exit with its exit code
+fn fail(error: FimblError) -> ! {
+    let mut message = error.to_string();
+    let mut source = std::error::Error::source(&error);
+    while let Some(cause) = source {
+        message.push_str(&format!(": {cause}"));
+        source = cause.source();
+    }
+    eprintln!("fimbl: {message}");
+    std::process::exit(error.exit_code())
+}
+
+fn main() {
+    let matches = CliArgs::command().get_matches();
+    let mut cli = CliArgs::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+    logging::init(cli.log_level, cli.log_format);
+    let _command = info_span!("fimbl", command = matches.subcommand_name()).entered();
+
+    // a plan is the changes of the command planned, made on a dry run
+    let mut plan_output = None;
+    if let Command::Plan {
+        output,
+        sign_key_file,
+        command,
+    } = cli.command
+    {
+        let key = SigningKey::from_file(&sign_key_file);
+        plan_output = Some((output, key.unwrap_or_else(|e| fail(e))));
+        let planned = std::iter::once("fimbl plan".to_string()).chain(command);
+        cli.command = Planned::try_parse_from(planned)
+            .unwrap_or_else(|e| e.exit())
+            .command;
+        if !cli.plans() {
+            fail(FimblError::PlanUnsupported);
+        }
+        cli.dry_run = true;
+    }
+
+    let verifies = matches!(
+        cli.command,
+        Command::Verify { .. }
+            | Command::VerifyAll { .. }
+            | Command::Check {}
+            | Command::Osquery {}
+    );
+    if cli.root.is_some() && !verifies {
+        fail(FimblError::RootOnlyForVerifying);
+    }
+    if cli.dry_run && !cli.dry_runs() {
+        fail(FimblError::DryRunOnlyForChanges);
+    }
+
+    if let Command::Doctor {} = &cli.command {
+        let findings = doctor(&cli);
+        for finding in &findings {
+            println!("{finding}");
+        }
+        let problems = findings.iter().any(|finding| finding.is_problem());
+        std::process::exit(problems as i32);
+    }
+
+    if let Command::Explain { code } = &cli.command {
+        match code {
+            Some(code) => println!("{}", explain::explain(code).unwrap_or_else(|e| fail(e))),
+            None => {
+                for explanation in explain::EXPLANATIONS {
+                    println!(
+                        "{} {:<28} {}",
+                        explanation.code, explanation.kind, explanation.summary
+                    );
+                }
+            }
+        }
+        return;
+    }
+
+    if let Command::Verify {
+        files,
+        manifest: Some(manifest),
+        sign_key_file: Some(key_file),
+        ..
+    } = &cli.command
+    {
+        let mut fingerprinter = cli.fingerprinter().unwrap_or_else(|e| fail(e));
+        let started = Instant::now();
+        let mut examined = 0;
+        let reports = verify_manifest(
+            manifest,
+            key_file,
+            files,
+            false,
+            &mut fingerprinter,
+            &mut examined,
+        );
+        let reports = conclude_verify(&cli.hooks(), reports.unwrap_or_else(|e| fail(e)));
+        let summary = Summary::new(started, examined, fingerprinter.bytes_hashed(), &reports);
+        finish(&cli, reports, None, summary, true);
+        return;
+    }
+
+    if let Command::VerifyImage {
+        image,
+        baseline,
+        sign_key_file,
+        prefix,
+    } = &cli.command
+    {
+        let started = Instant::now();
+        let mut examined = 0;
+        let mut bytes_hashed = 0;
+        let reports = verify_image(
+            image,
+            baseline,
+            sign_key_file,
+            prefix,
+            &cli,
+            &mut examined,
+            &mut bytes_hashed,
+        );
+        let reports = conclude_verify(&cli.hooks(), reports.unwrap_or_else(|e| fail(e)));
+        let summary = Summary::new(started, examined, bytes_hashed, &reports);
+        finish(&cli, reports, None, summary, true);
+        return;
+    }
+
+    if let Command::Gate {
+        manifest,
+        sign_key_file,
+        dirs,
+    } = &cli.command
+    {
+        // for the cluster's logs, unless asked otherwise
+        if matches.value_source("format") == Some(ValueSource::DefaultValue) {
+            cli.format = Format::Json;
+        }
+        let mut fingerprinter = cli.fingerprinter().unwrap_or_else(|e| fail(e));
+        let started = Instant::now();
+        let mut examined = 0;
+        let reports = verify_manifest(
+            manifest,
+            sign_key_file,
+            dirs,
+            true,
+            &mut fingerprinter,
+            &mut examined,
+        );
+        let reports = reports.unwrap_or_else(|e| fail(e));
+        // a gate that checked nothing must not open
+        if examined == 0 && reports.is_empty() {
+            fail(FimblError::NothingToGate);
+        }
+        let reports = conclude_verify(&cli.hooks(), reports);
+        let mismatched = !reports.is_empty();
+        let summary = Summary::new(started, examined, fingerprinter.bytes_hashed(), &reports);
+        finish(&cli, reports, None, summary, true);
+        std::process::exit(mismatched as i32);
+    }
+
+    let default_db = default_database().expect("No home directory for default database");
+
+    let db_path = cli.database().unwrap_or(&*default_db);
+
+    if cli.idle_priority {
+        throttle::lower_priority()
+            .map_err(FimblError::PriorityError)
+            .unwrap_or_else(|e| fail(e));
+    }
+
+    if let Command::Server {
+        listen,
+        sign_key_file,
+        token,
+    } = &cli.command
+    {
+        let config = ServerConfig {
+            token: token.clone(),
+            key: sign_key_file
+                .as_deref()
+                .map(SigningKey::from_file)
+                .transpose()
+                .unwrap_or_else(|e| fail(e)),
+        };
+        let db = database::open_sled(db_path, cli.lock_wait).unwrap_or_else(|e| fail(e));
+        server::serve(&db, listen, &config).unwrap_or_else(|e| fail(e));
+        return;
+    }
+
+    if let Command::Verify {
+        files,
+        baseline: Some(url),
+        sign_key_file: Some(key_file),
+        ..
+    } = &cli.command
+    {
+        let mut fingerprinter = cli.fingerprinter().unwrap_or_else(|e| fail(e));
+        let started = Instant::now();
+        let mut examined = 0;
+        let reports = verify_baseline(
+            url,
+            key_file,
+            files,
+            &mut fingerprinter,
+            cli.fast,
+            &mut examined,
+        );
+        let reports = conclude_verify(&cli.hooks(), reports.unwrap_or_else(|e| fail(e)));
+        let summary = Summary::new(started, examined, fingerprinter.bytes_hashed(), &reports);
+        finish(&cli, reports, None, summary, true);
+        return;
+    }
+
+    if let Command::SelfCheck {
+        anchor,
+        sign_key_file,
+        record,
+        executable,
+        files,
+    } = &cli.command
+    {
+        let mut fingerprinter = cli.fingerprinter().unwrap_or_else(|e| fail(e));
+        let started = Instant::now();
+        let mut examined = 0;
+        let reports = self_check(
+            anchor,
+            sign_key_file,
+            *record,
+            executable.as_deref(),
+            files,
+            &mut fingerprinter,
+            &mut examined,
+        )
+        .unwrap_or_else(|e| fail(e));
+        let changed = !reports.is_empty();
+        let summary = Summary::new(started, examined, fingerprinter.bytes_hashed(), &reports);
+        finish(&cli, reports, None, summary, !*record);
+        std::process::exit(changed as i32);
+    }
+
+    if let Command::Ctl { command } = &cli.command {
+        let reply = control::command(&cli.control_socket(db_path), *command);
+        println!("{}", reply.unwrap_or_else(|e| fail(e)));
+        return;
+    }
+
+    if let Command::Top { refresh } = &cli.command {
+        top::run(&cli.control_socket(db_path), *refresh).unwrap_or_else(|e| fail(e));
+        return;
+    }
+
+    if let Command::Check {} = &cli.command {
+        let (output, status) = match check(&cli, db_path) {
+            Ok((output, status)) => (output, status),
+            Err(e) => output::nagios_unknown(&e),
+        };
+        print!("{output}");
+        std::process::exit(status);
+    }
+
+    if let Command::Osquery {} = &cli.command {
+        print!("{}", osquery(&cli, db_path).unwrap_or_else(|e| fail(e)));
+        return;
+    }
+
+    let mut database = open_database(&cli, db_path).unwrap_or_else(|e| fail(e));
+    map_tag_severities(&database, &cli.tag_severity).unwrap_or_else(|e| fail(e));
+    let mut fingerprinter = cli.fingerprinter().unwrap_or_else(|e| fail(e));
+    let hooks = cli.hooks();
+    let started = Instant::now();
+    let mut examined = 0;
 
     let reports = match &cli.command {
-        Command::Add { files } => add(files, &mut database, cli.tolerant),
-        Command::Remove { files } => remove(files, &mut database, cli.tolerant),
-        Command::List {} => list(&database, cli.verbose),
-        Command::Verify { files } => verify(files, &mut database),
-        Command::VerifyAll {} => verify_all(&mut database),
-        Command::Accept { files } => accept(files, &mut database, cli.tolerant),
+        Command::Add {
+            files,
+            dir_entry,
+            recursive,
+            one_file_system,
+            tag,
+            preset,
+        } => add(
+            files,
+            preset,
+            &mut database,
+            &mut fingerprinter,
+            cli.tolerant,
+            AddDirs {
+                entries: *dir_entry,
+                recursive: *recursive,
+                one_file_system: *one_file_system,
+            },
+            tag,
+        ),
+        Command::Import {
+            format,
+            tag,
+            one_file_system,
+            baseline,
+        } => import(
+            *format,
+            baseline,
+            tag,
+            *one_file_system,
+            &mut database,
+            &mut fingerprinter,
+            cli.tolerant,
+        ),
+        Command::Init {
+            append_only,
+            two_phase_accept,
+            approval_key_file,
+            unicode_paths,
+        } => init(
+            *append_only,
+            *two_phase_accept,
+            approval_key_file.as_deref(),
+            *unicode_paths,
+            &mut database,
+        ),
+        Command::Remove { files, .. } => remove(files, &mut database, cli.tolerant),
+        Command::Rename { from, to, .. } => rename(from, to, &mut database, &mut fingerprinter),
+        Command::Note { file, note } => annotate(file, note.as_deref(), &mut database),
+        Command::List { tag, long } => list(&database, cli.verbose, tag, *long),
+        Command::Dupes {} => dupes(&database),
+        Command::Bench { size, algorithms } => bench(&cli, *size, algorithms, &database),
+        Command::Lookup { hash } => lookup(&database, hash),
+        Command::History { file } => history(file, &database),
+        Command::Blame { file } => blame(file, &database),
+        Command::Log { since, kind, path } => log(*since, kind, path.as_deref(), &database),
+        Command::GitAdd { repo, tag } => {
+            git_add(repo, &mut database, &mut fingerprinter, cli.tolerant, tag)
+        }
+        Command::Hook { hook } => git_hook(hook, &database, &mut fingerprinter, &mut examined),
+        Command::Export {
+            format,
+            prefix,
+            tag,
+        } => export(*format, prefix, tag, &cli.report_host(), &database),
+        Command::Attest {
+            format,
+            sign_key_file,
+            prefix,
+        } => attest(
+            *format,
+            sign_key_file,
+            prefix,
+            &cli.report_host(),
+            &database,
+            &mut fingerprinter,
+            &mut examined,
+        ),
+        Command::GitVerify { repo, against_head } => git_verify(
+            repo,
+            *against_head,
+            &mut database,
+            &mut fingerprinter,
+            cli.fast,
+            &mut examined,
+        )
+        .map(|reports| conclude_verify(&hooks, reports)),
+        Command::Verify { files, .. } => verify(
+            files,
+            &mut database,
+            &mut fingerprinter,
+            cli.fast,
+            &mut examined,
+        )
+        .map(|reports| conclude_verify(&hooks, reports)),
+        Command::VerifyAll {
+            prefix,
+            sample,
+            sample_count,
+            tag,
+            detect_renames,
+        } => verify_all(
+            &mut database,
+            &mut fingerprinter,
+            cli.fast,
+            prefix,
+            tag,
+            Sample::from_args(*sample, *sample_count),
+            &mut examined,
+        )
+        .and_then(|reports| match detect_renames {
+            true => find_moves(&database, &mut fingerprinter, reports),
+            false => Ok(reports),
+        })
+        .and_then(|reports| judge_change_volume(&database, reports, examined))
+        .map(|reports| conclude_verify(&hooks, reports)),
+        Command::Accept {
+            metadata_only,
+            files,
+            ..
+        } => accept(
+            files,
+            &mut database,
+            &mut fingerprinter,
+            cli.tolerant,
+            *metadata_only,
+            &hooks,
+        ),
+        Command::AcceptAll { filter, yes, .. } => accept_all(
+            filter.as_ref(),
+            *yes || cli.dry_run,
+            &mut database,
+            &mut fingerprinter,
+            &hooks,
+        ),
+        Command::Review { .. } => review(&mut database, &mut fingerprinter, &hooks),
+        Command::Approve {
+            token,
+            sign_key_file,
+        } => approve(token, sign_key_file.as_deref(), &mut database, &hooks),
+        Command::Apply {
+            plan,
+            sign_key_file,
+        } => apply(plan, sign_key_file, &mut database, &hooks),
+        Command::Plan { .. } => unreachable!("plan handled above"),
+        Command::Reject { token } => database.reject(token).map(|_| vec![]),
+        Command::Pending {} => pending(&database),
+        Command::Remediate {
+            permissions: _,
+            dry_run,
+            files,
+        } => remediate_permissions(files, &mut database, *dry_run || cli.dry_run),
+        Command::Fsck { repair } => database.fsck(*repair),
+        Command::DbDiff {
+            other,
+            other_db_key_file,
+        } => db_diff(other, other_db_key_file.as_deref(), &database),
+        Command::DbMerge {
+            source,
+            prefer,
+            source_db_key_file,
+        } => db_merge(
+            source,
+            source_db_key_file.as_deref(),
+            *prefer,
+            &mut database,
+        ),
+        Command::Agent {
+            endpoint,
+            interval,
+            once,
+            retries,
+            sign_key_file,
+            config,
+            listen,
+        } => {
+            let (state, signals) = match once {
+                true => (None, None),
+                false => {
+                    let (state, signals) = AgentState::new(&cli.report_host(), config.clone());
+                    (Some(Arc::new(state)), Some(signals))
+                }
+            };
+            let command_line = Configurable {
+                endpoint: endpoint.clone().unwrap_or_default(),
+                schedule: Schedule {
+                    interval: *interval,
+                    ..Schedule::default()
+                },
+                retries: *retries,
+                key: sign_key_file
+                    .as_deref()
+                    .map(SigningKey::from_file)
+                    .transpose()
+                    .unwrap_or_else(|e| fail(e)),
+            };
+            let mut settings = AgentSettings {
+                current: command_line.clone(),
+                command_line,
+                once: *once,
+                token: cli.remote_token.as_deref(),
+                host: cli.report_host(),
+                mailer: cli.mailer(),
+                notify_window: cli.notify_window,
+                state,
+                signals,
+                tag_severities: &cli.tag_severity,
+            };
+            if let Some(path) = config {
+                settings.configure(AgentConfig::load(path).unwrap_or_else(|e| fail(e)));
+                if settings.current.endpoint.is_empty() {
+                    fail(FimblError::ConfigError(
+                        path.clone(),
+                        "no endpoint given, here or on the command line".to_string(),
+                    ));
+                }
+            }
+            if let Some(state) = &settings.state {
+                let socket = cli.control_socket(db_path);
+                if let Err(e) = control::listen(&socket, state.clone()) {
+                    warn!(error = %e, "carrying on without a control socket");
+                }
+                if let Some(address) = listen {
+                    health::serve(address, state.clone()).unwrap_or_else(|e| fail(e));
+                }
+                fingerprinter = cli
+                    .fingerprinter()
+                    .unwrap_or_else(|e| fail(e))
+                    .with_progress(state.progress.clone());
+            }
+            agent(
+                settings,
+                &mut database,
+                &mut fingerprinter,
+                cli.fast,
+                &hooks,
+            )
+        }
+        Command::Server { .. } => unreachable!("server handled above"),
+        Command::Ctl { .. } => unreachable!("ctl handled above"),
+        Command::Top { .. } => unreachable!("top handled above"),
+        Command::Check {} => unreachable!("check handled above"),
+        Command::Osquery {} => unreachable!("osquery handled above"),
+        Command::SelfCheck { .. } => unreachable!("self-check handled above"),
+        Command::Doctor {} => unreachable!("doctor handled above"),
+        Command::Explain { .. } => unreachable!("explain handled above"),
+        Command::VerifyImage { .. } => unreachable!("verify-image handled above"),
+        Command::Gate { .. } => unreachable!("gate handled above"),
+        Command::Publish { url, sign_key_file } => {
+            publish(url, sign_key_file, &database, cli.verbose)
+        }
+        Command::RootHash { check } => root_hash(check.as_deref(), &cli, &database),
+        Command::EnrolRecordKey {} => record_key(&cli)
+            .and_then(|key| key.ok_or(FimblError::RecordKeyNotGiven))
+            .and_then(|key| database.enrol_record_key(key))
+            .map(|_| vec![]),
+        Command::SealRecordKey { from } => seal_record_key(from.as_deref(), &cli, &mut database),
+        Command::Backup { output } => backup(output, &database, cli.verbose),
+        Command::Restore { backup, force, .. } => {
+            restore(backup, &mut database, *force, cli.verbose)
+        }
+        Command::Generate { artifact } => generate(artifact, &cli, &database),
+        Command::Snapshot { action } => snapshot(action, &mut database),
     };
 
-    report(reports.unwrap());
+    let mut reports = reports.unwrap_or_else(|e| fail(e));
+    if let Some((output, key)) = &plan_output {
+        write_plan(output, key, cli.report_host(), &database).unwrap_or_else(|e| fail(e));
+    }
+    if cli.changes_baseline() && !cli.dry_run {
+        reports.extend(anchor(&cli, &database).unwrap_or_else(|e| fail(e)));
+    }
+    let summary = Summary::new(started, examined, fingerprinter.bytes_hashed(), &reports);
+    // findings are notified, by email and to sinks other than stdout,
+    // as the notification window allows
+    let mailer = cli.mailer();
+    let notifies = mailer.is_some() || cli.report_to.iter().any(|sink| *sink != SinkSpec::Stdout);
+    let coordinated = matches!(
+        cli.command,
+        Command::VerifyAll { .. } | Command::Agent { once: true, .. }
+    );
+    let (due, keep) = if notifies && coordinated {
+        // if the state can't be read, everything goes
+        let (mut items, mut state) = coordinate(&database, cli.notify_window, &reports)
+            .unwrap_or_else(|e| {
+                reports.push(ReportItem::NotificationFailed {
+                    sink: "notifications".to_string(),
+                    message: e.to_string(),
+                });
+                (reports.clone(), None)
+            });
+        if let Some(mailer) = mailer {
+            let host = cli.report_host();
+            if let Some(failure) = mailer.notify(&RunReport::new(&host, &items, &summary)) {
+                // the findings stay unsent for the next run
+                state = None;
+                reports.push(failure.clone());
+                items.push(failure);
+            }
+        }
+        (Some(items), state)
+    } else {
+        (None, None)
+    };
+    let show_summary = matches!(
+        cli.command,
+        Command::Verify { .. } | Command::VerifyAll { .. } | Command::GitVerify { .. }
+    );
+    // an attestation written is all there is to say
+    if matches!(cli.command, Command::Attest { .. }) && reports.is_empty() {
+        return;
+    }
+    let blocked =
+        matches!(cli.command, Command::Hook { .. } | Command::Attest { .. }) && !reports.is_empty();
+    finish(&cli, reports, due, summary, show_summary);
+    if let Some(Err(e)) = keep.map(|state| database.set_notify_state(&state)) {
+        fail(e);
+    }
+    if blocked {
+        std::process::exit(1);
+    }
 }
@@ -0,0 +1,199 @@
+//! Which file system a path is on, from the mount table
+//!
+//! The table is read without touching the mounts themselves, so that
+//! files on a network file system can be recognised (and avoided) even
+//! when the mount has hung.
+
+use std::{
+    io,
+    path::{Path, PathBuf},
+};
+
+/// Network file systems, whose server may go away, and virtual file
+/// systems, whose contents are made up as they are read
+const REMOTE_FILE_SYSTEMS: &[&str] = &[
+    "nfs",
+    "nfs4",
+    "cifs",
+    "smb3",
+    "smbfs",
+    "afs",
+    "afpfs",
+    "webdav",
+    "davfs",
+    "ceph",
+    "glusterfs",
+    "9p",
+    "proc",
+    "procfs",
+    "sysfs",
+    "debugfs",
+    "tracefs",
+    "securityfs",
+    "configfs",
+    "cgroup",
+    "cgroup2",
+    "pstore",
+    "bpf",
+    "efivarfs",
+];
+
+/// True for network and virtual file systems, including any FUSE file
+/// system (whose daemon may hang just like a server)
+fn is_remote(fs_type: &str) -> bool {
+    REMOTE_FILE_SYSTEMS.contains(&fs_type) || fs_type.contains("fuse")
+}
+
+/// Mount points and the types of file system mounted on them
+#[derive(Default, Debug)]
+pub struct Mounts(Vec<(PathBuf, String)>);
+
+impl Mounts {
+    /// Read the mount table, where the platform provides one
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pub fn load() -> io::Result<Self> {
+        Ok(Mounts(parse_mounts(&std::fs::read_to_string(
+            "/proc/self/mounts",
+        )?)))
+    }
+
+    /// Read the mount table, where the platform provides one
+    #[cfg(any(target_os = "macos", target_os = "freebsd"))]
+    pub fn load() -> io::Result<Self> {
+        use std::ffi::CStr;
+
+        let mut entries: *mut libc::statfs = std::ptr::null_mut();
+        // SAFETY: MNT_NOWAIT returns the kernel's cached table, without
+        // asking any file system for fresh statistics
+        let count = unsafe { libc::getmntinfo(&mut entries, libc::MNT_NOWAIT) };
+        if count <= 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: getmntinfo returned count entries, which stay valid
+        // until the next call
+        let entries = unsafe { std::slice::from_raw_parts(entries, count as usize) };
+        Ok(Mounts(
+            entries
+                .iter()
+                .map(|entry| {
+                    // SAFETY: both names are NUL terminated
+                    let (point, fs_type) = unsafe {
+                        (
+                            CStr::from_ptr(entry.f_mntonname.as_ptr()),
+                            CStr::from_ptr(entry.f_fstypename.as_ptr()),
+                        )
+                    };
+                    (
+                        PathBuf::from(point.to_string_lossy().into_owned()),
+                        fs_type.to_string_lossy().into_owned(),
+                    )
+                })
+                .collect(),
+        ))
+    }
+
+    /// Read the mount table, where the platform provides one
+    #[cfg(not(any(
+        target_os = "linux",
+        target_os = "android",
+        target_os = "macos",
+        target_os = "freebsd"
+    )))]
+    pub fn load() -> io::Result<Self> {
+        Ok(Mounts::default())
+    }
+
+    /// Type of the file system a path is on, if known
+    ///
+    /// The path should be absolute and is not resolved, so that
+    /// nothing on the mount is touched: symlinks into another file
+    /// system aren't followed.
+    pub fn file_system(&self, path: &Path) -> Option<&str> {
+        // the last of several mounts on the same point hides the rest
+        self.0
+            .iter()
+            .filter(|(point, _)| path.starts_with(point))
+            .max_by_key(|(point, _)| point.components().count())
+            .map(|(_, fs_type)| fs_type.as_str())
+    }
+
+    /// Type of the network or virtual file system a path is on, if it
+    /// is on one
+    pub fn remote_file_system(&self, path: &Path) -> Option<&str> {
+        self.file_system(path).filter(|fs_type| is_remote(fs_type))
+    }
+}
+
+/// Mount points and file system types from `/proc/self/mounts`, in
+/// which spaces and the like in mount points are octal escapes
+#[cfg(any(target_os = "linux", target_os = "android", test))]
+fn parse_mounts(table: &str) -> Vec<(PathBuf, String)> {
+    let unescape = |field: &str| {
+        let bytes = field.as_bytes();
+        let mut unescaped = vec![];
+        let mut i = 0;
+        while i < bytes.len() {
+            let octal = bytes
+                .get(i + 1..i + 4)
+                .and_then(|digits| std::str::from_utf8(digits).ok())
+                .and_then(|digits| u8::from_str_radix(digits, 8).ok());
+            match (bytes[i], octal) {
+                (b'\\', Some(byte)) => {
+                    unescaped.push(byte);
+                    i += 4;
+                }
+                (byte, _) => {
+                    unescaped.push(byte);
+                    i += 1;
+                }
+            }
+        }
+        String::from_utf8_lossy(&unescaped).into_owned()
+    };
+
+    table
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace().skip(1);
+            let point = fields.next()?;
+            let fs_type = fields.next()?;
+            Some((PathBuf::from(unescape(point)), fs_type.to_string()))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+pub mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_remote_file_system() {
+        let mounts = Mounts(parse_mounts(
+            "/dev/sda1 / ext4 rw,relatime 0 0\n\
+             proc /proc proc rw,nosuid 0 0\n\
+             nas:/export /mnt/nas nfs4 rw,hard 0 0\n\
+             /dev/sdb1 /mnt/nas/cache ext4 rw 0 0\n\
+             sshfs#me@host: /home/me/My\\040Host fuse.sshfs rw 0 0\n",
+        ));
+        assert_eq!(mounts.file_system(Path::new("/etc/hosts")), Some("ext4"));
+        assert_eq!(mounts.remote_file_system(Path::new("/etc/hosts")), None);
+        assert_eq!(
+            mounts.remote_file_system(Path::new("/proc/self/status")),
+            Some("proc")
+        );
+        assert_eq!(
+            mounts.remote_file_system(Path::new("/mnt/nas/data")),
+            Some("nfs4")
+        );
+        assert_eq!(
+            mounts.remote_file_system(Path::new("/mnt/nas/cache/x")),
+            None
+        );
+        assert_eq!(mounts.remote_file_system(Path::new("/mnt/nasty")), None);
+        assert_eq!(
+            mounts.remote_file_system(Path::new("/home/me/My Host/notes")),
+            Some("fuse.sshfs")
+        );
+    }
+}
@@ -0,0 +1,153 @@
+//! Coordinating notifications across scans
+//!
+//! With a notification window, a finding (a kind of item for a path)
+//! notified once is not notified again until the window has passed,
+//! and new findings are held back and sent together once the first of
+//! them has waited the window, so a flapping file doesn't page someone
+//! on every scan. Critical findings are sent straight away, along with
+//! any held, and held findings that a later scan no longer finds are
+//! dropped unsent. What has been notified and what is held is kept in
+//! the database between scans, and applies to every place findings
+//! are sent but stdout: email, webhooks, syslog and files alike.
+
+use crate::report::{ReportItem, Severity};
+
+use serde_derive::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    time::Duration,
+};
+
+/// A finding held back for the next batch
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Held {
+    /// When it was first found (seconds since the unix epoch)
+    pub found: u64,
+    pub item: ReportItem,
+}
+
+/// What has been notified and what is waiting to be
+#[derive(Serialize, Deserialize, Default)]
+pub struct NotifyState {
+    /// When each finding was last notified (seconds since the unix
+    /// epoch), by key
+    #[serde(default)]
+    pub notified: BTreeMap<String, u64>,
+
+    /// Findings held for the next batch, in the order found
+    #[serde(default)]
+    pub held: Vec<Held>,
+}
+
+/// Key identifying an item as the same finding in another scan: its
+/// kind and path, but not details (like sizes) which flap with it
+fn key(item: &ReportItem) -> String {
+    match item.path() {
+        Some(path) => format!("{} {}", item.kind(), path.display()),
+        None => item.kind(),
+    }
+}
+
+impl NotifyState {
+    /// The findings to notify now, given a scan's findings at `now`
+    /// (seconds since the unix epoch) and the window
+    ///
+    /// Findings notified within the window, or already held, are
+    /// dropped; the rest are held. Findings held but not found this
+    /// time have resolved, and are dropped too. The held findings are
+    /// released (and noted as notified) once the first has waited the
+    /// window, or when any is critical.
+    pub fn admit(
+        &mut self,
+        findings: &[ReportItem],
+        now: u64,
+        window: Duration,
+    ) -> Vec<ReportItem> {
+        let window = window.as_secs();
+        self.notified.retain(|_, notified| *notified + window > now);
+        let found: BTreeSet<String> = findings.iter().map(key).collect();
+        self.held.retain(|held| found.contains(&key(&held.item)));
+
+        for item in findings {
+            let id = key(item);
+            let held = self.held.iter().any(|held| key(&held.item) == id);
+            if !held && !self.notified.contains_key(&id) {
+                self.held.push(Held {
+                    found: now,
+                    item: item.clone(),
+                });
+            }
+        }
+
+        let waited = self
+            .held
+            .first()
+            .is_some_and(|held| held.found + window <= now);
+        let critical = self
+            .held
+            .iter()
+            .any(|held| held.item.severity() == Severity::Critical);
+        if !waited && !critical {
+            return vec![];
+        }
+        let released: Vec<ReportItem> = self.held.drain(..).map(|held| held.item).collect();
+        for item in &released {
+            self.notified.insert(key(item), now);
+        }
+        released
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+
+    use super::*;
+    use std::path::{Path, PathBuf};
+
+    #[test]
+    fn test_admit() {
+        let window = Duration::from_secs(600);
+        let missing = |path: &str| ReportItem::FileMissing {
+            path: PathBuf::from(path),
+        };
+        let shadow = vec![missing("/etc/shadow")];
+        let both = vec![missing("/etc/shadow"), missing("/etc/hosts")];
+        let mut state = NotifyState::default();
+
+        // held until the first has waited the window, then batched
+        assert!(state.admit(&shadow, 1000, window).is_empty());
+        assert!(state.admit(&both, 1300, window).is_empty());
+        assert_eq!(state.admit(&both, 1600, window).len(), 2);
+
+        // then not repeated until the window has passed again
+        assert!(state.admit(&shadow, 1900, window).is_empty());
+        assert!(state.held.is_empty());
+        assert!(state.admit(&shadow, 2200, window).is_empty());
+        assert_eq!(state.held.len(), 1);
+
+        // critical findings go straight away, with those held
+        let anomaly = vec![
+            missing("/etc/shadow"),
+            ReportItem::AnomalousChangeVolume {
+                files: 400,
+                examined: 1000,
+                normal: 0.5,
+            },
+        ];
+        assert_eq!(state.admit(&anomaly, 2300, window).len(), 2);
+        assert!(state.admit(&anomaly, 2400, window).is_empty());
+
+        // held, but resolved by the time the window has passed
+        let hosts = vec![missing("/etc/hosts")];
+        assert!(state.admit(&both, 3000, window).is_empty());
+        assert_eq!(state.held.len(), 2);
+        let released = state.admit(&hosts, 3600, window);
+        assert_eq!(released.len(), 1);
+        assert_eq!(released[0].path(), Some(Path::new("/etc/hosts")));
+
+        // without a window, everything goes at once, every time
+        let mut state = NotifyState::default();
+        assert_eq!(state.admit(&shadow, 1000, Duration::ZERO).len(), 1);
+        assert_eq!(state.admit(&shadow, 1000, Duration::ZERO).len(), 1);
+    }
+}
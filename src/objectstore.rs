@@ -0,0 +1,289 @@
+//! Putting and getting whole objects: S3, plain HTTP(S) or local files
+//!
+//! `s3://bucket/key` URLs are signed (AWS signature version 4) with
+//! credentials from the usual environment variables:
+//! `AWS_ACCESS_KEY_ID`, `AWS_SECRET_ACCESS_KEY`, optionally
+//! `AWS_SESSION_TOKEN`, and `AWS_REGION` (default `us-east-1`). Set
+//! `AWS_ENDPOINT_URL` to use another S3 compatible store, addressed
+//! path-style. `http://` and `https://` URLs (e.g. presigned) are
+//! used as is and anything else is a local file path.
+
+use crate::error::FimblError;
+
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+use std::{env, io::Read, time::SystemTime};
+
+/// Where an object lives
+#[derive(PartialEq, Eq, Debug)]
+enum Location<'a> {
+    S3 { bucket: &'a str, key: &'a str },
+    Http(&'a str),
+    File(&'a str),
+}
+
+impl<'a> Location<'a> {
+    fn parse(url: &'a str) -> Result<Self, FimblError> {
+        if let Some(rest) = url.strip_prefix("s3://") {
+            match rest.split_once('/') {
+                Some((bucket, key)) if !bucket.is_empty() && !key.is_empty() => {
+                    Ok(Location::S3 { bucket, key })
+                }
+                _ => Err(FimblError::ObjectStoreError(format!(
+                    "expected s3://bucket/key, not {url}"
+                ))),
+            }
+        } else if url.starts_with("http://") || url.starts_with("https://") {
+            Ok(Location::Http(url))
+        } else {
+            Ok(Location::File(url))
+        }
+    }
+}
+
+/// Credentials and region for S3
+struct S3Config {
+    access_key: String,
+    secret_key: String,
+    session_token: Option<String>,
+    region: String,
+    endpoint: Option<String>,
+}
+
+impl S3Config {
+    fn from_env() -> Result<Self, FimblError> {
+        let required = |name| {
+            env::var(name).map_err(|_| FimblError::ObjectStoreError(format!("{name} is not set")))
+        };
+        Ok(S3Config {
+            access_key: required("AWS_ACCESS_KEY_ID")?,
+            secret_key: required("AWS_SECRET_ACCESS_KEY")?,
+            session_token: env::var("AWS_SESSION_TOKEN").ok(),
+            region: env::var("AWS_REGION").unwrap_or_else(|_| "us-east-1".to_string()),
+            endpoint: env::var("AWS_ENDPOINT_URL").ok(),
+        })
+    }
+}
+
+fn hmac_sha256(key: &[u8], data: &str) -> Vec<u8> {
+    let mut mac =
+        <Hmac<Sha256> as Mac>::new_from_slice(key).expect("HMAC accepts keys of any size");
+    mac.update(data.as_bytes());
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// Key for signing requests on a date (YYYYMMDD)
+fn signing_key(secret_key: &str, date: &str, region: &str, service: &str) -> Vec<u8> {
+    let key = hmac_sha256(format!("AWS4{secret_key}").as_bytes(), date);
+    let key = hmac_sha256(&key, region);
+    let key = hmac_sha256(&key, service);
+    hmac_sha256(&key, "aws4_request")
+}
+
+/// Percent-encode a path, leaving unreserved characters and slashes
+fn uri_encode_path(path: &str) -> String {
+    path.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' | b'/' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{b:02X}"),
+        })
+        .collect()
+}
+
+/// Timestamp in the basic ISO 8601 format used by signatures
+fn amz_date(time: SystemTime) -> String {
+    humantime::format_rfc3339_seconds(time)
+        .to_string()
+        .replace(['-', ':'], "")
+}
+
+/// A signed S3 request ready to send
+struct SignedRequest {
+    url: String,
+    headers: Vec<(String, String)>,
+}
+
+/// Sign a request for an object (for an empty body when getting)
+fn sign_s3(
+    config: &S3Config,
+    method: &str,
+    bucket: &str,
+    key: &str,
+    body: &[u8],
+    time: SystemTime,
+) -> SignedRequest {
+    let (host, path, base) = match &config.endpoint {
+        Some(endpoint) => {
+            let endpoint = endpoint.trim_end_matches('/');
+            let host = endpoint
+                .split_once("://")
+                .map_or(endpoint, |(_, host)| host)
+                .to_string();
+            let path = uri_encode_path(&format!("/{bucket}/{key}"));
+            (host, path, endpoint.to_string())
+        }
+        None => {
+            let host = format!("{bucket}.s3.{}.amazonaws.com", config.region);
+            let path = uri_encode_path(&format!("/{key}"));
+            (host.clone(), path, format!("https://{host}"))
+        }
+    };
+
+    let timestamp = amz_date(time);
+    let date = &timestamp[..8];
+    let payload_hash = hex::encode(Sha256::digest(body));
+
+    let mut headers = vec![
+        ("host".to_string(), host),
+        ("x-amz-content-sha256".to_string(), payload_hash.clone()),
+        ("x-amz-date".to_string(), timestamp.clone()),
+    ];
+    if let Some(token) = &config.session_token {
+        headers.push(("x-amz-security-token".to_string(), token.clone()));
+    }
+
+    let canonical_headers: String = headers.iter().map(|(k, v)| format!("{k}:{v}\n")).collect();
+    let signed_headers = headers
+        .iter()
+        .map(|(k, _)| k.as_str())
+        .collect::<Vec<_>>()
+        .join(";");
+    let canonical_request =
+        format!("{method}\n{path}\n\n{canonical_headers}\n{signed_headers}\n{payload_hash}");
+
+    let scope = format!("{date}/{}/s3/aws4_request", config.region);
+    let string_to_sign = format!(
+        "AWS4-HMAC-SHA256\n{timestamp}\n{scope}\n{}",
+        hex::encode(Sha256::digest(canonical_request.as_bytes()))
+    );
+    let signature = hex::encode(hmac_sha256(
+        &signing_key(&config.secret_key, date, &config.region, "s3"),
+        &string_to_sign,
+    ));
+
+    headers.push((
+        "authorization".to_string(),
+        format!(
+            "AWS4-HMAC-SHA256 Credential={}/{scope}, SignedHeaders={signed_headers}, Signature={signature}",
+            config.access_key
+        ),
+    ));
+    // host is set by the HTTP client from the URL
+    headers.remove(0);
+
+    SignedRequest {
+        url: format!("{base}{path}"),
+        headers,
+    }
+}
+
+/// Build a request, signing it if it addresses S3
+fn request(method: &str, location: &Location, body: &[u8]) -> Result<ureq::Request, FimblError> {
+    match location {
+        Location::S3 { bucket, key } => {
+            let config = S3Config::from_env()?;
+            let signed = sign_s3(&config, method, bucket, key, body, SystemTime::now());
+            Ok(signed
+                .headers
+                .iter()
+                .fold(ureq::request(method, &signed.url), |request, (k, v)| {
+                    request.set(k, v)
+                }))
+        }
+        Location::Http(url) => Ok(ureq::request(method, url)),
+        Location::File(_) => unreachable!("files are not fetched over HTTP"),
+    }
+}
+
+/// Store an object at the URL
+pub fn put_object(url: &str, body: &[u8]) -> Result<(), FimblError> {
+    match Location::parse(url)? {
+        Location::File(path) => Ok(std::fs::write(path, body)?),
+        location => {
+            request("PUT", &location, body)?.send_bytes(body)?;
+            Ok(())
+        }
+    }
+}
+
+/// Fetch the object at the URL
+pub fn get_object(url: &str) -> Result<Vec<u8>, FimblError> {
+    match Location::parse(url)? {
+        Location::File(path) => Ok(std::fs::read(path)?),
+        location => {
+            let mut body = vec![];
+            request("GET", &location, b"")?
+                .call()?
+                .into_reader()
+                .read_to_end(&mut body)?;
+            Ok(body)
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+
+    use super::*;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    #[test]
+    fn test_locations() {
+        assert_eq!(
+            Location::parse("s3://bucket/fimbl/web1.baseline").unwrap(),
+            Location::S3 {
+                bucket: "bucket",
+                key: "fimbl/web1.baseline"
+            }
+        );
+        assert!(Location::parse("s3://bucket").is_err());
+        assert_eq!(
+            Location::parse("https://example.com/b").unwrap(),
+            Location::Http("https://example.com/b")
+        );
+        assert_eq!(Location::parse("/tmp/b").unwrap(), Location::File("/tmp/b"));
+    }
+
+    #[test]
+    fn test_signing_key_derivation() {
+        // Example from the AWS signature version 4 documentation
+        let key = signing_key(
+            "wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY",
+            "20120215",
+            "us-east-1",
+            "iam",
+        );
+        assert_eq!(
+            hex::encode(key),
+            "f4780e2d9f65fa895f9c67b32ce1baf0b0d8a43505a000a1a9e090d414db404d"
+        );
+    }
+
+    #[test]
+    fn test_signed_request() {
+        let config = S3Config {
+            access_key: "AKIDEXAMPLE".to_string(),
+            secret_key: "secret".to_string(),
+            session_token: None,
+            region: "eu-west-2".to_string(),
+            endpoint: None,
+        };
+        let time = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let signed = sign_s3(&config, "PUT", "bucket", "a b/c", b"body", time);
+
+        assert_eq!(
+            signed.url,
+            "https://bucket.s3.eu-west-2.amazonaws.com/a%20b/c"
+        );
+        let authorization = &signed.headers.last().unwrap().1;
+        assert!(authorization.starts_with(
+            "AWS4-HMAC-SHA256 Credential=AKIDEXAMPLE/20231114/eu-west-2/s3/aws4_request, \
+             SignedHeaders=host;x-amz-content-sha256;x-amz-date, Signature="
+        ));
+        assert!(signed
+            .headers
+            .contains(&("x-amz-date".to_string(), "20231114T221320Z".to_string())));
+    }
+}
@@ -0,0 +1,612 @@
+//! Rendering reports for people
+//!
+//! Items are grouped by the directory of the file they concern and,
+//! within that, by kind, so that large reports can be scanned. On a
+//! terminal (unless `NO_COLOR` is set) changes are shown in red and
+//! informational items dimmed.
+//!
+//! Reports can instead be output as JSON, as CSV (for spreadsheets
+//! and audit tooling), as Common Event Format lines (for SIEMs) or as
+//! osquery result log lines (for fleets collecting osquery's logs), and
+//! also written to a file, for hosts where the stdout of cron jobs is
+//! discarded.
+
+use crate::{
+    agent::RunReport,
+    error::FimblError,
+    report::{ReportItem, Severity, Summary},
+    schedule::{civil, weekday},
+};
+
+use std::{
+    collections::BTreeMap,
+    fs::{create_dir_all, OpenOptions},
+    io::{IsTerminal, Write},
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+const BOLD: &str = "\x1b[1m";
+const DIM: &str = "\x1b[2m";
+const RED: &str = "\x1b[31m";
+const BOLD_RED: &str = "\x1b[1;31m";
+const RESET: &str = "\x1b[0m";
+
+/// Heading for items that don't concern a single file
+const OTHER_GROUP: &str = "(other)";
+
+/// Format of reports
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum Format {
+    /// Lines grouped by directory, for people
+    Text,
+    /// The same JSON object that agents push
+    Json,
+    /// One row per item (path, kind, severity, hashes and time)
+    Csv,
+    /// One Common Event Format line per item, for SIEM ingestion
+    Cef,
+    /// One osquery result log line per item, with file_events columns
+    Osquery,
+}
+
+impl Format {
+    /// Format for an output file, from its extension
+    fn for_path(path: &Path) -> Format {
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("json") => Format::Json,
+            Some("csv") => Format::Csv,
+            Some("cef") => Format::Cef,
+            _ => Format::Text,
+        }
+    }
+}
+
+/// Whether to colour output written to stdout: only on a terminal and
+/// only if `NO_COLOR` isn't set (to anything non-empty)
+pub fn use_color() -> bool {
+    let no_color = std::env::var_os("NO_COLOR").is_some_and(|v| !v.is_empty());
+    !no_color && std::io::stdout().is_terminal()
+}
+
+/// Render report items as lines grouped under directory headings, each
+/// ending with the item's code
+pub fn render(items: &[ReportItem], color: bool) -> String {
+    let paint = |style: &str, text: &str| {
+        if color {
+            format!("{style}{text}{RESET}")
+        } else {
+            text.to_string()
+        }
+    };
+
+    let mut groups: BTreeMap<Option<PathBuf>, Vec<&ReportItem>> = BTreeMap::new();
+    for item in items {
+        let directory = item.path().map(|p| p.parent().unwrap_or(p).to_path_buf());
+        groups.entry(directory).or_default().push(item);
+    }
+
+    let mut out = String::new();
+    // items concerning no file go last
+    let (other, by_directory): (Vec<_>, Vec<_>) =
+        groups.into_iter().partition(|(dir, _)| dir.is_none());
+    for (directory, mut group) in by_directory.into_iter().chain(other) {
+        let heading = directory
+            .as_deref()
+            .map(Path::display)
+            .map(|d| d.to_string())
+            .unwrap_or_else(|| OTHER_GROUP.to_string());
+        out.push_str(&paint(BOLD, &heading));
+        out.push('\n');
+
+        group.sort_by_key(|item| (std::cmp::Reverse(item.severity()), item.kind()));
+        for item in group {
+            let code = item.code();
+            let line = match item.severity() {
+                Severity::Critical => paint(BOLD_RED, &format!("- CRITICAL {item} [{code}]")),
+                Severity::Warning => paint(RED, &format!("- {item} [{code}]")),
+                Severity::Info => paint(DIM, &format!("- {item} [{code}]")),
+            };
+            out.push_str("  ");
+            out.push_str(&line);
+            out.push('\n');
+        }
+    }
+    out
+}
+
+/// Columns of CSV output
+const CSV_HEADER: &str = "path,kind,severity,old_hash,new_hash,detected_at,code";
+
+/// Quote a CSV field if it needs it
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+/// Recorded and current content hashes reported with an item
+fn hashes(item: &ReportItem) -> (Option<&str>, Option<&str>) {
+    match item {
+        ReportItem::FileContentChanged {
+            recorded_hash,
+            current_hash,
+            ..
+        }
+        | ReportItem::FileSizeChanged {
+            recorded_hash,
+            current_hash,
+            ..
+        } => (recorded_hash.as_deref(), current_hash.as_deref()),
+        _ => (None, None),
+    }
+}
+
+/// Render a run's items as CSV, with a header row
+pub fn render_csv(run: &RunReport) -> String {
+    let detected_at =
+        humantime::format_rfc3339_seconds(UNIX_EPOCH + Duration::from_secs(run.generated))
+            .to_string();
+
+    let mut out = format!("{CSV_HEADER}\n");
+    for item in run.items {
+        let path = item
+            .path()
+            .map(|p| p.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let (old_hash, new_hash) = hashes(item);
+        let row = [
+            path.as_str(),
+            &item.kind(),
+            item.severity().name(),
+            old_hash.unwrap_or_default(),
+            new_hash.unwrap_or_default(),
+            &detected_at,
+            item.code(),
+        ]
+        .map(csv_field);
+        out.push_str(&row.join(","));
+        out.push('\n');
+    }
+    out
+}
+
+/// Escape a CEF header field, newlines included so that it stays on
+/// its line
+fn cef_header(field: &str) -> String {
+    field
+        .replace('\\', "\\\\")
+        .replace('|', "\\|")
+        .replace('\r', "\\r")
+        .replace('\n', "\\n")
+}
+
+/// Escape a CEF extension value
+fn cef_value(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('=', "\\=")
+        .replace('\r', "\\r")
+        .replace('\n', "\\n")
+}
+
+/// CEF severity (0-10) of an item
+fn cef_severity(severity: Severity) -> u8 {
+    match severity {
+        Severity::Info => 3,
+        Severity::Warning => 6,
+        Severity::Critical => 9,
+    }
+}
+
+/// Render a run's items as Common Event Format lines, with fimbl as
+/// device vendor and product, the item code as signature and its kind
+/// as category
+pub fn render_cef(run: &RunReport) -> String {
+    let mut out = String::new();
+    for item in run.items {
+        let mut extension = vec![
+            format!("rt={}", run.generated * 1000),
+            format!("dvchost={}", cef_value(run.host)),
+            format!("cat={}", cef_value(&item.kind())),
+        ];
+        if let Some(path) = item.path() {
+            extension.push(format!("filePath={}", cef_value(&path.to_string_lossy())));
+        }
+        let (old_hash, new_hash) = hashes(item);
+        if let Some(hash) = old_hash {
+            extension.push(format!("oldFileHash={}", cef_value(hash)));
+        }
+        if let Some(hash) = new_hash {
+            extension.push(format!("fileHash={}", cef_value(hash)));
+        }
+
+        out.push_str(&format!(
+            "CEF:0|fimbl|fimbl|{}|{}|{}|{}|{}\n",
+            cef_header(env!("CARGO_PKG_VERSION")),
+            cef_header(item.code()),
+            cef_header(&item.to_string()),
+            cef_severity(item.severity()),
+            extension.join(" ")
+        ));
+    }
+    out
+}
+
+/// Name of the query osquery result log lines are from
+const OSQUERY_NAME: &str = "fimbl_file_events";
+
+/// The file_events action (those of osquery's file integrity
+/// monitoring) an item amounts to, if it concerns a file
+fn osquery_action(item: &ReportItem) -> &'static str {
+    match item {
+        ReportItem::FileNotTracked { .. } => "CREATED",
+        ReportItem::FileMissing { .. } => "DELETED",
+        ReportItem::FileMoved { .. } => "MOVED_TO",
+        ReportItem::ImmutableFlagRemoved { .. }
+        | ReportItem::FileFlagsChanged { .. }
+        | ReportItem::XattrAdded { .. }
+        | ReportItem::XattrChanged { .. }
+        | ReportItem::XattrRemoved { .. } => "ATTRIBUTES_MODIFIED",
+        _ if item.path().is_some() => "UPDATED",
+        _ => "",
+    }
+}
+
+/// A time as osquery logs it, e.g. `Tue May  1 06:07:08 2024 UTC`
+fn calendar_time(seconds: u64) -> String {
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+    const WEEKDAYS: [&str; 7] = ["Sun", "Mon", "Tue", "Wed", "Thu", "Fri", "Sat"];
+    let days = (seconds / 86400) as i64;
+    let (year, month, day) = civil(days);
+    let time = seconds % 86400;
+    format!(
+        "{} {} {day:>2} {:02}:{:02}:{:02} {year} UTC",
+        WEEKDAYS[weekday(days) as usize],
+        MONTHS[month as usize - 1],
+        time / 3600,
+        time / 60 % 60,
+        time % 60
+    )
+}
+
+/// Render a run's items as osquery (event format) result log lines,
+/// each item's columns those of osquery's file_events table that fimbl
+/// knows (as strings, as osquery logs them) and fimbl's own
+///
+/// fimbl's hashes are SHA3-256, so osquery's hash columns are empty.
+pub fn render_osquery(run: &RunReport) -> String {
+    let mut out = String::new();
+    for (counter, item) in run.items.iter().enumerate() {
+        let path = item
+            .path()
+            .map(|p| p.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let (old_hash, new_hash) = hashes(item);
+        let columns = serde_json::json!({
+            "target_path": path,
+            "category": "fimbl",
+            "action": osquery_action(item),
+            "transaction_id": "0",
+            "md5": "",
+            "sha1": "",
+            "sha256": "",
+            "hashed": "0",
+            "time": run.generated.to_string(),
+            "code": item.code(),
+            "kind": item.kind(),
+            "severity": item.severity().name(),
+            "message": item.to_string(),
+            "recorded_hash": old_hash.unwrap_or_default(),
+            "current_hash": new_hash.unwrap_or_default(),
+        });
+        let line = serde_json::json!({
+            "name": OSQUERY_NAME,
+            "hostIdentifier": run.host,
+            "calendarTime": calendar_time(run.generated),
+            "unixTime": run.generated,
+            "epoch": 0,
+            "counter": counter,
+            "numerics": false,
+            "columns": columns,
+            "action": "added",
+        });
+        out.push_str(&line.to_string());
+        out.push('\n');
+    }
+    out
+}
+
+/// Render a run's report in a format (text optionally coloured and
+/// followed by the summary)
+pub fn render_run(run: &RunReport, format: Format, color: bool, show_summary: bool) -> String {
+    match format {
+        Format::Text => {
+            let mut text = render(run.items, color);
+            if show_summary {
+                text.push_str(&format!("Summary: {}\n", run.summary));
+            }
+            text
+        }
+        Format::Json => {
+            let mut json = serde_json::to_string(run).unwrap();
+            json.push('\n');
+            json
+        }
+        Format::Csv => render_csv(run),
+        Format::Cef => render_cef(run),
+        Format::Osquery => render_osquery(run),
+    }
+}
+
+/// Nagios plugin exit statuses
+const NAGIOS_OK: i32 = 0;
+const NAGIOS_WARNING: i32 = 1;
+const NAGIOS_CRITICAL: i32 = 2;
+const NAGIOS_UNKNOWN: i32 = 3;
+
+/// Nagios plugin output for a verification, with its exit status
+///
+/// The status line counts findings, performance data follows the `|`
+/// and the findings themselves go in the long output.
+pub fn nagios(items: &[ReportItem], summary: &Summary) -> (String, i32) {
+    let count = |severity| items.iter().filter(|i| i.severity() == severity).count();
+    let (critical, warning) = (count(Severity::Critical), count(Severity::Warning));
+    let (status, name) = if critical > 0 {
+        (NAGIOS_CRITICAL, "CRITICAL")
+    } else if warning > 0 {
+        (NAGIOS_WARNING, "WARNING")
+    } else {
+        (NAGIOS_OK, "OK")
+    };
+
+    let text = if critical + warning == 0 {
+        format!("{} files unchanged", summary.files_examined)
+    } else {
+        format!(
+            "{} critical, {} warning findings in {} files",
+            critical, warning, summary.files_examined
+        )
+    };
+    let mut out = format!(
+        "FIMBL {name} - {text} | files={} critical={critical} warning={warning} \
+         bytes={}B time={:.3}s\n",
+        summary.files_examined, summary.bytes_hashed, summary.elapsed_seconds
+    );
+    for item in items {
+        out.push_str(&format!("{item} [{}]\n", item.code()));
+    }
+    (out, status)
+}
+
+/// Nagios plugin output when the check itself could not be made
+pub fn nagios_unknown(error: &FimblError) -> (String, i32) {
+    (format!("FIMBL UNKNOWN - {error}\n"), NAGIOS_UNKNOWN)
+}
+
+/// Path of an output file, replacing `{date}` and `{time}` in the
+/// template with the (UTC) date and time, e.g. for per-run files like
+/// `/var/log/fimbl/verify-{date}.json`
+pub fn output_path(template: &str, now: SystemTime) -> PathBuf {
+    let timestamp = humantime::format_rfc3339_seconds(now).to_string();
+    let date = &timestamp[..10];
+    let time = timestamp[11..19].replace(':', "-");
+    PathBuf::from(template.replace("{date}", date).replace("{time}", &time))
+}
+
+/// Write a run's report to the output file named by the template, in
+/// the format given or, if none is, as JSON if it ends in `.json` (one
+/// object per line when appending), CSV if it ends in `.csv` and as
+/// uncoloured text otherwise
+///
+/// Missing parent directories are created.
+pub fn write_file(
+    template: &str,
+    format: Option<Format>,
+    append: bool,
+    run: &RunReport,
+    show_summary: bool,
+) -> Result<(), FimblError> {
+    let path = output_path(template, SystemTime::now());
+    let format = format.unwrap_or_else(|| Format::for_path(&path));
+    let contents = render_run(run, format, false, show_summary);
+
+    let write = || {
+        if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+            create_dir_all(parent)?;
+        }
+        OpenOptions::new()
+            .create(true)
+            .write(true)
+            .append(append)
+            .truncate(!append)
+            .open(&path)?
+            .write_all(contents.as_bytes())
+    };
+    write().map_err(|e| FimblError::OutputError(path.clone(), e))
+}
+
+#[cfg(test)]
+pub mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_render_groups_by_directory_and_kind() {
+        let items = vec![
+            ReportItem::FileNotTracked {
+                path: PathBuf::from("/etc/motd"),
+            },
+            ReportItem::HookFailed {
+                hook: "on-change".to_string(),
+                message: "exit status: 1".to_string(),
+            },
+            ReportItem::FileContentChanged {
+                path: PathBuf::from("/usr/bin/ls"),
+                recorded_hash: None,
+                current_hash: None,
+                blocks: None,
+                fuzzy_distance: None,
+            },
+            ReportItem::ImmutableFlagRemoved {
+                path: PathBuf::from("/etc/passwd"),
+            },
+        ];
+
+        assert_eq!(
+            render(&items, false),
+            "/etc\n\
+             \x20 - CRITICAL immutable flag removed: /etc/passwd [A001]\n\
+             \x20 - file is untracked: /etc/motd [C002]\n\
+             /usr/bin\n\
+             \x20 - file content changed: /usr/bin/ls [F001]\n\
+             (other)\n\
+             \x20 - on-change hook failed: exit status: 1 [E004]\n"
+        );
+        assert!(render(&items, true)
+            .contains("\x1b[31m- file content changed: /usr/bin/ls [F001]\x1b[0m"));
+    }
+
+    #[test]
+    fn test_render_csv() {
+        let items = vec![
+            ReportItem::FileContentChanged {
+                path: PathBuf::from("/etc/a,b"),
+                recorded_hash: Some("00ff".to_string()),
+                current_hash: Some("ff00".to_string()),
+                blocks: None,
+                fuzzy_distance: None,
+            },
+            ReportItem::FileMissing {
+                path: PathBuf::from("/etc/hosts"),
+            },
+        ];
+        let summary = crate::report::Summary::new(std::time::Instant::now(), 2, 0, &items);
+        let run = RunReport {
+            host: "web1",
+            generated: 1714543628,
+            items: &items,
+            summary: &summary,
+        };
+        assert_eq!(
+            render_csv(&run),
+            "path,kind,severity,old_hash,new_hash,detected_at,code\n\
+             \"/etc/a,b\",file-content-changed,warning,00ff,ff00,2024-05-01T06:07:08Z,F001\n\
+             /etc/hosts,file-missing,warning,,,2024-05-01T06:07:08Z,F003\n"
+        );
+    }
+
+    #[test]
+    fn test_render_cef() {
+        let items = vec![ReportItem::ImmutableFlagRemoved {
+            path: PathBuf::from("/etc/a=b"),
+        }];
+        let summary = crate::report::Summary::new(std::time::Instant::now(), 1, 0, &items);
+        let run = RunReport {
+            host: "web1",
+            generated: 1714543628,
+            items: &items,
+            summary: &summary,
+        };
+        assert_eq!(
+            render_cef(&run),
+            format!(
+                "CEF:0|fimbl|fimbl|{}|A001|immutable flag removed: /etc/a=b|9|\
+                 rt=1714543628000 dvchost=web1 cat=immutable-flag-removed filePath=/etc/a\\=b\n",
+                env!("CARGO_PKG_VERSION")
+            )
+        );
+
+        // a file named to forge a line of its own can't
+        let items = vec![ReportItem::FileMissing {
+            path: PathBuf::from("/tmp/x\r\nCEF:0|forged"),
+        }];
+        let run = RunReport {
+            items: &items,
+            ..run
+        };
+        let rendered = render_cef(&run);
+        assert_eq!(rendered.lines().count(), 1, "{rendered}");
+        assert!(
+            rendered.contains("/tmp/x\\r\\nCEF:0\\|forged|"),
+            "{rendered}"
+        );
+        assert!(
+            rendered.contains("filePath=/tmp/x\\r\\nCEF:0|forged"),
+            "{rendered}"
+        );
+    }
+
+    #[test]
+    fn test_render_osquery() {
+        let items = vec![ReportItem::FileMissing {
+            path: PathBuf::from("/etc/passwd"),
+        }];
+        let summary = crate::report::Summary::new(std::time::Instant::now(), 1, 0, &items);
+        let run = RunReport {
+            host: "web1",
+            generated: 1714543628,
+            items: &items,
+            summary: &summary,
+        };
+        let output = render_osquery(&run);
+        let line: serde_json::Value = serde_json::from_str(output.trim_end()).unwrap();
+        assert_eq!(line["hostIdentifier"], "web1");
+        assert_eq!(line["calendarTime"], "Wed May  1 06:07:08 2024 UTC");
+        assert_eq!(line["unixTime"], 1714543628);
+        assert_eq!(line["columns"]["target_path"], "/etc/passwd");
+        assert_eq!(line["columns"]["action"], "DELETED");
+        assert_eq!(line["columns"]["time"], "1714543628");
+        assert_eq!(line["columns"]["code"], "F003");
+    }
+
+    #[test]
+    fn test_nagios_status() {
+        let summary = Summary {
+            files_examined: 12,
+            bytes_hashed: 4096,
+            items: BTreeMap::new(),
+            elapsed_seconds: 0.25,
+            bytes_per_second: 16384.0,
+        };
+        assert_eq!(
+            nagios(&[], &summary),
+            (
+                "FIMBL OK - 12 files unchanged | files=12 critical=0 warning=0 bytes=4096B time=0.250s\n"
+                    .to_string(),
+                0
+            )
+        );
+
+        let items = vec![ReportItem::FileMissing {
+            path: PathBuf::from("/etc/hosts"),
+        }];
+        let (output, status) = nagios(&items, &summary);
+        assert_eq!(status, 1);
+        assert!(output.starts_with("FIMBL WARNING - 0 critical, 1 warning findings in 12 files |"));
+        assert!(output.ends_with("\nfile is missing: /etc/hosts [F003]\n"));
+
+        let items = vec![ReportItem::ImmutableFlagRemoved {
+            path: PathBuf::from("/etc/passwd"),
+        }];
+        assert_eq!(nagios(&items, &summary).1, 2);
+        assert_eq!(nagios_unknown(&FimblError::DatabaseEncrypted).1, 3);
+    }
+
+    #[test]
+    fn test_output_path_timestamps() {
+        let now = humantime::parse_rfc3339("2024-05-01T06:07:08Z").unwrap();
+        assert_eq!(
+            output_path("/var/log/fimbl/verify-{date}.json", now),
+            PathBuf::from("/var/log/fimbl/verify-2024-05-01.json")
+        );
+        assert_eq!(
+            output_path("run-{date}T{time}.log", now),
+            PathBuf::from("run-2024-05-01T06-07-08.log")
+        );
+    }
+}
@@ -0,0 +1,238 @@
+//! Plans of changes to the records, to review and apply later
+//!
+//! A plan is what a command changing the records (add, accept-all and
+//! so on) would change, worked out on a dry run: the files it would
+//! add, accept or remove, with the fingerprints it would record and
+//! the record each replaces. As JSON it can be reviewed, signed with
+//! the shared key (the plan's JSON signed as written, like a manifest)
+//! and applied later or on another host. Applying refuses a plan that
+//! isn't signed with the key, or that is made stale by records changed
+//! since.
+
+use crate::{agent::SigningKey, error::FimblError, fingerprint::Fingerprint};
+
+use serde_json::value::RawValue;
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+/// Version of the plan format
+const FORMAT_VERSION: u32 = 1;
+
+/// A plan as written, signed
+#[derive(Serialize, Deserialize)]
+struct Signed<'a> {
+    /// The plan's JSON, exactly as the signature was made
+    #[serde(borrow)]
+    plan: &'a RawValue,
+
+    /// Hex signature of the plan's JSON
+    signature: String,
+}
+
+/// A change to the record of a file
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone)]
+#[serde(tag = "action", rename_all = "kebab-case")]
+#[allow(clippy::large_enum_variant)]
+pub enum Change {
+    /// Start tracking an untracked file
+    Track {
+        path: PathBuf,
+        fingerprint: Fingerprint,
+    },
+    /// Record a tracked file's fingerprint anew
+    Accept {
+        path: PathBuf,
+        /// Fingerprint recorded, replaced
+        recorded: Fingerprint,
+        fingerprint: Fingerprint,
+    },
+    /// Stop tracking a file
+    Retract {
+        path: PathBuf,
+        /// Fingerprint recorded, removed
+        recorded: Fingerprint,
+    },
+}
+
+impl Change {
+    /// The file changed
+    pub fn path(&self) -> &Path {
+        match self {
+            Change::Track { path, .. }
+            | Change::Accept { path, .. }
+            | Change::Retract { path, .. } => path,
+        }
+    }
+
+    /// Fingerprint recorded for the file beforehand, if tracked
+    fn before(&self) -> Option<&Fingerprint> {
+        match self {
+            Change::Track { .. } => None,
+            Change::Accept { recorded, .. } | Change::Retract { recorded, .. } => Some(recorded),
+        }
+    }
+
+    /// Fingerprint recorded for the file afterwards, if tracked
+    fn after(&self) -> Option<&Fingerprint> {
+        match self {
+            Change::Track { fingerprint, .. } | Change::Accept { fingerprint, .. } => {
+                Some(fingerprint)
+            }
+            Change::Retract { .. } => None,
+        }
+    }
+}
+
+/// Changes to the records planned
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug)]
+pub struct Plan {
+    /// Plan format version
+    pub version: u32,
+
+    /// When the plan was made
+    pub created: SystemTime,
+
+    /// Host the plan was made on
+    pub host: String,
+
+    /// Command line planned (with any secrets redacted)
+    pub command: Vec<String>,
+
+    /// Changes, in the order they are made
+    pub changes: Vec<Change>,
+}
+
+impl Plan {
+    /// A plan of the changes given, made now
+    pub fn new(host: String, command: Vec<String>, changes: Vec<Change>) -> Self {
+        Plan {
+            version: FORMAT_VERSION,
+            created: SystemTime::now(),
+            host,
+            command,
+            changes,
+        }
+    }
+
+    /// Serialise as JSON, signed with the key
+    pub fn to_json(&self, key: &SigningKey) -> Vec<u8> {
+        let body = RawValue::from_string(serde_json::to_string_pretty(self).unwrap()).unwrap();
+        let signed = Signed {
+            plan: &body,
+            signature: key.sign(body.get().as_bytes()),
+        };
+        let mut json = serde_json::to_vec(&signed).unwrap();
+        json.push(b'\n');
+        json
+    }
+
+    /// Deserialise, checking the signature against the key (so an
+    /// unsigned plan is refused)
+    pub fn from_json(bytes: &[u8], key: &SigningKey) -> Result<Self, FimblError> {
+        let signed: Signed = serde_json::from_slice(bytes).map_err(|_| FimblError::PlanInvalid)?;
+        let body = signed.plan.get();
+        if !key.verify(body.as_bytes(), &signed.signature) {
+            return Err(FimblError::PlanInvalid);
+        }
+
+        let plan: Plan = serde_json::from_str(body).map_err(|_| FimblError::PlanInvalid)?;
+        if plan.version != FORMAT_VERSION {
+            return Err(FimblError::PlanInvalid);
+        }
+        Ok(plan)
+    }
+
+    /// Check that each change finds the record it expects (the whole
+    /// fingerprint, tags and note included), given the fingerprints
+    /// now recorded and the changes before it
+    pub fn check(
+        &self,
+        mut recorded: impl FnMut(&Path) -> Result<Option<Fingerprint>, FimblError>,
+    ) -> Result<(), FimblError> {
+        let mut planned: HashMap<&Path, Option<Fingerprint>> = HashMap::new();
+        for change in &self.changes {
+            let path = change.path();
+            let before = match planned.get(path) {
+                Some(before) => before.clone(),
+                None => recorded(path)?,
+            };
+            if before.as_ref() != change.before() {
+                return Err(FimblError::PlanStale(path.to_path_buf()));
+            }
+            planned.insert(path, change.after().cloned());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+
+    use super::*;
+    use crate::fingerprint::Fingerprinter;
+
+    #[test]
+    fn test_plan() {
+        let dir = tempfile::tempdir().unwrap();
+        let hosts = dir.path().join("hosts");
+        std::fs::write(&hosts, "127.0.0.1 localhost").unwrap();
+        let fingerprint = Fingerprinter::new(None).fingerprint(&hosts).unwrap();
+        let recorded = Fingerprint {
+            content_hash: [7; 32],
+            ..fingerprint.clone()
+        };
+        let changes = vec![
+            Change::Accept {
+                path: hosts.clone(),
+                recorded: recorded.clone(),
+                fingerprint: fingerprint.clone(),
+            },
+            Change::Retract {
+                path: hosts.clone(),
+                recorded: fingerprint.clone(),
+            },
+            Change::Track {
+                path: hosts.clone(),
+                fingerprint,
+            },
+        ];
+        let plan = Plan::new("web1".to_string(), vec!["fimbl".to_string()], changes);
+
+        let key = SigningKey::from_bytes(b"shared");
+        let json = plan.to_json(&key);
+        assert_eq!(Plan::from_json(&json, &key).unwrap(), plan);
+        assert!(matches!(
+            Plan::from_json(&json, &SigningKey::from_bytes(b"other")),
+            Err(FimblError::PlanInvalid)
+        ));
+        let tampered = String::from_utf8(json).unwrap().replace("web1", "web2");
+        assert!(matches!(
+            Plan::from_json(tampered.as_bytes(), &key),
+            Err(FimblError::PlanInvalid)
+        ));
+        let unsigned = format!("{{\"plan\":{}}}", serde_json::to_string(&plan).unwrap());
+        assert!(matches!(
+            Plan::from_json(unsigned.as_bytes(), &key),
+            Err(FimblError::PlanInvalid)
+        ));
+
+        assert!(plan.check(|_| Ok(Some(recorded.clone()))).is_ok());
+        let retagged = Fingerprint {
+            tags: vec!["dns".to_string()],
+            ..recorded.clone()
+        };
+        assert!(matches!(
+            plan.check(|_| Ok(Some(retagged.clone()))),
+            Err(FimblError::PlanStale(path)) if path == hosts
+        ));
+        let touched = Fingerprint {
+            unix_mode: Some(0o777),
+            ..recorded.clone()
+        };
+        assert!(plan.check(|_| Ok(Some(touched.clone()))).is_err());
+        assert!(plan.check(|_| Ok(None)).is_err());
+    }
+}
@@ -0,0 +1,140 @@
+//! Curated lists of files worth tracking on common platforms
+//!
+//! A preset is a list of glob patterns, expanded against the file
+//! system when files are added, so only what exists on this host is
+//! tracked.
+
+use std::path::{Path, PathBuf};
+
+/// A curated starting baseline
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Preset {
+    /// Accounts, authentication, sudo, scheduled jobs, dynamic linker
+    /// and shell start-up configuration under /etc
+    LinuxEtc,
+    /// OpenSSH server configuration, host keys and root's authorized
+    /// keys
+    Sshd,
+    /// Third party launch daemons and agents (the system's own are on
+    /// the sealed system volume)
+    MacosLaunchd,
+}
+
+impl Preset {
+    /// Glob patterns for the files in the preset
+    pub fn patterns(&self) -> &'static [&'static str] {
+        match self {
+            Preset::LinuxEtc => &[
+                "/etc/passwd",
+                "/etc/group",
+                "/etc/shadow",
+                "/etc/gshadow",
+                "/etc/sudoers",
+                "/etc/sudoers.d/*",
+                "/etc/pam.d/*",
+                "/etc/security/*.conf",
+                "/etc/login.defs",
+                "/etc/nsswitch.conf",
+                "/etc/hosts",
+                "/etc/resolv.conf",
+                "/etc/fstab",
+                "/etc/crontab",
+                "/etc/cron.d/*",
+                "/etc/ld.so.preload",
+                "/etc/ld.so.conf",
+                "/etc/ld.so.conf.d/*",
+                "/etc/profile",
+                "/etc/profile.d/*",
+                "/etc/environment",
+                "/etc/modprobe.d/*",
+                "/etc/systemd/system/*.service",
+            ],
+            Preset::Sshd => &[
+                "/etc/ssh/sshd_config",
+                "/etc/ssh/sshd_config.d/*",
+                "/etc/ssh/ssh_host_*_key.pub",
+                "/etc/ssh/moduli",
+                "/root/.ssh/authorized_keys",
+            ],
+            Preset::MacosLaunchd => &[
+                "/Library/LaunchDaemons/*.plist",
+                "/Library/LaunchAgents/*.plist",
+                "/etc/sudoers",
+                "/etc/sudoers.d/*",
+                "/etc/pam.d/*",
+            ],
+        }
+    }
+}
+
+/// The files (not directories) on this host matching the presets, in
+/// order and without repeats
+pub fn expand(presets: &[Preset]) -> Vec<PathBuf> {
+    let mut files = vec![];
+    for pattern in presets.iter().flat_map(|preset| preset.patterns()) {
+        // patterns are known to be valid, and unreadable directories
+        // simply have nothing to offer
+        let matches = glob::glob(pattern).into_iter().flatten().flatten();
+        files.extend(matches.filter(|path| !path.is_dir()));
+    }
+    files.sort();
+    files.dedup();
+    files
+}
+
+/// True if this process may read a file, found without opening it, so
+/// neither blocking on a FIFO nor opening the file twice
+#[cfg(unix)]
+pub fn readable(path: &Path) -> bool {
+    use std::os::unix::ffi::OsStrExt;
+
+    let Ok(path) = std::ffi::CString::new(path.as_os_str().as_bytes()) else {
+        return false;
+    };
+    // SAFETY: the path is a NUL-terminated string outliving the call
+    unsafe { libc::faccessat(libc::AT_FDCWD, path.as_ptr(), libc::R_OK, libc::AT_EACCESS) == 0 }
+}
+
+/// True if the file is there (whether it can be read is found when
+/// it is fingerprinted)
+#[cfg(not(unix))]
+pub fn readable(path: &Path) -> bool {
+    std::fs::symlink_metadata(path).is_ok()
+}
+
+#[cfg(test)]
+pub mod tests {
+
+    use super::*;
+    use clap::ValueEnum;
+
+    #[test]
+    fn test_patterns_are_valid() {
+        for preset in Preset::value_variants() {
+            for pattern in preset.patterns() {
+                assert!(glob::Pattern::new(pattern).is_ok(), "{pattern}");
+            }
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_expand() {
+        let files = expand(&[Preset::LinuxEtc, Preset::LinuxEtc]);
+        assert!(files.contains(&PathBuf::from("/etc/passwd")));
+        assert!(files.windows(2).all(|pair| pair[0] < pair[1]));
+        assert!(files.iter().all(|file| !file.is_dir()));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_readable() {
+        let dir = tempfile::tempdir().unwrap();
+        let fifo = dir.path().join("fifo");
+        let name = std::ffi::CString::new(fifo.to_str().unwrap()).unwrap();
+        // SAFETY: the path is a NUL-terminated string outliving the call
+        assert_eq!(unsafe { libc::mkfifo(name.as_ptr(), 0o600) }, 0);
+        assert!(readable(&fifo), "without blocking");
+        assert!(!readable(&dir.path().join("missing")));
+    }
+}
@@ -0,0 +1,121 @@
+//! Reversing drift in file permissions and ownership
+//!
+//! Unlike content, permissions and ownership can be safely put back
+//! as they were recorded.
+
+use crate::fingerprint::Fingerprint;
+
+use std::{io, path::Path};
+
+/// Permissions and ownership to restore, where they differ from those
+/// recorded
+#[derive(Default, PartialEq, Eq, Debug)]
+pub struct PermissionDrift {
+    /// Recorded permission bits, if they differ
+    pub mode: Option<u32>,
+
+    /// Recorded owner, if different
+    pub uid: Option<u32>,
+
+    /// Recorded group, if different
+    pub gid: Option<u32>,
+}
+
+impl PermissionDrift {
+    /// True if nothing has drifted
+    pub fn is_empty(&self) -> bool {
+        *self == PermissionDrift::default()
+    }
+}
+
+/// Permission bits of a mode (excluding file type)
+#[cfg(unix)]
+const PERMISSION_BITS: u32 = 0o7777;
+
+/// Compare the recorded permissions and ownership with the file's
+/// current ones (not following symlinks)
+///
+/// Attributes that weren't recorded are left alone.
+#[cfg(unix)]
+pub fn permission_drift(path: &Path, recorded: &Fingerprint) -> io::Result<PermissionDrift> {
+    use std::os::unix::fs::MetadataExt;
+
+    let metadata = std::fs::symlink_metadata(path)?;
+    let differs = |recorded: Option<u32>, current: u32| recorded.filter(|r| *r != current);
+
+    Ok(PermissionDrift {
+        mode: differs(
+            recorded.unix_mode.map(|mode| mode & PERMISSION_BITS),
+            metadata.mode() & PERMISSION_BITS,
+        ),
+        uid: differs(recorded.uid, metadata.uid()),
+        gid: differs(recorded.gid, metadata.gid()),
+    })
+}
+
+/// Restore recorded permissions and ownership
+///
+/// Ownership is restored first as changing it may clear setuid and
+/// setgid bits. The mode of a symlink itself is meaningless and left
+/// alone.
+#[cfg(unix)]
+pub fn restore_permissions(path: &Path, drift: &PermissionDrift) -> io::Result<()> {
+    use std::os::unix::fs::{lchown, PermissionsExt};
+
+    if drift.uid.is_some() || drift.gid.is_some() {
+        lchown(path, drift.uid, drift.gid)?;
+    }
+    if let Some(mode) = drift.mode {
+        if !path.is_symlink() {
+            std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn permission_drift(_path: &Path, _recorded: &Fingerprint) -> io::Result<PermissionDrift> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "permission remediation is only supported on unix",
+    ))
+}
+
+#[cfg(not(unix))]
+pub fn restore_permissions(_path: &Path, _drift: &PermissionDrift) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "permission remediation is only supported on unix",
+    ))
+}
+
+#[cfg(all(test, unix))]
+pub mod tests {
+
+    use super::*;
+    use crate::fingerprint::Fingerprinter;
+    use std::os::unix::fs::PermissionsExt;
+
+    #[test]
+    fn test_restore_drifted_mode() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("config");
+        std::fs::write(&file, "secret").unwrap();
+        std::fs::set_permissions(&file, std::fs::Permissions::from_mode(0o600)).unwrap();
+        let recorded = Fingerprinter::new(None).fingerprint(&file).unwrap();
+        assert!(permission_drift(&file, &recorded).unwrap().is_empty());
+
+        std::fs::set_permissions(&file, std::fs::Permissions::from_mode(0o644)).unwrap();
+        let drift = permission_drift(&file, &recorded).unwrap();
+        assert_eq!(
+            drift,
+            PermissionDrift {
+                mode: Some(0o600),
+                ..Default::default()
+            }
+        );
+
+        restore_permissions(&file, &drift).unwrap();
+        assert!(permission_drift(&file, &recorded).unwrap().is_empty());
+    }
+}
@@ -1,22 +1,754 @@
 //! Report items (info and warn) for unexpected modifications and
 //! other conditions.
 
-use std::path::PathBuf;
+use crate::fingerprint::HashAlgorithm;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    path::{Path, PathBuf},
+    sync::RwLock,
+    time::{Duration, Instant},
+};
+
+/// The severity of changes to each file whose tags are mapped to one
+///
+/// Items are judged (by each output format, sink, hook and exit
+/// status) far from the database the tags are kept in, so the mapping
+/// for the files tracked is kept here, for the process.
+static TAGGED_SEVERITIES: RwLock<BTreeMap<PathBuf, Severity>> = RwLock::new(BTreeMap::new());
+
+/// A tag mapped to a severity, as `TAG=SEVERITY`
+pub fn parse_tag_severity(mapping: &str) -> Result<(String, Severity), String> {
+    let (tag, severity) = mapping
+        .rsplit_once('=')
+        .ok_or_else(|| "expected TAG=SEVERITY".to_string())?;
+    let severity = <Severity as clap::ValueEnum>::from_str(severity, true)
+        .map_err(|_| format!("{severity}: expected info, warning or critical"))?;
+    Ok((tag.to_string(), severity))
+}
+
+/// Have changes to files tagged with a tag mapped take its severity
+/// (the most severe, for a file with several such tags), in place of
+/// any mapping before
+pub fn map_tag_severities(
+    files: impl IntoIterator<Item = (PathBuf, Vec<String>)>,
+    mapping: &[(String, Severity)],
+) {
+    let severities = files
+        .into_iter()
+        .filter_map(|(path, tags)| {
+            let mapped = mapping.iter().filter(|(tag, _)| tags.contains(tag));
+            mapped
+                .map(|(_, severity)| *severity)
+                .max()
+                .map(|severity| (path, severity))
+        })
+        .collect();
+    *TAGGED_SEVERITIES
+        .write()
+        .unwrap_or_else(|poisoned| poisoned.into_inner()) = severities;
+}
+
+/// Where a file's contents have changed, by fixed-size block
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct BlockChanges {
+    /// Size of each block in bytes
+    pub block_size: u64,
+
+    /// Number of blocks the file now has
+    pub blocks: usize,
+
+    /// Ranges (inclusive, numbered from 1) of blocks that have changed,
+    /// appeared or gone
+    pub changed: Vec<(usize, usize)>,
+}
+
+impl BlockChanges {
+    /// Compare recorded and current block hashes, returning nothing
+    /// if no block has changed
+    pub fn between<T: PartialEq>(block_size: u64, recorded: &[T], current: &[T]) -> Option<Self> {
+        let mut changed: Vec<(usize, usize)> = vec![];
+        for block in 1..=recorded.len().max(current.len()) {
+            if recorded.get(block - 1) == current.get(block - 1) {
+                continue;
+            }
+            match changed.last_mut() {
+                Some((_, end)) if *end == block - 1 => *end = block,
+                _ => changed.push((block, block)),
+            }
+        }
+        (!changed.is_empty()).then_some(BlockChanges {
+            block_size,
+            blocks: current.len(),
+            changed,
+        })
+    }
+}
+
+impl std::fmt::Display for BlockChanges {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let ranges: Vec<String> = self
+            .changed
+            .iter()
+            .map(|(start, end)| match start == end {
+                true => start.to_string(),
+                false => format!("{start}-{end}"),
+            })
+            .collect();
+        let noun = match self.changed.as_slice() {
+            [(start, end)] if start == end => "block",
+            _ => "blocks",
+        };
+        write!(f, "{noun} {} of {} changed", ranges.join(", "), self.blocks)
+    }
+}
+
+/// How much a TLSH distance suggests a file has changed
+///
+/// Below about 30, TLSH rarely judges unrelated files similar; above
+/// about 100 the files have little in common.
+fn similarity(distance: i32) -> &'static str {
+    match distance {
+        ..=30 => "minor edit",
+        31..=100 => "substantial edit",
+        _ => "largely replaced",
+    }
+}
+
+/// Descriptions of a content change for its display
+fn change_details(
+    size: Option<String>,
+    blocks: &Option<BlockChanges>,
+    fuzzy_distance: &Option<i32>,
+) -> Vec<String> {
+    let blocks = blocks.as_ref().map(BlockChanges::to_string);
+    let fuzzy = fuzzy_distance
+        .map(|distance| format!("{}, TLSH distance {distance}", similarity(distance)));
+    size.into_iter().chain(blocks).chain(fuzzy).collect()
+}
 
 /// A report item that may represent unexpected file system
 /// modification or other concerning situation.
+///
+/// Serialised as `{code, kind, severity, path, details}`, the details
+/// being the variant's other fields (see `code` and `explain`).
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(remote = "Self", tag = "kind", rename_all = "kebab-case")]
 #[allow(clippy::enum_variant_names)]
 pub enum ReportItem {
     /// The file exists (unexpectedly) and is not tolerated
     FileAlreadyTracked { path: PathBuf },
     /// The file is missing (unexpectedly) from the database
     FileNotTracked { path: PathBuf },
-    /// The file contents have changed
-    FileContentChanged { path: PathBuf },
+    /// The file contents (or attributes) have changed
+    FileContentChanged {
+        path: PathBuf,
+        /// Recorded content hash (hex), where known
+        #[serde(skip_serializing_if = "Option::is_none")]
+        recorded_hash: Option<String>,
+        /// Current content hash (hex), where known
+        #[serde(skip_serializing_if = "Option::is_none")]
+        current_hash: Option<String>,
+        /// Which blocks changed, where block hashes were recorded
+        #[serde(skip_serializing_if = "Option::is_none")]
+        blocks: Option<BlockChanges>,
+        /// TLSH distance from the recorded contents, where fuzzy hashes
+        /// were recorded
+        #[serde(skip_serializing_if = "Option::is_none")]
+        fuzzy_distance: Option<i32>,
+    },
+    /// The file size has changed (so contents have too)
+    FileSizeChanged {
+        path: PathBuf,
+        recorded: u64,
+        current: u64,
+        /// Recorded content hash (hex), where known
+        #[serde(skip_serializing_if = "Option::is_none")]
+        recorded_hash: Option<String>,
+        /// Current content hash (hex), unless not hashed in fast mode
+        #[serde(skip_serializing_if = "Option::is_none")]
+        current_hash: Option<String>,
+        /// Which blocks changed, where block hashes were recorded
+        #[serde(skip_serializing_if = "Option::is_none")]
+        blocks: Option<BlockChanges>,
+        /// TLSH distance from the recorded contents, where fuzzy hashes
+        /// were recorded
+        #[serde(skip_serializing_if = "Option::is_none")]
+        fuzzy_distance: Option<i32>,
+    },
+    /// The tracked file no longer exists
+    FileMissing { path: PathBuf },
+    /// The path now refers to a different file (device / inode)
+    FileReplaced { path: PathBuf },
+    /// The recorded fingerprint was hashed differently (e.g. with a key)
+    HashAlgorithmMismatch {
+        path: PathBuf,
+        recorded: HashAlgorithm,
+        current: HashAlgorithm,
+    },
+    /// An NTFS alternate data stream has appeared
+    StreamAdded { path: PathBuf, stream: String },
+    /// An NTFS alternate data stream's contents have changed
+    StreamChanged { path: PathBuf, stream: String },
+    /// An NTFS alternate data stream has gone
+    StreamRemoved { path: PathBuf, stream: String },
+    /// The immutable flag has been removed, often the first step in
+    /// tampering with a protected file
+    ImmutableFlagRemoved { path: PathBuf },
+    /// Immutable or append-only flags have otherwise changed
+    FileFlagsChanged { path: PathBuf },
+    /// The file is on a network or virtual file system (when warning
+    /// of these)
+    NetworkFileSystem { path: PathBuf, file_system: String },
+    /// The file is on a network or virtual file system and was not
+    /// read (when skipping these)
+    NetworkFileSkipped { path: PathBuf, file_system: String },
+    /// Reading the file took longer than its time limit, so it could
+    /// not be fingerprinted
+    FileReadTimeout { path: PathBuf },
+    /// The file changed (or was replaced) while it was read, so could
+    /// not be fingerprinted
+    FileChangedDuringRead { path: PathBuf },
+    /// The file was read twice (paranoid reads) and the reads gave
+    /// different contents, though it didn't change in between
+    ReadsDisagree { path: PathBuf },
+    /// The file could not be fingerprinted (not readable, say, or gone
+    /// while being looked at)
+    FileUnreadable { path: PathBuf, error: String },
+    /// A file in a preset could not be read, so was not added
+    PresetFileUnreadable { path: PathBuf },
+    /// A file in a baseline imported was not imported, as no hash of
+    /// its contents was recorded that fimbl can check (or it is a
+    /// symlink, with no contents to check)
+    ImportUnverifiable { path: PathBuf },
+    /// The note on a file found to have changed, for whoever triages
+    /// the change
+    FileNote { path: PathBuf, note: String },
+    /// A change was accepted but must be approved (with the token)
+    /// before it is recorded
+    AcceptPending { path: PathBuf, token: String },
+    /// Entries have been added to, removed from or renamed in a
+    /// directory tracked as an entry
+    DirectoryEntriesChanged { path: PathBuf },
+    /// The contents have gone from plaintext to looking encrypted (or
+    /// compressed), as ransomware leaves them
+    EntropyIncreased {
+        path: PathBuf,
+        /// Recorded entropy in bits per byte
+        recorded: f64,
+        /// Current entropy in bits per byte
+        current: f64,
+    },
+    /// Many files jumped to high entropy in the same run, suggesting
+    /// ransomware at work
+    MassEncryptionSuspected { files: usize },
+    /// Modifications to a file were not accepted as its contents have
+    /// changed (when accepting metadata only, or since the files to
+    /// accept were listed)
+    ContentChangeNotAccepted { path: PathBuf },
+    /// A missing file has turned up, with its recorded contents, at
+    /// another path
+    FileMoved { from: PathBuf, to: PathBuf },
+    /// The file's contents differ from those in a git commit, or it
+    /// isn't in the commit
+    DiffersFromCommit {
+        path: PathBuf,
+        commit: String,
+        /// Object id of the file in the commit, if it is there
+        #[serde(skip_serializing_if = "Option::is_none")]
+        committed: Option<String>,
+        /// Object id git gives the file's current contents
+        current: String,
+    },
+    /// Another path a file was given by (an alias) now leads to a
+    /// different file than the one recorded, as when a symlinked
+    /// directory on the way has been repointed
+    AliasRedirected {
+        path: PathBuf,
+        /// Path the file is recorded by
+        recorded: PathBuf,
+        /// Path the alias now leads to
+        target: PathBuf,
+    },
+    /// A symlink now points somewhere else
+    SymlinkTargetChanged {
+        path: PathBuf,
+        /// Target recorded, as written in the link
+        recorded: PathBuf,
+        /// Target now
+        current: PathBuf,
+    },
+    /// A file is in a directory the manifest covers but not in the
+    /// manifest
+    FileNotInManifest { path: PathBuf },
+    /// A tracked file was not renamed as the file at its new path has
+    /// different contents
+    RenameRefused { from: PathBuf, to: PathBuf },
+    /// Recorded permissions and ownership were (or would be) restored
+    PermissionsRestored { path: PathBuf, dry_run: bool },
+    /// A tracked extended attribute (e.g. quarantine) has appeared
+    XattrAdded { path: PathBuf, name: String },
+    /// A tracked extended attribute's value has changed
+    XattrChanged { path: PathBuf, name: String },
+    /// A tracked extended attribute has gone
+    XattrRemoved { path: PathBuf, name: String },
     /// The filename is not supported
     FileNameNotSupported { path: PathBuf },
+    /// The file would be added (on a dry run)
+    WouldTrack { path: PathBuf },
+    /// The file's record would be removed (on a dry run)
+    WouldRetract { path: PathBuf },
+    /// The file's changes would be accepted, or held pending approval
+    /// (on a dry run)
+    WouldAccept { path: PathBuf, pending: bool },
+    /// The file's record would be moved to the path it has moved to
+    /// (on a dry run)
+    WouldRename { from: PathBuf, to: PathBuf },
+    /// The file's note would be set, or cleared (on a dry run)
+    WouldAnnotate { path: PathBuf, note: Option<String> },
+    /// A corrupt database entry would be removed (on a dry run)
+    WouldRemoveEntry { tree: String, key: String },
+    /// The file's record would be put back as it was in a snapshot
+    /// (on a dry run)
+    WouldRollBack { path: PathBuf, snapshot: String },
     /// File is (now) a directory
     FileIsDirectory { path: PathBuf },
+    /// The file is tracked in only one of two databases compared
+    OnlyInDatabase { path: PathBuf, database: PathBuf },
+    /// Two databases compared disagree on the file's fingerprint
+    DatabasesDisagree { path: PathBuf, content: bool },
+    /// Databases being merged disagree on the file's fingerprint
+    MergeConflict { path: PathBuf, took_theirs: bool },
+    /// An external hook command failed
+    HookFailed { hook: String, message: String },
+    /// The file's record in the database was not written by fimbl
+    /// with the record key: it has been tampered with
+    RecordTampered { path: PathBuf },
+    /// A notification (e.g. email) could not be sent
+    NotificationFailed { sink: String, message: String },
+    /// The database's root hash is not the one last anchored, so it
+    /// has been changed other than by fimbl (or without anchoring)
+    RootHashMismatch { anchored: String, actual: String },
+    /// A database entry is corrupt (and may have been removed)
+    CorruptEntry {
+        tree: String,
+        key: String,
+        problem: String,
+        removed: bool,
+    },
+    /// A scan found changes to a far larger fraction of the files
+    /// examined than scans normally do, suggesting mass tampering
+    AnomalousChangeVolume {
+        files: usize,
+        examined: u64,
+        /// Percentage of files normally changed
+        normal: f64,
+    },
+}
+
+/// How urgently a report item needs attention
+#[derive(PartialEq, Eq, PartialOrd, Ord, Debug, Clone, Copy, clap::ValueEnum)]
+pub enum Severity {
+    /// Information, not a sign of tampering in itself
+    Info,
+    /// Unexpected modification
+    Warning,
+    /// Strong sign of tampering
+    Critical,
+}
+
+impl Severity {
+    /// Lower case name of the severity
+    pub fn name(&self) -> &'static str {
+        match self {
+            Severity::Info => "info",
+            Severity::Warning => "warning",
+            Severity::Critical => "critical",
+        }
+    }
+}
+
+/// A report item as serialised
+#[derive(Serialize, Deserialize)]
+struct Structured {
+    #[serde(default)]
+    code: String,
+    kind: String,
+    #[serde(default)]
+    severity: String,
+    #[serde(default)]
+    path: Option<PathBuf>,
+    #[serde(default)]
+    details: serde_json::Map<String, serde_json::Value>,
+}
+
+impl serde::Serialize for ReportItem {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut details = self.tagged();
+        let kind = details
+            .remove("kind")
+            .and_then(|kind| kind.as_str().map(String::from));
+        details.remove("path");
+        Structured {
+            code: self.code().to_string(),
+            kind: kind.unwrap_or_default(),
+            severity: self.severity().name().to_string(),
+            path: self.path().map(Path::to_path_buf),
+            details,
+        }
+        .serialize(serializer)
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for ReportItem {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        use serde::de::Error;
+
+        let structured = Structured::deserialize(deserializer)?;
+        let mut tagged = structured.details;
+        tagged.insert("kind".to_string(), structured.kind.into());
+        if let Some(path) = structured.path {
+            let path = serde_json::to_value(path).map_err(D::Error::custom)?;
+            tagged.entry("path").or_insert(path);
+        }
+        ReportItem::deserialize(serde_json::Value::Object(tagged)).map_err(D::Error::custom)
+    }
+}
+
+impl ReportItem {
+    /// The item's fields, tagged with its kind
+    fn tagged(&self) -> serde_json::Map<String, serde_json::Value> {
+        match ReportItem::serialize(self, serde_json::value::Serializer) {
+            Ok(serde_json::Value::Object(fields)) => fields,
+            _ => serde_json::Map::new(),
+        }
+    }
+
+    /// Kebab-case kind of the item, as in its JSON
+    pub fn kind(&self) -> String {
+        self.tagged()
+            .get("kind")
+            .and_then(|kind| kind.as_str().map(String::from))
+            .unwrap_or_default()
+    }
+
+    /// Stable code of the item's kind (see `explain`)
+    pub fn code(&self) -> &'static str {
+        match self {
+            ReportItem::FileContentChanged { .. } => "F001",
+            ReportItem::FileSizeChanged { .. } => "F002",
+            ReportItem::FileMissing { .. } => "F003",
+            ReportItem::FileReplaced { .. } => "F004",
+            ReportItem::FileIsDirectory { .. } => "F005",
+            ReportItem::FileMoved { .. } => "F006",
+            ReportItem::DirectoryEntriesChanged { .. } => "F007",
+            ReportItem::EntropyIncreased { .. } => "F008",
+            ReportItem::DiffersFromCommit { .. } => "F009",
+            ReportItem::AliasRedirected { .. } => "F010",
+            ReportItem::SymlinkTargetChanged { .. } => "F011",
+            ReportItem::FileNotInManifest { .. } => "F012",
+            ReportItem::ImmutableFlagRemoved { .. } => "A001",
+            ReportItem::FileFlagsChanged { .. } => "A002",
+            ReportItem::XattrAdded { .. } => "A003",
+            ReportItem::XattrChanged { .. } => "A004",
+            ReportItem::XattrRemoved { .. } => "A005",
+            ReportItem::StreamAdded { .. } => "A006",
+            ReportItem::StreamChanged { .. } => "A007",
+            ReportItem::StreamRemoved { .. } => "A008",
+            ReportItem::MassEncryptionSuspected { .. } => "S001",
+            ReportItem::AnomalousChangeVolume { .. } => "S002",
+            ReportItem::RecordTampered { .. } => "D001",
+            ReportItem::RootHashMismatch { .. } => "D002",
+            ReportItem::CorruptEntry { .. } => "D003",
+            ReportItem::HashAlgorithmMismatch { .. } => "D004",
+            ReportItem::OnlyInDatabase { .. } => "D005",
+            ReportItem::DatabasesDisagree { .. } => "D006",
+            ReportItem::MergeConflict { .. } => "D007",
+            ReportItem::FileAlreadyTracked { .. } => "C001",
+            ReportItem::FileNotTracked { .. } => "C002",
+            ReportItem::ContentChangeNotAccepted { .. } => "C003",
+            ReportItem::RenameRefused { .. } => "C004",
+            ReportItem::AcceptPending { .. } => "C005",
+            ReportItem::PermissionsRestored { .. } => "C006",
+            ReportItem::PresetFileUnreadable { .. } => "C007",
+            ReportItem::FileNote { .. } => "C008",
+            ReportItem::FileNameNotSupported { .. } => "C009",
+            ReportItem::WouldTrack { .. } => "C010",
+            ReportItem::WouldRetract { .. } => "C011",
+            ReportItem::WouldAccept { .. } => "C012",
+            ReportItem::ImportUnverifiable { .. } => "C013",
+            ReportItem::WouldRename { .. } => "C014",
+            ReportItem::WouldAnnotate { .. } => "C015",
+            ReportItem::WouldRemoveEntry { .. } => "C016",
+            ReportItem::WouldRollBack { .. } => "C017",
+            ReportItem::NetworkFileSystem { .. } => "E001",
+            ReportItem::NetworkFileSkipped { .. } => "E002",
+            ReportItem::FileReadTimeout { .. } => "E003",
+            ReportItem::HookFailed { .. } => "E004",
+            ReportItem::NotificationFailed { .. } => "E005",
+            ReportItem::FileChangedDuringRead { .. } => "E006",
+            ReportItem::ReadsDisagree { .. } => "E007",
+            ReportItem::FileUnreadable { .. } => "E008",
+        }
+    }
+
+    /// Severity of the item: a change to a file (a warning) takes the
+    /// severity its tags are mapped to, if they are
+    pub fn severity(&self) -> Severity {
+        let severity = self.kind_severity();
+        if severity != Severity::Warning {
+            return severity;
+        }
+        let tagged = TAGGED_SEVERITIES
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        self.path()
+            .and_then(|path| tagged.get(path))
+            .copied()
+            .unwrap_or(severity)
+    }
+
+    /// Severity of an item of its kind
+    pub fn kind_severity(&self) -> Severity {
+        match self {
+            ReportItem::ImmutableFlagRemoved { .. }
+            | ReportItem::MassEncryptionSuspected { .. }
+            | ReportItem::AnomalousChangeVolume { .. }
+            | ReportItem::RootHashMismatch { .. }
+            | ReportItem::RecordTampered { .. } => Severity::Critical,
+            ReportItem::CorruptEntry { removed: false, .. } => Severity::Warning,
+            ReportItem::FileContentChanged { .. }
+            | ReportItem::FileSizeChanged { .. }
+            | ReportItem::FileReplaced { .. }
+            | ReportItem::FileMissing { .. }
+            | ReportItem::StreamAdded { .. }
+            | ReportItem::StreamChanged { .. }
+            | ReportItem::StreamRemoved { .. }
+            | ReportItem::FileFlagsChanged { .. }
+            | ReportItem::XattrAdded { .. }
+            | ReportItem::XattrChanged { .. }
+            | ReportItem::XattrRemoved { .. }
+            | ReportItem::FileIsDirectory { .. }
+            | ReportItem::DirectoryEntriesChanged { .. }
+            | ReportItem::NetworkFileSystem { .. }
+            | ReportItem::FileReadTimeout { .. }
+            | ReportItem::FileChangedDuringRead { .. }
+            | ReportItem::ReadsDisagree { .. }
+            | ReportItem::FileUnreadable { .. }
+            | ReportItem::EntropyIncreased { .. }
+            | ReportItem::ContentChangeNotAccepted { .. }
+            | ReportItem::RenameRefused { .. }
+            | ReportItem::ImportUnverifiable { .. }
+            | ReportItem::FileMoved { .. }
+            | ReportItem::DiffersFromCommit { .. }
+            | ReportItem::AliasRedirected { .. }
+            | ReportItem::SymlinkTargetChanged { .. }
+            | ReportItem::FileNotInManifest { .. } => Severity::Warning,
+            _ => Severity::Info,
+        }
+    }
+
+    /// The file the item concerns, if any
+    pub fn path(&self) -> Option<&Path> {
+        match self {
+            ReportItem::FileAlreadyTracked { path }
+            | ReportItem::FileNotTracked { path }
+            | ReportItem::FileContentChanged { path, .. }
+            | ReportItem::ContentChangeNotAccepted { path }
+            | ReportItem::RenameRefused { to: path, .. }
+            | ReportItem::FileMoved { from: path, .. }
+            | ReportItem::DiffersFromCommit { path, .. }
+            | ReportItem::AliasRedirected { path, .. }
+            | ReportItem::SymlinkTargetChanged { path, .. }
+            | ReportItem::FileNotInManifest { path }
+            | ReportItem::EntropyIncreased { path, .. }
+            | ReportItem::DirectoryEntriesChanged { path }
+            | ReportItem::NetworkFileSystem { path, .. }
+            | ReportItem::NetworkFileSkipped { path, .. }
+            | ReportItem::FileReadTimeout { path }
+            | ReportItem::FileChangedDuringRead { path }
+            | ReportItem::ReadsDisagree { path }
+            | ReportItem::FileUnreadable { path, .. }
+            | ReportItem::FileNote { path, .. }
+            | ReportItem::AcceptPending { path, .. }
+            | ReportItem::RecordTampered { path }
+            | ReportItem::PresetFileUnreadable { path }
+            | ReportItem::ImportUnverifiable { path }
+            | ReportItem::FileSizeChanged { path, .. }
+            | ReportItem::FileMissing { path }
+            | ReportItem::FileReplaced { path }
+            | ReportItem::HashAlgorithmMismatch { path, .. }
+            | ReportItem::StreamAdded { path, .. }
+            | ReportItem::StreamChanged { path, .. }
+            | ReportItem::StreamRemoved { path, .. }
+            | ReportItem::ImmutableFlagRemoved { path }
+            | ReportItem::FileFlagsChanged { path }
+            | ReportItem::PermissionsRestored { path, .. }
+            | ReportItem::XattrAdded { path, .. }
+            | ReportItem::XattrChanged { path, .. }
+            | ReportItem::XattrRemoved { path, .. }
+            | ReportItem::FileNameNotSupported { path }
+            | ReportItem::WouldTrack { path }
+            | ReportItem::WouldRetract { path }
+            | ReportItem::WouldAccept { path, .. }
+            | ReportItem::WouldRename { to: path, .. }
+            | ReportItem::WouldAnnotate { path, .. }
+            | ReportItem::WouldRollBack { path, .. }
+            | ReportItem::FileIsDirectory { path }
+            | ReportItem::OnlyInDatabase { path, .. }
+            | ReportItem::DatabasesDisagree { path, .. }
+            | ReportItem::MergeConflict { path, .. } => Some(path),
+            ReportItem::HookFailed { .. }
+            | ReportItem::MassEncryptionSuspected { .. }
+            | ReportItem::AnomalousChangeVolume { .. }
+            | ReportItem::NotificationFailed { .. }
+            | ReportItem::RootHashMismatch { .. }
+            | ReportItem::WouldRemoveEntry { .. }
+            | ReportItem::CorruptEntry { .. } => None,
+        }
+    }
+}
+
+/// Number of files jumping to high entropy in one run that suggests
+/// ransomware rather than, say, someone compressing a log
+pub const MASS_ENCRYPTION_THRESHOLD: usize = 10;
+
+/// A report of suspected mass encryption, if enough of the items are
+/// files jumping to high entropy
+pub fn mass_encryption(items: &[ReportItem]) -> Option<ReportItem> {
+    let files = items
+        .iter()
+        .filter(|item| matches!(item, ReportItem::EntropyIncreased { .. }))
+        .count();
+    (files >= MASS_ENCRYPTION_THRESHOLD).then_some(ReportItem::MassEncryptionSuspected { files })
+}
+
+/// Fewest earlier scans to judge a scan's volume of changes against
+pub const CHANGE_VOLUME_SCANS: usize = 5;
+
+/// Fewest files changed in one scan that may be anomalous
+const CHANGE_VOLUME_MIN_FILES: usize = 10;
+
+/// Largest fraction of files changed in one scan that is never
+/// anomalous, however quiet scans normally are
+const CHANGE_VOLUME_FLOOR: f64 = 0.05;
+
+/// Number of files with changes worth a warning among the items
+pub fn changed_files(items: &[ReportItem]) -> usize {
+    items
+        .iter()
+        .filter(|item| item.severity() >= Severity::Warning)
+        .filter_map(|item| item.path())
+        .collect::<BTreeSet<_>>()
+        .len()
+}
+
+/// A report of an anomalous volume of changes, if the fraction of
+/// files examined that the items show changed is far above the
+/// fractions changed in earlier scans
+///
+/// Anomalous means more than three times the mean, more than four
+/// standard deviations above it and more than a floor, so that neither
+/// steady churn nor a handful of files in a quiet tree raise it.
+pub fn anomalous_change_volume(
+    items: &[ReportItem],
+    examined: u64,
+    earlier: &[f64],
+) -> Option<ReportItem> {
+    let files = changed_files(items);
+    if earlier.len() < CHANGE_VOLUME_SCANS || files < CHANGE_VOLUME_MIN_FILES || examined == 0 {
+        return None;
+    }
+    let fraction = files as f64 / examined as f64;
+    let mean = earlier.iter().sum::<f64>() / earlier.len() as f64;
+    let variance = earlier.iter().map(|f| (f - mean).powi(2)).sum::<f64>() / earlier.len() as f64;
+    let threshold = (3.0 * mean)
+        .max(mean + 4.0 * variance.sqrt())
+        .max(CHANGE_VOLUME_FLOOR);
+    (fraction > threshold).then_some(ReportItem::AnomalousChangeVolume {
+        files,
+        examined,
+        normal: mean * 100.0,
+    })
+}
+
+/// Totals for a run (e.g. of verify-all), for the footer of the report
+#[derive(Serialize, Debug)]
+pub struct Summary {
+    /// Number of files looked at
+    pub files_examined: u64,
+
+    /// Bytes of file contents read and hashed
+    pub bytes_hashed: u64,
+
+    /// Number of items reported, by kind
+    pub items: BTreeMap<String, usize>,
+
+    /// Wall clock time taken
+    pub elapsed_seconds: f64,
+
+    /// Hashing throughput over the run
+    pub bytes_per_second: f64,
+}
+
+impl Summary {
+    /// Summarise a run that started at `started`
+    pub fn new(
+        started: Instant,
+        files_examined: u64,
+        bytes_hashed: u64,
+        items: &[ReportItem],
+    ) -> Self {
+        let elapsed = started.elapsed().max(Duration::from_micros(1));
+        let mut counts = BTreeMap::new();
+        for item in items {
+            *counts.entry(item.kind()).or_default() += 1;
+        }
+        Summary {
+            files_examined,
+            bytes_hashed,
+            items: counts,
+            elapsed_seconds: elapsed.as_secs_f64(),
+            bytes_per_second: bytes_hashed as f64 / elapsed.as_secs_f64(),
+        }
+    }
+}
+
+/// Byte count scaled to a readable unit
+pub fn scaled(bytes: f64) -> String {
+    if bytes < 1024.0 {
+        return format!("{bytes:.0} B");
+    }
+    let mut value = bytes / 1024.0;
+    for unit in ["KiB", "MiB", "GiB"] {
+        if value < 1024.0 {
+            return format!("{value:.1} {unit}");
+        }
+        value /= 1024.0;
+    }
+    format!("{value:.1} TiB")
+}
+
+impl std::fmt::Display for Summary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} files examined, {} hashed in {:.2}s ({}/s)",
+            self.files_examined,
+            scaled(self.bytes_hashed as f64),
+            self.elapsed_seconds,
+            scaled(self.bytes_per_second)
+        )?;
+        if self.items.is_empty() {
+            write!(f, "; nothing to report")
+        } else {
+            let items: Vec<_> = self
+                .items
+                .iter()
+                .map(|(kind, count)| format!("{count} {kind}"))
+                .collect();
+            write!(f, "; {}", items.join(", "))
+        }
+    }
 }
 
 impl std::fmt::Display for ReportItem {
@@ -25,8 +757,245 @@ impl std::fmt::Display for ReportItem {
             ReportItem::FileAlreadyTracked { path } => {
                 write!(f, "file already exists: {}", path.display())
             }
-            ReportItem::FileContentChanged { path } => {
-                write!(f, "file content changed: {}", path.display())
+            ReportItem::FileContentChanged {
+                path,
+                blocks,
+                fuzzy_distance,
+                ..
+            } => {
+                write!(f, "file content changed: {}", path.display())?;
+                let details = change_details(None, blocks, fuzzy_distance);
+                match details.is_empty() {
+                    true => Ok(()),
+                    false => write!(f, " ({})", details.join(", ")),
+                }
+            }
+            ReportItem::FileSizeChanged {
+                path,
+                recorded,
+                current,
+                blocks,
+                fuzzy_distance,
+                ..
+            } => {
+                let size = format!("{recorded} -> {current} bytes");
+                let details = change_details(Some(size), blocks, fuzzy_distance);
+                write!(
+                    f,
+                    "file size changed: {} ({})",
+                    path.display(),
+                    details.join(", ")
+                )
+            }
+            ReportItem::NetworkFileSystem { path, file_system } => {
+                write!(
+                    f,
+                    "file on network or virtual file system: {} ({file_system})",
+                    path.display()
+                )
+            }
+            ReportItem::NetworkFileSkipped { path, file_system } => {
+                write!(
+                    f,
+                    "file on network or virtual file system skipped: {} ({file_system})",
+                    path.display()
+                )
+            }
+            ReportItem::FileReadTimeout { path } => {
+                write!(f, "file read timed out: {}", path.display())
+            }
+            ReportItem::FileChangedDuringRead { path } => {
+                write!(f, "file changed while it was read: {}", path.display())
+            }
+            ReportItem::ReadsDisagree { path } => {
+                write!(f, "reads of file disagree: {}", path.display())
+            }
+            ReportItem::FileUnreadable { path, error } => {
+                write!(f, "file could not be read: {} ({error})", path.display())
+            }
+            ReportItem::PresetFileUnreadable { path } => {
+                write!(f, "preset file not readable, not added: {}", path.display())
+            }
+            ReportItem::ImportUnverifiable { path } => {
+                write!(
+                    f,
+                    "no hash that can be checked recorded, not imported: {}",
+                    path.display()
+                )
+            }
+            ReportItem::FileNote { path, note } => {
+                write!(f, "note on {}: {}", path.display(), note)
+            }
+            ReportItem::AcceptPending { path, token } => {
+                write!(
+                    f,
+                    "accept pending approval: {} (token {})",
+                    path.display(),
+                    token
+                )
+            }
+            ReportItem::DirectoryEntriesChanged { path } => {
+                write!(f, "directory entries changed: {}", path.display())
+            }
+            ReportItem::EntropyIncreased {
+                path,
+                recorded,
+                current,
+            } => {
+                write!(
+                    f,
+                    "entropy jumped, possibly encrypted: {} ({:.2} -> {:.2} bits/byte)",
+                    path.display(),
+                    recorded,
+                    current
+                )
+            }
+            ReportItem::MassEncryptionSuspected { files } => {
+                write!(
+                    f,
+                    "mass encryption suspected: {files} files jumped to high entropy"
+                )
+            }
+            ReportItem::AnomalousChangeVolume {
+                files,
+                examined,
+                normal,
+            } => {
+                write!(
+                    f,
+                    "anomalous change volume: {files} of {examined} files changed (normally {normal:.1}%)"
+                )
+            }
+            ReportItem::FileMoved { from, to } => {
+                write!(f, "file moved: {} -> {}", from.display(), to.display())
+            }
+            ReportItem::DiffersFromCommit {
+                path,
+                commit,
+                committed,
+                ..
+            } => {
+                let commit = &commit[..commit.len().min(12)];
+                match committed {
+                    Some(_) => write!(f, "differs from commit {commit}: {}", path.display()),
+                    None => write!(f, "not in commit {commit}: {}", path.display()),
+                }
+            }
+            ReportItem::AliasRedirected {
+                path,
+                recorded,
+                target,
+            } => {
+                write!(
+                    f,
+                    "alias leads elsewhere: {} -> {} (recorded as {})",
+                    path.display(),
+                    target.display(),
+                    recorded.display()
+                )
+            }
+            ReportItem::SymlinkTargetChanged {
+                path,
+                recorded,
+                current,
+            } => {
+                write!(
+                    f,
+                    "symlink target changed: {} ({} -> {})",
+                    path.display(),
+                    recorded.display(),
+                    current.display()
+                )
+            }
+            ReportItem::FileNotInManifest { path } => {
+                write!(f, "file not in manifest: {}", path.display())
+            }
+            ReportItem::RenameRefused { from, to } => {
+                write!(
+                    f,
+                    "contents differ, not renamed: {} -> {}",
+                    from.display(),
+                    to.display()
+                )
+            }
+            ReportItem::ContentChangeNotAccepted { path } => {
+                write!(f, "content changed, not accepted: {}", path.display())
+            }
+            ReportItem::FileMissing { path } => {
+                write!(f, "file is missing: {}", path.display())
+            }
+            ReportItem::FileReplaced { path } => {
+                write!(f, "file replaced (new inode): {}", path.display())
+            }
+            ReportItem::HashAlgorithmMismatch {
+                path,
+                recorded,
+                current,
+            } => {
+                write!(
+                    f,
+                    "cannot compare {} fingerprint with {}: {}",
+                    recorded,
+                    current,
+                    path.display()
+                )
+            }
+            ReportItem::StreamAdded { path, stream } => {
+                write!(f, "data stream added: {}:{}", path.display(), stream)
+            }
+            ReportItem::StreamChanged { path, stream } => {
+                write!(f, "data stream changed: {}:{}", path.display(), stream)
+            }
+            ReportItem::StreamRemoved { path, stream } => {
+                write!(f, "data stream removed: {}:{}", path.display(), stream)
+            }
+            ReportItem::ImmutableFlagRemoved { path } => {
+                write!(f, "immutable flag removed: {}", path.display())
+            }
+            ReportItem::FileFlagsChanged { path } => {
+                write!(f, "file flags changed: {}", path.display())
+            }
+            ReportItem::PermissionsRestored { path, dry_run } => {
+                if *dry_run {
+                    write!(f, "would restore permissions: {}", path.display())
+                } else {
+                    write!(f, "permissions restored: {}", path.display())
+                }
+            }
+            ReportItem::XattrAdded { path, name } => {
+                write!(f, "extended attribute {} added: {}", name, path.display())
+            }
+            ReportItem::XattrChanged { path, name } => {
+                write!(f, "extended attribute {} changed: {}", name, path.display())
+            }
+            ReportItem::XattrRemoved { path, name } => {
+                write!(f, "extended attribute {} removed: {}", name, path.display())
+            }
+            ReportItem::WouldTrack { path } => {
+                write!(f, "would add: {}", path.display())
+            }
+            ReportItem::WouldRetract { path } => {
+                write!(f, "would remove: {}", path.display())
+            }
+            ReportItem::WouldAccept { path, pending } => {
+                if *pending {
+                    write!(f, "would hold accept pending approval: {}", path.display())
+                } else {
+                    write!(f, "would accept: {}", path.display())
+                }
+            }
+            ReportItem::WouldRename { from, to } => {
+                write!(f, "would rename: {} to {}", from.display(), to.display())
+            }
+            ReportItem::WouldAnnotate { path, note } => match note {
+                Some(note) => write!(f, "would note on {}: {}", path.display(), note),
+                None => write!(f, "would clear note on {}", path.display()),
+            },
+            ReportItem::WouldRemoveEntry { tree, key } => {
+                write!(f, "would remove corrupt {} entry {}", tree, key)
+            }
+            ReportItem::WouldRollBack { path, snapshot } => {
+                write!(f, "would roll back to {}: {}", snapshot, path.display())
             }
             ReportItem::FileNameNotSupported { path } => {
                 write!(
@@ -41,6 +1010,51 @@ impl std::fmt::Display for ReportItem {
             ReportItem::FileIsDirectory { path } => {
                 write!(f, "file is (now) a directory: {}", path.display())
             }
+            ReportItem::OnlyInDatabase { path, database } => {
+                write!(f, "only in {}: {}", database.display(), path.display())
+            }
+            ReportItem::DatabasesDisagree { path, content } => {
+                let what = if *content { "content" } else { "attributes" };
+                write!(f, "databases disagree ({}): {}", what, path.display())
+            }
+            ReportItem::MergeConflict { path, took_theirs } => {
+                let resolution = if *took_theirs {
+                    "took theirs"
+                } else {
+                    "kept ours"
+                };
+                write!(f, "merge conflict ({}): {}", resolution, path.display())
+            }
+            ReportItem::HookFailed { hook, message } => {
+                write!(f, "{} hook failed: {}", hook, message)
+            }
+            ReportItem::NotificationFailed { sink, message } => {
+                write!(f, "{} notification failed: {}", sink, message)
+            }
+            ReportItem::RecordTampered { path } => {
+                write!(
+                    f,
+                    "database record tampered with (not written with the record key): {}",
+                    path.display()
+                )
+            }
+            ReportItem::RootHashMismatch { anchored, actual } => {
+                write!(
+                    f,
+                    "database root hash {} is not the one last anchored ({}): \
+                     it may have been rewritten",
+                    actual, anchored
+                )
+            }
+            ReportItem::CorruptEntry {
+                tree,
+                key,
+                problem,
+                removed,
+            } => {
+                let action = if *removed { " (removed)" } else { "" };
+                write!(f, "corrupt {} entry {}: {}{}", tree, key, problem, action)
+            }
         }
     }
 }
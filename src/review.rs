@@ -0,0 +1,400 @@
+//! Reviewing verification findings interactively
+//!
+//! Each file with findings is listed with what was reported and its
+//! recorded and current metadata side by side. Files are marked to be
+//! accepted, ignored or investigated, and nothing is done until the
+//! review is finished, when the accepts are applied in one batch.
+
+use crate::{
+    error::FimblError,
+    fingerprint::{FileFlags, Fingerprint},
+    report::{ReportItem, Severity},
+};
+
+use ratatui::{
+    crossterm::event::{self, Event, KeyCode, KeyEventKind},
+    layout::{Constraint, Layout},
+    style::{Modifier, Style, Stylize},
+    text::Line,
+    widgets::{Block, List, ListItem, ListState, Paragraph, Row, Table},
+    DefaultTerminal, Frame,
+};
+use std::{path::PathBuf, time::SystemTime};
+
+/// What to do about a file's findings
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Decision {
+    /// Not decided yet: nothing is done
+    Undecided,
+    /// Accept the file as it is now
+    Accept,
+    /// Leave the record alone, for now
+    Ignore,
+    /// Leave the record alone, and list the file to be looked into
+    Investigate,
+}
+
+impl Decision {
+    /// Marker shown against the file
+    fn marker(&self) -> &'static str {
+        match self {
+            Decision::Undecided => "[ ]",
+            Decision::Accept => "[A]",
+            Decision::Ignore => "[I]",
+            Decision::Investigate => "[?]",
+        }
+    }
+}
+
+/// One attribute of a file, as recorded and as it is now
+#[derive(Debug, PartialEq, Eq)]
+pub struct MetadataRow {
+    pub field: &'static str,
+    pub recorded: String,
+    pub current: String,
+}
+
+impl MetadataRow {
+    /// True if the attribute has changed
+    pub fn differs(&self) -> bool {
+        self.recorded != self.current
+    }
+}
+
+/// Text for an optional value, "-" if it's absent
+fn optional<T: std::fmt::Display>(value: Option<T>) -> String {
+    value
+        .map(|v| v.to_string())
+        .unwrap_or_else(|| "-".to_string())
+}
+
+/// Text for an optional time
+fn time(time: Option<SystemTime>) -> String {
+    optional(time.map(humantime::format_rfc3339_seconds))
+}
+
+/// Text for file flags: those set, or "none"
+fn flag_names(flags: FileFlags) -> String {
+    let names: Vec<_> = [
+        (flags.immutable, "immutable"),
+        (flags.append_only, "append-only"),
+    ]
+    .into_iter()
+    .filter_map(|(set, name)| set.then_some(name))
+    .collect();
+    match names.is_empty() {
+        true => "none".to_string(),
+        false => names.join(", "),
+    }
+}
+
+/// Shows an attribute of a fingerprint
+type Show = fn(&Fingerprint) -> String;
+
+/// The attributes of a file worth comparing, recorded and current,
+/// with "(none)" for a side with no fingerprint (say, a missing file)
+pub fn metadata_rows(
+    recorded: Option<&Fingerprint>,
+    current: Option<&Fingerprint>,
+) -> Vec<MetadataRow> {
+    let fields: [(&'static str, Show); 14] = [
+        ("content hash", |f| hex::encode(f.content_hash)),
+        ("size", |f| optional(f.size)),
+        ("mode", |f| optional(f.unix_mode.map(|m| format!("{m:o}")))),
+        ("owner", |f| optional(f.uid)),
+        ("group", |f| optional(f.gid)),
+        ("modified", |f| time(f.modified)),
+        ("created", |f| time(f.created)),
+        ("read only", |f| f.read_only.to_string()),
+        ("symlink", |f| f.symlink.to_string()),
+        ("directory", |f| f.directory.to_string()),
+        ("special", |f| optional(f.special)),
+        ("flags", |f| optional(f.flags.map(flag_names))),
+        ("inode", |f| optional(f.ino)),
+        ("attributes", |f| {
+            optional(f.windows_attributes.map(|a| format!("{a:#x}")))
+        }),
+    ];
+    let side = |fingerprint: Option<&Fingerprint>, show: Show| {
+        fingerprint
+            .map(show)
+            .unwrap_or_else(|| "(none)".to_string())
+    };
+    fields
+        .into_iter()
+        .map(|(field, show)| MetadataRow {
+            field,
+            recorded: side(recorded, show),
+            current: side(current, show),
+        })
+        .collect()
+}
+
+/// A file with findings under review
+pub struct Finding {
+    /// The file
+    pub path: PathBuf,
+
+    /// What verification reported about it
+    pub items: Vec<ReportItem>,
+
+    /// Its attributes, recorded and current
+    pub rows: Vec<MetadataRow>,
+
+    /// Its fingerprint now, as reviewed, if it could be taken
+    pub current: Option<Fingerprint>,
+
+    /// What to do about it
+    pub decision: Decision,
+}
+
+impl Finding {
+    pub fn new(
+        path: PathBuf,
+        items: Vec<ReportItem>,
+        recorded: Option<&Fingerprint>,
+        current: Option<Fingerprint>,
+    ) -> Self {
+        Finding {
+            rows: metadata_rows(recorded, current.as_ref()),
+            path,
+            items,
+            current,
+            decision: Decision::Undecided,
+        }
+    }
+
+    /// True if the file can be accepted as it is: there is a file to
+    /// accept and it was fingerprinted
+    pub fn acceptable(&self) -> bool {
+        let missing = self
+            .items
+            .iter()
+            .any(|item| matches!(item, ReportItem::FileMissing { .. }));
+        self.current.is_some() && !missing
+    }
+
+    /// The most severe of its findings
+    fn severity(&self) -> Severity {
+        self.items
+            .iter()
+            .map(ReportItem::severity)
+            .max()
+            .unwrap_or(Severity::Info)
+    }
+}
+
+/// The files under review, and which is selected
+pub struct Review {
+    pub findings: Vec<Finding>,
+    selected: usize,
+}
+
+impl Review {
+    pub fn new(findings: Vec<Finding>) -> Self {
+        Review {
+            findings,
+            selected: 0,
+        }
+    }
+
+    /// The file selected
+    pub fn selected(&self) -> Option<&Finding> {
+        self.findings.get(self.selected)
+    }
+
+    /// Select the next file, if there is one
+    pub fn next(&mut self) {
+        if self.selected + 1 < self.findings.len() {
+            self.selected += 1;
+        }
+    }
+
+    /// Select the previous file, if there is one
+    pub fn previous(&mut self) {
+        self.selected = self.selected.saturating_sub(1);
+    }
+
+    /// Decide what to do about the selected file (accepting only
+    /// those that can be) and move on to the next
+    pub fn decide(&mut self, decision: Decision) {
+        let Some(finding) = self.findings.get_mut(self.selected) else {
+            return;
+        };
+        if decision == Decision::Accept && !finding.acceptable() {
+            return;
+        }
+        finding.decision = decision;
+        self.next();
+    }
+
+    /// Number of files with a decision
+    pub fn count(&self, decision: Decision) -> usize {
+        self.findings
+            .iter()
+            .filter(|finding| finding.decision == decision)
+            .count()
+    }
+}
+
+/// Keys, shown at the foot of the screen
+const HELP: &str = "a accept  i ignore  v investigate  u undecide  ↑↓ move  enter finish  q quit";
+
+/// Draw the review: files on the left, the selected file's findings
+/// and metadata on the right
+fn draw(frame: &mut Frame, review: &Review) {
+    let [body, footer] =
+        Layout::vertical([Constraint::Min(0), Constraint::Length(1)]).areas(frame.area());
+    let [files, detail] =
+        Layout::horizontal([Constraint::Percentage(40), Constraint::Percentage(60)]).areas(body);
+
+    let items: Vec<ListItem> = review
+        .findings
+        .iter()
+        .map(|finding| {
+            let line = format!("{} {}", finding.decision.marker(), finding.path.display());
+            match finding.severity() {
+                Severity::Critical => ListItem::new(line).red().bold(),
+                Severity::Warning => ListItem::new(line).red(),
+                Severity::Info => ListItem::new(line).dim(),
+            }
+        })
+        .collect();
+    let list = List::new(items)
+        .block(Block::bordered().title(format!("Findings ({})", review.findings.len())))
+        .highlight_style(Style::new().add_modifier(Modifier::REVERSED));
+    let mut state = ListState::default().with_selected(Some(review.selected));
+    frame.render_stateful_widget(list, files, &mut state);
+
+    if let Some(finding) = review.selected() {
+        let reported: Vec<Line> = finding
+            .items
+            .iter()
+            .map(|item| Line::from(item.to_string()))
+            .collect();
+        let height = reported.len() as u16 + 2;
+        let [items, metadata] =
+            Layout::vertical([Constraint::Length(height), Constraint::Min(0)]).areas(detail);
+        frame.render_widget(
+            Paragraph::new(reported).block(Block::bordered().title("Reported")),
+            items,
+        );
+
+        let rows = finding.rows.iter().map(|row| {
+            let cells = [row.field, row.recorded.as_str(), row.current.as_str()];
+            match row.differs() {
+                true => Row::new(cells).red().bold(),
+                false => Row::new(cells),
+            }
+        });
+        let widths = [
+            Constraint::Length(12),
+            Constraint::Percentage(50),
+            Constraint::Percentage(50),
+        ];
+        let table = Table::new(rows, widths)
+            .header(Row::new(["", "recorded", "current"]).bold())
+            .block(Block::bordered().title("Metadata"));
+        frame.render_widget(table, metadata);
+    }
+
+    let counts = format!(
+        "  A:{} I:{} ?:{}",
+        review.count(Decision::Accept),
+        review.count(Decision::Ignore),
+        review.count(Decision::Investigate)
+    );
+    frame.render_widget(Paragraph::new(format!("{HELP}{counts}")).dim(), footer);
+}
+
+/// Handle keys until the review is finished or abandoned
+fn interact(terminal: &mut DefaultTerminal, mut review: Review) -> std::io::Result<Option<Review>> {
+    loop {
+        terminal.draw(|frame| draw(frame, &review))?;
+        let Event::Key(key) = event::read()? else {
+            continue;
+        };
+        if key.kind != KeyEventKind::Press {
+            continue;
+        }
+        match key.code {
+            KeyCode::Down | KeyCode::Char('j') => review.next(),
+            KeyCode::Up | KeyCode::Char('k') => review.previous(),
+            KeyCode::Char('a') => review.decide(Decision::Accept),
+            KeyCode::Char('i') => review.decide(Decision::Ignore),
+            KeyCode::Char('v') => review.decide(Decision::Investigate),
+            KeyCode::Char('u') => review.decide(Decision::Undecided),
+            KeyCode::Enter => return Ok(Some(review)),
+            KeyCode::Char('q') | KeyCode::Esc => return Ok(None),
+            _ => {}
+        }
+    }
+}
+
+/// Run the review on the terminal, giving the decisions made, or
+/// nothing if it was abandoned
+pub fn run(review: Review) -> Result<Option<Review>, FimblError> {
+    let mut terminal = ratatui::try_init().map_err(FimblError::TerminalError)?;
+    let result = interact(&mut terminal, review);
+    ratatui::try_restore().map_err(FimblError::TerminalError)?;
+    result.map_err(FimblError::TerminalError)
+}
+
+#[cfg(test)]
+pub mod tests {
+
+    use super::*;
+    use crate::fingerprint::fingerprint_file;
+
+    #[test]
+    fn test_metadata_rows() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("hosts");
+        std::fs::write(&path, "127.0.0.1 localhost").unwrap();
+        let recorded = fingerprint_file(&path).unwrap();
+        std::fs::write(&path, "10.0.0.1 localhost").unwrap();
+        let current = fingerprint_file(&path).unwrap();
+
+        let rows = metadata_rows(Some(&recorded), Some(&current));
+        let changed: Vec<_> = rows
+            .iter()
+            .filter(|row| row.differs())
+            .map(|row| row.field)
+            .collect();
+        assert!(changed.contains(&"content hash"));
+        assert!(changed.contains(&"size"));
+        assert!(!changed.contains(&"mode"));
+
+        let rows = metadata_rows(Some(&recorded), None);
+        assert!(rows.iter().all(|row| row.current == "(none)"));
+    }
+
+    #[test]
+    fn test_decide() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("hosts");
+        std::fs::write(&path, "127.0.0.1 localhost").unwrap();
+        let current = fingerprint_file(&path).unwrap();
+        let present = Finding::new(path.clone(), vec![], None, Some(current.clone()));
+        let missing = Finding::new(
+            path.clone(),
+            vec![ReportItem::FileMissing { path: path.clone() }],
+            Some(&current),
+            None,
+        );
+        let mut review = Review::new(vec![present, missing]);
+
+        review.decide(Decision::Accept);
+        assert_eq!(review.findings[0].decision, Decision::Accept);
+        // a missing file can't be accepted, and stays selected
+        review.decide(Decision::Accept);
+        assert_eq!(review.findings[1].decision, Decision::Undecided);
+        review.decide(Decision::Investigate);
+        assert_eq!(review.count(Decision::Investigate), 1);
+        review.next();
+        assert_eq!(review.selected().unwrap().decision, Decision::Investigate);
+        review.previous();
+        review.previous();
+        assert_eq!(review.selected().unwrap().decision, Decision::Accept);
+    }
+}
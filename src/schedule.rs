@@ -0,0 +1,404 @@
+//! When a running agent's next run is due
+//!
+//! By default runs follow one another at the agent's interval. A cron
+//! expression (`schedule = "0 */4 * * *"`) instead fixes the times of
+//! day, random jitter spreads a fleet's runs out so they don't all
+//! hit shared storage at once, and quiet hours (`quiet-hours = "Mon-Fri
+//! 09:00-17:00"`) put off any run falling in them until they end. Times
+//! are local (UTC where the local time zone isn't known).
+
+use std::{
+    str::FromStr,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+const MINUTE: i64 = 60;
+const DAY: i64 = 24 * 60 * MINUTE;
+
+/// Years searched for a time matching a cron expression
+const SEARCH_YEARS: i64 = 8;
+
+const MONTHS: &[&str] = &[
+    "jan", "feb", "mar", "apr", "may", "jun", "jul", "aug", "sep", "oct", "nov", "dec",
+];
+const WEEKDAYS: &[&str] = &["sun", "mon", "tue", "wed", "thu", "fri", "sat"];
+
+/// Year, month and day of a day counted from the unix epoch
+pub fn civil(days: i64) -> (i64, u32, u32) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + (month <= 2) as i64;
+    (year, month, day)
+}
+
+/// Day of the week (0 for Sunday) of a day counted from the unix epoch
+pub fn weekday(days: i64) -> u32 {
+    (days + 4).rem_euclid(7) as u32
+}
+
+/// Parse a cron field into a bitmask of the values it allows, between
+/// `min` and `max`, with `names` for values from `min` if given
+fn field(spec: &str, min: u32, max: u32, names: &[&str]) -> Result<u64, String> {
+    let value = |text: &str| -> Result<u32, String> {
+        let lower = text.to_ascii_lowercase();
+        let value = match names.iter().position(|name| *name == lower) {
+            Some(index) => index as u32 + min,
+            None => text.parse().map_err(|_| format!("bad value {text:?}"))?,
+        };
+        match value >= min && value <= max {
+            true => Ok(value),
+            false => Err(format!("{text} is not between {min} and {max}")),
+        }
+    };
+
+    let mut mask = 0;
+    for part in spec.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => match step.parse::<u32>() {
+                Ok(step) if step > 0 => (range, step),
+                _ => return Err(format!("bad step {step:?}")),
+            },
+            None => (part, 1),
+        };
+        let (first, last) = match range.split_once('-') {
+            _ if range == "*" => (min, max),
+            Some((first, last)) => (value(first)?, value(last)?),
+            None if step > 1 => (value(range)?, max),
+            None => (value(range)?, value(range)?),
+        };
+        if first > last {
+            return Err(format!("range {range} runs backwards"));
+        }
+        for value in (first..=last).step_by(step as usize) {
+            mask |= 1 << value;
+        }
+    }
+    Ok(mask)
+}
+
+/// Parse days of the week into a bitmask, Sunday being 0 or 7
+fn weekdays(spec: &str) -> Result<u64, String> {
+    let mask = field(spec, 0, 7, WEEKDAYS)?;
+    Ok(match mask & (1 << 7) {
+        0 => mask,
+        _ => (mask | 1) & !(1 << 7),
+    })
+}
+
+/// A cron expression: minute, hour, day of month, month and day of
+/// week, each `*`, a value, a range, a list and/or with a `/` step
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Cron {
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    /// Whether both days of the month and of the week are restricted,
+    /// in which case a day matching either will do
+    either_day: bool,
+}
+
+impl FromStr for Cron {
+    type Err = String;
+
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        let spec = match spec.trim() {
+            "@hourly" => "0 * * * *",
+            "@daily" => "0 0 * * *",
+            "@weekly" => "0 0 * * 0",
+            "@monthly" => "0 0 1 * *",
+            spec => spec,
+        };
+        let fields: Vec<_> = spec.split_whitespace().collect();
+        let [minute, hour, day, month, weekday] = fields[..] else {
+            return Err(format!("{spec:?} does not have five fields"));
+        };
+        let in_field = |name: &'static str| move |e: String| format!("{name}: {e}");
+        let cron = Cron {
+            minutes: field(minute, 0, 59, &[]).map_err(in_field("minute"))?,
+            hours: field(hour, 0, 23, &[]).map_err(in_field("hour"))?,
+            days: field(day, 1, 31, &[]).map_err(in_field("day of month"))?,
+            months: field(month, 1, 12, MONTHS).map_err(in_field("month"))?,
+            weekdays: weekdays(weekday).map_err(in_field("day of week"))?,
+            either_day: !day.starts_with('*') && !weekday.starts_with('*'),
+        };
+        match cron.next_after(0) {
+            Some(_) => Ok(cron),
+            None => Err(format!("{spec:?} never matches")),
+        }
+    }
+}
+
+impl Cron {
+    /// Whether runs may happen on a day counted from the unix epoch
+    fn on(&self, days: i64) -> bool {
+        let (_, month, day) = civil(days);
+        let by_date = self.days & (1 << day) != 0;
+        let by_weekday = self.weekdays & (1 << weekday(days)) != 0;
+        self.months & (1 << month) != 0
+            && match self.either_day {
+                true => by_date || by_weekday,
+                false => by_date && by_weekday,
+            }
+    }
+
+    /// The first matching time (in local seconds since the unix epoch)
+    /// after a time, if there is one within a few years
+    pub fn next_after(&self, time: i64) -> Option<i64> {
+        let start = time.div_euclid(MINUTE) * MINUTE + MINUTE;
+        let first_day = start.div_euclid(DAY);
+        (first_day..first_day + SEARCH_YEARS * 366)
+            .filter(|day| self.on(*day))
+            .find_map(|day| {
+                let first_minute = match day == first_day {
+                    true => start.rem_euclid(DAY) / MINUTE,
+                    false => 0,
+                };
+                (first_minute..DAY / MINUTE)
+                    .find(|minute| {
+                        self.hours & (1 << (minute / 60)) != 0
+                            && self.minutes & (1 << (minute % 60)) != 0
+                    })
+                    .map(|minute| day * DAY + minute * MINUTE)
+            })
+    }
+}
+
+/// Times of day, on some days of the week, when runs are put off
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct QuietHours {
+    /// Days (as a bitmask, bit 0 for Sunday) the quiet starts on
+    weekdays: u64,
+    /// Minute of the day the quiet starts
+    start: i64,
+    /// Minute of the day the quiet ends, the next day if before the
+    /// start
+    end: i64,
+}
+
+impl FromStr for QuietHours {
+    type Err = String;
+
+    /// Parse e.g. `09:00-17:00`, `Mon-Fri 09:00-17:00` or
+    /// `Sat,Sun 22:00-06:00`
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        let (days, hours) = match spec.trim().rsplit_once(' ') {
+            Some((days, hours)) => (days.trim(), hours),
+            None => ("*", spec.trim()),
+        };
+        let weekdays = weekdays(days)?;
+        let minute = |time: &str| -> Result<i64, String> {
+            let bad = || format!("bad time {time:?}, expected HH:MM");
+            let (hour, minute) = time.split_once(':').ok_or_else(bad)?;
+            match (hour.parse::<i64>(), minute.parse::<i64>()) {
+                (Ok(hour @ 0..=24), Ok(minute @ 0..=59)) if hour * 60 + minute <= 24 * 60 => {
+                    Ok(hour * 60 + minute)
+                }
+                _ => Err(bad()),
+            }
+        };
+        let (start, end) = hours
+            .split_once('-')
+            .ok_or_else(|| format!("bad hours {hours:?}, expected HH:MM-HH:MM"))?;
+        let (start, end) = (minute(start)?, minute(end)?);
+        if start == end {
+            return Err(format!("{hours} is empty"));
+        }
+        Ok(QuietHours {
+            weekdays,
+            start,
+            end,
+        })
+    }
+}
+
+impl QuietHours {
+    /// When the quiet ends (in local seconds since the unix epoch), if
+    /// a time is within it
+    pub fn until(&self, time: i64) -> Option<i64> {
+        let day = time.div_euclid(DAY);
+        let minute = time.rem_euclid(DAY) / MINUTE;
+        let starts_on = |day: i64| self.weekdays & (1 << weekday(day)) != 0;
+        let end_of = |day: i64| day * DAY + self.end * MINUTE;
+        if self.start < self.end {
+            (starts_on(day) && minute >= self.start && minute < self.end).then(|| end_of(day))
+        } else if starts_on(day) && minute >= self.start {
+            Some(end_of(day + 1))
+        } else {
+            (starts_on(day - 1) && minute < self.end).then(|| end_of(day))
+        }
+    }
+}
+
+/// Offset of local time from UTC, in seconds, at a time
+#[cfg(unix)]
+fn utc_offset(time: i64) -> i64 {
+    let time = time as libc::time_t;
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    match unsafe { libc::localtime_r(&time, &mut tm) }.is_null() {
+        true => 0,
+        false => tm.tm_gmtoff as i64,
+    }
+}
+
+#[cfg(not(unix))]
+fn utc_offset(_time: i64) -> i64 {
+    0
+}
+
+/// Local seconds since the unix epoch
+fn local(time: SystemTime) -> i64 {
+    let utc = time
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0);
+    utc + utc_offset(utc)
+}
+
+/// The time at local seconds since the unix epoch
+fn from_local(local: i64) -> SystemTime {
+    let utc = local - utc_offset(local - utc_offset(local));
+    UNIX_EPOCH + Duration::from_secs(utc.max(0) as u64)
+}
+
+/// How an agent's runs are spaced
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Schedule {
+    /// Time from the end of one run to the next, without a cron
+    /// expression
+    pub interval: Duration,
+
+    /// Times runs are due, if not at the interval
+    pub cron: Option<Cron>,
+
+    /// Most random delay added to each run
+    pub jitter: Duration,
+
+    /// When runs are put off, if ever
+    pub quiet_hours: Option<QuietHours>,
+}
+
+impl Schedule {
+    /// When the run after one finishing at a time is due, using a
+    /// random number for the jitter
+    ///
+    /// A run put off by quiet hours is due when they end, plus the
+    /// jitter unless that would take it into quiet hours again.
+    pub fn next_run(&self, finished: SystemTime, random: u64) -> SystemTime {
+        let jitter = match self.jitter.as_nanos() as u64 {
+            0 => Duration::ZERO,
+            most => Duration::from_nanos(random % most),
+        };
+        let due = match self
+            .cron
+            .as_ref()
+            .and_then(|c| c.next_after(local(finished)))
+        {
+            Some(due) => from_local(due),
+            None => finished + self.interval,
+        } + jitter;
+        let Some(quiet) = &self.quiet_hours else {
+            return due;
+        };
+        match quiet.until(local(due)).map(from_local) {
+            Some(end) if quiet.until(local(end + jitter)).is_none() => end + jitter,
+            Some(end) => end,
+            None => due,
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+
+    use super::*;
+
+    /// Local seconds at a time on 2024-07-01 (a Monday) plus `days`
+    fn at(days: i64, hour: i64, minute: i64) -> i64 {
+        (19905 + days) * DAY + hour * 3600 + minute * MINUTE
+    }
+
+    #[test]
+    fn test_civil() {
+        assert_eq!(civil(0), (1970, 1, 1));
+        assert_eq!(civil(19905), (2024, 7, 1));
+        assert_eq!(weekday(19905), 1);
+        assert_eq!(civil(-1), (1969, 12, 31));
+    }
+
+    #[test]
+    fn test_cron() {
+        let cron: Cron = "*/15 9-17 * * mon-fri".parse().unwrap();
+        assert_eq!(cron.next_after(at(0, 9, 0)), Some(at(0, 9, 15)));
+        assert_eq!(cron.next_after(at(0, 17, 50)), Some(at(1, 9, 0)));
+        assert_eq!(cron.next_after(at(4, 17, 45)), Some(at(7, 9, 0)));
+
+        let cron: Cron = "30 2 1 * *".parse().unwrap();
+        assert_eq!(cron.next_after(at(0, 2, 30)), Some(at(31, 2, 30)));
+
+        // either the 15th or a Sunday
+        let cron: Cron = "0 0 15 * 7".parse().unwrap();
+        assert_eq!(cron.next_after(at(0, 0, 0)), Some(at(6, 0, 0)));
+
+        assert_eq!("@daily".parse::<Cron>(), "0 0 * * *".parse());
+        assert!("0 0 30 feb *"
+            .parse::<Cron>()
+            .unwrap_err()
+            .contains("never"));
+        assert!("60 * * * *".parse::<Cron>().is_err());
+        assert!("* * * *".parse::<Cron>().is_err());
+        assert!("*/0 * * * *".parse::<Cron>().is_err());
+    }
+
+    #[test]
+    fn test_quiet_hours() {
+        let business: QuietHours = "Mon-Fri 09:00-17:00".parse().unwrap();
+        assert_eq!(business.until(at(0, 8, 59)), None);
+        assert_eq!(business.until(at(0, 9, 0)), Some(at(0, 17, 0)));
+        assert_eq!(business.until(at(0, 17, 0)), None);
+        assert_eq!(business.until(at(5, 12, 0)), None);
+
+        let nights: QuietHours = "Fri 22:00-06:00".parse().unwrap();
+        assert_eq!(nights.until(at(4, 23, 0)), Some(at(5, 6, 0)));
+        assert_eq!(nights.until(at(5, 5, 0)), Some(at(5, 6, 0)));
+        assert_eq!(nights.until(at(5, 23, 0)), None);
+
+        assert!("09:00-09:00".parse::<QuietHours>().is_err());
+        assert!("9-17".parse::<QuietHours>().is_err());
+        assert!("Mon-Fri 09:00-25:00".parse::<QuietHours>().is_err());
+    }
+
+    #[test]
+    fn test_next_run() {
+        let finished = UNIX_EPOCH + Duration::from_secs(1_000_000);
+        let mut schedule = Schedule {
+            interval: Duration::from_secs(3600),
+            ..Schedule::default()
+        };
+        assert_eq!(
+            schedule.next_run(finished, 123),
+            finished + Duration::from_secs(3600)
+        );
+        schedule.jitter = Duration::from_secs(600);
+        let due = schedule.next_run(finished, 7_000_000_000_123);
+        let jitter = Duration::from_nanos(400_000_000_123);
+        assert_eq!(due, finished + Duration::from_secs(3600) + jitter);
+
+        // put off until the quiet ends, plus the jitter
+        schedule.quiet_hours = Some("00:00-23:00".parse().unwrap());
+        let due = schedule.next_run(finished, 7_000_000_000_123);
+        assert_eq!(local(due - jitter).rem_euclid(DAY), 23 * 3600);
+
+        // but not into the next day's quiet
+        schedule.quiet_hours = Some("00:00-23:59".parse().unwrap());
+        let due = schedule.next_run(finished, 7_000_000_000_123);
+        assert_eq!(local(due).rem_euclid(DAY), 23 * 3600 + 59 * MINUTE);
+    }
+}
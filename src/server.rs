@@ -0,0 +1,312 @@
+//! Central fimbl server
+//!
+//! Serves database trees to remote clients (see `storage`) and
+//! collects the reports pushed by agents. The server only ever sees
+//! stored bytes; encrypted databases stay encrypted. It speaks plain
+//! HTTP so must sit behind a TLS terminating proxy, never facing the
+//! network directly.
+
+use crate::{agent::SigningKey, agent::SIGNATURE_HEADER, error::FimblError, storage::Entry};
+
+use ring::constant_time::verify_slices_are_equal;
+use sled::Db;
+use std::io::Read;
+use tiny_http::{Header, Request, Response, Server};
+use tracing::info;
+
+/// Trees which clients may access
+const TREES: &[&str] = &[
+    "fingerprints",
+    "logs",
+    "coverage",
+    "hash-cache",
+    "history",
+    "snapshots",
+    "pending",
+    "record-macs",
+    "aliases",
+    "meta",
+];
+
+/// Tree holding reports pushed by agents
+const REPORTS_TREE: &str = "reports";
+
+/// Largest request body accepted
+const MAX_BODY: u64 = 16 << 20;
+
+/// Settings for the server
+pub struct ServerConfig {
+    /// Bearer token required of all clients, if any (without one,
+    /// nothing may be written to the database trees)
+    pub token: Option<String>,
+
+    /// Key required to have signed pushed reports, if any
+    pub key: Option<SigningKey>,
+}
+
+/// A response: status, content type and body
+pub type Reply = (u16, &'static str, Vec<u8>);
+
+/// An empty response with a status
+pub fn status(code: u16) -> Reply {
+    (code, "text/plain", vec![])
+}
+
+/// Serve requests on the listen address until killed
+pub fn serve(db: &Db, listen: &str, config: &ServerConfig) -> Result<(), FimblError> {
+    let server = Server::http(listen).map_err(|e| FimblError::ServerError(e.to_string()))?;
+
+    for mut request in server.incoming_requests() {
+        let authorization = header(&request, "Authorization");
+        let signature = header(&request, SIGNATURE_HEADER);
+
+        let (code, content_type, reply) = if request
+            .body_length()
+            .is_some_and(|length| length as u64 > MAX_BODY)
+        {
+            status(413)
+        } else {
+            let method = request.method().as_str().to_string();
+            let url = request.url().to_string();
+            route(
+                db,
+                config,
+                &method,
+                &url,
+                authorization.as_deref(),
+                signature.as_deref(),
+                request.as_reader(),
+            )
+            .unwrap_or_else(|_| status(500))
+        };
+
+        info!(method = %request.method(), url = request.url(), code, "request");
+        respond(request, code, content_type, reply);
+    }
+
+    Ok(())
+}
+
+/// Value of a request header, if present
+fn header(request: &Request, name: &'static str) -> Option<String> {
+    request
+        .headers()
+        .iter()
+        .find(|h| h.field.equiv(name))
+        .map(|h| h.value.as_str().to_string())
+}
+
+/// Send a response, ignoring clients that have gone away
+pub fn respond(request: Request, code: u16, content_type: &str, body: Vec<u8>) {
+    let header = Header::from_bytes("Content-Type", content_type).unwrap();
+    let _ = request.respond(
+        Response::from_data(body)
+            .with_status_code(code)
+            .with_header(header),
+    );
+}
+
+/// Whether the authorization header presents the token, compared in
+/// constant time
+fn presents(authorization: Option<&str>, token: &str) -> bool {
+    authorization
+        .and_then(|a| a.strip_prefix("Bearer "))
+        .is_some_and(|given| verify_slices_are_equal(given.as_bytes(), token.as_bytes()).is_ok())
+}
+
+/// Handle a single request, reading its body only once it is
+/// authorized
+fn route(
+    db: &Db,
+    config: &ServerConfig,
+    method: &str,
+    url: &str,
+    authorization: Option<&str>,
+    signature: Option<&str>,
+    body: &mut dyn Read,
+) -> Result<Reply, FimblError> {
+    match &config.token {
+        Some(token) if !presents(authorization, token) => return Ok(status(401)),
+        None if matches!(method, "PUT" | "DELETE") => return Ok(status(403)),
+        _ => {}
+    }
+
+    let mut bytes = vec![];
+    if body.take(MAX_BODY + 1).read_to_end(&mut bytes).is_err() {
+        return Ok(status(400));
+    }
+    if bytes.len() as u64 > MAX_BODY {
+        return Ok(status(413));
+    }
+    let body = bytes;
+
+    let (path, query) = url.split_once('?').unwrap_or((url, ""));
+    let mut segments = path.trim_matches('/').split('/');
+
+    match (method, segments.next(), segments.next(), segments.next()) {
+        ("POST", Some(REPORTS_TREE), None, None) => {
+            if let Some(key) = &config.key {
+                if !signature.is_some_and(|s| key.verify(&body, s)) {
+                    return Ok(status(403));
+                }
+            }
+            if serde_json::from_slice::<serde_json::Value>(&body).is_err() {
+                return Ok(status(400));
+            }
+            let reports = db.open_tree(REPORTS_TREE)?;
+            reports.insert(db.generate_id()?.to_be_bytes(), body)?;
+            Ok(status(201))
+        }
+        ("GET", Some(REPORTS_TREE), None, None) => {
+            let mut json = b"[".to_vec();
+            for (i, item) in db.open_tree(REPORTS_TREE)?.iter().enumerate() {
+                if i > 0 {
+                    json.push(b',');
+                }
+                json.extend_from_slice(&item?.1);
+            }
+            json.push(b']');
+            Ok((200, "application/json", json))
+        }
+        (method, Some(tree), key, None) if TREES.contains(&tree) => {
+            let tree = db.open_tree(tree)?;
+            let key = match key.map(hex::decode) {
+                Some(Ok(key)) => Some(key),
+                Some(Err(_)) => return Ok(status(400)),
+                None => None,
+            };
+
+            match (method, key) {
+                ("GET", Some(key)) => match tree.get(key)? {
+                    Some(value) => Ok((200, "application/octet-stream", value.to_vec())),
+                    None => Ok(status(404)),
+                },
+                ("PUT", Some(key)) => {
+                    tree.insert(key, body)?;
+                    Ok(status(204))
+                }
+                ("DELETE", Some(key)) => {
+                    tree.remove(key)?;
+                    Ok(status(204))
+                }
+                ("GET", None) => {
+                    let Ok(prefix) = hex::decode(query.strip_prefix("prefix=").unwrap_or(""))
+                    else {
+                        return Ok(status(400));
+                    };
+                    let entries = tree
+                        .scan_prefix(prefix)
+                        .map(|item| item.map(|(k, v)| (k.to_vec(), v.to_vec())))
+                        .collect::<Result<Vec<Entry>, _>>()?;
+                    Ok((
+                        200,
+                        "application/msgpack",
+                        rmp_serde::to_vec(&entries).unwrap(),
+                    ))
+                }
+                _ => Ok(status(405)),
+            }
+        }
+        _ => Ok(status(404)),
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+
+    use super::*;
+
+    fn temporary_db() -> Db {
+        sled::Config::new().temporary(true).open().unwrap()
+    }
+
+    #[test]
+    fn test_store_protocol() {
+        let db = temporary_db();
+        let config = ServerConfig {
+            token: Some("t".to_string()),
+            key: None,
+        };
+        let call = |method, url: &str, mut body: &[u8]| {
+            route(&db, &config, method, url, Some("Bearer t"), None, &mut body).unwrap()
+        };
+
+        assert_eq!(call("GET", "/fingerprints/2f", b"").0, 404);
+        assert_eq!(call("PUT", "/fingerprints/2f", b"root").0, 204);
+        assert_eq!(call("GET", "/fingerprints/2f", b"").2, b"root");
+        let (code, _, body) = call("GET", "/fingerprints?prefix=", b"");
+        assert_eq!(code, 200);
+        let entries: Vec<Entry> = rmp_serde::from_slice(&body).unwrap();
+        assert_eq!(entries, vec![(b"/".to_vec(), b"root".to_vec())]);
+        assert_eq!(call("GET", "/elsewhere/2f", b"").0, 404);
+        assert_eq!(call("DELETE", "/fingerprints/2f", b"").0, 204);
+        assert_eq!(call("GET", "/fingerprints/2f", b"").0, 404);
+
+        let oversized = vec![0; MAX_BODY as usize + 1];
+        assert_eq!(call("PUT", "/fingerprints/2f", &oversized).0, 413);
+        assert_eq!(call("GET", "/fingerprints/2f", b"").0, 404);
+    }
+
+    #[test]
+    fn test_writes_require_token() {
+        let db = temporary_db();
+        let call = |token: Option<&str>, method, authorization| {
+            let config = ServerConfig {
+                token: token.map(str::to_string),
+                key: None,
+            };
+            let url = "/fingerprints/2f";
+            route(
+                &db,
+                &config,
+                method,
+                url,
+                authorization,
+                None,
+                &mut &b"root"[..],
+            )
+            .unwrap()
+            .0
+        };
+
+        assert_eq!(call(None, "PUT", None), 403);
+        assert_eq!(call(None, "DELETE", None), 403);
+        assert_eq!(call(None, "GET", None), 404);
+        assert_eq!(call(Some("t"), "PUT", Some("Bearer u")), 401);
+        assert_eq!(call(Some("t"), "PUT", Some("t")), 401);
+        assert_eq!(call(Some("t"), "PUT", Some("Bearer t")), 204);
+    }
+
+    #[test]
+    fn test_reports_require_token_and_signature() {
+        let db = temporary_db();
+        let config = ServerConfig {
+            token: Some("t".to_string()),
+            key: Some(SigningKey::from_bytes(b"k")),
+        };
+        let body = br#"{"host":"web1"}"#.to_vec();
+        let signature = SigningKey::from_bytes(b"k").sign(&body);
+        let post = |auth, sig| {
+            route(&db, &config, "POST", "/reports", auth, sig, &mut &body[..])
+                .unwrap()
+                .0
+        };
+
+        assert_eq!(post(None, Some(&signature)), 401);
+        assert_eq!(post(Some("Bearer t"), None), 403);
+        assert_eq!(post(Some("Bearer t"), Some(&signature)), 201);
+
+        let (code, _, reports) = route(
+            &db,
+            &config,
+            "GET",
+            "/reports",
+            Some("Bearer t"),
+            None,
+            &mut &b""[..],
+        )
+        .unwrap();
+        assert_eq!(code, 200);
+        assert_eq!(reports, br#"[{"host":"web1"}]"#);
+    }
+}
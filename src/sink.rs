@@ -0,0 +1,333 @@
+//! Where reports go
+//!
+//! Each sink delivers a run's report somewhere: stdout, a file, the
+//! system log (or, on Windows, the Event Log) or a webhook. Commands hand their report to every sink
+//! asked for (`--report-to stdout,json-file:/var/log/fimbl.json`), so
+//! a new sink needs no changes to them.
+
+use crate::{
+    agent::RunReport,
+    error::FimblError,
+    output::{self, Format},
+};
+
+use std::str::FromStr;
+
+/// A destination for reports
+pub trait ReportSink {
+    /// Deliver a run's report (followed by its summary, if wanted)
+    fn report(&mut self, run: &RunReport, show_summary: bool) -> Result<(), FimblError>;
+}
+
+/// A sink, as named on the command line
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SinkSpec {
+    /// `stdout`, in the format given by `--format`
+    Stdout,
+    /// `file:FILE`, in the format its extension suggests
+    File(String),
+    /// `json-file:FILE`, as JSON whatever its extension
+    JsonFile(String),
+    /// `syslog`, one message per item
+    Syslog,
+    /// `eventlog`, one Windows event per item
+    EventLog,
+    /// `webhook:URL`, posting the JSON report
+    Webhook(String),
+}
+
+impl FromStr for SinkSpec {
+    type Err = String;
+
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        let nonempty = |value: &str, what: &str| match value.is_empty() {
+            true => Err(format!("{spec} names no {what}")),
+            false => Ok(value.to_string()),
+        };
+        match spec.split_once(':') {
+            None if spec == "stdout" => Ok(SinkSpec::Stdout),
+            None if spec == "syslog" => match cfg!(unix) {
+                true => Ok(SinkSpec::Syslog),
+                false => Err("syslog is only available on unix".to_string()),
+            },
+            None if spec == "eventlog" => match cfg!(windows) {
+                true => Ok(SinkSpec::EventLog),
+                false => Err("eventlog is only available on Windows".to_string()),
+            },
+            Some(("file", file)) => Ok(SinkSpec::File(nonempty(file, "file")?)),
+            Some(("json-file", file)) => Ok(SinkSpec::JsonFile(nonempty(file, "file")?)),
+            Some(("webhook", url)) => Ok(SinkSpec::Webhook(nonempty(url, "URL")?)),
+            _ => Err(format!(
+                "{spec}: expected stdout, file:FILE, json-file:FILE, syslog, eventlog or webhook:URL"
+            )),
+        }
+    }
+}
+
+/// How sinks render and deliver reports, where they have a choice
+pub struct SinkOptions {
+    /// Format for stdout
+    pub format: Format,
+
+    /// Append to files rather than replacing them
+    pub append: bool,
+
+    /// Bearer token for webhooks
+    pub webhook_token: Option<String>,
+}
+
+impl SinkSpec {
+    /// The sink named
+    pub fn open(&self, options: &SinkOptions) -> Box<dyn ReportSink> {
+        match self {
+            SinkSpec::Stdout => Box::new(Stdout {
+                format: options.format,
+                color: output::use_color(),
+            }),
+            SinkSpec::File(template) => Box::new(File {
+                template: template.clone(),
+                format: None,
+                append: options.append,
+            }),
+            SinkSpec::JsonFile(template) => Box::new(File {
+                template: template.clone(),
+                format: Some(Format::Json),
+                append: options.append,
+            }),
+            #[cfg(unix)]
+            SinkSpec::Syslog => Box::new(Syslog::open()),
+            #[cfg(not(unix))]
+            SinkSpec::Syslog => unreachable!("syslog is refused when parsed"),
+            #[cfg(windows)]
+            SinkSpec::EventLog => Box::new(EventLog),
+            #[cfg(not(windows))]
+            SinkSpec::EventLog => unreachable!("eventlog is refused when parsed"),
+            SinkSpec::Webhook(url) => Box::new(Webhook {
+                url: url.clone(),
+                token: options.webhook_token.clone(),
+            }),
+        }
+    }
+}
+
+/// The report on stdout
+struct Stdout {
+    format: Format,
+    color: bool,
+}
+
+impl ReportSink for Stdout {
+    fn report(&mut self, run: &RunReport, show_summary: bool) -> Result<(), FimblError> {
+        print!(
+            "{}",
+            output::render_run(run, self.format, self.color, show_summary)
+        );
+        Ok(())
+    }
+}
+
+/// The report in a file, named by a template (see
+/// [`output::output_path`])
+struct File {
+    template: String,
+    format: Option<Format>,
+    append: bool,
+}
+
+impl ReportSink for File {
+    fn report(&mut self, run: &RunReport, show_summary: bool) -> Result<(), FimblError> {
+        output::write_file(&self.template, self.format, self.append, run, show_summary)
+    }
+}
+
+/// Each item in the system log, led by its code, at a priority matching
+/// its severity
+#[cfg(unix)]
+struct Syslog;
+
+#[cfg(unix)]
+impl Syslog {
+    fn open() -> Self {
+        // the identity must outlive the connection, hence a static
+        unsafe { libc::openlog(c"fimbl".as_ptr(), libc::LOG_PID, libc::LOG_USER) };
+        Syslog
+    }
+
+    fn log(priority: libc::c_int, message: &str) {
+        let message = std::ffi::CString::new(message.replace('\0', "")).unwrap();
+        unsafe { libc::syslog(priority, c"%s".as_ptr(), message.as_ptr()) };
+    }
+}
+
+#[cfg(unix)]
+impl ReportSink for Syslog {
+    fn report(&mut self, run: &RunReport, show_summary: bool) -> Result<(), FimblError> {
+        use crate::report::Severity;
+
+        for item in run.items {
+            let priority = match item.severity() {
+                Severity::Critical => libc::LOG_CRIT,
+                Severity::Warning => libc::LOG_WARNING,
+                Severity::Info => libc::LOG_INFO,
+            };
+            Syslog::log(priority, &format!("{}: {item}", item.code()));
+        }
+        if show_summary {
+            Syslog::log(libc::LOG_INFO, &format!("summary: {}", run.summary));
+        }
+        Ok(())
+    }
+}
+
+/// Each item in the Windows Event Log (Application), from the source
+/// `fimbl`, with a type matching its severity and an event id from its
+/// code: F001 is 6001, A for 1000s to Z for 26000s
+#[cfg(windows)]
+struct EventLog;
+
+#[cfg(windows)]
+impl ReportSink for EventLog {
+    fn report(&mut self, run: &RunReport, show_summary: bool) -> Result<(), FimblError> {
+        use crate::report::Severity;
+        use windows_sys::Win32::System::EventLog::{
+            DeregisterEventSource, RegisterEventSourceW, ReportEventW, EVENTLOG_ERROR_TYPE,
+            EVENTLOG_INFORMATION_TYPE, EVENTLOG_WARNING_TYPE,
+        };
+
+        let failed = || {
+            FimblError::ReportSinkError(
+                "eventlog".to_string(),
+                std::io::Error::last_os_error().to_string(),
+            )
+        };
+        let wide = |text: &str| -> Vec<u16> {
+            text.replace('\0', "")
+                .encode_utf16()
+                .chain(Some(0))
+                .collect()
+        };
+        let source = wide("fimbl");
+        let handle = unsafe { RegisterEventSourceW(std::ptr::null(), source.as_ptr()) };
+        if handle == 0 {
+            return Err(failed());
+        }
+
+        let mut events = vec![];
+        for item in run.items {
+            let kind = match item.severity() {
+                Severity::Critical => EVENTLOG_ERROR_TYPE,
+                Severity::Warning => EVENTLOG_WARNING_TYPE,
+                Severity::Info => EVENTLOG_INFORMATION_TYPE,
+            };
+            events.push((
+                kind,
+                event_id(item.code()),
+                format!("{}: {item}", item.code()),
+            ));
+        }
+        if show_summary {
+            let summary = format!("summary: {}", run.summary);
+            events.push((EVENTLOG_INFORMATION_TYPE, 0, summary));
+        }
+        let mut result = Ok(());
+        for (kind, id, message) in events {
+            let message = wide(&message);
+            let strings = [message.as_ptr()];
+            let reported = unsafe {
+                ReportEventW(
+                    handle,
+                    kind,
+                    0,
+                    id,
+                    std::ptr::null_mut(),
+                    1,
+                    0,
+                    strings.as_ptr(),
+                    std::ptr::null(),
+                )
+            };
+            if reported == 0 {
+                result = Err(failed());
+                break;
+            }
+        }
+        unsafe { DeregisterEventSource(handle) };
+        result
+    }
+}
+
+/// Windows event id of an item's code
+#[cfg(windows)]
+fn event_id(code: &str) -> u32 {
+    let mut chars = code.chars();
+    let letter = chars.next().map_or(0, |c| c as u32 - 'A' as u32 + 1);
+    letter * 1000 + chars.as_str().parse::<u32>().unwrap_or(0)
+}
+
+/// The JSON report posted to a URL
+struct Webhook {
+    url: String,
+    token: Option<String>,
+}
+
+impl ReportSink for Webhook {
+    fn report(&mut self, run: &RunReport, _show_summary: bool) -> Result<(), FimblError> {
+        let mut request = ureq::post(&self.url).set("Content-Type", "application/json");
+        if let Some(token) = &self.token {
+            request = request.set("Authorization", &format!("Bearer {token}"));
+        }
+        request
+            .send_bytes(&serde_json::to_vec(run).unwrap())
+            .map(|_| ())
+            .map_err(|e| FimblError::ReportSinkError("webhook".to_string(), e.to_string()))
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+
+    use super::*;
+    use crate::report::{ReportItem, Summary};
+    use std::{path::PathBuf, time::Instant};
+
+    #[test]
+    fn test_parse() {
+        assert_eq!("stdout".parse(), Ok(SinkSpec::Stdout));
+        assert_eq!(
+            "json-file:/var/log/fimbl.json".parse(),
+            Ok(SinkSpec::JsonFile("/var/log/fimbl.json".to_string()))
+        );
+        assert_eq!(
+            "webhook:https://example.com/hook".parse(),
+            Ok(SinkSpec::Webhook("https://example.com/hook".to_string()))
+        );
+        assert!("file:".parse::<SinkSpec>().is_err());
+        assert!("stderr".parse::<SinkSpec>().is_err());
+    }
+
+    #[test]
+    fn test_json_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("report.txt");
+        let items = vec![ReportItem::FileMissing {
+            path: PathBuf::from("/etc/hosts"),
+        }];
+        let summary = Summary::new(Instant::now(), 1, 0, &items);
+        let run = RunReport::new("web1", &items, &summary);
+        let options = SinkOptions {
+            format: Format::Text,
+            append: true,
+            webhook_token: None,
+        };
+
+        let mut sink = SinkSpec::JsonFile(file.to_string_lossy().into_owned()).open(&options);
+        sink.report(&run, true).unwrap();
+        sink.report(&run, true).unwrap();
+        let written = std::fs::read_to_string(&file).unwrap();
+        assert_eq!(written.lines().count(), 2);
+        for line in written.lines() {
+            let json: serde_json::Value = serde_json::from_str(line).unwrap();
+            assert_eq!(json["host"], "web1");
+        }
+    }
+}
@@ -0,0 +1,394 @@
+//! Key-value storage behind the database, local or remote
+//!
+//! Each tree of the database (fingerprints, logs, meta) is a `Store`.
+//! Locally this is a sled tree. Remotely, a fimbl server exposes each
+//! tree as a collection over HTTP(S):
+//!
+//! - `GET {base}/{tree}/{hex key}` returns the value (404 if absent)
+//! - `PUT {base}/{tree}/{hex key}` stores the request body as value
+//! - `DELETE {base}/{tree}/{hex key}` removes the entry
+//! - `GET {base}/{tree}?prefix={hex prefix}` returns all entries with
+//!   keys starting with the prefix as a MessagePack array of
+//!   `[key, value]` pairs, in key order
+//!
+//! If a token is configured it is sent as a bearer token.
+//!
+//! For a dry run, each store is wrapped in an `Overlay`, which holds
+//! the changes made in memory and leaves the store itself untouched.
+
+use crate::error::FimblError;
+
+use std::{collections::BTreeMap, io::Read, iter::Peekable, sync::Mutex};
+
+/// A stored entry: key and value
+pub type Entry = (Vec<u8>, Vec<u8>);
+
+/// Iterator over stored entries
+pub type Entries<'a> = Box<dyn Iterator<Item = Result<Entry, FimblError>> + 'a>;
+
+/// An ordered key-value collection holding one tree of the database
+pub trait Store {
+    /// Value stored under a key, if any
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, FimblError>;
+
+    /// Store a value under a key, replacing any previous value
+    fn insert(&self, key: &[u8], value: Vec<u8>) -> Result<(), FimblError>;
+
+    /// Remove the entry for a key, if any
+    fn remove(&self, key: &[u8]) -> Result<(), FimblError>;
+
+    /// Entries whose keys start with prefix, in key order
+    fn scan_prefix(&self, prefix: &[u8]) -> Entries<'_>;
+
+    /// Entries whose keys are at or after start, in key order
+    fn scan_from(&self, start: &[u8]) -> Entries<'_> {
+        let start = start.to_vec();
+        Box::new(self.scan_prefix(b"").filter(move |item| match item {
+            Ok((key, _)) => *key >= start,
+            Err(_) => true,
+        }))
+    }
+
+    /// True if the store has no entries
+    fn is_empty(&self) -> Result<bool, FimblError> {
+        Ok(self.scan_prefix(b"").next().transpose()?.is_none())
+    }
+}
+
+impl Store for sled::Tree {
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, FimblError> {
+        Ok(sled::Tree::get(self, key)?.map(|v| v.to_vec()))
+    }
+
+    fn insert(&self, key: &[u8], value: Vec<u8>) -> Result<(), FimblError> {
+        sled::Tree::insert(self, key, value)?;
+        Ok(())
+    }
+
+    fn remove(&self, key: &[u8]) -> Result<(), FimblError> {
+        sled::Tree::remove(self, key)?;
+        Ok(())
+    }
+
+    fn scan_prefix(&self, prefix: &[u8]) -> Entries<'_> {
+        Box::new(sled::Tree::scan_prefix(self, prefix).map(|item| {
+            item.map(|(k, v)| (k.to_vec(), v.to_vec()))
+                .map_err(FimblError::from)
+        }))
+    }
+
+    fn scan_from(&self, start: &[u8]) -> Entries<'_> {
+        Box::new(sled::Tree::range(self, start..).map(|item| {
+            item.map(|(k, v)| (k.to_vec(), v.to_vec()))
+                .map_err(FimblError::from)
+        }))
+    }
+
+    fn is_empty(&self) -> Result<bool, FimblError> {
+        Ok(sled::Tree::is_empty(self))
+    }
+}
+
+/// One tree of a database held by a remote fimbl server
+pub struct RemoteStore {
+    /// HTTP agent (shared connection pool)
+    agent: ureq::Agent,
+
+    /// URL of the tree's collection
+    url: String,
+
+    /// Bearer token for authentication
+    token: Option<String>,
+}
+
+impl RemoteStore {
+    /// Store for the named tree of the database at base URL
+    pub fn new(agent: ureq::Agent, base: &str, tree: &str, token: Option<String>) -> Self {
+        RemoteStore {
+            agent,
+            url: format!("{}/{}", base.trim_end_matches('/'), tree),
+            token,
+        }
+    }
+
+    /// Prepare a request, adding authentication
+    fn request(&self, method: &str, url: &str) -> ureq::Request {
+        let request = self.agent.request(method, url);
+        match &self.token {
+            Some(token) => request.set("Authorization", &format!("Bearer {token}")),
+            None => request,
+        }
+    }
+
+    /// URL of the entry for a key
+    fn entry_url(&self, key: &[u8]) -> String {
+        format!("{}/{}", self.url, hex::encode(key))
+    }
+}
+
+/// Read the entire body of a response
+fn read_body(response: ureq::Response) -> Result<Vec<u8>, FimblError> {
+    let mut body = vec![];
+    response.into_reader().read_to_end(&mut body)?;
+    Ok(body)
+}
+
+impl From<ureq::Error> for FimblError {
+    fn from(e: ureq::Error) -> Self {
+        FimblError::RemoteError(e.to_string())
+    }
+}
+
+impl Store for RemoteStore {
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, FimblError> {
+        match self.request("GET", &self.entry_url(key)).call() {
+            Ok(response) => Ok(Some(read_body(response)?)),
+            Err(ureq::Error::Status(404, _)) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn insert(&self, key: &[u8], value: Vec<u8>) -> Result<(), FimblError> {
+        self.request("PUT", &self.entry_url(key))
+            .send_bytes(&value)?;
+        Ok(())
+    }
+
+    fn remove(&self, key: &[u8]) -> Result<(), FimblError> {
+        match self.request("DELETE", &self.entry_url(key)).call() {
+            Ok(_) | Err(ureq::Error::Status(404, _)) => Ok(()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn scan_prefix(&self, prefix: &[u8]) -> Entries<'_> {
+        let entries = self
+            .request("GET", &self.url)
+            .query("prefix", &hex::encode(prefix))
+            .call()
+            .map_err(FimblError::from)
+            .and_then(read_body)
+            .and_then(|body| {
+                rmp_serde::from_slice::<Vec<Entry>>(&body)
+                    .map_err(|e| FimblError::RemoteError(e.to_string()))
+            });
+
+        match entries {
+            Ok(entries) => Box::new(entries.into_iter().map(Ok)),
+            Err(e) => Box::new(std::iter::once(Err(e))),
+        }
+    }
+}
+
+/// Changes to a store held in memory, over the store left as it is
+///
+/// Reads see the changes made so far, removals included, so a dry run
+/// reports what a real one would.
+pub struct Overlay {
+    base: Box<dyn Store>,
+
+    /// Values stored since, or none for entries removed
+    changes: Mutex<BTreeMap<Vec<u8>, Option<Vec<u8>>>>,
+}
+
+impl Overlay {
+    /// An overlay of a store, with no changes yet
+    pub fn new(base: Box<dyn Store>) -> Self {
+        Overlay {
+            base,
+            changes: Mutex::new(BTreeMap::new()),
+        }
+    }
+}
+
+impl Store for Overlay {
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, FimblError> {
+        match self.changes.lock().unwrap().get(key) {
+            Some(change) => Ok(change.clone()),
+            None => self.base.get(key),
+        }
+    }
+
+    fn insert(&self, key: &[u8], value: Vec<u8>) -> Result<(), FimblError> {
+        self.changes
+            .lock()
+            .unwrap()
+            .insert(key.to_vec(), Some(value));
+        Ok(())
+    }
+
+    fn remove(&self, key: &[u8]) -> Result<(), FimblError> {
+        self.changes.lock().unwrap().insert(key.to_vec(), None);
+        Ok(())
+    }
+
+    fn scan_prefix(&self, prefix: &[u8]) -> Entries<'_> {
+        let changes: Vec<_> = self
+            .changes
+            .lock()
+            .unwrap()
+            .range(prefix.to_vec()..)
+            .take_while(|(key, _)| key.starts_with(prefix))
+            .map(|(key, change)| (key.clone(), change.clone()))
+            .collect();
+        Box::new(Merged {
+            base: self.base.scan_prefix(prefix).peekable(),
+            changes: changes.into_iter().peekable(),
+        })
+    }
+}
+
+/// Entries of a store merged, in key order, with changes over them
+struct Merged<'a, C: Iterator<Item = (Vec<u8>, Option<Vec<u8>>)>> {
+    base: Peekable<Entries<'a>>,
+    changes: Peekable<C>,
+}
+
+impl<C: Iterator<Item = (Vec<u8>, Option<Vec<u8>>)>> Iterator for Merged<'_, C> {
+    type Item = Result<Entry, FimblError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let changed_first = match (self.base.peek(), self.changes.peek()) {
+                (_, None) | (Some(Err(_)), _) => return self.base.next(),
+                (Some(Ok((key, _))), Some((changed, _))) => changed <= key,
+                (None, Some(_)) => true,
+            };
+            if !changed_first {
+                return self.base.next();
+            }
+            let (key, change) = self.changes.next().unwrap();
+            if matches!(self.base.peek(), Some(Ok((base_key, _))) if *base_key == key) {
+                self.base.next();
+            }
+            if let Some(value) = change {
+                return Some(Ok((key, value)));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+
+    use super::*;
+    use std::{
+        collections::BTreeMap,
+        io::{BufRead, BufReader, Write},
+        net::TcpListener,
+        sync::{Arc, Mutex},
+        thread,
+    };
+
+    /// Serve the remote protocol for a single tree from memory,
+    /// returning the base URL
+    fn serve(tree: &'static str) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let base = format!("http://{}", listener.local_addr().unwrap());
+        let entries = Arc::new(Mutex::new(BTreeMap::<Vec<u8>, Vec<u8>>::new()));
+
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut request_line = String::new();
+                reader.read_line(&mut request_line).unwrap();
+                let mut length = 0;
+                loop {
+                    let mut header = String::new();
+                    reader.read_line(&mut header).unwrap();
+                    if header.trim().is_empty() {
+                        break;
+                    }
+                    if let Some(value) = header.to_lowercase().strip_prefix("content-length:") {
+                        length = value.trim().parse().unwrap();
+                    }
+                }
+                let mut body = vec![0; length];
+                reader.read_exact(&mut body).unwrap();
+
+                let mut parts = request_line.split_whitespace();
+                let method = parts.next().unwrap();
+                let target = parts.next().unwrap();
+                let (path, query) = target.split_once('?').unwrap_or((target, ""));
+                let path = path.trim_start_matches(&format!("/{tree}"));
+                let mut entries = entries.lock().unwrap();
+
+                let (status, reply) = match (method, path.strip_prefix('/')) {
+                    ("GET", Some(key)) => match entries.get(&hex::decode(key).unwrap()) {
+                        Some(value) => ("200 OK", value.clone()),
+                        None => ("404 Not Found", vec![]),
+                    },
+                    ("PUT", Some(key)) => {
+                        entries.insert(hex::decode(key).unwrap(), body);
+                        ("204 No Content", vec![])
+                    }
+                    ("GET", None) => {
+                        let prefix = hex::decode(query.trim_start_matches("prefix=")).unwrap();
+                        let matching: Vec<Entry> = entries
+                            .iter()
+                            .filter(|(k, _)| k.starts_with(&prefix))
+                            .map(|(k, v)| (k.clone(), v.clone()))
+                            .collect();
+                        ("200 OK", rmp_serde::to_vec(&matching).unwrap())
+                    }
+                    _ => ("400 Bad Request", vec![]),
+                };
+
+                write!(
+                    stream,
+                    "HTTP/1.1 {status}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                    reply.len()
+                )
+                .unwrap();
+                stream.write_all(&reply).unwrap();
+            }
+        });
+
+        base
+    }
+
+    #[test]
+    fn test_remote_store_round_trip() {
+        let base = serve("fingerprints");
+        let store = RemoteStore::new(ureq::Agent::new(), &base, "fingerprints", None);
+
+        assert!(store.is_empty().unwrap());
+        assert_eq!(store.get(b"/etc/hosts").unwrap(), None);
+        store.insert(b"/etc/hosts", b"one".to_vec()).unwrap();
+        store.insert(b"/usr/bin", b"two".to_vec()).unwrap();
+        assert_eq!(store.get(b"/etc/hosts").unwrap(), Some(b"one".to_vec()));
+
+        let etc: Vec<Entry> = store.scan_prefix(b"/etc").map(Result::unwrap).collect();
+        assert_eq!(etc, vec![(b"/etc/hosts".to_vec(), b"one".to_vec())]);
+        assert!(!store.is_empty().unwrap());
+    }
+
+    #[test]
+    fn test_overlay() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let tree = db.open_tree("fingerprints").unwrap();
+        tree.insert(b"/etc/group", b"kept".to_vec()).unwrap();
+        tree.insert(b"/etc/hosts", b"old".to_vec()).unwrap();
+        tree.insert(b"/etc/passwd", b"removed".to_vec()).unwrap();
+
+        let overlay = Overlay::new(Box::new(tree.clone()));
+        overlay.insert(b"/etc/hosts", b"new".to_vec()).unwrap();
+        overlay.insert(b"/etc/fstab", b"added".to_vec()).unwrap();
+        overlay.remove(b"/etc/passwd").unwrap();
+        assert_eq!(overlay.get(b"/etc/hosts").unwrap(), Some(b"new".to_vec()));
+        assert_eq!(overlay.get(b"/etc/passwd").unwrap(), None);
+
+        let etc: Vec<Entry> = overlay.scan_prefix(b"/etc").map(Result::unwrap).collect();
+        assert_eq!(
+            etc,
+            [
+                (b"/etc/fstab".to_vec(), b"added".to_vec()),
+                (b"/etc/group".to_vec(), b"kept".to_vec()),
+                (b"/etc/hosts".to_vec(), b"new".to_vec()),
+            ]
+        );
+        assert_eq!(tree.len(), 3, "nothing written underneath");
+        assert_eq!(&*tree.get(b"/etc/hosts").unwrap().unwrap(), b"old");
+    }
+}
@@ -0,0 +1,125 @@
+//! Keeping background scans out of the way of interactive work
+//!
+//! Hashing reads can be rate-limited and the whole process can be
+//! given idle CPU and IO priority, where the platform supports it.
+
+use std::{
+    io,
+    thread::sleep,
+    time::{Duration, Instant},
+};
+
+/// Longest the reader may fall behind its limit (say while the
+/// database is busy) before the shortfall is forgotten, so that it
+/// never catches up in a burst
+const MAX_CREDIT: Duration = Duration::from_secs(1);
+
+/// Limits the average rate of reads
+#[derive(Clone, Debug)]
+pub struct Throttle {
+    /// Limit in bytes per second
+    bytes_per_sec: u64,
+
+    /// Start of the current accounting window
+    started: Instant,
+
+    /// Bytes read since the window started
+    bytes: u64,
+}
+
+impl Throttle {
+    pub fn new(bytes_per_sec: u64) -> Self {
+        Throttle {
+            bytes_per_sec: bytes_per_sec.max(1),
+            started: Instant::now(),
+            bytes: 0,
+        }
+    }
+
+    /// Start afresh, e.g. after idling between runs
+    pub fn reset(&mut self) {
+        self.started = Instant::now();
+        self.bytes = 0;
+    }
+
+    /// Account for bytes read, sleeping as long as it takes to bring
+    /// the average rate back within the limit
+    pub fn consume(&mut self, bytes: u64) {
+        if self.started.elapsed() > self.due() + MAX_CREDIT {
+            self.reset();
+        }
+        self.bytes += bytes;
+        let due = self.due();
+        let elapsed = self.started.elapsed();
+        if due > elapsed {
+            sleep(due - elapsed);
+        }
+    }
+
+    /// Time the bytes read so far should have taken at the limit
+    fn due(&self) -> Duration {
+        Duration::from_secs_f64(self.bytes as f64 / self.bytes_per_sec as f64)
+    }
+}
+
+/// Give the process the lowest CPU priority and idle IO priority
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn lower_priority() -> io::Result<()> {
+    // from linux/ioprio.h
+    const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+    const IOPRIO_CLASS_IDLE: libc::c_int = 3;
+    const IOPRIO_CLASS_SHIFT: libc::c_int = 13;
+
+    nice()?;
+    // SAFETY: a plain syscall on the calling process
+    let result = unsafe {
+        libc::syscall(
+            libc::SYS_ioprio_set,
+            IOPRIO_WHO_PROCESS,
+            0,
+            IOPRIO_CLASS_IDLE << IOPRIO_CLASS_SHIFT,
+        )
+    };
+    if result < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Give the process the lowest CPU priority (IO priority can't be set
+/// here)
+#[cfg(all(unix, not(any(target_os = "linux", target_os = "android"))))]
+pub fn lower_priority() -> io::Result<()> {
+    nice()
+}
+
+/// Priority can't be lowered here
+#[cfg(not(unix))]
+pub fn lower_priority() -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(unix)]
+fn nice() -> io::Result<()> {
+    // SAFETY: setpriority only affects the calling process
+    if unsafe { libc::setpriority(libc::PRIO_PROCESS as _, 0, 19) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+pub mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_throttle_limits_rate() {
+        let mut throttle = Throttle::new(1000);
+        let started = Instant::now();
+        for _ in 0..5 {
+            throttle.consume(100);
+        }
+        assert!(started.elapsed() >= Duration::from_millis(450));
+    }
+}
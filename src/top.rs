@@ -0,0 +1,187 @@
+//! A live view of a running agent, from its control socket
+//!
+//! The agent's status is fetched every refresh and shown as the
+//! progress of the run under way (or when the next is due), the last
+//! run's totals and throughput, and the most recent findings.
+
+use crate::{
+    control::{self, epoch_seconds, Status},
+    error::FimblError,
+    report::scaled,
+};
+
+use ratatui::{
+    crossterm::event::{self, Event, KeyCode, KeyEventKind},
+    layout::{Constraint, Layout},
+    style::Stylize,
+    text::Line,
+    widgets::{Block, Gauge, List, ListItem, Paragraph},
+    DefaultTerminal, Frame,
+};
+use std::{
+    path::Path,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// Time given as seconds since the unix epoch, for display
+fn shown(seconds: u64) -> String {
+    humantime::format_rfc3339_seconds(UNIX_EPOCH + Duration::from_secs(seconds)).to_string()
+}
+
+/// Time between two times given as seconds since the unix epoch, for
+/// display
+fn between(from: u64, to: u64) -> String {
+    humantime::format_duration(Duration::from_secs(to.saturating_sub(from))).to_string()
+}
+
+/// What the agent is doing now, in a line
+pub fn activity(status: &Status, now: u64) -> String {
+    match (status.scanning_since, status.next_run) {
+        (Some(since), _) => {
+            let elapsed = now.saturating_sub(since).max(1);
+            format!(
+                "scanning: {} of {} files, {} hashed ({}/s), for {}",
+                status.files_examined,
+                status.files_tracked,
+                scaled(status.bytes_hashed as f64),
+                scaled(status.bytes_hashed as f64 / elapsed as f64),
+                between(since, now)
+            )
+        }
+        (None, Some(next)) => format!(
+            "idle: next run in {}, at {}",
+            between(now, next),
+            shown(next)
+        ),
+        (None, None) => "idle: no run scheduled".to_string(),
+    }
+}
+
+/// How the last run went, in a line
+fn last_run(status: &Status) -> String {
+    match &status.last_run {
+        Some(run) => format!(
+            "last run: finished {}, {} files, {} hashed in {:.1}s ({}/s), {} findings",
+            shown(run.finished),
+            run.files_examined,
+            scaled(run.bytes_hashed as f64),
+            run.elapsed_seconds,
+            scaled(run.bytes_hashed as f64 / run.elapsed_seconds.max(1e-6)),
+            run.findings
+        ),
+        None => "last run: none yet".to_string(),
+    }
+}
+
+/// Draw the agent's status, or why it couldn't be had
+fn draw(frame: &mut Frame, socket: &Path, status: &Result<Status, FimblError>) {
+    let [header, gauge, findings, footer] = Layout::vertical([
+        Constraint::Length(4),
+        Constraint::Length(1),
+        Constraint::Min(0),
+        Constraint::Length(1),
+    ])
+    .areas(frame.area());
+    frame.render_widget(Paragraph::new("q quit").dim(), footer);
+
+    let status = match status {
+        Ok(status) => status,
+        Err(e) => {
+            let block = Block::bordered().title(format!("fimbl agent at {}", socket.display()));
+            frame.render_widget(Paragraph::new(e.to_string()).red().block(block), header);
+            return;
+        }
+    };
+
+    let now = epoch_seconds(SystemTime::now());
+    let mut lines = vec![
+        Line::from(activity(status, now)),
+        Line::from(last_run(status)),
+    ];
+    if status.draining {
+        lines.push(Line::from("draining: exiting when the run under way is done").yellow());
+    }
+    let block = Block::bordered().title(format!("fimbl agent on {}", status.host));
+    frame.render_widget(Paragraph::new(lines).block(block), header);
+
+    if status.scanning_since.is_some() {
+        let ratio = match status.files_tracked {
+            0 => 0.0,
+            tracked => (status.files_examined as f64 / tracked as f64).min(1.0),
+        };
+        frame.render_widget(Gauge::default().ratio(ratio).cyan(), gauge);
+    }
+
+    let items: Vec<ListItem> = status
+        .recent
+        .iter()
+        .rev()
+        .map(|finding| {
+            let line = format!(
+                "{} {} {}",
+                shown(finding.time),
+                finding.code,
+                finding.message
+            );
+            match finding.severity.as_str() {
+                "critical" => ListItem::new(line).red().bold(),
+                "warning" => ListItem::new(line).red(),
+                _ => ListItem::new(line).dim(),
+            }
+        })
+        .collect();
+    let block = Block::bordered().title("Recent findings, newest first");
+    frame.render_widget(List::new(items).block(block), findings);
+}
+
+/// Redraw the status every refresh until told to quit
+fn watch(terminal: &mut DefaultTerminal, socket: &Path, refresh: Duration) -> std::io::Result<()> {
+    loop {
+        let status = control::status(socket);
+        terminal.draw(|frame| draw(frame, socket, &status))?;
+        if !event::poll(refresh)? {
+            continue;
+        }
+        if let Event::Key(key) = event::read()? {
+            let quit = matches!(key.code, KeyCode::Char('q') | KeyCode::Esc);
+            if key.kind == KeyEventKind::Press && quit {
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// Show the status of the agent listening on the socket until told to
+/// quit
+pub fn run(socket: &Path, refresh: Duration) -> Result<(), FimblError> {
+    let mut terminal = ratatui::try_init().map_err(FimblError::TerminalError)?;
+    let result = watch(&mut terminal, socket, refresh);
+    ratatui::try_restore().map_err(FimblError::TerminalError)?;
+    result.map_err(FimblError::TerminalError)
+}
+
+#[cfg(test)]
+pub mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_activity() {
+        let mut status = Status {
+            scanning_since: Some(1000),
+            files_tracked: 200,
+            files_examined: 50,
+            bytes_hashed: 10 * 1024 * 1024,
+            ..Status::default()
+        };
+        assert_eq!(
+            activity(&status, 1010),
+            "scanning: 50 of 200 files, 10.0 MiB hashed (1.0 MiB/s), for 10s"
+        );
+        status.scanning_since = None;
+        status.next_run = Some(1600);
+        assert!(
+            activity(&status, 1000).starts_with("idle: next run in 10m, at 1970-01-01T00:26:40Z")
+        );
+    }
+}
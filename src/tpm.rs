@@ -0,0 +1,185 @@
+//! Unsealing keys sealed to a TPM 2.0
+//!
+//! A key sealed to the TPM (and stored at a persistent handle) never
+//! rests on disk, so it can't be copied off the host to forge records
+//! elsewhere, and with a PCR policy it only unseals while the host
+//! runs the measured boot chain it was sealed under. Sealing and
+//! unsealing go through tpm2-tools, which must be installed where the
+//! system installs programs: they are run from there by absolute path,
+//! never looked up on `PATH`, which whoever starts fimbl controls.
+
+use crate::error::FimblError;
+
+use std::{
+    io::Write,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+};
+
+/// Where tpm2-tools are looked for, in order
+const TOOL_DIRS: &[&str] = &["/usr/bin", "/usr/local/bin"];
+
+/// First and last persistent object handles in the owner hierarchy
+const PERSISTENT_HANDLES: (u32, u32) = (0x8100_0000, 0x81FF_FFFF);
+
+/// Persistent handles are given in hex, e.g. "0x81000100"
+pub fn parse_handle(handle: &str) -> Result<u32, String> {
+    let digits = handle
+        .strip_prefix("0x")
+        .or_else(|| handle.strip_prefix("0X"))
+        .unwrap_or(handle);
+    match u32::from_str_radix(digits, 16) {
+        Ok(handle) if (PERSISTENT_HANDLES.0..=PERSISTENT_HANDLES.1).contains(&handle) => Ok(handle),
+        _ => Err("expected a persistent handle from 0x81000000 to 0x81FFFFFF".to_string()),
+    }
+}
+
+/// PCR selections are given as for tpm2-tools, e.g. "sha256:0,7"
+pub fn parse_pcrs(pcrs: &str) -> Result<String, String> {
+    let valid = pcrs.split_once(':').is_some_and(|(bank, list)| {
+        !bank.is_empty()
+            && bank.chars().all(|c| c.is_ascii_alphanumeric())
+            && list.split(',').all(|pcr| pcr.parse::<u8>().is_ok())
+    });
+    match valid {
+        true => Ok(pcrs.to_string()),
+        false => Err("expected a bank and PCR indices, e.g. sha256:0,7".to_string()),
+    }
+}
+
+/// The path of a tpm2-tools program, in the first of the directories
+/// given that has it
+fn tool(name: &str, dirs: &[&str]) -> Result<PathBuf, FimblError> {
+    dirs.iter()
+        .map(|dir| Path::new(dir).join(name))
+        .find(|path| path.is_file())
+        .ok_or_else(|| FimblError::TpmError(format!("{name} not found in {}", dirs.join(" or "))))
+}
+
+/// Run a tpm2-tools program with the arguments given, and any input,
+/// returning its output
+fn run(name: &str, args: &[&str], input: Option<&[u8]>) -> Result<Vec<u8>, FimblError> {
+    let mut child = Command::new(tool(name, TOOL_DIRS)?)
+        .args(args)
+        .stdin(match input {
+            Some(_) => Stdio::piped(),
+            None => Stdio::null(),
+        })
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| FimblError::TpmError(format!("cannot run {name}: {e}")))?;
+    if let (Some(input), Some(mut stdin)) = (input, child.stdin.take()) {
+        stdin
+            .write_all(input)
+            .map_err(|e| FimblError::TpmError(format!("cannot write to {name}: {e}")))?;
+    }
+    let output = child
+        .wait_with_output()
+        .map_err(|e| FimblError::TpmError(format!("cannot run {name}: {e}")))?;
+    match output.status.success() {
+        true => Ok(output.stdout),
+        false => {
+            let message = String::from_utf8_lossy(&output.stderr).trim().to_string();
+            Err(FimblError::TpmError(format!("{name} failed: {message}")))
+        }
+    }
+}
+
+/// Seal a key to the TPM under the owner hierarchy's primary key, with
+/// a policy of the PCRs given as they are now (if any are), and make it
+/// persistent at the handle given
+///
+/// The key goes to the TPM on stdin; only the sealed object (which
+/// only this TPM can load) is written out, to a temporary directory.
+pub fn seal(handle: u32, pcrs: Option<&str>, key: &[u8]) -> Result<(), FimblError> {
+    let dir = tempfile::tempdir()?;
+    let file = |name: &str| dir.path().join(name).to_string_lossy().into_owned();
+    let (primary, policy, public, private, sealed) = (
+        file("primary.ctx"),
+        file("policy.dat"),
+        file("key.pub"),
+        file("key.priv"),
+        file("key.ctx"),
+    );
+    run("tpm2_createprimary", &["-C", "o", "-c", &primary], None)?;
+    let mut create = vec!["-C", &primary, "-i", "-", "-u", &public, "-r", &private];
+    if let Some(pcrs) = pcrs {
+        run(
+            "tpm2_createpolicy",
+            &["--policy-pcr", "-l", pcrs, "-L", &policy],
+            None,
+        )?;
+        create.extend(["-L", &policy]);
+    }
+    run("tpm2_create", &create, Some(key))?;
+    run(
+        "tpm2_load",
+        &["-C", &primary, "-u", &public, "-r", &private, "-c", &sealed],
+        None,
+    )?;
+    let handle = format!("{handle:#010x}");
+    run(
+        "tpm2_evictcontrol",
+        &["-C", "o", "-c", &sealed, &handle],
+        None,
+    )?;
+    Ok(())
+}
+
+/// Unseal the key at a persistent handle, satisfying its PCR policy
+/// (if it was sealed with one)
+pub fn unseal(handle: u32, pcrs: Option<&str>) -> Result<Vec<u8>, FimblError> {
+    let object = format!("{handle:#010x}");
+    let auth = pcrs.map(|pcrs| format!("pcr:{pcrs}"));
+    let mut args = vec!["--object-context", &object];
+    if let Some(auth) = &auth {
+        args.extend(["--auth", auth]);
+    }
+    let key = run("tpm2_unseal", &args, None).map_err(|e| match e {
+        FimblError::TpmError(message) => {
+            FimblError::TpmError(format!("cannot unseal {object}: {message}"))
+        }
+        e => e,
+    })?;
+    if key.is_empty() {
+        return Err(FimblError::TpmError(format!(
+            "key sealed at {object} is empty"
+        )));
+    }
+    Ok(key)
+}
+
+#[cfg(test)]
+pub mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_parse_handle() {
+        assert_eq!(parse_handle("0x81000100"), Ok(0x8100_0100));
+        assert_eq!(parse_handle("81000100"), Ok(0x8100_0100));
+        assert!(parse_handle("0x80000000").is_err());
+        assert!(parse_handle("persistent").is_err());
+    }
+
+    #[test]
+    fn test_tool() {
+        let dir = tempfile::tempdir().unwrap();
+        let dirs = [dir.path().to_str().unwrap()];
+        assert!(tool("tpm2_unseal", &dirs).is_err(), "not looked up on PATH");
+        std::fs::write(dir.path().join("tpm2_unseal"), "").unwrap();
+        assert_eq!(
+            tool("tpm2_unseal", &dirs).unwrap(),
+            dir.path().join("tpm2_unseal")
+        );
+    }
+
+    #[test]
+    fn test_parse_pcrs() {
+        assert!(parse_pcrs("sha256:0,7").is_ok());
+        assert!(parse_pcrs("sha256:").is_err());
+        assert!(parse_pcrs("0,7").is_err());
+        assert!(parse_pcrs("sha256:0;7").is_err());
+    }
+}
@@ -0,0 +1,164 @@
+//! Windows file details not exposed by the standard library
+
+use crate::fingerprint::{HashValue, NamedHash};
+
+use sha3::{Digest, Sha3_256};
+use std::{
+    ffi::{OsStr, OsString},
+    fs::OpenOptions,
+    io,
+    os::windows::{
+        ffi::{OsStrExt, OsStringExt},
+        fs::OpenOptionsExt,
+        io::AsRawHandle,
+    },
+    path::{Path, PathBuf},
+    ptr, slice,
+};
+use windows_sys::Win32::{
+    Foundation::{ERROR_HANDLE_EOF, ERROR_SUCCESS, HANDLE, HLOCAL, INVALID_HANDLE_VALUE},
+    Security::{
+        Authorization::{GetSecurityInfo, SE_FILE_OBJECT},
+        GetSecurityDescriptorLength, DACL_SECURITY_INFORMATION, GROUP_SECURITY_INFORMATION,
+        OWNER_SECURITY_INFORMATION, PSECURITY_DESCRIPTOR,
+    },
+    Storage::FileSystem::{
+        FindClose, FindFirstStreamW, FindNextStreamW, FindStreamInfoStandard,
+        GetFileInformationByHandle, BY_HANDLE_FILE_INFORMATION, FILE_FLAG_BACKUP_SEMANTICS,
+        FILE_FLAG_OPEN_REPARSE_POINT, FILE_READ_ATTRIBUTES, READ_CONTROL, WIN32_FIND_STREAM_DATA,
+    },
+    System::Memory::LocalFree,
+};
+
+/// Identity, link count, attributes and security of a file
+pub struct FileDetails {
+    /// Serial number of the volume containing the file
+    pub volume: u64,
+
+    /// File index, unique within the volume
+    pub index: u64,
+
+    /// Number of hardlinks to the file
+    pub links: u32,
+
+    /// File attribute flags
+    pub attributes: u32,
+
+    /// Hash of the owner, group and DACL, if readable
+    pub security_hash: Option<HashValue>,
+}
+
+/// Read details of a file (not following symlinks)
+pub fn file_details(path: &Path) -> io::Result<FileDetails> {
+    let file = OpenOptions::new()
+        .access_mode(FILE_READ_ATTRIBUTES | READ_CONTROL)
+        .custom_flags(FILE_FLAG_BACKUP_SEMANTICS | FILE_FLAG_OPEN_REPARSE_POINT)
+        .open(path)?;
+    let handle = file.as_raw_handle() as HANDLE;
+
+    // SAFETY: handle is open for the lifetime of file and info is a
+    // plain struct for the call to fill
+    let mut info: BY_HANDLE_FILE_INFORMATION = unsafe { std::mem::zeroed() };
+    if unsafe { GetFileInformationByHandle(handle, &mut info) } == 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(FileDetails {
+        volume: info.dwVolumeSerialNumber as u64,
+        index: (info.nFileIndexHigh as u64) << 32 | info.nFileIndexLow as u64,
+        links: info.nNumberOfLinks,
+        attributes: info.dwFileAttributes,
+        security_hash: security_hash(handle),
+    })
+}
+
+/// Hash of the self-relative security descriptor (owner, group and
+/// DACL) of an open file
+///
+/// The SACL is left out as reading it requires privileges.
+fn security_hash(handle: HANDLE) -> Option<HashValue> {
+    let mut descriptor: PSECURITY_DESCRIPTOR = ptr::null_mut();
+
+    // SAFETY: on success the descriptor is allocated by the system,
+    // valid for its reported length, and freed with LocalFree
+    unsafe {
+        let status = GetSecurityInfo(
+            handle,
+            SE_FILE_OBJECT,
+            OWNER_SECURITY_INFORMATION | GROUP_SECURITY_INFORMATION | DACL_SECURITY_INFORMATION,
+            ptr::null_mut(),
+            ptr::null_mut(),
+            ptr::null_mut(),
+            ptr::null_mut(),
+            &mut descriptor,
+        );
+        if status != ERROR_SUCCESS {
+            return None;
+        }
+
+        let length = GetSecurityDescriptorLength(descriptor) as usize;
+        let hash = Sha3_256::digest(slice::from_raw_parts(descriptor as *const u8, length));
+        LocalFree(descriptor as HLOCAL);
+        Some(hash.into())
+    }
+}
+
+/// Names of the alternate data streams of a file, without the leading
+/// colon or the `:$DATA` suffix, in order
+fn alternate_stream_names(path: &Path) -> io::Result<Vec<String>> {
+    let wide: Vec<u16> = path.as_os_str().encode_wide().chain(Some(0)).collect();
+    let mut names = vec![];
+
+    // SAFETY: wide is NUL terminated, data is a plain struct for the
+    // calls to fill and the find handle is closed before returning
+    unsafe {
+        let mut data: WIN32_FIND_STREAM_DATA = std::mem::zeroed();
+        let find = FindFirstStreamW(
+            wide.as_ptr(),
+            FindStreamInfoStandard,
+            &mut data as *mut _ as *mut _,
+            0,
+        );
+        if find == INVALID_HANDLE_VALUE {
+            let error = io::Error::last_os_error();
+            return match error.raw_os_error() {
+                Some(code) if code as u32 == ERROR_HANDLE_EOF => Ok(names),
+                _ => Err(error),
+            };
+        }
+
+        loop {
+            let length = data.cStreamName.iter().position(|&c| c == 0).unwrap_or(0);
+            let name = OsString::from_wide(&data.cStreamName[..length]);
+            let name = name.to_string_lossy();
+            let name = name.strip_suffix(":$DATA").unwrap_or(&name);
+            let name = name.strip_prefix(':').unwrap_or(name);
+            if !name.is_empty() {
+                names.push(name.to_string());
+            }
+            if FindNextStreamW(find, &mut data as *mut _ as *mut _) == 0 {
+                break;
+            }
+        }
+        FindClose(find);
+    }
+
+    names.sort();
+    Ok(names)
+}
+
+/// Names and content hashes of the alternate data streams of a file
+pub fn alternate_streams(
+    path: &Path,
+    mut hash: impl FnMut(&Path) -> io::Result<HashValue>,
+) -> io::Result<Vec<NamedHash>> {
+    alternate_stream_names(path)?
+        .into_iter()
+        .map(|name| {
+            let mut stream = path.as_os_str().to_owned();
+            stream.push(OsStr::new(":"));
+            stream.push(OsStr::new(&name));
+            Ok((name, hash(&PathBuf::from(stream))?))
+        })
+        .collect()
+}
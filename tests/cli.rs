@@ -0,0 +1,303 @@
+//! Commands run end to end, as fimbl is run
+
+use std::{
+    path::Path,
+    process::{Command, Output},
+};
+
+/// Run fimbl in a directory, with a database and home of its own there
+fn fimbl(dir: &Path, args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_fimbl"))
+        .current_dir(dir)
+        .env("HOME", dir)
+        .arg("--database")
+        .arg(dir.join("db"))
+        .args(args)
+        .output()
+        .unwrap()
+}
+
+/// Run fimbl as above, checking it succeeds, and return its stdout
+fn stdout(dir: &Path, args: &[&str]) -> String {
+    let output = fimbl(dir, args);
+    assert!(
+        output.status.success(),
+        "{args:?}: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    String::from_utf8(output.stdout).unwrap()
+}
+
+#[cfg(unix)]
+#[test]
+fn test_repointed_symlink() {
+    let dir = tempfile::tempdir().unwrap();
+    let dir = dir.path();
+    std::fs::write(dir.join("one"), "one").unwrap();
+    std::fs::write(dir.join("two"), "two").unwrap();
+    std::os::unix::fs::symlink("one", dir.join("link")).unwrap();
+
+    stdout(dir, &["add", "link"]);
+    let listed = stdout(dir, &["list"]);
+    let dir = dir.canonicalize().unwrap();
+    assert!(listed.contains(&format!("{}\n", dir.join("link").display())));
+    assert!(listed.contains(&format!("{}\n", dir.join("one").display())));
+    assert!(!stdout(&dir, &["verify", "link"]).contains("[F011]"));
+
+    std::fs::remove_file(dir.join("link")).unwrap();
+    std::os::unix::fs::symlink("two", dir.join("link")).unwrap();
+    let verified = stdout(&dir, &["verify-all"]);
+    assert!(verified.contains("symlink target changed"), "{verified}");
+    assert!(verified.contains("[F011]"));
+}
+
+#[cfg(unix)]
+#[test]
+fn test_path_policies() {
+    let dir = tempfile::tempdir().unwrap();
+    let dir = dir.path().canonicalize().unwrap();
+    std::fs::create_dir(dir.join("data")).unwrap();
+    std::fs::write(dir.join("data/.bashrc"), "alias ll='ls -l'").unwrap();
+    std::os::unix::fs::symlink("data", dir.join("home")).unwrap();
+    std::fs::create_dir(dir.join("as-given")).unwrap();
+    std::fs::create_dir(dir.join("aliased")).unwrap();
+    let given = dir.join("home/.bashrc");
+    let canonical = dir.join("data/.bashrc");
+    let given = given.to_str().unwrap();
+
+    // as given: recorded by the path through the link, and only that
+    let db = dir.join("as-given");
+    stdout(&db, &["--path-policy", "as-given", "add", given]);
+    let listed = stdout(&db, &["list"]);
+    assert!(listed.contains(given), "{listed}");
+    assert!(!listed.contains(canonical.to_str().unwrap()), "{listed}");
+    let verified = stdout(&db, &["verify", canonical.to_str().unwrap()]);
+    assert!(verified.contains("[C002]"), "{verified}");
+    assert!(!stdout(&db, &["verify", given]).contains("[C002]"));
+    let conflicting = fimbl(&db, &["--path-policy", "canonical", "list"]);
+    assert!(!conflicting.status.success());
+    assert!(String::from_utf8_lossy(&conflicting.stderr).contains("as-given"));
+
+    // aliased: recorded canonically, found by either path, and the
+    // alias repointed is reported
+    let db = dir.join("aliased");
+    stdout(&db, &["--path-policy", "aliased", "add", given]);
+    let listed = stdout(&db, &["list"]);
+    assert!(listed.contains(canonical.to_str().unwrap()), "{listed}");
+    assert!(!stdout(&db, &["verify", given]).contains("[C002]"));
+    assert!(!stdout(&db, &["verify", canonical.to_str().unwrap()]).contains("[C002]"));
+
+    std::fs::create_dir(dir.join("other")).unwrap();
+    std::fs::write(dir.join("other/.bashrc"), "alias ll='ls -l'").unwrap();
+    std::fs::remove_file(dir.join("home")).unwrap();
+    std::os::unix::fs::symlink("other", dir.join("home")).unwrap();
+    let verified = stdout(&db, &["verify-all"]);
+    assert!(verified.contains("[F010]"), "{verified}");
+}
+
+#[cfg(unix)]
+#[test]
+fn test_import_aide_records_as_add() {
+    use base64::{engine::general_purpose::STANDARD, Engine};
+    use sha2::{Digest, Sha256};
+
+    let dir = tempfile::tempdir().unwrap();
+    let dir = dir.path().canonicalize().unwrap();
+    std::fs::create_dir(dir.join("data")).unwrap();
+    std::fs::write(dir.join("data/hosts"), "127.0.0.1 localhost").unwrap();
+    std::os::unix::fs::symlink("data", dir.join("etc")).unwrap();
+    std::os::unix::fs::symlink("hosts", dir.join("data/localhost")).unwrap();
+    let sha256 = STANDARD.encode(Sha256::digest("127.0.0.1 localhost"));
+    let aide = format!(
+        "@@begin_db\n\
+         @@db_spec name lname attr perm uid gid size mtime sha256\n\
+         {etc}/hosts 0 1 100644 0 0 19 MA== {sha256}\n\
+         {etc}/localhost hosts 1 120777 0 0 5 MA== 0\n\
+         @@end_db\n",
+        etc = dir.join("etc").display()
+    );
+    std::fs::write(dir.join("aide.db"), aide).unwrap();
+
+    let output = fimbl(&dir, &["import", "--format", "aide", "aide.db"]);
+    let imported = String::from_utf8_lossy(&output.stdout);
+    assert!(imported.contains("[C013]"), "{imported}");
+    assert!(imported.contains("data/localhost"), "{imported}");
+    let listed = stdout(&dir, &["list"]);
+    assert_eq!(listed, format!("{}\n", dir.join("data/hosts").display()));
+}
+
+#[test]
+fn test_gate() {
+    let dir = tempfile::tempdir().unwrap();
+    let dir = dir.path().canonicalize().unwrap();
+    std::fs::create_dir_all(dir.join("config")).unwrap();
+    std::fs::create_dir_all(dir.join("empty")).unwrap();
+    std::fs::write(dir.join("config/app.toml"), "port = 80").unwrap();
+    std::fs::write(dir.join("key"), "secret").unwrap();
+    stdout(&dir, &["add", "config/app.toml"]);
+    stdout(
+        &dir,
+        &["publish", "--sign-key-file", "key", "manifest.json"],
+    );
+    let gate = |dirs: &[&str]| {
+        let args = [
+            &[
+                "gate",
+                "--manifest",
+                "manifest.json",
+                "--sign-key-file",
+                "key",
+            ],
+            dirs,
+        ];
+        fimbl(&dir, &args.concat())
+    };
+
+    #[cfg(unix)]
+    {
+        std::fs::create_dir(dir.join("config/..2026_10_14")).unwrap();
+        std::os::unix::fs::symlink("..2026_10_14", dir.join("config/..data")).unwrap();
+    }
+    assert!(gate(&["config"]).status.success());
+    assert!(!gate(&["empty"]).status.success());
+
+    std::fs::write(dir.join("config/extra.toml"), "debug = true").unwrap();
+    let added = gate(&["config"]);
+    assert!(!added.status.success());
+    assert!(String::from_utf8_lossy(&added.stdout).contains("F012"));
+}
+
+#[test]
+fn test_moves() {
+    let dir = tempfile::tempdir().unwrap();
+    let dir = dir.path().canonicalize().unwrap();
+    for (name, contents) in [("one", "1111"), ("two", "2222"), ("three", "3333")] {
+        std::fs::write(dir.join(name), contents).unwrap();
+        stdout(&dir, &["add", name]);
+    }
+
+    // to an untracked file: only the move
+    std::fs::rename(dir.join("one"), dir.join("moved")).unwrap();
+    let verified = stdout(&dir, &["verify-all", "--detect-renames"]);
+    assert!(verified.contains("[F006]"), "{verified}");
+    assert!(!verified.contains("[F003]"), "{verified}");
+    std::fs::rename(dir.join("moved"), dir.join("one")).unwrap();
+
+    // over a tracked file: the move and the file overwritten
+    std::fs::rename(dir.join("two"), dir.join("three")).unwrap();
+    let verified = stdout(&dir, &["verify-all", "--detect-renames"]);
+    assert!(verified.contains("[F006]"), "{verified}");
+    assert!(verified.contains("[F001]"), "{verified}");
+    assert!(!verified.contains("[F003]"), "{verified}");
+}
+
+#[test]
+fn test_tag_severity() {
+    let dir = tempfile::tempdir().unwrap();
+    let dir = dir.path().canonicalize().unwrap();
+    std::fs::write(dir.join("sshd_config"), "PermitRootLogin no").unwrap();
+    std::fs::write(dir.join("motd"), "hello").unwrap();
+    stdout(&dir, &["add", "--tag", "critical", "sshd_config"]);
+    stdout(&dir, &["add", "motd"]);
+    std::fs::write(dir.join("sshd_config"), "PermitRootLogin yes").unwrap();
+    std::fs::write(dir.join("motd"), "goodbye").unwrap();
+
+    let verified = stdout(
+        &dir,
+        &[
+            "--tag-severity",
+            "critical=critical",
+            "--format",
+            "csv",
+            "verify-all",
+        ],
+    );
+    let severity = |file: &str| {
+        let line = verified.lines().find(|line| line.contains(file)).unwrap();
+        line.split(',').nth(2).unwrap().to_string()
+    };
+    assert_eq!(severity("sshd_config"), "critical", "{verified}");
+    assert_eq!(severity("motd"), "warning", "{verified}");
+}
+
+#[test]
+fn test_notify_window_sinks() {
+    let dir = tempfile::tempdir().unwrap();
+    let dir = dir.path().canonicalize().unwrap();
+    std::fs::write(dir.join("hosts"), "127.0.0.1 localhost").unwrap();
+    stdout(&dir, &["add", "hosts"]);
+    std::fs::write(dir.join("hosts"), "10.0.0.1 localhost").unwrap();
+
+    // held back from sinks other than stdout, which gets it all
+    let args = [
+        "--notify-window",
+        "1h",
+        "--report-to",
+        "stdout,json-file:notified.json",
+        "verify-all",
+    ];
+    let verified = stdout(&dir, &args);
+    assert!(verified.contains("hosts"), "{verified}");
+    let notified = std::fs::read_to_string(dir.join("notified.json")).unwrap();
+    assert!(!notified.contains("hosts"), "{notified}");
+}
+
+#[test]
+fn test_overlapping_prefixes() {
+    let dir = tempfile::tempdir().unwrap();
+    let dir = dir.path().canonicalize().unwrap();
+    std::fs::create_dir_all(dir.join("etc/ssh")).unwrap();
+    std::fs::write(dir.join("etc/ssh/sshd_config"), "PermitRootLogin no").unwrap();
+    stdout(&dir, &["add", "etc/ssh/sshd_config"]);
+    std::fs::write(dir.join("etc/ssh/sshd_config"), "PermitRootLogin yes").unwrap();
+
+    // each file verified and reported once, however often it is covered
+    let etc = dir.join("etc");
+    let ssh = dir.join("etc/ssh");
+    let (etc, ssh) = (etc.to_str().unwrap(), ssh.to_str().unwrap());
+    let args = [
+        "verify-all",
+        "--prefix",
+        ssh,
+        "--prefix",
+        etc,
+        "--prefix",
+        etc,
+    ];
+    let verified = stdout(&dir, &args);
+    assert_eq!(verified.matches("sshd_config").count(), 1, "{verified}");
+}
+
+#[test]
+fn test_since_too_long_ago() {
+    let dir = tempfile::tempdir().unwrap();
+    let logged = fimbl(dir.path(), &["log", "--since", "18446744073709551615s"]);
+    assert_eq!(logged.status.code(), Some(2));
+    let stderr = String::from_utf8_lossy(&logged.stderr);
+    assert!(stderr.contains("duration too long"), "{stderr}");
+}
+
+#[test]
+fn test_check_writes_nothing() {
+    let dir = tempfile::tempdir().unwrap();
+    let dir = dir.path().canonicalize().unwrap();
+    std::fs::write(dir.join("hosts"), "127.0.0.1 localhost").unwrap();
+    // in a flat file, so that any write shows
+    let db = dir.join("db.jsonl");
+    let flat = |args: &[&str]| {
+        Command::new(env!("CARGO_BIN_EXE_fimbl"))
+            .current_dir(&dir)
+            .env("HOME", &dir)
+            .arg("--database")
+            .arg(&db)
+            .args(args)
+            .output()
+            .unwrap()
+    };
+    assert!(flat(&["add", "hosts"]).status.success());
+    std::fs::write(dir.join("hosts"), "10.0.0.1 localhost").unwrap();
+
+    let before = std::fs::read(&db).unwrap();
+    assert_eq!(flat(&["check"]).status.code(), Some(1));
+    assert_eq!(std::fs::read(&db).unwrap(), before);
+}